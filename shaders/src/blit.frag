@@ -0,0 +1,54 @@
+#version 460
+
+layout(set = 0, binding = 0) uniform texture2D inputTexture;
+layout(set = 0, binding = 2) uniform sampler inputSampler;
+
+layout(push_constant) uniform PostParams {
+    vec2 texelSize;
+    vec2 direction; // X is the swapchain's color space: 0 sRGB, 1 Display P3, 2 HDR10, 3 scRGB. Y is 1 to sRGB encode here.
+    vec4 params; // Z is 1 when the input is HDR and hasn't been tonemapped yet, Y is then the exposure. X is the paper white in nits.
+} post;
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+// Narkowicz's fit of the ACES reference rendering transform, as in tonemap.frag
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+vec3 srgbEncode(vec3 c) {
+    return mix(c * 12.92, 1.055 * pow(c, vec3(1.0 / 2.4)) - 0.055, step(vec3(0.0031308), c));
+}
+
+// SMPTE ST 2084, nits from 0 to 10000
+vec3 pqEncode(vec3 nits) {
+    vec3 y = pow(clamp(nits / 10000.0, 0.0, 1.0), vec3(0.1593017578125));
+    return pow((0.8359375 + 18.8515625 * y) / (1.0 + 18.6875 * y), vec3(78.84375));
+}
+
+// Copies the end of the post-process chain to the swapchain, converting from linear sRGB primaries to its
+// color space. sRGB formats apply their encoding on write.
+void main() {
+    vec3 color = texture(sampler2D(inputTexture, inputSampler), fragUV).rgb;
+    if (post.params.z > 0.5) {
+        color = aces(color * post.params.y);
+    }
+
+    // Columns of the BT.709 to target primaries matrices
+    mat3 toP3 = mat3(0.8225, 0.0332, 0.0171, 0.1774, 0.9669, 0.0724, 0.0, 0.0, 0.9108);
+    mat3 toBt2020 = mat3(0.6274, 0.0691, 0.0164, 0.3293, 0.9195, 0.0880, 0.0433, 0.0114, 0.8956);
+    float paperWhite = post.params.x;
+    if (post.direction.x > 2.5) {
+        color = color * paperWhite / 80.0; // scRGB's 1 is 80 nits
+    } else if (post.direction.x > 1.5) {
+        color = pqEncode(toBt2020 * max(color, vec3(0.0)) * paperWhite);
+    } else if (post.direction.x > 0.5) {
+        color = toP3 * color;
+    }
+    if (post.direction.y > 0.5) {
+        color = srgbEncode(clamp(color, 0.0, 1.0));
+    }
+    outColor = vec4(color, 1.0);
+}
@@ -0,0 +1,21 @@
+#version 460
+
+layout(set = 0, binding = 0) uniform texture2D inputTexture; // The scene
+layout(set = 0, binding = 1) uniform texture2D bloomTexture; // Blurred bright parts, half size
+layout(set = 0, binding = 2) uniform sampler inputSampler;
+
+layout(push_constant) uniform PostParams {
+    vec2 texelSize;
+    vec2 direction;
+    vec4 params; // X is the intensity
+} post;
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    vec3 scene = texture(sampler2D(inputTexture, inputSampler), fragUV).rgb;
+    vec3 bloom = texture(sampler2D(bloomTexture, inputSampler), fragUV).rgb;
+    outColor = vec4(scene + bloom * post.params.x, 1.0);
+}
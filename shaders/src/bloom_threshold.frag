@@ -0,0 +1,28 @@
+#version 460
+
+layout(set = 0, binding = 0) uniform texture2D inputTexture;
+layout(set = 0, binding = 2) uniform sampler inputSampler;
+
+layout(push_constant) uniform PostParams {
+    vec2 texelSize;
+    vec2 direction;
+    vec4 params; // X is the threshold
+} post;
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+// Keeps the part of each pixel brighter than the threshold, downsampling to the half size bloom target
+void main() {
+    // Four bilinear taps average the 4x4 texels under each half size pixel, which keeps small highlights from flickering
+    vec2 offset = post.texelSize;
+    vec3 color = (texture(sampler2D(inputTexture, inputSampler), fragUV + vec2(-offset.x, -offset.y)).rgb +
+                  texture(sampler2D(inputTexture, inputSampler), fragUV + vec2(offset.x, -offset.y)).rgb +
+                  texture(sampler2D(inputTexture, inputSampler), fragUV + vec2(-offset.x, offset.y)).rgb +
+                  texture(sampler2D(inputTexture, inputSampler), fragUV + vec2(offset.x, offset.y)).rgb) * 0.25;
+
+    float brightness = max(color.r, max(color.g, color.b));
+    float contribution = max(brightness - post.params.x, 0.0) / max(brightness, 1e-4);
+    outColor = vec4(color * contribution, 1.0);
+}
@@ -0,0 +1,25 @@
+#version 460
+
+layout(set = 0, binding = 0) uniform texture2D inputTexture;
+layout(set = 0, binding = 2) uniform sampler inputSampler;
+
+layout(push_constant) uniform PostParams {
+    vec2 texelSize;
+    vec2 direction; // (1, 0) for the horizontal pass, (0, 1) for the vertical one
+    vec4 params;
+} post;
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+// One axis of a separable 9 tap Gaussian, using bilinear filtering to read two texels per tap
+void main() {
+    vec2 step = post.direction * post.texelSize;
+    vec3 color = texture(sampler2D(inputTexture, inputSampler), fragUV).rgb * 0.2270270270;
+    color += texture(sampler2D(inputTexture, inputSampler), fragUV + step * 1.3846153846).rgb * 0.3162162162;
+    color += texture(sampler2D(inputTexture, inputSampler), fragUV - step * 1.3846153846).rgb * 0.3162162162;
+    color += texture(sampler2D(inputTexture, inputSampler), fragUV + step * 3.2307692308).rgb * 0.0702702703;
+    color += texture(sampler2D(inputTexture, inputSampler), fragUV - step * 3.2307692308).rgb * 0.0702702703;
+    outColor = vec4(color, 1.0);
+}
@@ -0,0 +1,17 @@
+#version 460
+
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 view;
+    mat4 proj;
+    vec4 cameraPos;
+} ubo; // Only the start of the block
+
+layout(location = 2) in vec3 fragWorldPos;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    // Linear distance rather than the buffer's depth, which is almost all close to 1
+    float distance = length(fragWorldPos - ubo.cameraPos.xyz);
+    outColor = vec4(vec3(exp(-distance / 16.0)), 1.0);
+}
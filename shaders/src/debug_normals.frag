@@ -0,0 +1,9 @@
+#version 460
+
+layout(location = 3) in vec3 fragNormal;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    outColor = vec4(normalize(fragNormal) * 0.5 + 0.5, 1.0); // -1..1 to 0..1 per axis
+}
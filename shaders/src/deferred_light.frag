@@ -0,0 +1,193 @@
+#version 460
+
+#define MAX_LIGHTS 16
+#define MAX_SHADOW_CASTERS 4
+#define PI 3.14159265359
+
+struct Light {
+    vec4 position; // W is 0 for directional lights, where xyz is the direction towards the light
+    vec4 color; // Premultiplied by intensity, W is the range of point lights
+    vec4 shadow; // X is the shadow map layer, negative without one
+};
+
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 view;
+    mat4 proj;
+    vec4 cameraPos;
+    vec4 ambient; // Sky
+    vec4 ambientGround;
+    uvec4 lightCount;
+    Light lights[MAX_LIGHTS];
+    mat4 shadowViewProj[MAX_SHADOW_CASTERS];
+    vec4 shadowParams; // X is the texel size, Y the normal offset
+    vec4 fogColor; // W is the falloff, 0 without fog, 1 linear, 2 exponential, 3 exponential squared
+    vec4 fogParams; // X and Y are the linear start and end, Z the density, W the far plane
+} ubo;
+
+layout(push_constant) uniform PushConstants {
+    mat4 invViewProj; // Clip space back to world space
+    vec4 occlusion; // X is 1 when gOcclusion holds this frame's SSAO
+    vec4 rayTracing; // X is 1 when rtShadows replaces the shadow maps, Y when rtReflections holds reflections
+} pc;
+
+// Written by gbuffer.frag
+layout(set = 1, binding = 0) uniform texture2D gAlbedo;
+layout(set = 1, binding = 1) uniform texture2D gNormal;
+layout(set = 1, binding = 2) uniform texture2D gMaterial;
+layout(set = 1, binding = 3) uniform texture2D gDepth;
+layout(set = 1, binding = 4) uniform sampler gSampler;
+layout(set = 1, binding = 5) uniform texture2D gOcclusion; // Written by ssao_blur.frag
+// Written by rt.rgen
+layout(set = 1, binding = 6) uniform texture2D rtShadows; // Visibility of each shadow map layer's light, one per channel
+layout(set = 1, binding = 7) uniform texture2D rtReflections; // Alpha is how much it replaces the ambient specular
+
+layout(set = 2, binding = 0) uniform texture2DArray shadowMaps;
+layout(set = 2, binding = 1) uniform samplerShadow shadowSampler;
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+// Fraction of a directional light reaching the position, 3x3 PCF over the light's shadow map or a traced ray
+float shadowFactor(Light light, vec3 worldPos, vec3 normal) {
+    if (light.shadow.x < 0.0) {
+        return 1.0;
+    }
+    if (pc.rayTracing.x > 0.0) {
+        return texture(sampler2D(rtShadows, gSampler), fragUV)[int(light.shadow.x)];
+    }
+    vec3 offsetPos = worldPos + normal * ubo.shadowParams.y; // Normal offset against acne on steep surfaces
+    vec4 lightPos = ubo.shadowViewProj[int(light.shadow.x)] * vec4(offsetPos, 1.0);
+    vec3 coords = lightPos.xyz / lightPos.w;
+    if (coords.z > 1.0) {
+        return 1.0; // Past the shadow distance
+    }
+    vec2 uv = coords.xy * 0.5 + 0.5;
+
+    float lit = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            vec2 offset = vec2(float(x), float(y)) * ubo.shadowParams.x;
+            lit += texture(sampler2DArrayShadow(shadowMaps, shadowSampler), vec4(uv + offset, light.shadow.x, coords.z));
+        }
+    }
+    return lit / 9.0;
+}
+
+// The BRDF below matches pbr.frag
+
+float distributionGGX(float nDotH, float alpha) {
+    float a2 = alpha * alpha;
+    float d = nDotH * nDotH * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+float visibilitySmithGGX(float nDotL, float nDotV, float alpha) {
+    float a2 = alpha * alpha;
+    float ggxV = nDotL * sqrt(nDotV * nDotV * (1.0 - a2) + a2);
+    float ggxL = nDotV * sqrt(nDotL * nDotL * (1.0 - a2) + a2);
+    return 0.5 / max(ggxV + ggxL, 1e-5);
+}
+
+vec3 fresnelSchlick(float cosTheta, vec3 f0) {
+    return f0 + (1.0 - f0) * pow(1.0 - cosTheta, 5.0);
+}
+
+vec3 envBRDFApprox(vec3 f0, float roughness, float nDotV) {
+    vec4 c0 = vec4(-1.0, -0.0275, -0.572, 0.022);
+    vec4 c1 = vec4(1.0, 0.0425, 1.04, -0.04);
+    vec4 r = roughness * c0 + c1;
+    float a004 = min(r.x * r.x, exp2(-9.28 * nDotV)) * r.x + r.y;
+    vec2 ab = vec2(-1.04, 1.04) * a004 + r.zw;
+    return f0 * ab.x + ab.y;
+}
+
+vec3 hemisphere(vec3 dir, float roughness) {
+    float t = dir.y * 0.5 + 0.5;
+    vec3 sharp = mix(ubo.ambientGround.rgb, ubo.ambient.rgb, t);
+    vec3 average = (ubo.ambientGround.rgb + ubo.ambient.rgb) * 0.5;
+    return mix(sharp, average, roughness);
+}
+
+// 0 to 1, how much of a surface at a distance from the camera the fog hides. Always opaque at the far plane.
+float fogAmount(float distance) {
+    uint falloff = uint(ubo.fogColor.w);
+    float fog = 0.0;
+    if (falloff == 1u) {
+        fog = clamp((distance - ubo.fogParams.x) / max(ubo.fogParams.y - ubo.fogParams.x, 1e-4), 0.0, 1.0);
+    } else if (falloff == 2u) {
+        fog = 1.0 - exp(-ubo.fogParams.z * distance);
+    } else if (falloff == 3u) {
+        float d = ubo.fogParams.z * distance;
+        fog = 1.0 - exp(-d * d);
+    }
+    return falloff == 0u ? 0.0 : max(fog, smoothstep(ubo.fogParams.w * 0.9, ubo.fogParams.w, distance));
+}
+
+void main() {
+    float depth = texture(sampler2D(gDepth, gSampler), fragUV).r;
+    if (depth >= 1.0) {
+        outColor = vec4(0.0, 0.0, 0.0, 1.0); // Nothing was drawn here, matches the forward clear color
+        return;
+    }
+    vec4 albedo = texture(sampler2D(gAlbedo, gSampler), fragUV);
+    vec4 normalRoughness = texture(sampler2D(gNormal, gSampler), fragUV);
+    vec4 emissiveMetallic = texture(sampler2D(gMaterial, gSampler), fragUV);
+
+    vec4 clipPos = pc.invViewProj * vec4(fragUV * 2.0 - 1.0, depth, 1.0);
+    vec3 worldPos = clipPos.xyz / clipPos.w;
+    vec3 normal = normalize(normalRoughness.xyz);
+    float roughness = normalRoughness.w;
+    float metallic = emissiveMetallic.a;
+    float alpha = roughness * roughness;
+
+    vec3 toCamera = normalize(ubo.cameraPos.xyz - worldPos);
+    float nDotV = max(dot(normal, toCamera), 1e-4);
+    vec3 f0 = mix(vec3(0.04), albedo.rgb, metallic);
+    vec3 diffuseColor = albedo.rgb * (1.0 - metallic);
+
+    // Every pixel pays for the lights once, however many surfaces were drawn over it
+    vec3 lit = vec3(0.0);
+    for (uint i = 0u; i < min(ubo.lightCount.x, uint(MAX_LIGHTS)); i++) {
+        Light light = ubo.lights[i];
+        vec3 toLight;
+        float attenuation;
+        if (light.position.w == 0.0) {
+            toLight = light.position.xyz;
+            attenuation = shadowFactor(light, worldPos, normal);
+        } else {
+            vec3 offset = light.position.xyz - worldPos;
+            float distance = length(offset);
+            if (distance >= light.color.w) {
+                continue; // Out of range
+            }
+            toLight = offset / distance;
+            float falloff = clamp(1.0 - pow(distance / light.color.w, 4.0), 0.0, 1.0);
+            attenuation = falloff * falloff / (distance * distance + 1.0);
+        }
+
+        float nDotL = max(dot(normal, toLight), 0.0);
+        if (nDotL > 0.0) {
+            vec3 halfway = normalize(toLight + toCamera);
+            float nDotH = max(dot(normal, halfway), 0.0);
+            vec3 fresnel = fresnelSchlick(max(dot(halfway, toCamera), 0.0), f0);
+            vec3 specular = fresnel * distributionGGX(nDotH, alpha) * visibilitySmithGGX(nDotL, nDotV, alpha);
+            vec3 diffuse = (1.0 - fresnel) * diffuseColor / PI;
+            lit += (diffuse + specular) * light.color.rgb * attenuation * nDotL;
+        }
+    }
+
+    vec3 ambientDiffuse = hemisphere(normal, 1.0) * diffuseColor;
+    vec3 environment = hemisphere(reflect(-toCamera, normal), roughness);
+    if (pc.rayTracing.y > 0.0) {
+        vec4 reflection = texture(sampler2D(rtReflections, gSampler), fragUV);
+        environment = mix(environment, reflection.rgb, reflection.a);
+    }
+    vec3 ambientSpecular = environment * envBRDFApprox(f0, roughness, nDotV);
+    float occlusion = albedo.a * mix(1.0, texture(sampler2D(gOcclusion, gSampler), fragUV).r, pc.occlusion.x);
+    lit += (ambientDiffuse + ambientSpecular) * occlusion;
+    lit += emissiveMetallic.rgb;
+
+    lit = mix(lit, ubo.fogColor.rgb, fogAmount(distance(ubo.cameraPos.xyz, worldPos)));
+    outColor = vec4(lit, 1.0);
+}
@@ -0,0 +1,10 @@
+#version 460
+
+layout(location = 0) out vec2 fragUV;
+
+// Oversized triangle covering the screen, from the vertex index alone so no vertex buffer is bound.
+// Counter clockwise in framebuffer space to survive back face culling.
+void main() {
+    fragUV = vec2(float(gl_VertexIndex & 2), float((gl_VertexIndex << 1) & 2));
+    gl_Position = vec4(fragUV * 2.0 - 1.0, 0.0, 1.0);
+}
@@ -0,0 +1,53 @@
+#version 460
+
+#define FXAA_REDUCE_MIN (1.0 / 128.0)
+#define FXAA_REDUCE_MUL (1.0 / 8.0)
+#define FXAA_SPAN_MAX 8.0
+
+layout(set = 0, binding = 0) uniform texture2D inputTexture;
+layout(set = 0, binding = 2) uniform sampler inputSampler;
+
+layout(push_constant) uniform PostParams {
+    vec2 texelSize;
+    vec2 direction;
+    vec4 params;
+} post;
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+// Perceptual luma, the input is tonemapped but still linear so sqrt approximates the sRGB curve
+float luma(vec3 color) {
+    return dot(sqrt(max(color, vec3(0.0))), vec3(0.299, 0.587, 0.114));
+}
+
+vec3 fetch(vec2 uv) {
+    return texture(sampler2D(inputTexture, inputSampler), uv).rgb;
+}
+
+// Lottes' FXAA, the console variant: finds the local edge direction from the luma of the diagonal
+// neighbours and blurs along it
+void main() {
+    vec2 texel = post.texelSize;
+    float lumaNW = luma(fetch(fragUV + vec2(-1.0, -1.0) * texel));
+    float lumaNE = luma(fetch(fragUV + vec2(1.0, -1.0) * texel));
+    float lumaSW = luma(fetch(fragUV + vec2(-1.0, 1.0) * texel));
+    float lumaSE = luma(fetch(fragUV + vec2(1.0, 1.0) * texel));
+    vec3 colorM = fetch(fragUV);
+    float lumaM = luma(colorM);
+    float lumaMin = min(lumaM, min(min(lumaNW, lumaNE), min(lumaSW, lumaSE)));
+    float lumaMax = max(lumaM, max(max(lumaNW, lumaNE), max(lumaSW, lumaSE)));
+
+    vec2 dir = vec2(-((lumaNW + lumaNE) - (lumaSW + lumaSE)), (lumaNW + lumaSW) - (lumaNE + lumaSE));
+    float dirReduce = max((lumaNW + lumaNE + lumaSW + lumaSE) * 0.25 * FXAA_REDUCE_MUL, FXAA_REDUCE_MIN);
+    float rcpDirMin = 1.0 / (min(abs(dir.x), abs(dir.y)) + dirReduce);
+    dir = clamp(dir * rcpDirMin, vec2(-FXAA_SPAN_MAX), vec2(FXAA_SPAN_MAX)) * texel;
+
+    vec3 colorA = 0.5 * (fetch(fragUV + dir * (1.0 / 3.0 - 0.5)) + fetch(fragUV + dir * (2.0 / 3.0 - 0.5)));
+    vec3 colorB = colorA * 0.5 + 0.25 * (fetch(fragUV + dir * -0.5) + fetch(fragUV + dir * 0.5));
+    float lumaB = luma(colorB);
+
+    // The wider blur overshot into a different surface, fall back to the narrow one
+    outColor = vec4((lumaB < lumaMin || lumaB > lumaMax) ? colorA : colorB, 1.0);
+}
@@ -0,0 +1,122 @@
+#version 460
+
+#define MAX_LIGHTS 16
+#define MAX_SHADOW_CASTERS 4
+#define MAX_TEXTURES 4096
+#define MAX_DECALS 64
+
+struct Light {
+    vec4 position; // W is 0 for directional lights, where xyz is the direction towards the light
+    vec4 color; // Premultiplied by intensity, W is the range of point lights
+    vec4 shadow; // X is the shadow map layer, negative without one
+};
+
+struct Decal {
+    mat4 worldToDecal; // Into the unit cube the decal covers
+    vec4 color; // Multiplies the texture, A includes the fade
+    vec4 params; // X is the texture slot, Y 1 for box projection onto every side
+};
+
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 view;
+    mat4 proj;
+    vec4 cameraPos;
+    vec4 ambient;
+    vec4 ambientGround;
+    uvec4 lightCount;
+    Light lights[MAX_LIGHTS];
+    mat4 shadowViewProj[MAX_SHADOW_CASTERS];
+    vec4 shadowParams; // X is the texel size, Y the normal offset
+    vec4 fogColor; // W is the falloff, 0 without fog, 1 linear, 2 exponential, 3 exponential squared
+    vec4 fogParams; // X and Y are the linear start and end, Z the density, W the far plane
+    uvec4 decalCount; // Only X is used
+    Decal decals[MAX_DECALS]; // The nearest ones in view
+} ubo;
+
+// Shared with the vertex shader, which only reads the model matrix
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    vec4 baseColor;
+    vec3 emissive;
+    float metallic;
+    float roughness;
+    float normalScale;
+    float occlusionStrength;
+    uint baseColorTexture; // Slots in textures
+    uint normalTexture;
+    uint metallicRoughnessTexture;
+    uint occlusionTexture;
+    uint emissiveTexture;
+} material;
+layout(set = 1, binding = 0) uniform sampler materialSampler;
+layout(set = 1, binding = 1) uniform texture2D textures[MAX_TEXTURES]; // Every texture the renderer owns
+
+layout(location = 0) in vec3 fragColor;
+layout(location = 1) in vec2 fragUV;
+layout(location = 2) in vec3 fragWorldPos;
+layout(location = 3) in vec3 fragNormal;
+layout(location = 4) in vec4 fragTangent;
+
+// Surface attributes for deferred_light.frag, every material goes through the metallic-roughness model
+layout(location = 0) out vec4 outAlbedo; // Base color, occlusion in A
+layout(location = 1) out vec4 outNormal; // World space shading normal, roughness in W
+layout(location = 2) out vec4 outMaterial; // Emissive, metallic in A
+layout(location = 3) out vec4 outDepth; // Depth buffer value in R, for reconstructing the position
+
+// The two axes of decal space a decal's texture lies along, projecting along axis
+vec2 decalPlane(vec3 v, int axis) {
+    return axis == 0 ? v.zy : (axis == 1 ? v.xz : v.xy);
+}
+
+// Blends the decals covering a surface over its base color
+vec3 applyDecals(vec3 baseColor, vec3 worldPos, vec3 normal) {
+    vec3 dx = dFdx(worldPos); // Taken before the loop, derivatives are undefined where it diverges
+    vec3 dy = dFdy(worldPos);
+    for (uint i = 0u; i < min(ubo.decalCount.x, uint(MAX_DECALS)); i++) {
+        Decal decal = ubo.decals[i];
+        vec3 local = (decal.worldToDecal * vec4(worldPos, 1.0)).xyz;
+        if (any(greaterThan(abs(local), vec3(0.5)))) {
+            continue;
+        }
+        mat3 toDecal = mat3(decal.worldToDecal);
+        mat3 rows = transpose(toDecal); // The decal's axes, scaled by the inverse of its size along them
+        vec3 localNormal = vec3(dot(rows[0], normal) / length(rows[0]), dot(rows[1], normal) / length(rows[1]),
+                                dot(rows[2], normal) / length(rows[2])); // Cosines between the normal and the axes
+        int axis = 2;
+        float facing = smoothstep(0.2, 0.5, localNormal.z); // Surfaces turned away from the projection fade out
+        if (decal.params.y > 0.5) {
+            vec3 a = abs(localNormal);
+            axis = a.x > a.y && a.x > a.z ? 0 : (a.y > a.z ? 1 : 2);
+            facing = 1.0;
+        }
+        vec2 flip = vec2(1.0, -1.0); // The texture's top is +Y
+        vec2 uv = decalPlane(local, axis) * flip + 0.5;
+        vec4 texel = textureGrad(sampler2D(textures[uint(decal.params.x)], materialSampler), uv,
+                                 decalPlane(toDecal * dx, axis) * flip, decalPlane(toDecal * dy, axis) * flip) * decal.color;
+        baseColor = mix(baseColor, texel.rgb, texel.a * facing);
+    }
+    return baseColor;
+}
+
+void main() {
+    vec4 albedo = vec4(fragColor, 1.0) * material.baseColor * texture(sampler2D(textures[material.baseColorTexture], materialSampler), fragUV);
+    albedo.rgb = applyDecals(albedo.rgb, fragWorldPos, normalize(fragNormal));
+    vec4 metallicRoughness = texture(sampler2D(textures[material.metallicRoughnessTexture], materialSampler), fragUV);
+    float metallic = clamp(material.metallic * metallicRoughness.b, 0.0, 1.0);
+    float roughness = clamp(material.roughness * metallicRoughness.g, 0.04, 1.0); // Fully smooth surfaces alias
+
+    vec3 tangentNormal = texture(sampler2D(textures[material.normalTexture], materialSampler), fragUV).xyz * 2.0 - 1.0;
+    tangentNormal.xy *= material.normalScale;
+    vec3 normal = normalize(fragNormal);
+    vec3 tangent = normalize(fragTangent.xyz - normal * dot(normal, fragTangent.xyz)); // Gram-Schmidt
+    vec3 bitangent = cross(normal, tangent) * fragTangent.w;
+    vec3 shadingNormal = normalize(mat3(tangent, bitangent, normal) * tangentNormal);
+
+    float occlusion = mix(1.0, texture(sampler2D(textures[material.occlusionTexture], materialSampler), fragUV).r, material.occlusionStrength);
+    vec3 emissive = material.emissive * texture(sampler2D(textures[material.emissiveTexture], materialSampler), fragUV).rgb;
+
+    outAlbedo = vec4(albedo.rgb, occlusion);
+    outNormal = vec4(shadingNormal, roughness);
+    outMaterial = vec4(emissive, metallic);
+    outDepth = vec4(gl_FragCoord.z, 0.0, 0.0, 0.0);
+}
@@ -0,0 +1,28 @@
+#version 460
+
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 view;
+    mat4 proj;
+} ubo; // Only the start of the block
+
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    uint id; // 0 for instanced draws, which take each instance's
+} push;
+
+layout(location = 0) in vec3 inPosition;
+
+// Per instance, binding 1
+layout(location = 5) in vec4 inInstanceModel0;
+layout(location = 6) in vec4 inInstanceModel1;
+layout(location = 7) in vec4 inInstanceModel2;
+layout(location = 8) in vec4 inInstanceModel3;
+layout(location = 10) in uint inInstanceId;
+
+layout(location = 0) flat out uint fragId;
+
+void main() {
+    mat4 instanceModel = mat4(inInstanceModel0, inInstanceModel1, inInstanceModel2, inInstanceModel3);
+    gl_Position = ubo.proj * ubo.view * push.model * instanceModel * vec4(inPosition, 1.0);
+    fragId = push.id != 0u ? push.id : inInstanceId; // The identity instance's is 0
+}
@@ -0,0 +1,13 @@
+#version 460
+
+layout(set = 0, binding = 0) uniform texture2D overlayTexture;
+layout(set = 0, binding = 1) uniform sampler overlaySampler;
+
+layout(location = 0) in vec2 fragUV;
+layout(location = 1) in vec4 fragColor;
+
+layout(location = 0) out vec4 outColor;
+
+void main() {
+    outColor = fragColor * texture(sampler2D(overlayTexture, overlaySampler), fragUV);
+}
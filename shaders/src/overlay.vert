@@ -0,0 +1,18 @@
+#version 460
+
+layout(push_constant) uniform PushConstants {
+    vec2 screenSize; // In pixels
+} push;
+
+layout(location = 0) in vec2 inPosition; // Pixels from the top left
+layout(location = 1) in vec2 inUV;
+layout(location = 2) in vec4 inColor;
+
+layout(location = 0) out vec2 fragUV;
+layout(location = 1) out vec4 fragColor;
+
+void main() {
+    gl_Position = vec4(inPosition / push.screenSize * 2.0 - 1.0, 0.0, 1.0); // Vulkan's Y already points down
+    fragUV = inUV;
+    fragColor = inColor;
+}
@@ -0,0 +1,234 @@
+#version 460
+
+#define MAX_LIGHTS 16
+#define MAX_SHADOW_CASTERS 4
+#define MAX_TEXTURES 4096
+#define MAX_DECALS 64
+#define PI 3.14159265359
+
+struct Light {
+    vec4 position; // W is 0 for directional lights, where xyz is the direction towards the light
+    vec4 color; // Premultiplied by intensity, W is the range of point lights
+    vec4 shadow; // X is the shadow map layer, negative without one
+};
+
+struct Decal {
+    mat4 worldToDecal; // Into the unit cube the decal covers
+    vec4 color; // Multiplies the texture, A includes the fade
+    vec4 params; // X is the texture slot, Y 1 for box projection onto every side
+};
+
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 view;
+    mat4 proj;
+    vec4 cameraPos;
+    vec4 ambient; // Sky
+    vec4 ambientGround;
+    uvec4 lightCount;
+    Light lights[MAX_LIGHTS];
+    mat4 shadowViewProj[MAX_SHADOW_CASTERS];
+    vec4 shadowParams; // X is the texel size, Y the normal offset
+    vec4 fogColor; // W is the falloff, 0 without fog, 1 linear, 2 exponential, 3 exponential squared
+    vec4 fogParams; // X and Y are the linear start and end, Z the density, W the far plane
+    uvec4 decalCount; // Only X is used
+    Decal decals[MAX_DECALS]; // The nearest ones in view
+} ubo;
+
+// Shared with the vertex shader, which only reads the model matrix
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    vec4 baseColor;
+    vec3 emissive;
+    float metallic;
+    float roughness;
+    float normalScale;
+    float occlusionStrength;
+    uint baseColorTexture; // Slots in textures
+    uint normalTexture;
+    uint metallicRoughnessTexture;
+    uint occlusionTexture;
+    uint emissiveTexture;
+} material;
+layout(set = 1, binding = 0) uniform sampler materialSampler;
+layout(set = 1, binding = 1) uniform texture2D textures[MAX_TEXTURES]; // Every texture the renderer owns
+
+layout(set = 2, binding = 0) uniform texture2DArray shadowMaps;
+layout(set = 2, binding = 1) uniform samplerShadow shadowSampler;
+
+layout(location = 0) in vec3 fragColor;
+layout(location = 1) in vec2 fragUV;
+layout(location = 2) in vec3 fragWorldPos;
+layout(location = 3) in vec3 fragNormal;
+layout(location = 4) in vec4 fragTangent;
+
+layout(location = 0) out vec4 outColor;
+
+// Fraction of a directional light reaching the fragment, 3x3 PCF over the light's shadow map
+float shadowFactor(Light light, vec3 normal) {
+    if (light.shadow.x < 0.0) {
+        return 1.0;
+    }
+    vec3 offsetPos = fragWorldPos + normal * ubo.shadowParams.y; // Normal offset against acne on steep surfaces
+    vec4 lightPos = ubo.shadowViewProj[int(light.shadow.x)] * vec4(offsetPos, 1.0);
+    vec3 coords = lightPos.xyz / lightPos.w;
+    if (coords.z > 1.0) {
+        return 1.0; // Past the shadow distance
+    }
+    vec2 uv = coords.xy * 0.5 + 0.5;
+
+    float lit = 0.0;
+    for (int x = -1; x <= 1; x++) {
+        for (int y = -1; y <= 1; y++) {
+            vec2 offset = vec2(float(x), float(y)) * ubo.shadowParams.x;
+            lit += texture(sampler2DArrayShadow(shadowMaps, shadowSampler), vec4(uv + offset, light.shadow.x, coords.z));
+        }
+    }
+    return lit / 9.0;
+}
+
+// Trowbridge-Reitz GGX normal distribution
+float distributionGGX(float nDotH, float alpha) {
+    float a2 = alpha * alpha;
+    float d = nDotH * nDotH * (a2 - 1.0) + 1.0;
+    return a2 / (PI * d * d);
+}
+
+// Smith height correlated visibility, includes the 1 / (4 nDotL nDotV) of the specular term
+float visibilitySmithGGX(float nDotL, float nDotV, float alpha) {
+    float a2 = alpha * alpha;
+    float ggxV = nDotL * sqrt(nDotV * nDotV * (1.0 - a2) + a2);
+    float ggxL = nDotV * sqrt(nDotL * nDotL * (1.0 - a2) + a2);
+    return 0.5 / max(ggxV + ggxL, 1e-5);
+}
+
+vec3 fresnelSchlick(float cosTheta, vec3 f0) {
+    return f0 + (1.0 - f0) * pow(1.0 - cosTheta, 5.0);
+}
+
+// Analytic fit of the split sum environment BRDF (Karis, "Physically Based Shading on Mobile")
+vec3 envBRDFApprox(vec3 f0, float roughness, float nDotV) {
+    vec4 c0 = vec4(-1.0, -0.0275, -0.572, 0.022);
+    vec4 c1 = vec4(1.0, 0.0425, 1.04, -0.04);
+    vec4 r = roughness * c0 + c1;
+    float a004 = min(r.x * r.x, exp2(-9.28 * nDotV)) * r.x + r.y;
+    vec2 ab = vec2(-1.04, 1.04) * a004 + r.zw;
+    return f0 * ab.x + ab.y;
+}
+
+// Radiance from the ambient hemisphere in a direction. Stands in for an environment map, so rough
+// reflections blur towards the average of sky and ground.
+vec3 hemisphere(vec3 dir, float roughness) {
+    float t = dir.y * 0.5 + 0.5;
+    vec3 sharp = mix(ubo.ambientGround.rgb, ubo.ambient.rgb, t);
+    vec3 average = (ubo.ambientGround.rgb + ubo.ambient.rgb) * 0.5;
+    return mix(sharp, average, roughness);
+}
+
+// 0 to 1, how much of a surface at a distance from the camera the fog hides. Always opaque at the far plane.
+float fogAmount(float distance) {
+    uint falloff = uint(ubo.fogColor.w);
+    float fog = 0.0;
+    if (falloff == 1u) {
+        fog = clamp((distance - ubo.fogParams.x) / max(ubo.fogParams.y - ubo.fogParams.x, 1e-4), 0.0, 1.0);
+    } else if (falloff == 2u) {
+        fog = 1.0 - exp(-ubo.fogParams.z * distance);
+    } else if (falloff == 3u) {
+        float d = ubo.fogParams.z * distance;
+        fog = 1.0 - exp(-d * d);
+    }
+    return falloff == 0u ? 0.0 : max(fog, smoothstep(ubo.fogParams.w * 0.9, ubo.fogParams.w, distance));
+}
+
+// The two axes of decal space a decal's texture lies along, projecting along axis
+vec2 decalPlane(vec3 v, int axis) {
+    return axis == 0 ? v.zy : (axis == 1 ? v.xz : v.xy);
+}
+
+// Blends the decals covering a surface over its base color
+vec3 applyDecals(vec3 baseColor, vec3 worldPos, vec3 normal) {
+    vec3 dx = dFdx(worldPos); // Taken before the loop, derivatives are undefined where it diverges
+    vec3 dy = dFdy(worldPos);
+    for (uint i = 0u; i < min(ubo.decalCount.x, uint(MAX_DECALS)); i++) {
+        Decal decal = ubo.decals[i];
+        vec3 local = (decal.worldToDecal * vec4(worldPos, 1.0)).xyz;
+        if (any(greaterThan(abs(local), vec3(0.5)))) {
+            continue;
+        }
+        mat3 toDecal = mat3(decal.worldToDecal);
+        mat3 rows = transpose(toDecal); // The decal's axes, scaled by the inverse of its size along them
+        vec3 localNormal = vec3(dot(rows[0], normal) / length(rows[0]), dot(rows[1], normal) / length(rows[1]),
+                                dot(rows[2], normal) / length(rows[2])); // Cosines between the normal and the axes
+        int axis = 2;
+        float facing = smoothstep(0.2, 0.5, localNormal.z); // Surfaces turned away from the projection fade out
+        if (decal.params.y > 0.5) {
+            vec3 a = abs(localNormal);
+            axis = a.x > a.y && a.x > a.z ? 0 : (a.y > a.z ? 1 : 2);
+            facing = 1.0;
+        }
+        vec2 flip = vec2(1.0, -1.0); // The texture's top is +Y
+        vec2 uv = decalPlane(local, axis) * flip + 0.5;
+        vec4 texel = textureGrad(sampler2D(textures[uint(decal.params.x)], materialSampler), uv,
+                                 decalPlane(toDecal * dx, axis) * flip, decalPlane(toDecal * dy, axis) * flip) * decal.color;
+        baseColor = mix(baseColor, texel.rgb, texel.a * facing);
+    }
+    return baseColor;
+}
+
+void main() {
+    vec4 albedo = vec4(fragColor, 1.0) * material.baseColor * texture(sampler2D(textures[material.baseColorTexture], materialSampler), fragUV);
+    albedo.rgb = applyDecals(albedo.rgb, fragWorldPos, normalize(fragNormal));
+    vec4 metallicRoughness = texture(sampler2D(textures[material.metallicRoughnessTexture], materialSampler), fragUV);
+    float metallic = clamp(material.metallic * metallicRoughness.b, 0.0, 1.0);
+    float roughness = clamp(material.roughness * metallicRoughness.g, 0.04, 1.0); // Fully smooth surfaces alias
+    float alpha = roughness * roughness;
+
+    vec3 tangentNormal = texture(sampler2D(textures[material.normalTexture], materialSampler), fragUV).xyz * 2.0 - 1.0;
+    tangentNormal.xy *= material.normalScale;
+    vec3 normal = normalize(fragNormal);
+    vec3 tangent = normalize(fragTangent.xyz - normal * dot(normal, fragTangent.xyz)); // Gram-Schmidt
+    vec3 bitangent = cross(normal, tangent) * fragTangent.w;
+    normal = normalize(mat3(tangent, bitangent, normal) * tangentNormal);
+
+    vec3 toCamera = normalize(ubo.cameraPos.xyz - fragWorldPos);
+    float nDotV = max(dot(normal, toCamera), 1e-4);
+    vec3 f0 = mix(vec3(0.04), albedo.rgb, metallic); // Dielectrics reflect about 4% head on
+    vec3 diffuseColor = albedo.rgb * (1.0 - metallic);
+
+    vec3 lit = vec3(0.0);
+    for (uint i = 0u; i < min(ubo.lightCount.x, uint(MAX_LIGHTS)); i++) {
+        Light light = ubo.lights[i];
+        vec3 toLight;
+        float attenuation;
+        if (light.position.w == 0.0) {
+            toLight = light.position.xyz;
+            attenuation = shadowFactor(light, normalize(fragNormal)); // The normal map doesn't move the geometry
+        } else {
+            vec3 offset = light.position.xyz - fragWorldPos;
+            float distance = length(offset);
+            toLight = offset / distance;
+            float falloff = clamp(1.0 - pow(distance / light.color.w, 4.0), 0.0, 1.0); // Reaches 0 at the range
+            attenuation = falloff * falloff / (distance * distance + 1.0);
+        }
+
+        float nDotL = max(dot(normal, toLight), 0.0);
+        if (nDotL > 0.0) {
+            vec3 halfway = normalize(toLight + toCamera);
+            float nDotH = max(dot(normal, halfway), 0.0);
+            vec3 fresnel = fresnelSchlick(max(dot(halfway, toCamera), 0.0), f0);
+            vec3 specular = fresnel * distributionGGX(nDotH, alpha) * visibilitySmithGGX(nDotL, nDotV, alpha);
+            vec3 diffuse = (1.0 - fresnel) * diffuseColor / PI;
+            lit += (diffuse + specular) * light.color.rgb * attenuation * nDotL;
+        }
+    }
+
+    // Image based ambient term, with the hemisphere standing in for the environment
+    float occlusion = mix(1.0, texture(sampler2D(textures[material.occlusionTexture], materialSampler), fragUV).r, material.occlusionStrength);
+    vec3 ambientDiffuse = hemisphere(normal, 1.0) * diffuseColor;
+    vec3 ambientSpecular = hemisphere(reflect(-toCamera, normal), roughness) * envBRDFApprox(f0, roughness, nDotV);
+    lit += (ambientDiffuse + ambientSpecular) * occlusion;
+
+    lit += material.emissive * texture(sampler2D(textures[material.emissiveTexture], materialSampler), fragUV).rgb;
+
+    lit = mix(lit, ubo.fogColor.rgb, fogAmount(distance(ubo.cameraPos.xyz, fragWorldPos)));
+    outColor = vec4(lit, albedo.a);
+}
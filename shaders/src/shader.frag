@@ -1,9 +1,31 @@
 #version 460
 
+#define MAX_TEXTURES 4096
+
+// Shared with the vertex shader, which only reads the model matrix
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    vec4 baseColor;
+    vec3 emissive;
+    float metallic;
+    float roughness;
+    float normalScale;
+    float occlusionStrength;
+    uint baseColorTexture; // Slots in textures
+    uint normalTexture;
+    uint metallicRoughnessTexture;
+    uint occlusionTexture;
+    uint emissiveTexture;
+} material;
+layout(set = 1, binding = 0) uniform sampler materialSampler;
+layout(set = 1, binding = 1) uniform texture2D textures[MAX_TEXTURES]; // Every texture the renderer owns
+
 layout(location = 0) in vec3 fragColor;
+layout(location = 1) in vec2 fragUV;
 
 layout(location = 0) out vec4 outColor;
 
 void main() {
-    outColor = vec4(fragColor, 1.0);
+    vec4 texel = texture(sampler2D(textures[material.baseColorTexture], materialSampler), fragUV);
+    outColor = vec4(fragColor, 1.0) * material.baseColor * texel;
 }
\ No newline at end of file
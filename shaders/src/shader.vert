@@ -1,11 +1,74 @@
 #version 460
 
-layout(location = 0) in vec2 inPosition;
-layout(location = 1) in vec3 inColor;
+#define MAX_MORPH_TARGETS 8
+
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 view;
+    mat4 proj;
+} ubo; // Only the start of the block, the lights after it are read by the lit fragment shader
+
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+} push;
+
+struct MorphDelta {
+    vec4 position;
+    vec4 normal;
+    vec4 tangent;
+};
+
+// Every target's offsets, target major. Meshes without targets bind a set with a target count of 0.
+layout(set = 3, binding = 0) readonly buffer MorphDeltas {
+    MorphDelta deltas[];
+} morph;
+layout(set = 3, binding = 1) uniform MorphWeights {
+    uvec4 counts; // X is the target count, Y the vertex count
+    vec4 weights[MAX_MORPH_TARGETS / 4];
+} morphWeights;
+
+layout(location = 0) in vec3 inPosition;
+layout(location = 1) in vec3 inNormal;
+layout(location = 2) in vec2 inUV;
+layout(location = 3) in vec4 inTangent;
+layout(location = 4) in vec3 inColor;
+
+// Per instance, binding 1
+layout(location = 5) in vec4 inInstanceModel0; // Columns of the instance transform
+layout(location = 6) in vec4 inInstanceModel1;
+layout(location = 7) in vec4 inInstanceModel2;
+layout(location = 8) in vec4 inInstanceModel3;
+layout(location = 9) in vec4 inInstanceColor;
+layout(location = 10) in uint inInstanceId;
+
+layout(location = 11) in uint inLayer; // Per vertex again, after the instance attributes
 
 layout(location = 0) out vec3 fragColor;
+layout(location = 1) out vec2 fragUV;
+layout(location = 2) out vec3 fragWorldPos;
+layout(location = 3) out vec3 fragNormal;
+layout(location = 4) out vec4 fragTangent; // W is the bitangent sign
+layout(location = 5) flat out uint fragLayer; // Texture array layer
 
 void main() {
-    gl_Position = vec4(inPosition, 0.0, 1.0);
-    fragColor = inColor;
-}
\ No newline at end of file
+    vec3 position = inPosition;
+    vec3 normal = inNormal;
+    vec3 tangent = inTangent.xyz;
+    for (uint i = 0u; i < morphWeights.counts.x; i++) {
+        float weight = morphWeights.weights[i / 4u][i % 4u];
+        MorphDelta delta = morph.deltas[i * morphWeights.counts.y + uint(gl_VertexIndex)];
+        position += delta.position.xyz * weight;
+        normal += delta.normal.xyz * weight;
+        tangent += delta.tangent.xyz * weight;
+    }
+
+    mat4 instanceModel = mat4(inInstanceModel0, inInstanceModel1, inInstanceModel2, inInstanceModel3);
+    mat4 model = push.model * instanceModel;
+    vec4 worldPos = model * vec4(position, 1.0);
+    gl_Position = ubo.proj * ubo.view * worldPos;
+    fragWorldPos = worldPos.xyz;
+    fragNormal = mat3(model) * normal; // Only correct for uniform scales, renormalized per fragment
+    fragTangent = vec4(mat3(model) * tangent, inTangent.w);
+    fragColor = inColor * inInstanceColor.rgb;
+    fragUV = inUV;
+    fragLayer = inLayer;
+}
@@ -0,0 +1,39 @@
+#version 460
+
+#define MAX_LIGHTS 16
+#define MAX_SHADOW_CASTERS 4
+
+struct Light {
+    vec4 position;
+    vec4 color;
+    vec4 shadow;
+};
+
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 view;
+    mat4 proj;
+    vec4 cameraPos;
+    vec4 ambient;
+    vec4 ambientGround;
+    uvec4 lightCount;
+    Light lights[MAX_LIGHTS];
+    mat4 shadowViewProj[MAX_SHADOW_CASTERS];
+} ubo; // Only up to the light matrices
+
+layout(push_constant) uniform PushConstants {
+    mat4 model;
+    uint layer; // Shadow map being rendered
+} push;
+
+layout(location = 0) in vec3 inPosition;
+
+// Per instance, binding 1
+layout(location = 5) in vec4 inInstanceModel0;
+layout(location = 6) in vec4 inInstanceModel1;
+layout(location = 7) in vec4 inInstanceModel2;
+layout(location = 8) in vec4 inInstanceModel3;
+
+void main() {
+    mat4 instanceModel = mat4(inInstanceModel0, inInstanceModel1, inInstanceModel2, inInstanceModel3);
+    gl_Position = ubo.shadowViewProj[push.layer] * push.model * instanceModel * vec4(inPosition, 1.0);
+}
@@ -0,0 +1,81 @@
+#version 460
+
+#define PI 3.14159265
+
+// Single scattering through an atmosphere of uniform density, after Preetham et al. The path length through
+// the air grows towards the horizon, which reddens the sun and the sky around it.
+const vec3 RAYLEIGH = vec3(5.8e-6, 13.5e-6, 33.1e-6); // Scattering coefficients per meter, blue scatters most
+const float MIE = 21e-6; // Haze and dust, the same for every wavelength
+const float RAYLEIGH_HEIGHT = 8.4e3; // Meters of air the light crosses looking straight up
+const float MIE_HEIGHT = 1.25e3;
+const float MIE_G = 0.76; // How much haze scatters forwards, the glow around the sun
+const float SUN_RADIUS = 0.02; // Radians, larger than the real sun so it reads at low resolutions
+const float MOON_RADIUS = 0.015;
+
+layout(push_constant) uniform PushConstants {
+    mat4 invViewProj;
+    vec4 sunDirection; // Towards the sun, W is the intensity
+    vec4 moonDirection;
+    vec4 params; // X is the haze, Y the star brightness
+    vec4 fog; // W is how much it covers the horizon
+} pc;
+
+layout(location = 0) in vec2 fragNdc;
+
+layout(location = 0) out vec4 outColor;
+
+// Length of the path through the air relative to straight up (Kasten and Young), finite at the horizon
+float airMass(float cosZenith) {
+    float zenith = acos(clamp(cosZenith, 0.0, 1.0));
+    return 1.0 / (cos(zenith) + 0.15 * pow(93.885 - degrees(zenith), -1.253));
+}
+
+vec3 extinction(float cosZenith, float mie) {
+    return exp(-(RAYLEIGH * RAYLEIGH_HEIGHT + vec3(mie * MIE_HEIGHT)) * airMass(cosZenith));
+}
+
+// Light from one body scattered towards the viewer along dir
+vec3 scatter(vec3 dir, vec3 toLight, float intensity, float mie) {
+    float mu = dot(dir, toLight);
+    float rayleighPhase = 3.0 / (16.0 * PI) * (1.0 + mu * mu);
+    float g2 = MIE_G * MIE_G;
+    float miePhase = 3.0 / (8.0 * PI) * (1.0 - g2) * (1.0 + mu * mu) / ((2.0 + g2) * pow(1.0 + g2 - 2.0 * MIE_G * mu, 1.5));
+    vec3 scattered = (RAYLEIGH * rayleighPhase + vec3(mie * miePhase)) / (RAYLEIGH + vec3(mie));
+    vec3 inscatter = scattered * (1.0 - extinction(dir.y, mie));
+    // The light reaching the air is already dimmed by its own path, softened since the air is lit from above too
+    vec3 lightColor = sqrt(extinction(toLight.y, mie));
+    return intensity * inscatter * lightColor * smoothstep(-0.15, 0.05, toLight.y);
+}
+
+float disc(vec3 dir, vec3 center, float radius) {
+    return smoothstep(cos(radius), cos(radius * 0.8), dot(dir, center));
+}
+
+void main() {
+    vec4 nearPoint = pc.invViewProj * vec4(fragNdc, 0.0, 1.0);
+    vec4 farPoint = pc.invViewProj * vec4(fragNdc, 1.0, 1.0);
+    vec3 dir = normalize(farPoint.xyz / farPoint.w - nearPoint.xyz / nearPoint.w);
+    vec3 sun = normalize(pc.sunDirection.xyz);
+    vec3 moon = normalize(pc.moonDirection.xyz);
+    float mie = MIE * pc.params.x;
+
+    vec3 color = vec3(0.002, 0.003, 0.008); // Night sky
+    color += scatter(dir, sun, pc.sunDirection.w, mie);
+    color += scatter(dir, moon, pc.moonDirection.w, mie) * vec3(0.6, 0.7, 1.0);
+
+    vec3 seen = extinction(dir.y, mie); // What's left of the light behind the atmosphere
+    float aboveHorizon = smoothstep(-0.02, 0.02, dir.y);
+    color += disc(dir, sun, SUN_RADIUS) * pc.sunDirection.w * 5.0 * seen * aboveHorizon;
+    color += disc(dir, moon, MOON_RADIUS) * pc.moonDirection.w * vec3(0.9, 0.9, 0.85) * aboveHorizon;
+
+    // Hashed directions, fading towards the horizon where they'd be lost in the haze
+    vec3 cell = floor(dir * 250.0);
+    float star = step(0.9985, fract(sin(dot(cell, vec3(12.9898, 78.233, 37.719))) * 43758.5453));
+    color += star * pc.params.y * smoothstep(0.0, 0.3, dir.y) * seen;
+
+    // Below the horizon fades to a darker ground haze
+    color *= mix(0.3, 1.0, smoothstep(-0.2, 0.0, dir.y));
+    // Fogged terrain at the far plane meets the horizon in the fog color, so there's no seam between them
+    color = mix(color, pc.fog.rgb, pc.fog.w * (1.0 - smoothstep(0.0, 0.25, dir.y)));
+    outColor = vec4(color, 1.0);
+}
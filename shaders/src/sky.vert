@@ -0,0 +1,10 @@
+#version 460
+
+layout(location = 0) out vec2 fragNdc;
+
+// Oversized triangle covering the screen at the far plane, like fullscreen.vert
+void main() {
+    vec2 uv = vec2(float(gl_VertexIndex & 2), float((gl_VertexIndex << 1) & 2));
+    fragNdc = uv * 2.0 - 1.0;
+    gl_Position = vec4(fragNdc, 1.0, 1.0);
+}
@@ -0,0 +1,74 @@
+#version 460
+
+#define MAX_SAMPLES 32 // Matches SsaoQuality::High
+#define GOLDEN_ANGLE 2.39996323
+#define TAU 6.28318530718
+
+layout(binding = 0) uniform UniformBufferObject {
+    mat4 view;
+    mat4 proj; // The rest of the frame's uniforms are unused here
+} ubo;
+
+layout(set = 1, binding = 0) uniform texture2D gNormal;
+layout(set = 1, binding = 1) uniform texture2D gDepth;
+layout(set = 1, binding = 2) uniform sampler gSampler;
+
+layout(push_constant) uniform SsaoParams {
+    mat4 invViewProj;
+    vec4 params; // Radius in world units, depth bias, sample count, intensity
+    vec4 blur; // Texel size in XY, blur radius in texels in Z
+} ssao;
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outOcclusion;
+
+vec3 worldPosition(vec2 uv, float depth) {
+    vec4 pos = ssao.invViewProj * vec4(uv * 2.0 - 1.0, depth, 1.0);
+    return pos.xyz / pos.w;
+}
+
+float viewDepth(vec3 worldPos) {
+    return -(ubo.view * vec4(worldPos, 1.0)).z; // The camera looks down -Z
+}
+
+// Fraction of a hemisphere around each pixel's normal that isn't inside nearby geometry. Samples spiral
+// out from the pixel, rotated per pixel by interleaved gradient noise which the blur pass smooths over.
+void main() {
+    float depth = texture(sampler2D(gDepth, gSampler), fragUV).r;
+    if (depth >= 1.0) {
+        outOcclusion = vec4(1.0); // Nothing drawn here
+        return;
+    }
+    vec3 worldPos = worldPosition(fragUV, depth);
+    vec3 normal = normalize(texture(sampler2D(gNormal, gSampler), fragUV).xyz);
+    float pixelDepth = viewDepth(worldPos);
+
+    vec3 helper = abs(normal.y) < 0.99 ? vec3(0.0, 1.0, 0.0) : vec3(1.0, 0.0, 0.0);
+    vec3 tangent = normalize(cross(helper, normal));
+    vec3 bitangent = cross(normal, tangent);
+    float noise = fract(52.9829189 * fract(dot(gl_FragCoord.xy, vec2(0.06711056, 0.00583715))));
+
+    float radius = ssao.params.x;
+    int count = min(int(ssao.params.z), MAX_SAMPLES);
+    float occlusion = 0.0;
+    for (int i = 0; i < count; i++) {
+        float t = (float(i) + 0.5) / float(count);
+        float angle = float(i) * GOLDEN_ANGLE + noise * TAU;
+        float r = sqrt(t);
+        vec3 dir = tangent * cos(angle) * r + bitangent * sin(angle) * r + normal * sqrt(1.0 - t); // Cosine weighted
+        vec3 samplePos = worldPos + dir * radius * mix(0.1, 1.0, t * t); // Denser close to the pixel
+
+        vec4 clip = ubo.proj * ubo.view * vec4(samplePos, 1.0);
+        vec2 uv = clip.xy / clip.w * 0.5 + 0.5;
+        float sceneDepth = viewDepth(worldPosition(uv, texture(sampler2D(gDepth, gSampler), uv).r));
+        float sampleDepth = viewDepth(samplePos);
+
+        // Geometry far in front of the pixel, I.E. a silhouette, doesn't occlude it
+        float inRange = smoothstep(0.0, 1.0, radius / max(abs(pixelDepth - sceneDepth), 1e-4));
+        occlusion += (sceneDepth <= sampleDepth - ssao.params.y ? 1.0 : 0.0) * inRange;
+    }
+
+    float ao = 1.0 - occlusion / float(max(count, 1));
+    outOcclusion = vec4(pow(ao, ssao.params.w), 0.0, 0.0, 1.0);
+}
@@ -0,0 +1,32 @@
+#version 460
+
+layout(set = 1, binding = 0) uniform texture2D rawOcclusion;
+layout(set = 1, binding = 1) uniform texture2D gDepth;
+layout(set = 1, binding = 2) uniform sampler gSampler;
+
+layout(push_constant) uniform SsaoParams {
+    mat4 invViewProj;
+    vec4 params;
+    vec4 blur; // Texel size in XY, blur radius in texels in Z
+} ssao;
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outOcclusion;
+
+// Box blur over the noisy occlusion. Texels where nothing was drawn are left out so the sky doesn't
+// lighten the edges of geometry.
+void main() {
+    int radius = int(ssao.blur.z);
+    float sum = 0.0;
+    float weight = 0.0;
+    for (int x = -radius; x <= radius; x++) {
+        for (int y = -radius; y <= radius; y++) {
+            vec2 uv = fragUV + vec2(float(x), float(y)) * ssao.blur.xy;
+            float covered = texture(sampler2D(gDepth, gSampler), uv).r < 1.0 ? 1.0 : 0.0;
+            sum += texture(sampler2D(rawOcclusion, gSampler), uv).r * covered;
+            weight += covered;
+        }
+    }
+    outOcclusion = vec4(weight > 0.0 ? sum / weight : 1.0, 0.0, 0.0, 1.0);
+}
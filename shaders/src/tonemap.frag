@@ -0,0 +1,30 @@
+#version 460
+
+layout(set = 0, binding = 0) uniform texture2D inputTexture;
+layout(set = 0, binding = 2) uniform sampler inputSampler;
+
+layout(push_constant) uniform PostParams {
+    vec2 texelSize;
+    vec2 direction;
+    vec4 params; // X is the operator, 0 for ACES and 1 for Reinhard, Y the exposure
+} post;
+
+layout(location = 0) in vec2 fragUV;
+
+layout(location = 0) out vec4 outColor;
+
+// Narkowicz's fit of the ACES reference rendering transform
+vec3 aces(vec3 x) {
+    return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+vec3 reinhard(vec3 x) {
+    return x / (1.0 + x);
+}
+
+// Maps HDR scene values into 0 to 1, output is still linear
+void main() {
+    vec3 color = texture(sampler2D(inputTexture, inputSampler), fragUV).rgb * post.params.y;
+    color = post.params.x < 0.5 ? aces(color) : reinhard(color);
+    outColor = vec4(color, 1.0);
+}
@@ -0,0 +1,234 @@
+use std::ops::{Add, Mul};
+use std::time::Duration;
+
+use glam::{Quat, Vec3};
+
+use crate::assets::gltf::GltfScene;
+use crate::ecs::components::Transform;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Step, // Holds each keyframe until the next one
+    Linear, // Rotations are slerped
+    CubicSpline // Hermite spline, each keyframe stores an in tangent, the value and an out tangent
+}
+
+// The values of a channel, one per keyframe time, or three per time for CubicSpline
+#[derive(Clone, Debug)]
+pub enum Keyframes {
+    Translation(Vec<Vec3>),
+    Rotation(Vec<Quat>),
+    Scale(Vec<Vec3>)
+}
+
+// Animates one property of one node
+#[derive(Clone, Debug)]
+pub struct Channel {
+    pub node: usize, // Index into GltfScene::nodes
+    pub times: Vec<f32>, // Seconds, increasing
+    pub keyframes: Keyframes,
+    pub interpolation: Interpolation
+}
+
+#[derive(Clone, Debug)]
+pub struct AnimationClip {
+    pub name: Option<String>,
+    pub channels: Vec<Channel>,
+    pub duration: f32 // Seconds, the last keyframe of any channel
+}
+
+impl AnimationClip {
+    pub fn new(name: Option<String>, channels: Vec<Channel>) -> AnimationClip {
+        let duration = channels.iter()
+            .filter_map(|c| c.times.last().copied())
+            .fold(0.0, f32::max);
+
+        AnimationClip {
+            name,
+            channels,
+            duration
+        }
+    }
+
+    // Overwrites the animated properties of the nodes in pose, which is indexed like GltfScene::nodes.
+    // Times outside the clip hold the first or last keyframe.
+    pub fn apply(&self, time: f32, pose: &mut [Transform]) {
+        for channel in self.channels.iter() {
+            let transform = match pose.get_mut(channel.node) {
+                Some(t) => t,
+                None => continue
+            };
+            match &channel.keyframes {
+                Keyframes::Translation(values) => {
+                    if let Some(v) = sample(&channel.times, values, channel.interpolation, time, Vec3::lerp) {
+                        transform.translation = v;
+                    }
+                },
+                Keyframes::Rotation(values) => {
+                    if let Some(v) = sample(&channel.times, values, channel.interpolation, time, Quat::slerp) {
+                        transform.rotation = v.normalize(); // Splines leave the unit sphere between keyframes
+                    }
+                },
+                Keyframes::Scale(values) => {
+                    if let Some(v) = sample(&channel.times, values, channel.interpolation, time, Vec3::lerp) {
+                        transform.scale = v;
+                    }
+                }
+            }
+        }
+    }
+}
+
+// None if the channel has no keyframes or fewer values than it should
+fn sample<T>(times: &[f32], values: &[T], interpolation: Interpolation, time: f32, lerp: fn(T, T, f32) -> T) -> Option<T>
+    where T: Copy + Add<Output = T> + Mul<f32, Output = T> {
+    let stride = match interpolation {
+        Interpolation::CubicSpline => 3, // In tangent, value, out tangent
+        _ => 1
+    };
+    let value = |i: usize| values.get(i * stride + stride / 2).copied();
+    let last = times.len().checked_sub(1)?;
+    if values.len() < times.len() * stride {
+        return None;
+    }
+
+    // The keyframe at or before time
+    let i = times.partition_point(|&t| t <= time);
+    if i == 0 {
+        return value(0);
+    }
+    if i > last {
+        return value(last);
+    }
+    let (k, t0, t1) = (i - 1, times[i - 1], times[i]);
+    let dt = t1 - t0;
+    let s = match dt > 0.0 {
+        true => (time - t0) / dt,
+        false => 0.0
+    };
+
+    match interpolation {
+        Interpolation::Step => value(k),
+        Interpolation::Linear => Some(lerp(value(k)?, value(k + 1)?, s)),
+        Interpolation::CubicSpline => {
+            let (v0, v1) = (value(k)?, value(k + 1)?);
+            let out_tangent = values[k * 3 + 2] * dt;
+            let in_tangent = values[(k + 1) * 3] * dt;
+            let (s2, s3) = (s * s, s * s * s);
+            Some(v0 * (2.0 * s3 - 3.0 * s2 + 1.0) +
+                out_tangent * (s3 - 2.0 * s2 + s) +
+                v1 * (-2.0 * s3 + 3.0 * s2) +
+                in_tangent * (s3 - s2))
+        }
+    }
+}
+
+// Time every animation is measured against. Advance it once per frame (or tick), pausing or slowing it
+// down affects every Animator reading it.
+#[derive(Clone, Copy, Debug)]
+pub struct AnimationClock {
+    time: f32, // Seconds since the clock was created, scaled by speed
+    speed: f32,
+    paused: bool
+}
+
+impl AnimationClock {
+    pub fn new() -> AnimationClock {
+        AnimationClock {
+            time: 0.0,
+            speed: 1.0,
+            paused: false
+        }
+    }
+
+    pub fn advance(&mut self, delta: Duration) {
+        if !self.paused {
+            self.time += delta.as_secs_f32() * self.speed;
+        }
+    }
+
+    pub fn time(&self) -> f32 {
+        self.time
+    }
+
+    pub fn set_speed(&mut self, speed: f32) {
+        self.speed = speed.max(0.0);
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+}
+
+impl Default for AnimationClock {
+    fn default() -> Self {
+        AnimationClock::new()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Playing {
+    clip: usize,
+    start: f32, // Clock time the clip was started at
+    looping: bool
+}
+
+// The clips playing on one instance of a scene, I.E. a door or a platform. Clips are applied in the order
+// they were started, so a later clip wins where two animate the same property.
+#[derive(Clone, Debug, Default)]
+pub struct Animator {
+    playing: Vec<Playing>
+}
+
+impl Animator {
+    pub fn new() -> Animator {
+        Animator {
+            playing: Vec::new()
+        }
+    }
+
+    // Starts the clip (an index into GltfScene::animations) from the beginning, restarting it if it's
+    // already playing
+    pub fn play(&mut self, clock: &AnimationClock, clip: usize, looping: bool) {
+        self.stop(clip);
+        self.playing.push(Playing {
+            clip,
+            start: clock.time(),
+            looping
+        });
+    }
+
+    pub fn stop(&mut self, clip: usize) {
+        self.playing.retain(|p| p.clip != clip);
+    }
+
+    // Whether the clip is looping or hasn't reached its end yet. Finished clips keep holding their last
+    // keyframe until stopped.
+    pub fn is_playing(&self, scene: &GltfScene, clock: &AnimationClock, clip: usize) -> bool {
+        self.playing.iter()
+            .filter(|p| p.clip == clip)
+            .any(|p| p.looping || scene.animations.get(clip).map_or(false, |c| clock.time() - p.start < c.duration))
+    }
+
+    // Node transforms at the clock's current time, for GltfScene::queue_pose
+    pub fn pose(&self, scene: &GltfScene, clock: &AnimationClock) -> Vec<Transform> {
+        let mut pose = scene.rest_pose();
+        for playing in self.playing.iter() {
+            let clip = match scene.animations.get(playing.clip) {
+                Some(c) => c,
+                None => continue
+            };
+            let elapsed = clock.time() - playing.start;
+            let time = match playing.looping && clip.duration > 0.0 {
+                true => elapsed.rem_euclid(clip.duration),
+                false => elapsed
+            };
+            clip.apply(time, &mut pose);
+        }
+        pose
+    }
+}
@@ -0,0 +1,164 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::assets::{image, ktx2, obj, AssetError};
+use crate::renderer::mesh::MeshHandle;
+use crate::renderer::renderer::CubulousRenderer;
+use crate::renderer::texture::TextureHandle;
+
+struct FileWatcher {
+    watcher: RecommendedWatcher, // Stops watching when dropped
+    events: Receiver<notify::Result<notify::Event>>,
+    dirs: HashSet<PathBuf> // Editors save by replacing the file, so its directory is watched instead
+}
+
+// Loads each file once and hands out the same handles afterwards. Loaded files are watched, and update()
+// uploads them again behind the same handles when they change on disk, so edits show up without a
+// restart. Materials using a reloaded texture are rewritten by the renderer.
+pub struct AssetCache {
+    watcher: Option<FileWatcher>, // None when files can't be watched, everything still loads
+    textures: HashMap<PathBuf, (TextureHandle, bool)>, // Keyed by canonical path, with whether it's sRGB
+    meshes: HashMap<PathBuf, Vec<MeshHandle>> // OBJ files, one mesh per material group
+}
+
+impl AssetCache {
+    pub fn new() -> AssetCache {
+        let (tx, events) = channel();
+        let watcher = notify::recommended_watcher(move |res| {
+            let _ = tx.send(res); // The receiver only goes away with the watcher
+        });
+        let watcher = match watcher {
+            Ok(watcher) => Some(FileWatcher {
+                watcher,
+                events,
+                dirs: HashSet::new()
+            }),
+            Err(e) => {
+                log::warn!("Asset hot reload disabled: {}", e);
+                None
+            }
+        };
+
+        AssetCache {
+            watcher,
+            textures: HashMap::new(),
+            meshes: HashMap::new()
+        }
+    }
+
+    // A PNG, see CubulousRenderer::upload_texture for srgb, or a KTX2 file, which says itself whether it's
+    // sRGB. Later calls return the first handle, whatever their srgb.
+    pub fn texture(&mut self, renderer: &mut CubulousRenderer, path: &Path, srgb: bool) -> Result<TextureHandle, AssetError> {
+        let path = canonical(path)?;
+        if let Some((handle, _)) = self.textures.get(&path) {
+            return Ok(*handle);
+        }
+
+        let handle = match is_ktx2(&path) {
+            true => {
+                let image = ktx2::load(&path, renderer.compressed_formats()).map_err(AssetError::Load)?;
+                renderer.upload_compressed_texture(&image)?
+            },
+            false => {
+                let image = image::load(&path).map_err(AssetError::Load)?;
+                renderer.upload_texture(image.width, image.height, &image.pixels, srgb)?
+            }
+        };
+        self.watch(&path);
+        self.textures.insert(path, (handle, srgb));
+
+        Ok(handle)
+    }
+
+    pub fn obj(&mut self, renderer: &mut CubulousRenderer, path: &Path) -> Result<&[MeshHandle], AssetError> {
+        let path = canonical(path)?;
+        if !self.meshes.contains_key(&path) {
+            let handles = obj::load(&path).map_err(AssetError::Load)?.upload(renderer)?;
+            self.watch(&path);
+            self.meshes.insert(path.clone(), handles);
+        }
+
+        Ok(&self.meshes[&path])
+    }
+
+    fn watch(&mut self, path: &Path) {
+        let (watcher, dir) = match (self.watcher.as_mut(), path.parent()) {
+            (Some(w), Some(dir)) => (w, dir),
+            _ => return
+        };
+        if watcher.dirs.contains(dir) {
+            return;
+        }
+        match watcher.watcher.watch(dir, RecursiveMode::NonRecursive) {
+            Ok(()) => {
+                watcher.dirs.insert(dir.to_owned());
+            },
+            Err(e) => log::warn!("Can't watch {} for changes: {}", dir.display(), e)
+        }
+    }
+
+    // Reloads the files that changed since the last call. A file that fails to load, I.E. because it's
+    // still being written, keeps its previous contents until it changes again.
+    pub fn update(&mut self, renderer: &mut CubulousRenderer) {
+        let watcher = match self.watcher.as_ref() {
+            Some(w) => w,
+            None => return
+        };
+
+        let mut changed: HashSet<PathBuf> = HashSet::new();
+        for res in watcher.events.try_iter() {
+            match res {
+                Ok(event) if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) => changed.extend(event.paths),
+                Ok(_) => (),
+                Err(e) => log::warn!("Asset watcher error: {}", e)
+            }
+        }
+
+        for path in changed {
+            if let Err(e) = self.reload(renderer, &path) {
+                log::warn!("Failed to reload {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    fn reload(&self, renderer: &mut CubulousRenderer, path: &Path) -> Result<(), AssetError> {
+        if let Some((handle, srgb)) = self.textures.get(path) {
+            match is_ktx2(path) {
+                true => {
+                    let image = ktx2::load(path, renderer.compressed_formats()).map_err(AssetError::Load)?;
+                    renderer.replace_compressed_texture(*handle, &image)?;
+                },
+                false => {
+                    let image = image::load(path).map_err(AssetError::Load)?;
+                    renderer.replace_texture(*handle, image.width, image.height, &image.pixels, *srgb)?;
+                }
+            }
+            log::info!("Reloaded {}", path.display());
+        }
+        if let Some(handles) = self.meshes.get(path) {
+            let model = obj::load(path).map_err(AssetError::Load)?;
+            if model.meshes.len() != handles.len() {
+                return Err(AssetError::Load(format!("{} material groups instead of {}", model.meshes.len(), handles.len())));
+            }
+            for (mesh, handle) in model.meshes.iter().zip(handles.iter()) {
+                renderer.replace_mesh(*handle, &mesh.vertices(), &mesh.indices)?;
+            }
+            log::info!("Reloaded {}", path.display());
+        }
+
+        Ok(())
+    }
+}
+
+fn is_ktx2(path: &Path) -> bool {
+    path.extension().map_or(false, |e| e.eq_ignore_ascii_case("ktx2"))
+}
+
+// Events report paths under the watched directory, so files are keyed the same way
+fn canonical(path: &Path) -> Result<PathBuf, AssetError> {
+    fs::canonicalize(path).map_err(|e| AssetError::Load(format!("Failed to read {}: {}", path.display(), e)))
+}
@@ -0,0 +1,286 @@
+use std::path::Path;
+
+use glam::{Mat4, Quat, Vec3};
+
+use crate::assets::animation::{AnimationClip, Channel, Interpolation, Keyframes};
+use crate::assets::{ImageData, MaterialData, MeshData};
+use crate::ecs::components::Transform;
+use crate::profiling::profile_scope;
+use crate::renderer::error::RendererError;
+use crate::renderer::mesh::MeshHandle;
+use crate::renderer::morph::MorphTarget;
+use crate::renderer::render_queue::{MaterialHandle, RenderQueue};
+use crate::renderer::renderer::CubulousRenderer;
+
+pub struct GltfMesh {
+    pub name: Option<String>,
+    pub primitives: Vec<MeshData>, // One per glTF primitive, each with its own material
+    pub weights: Vec<f32> // Initial morph target weights, shared by the primitives
+}
+
+pub struct GltfNode {
+    pub name: Option<String>,
+    pub transform: Transform, // Relative to the parent node, animations replace parts of it
+    pub mesh: Option<usize>,
+    pub children: Vec<usize>
+}
+
+pub struct GltfScene {
+    pub meshes: Vec<GltfMesh>,
+    pub materials: Vec<MaterialData>,
+    pub images: Vec<ImageData>,
+    pub nodes: Vec<GltfNode>,
+    pub roots: Vec<usize>, // Top level nodes of the default scene
+    pub animations: Vec<AnimationClip> // Played through an Animator
+}
+
+// Renderer handles for every primitive of every mesh, indexed the same way as GltfScene::meshes
+pub struct GltfHandles {
+    pub meshes: Vec<Vec<MeshHandle>>
+}
+
+// Handles both .gltf (with external or embedded buffers) and binary .glb files
+pub fn load(path: &Path) -> Result<GltfScene, String> {
+    fn convert_image(image: &::gltf::image::Data) -> Result<ImageData, String> {
+        use ::gltf::image::Format;
+
+        let pixel_count = (image.width * image.height) as usize;
+        let pixels: Vec<u8> = match image.format {
+            Format::R8G8B8A8 => image.pixels.clone(),
+            Format::R8G8B8 => image.pixels
+                .chunks_exact(3)
+                .flat_map(|p| [p[0], p[1], p[2], 255])
+                .collect(),
+            Format::R8G8 => image.pixels
+                .chunks_exact(2)
+                .flat_map(|p| [p[0], p[1], 0, 255])
+                .collect(),
+            Format::R8 => image.pixels
+                .iter()
+                .flat_map(|&p| [p, p, p, 255])
+                .collect(),
+            f => return Err(format!("Unsupported glTF image format {:?}", f))
+        };
+
+        match pixels.len() == pixel_count * 4 {
+            true => Ok(ImageData {
+                width: image.width,
+                height: image.height,
+                pixels
+            }),
+            false => Err(String::from("Malformed glTF image data"))
+        }
+    }
+
+    fn convert_material(material: &::gltf::Material) -> MaterialData {
+        let pbr = material.pbr_metallic_roughness();
+        let texture_index = |info: Option<::gltf::texture::Info>| info.map(|t| t.texture().source().index());
+
+        MaterialData {
+            name: material.name().map(String::from),
+            base_color: pbr.base_color_factor(),
+            base_color_texture: texture_index(pbr.base_color_texture()),
+            metallic: pbr.metallic_factor(),
+            roughness: pbr.roughness_factor(),
+            metallic_roughness_texture: texture_index(pbr.metallic_roughness_texture()),
+            normal_texture: material.normal_texture().map(|t| t.texture().source().index()),
+            occlusion_texture: material.occlusion_texture().map(|t| t.texture().source().index()),
+            emissive: material.emissive_factor(),
+            emissive_texture: texture_index(material.emissive_texture()),
+            blended: material.alpha_mode() == ::gltf::material::AlphaMode::Blend
+        }
+    }
+
+    fn convert_primitive(primitive: &::gltf::Primitive, buffers: &[::gltf::buffer::Data]) -> Result<MeshData, String> {
+        if primitive.mode() != ::gltf::mesh::Mode::Triangles {
+            return Err(format!("Unsupported glTF primitive mode {:?}", primitive.mode()));
+        }
+
+        let reader = primitive.reader(|b| buffers.get(b.index()).map(|d| &d.0[..]));
+
+        let positions: Vec<[f32; 3]> = match reader.read_positions() {
+            Some(p) => p.collect(),
+            None => return Err(String::from("glTF primitive has no positions"))
+        };
+        let normals: Vec<[f32; 3]> = reader.read_normals().map(|n| n.collect()).unwrap_or_default();
+        let uvs: Vec<[f32; 2]> = reader.read_tex_coords(0).map(|t| t.into_f32().collect()).unwrap_or_default();
+        let tangents: Vec<[f32; 4]> = reader.read_tangents().map(|t| t.collect()).unwrap_or_default();
+        let colors: Vec<[f32; 4]> = reader.read_colors(0).map(|c| c.into_rgba_f32().collect()).unwrap_or_default();
+        let indices: Vec<u32> = match reader.read_indices() {
+            Some(i) => i.into_u32().collect(),
+            None => (0..positions.len() as u32).collect() // Non indexed geometry
+        };
+        let morph_targets: Vec<MorphTarget> = reader.read_morph_targets()
+            .map(|(p, n, t)| MorphTarget {
+                positions: p.map(|p| p.collect()).unwrap_or_else(|| vec![[0.0; 3]; positions.len()]),
+                normals: n.map(|n| n.collect()).unwrap_or_default(),
+                tangents: t.map(|t| t.collect()).unwrap_or_default()
+            })
+            .collect();
+
+        Ok(MeshData {
+            positions,
+            normals,
+            uvs,
+            tangents,
+            colors,
+            indices,
+            morph_targets,
+            material: primitive.material().index()
+        })
+    }
+
+    // Morph target weights aren't supported, those channels are dropped
+    fn convert_channel(channel: &::gltf::animation::Channel, buffers: &[::gltf::buffer::Data]) -> Result<Option<Channel>, String> {
+        use ::gltf::animation::util::ReadOutputs;
+
+        let reader = channel.reader(|b| buffers.get(b.index()).map(|d| &d.0[..]));
+        let times: Vec<f32> = match reader.read_inputs() {
+            Some(t) => t.collect(),
+            None => return Err(String::from("glTF animation channel has no keyframe times"))
+        };
+        let keyframes = match reader.read_outputs() {
+            Some(ReadOutputs::Translations(t)) => Keyframes::Translation(t.map(Vec3::from).collect()),
+            Some(ReadOutputs::Rotations(r)) => Keyframes::Rotation(r.into_f32().map(Quat::from_array).collect()),
+            Some(ReadOutputs::Scales(s)) => Keyframes::Scale(s.map(Vec3::from).collect()),
+            Some(ReadOutputs::MorphTargetWeights(_)) => return Ok(None),
+            None => return Err(String::from("glTF animation channel has no keyframe values"))
+        };
+        let interpolation = match channel.sampler().interpolation() {
+            ::gltf::animation::Interpolation::Step => Interpolation::Step,
+            ::gltf::animation::Interpolation::Linear => Interpolation::Linear,
+            ::gltf::animation::Interpolation::CubicSpline => Interpolation::CubicSpline
+        };
+
+        Ok(Some(Channel {
+            node: channel.target().node().index(),
+            times,
+            keyframes,
+            interpolation
+        }))
+    }
+
+    profile_scope!("load gltf", path.to_string_lossy());
+    let (document, buffers, images) = ::gltf::import(path)
+        .map_err(|e| format!("Failed to import {}: {}", path.display(), e))?;
+
+    let mut meshes: Vec<GltfMesh> = Vec::with_capacity(document.meshes().len());
+    for m in document.meshes() {
+        let primitives = m.primitives()
+            .map(|p| convert_primitive(&p, &buffers))
+            .collect::<Result<Vec<MeshData>, String>>()?;
+        meshes.push(GltfMesh {
+            name: m.name().map(String::from),
+            primitives,
+            weights: m.weights().map(|w| w.to_vec()).unwrap_or_default()
+        });
+    }
+
+    let materials: Vec<MaterialData> = document.materials().map(|m| convert_material(&m)).collect();
+
+    let images = images.iter()
+        .map(convert_image)
+        .collect::<Result<Vec<ImageData>, String>>()?;
+
+    let nodes: Vec<GltfNode> = document.nodes()
+        .map(|n| {
+            let (translation, rotation, scale) = n.transform().decomposed(); // Animated nodes are TRS by the spec
+            GltfNode {
+                name: n.name().map(String::from),
+                transform: Transform {
+                    translation: Vec3::from(translation),
+                    rotation: Quat::from_array(rotation),
+                    scale: Vec3::from(scale)
+                },
+                mesh: n.mesh().map(|m| m.index()),
+                children: n.children().map(|c| c.index()).collect()
+            }
+        })
+        .collect();
+
+    let mut animations: Vec<AnimationClip> = Vec::with_capacity(document.animations().len());
+    for a in document.animations() {
+        let mut channels = Vec::new();
+        for c in a.channels() {
+            channels.extend(convert_channel(&c, &buffers)?);
+        }
+        animations.push(AnimationClip::new(a.name().map(String::from), channels));
+    }
+
+    // Fall back to the first scene, and then to every node when the file has no scenes at all
+    let roots: Vec<usize> = match document.default_scene().or_else(|| document.scenes().next()) {
+        Some(s) => s.nodes().map(|n| n.index()).collect(),
+        None => (0..nodes.len()).collect()
+    };
+
+    Ok(GltfScene {
+        meshes,
+        materials,
+        images,
+        nodes,
+        roots,
+        animations
+    })
+}
+
+impl GltfScene {
+    pub fn upload(&self, renderer: &mut CubulousRenderer) -> Result<GltfHandles, RendererError> {
+        let meshes = self.meshes
+            .iter()
+            .map(|m| m.primitives
+                .iter()
+                .map(|p| match p.morph_targets.is_empty() {
+                    true => renderer.upload_mesh(&p.vertices(), &p.indices),
+                    false => {
+                        let handle = renderer.upload_morph_mesh(&p.vertices(), &p.indices, &p.morph_targets)?;
+                        renderer.set_morph_weights(handle, &m.weights);
+                        Ok(handle)
+                    }
+                })
+                .collect::<Result<Vec<MeshHandle>, RendererError>>())
+            .collect::<Result<Vec<Vec<MeshHandle>>, RendererError>>()?;
+
+        Ok(GltfHandles {
+            meshes
+        })
+    }
+
+    pub fn animation(&self, name: &str) -> Option<usize> {
+        self.animations.iter().position(|a| a.name.as_deref() == Some(name))
+    }
+
+    // Every node's own transform, indexed like nodes
+    pub fn rest_pose(&self) -> Vec<Transform> {
+        self.nodes.iter().map(|n| n.transform).collect()
+    }
+
+    // Walks the node hierarchy and queues every mesh with its world transform
+    pub fn queue(&self, handles: &GltfHandles, root_transform: Mat4, render_queue: &mut RenderQueue) {
+        self.queue_pose(handles, &self.rest_pose(), root_transform, render_queue);
+    }
+
+    // As queue, with the node transforms taken from pose (see Animator::pose) so animated children move
+    // along with their parents
+    pub fn queue_pose(&self, handles: &GltfHandles, pose: &[Transform], root_transform: Mat4, render_queue: &mut RenderQueue) {
+        fn queue_node(scene: &GltfScene, handles: &GltfHandles, pose: &[Transform], node_idx: usize, parent: Mat4,
+                      render_queue: &mut RenderQueue) {
+            let node = &scene.nodes[node_idx];
+            let local = pose.get(node_idx).unwrap_or(&node.transform);
+            let world = parent * local.matrix();
+
+            if let Some(m) = node.mesh {
+                for handle in handles.meshes[m].iter() {
+                    render_queue.push(*handle, world, MaterialHandle::DEFAULT);
+                }
+            }
+
+            for &c in node.children.iter() {
+                queue_node(scene, handles, pose, c, world, render_queue);
+            }
+        }
+
+        for &r in self.roots.iter() {
+            queue_node(self, handles, pose, r, root_transform, render_queue);
+        }
+    }
+}
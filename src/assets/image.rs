@@ -0,0 +1,31 @@
+use std::fs::File;
+use std::path::Path;
+
+use crate::assets::ImageData;
+use crate::profiling::profile_scope;
+
+// Decodes a PNG of any color type and bit depth into RGBA8
+pub fn load(path: &Path) -> Result<ImageData, String> {
+    profile_scope!("load png", path.to_string_lossy());
+    let file = File::open(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16); // Palettes, low and high bit depths to 8 bit
+    let mut reader = decoder.read_info().map_err(|e| format!("{}: {}", path.display(), e))?;
+    let mut buf = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buf).map_err(|e| format!("{}: {}", path.display(), e))?;
+    buf.truncate(info.buffer_size());
+
+    let pixels = match info.color_type {
+        png::ColorType::Rgba => buf,
+        png::ColorType::Rgb => buf.chunks_exact(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+        png::ColorType::GrayscaleAlpha => buf.chunks_exact(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+        png::ColorType::Grayscale => buf.iter().flat_map(|&p| [p, p, p, 255]).collect(),
+        png::ColorType::Indexed => return Err(format!("{}: unexpanded palette", path.display()))
+    };
+
+    Ok(ImageData {
+        width: info.width,
+        height: info.height,
+        pixels
+    })
+}
@@ -0,0 +1,171 @@
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use ash::vk;
+use basis_universal::{DecodeFlags, LowLevelUastcTranscoder, SliceParametersUastc, TranscoderBlockFormat};
+
+use crate::profiling::profile_scope;
+use crate::renderer::texture::{BlockFormat, CompressedImage};
+
+const IDENTIFIER: [u8; 12] = [0xAB, b'K', b'T', b'X', b' ', b'2', b'0', 0xBB, b'\r', b'\n', 0x1A, b'\n'];
+const HEADER_SIZE: usize = 80; // Identifier, the nine u32 header fields and the section index, the level index follows
+const LEVEL_INDEX_ENTRY_SIZE: usize = 24; // Offset, stored length and unpacked length, all u64
+
+const SUPERCOMPRESSION_NONE: u32 = 0;
+const SUPERCOMPRESSION_BASIS_LZ: u32 = 1; // ETC1S, needs the global codebooks the low level transcoder can't take
+const SUPERCOMPRESSION_ZSTD: u32 = 2;
+
+// Data format descriptor values, see the Khronos Data Format Specification
+const DF_MODEL_UASTC: u8 = 166;
+const DF_TRANSFER_SRGB: u8 = 2;
+const DF_CHANNEL_UASTC_RGBA: u8 = 3;
+const DF_CHANNEL_UASTC_RRRG: u8 = 5;
+
+const UASTC_BLOCK_BYTES: usize = 16;
+const UASTC_TARGETS: [BlockFormat; 3] = [BlockFormat::Bc7, BlockFormat::Astc4x4, BlockFormat::Etc2Rgba8]; // In order of preference
+
+// Loads a 2D KTX2 texture for a device that samples the given formats, usually
+// CubulousRenderer::compressed_formats. Files stored as BC7, ASTC 4x4 or ETC2 are used as is, UASTC files
+// are transcoded to the best of those the device supports. Mip levels in the file are kept.
+pub fn load(path: &Path, formats: &[BlockFormat]) -> Result<CompressedImage, String> {
+    profile_scope!("load ktx2", path.to_string_lossy());
+    let data = fs::read(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    parse(&data, formats).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, String> {
+    data.get(offset..offset + 4)
+        .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| String::from("Truncated file"))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Result<u64, String> {
+    data.get(offset..offset + 8)
+        .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+        .ok_or_else(|| String::from("Truncated file"))
+}
+
+fn parse(data: &[u8], formats: &[BlockFormat]) -> Result<CompressedImage, String> {
+    if data.len() < HEADER_SIZE || data[..12] != IDENTIFIER {
+        return Err(String::from("Not a KTX2 file"));
+    }
+    let vk_format = u32_at(data, 12)?;
+    let width = u32_at(data, 20)?;
+    let height = u32_at(data, 24)?;
+    let depth = u32_at(data, 28)?;
+    let layers = u32_at(data, 32)?;
+    let faces = u32_at(data, 36)?;
+    let level_count = u32_at(data, 40)?.max(1); // 0 asks for mips to be generated, only the base level is used then
+    let supercompression = u32_at(data, 44)?;
+    let dfd_offset = u32_at(data, 48)? as usize;
+
+    if width == 0 || height == 0 || depth > 1 || layers > 1 || faces != 1 {
+        return Err(String::from("Only 2D textures without array layers or cube faces are supported"));
+    }
+    if level_count > 32 || width.max(height) >> (level_count - 1) == 0 {
+        return Err(format!("{} mip levels is more than a {}x{} texture has", level_count, width, height));
+    }
+
+    let mut levels = Vec::with_capacity(level_count as usize);
+    for i in 0..level_count as usize {
+        let entry = HEADER_SIZE + i * LEVEL_INDEX_ENTRY_SIZE;
+        let offset = u64_at(data, entry)? as usize;
+        let length = u64_at(data, entry + 8)? as usize;
+        let unpacked_length = u64_at(data, entry + 16)? as usize;
+        let stored = data.get(offset..offset.saturating_add(length)).ok_or("Truncated mip level")?;
+
+        let level = match supercompression {
+            SUPERCOMPRESSION_NONE => stored.to_vec(),
+            SUPERCOMPRESSION_ZSTD => {
+                let mut level = Vec::with_capacity(unpacked_length);
+                ruzstd::StreamingDecoder::new(stored)
+                    .map_err(|e| e.to_string())?
+                    .read_to_end(&mut level)
+                    .map_err(|e| format!("Failed to decompress mip level {}: {}", i, e))?;
+                level
+            },
+            SUPERCOMPRESSION_BASIS_LZ => return Err(String::from("ETC1S/BasisLZ isn't supported, encode as UASTC instead")),
+            s => return Err(format!("Unsupported supercompression scheme {}", s))
+        };
+        if level.len() != unpacked_length {
+            return Err(format!("Mip level {} unpacked to the wrong size", i));
+        }
+        levels.push(level);
+    }
+
+    if vk_format == vk::Format::UNDEFINED.as_raw() as u32 {
+        return transcode_uastc(data, dfd_offset, width, height, &levels, formats);
+    }
+
+    let (format, srgb) = BlockFormat::from_vk(vk::Format::from_raw(vk_format as i32))
+        .ok_or_else(|| format!("Unsupported format {}, expected BC7, ASTC 4x4, ETC2 or UASTC", vk_format))?;
+    if !formats.contains(&format) {
+        return Err(format!("The GPU can't sample {:?}, store it as UASTC to have it transcoded", format));
+    }
+    for (i, level) in levels.iter().enumerate() {
+        if level.len() != format.level_size((width >> i).max(1), (height >> i).max(1)) {
+            return Err(format!("Mip level {} isn't the size of its {:?} blocks", i, format));
+        }
+    }
+
+    Ok(CompressedImage {
+        width,
+        height,
+        format,
+        srgb,
+        levels
+    })
+}
+
+fn transcode_uastc(data: &[u8], dfd_offset: usize, width: u32, height: u32, levels: &[Vec<u8>],
+                   formats: &[BlockFormat]) -> Result<CompressedImage, String> {
+    // Past the DFD's total size and the basic block's two header words
+    let descriptor = data.get(dfd_offset + 12..dfd_offset + 32).ok_or("Truncated data format descriptor")?;
+    if descriptor[0] != DF_MODEL_UASTC {
+        return Err(format!("Unsupported color model {}, expected UASTC", descriptor[0]));
+    }
+    let srgb = descriptor[2] == DF_TRANSFER_SRGB;
+    let channels = descriptor[19] & 0xF; // The first sample's channel type
+    let has_alpha = channels == DF_CHANNEL_UASTC_RGBA || channels == DF_CHANNEL_UASTC_RRRG;
+
+    let format = UASTC_TARGETS.iter()
+        .copied()
+        .find(|f| formats.contains(f))
+        .ok_or("The GPU samples none of BC7, ASTC 4x4 and ETC2, which UASTC is transcoded to")?;
+    let target = match format {
+        BlockFormat::Bc7 => TranscoderBlockFormat::BC7,
+        BlockFormat::Astc4x4 => TranscoderBlockFormat::ASTC_4x4,
+        _ => TranscoderBlockFormat::ETC2_RGBA
+    };
+
+    basis_universal::transcoder_init(); // Only builds the tables the first time
+    let transcoder = LowLevelUastcTranscoder::new();
+    let transcoded = levels.iter()
+        .enumerate()
+        .map(|(i, level)| {
+            let (level_width, level_height) = ((width >> i).max(1), (height >> i).max(1));
+            let (blocks_x, blocks_y) = ((level_width + 3) / 4, (level_height + 3) / 4);
+            if level.len() != blocks_x as usize * blocks_y as usize * UASTC_BLOCK_BYTES {
+                return Err(format!("Mip level {} isn't the size of its UASTC blocks", i));
+            }
+            let params = SliceParametersUastc {
+                num_blocks_x: blocks_x,
+                num_blocks_y: blocks_y,
+                has_alpha,
+                original_width: level_width,
+                original_height: level_height
+            };
+            transcoder.transcode_slice(level, params, DecodeFlags::HIGH_QUALITY, target)
+                .map_err(|e| format!("Failed to transcode mip level {}: {:?}", i, e))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+
+    Ok(CompressedImage {
+        width,
+        height,
+        format,
+        srgb,
+        levels: transcoded
+    })
+}
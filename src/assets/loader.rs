@@ -0,0 +1,204 @@
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use crate::assets::gltf::{self, GltfHandles, GltfScene};
+use crate::assets::obj::{self, ObjModel};
+use crate::assets::{image, ktx2, ImageData};
+use crate::profiling::profile_function;
+use crate::renderer::error::RendererError;
+use crate::renderer::mesh::MeshHandle;
+use crate::renderer::renderer::CubulousRenderer;
+use crate::renderer::texture::{BlockFormat, CompressedImage, TextureHandle};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct LoadHandle(usize); // Index into the loader's load list
+
+enum Request {
+    Texture { path: PathBuf, srgb: bool },
+    Ktx2 { path: PathBuf, formats: Vec<BlockFormat> },
+    Obj(PathBuf),
+    Gltf(PathBuf)
+}
+
+// Parsed on a worker, uploaded on the render thread by update()
+enum Decoded {
+    Texture(ImageData, bool),
+    Compressed(CompressedImage),
+    Obj(ObjModel),
+    Gltf(GltfScene)
+}
+
+pub enum LoadedAsset {
+    Texture(TextureHandle),
+    Obj(Vec<MeshHandle>), // One per material group, as from ObjModel::upload
+    Gltf(GltfScene, GltfHandles) // The scene is kept for GltfScene::queue
+}
+
+pub enum LoadState<'a> {
+    Loading,
+    Ready(&'a LoadedAsset),
+    Failed(&'a str)
+}
+
+enum Status {
+    Loading,
+    Ready(LoadedAsset),
+    Failed(String)
+}
+
+type Job = (LoadHandle, Request);
+type JobResult = (LoadHandle, Result<Decoded, String>);
+
+// Reads and decodes files on worker threads so big assets don't stall the render loop. update() uploads
+// what the workers finished a few assets at a time, the copies go out through the renderer's upload
+// context on the transfer queue before the next frame.
+pub struct AssetLoader {
+    jobs: Option<Sender<Job>>, // Dropped on shutdown, which ends the workers' loops
+    results: Receiver<JobResult>,
+    cancelled: Arc<AtomicBool>, // Makes workers skip whatever is still queued on shutdown
+    workers: Vec<JoinHandle<()>>,
+    loads: Vec<Status>, // Indexed by LoadHandle
+    pub max_uploads_per_update: usize // Bounds the time update() takes when many loads finish at once
+}
+
+impl AssetLoader {
+    // threads is usually a few less than the core count, so decoding doesn't starve the render thread
+    pub fn new(threads: usize) -> AssetLoader {
+        let (job_sender, job_receiver) = mpsc::channel::<Job>();
+        let (result_sender, results) = mpsc::channel::<JobResult>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        let workers = (0..threads.max(1))
+            .map(|i| {
+                let jobs = job_receiver.clone();
+                let results = result_sender.clone();
+                let cancelled = cancelled.clone();
+                thread::Builder::new()
+                    .name(format!("asset loader {}", i))
+                    .spawn(move || work(&jobs, &results, &cancelled))
+                    .expect("Failed to spawn an asset loader thread")
+            })
+            .collect();
+
+        AssetLoader {
+            jobs: Some(job_sender),
+            results,
+            cancelled,
+            workers,
+            loads: Vec::new(),
+            max_uploads_per_update: 4
+        }
+    }
+
+    // A PNG. srgb should be set for colors and unset for data, I.E. normal maps.
+    pub fn load_texture(&mut self, path: &Path, srgb: bool) -> LoadHandle {
+        self.queue(Request::Texture { path: path.to_owned(), srgb })
+    }
+
+    // formats are the ones the device samples, from CubulousRenderer::compressed_formats. UASTC is
+    // transcoded on the worker.
+    pub fn load_ktx2(&mut self, path: &Path, formats: &[BlockFormat]) -> LoadHandle {
+        self.queue(Request::Ktx2 { path: path.to_owned(), formats: formats.to_vec() })
+    }
+
+    pub fn load_obj(&mut self, path: &Path) -> LoadHandle {
+        self.queue(Request::Obj(path.to_owned()))
+    }
+
+    pub fn load_gltf(&mut self, path: &Path) -> LoadHandle {
+        self.queue(Request::Gltf(path.to_owned()))
+    }
+
+    fn queue(&mut self, request: Request) -> LoadHandle {
+        let handle = LoadHandle(self.loads.len());
+        self.loads.push(Status::Loading);
+        if let Some(jobs) = self.jobs.as_ref() {
+            jobs.send((handle, request)).expect("Every asset loader thread has exited");
+        }
+
+        handle
+    }
+
+    // Uploads up to max_uploads_per_update of the assets the workers have finished decoding. Call once per
+    // frame, before the frame is drawn.
+    pub fn update(&mut self, renderer: &mut CubulousRenderer) {
+        let mut uploaded = 0;
+        while uploaded < self.max_uploads_per_update {
+            let (handle, decoded) = match self.results.try_recv() {
+                Ok(r) => r,
+                Err(_) => break
+            };
+            self.loads[handle.0] = match decoded.map(|d| upload(renderer, d)) {
+                Ok(Ok(asset)) => Status::Ready(asset),
+                Ok(Err(e)) => Status::Failed(e.to_string()),
+                Err(e) => Status::Failed(e)
+            };
+            uploaded += 1;
+        }
+    }
+
+    pub fn state(&self, handle: LoadHandle) -> LoadState {
+        match &self.loads[handle.0] {
+            Status::Loading => LoadState::Loading,
+            Status::Ready(asset) => LoadState::Ready(asset),
+            Status::Failed(e) => LoadState::Failed(e)
+        }
+    }
+
+    // Loads that haven't been uploaded or failed yet
+    pub fn pending(&self) -> usize {
+        self.loads.iter().filter(|s| matches!(s, Status::Loading)).count()
+    }
+}
+
+impl Drop for AssetLoader {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.jobs = None;
+        for w in self.workers.drain(..) {
+            let _ = w.join(); // A panicked worker has nothing left to clean up
+        }
+    }
+}
+
+fn work(jobs: &Mutex<Receiver<Job>>, results: &Sender<JobResult>, cancelled: &AtomicBool) {
+    loop {
+        let job = jobs.lock().unwrap().recv(); // The lock is only held while waiting for the next job
+        let (handle, request) = match job {
+            Ok(j) => j,
+            Err(_) => return // The loader was dropped
+        };
+        if cancelled.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let decoded = match request {
+            Request::Texture { path, srgb } => image::load(&path).map(|i| Decoded::Texture(i, srgb)),
+            Request::Ktx2 { path, formats } => ktx2::load(&path, &formats).map(Decoded::Compressed),
+            Request::Obj(path) => obj::load(&path).map(Decoded::Obj),
+            Request::Gltf(path) => gltf::load(&path).map(Decoded::Gltf)
+        };
+        if results.send((handle, decoded)).is_err() {
+            return;
+        }
+    }
+}
+
+fn upload(renderer: &mut CubulousRenderer, decoded: Decoded) -> Result<LoadedAsset, RendererError> {
+    profile_function!();
+    Ok(match decoded {
+        Decoded::Texture(image, srgb) => {
+            LoadedAsset::Texture(renderer.upload_texture(image.width, image.height, &image.pixels, srgb)?)
+        },
+        Decoded::Compressed(image) => LoadedAsset::Texture(renderer.upload_compressed_texture(&image)?),
+        Decoded::Obj(model) => LoadedAsset::Obj(model.upload(renderer)?),
+        Decoded::Gltf(scene) => {
+            let handles = scene.upload(renderer)?;
+            LoadedAsset::Gltf(scene, handles)
+        }
+    })
+}
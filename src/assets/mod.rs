@@ -0,0 +1,113 @@
+pub mod gltf;
+pub mod animation;
+pub mod obj;
+pub mod image;
+pub mod loader;
+pub mod cache;
+pub mod pack;
+pub mod ktx2;
+
+use std::fmt;
+
+use crate::renderer::error::RendererError;
+use crate::renderer::morph::MorphTarget;
+use crate::renderer::vertex::Vertex;
+
+#[derive(Debug)]
+pub enum AssetError {
+    Load(String), // The file couldn't be read or parsed
+    Renderer(RendererError)
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssetError::Load(e) => write!(f, "{}", e),
+            AssetError::Renderer(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl std::error::Error for AssetError {}
+
+impl From<RendererError> for AssetError {
+    fn from(e: RendererError) -> Self {
+        AssetError::Renderer(e)
+    }
+}
+
+// CPU side geometry produced by the importers, independent of the renderer's vertex format
+#[derive(Clone, Debug, Default)]
+pub struct MeshData {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>, // Empty when the source has none
+    pub uvs: Vec<[f32; 2]>,
+    pub tangents: Vec<[f32; 4]>,
+    pub colors: Vec<[f32; 4]>,
+    pub indices: Vec<u32>,
+    pub morph_targets: Vec<MorphTarget>, // Empty for static meshes
+    pub material: Option<usize> // Index into the importer's material list
+}
+
+impl MeshData {
+    pub fn vertices(&self) -> Vec<Vertex> {
+        let defaults = Vertex::default();
+
+        self.positions
+            .iter()
+            .enumerate()
+            .map(|(i, p)| Vertex {
+                pos: *p,
+                normal: self.normals.get(i).copied().unwrap_or(defaults.normal),
+                uv: self.uvs.get(i).copied().unwrap_or(defaults.uv),
+                tangent: self.tangents.get(i).copied().unwrap_or(defaults.tangent),
+                color: match self.colors.get(i) {
+                    Some(c) => [c[0], c[1], c[2]],
+                    None => defaults.color
+                },
+                layer: defaults.layer
+            })
+            .collect()
+    }
+}
+
+// Decoded RGBA8 pixels
+#[derive(Clone, Debug)]
+pub struct ImageData {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>
+}
+
+#[derive(Clone, Debug)]
+pub struct MaterialData {
+    pub name: Option<String>,
+    pub base_color: [f32; 4],
+    pub base_color_texture: Option<usize>, // Index into the importer's image list
+    pub metallic: f32,
+    pub roughness: f32,
+    pub metallic_roughness_texture: Option<usize>,
+    pub normal_texture: Option<usize>,
+    pub occlusion_texture: Option<usize>,
+    pub emissive: [f32; 3],
+    pub emissive_texture: Option<usize>,
+    pub blended: bool // Alpha blended rather than opaque, I.E. glTF's BLEND alpha mode
+}
+
+impl Default for MaterialData {
+    fn default() -> Self {
+        MaterialData {
+            name: None,
+            base_color: [1.0, 1.0, 1.0, 1.0],
+            base_color_texture: None,
+            metallic: 0.0,
+            roughness: 1.0,
+            metallic_roughness_texture: None,
+            normal_texture: None,
+            occlusion_texture: None,
+            emissive: [0.0, 0.0, 0.0],
+            emissive_texture: None,
+            blended: false
+        }
+    }
+}
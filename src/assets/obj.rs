@@ -0,0 +1,271 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::assets::{MaterialData, MeshData};
+use crate::profiling::profile_scope;
+use crate::renderer::error::RendererError;
+use crate::renderer::mesh::MeshHandle;
+use crate::renderer::renderer::CubulousRenderer;
+
+pub struct ObjModel {
+    pub meshes: Vec<MeshData>, // One per material group
+    pub materials: Vec<MaterialData>,
+    pub textures: Vec<PathBuf> // Material texture indices refer to this list, relative paths are resolved against the .mtl
+}
+
+// Position, texture coordinate and normal indices of a single face corner, zero based
+type Corner = (usize, Option<usize>, Option<usize>);
+
+pub fn load(path: &Path) -> Result<ObjModel, String> {
+    fn parse_floats<const N: usize>(parts: &[&str], line_num: usize) -> Result<[f32; N], String> {
+        let mut out = [0.0; N];
+        for (i, o) in out.iter_mut().enumerate() {
+            *o = match parts.get(i) {
+                Some(p) => p.parse::<f32>().map_err(|e| format!("Line {}: {}", line_num, e))?,
+                None => return Err(format!("Line {}: expected {} values", line_num, N))
+            };
+        }
+
+        Ok(out)
+    }
+
+    // OBJ indices are one based, negative values count back from the most recent element
+    fn resolve_index(idx: &str, len: usize, line_num: usize) -> Result<usize, String> {
+        let i = idx.parse::<i64>().map_err(|e| format!("Line {}: {}", line_num, e))?;
+        let resolved = if i < 0 { len as i64 + i } else { i - 1 };
+
+        match resolved >= 0 && (resolved as usize) < len {
+            true => Ok(resolved as usize),
+            false => Err(format!("Line {}: index {} out of range", line_num, i))
+        }
+    }
+
+    fn parse_corner(corner: &str, counts: (usize, usize, usize), line_num: usize) -> Result<Corner, String> {
+        let mut fields = corner.split('/');
+        let v = resolve_index(fields.next().unwrap_or(""), counts.0, line_num)?;
+        let vt = match fields.next() {
+            Some(t) if !t.is_empty() => Some(resolve_index(t, counts.1, line_num)?),
+            _ => None
+        };
+        let vn = match fields.next() {
+            Some(n) if !n.is_empty() => Some(resolve_index(n, counts.2, line_num)?),
+            _ => None
+        };
+
+        Ok((v, vt, vn))
+    }
+
+    profile_scope!("load obj", path.to_string_lossy());
+    let source = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+
+    let mut positions: Vec<[f32; 3]> = Vec::new();
+    let mut colors: Vec<[f32; 3]> = Vec::new(); // Non standard "v x y z r g b" extension, only used if every v has one
+    let mut uvs: Vec<[f32; 2]> = Vec::new();
+    let mut normals: Vec<[f32; 3]> = Vec::new();
+
+    let mut materials: Vec<MaterialData> = Vec::new();
+    let mut textures: Vec<PathBuf> = Vec::new();
+    let mut material_lookup: HashMap<String, usize> = HashMap::new();
+
+    // Faces are grouped per material so each group becomes one mesh
+    let mut groups: Vec<(Option<usize>, Vec<[Corner; 3]>)> = vec![(None, Vec::new())];
+
+    for (line_idx, line) in source.lines().enumerate() {
+        let line_num = line_idx + 1;
+        let line = line.split('#').next().unwrap().trim();
+        let mut parts = line.split_whitespace();
+        let keyword = match parts.next() {
+            Some(k) => k,
+            None => continue
+        };
+        let args: Vec<&str> = parts.collect();
+
+        match keyword {
+            "v" => {
+                let p = parse_floats::<3>(&args, line_num)?;
+                positions.push(p);
+                if args.len() >= 6 {
+                    colors.push(parse_floats::<3>(&args[3..], line_num)?);
+                }
+            },
+            "vt" => {
+                let t = parse_floats::<2>(&args, line_num)?;
+                uvs.push([t[0], 1.0 - t[1]]); // OBJ puts the origin at the bottom left
+            },
+            "vn" => normals.push(parse_floats::<3>(&args, line_num)?),
+            "f" => {
+                if args.len() < 3 {
+                    return Err(format!("Line {}: face with fewer than 3 vertices", line_num));
+                }
+                let counts = (positions.len(), uvs.len(), normals.len());
+                let corners = args.iter()
+                    .map(|c| parse_corner(c, counts, line_num))
+                    .collect::<Result<Vec<Corner>, String>>()?;
+
+                // Triangle fan, fine for the convex polygons exporters produce
+                let faces = &mut groups.last_mut().unwrap().1;
+                for i in 1..corners.len() - 1 {
+                    faces.push([corners[0], corners[i], corners[i + 1]]);
+                }
+            },
+            "usemtl" => {
+                let name = args.join(" ");
+                let material = material_lookup.get(&name).copied();
+                if material.is_none() {
+                    log::warn!("OBJ line {}: unknown material {}", line_num, name);
+                }
+                groups.push((material, Vec::new()));
+            },
+            "mtllib" => {
+                for lib in args.iter() {
+                    load_mtl(&base_dir.join(lib), &mut materials, &mut textures, &mut material_lookup)?;
+                }
+            },
+            _ => () // o, g, s and l aren't needed for rendering
+        }
+    }
+
+    if colors.len() != positions.len() {
+        colors.clear(); // Otherwise they'd land on the wrong vertices
+    }
+
+    let mut meshes: Vec<MeshData> = Vec::new();
+    for (material, faces) in groups.into_iter().filter(|(_, f)| !f.is_empty()) {
+        let mut mesh = MeshData {
+            material,
+            ..Default::default()
+        };
+        let mut corner_lookup: HashMap<Corner, u32> = HashMap::new(); // De-duplicates shared corners
+
+        for corner in faces.iter().flatten() {
+            let index = *corner_lookup.entry(*corner).or_insert_with(|| {
+                let (v, vt, vn) = *corner;
+                mesh.positions.push(positions[v]);
+                if let Some(t) = vt {
+                    mesh.uvs.push(uvs[t]);
+                }
+                if let Some(n) = vn {
+                    mesh.normals.push(normals[n]);
+                }
+                if let Some(c) = colors.get(v) {
+                    mesh.colors.push([c[0], c[1], c[2], 1.0]);
+                }
+                mesh.positions.len() as u32 - 1
+            });
+            mesh.indices.push(index);
+        }
+
+        // Attributes only some corners specified are unusable
+        if mesh.uvs.len() != mesh.positions.len() {
+            mesh.uvs.clear();
+        }
+        if mesh.normals.len() != mesh.positions.len() {
+            mesh.normals.clear();
+        }
+        if mesh.colors.len() != mesh.positions.len() {
+            mesh.colors.clear();
+        }
+
+        meshes.push(mesh);
+    }
+
+    Ok(ObjModel {
+        meshes,
+        materials,
+        textures
+    })
+}
+
+fn load_mtl(path: &Path, materials: &mut Vec<MaterialData>, textures: &mut Vec<PathBuf>,
+            material_lookup: &mut HashMap<String, usize>) -> Result<(), String> {
+    fn texture_index(args: &[&str], base_dir: &Path, textures: &mut Vec<PathBuf>) -> Option<usize> {
+        // Options such as -bm 1.0 precede the file name, which is always last
+        let file = base_dir.join(args.last()?);
+        match textures.iter().position(|t| *t == file) {
+            Some(i) => Some(i),
+            None => {
+                textures.push(file);
+                Some(textures.len() - 1)
+            }
+        }
+    }
+
+    let source = fs::read_to_string(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let base_dir = path.parent().unwrap_or(Path::new(""));
+
+    for line in source.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        let mut parts = line.split_whitespace();
+        let keyword = match parts.next() {
+            Some(k) => k,
+            None => continue
+        };
+        let args: Vec<&str> = parts.collect();
+        let float = |i: usize| args.get(i).and_then(|a| a.parse::<f32>().ok());
+
+        if keyword == "newmtl" {
+            let name = args.join(" ");
+            materials.push(MaterialData {
+                name: Some(name.clone()),
+                ..Default::default()
+            });
+            material_lookup.insert(name, materials.len() - 1);
+            continue;
+        }
+
+        let material = match materials.last_mut() {
+            Some(m) => m,
+            None => continue // Statements before the first newmtl have nothing to apply to
+        };
+
+        match keyword {
+            "Kd" => {
+                if let (Some(r), Some(g), Some(b)) = (float(0), float(1), float(2)) {
+                    material.base_color = [r, g, b, material.base_color[3]];
+                }
+            },
+            "d" => {
+                if let Some(d) = float(0) {
+                    material.base_color[3] = d;
+                    material.blended = d < 1.0;
+                }
+            },
+            "Tr" => {
+                if let Some(t) = float(0) {
+                    material.base_color[3] = 1.0 - t;
+                    material.blended = t > 0.0;
+                }
+            },
+            "Ns" => {
+                // Approximate the Blinn-Phong exponent as a roughness value
+                if let Some(ns) = float(0) {
+                    material.roughness = (2.0 / (ns + 2.0)).sqrt();
+                }
+            },
+            "Ke" => {
+                if let (Some(r), Some(g), Some(b)) = (float(0), float(1), float(2)) {
+                    material.emissive = [r, g, b];
+                }
+            },
+            "Pm" => material.metallic = float(0).unwrap_or(material.metallic),
+            "Pr" => material.roughness = float(0).unwrap_or(material.roughness),
+            "map_Kd" => material.base_color_texture = texture_index(&args, base_dir, textures),
+            "map_Bump" | "map_bump" | "bump" | "norm" => material.normal_texture = texture_index(&args, base_dir, textures),
+            "map_Ke" => material.emissive_texture = texture_index(&args, base_dir, textures),
+            _ => () // Ka, Ks and illum have no equivalent in the metallic roughness model
+        }
+    }
+
+    Ok(())
+}
+
+impl ObjModel {
+    pub fn upload(&self, renderer: &mut CubulousRenderer) -> Result<Vec<MeshHandle>, RendererError> {
+        self.meshes
+            .iter()
+            .map(|m| renderer.upload_mesh(&m.vertices(), &m.indices))
+            .collect()
+    }
+}
@@ -0,0 +1,469 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::assets::{AssetError, ImageData};
+use crate::renderer::mesh::MeshHandle;
+use crate::renderer::renderer::CubulousRenderer;
+use crate::renderer::shader::ShaderSource;
+use crate::renderer::texture::TextureHandle;
+use crate::renderer::vertex::Vertex;
+use crate::util::cursor::Cursor;
+use crate::util::lz4::decompress_sized;
+
+// Layout, numbers little endian:
+//   header: MAGIC, VERSION u32, entry count u32, index offset u64
+//   entry payloads, back to back
+//   index: per entry a u16 name length, the UTF-8 name, kind u8, compressed u8, offset u64, stored size u64
+//          and unpacked size u64
+// Only the header and index are read when a pack is opened, payloads are read as they're asked for.
+const MAGIC: [u8; 4] = *b"CBPK";
+const VERSION: u32 = 2; // 2 added Vertex::layer
+const HEADER_SIZE: u64 = 20;
+const MIN_INDEX_ENTRY_SIZE: usize = 28; // With an empty name
+const LZ4_MAX_RATIO: u64 = 255; // No LZ4 block unpacks to more than this times its stored size
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    Mesh, // Vertex and index counts as u32s, the vertices' floats, then the indices
+    Texture, // Width and height as u32s, an sRGB flag byte, then RGBA8 pixels
+    Shader // A language byte from ShaderLanguage, then the source text or SPIR-V words
+}
+
+impl EntryKind {
+    fn from_u8(kind: u8) -> Option<EntryKind> {
+        match kind {
+            0 => Some(EntryKind::Mesh),
+            1 => Some(EntryKind::Texture),
+            2 => Some(EntryKind::Shader),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ShaderLanguage {
+    Glsl,
+    Wgsl,
+    Spirv
+}
+
+impl ShaderLanguage {
+    fn from_u8(language: u8) -> Option<ShaderLanguage> {
+        match language {
+            0 => Some(ShaderLanguage::Glsl),
+            1 => Some(ShaderLanguage::Wgsl),
+            2 => Some(ShaderLanguage::Spirv),
+            _ => None
+        }
+    }
+}
+
+struct Entry {
+    kind: EntryKind,
+    compressed: bool, // LZ4 with the unpacked size prepended
+    offset: u64,
+    size: u64, // As stored
+    unpacked_size: u64
+}
+
+fn pack_error(path: &Path, e: impl ToString) -> AssetError {
+    AssetError::Load(format!("{}: {}", path.display(), e.to_string()))
+}
+
+// Collects meshes, textures and shaders in memory, then writes them out as one pack
+pub struct PackWriter {
+    entries: Vec<(String, EntryKind, Vec<u8>)>,
+    pub compress: bool // LZ4 compress payloads where it makes them smaller
+}
+
+impl PackWriter {
+    pub fn new(compress: bool) -> PackWriter {
+        PackWriter {
+            entries: Vec::new(),
+            compress
+        }
+    }
+
+    // Names must be unique, adding one twice replaces the earlier entry
+    fn add(&mut self, name: &str, kind: EntryKind, payload: Vec<u8>) {
+        assert!(name.len() <= u16::MAX as usize, "Pack entry names are at most 65535 bytes");
+        self.entries.retain(|(n, _, _)| n != name);
+        self.entries.push((name.to_owned(), kind, payload));
+    }
+
+    pub fn add_mesh(&mut self, name: &str, vertices: &[Vertex], indices: &[u32]) {
+        let floats: &[f32] = bytemuck::cast_slice(vertices);
+        let mut payload = Vec::with_capacity(8 + floats.len() * 4 + indices.len() * 4);
+        payload.extend_from_slice(&(vertices.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&(indices.len() as u32).to_le_bytes());
+        payload.extend(floats.iter().flat_map(|f| f.to_le_bytes()));
+        payload.extend(indices.iter().flat_map(|i| i.to_le_bytes()));
+        self.add(name, EntryKind::Mesh, payload);
+    }
+
+    // srgb as in CubulousRenderer::upload_texture
+    pub fn add_texture(&mut self, name: &str, image: &ImageData, srgb: bool) {
+        let mut payload = Vec::with_capacity(9 + image.pixels.len());
+        payload.extend_from_slice(&image.width.to_le_bytes());
+        payload.extend_from_slice(&image.height.to_le_bytes());
+        payload.push(srgb as u8);
+        payload.extend_from_slice(&image.pixels);
+        self.add(name, EntryKind::Texture, payload);
+    }
+
+    // File sources are read now, so the pack doesn't depend on them afterwards
+    pub fn add_shader(&mut self, name: &str, source: &ShaderSource) -> Result<(), AssetError> {
+        let read = |path: &PathBuf| std::fs::read(path).map_err(|e| pack_error(path, e));
+        let (language, bytes) = match source {
+            ShaderSource::GlslFile(path) => (ShaderLanguage::Glsl, read(path)?),
+            ShaderSource::WgslFile(path) => (ShaderLanguage::Wgsl, read(path)?),
+            ShaderSource::SpirvFile(path) => (ShaderLanguage::Spirv, read(path)?),
+            ShaderSource::Glsl(src) => (ShaderLanguage::Glsl, src.as_bytes().to_vec()),
+            ShaderSource::Wgsl(src) => (ShaderLanguage::Wgsl, src.as_bytes().to_vec()),
+            ShaderSource::Spirv(code) => (ShaderLanguage::Spirv, code.iter().flat_map(|w| w.to_le_bytes()).collect())
+        };
+        if language == ShaderLanguage::Spirv && bytes.len() % 4 != 0 {
+            return Err(AssetError::Load(format!("{}: SPIR-V isn't a whole number of words", name)));
+        }
+
+        let mut payload = Vec::with_capacity(1 + bytes.len());
+        payload.push(language as u8);
+        payload.extend_from_slice(&bytes);
+        self.add(name, EntryKind::Shader, payload);
+
+        Ok(())
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), AssetError> {
+        let file = File::create(path).map_err(|e| pack_error(path, e))?;
+        let mut out = BufWriter::new(file);
+        let mut index = Vec::new();
+        let mut offset = HEADER_SIZE;
+
+        let mut payloads = Vec::with_capacity(self.entries.len());
+        for (name, kind, payload) in self.entries.iter() {
+            let packed = match self.compress {
+                true => Some(lz4_flex::compress_prepend_size(payload)).filter(|c| c.len() < payload.len()),
+                false => None
+            };
+            let stored = packed.as_deref().unwrap_or(payload);
+
+            index.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            index.extend_from_slice(name.as_bytes());
+            index.push(*kind as u8);
+            index.push(packed.is_some() as u8);
+            index.extend_from_slice(&offset.to_le_bytes());
+            index.extend_from_slice(&(stored.len() as u64).to_le_bytes());
+            index.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+            offset += stored.len() as u64;
+            payloads.push(packed);
+        }
+
+        let mut write = |bytes: &[u8]| out.write_all(bytes).map_err(|e| pack_error(path, e));
+        write(&MAGIC)?;
+        write(&VERSION.to_le_bytes())?;
+        write(&(self.entries.len() as u32).to_le_bytes())?;
+        write(&offset.to_le_bytes())?; // The index follows the last payload
+        for ((_, _, payload), packed) in self.entries.iter().zip(payloads.iter()) {
+            write(packed.as_deref().unwrap_or(payload))?;
+        }
+        write(&index)?;
+
+        out.flush().map_err(|e| pack_error(path, e))
+    }
+}
+
+// An open pack. Opening reads only the index, entries are read and decompressed when they're loaded.
+pub struct AssetPack {
+    path: PathBuf,
+    file: File,
+    len: u64, // Of the file, what entry offsets and sizes are checked against before reading
+    entries: HashMap<String, Entry>
+}
+
+impl AssetPack {
+    pub fn open(path: &Path) -> Result<AssetPack, AssetError> {
+        let err = |e: String| pack_error(path, e);
+        let mut file = File::open(path).map_err(|e| pack_error(path, e))?;
+        let len = file.metadata().map_err(|e| pack_error(path, e))?.len();
+
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_exact(&mut header).map_err(|e| pack_error(path, e))?;
+        let mut cursor = Cursor::new(&header);
+        if cursor.bytes(4).map_err(err)? != MAGIC {
+            return Err(err(String::from("Not an asset pack")));
+        }
+        let version = cursor.u32().map_err(err)?;
+        if version != VERSION {
+            return Err(err(format!("Unsupported pack version {}", version)));
+        }
+        let count = cursor.u32().map_err(err)?;
+        let index_offset = cursor.u64().map_err(err)?;
+
+        let mut index = Vec::new();
+        file.seek(SeekFrom::Start(index_offset))
+            .and_then(|_| file.read_to_end(&mut index))
+            .map_err(|e| pack_error(path, e))?;
+        let mut cursor = Cursor::new(&index);
+        let mut entries = HashMap::with_capacity((count as usize).min(index.len() / MIN_INDEX_ENTRY_SIZE)); // count isn't trusted
+        for _ in 0..count {
+            let name_len = cursor.u16().map_err(err)? as usize;
+            let name = String::from_utf8(cursor.bytes(name_len).map_err(err)?.to_vec()).map_err(|e| err(e.to_string()))?;
+            let kind = EntryKind::from_u8(cursor.u8().map_err(err)?).ok_or_else(|| err(format!("{} has an unknown kind", name)))?;
+            let entry = Entry {
+                kind,
+                compressed: cursor.u8().map_err(err)? != 0,
+                offset: cursor.u64().map_err(err)?,
+                size: cursor.u64().map_err(err)?,
+                unpacked_size: cursor.u64().map_err(err)?
+            };
+            entries.insert(name, entry);
+        }
+
+        Ok(AssetPack {
+            path: path.to_owned(),
+            file,
+            len,
+            entries
+        })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = (&str, EntryKind)> {
+        self.entries.iter().map(|(name, e)| (name.as_str(), e.kind))
+    }
+
+    pub fn kind(&self, name: &str) -> Option<EntryKind> {
+        self.entries.get(name).map(|e| e.kind)
+    }
+
+    fn read(&mut self, name: &str, kind: EntryKind) -> Result<Vec<u8>, AssetError> {
+        let entry = match self.entries.get(name) {
+            Some(e) if e.kind == kind => e,
+            Some(e) => return Err(pack_error(&self.path, format!("{} is a {:?}, not a {:?}", name, e.kind, kind))),
+            None => return Err(pack_error(&self.path, format!("No entry named {}", name)))
+        };
+
+        // Sizes come from the index, so they're checked before anything is allocated
+        let err = |e: String| pack_error(&self.path, format!("{}: {}", name, e));
+        if entry.offset.checked_add(entry.size).map_or(true, |end| end > self.len) {
+            return Err(err(String::from("Entry runs past the end of the pack")));
+        }
+        let plausible = match entry.compressed {
+            true => entry.unpacked_size <= entry.size.saturating_mul(LZ4_MAX_RATIO),
+            false => entry.unpacked_size == entry.size
+        };
+        if !plausible {
+            return Err(err(format!("{} bytes can't unpack to {}", entry.size, entry.unpacked_size)));
+        }
+
+        let mut stored = vec![0; entry.size as usize];
+        self.file.seek(SeekFrom::Start(entry.offset))
+            .and_then(|_| self.file.read_exact(&mut stored))
+            .map_err(|e| pack_error(&self.path, e))?;
+        match entry.compressed {
+            true => decompress_sized(&stored, entry.unpacked_size as usize).map_err(err),
+            false => Ok(stored)
+        }
+    }
+
+    pub fn read_mesh(&mut self, name: &str) -> Result<(Vec<Vertex>, Vec<u32>), AssetError> {
+        let payload = self.read(name, EntryKind::Mesh)?;
+        let mut cursor = Cursor::new(&payload);
+        let mut parse = || -> Result<(Vec<Vertex>, Vec<u32>), String> {
+            let vertex_count = cursor.u32()? as usize;
+            let index_count = cursor.u32()? as usize;
+            let floats_per_vertex = std::mem::size_of::<Vertex>() / 4;
+            let floats: Vec<f32> = cursor.bytes(vertex_count * floats_per_vertex * 4)?
+                .chunks_exact(4)
+                .map(|f| f32::from_le_bytes(f.try_into().unwrap()))
+                .collect();
+            let indices = cursor.bytes(index_count * 4)?
+                .chunks_exact(4)
+                .map(|i| u32::from_le_bytes(i.try_into().unwrap()))
+                .collect();
+            Ok((bytemuck::cast_slice(&floats).to_vec(), indices))
+        };
+
+        parse().map_err(|e| pack_error(&self.path, format!("{}: {}", name, e)))
+    }
+
+    // The pixels and whether they're sRGB
+    pub fn read_texture(&mut self, name: &str) -> Result<(ImageData, bool), AssetError> {
+        let payload = self.read(name, EntryKind::Texture)?;
+        let mut cursor = Cursor::new(&payload);
+        let mut parse = || -> Result<(ImageData, bool), String> {
+            let width = cursor.u32()?;
+            let height = cursor.u32()?;
+            let srgb = cursor.u8()? != 0;
+            let pixels = cursor.bytes(width as usize * height as usize * 4)?.to_vec();
+            Ok((ImageData { width, height, pixels }, srgb))
+        };
+
+        parse().map_err(|e| pack_error(&self.path, format!("{}: {}", name, e)))
+    }
+
+    // Usable in a ShaderSet like any other source
+    pub fn read_shader(&mut self, name: &str) -> Result<ShaderSource, AssetError> {
+        let payload = self.read(name, EntryKind::Shader)?;
+        let mut cursor = Cursor::new(&payload);
+        let err = |e: String| pack_error(&self.path, format!("{}: {}", name, e));
+        let language = cursor.u8().map_err(err)?;
+        let body = cursor.rest();
+        let text = || String::from_utf8(body.to_vec()).map_err(|e| err(e.to_string()));
+
+        match ShaderLanguage::from_u8(language) {
+            Some(ShaderLanguage::Glsl) => Ok(ShaderSource::Glsl(text()?)),
+            Some(ShaderLanguage::Wgsl) => Ok(ShaderSource::Wgsl(text()?)),
+            Some(ShaderLanguage::Spirv) if body.len() % 4 == 0 => {
+                Ok(ShaderSource::Spirv(body.chunks_exact(4).map(|w| u32::from_le_bytes(w.try_into().unwrap())).collect()))
+            },
+            Some(ShaderLanguage::Spirv) => Err(err(String::from("SPIR-V isn't a whole number of words"))),
+            None => Err(err(format!("Unknown shader language {}", language)))
+        }
+    }
+
+    pub fn upload_mesh(&mut self, renderer: &mut CubulousRenderer, name: &str) -> Result<MeshHandle, AssetError> {
+        let (vertices, indices) = self.read_mesh(name)?;
+        Ok(renderer.upload_mesh(&vertices, &indices)?)
+    }
+
+    pub fn upload_texture(&mut self, renderer: &mut CubulousRenderer, name: &str) -> Result<TextureHandle, AssetError> {
+        let (image, srgb) = self.read_texture(name)?;
+        Ok(renderer.upload_texture(image.width, image.height, &image.pixels, srgb)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Removed again when dropped, so failing tests don't leave packs behind
+    struct TempPack(PathBuf);
+
+    impl TempPack {
+        fn new(name: &str) -> TempPack {
+            TempPack(std::env::temp_dir().join(format!("cubulous-{}-{}.cbpk", name, std::process::id())))
+        }
+    }
+
+    impl Drop for TempPack {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn test_writer(compress: bool) -> PackWriter {
+        let mut writer = PackWriter::new(compress);
+        let vertices: Vec<Vertex> = (0..64)
+            .map(|i| Vertex {
+                pos: [i as f32, 0.5, -1.0],
+                color: [i as f32 / 64.0, 0.0, 1.0],
+                ..Vertex::default()
+            })
+            .collect();
+        let indices: Vec<u32> = (0..63).flat_map(|i| [0, i, i + 1]).collect();
+        writer.add_mesh("mesh", &vertices, &indices);
+        let image = ImageData {
+            width: 16,
+            height: 8,
+            pixels: (0..16 * 8 * 4).map(|i| (i % 7) as u8).collect()
+        };
+        writer.add_texture("texture", &image, true);
+        writer.add_shader("glsl", &ShaderSource::Glsl(String::from("void main() {}"))).unwrap();
+        writer.add_shader("spirv", &ShaderSource::Spirv(vec![0x07230203, 1, 2, 3])).unwrap();
+        writer
+    }
+
+    fn round_trip(compress: bool) {
+        let pack = TempPack::new(if compress { "compressed" } else { "uncompressed" });
+        let writer = test_writer(compress);
+        writer.write(&pack.0).unwrap();
+        let mut reader = AssetPack::open(&pack.0).unwrap();
+        assert_eq!(reader.names().count(), 4);
+        assert_eq!(reader.kind("texture"), Some(EntryKind::Texture));
+
+        let (vertices, indices) = reader.read_mesh("mesh").unwrap();
+        let (_, _, expected) = &writer.entries[0];
+        let mut payload = Vec::new();
+        payload.extend_from_slice(&(vertices.len() as u32).to_le_bytes());
+        payload.extend_from_slice(&(indices.len() as u32).to_le_bytes());
+        payload.extend_from_slice(bytemuck::cast_slice(&vertices));
+        payload.extend(indices.iter().flat_map(|i| i.to_le_bytes()));
+        assert_eq!(&payload, expected);
+
+        let (image, srgb) = reader.read_texture("texture").unwrap();
+        assert!(srgb);
+        assert_eq!((image.width, image.height), (16, 8));
+        assert_eq!(image.pixels, (0..16 * 8 * 4).map(|i| (i % 7) as u8).collect::<Vec<u8>>());
+
+        match reader.read_shader("glsl").unwrap() {
+            ShaderSource::Glsl(src) => assert_eq!(src, "void main() {}"),
+            _ => panic!("Expected GLSL source")
+        }
+        match reader.read_shader("spirv").unwrap() {
+            ShaderSource::Spirv(code) => assert_eq!(code, vec![0x07230203, 1, 2, 3]),
+            _ => panic!("Expected SPIR-V")
+        }
+        assert!(reader.read_mesh("texture").is_err());
+        assert!(reader.read_mesh("missing").is_err());
+    }
+
+    #[test]
+    fn round_trip_uncompressed() {
+        round_trip(false);
+    }
+
+    #[test]
+    fn round_trip_compressed() {
+        round_trip(true);
+    }
+
+    #[test]
+    fn adding_a_name_again_replaces_it() {
+        let mut writer = PackWriter::new(false);
+        writer.add_shader("shader", &ShaderSource::Glsl(String::from("a"))).unwrap();
+        writer.add_shader("shader", &ShaderSource::Glsl(String::from("b"))).unwrap();
+        assert_eq!(writer.entries.len(), 1);
+        assert_eq!(writer.entries[0].2, b"\0b");
+    }
+
+    #[test]
+    fn huge_entry_count_is_an_error() {
+        let pack = TempPack::new("count");
+        test_writer(false).write(&pack.0).unwrap();
+        let mut data = std::fs::read(&pack.0).unwrap();
+        data[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&pack.0, &data).unwrap();
+        assert!(AssetPack::open(&pack.0).is_err());
+    }
+
+    #[test]
+    fn huge_entry_sizes_are_errors() {
+        let pack = TempPack::new("size");
+        let mut writer = PackWriter::new(true);
+        writer.add_shader("s", &ShaderSource::Glsl("void main() {} ".repeat(64))).unwrap();
+        writer.write(&pack.0).unwrap();
+        let data = std::fs::read(&pack.0).unwrap();
+        let index = u64::from_le_bytes(data[12..20].try_into().unwrap()) as usize;
+        let size_at = index + 2 + 1 + 1 + 1 + 8; // Past the name length, "s", kind, compressed and offset
+
+        let mut stored = data.clone();
+        stored[size_at..size_at + 8].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        std::fs::write(&pack.0, &stored).unwrap();
+        assert!(AssetPack::open(&pack.0).unwrap().read_shader("s").is_err());
+
+        let mut unpacked = data;
+        unpacked[size_at + 8..size_at + 16].copy_from_slice(&(u64::MAX / 2).to_le_bytes());
+        std::fs::write(&pack.0, &unpacked).unwrap();
+        assert!(AssetPack::open(&pack.0).unwrap().read_shader("s").is_err());
+    }
+
+    #[test]
+    fn not_a_pack() {
+        let pack = TempPack::new("magic");
+        std::fs::write(&pack.0, [0u8; HEADER_SIZE as usize]).unwrap();
+        assert!(AssetPack::open(&pack.0).is_err());
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use glam::Vec3;
+
+use crate::audio::mixer::{Bus, Spatial};
+use crate::audio::{Audio, AudioError, PlayParams, SoundHandle, VoiceHandle};
+
+// Where an event's clip plays from
+#[derive(Clone, Debug)]
+enum Clip {
+    Loaded(SoundHandle),
+    Streamed(PathBuf) // Decoded while it plays, see Audio::stream
+}
+
+#[derive(Clone, Debug)]
+pub struct SoundEvent {
+    clips: Vec<(Clip, f32)>, // With their weights
+    pub bus: Bus,
+    pub volume: f32,
+    pub volume_variance: f32, // Each play picks a volume up to this much either side of volume
+    pub pitch: f32,
+    pub pitch_variance: f32,
+    pub looping: bool,
+    pub spatial: Option<(f32, f32)>, // Min and max distance, None for events heard the same everywhere
+    last_clip: Option<usize> // Not picked twice in a row while there's another
+}
+
+impl SoundEvent {
+    fn new() -> SoundEvent {
+        SoundEvent {
+            clips: Vec::new(),
+            bus: Bus::Sfx,
+            volume: 1.0,
+            volume_variance: 0.0,
+            pitch: 1.0,
+            pitch_variance: 0.0,
+            looping: false,
+            spatial: None,
+            last_clip: None
+        }
+    }
+}
+
+// Named sound events loaded from a bank file, so gameplay code plays "block.break" and the file decides
+// which clips that picks from and how they vary. Line based like OBJ, # starts a comment, and everything
+// after an event line applies to that event:
+//
+//   event block.break
+//   bus sfx                      # sfx, music, ui or ambient
+//   clip sounds/break1.ogg       # picked at random each play, paths relative to the bank
+//   clip sounds/break2.ogg 0.5   # an optional weight, 1 by default
+//   volume 0.8 0.1               # base and variance either side, variance optional
+//   pitch 1.0 0.05
+//   spatial 1 40                 # min and max distance, played at a position
+//   loop
+//   stream sounds/wind.ogg       # a clip decoded while it plays instead of up front
+pub struct SoundBank {
+    events: HashMap<String, SoundEvent>,
+    random: u64 // xorshift state
+}
+
+impl SoundBank {
+    // Decodes every clip that isn't streamed into audio
+    pub fn load(audio: &mut Audio, path: &Path) -> Result<SoundBank, AudioError> {
+        let source = fs::read_to_string(path).map_err(|e| AudioError::Io(path.to_owned(), e.to_string()))?;
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        let mut events = HashMap::new();
+        let mut current: Option<(String, SoundEvent)> = None;
+
+        for (line_idx, line) in source.lines().enumerate() {
+            let error = |e: &str| AudioError::Bank(path.to_owned(), line_idx + 1, e.to_owned());
+            let line = line.split('#').next().unwrap().trim();
+            let mut parts = line.split_whitespace();
+            let keyword = match parts.next() {
+                Some(k) => k,
+                None => continue
+            };
+            let args: Vec<&str> = parts.collect();
+            let float = |i: usize| args.get(i).map(|a| a.parse::<f32>().map_err(|_| error("Expected a number")));
+
+            if keyword == "event" {
+                let name = args.first().ok_or_else(|| error("Expected an event name"))?;
+                if let Some((name, event)) = current.take() {
+                    events.insert(name, event);
+                }
+                current = Some((name.to_string(), SoundEvent::new()));
+                continue;
+            }
+            let event = match current.as_mut() {
+                Some((_, e)) => e,
+                None => return Err(error("Expected an event line first"))
+            };
+
+            match keyword {
+                "bus" => {
+                    let name = args.first().ok_or_else(|| error("Expected a bus name"))?;
+                    event.bus = Bus::from_name(name).ok_or_else(|| error("Unknown bus"))?;
+                },
+                "clip" | "stream" => {
+                    let file = args.first().ok_or_else(|| error("Expected a file"))?;
+                    let clip_path = base_dir.join(file);
+                    let clip = match keyword {
+                        "clip" => Clip::Loaded(audio.load_sound(&clip_path)?),
+                        _ => Clip::Streamed(clip_path)
+                    };
+                    let weight = float(1).transpose()?.unwrap_or(1.0);
+                    event.clips.push((clip, weight.max(0.0)));
+                },
+                "volume" => {
+                    event.volume = float(0).ok_or_else(|| error("Expected a volume"))??;
+                    event.volume_variance = float(1).transpose()?.unwrap_or(0.0);
+                },
+                "pitch" => {
+                    event.pitch = float(0).ok_or_else(|| error("Expected a pitch"))??;
+                    event.pitch_variance = float(1).transpose()?.unwrap_or(0.0);
+                },
+                "spatial" => {
+                    let min = float(0).transpose()?.unwrap_or(1.0);
+                    let max = float(1).transpose()?.unwrap_or(50.0);
+                    event.spatial = Some((min, max));
+                },
+                "loop" => event.looping = true,
+                _ => return Err(error(&format!("Unknown keyword {}", keyword)))
+            }
+        }
+        if let Some((name, event)) = current.take() {
+            events.insert(name, event);
+        }
+
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64);
+        Ok(SoundBank {
+            events,
+            random: seed | 1 // xorshift never leaves 0
+        })
+    }
+
+    pub fn event(&self, name: &str) -> Option<&SoundEvent> {
+        self.events.get(name)
+    }
+
+    // Tweaks an event at runtime, I.E. from a debug panel
+    pub fn event_mut(&mut self, name: &str) -> Option<&mut SoundEvent> {
+        self.events.get_mut(name)
+    }
+
+    // 0 to 1
+    fn random(&mut self) -> f32 {
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 7;
+        self.random ^= self.random << 17;
+        (self.random >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    // Plays one of the event's clips with its volume and pitch varied. position is ignored by events
+    // without spatial. None for unknown events, events without clips and streams that failed to open,
+    // which are logged since gameplay code has nothing to do about them.
+    pub fn play(&mut self, audio: &mut Audio, name: &str, position: Option<Vec3>) -> Option<VoiceHandle> {
+        let rolls = [self.random(), self.random(), self.random()];
+        let event = match self.events.get_mut(name) {
+            Some(e) => e,
+            None => {
+                log::warn!("Unknown sound event {}", name);
+                return None;
+            }
+        };
+
+        // Weighted pick, leaving out the last clip played when there's a choice
+        let skip = event.last_clip.filter(|_| event.clips.len() > 1);
+        let total: f32 = event.clips.iter().enumerate().filter(|(i, _)| Some(*i) != skip).map(|(_, (_, w))| w).sum();
+        let mut roll = rolls[0] * total;
+        let mut picked = None;
+        for (i, (_, weight)) in event.clips.iter().enumerate().filter(|(i, _)| Some(*i) != skip) {
+            picked = Some(i);
+            if roll < *weight {
+                break;
+            }
+            roll -= weight;
+        }
+        let picked = picked?;
+        event.last_clip = Some(picked);
+
+        let params = PlayParams {
+            volume: (event.volume + (rolls[1] * 2.0 - 1.0) * event.volume_variance).max(0.0),
+            pitch: (event.pitch + (rolls[2] * 2.0 - 1.0) * event.pitch_variance).max(0.01),
+            looping: event.looping,
+            bus: event.bus,
+            spatial: event.spatial.map(|(min_distance, max_distance)| Spatial {
+                position: position.unwrap_or(Vec3::ZERO),
+                min_distance,
+                max_distance
+            })
+        };
+        match &event.clips[picked].0 {
+            Clip::Loaded(sound) => Some(audio.play(*sound, params)),
+            Clip::Streamed(path) => match audio.stream(path, params) {
+                Ok(voice) => Some(voice),
+                Err(e) => {
+                    log::error!("Sound event {}: {}", name, e);
+                    None
+                }
+            }
+        }
+    }
+}
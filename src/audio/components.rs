@@ -0,0 +1,42 @@
+use crate::audio::mixer::Bus;
+use crate::audio::{SoundHandle, VoiceHandle};
+
+// Plays a sound from the entity's Transform. Audio::update starts it the first update it sees it with
+// playing set, and keeps the voice at the entity's position and volume afterwards. Non looping sounds
+// clear playing once they've finished, setting it again restarts them. Removing the component stops it.
+#[derive(Clone, Copy, Debug)]
+pub struct AudioSource {
+    pub sound: SoundHandle,
+    pub volume: f32,
+    pub pitch: f32, // Only read when the sound starts
+    pub looping: bool, // Only read when the sound starts
+    pub bus: Bus, // Only read when the sound starts
+    pub min_distance: f32, // Full volume up to this far from the listener
+    pub max_distance: f32, // Silent past this
+    pub playing: bool,
+    pub(crate) voice: Option<VoiceHandle> // Set while the sound plays
+}
+
+impl AudioSource {
+    // Starts playing straight away
+    pub fn new(sound: SoundHandle) -> AudioSource {
+        AudioSource {
+            sound,
+            volume: 1.0,
+            pitch: 1.0,
+            looping: false,
+            bus: Bus::Sfx,
+            min_distance: 1.0,
+            max_distance: 50.0,
+            playing: true,
+            voice: None
+        }
+    }
+
+    pub fn looping(sound: SoundHandle) -> AudioSource {
+        AudioSource {
+            looping: true,
+            ..AudioSource::new(sound)
+        }
+    }
+}
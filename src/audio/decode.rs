@@ -0,0 +1,108 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+
+use crate::audio::AudioError;
+
+const WAV_BLOCK: usize = 4096; // Samples read from a WAV file at a time, OGG files decode a packet at a time
+
+enum Format {
+    Wav { reader: hound::WavReader<BufReader<File>>, scale: f32 }, // scale maps integer samples to -1 to 1, 0 for float files
+    Ogg(lewton::inside_ogg::OggStreamReader<BufReader<File>>)
+}
+
+// Reads a WAV or OGG/Vorbis file a block at a time, picking the format by the file's extension
+pub(crate) struct Decoder {
+    path: PathBuf,
+    format: Format,
+    channels: u16,
+    sample_rate: u32
+}
+
+impl Decoder {
+    pub(crate) fn open(path: &Path) -> Result<Decoder, AudioError> {
+        let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_ascii_lowercase());
+        let decode_error = |e: String| AudioError::Decode(path.to_owned(), e);
+        let (format, channels, sample_rate) = match extension.as_deref() {
+            Some("wav") => {
+                let reader = hound::WavReader::open(path).map_err(|e| decode_error(e.to_string()))?;
+                let spec = reader.spec();
+                let scale = match spec.sample_format {
+                    hound::SampleFormat::Float => 0.0,
+                    hound::SampleFormat::Int => 1.0 / (1u64 << (spec.bits_per_sample - 1)) as f32
+                };
+                (Format::Wav { reader, scale }, spec.channels, spec.sample_rate)
+            },
+            Some("ogg") | Some("oga") => {
+                let file = File::open(path).map_err(|e| AudioError::Io(path.to_owned(), e.to_string()))?;
+                let reader = lewton::inside_ogg::OggStreamReader::new(BufReader::new(file))
+                    .map_err(|e| decode_error(e.to_string()))?;
+                let (channels, sample_rate) = (reader.ident_hdr.audio_channels as u16, reader.ident_hdr.audio_sample_rate);
+                (Format::Ogg(reader), channels, sample_rate)
+            },
+            _ => return Err(AudioError::UnsupportedFormat(path.to_owned()))
+        };
+        if channels == 0 {
+            return Err(decode_error(String::from("No channels")));
+        }
+
+        Ok(Decoder {
+            path: path.to_owned(),
+            format,
+            channels,
+            sample_rate
+        })
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub(crate) fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Appends the next block of interleaved samples to out, returning false once the file has ended
+    pub(crate) fn read(&mut self, out: &mut Vec<f32>) -> Result<bool, AudioError> {
+        let block = WAV_BLOCK / self.channels as usize * self.channels as usize; // Whole frames
+        let path = &self.path;
+        let decode_error = |e: String| AudioError::Decode(path.clone(), e);
+        match &mut self.format {
+            Format::Wav { reader, scale } => {
+                let start = out.len();
+                match *scale == 0.0 {
+                    true => for s in reader.samples::<f32>().take(block) {
+                        out.push(s.map_err(|e| decode_error(e.to_string()))?);
+                    },
+                    false => for s in reader.samples::<i32>().take(block) {
+                        out.push(s.map_err(|e| decode_error(e.to_string()))? as f32 * *scale);
+                    }
+                }
+                Ok(out.len() > start)
+            },
+            Format::Ogg(reader) => loop {
+                match reader.read_dec_packet_itl().map_err(|e| decode_error(e.to_string()))? {
+                    Some(packet) if packet.is_empty() => continue, // Header packets decode to nothing
+                    Some(packet) => {
+                        out.extend(packet.iter().map(|s| *s as f32 / 32768.0));
+                        return Ok(true);
+                    },
+                    None => return Ok(false)
+                }
+            }
+        }
+    }
+}
+
+// The whole file as interleaved samples, with its channel count and sample rate
+pub(crate) fn decode_file(path: &Path) -> Result<(Vec<f32>, u16, u32), AudioError> {
+    let mut decoder = Decoder::open(path)?;
+    let mut samples = Vec::new();
+    while decoder.read(&mut samples)? {}
+
+    Ok((samples, decoder.channels(), decoder.sample_rate()))
+}
@@ -0,0 +1,276 @@
+use std::sync::Arc;
+
+use glam::Vec3;
+
+use crate::audio::stream::StreamReader;
+
+// Decoded PCM, interleaved when there's more than one channel
+pub(crate) struct SoundData {
+    pub(crate) samples: Arc<[f32]>,
+    pub(crate) channels: u16,
+    pub(crate) sample_rate: u32
+}
+
+impl SoundData {
+    fn frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    // Left and right at a frame, mono played on both sides and anything past stereo dropped
+    fn frame(&self, frame: usize) -> (f32, f32) {
+        let i = frame * self.channels as usize;
+        match self.channels {
+            1 => (self.samples[i], self.samples[i]),
+            _ => (self.samples[i], self.samples[i + 1])
+        }
+    }
+}
+
+// Groups voices under one volume control, I.E. for a music volume slider
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum Bus {
+    #[default]
+    Sfx,
+    Music,
+    Ui,
+    Ambient
+}
+
+impl Bus {
+    pub const ALL: [Bus; 4] = [Bus::Sfx, Bus::Music, Bus::Ui, Bus::Ambient];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Bus::Sfx => "sfx",
+            Bus::Music => "music",
+            Bus::Ui => "ui",
+            Bus::Ambient => "ambient"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Bus> {
+        Bus::ALL.into_iter().find(|b| b.name() == name)
+    }
+}
+
+// Where and how loud a voice is heard from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Spatial {
+    pub position: Vec3,
+    pub min_distance: f32, // Full volume up to this far from the listener
+    pub max_distance: f32 // Silent past this
+}
+
+impl Spatial {
+    pub fn new(position: Vec3) -> Spatial {
+        Spatial {
+            position,
+            min_distance: 1.0,
+            max_distance: 50.0
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Listener {
+    pub(crate) position: Vec3,
+    pub(crate) right: Vec3 // Unit length
+}
+
+impl Default for Listener {
+    fn default() -> Self {
+        Listener {
+            position: Vec3::ZERO,
+            right: Vec3::X
+        }
+    }
+}
+
+// Left and right gains for a voice at spatial heard by listener. Volume falls off with the inverse of the
+// distance past min_distance and fades out towards max_distance, and sounds pan with equal power by how
+// far to the listener's side they are.
+pub(crate) fn spatial_gains(spatial: &Spatial, listener: &Listener) -> (f32, f32) {
+    let offset = spatial.position - listener.position;
+    let distance = offset.length();
+    if distance >= spatial.max_distance {
+        return (0.0, 0.0);
+    }
+
+    let min = spatial.min_distance.max(1e-3);
+    let falloff = min / distance.max(min);
+    let fade = match distance > min {
+        true => 1.0 - (distance - min) / (spatial.max_distance - min).max(1e-3),
+        false => 1.0
+    };
+    let gain = falloff * fade;
+
+    let pan = match distance > 1e-3 {
+        true => offset.dot(listener.right) / distance, // -1 left to 1 right
+        false => 0.0
+    };
+    let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+    (gain * angle.cos(), gain * angle.sin())
+}
+
+// Where a voice's frames come from
+pub(crate) enum Source {
+    Buffer { sound: Arc<SoundData>, cursor: f64 }, // cursor is in frames, fractional while resampling
+    Stream { stream: StreamReader, prev: (f32, f32), next: (f32, f32), t: f64 } // t from prev to next
+}
+
+impl Source {
+    pub(crate) fn buffer(sound: Arc<SoundData>) -> Source {
+        Source::Buffer { sound, cursor: 0.0 }
+    }
+
+    pub(crate) fn stream(stream: StreamReader) -> Source {
+        Source::Stream { stream, prev: (0.0, 0.0), next: (0.0, 0.0), t: 1.0 }
+    }
+
+    fn sample_rate(&self) -> u32 {
+        match self {
+            Source::Buffer { sound, .. } => sound.sample_rate,
+            Source::Stream { stream, .. } => stream.sample_rate()
+        }
+    }
+
+    // The next output frame, interpolating linearly between the source's frames around the cursor, then
+    // moves on by step source frames. None once a non looping source has run out.
+    fn next(&mut self, step: f64, looping: bool) -> Option<(f32, f32)> {
+        match self {
+            Source::Buffer { sound, cursor } => {
+                let frames = sound.frames();
+                if frames == 0 {
+                    return None;
+                }
+                if *cursor >= frames as f64 {
+                    if !looping {
+                        return None;
+                    }
+                    *cursor %= frames as f64;
+                }
+
+                let i = *cursor as usize;
+                let t = (*cursor - i as f64) as f32;
+                let next = match (i + 1 < frames, looping) {
+                    (true, _) => i + 1,
+                    (false, true) => 0,
+                    (false, false) => i
+                };
+                *cursor += step;
+                Some(lerp(sound.frame(i), sound.frame(next), t))
+            },
+            Source::Stream { stream, prev, next, t } => {
+                while *t >= 1.0 {
+                    match stream.pop_frame() {
+                        Some(frame) => {
+                            *prev = *next;
+                            *next = frame;
+                            *t -= 1.0;
+                        },
+                        None if stream.ended() => return None,
+                        None => return Some((0.0, 0.0)) // The decoder is behind, wait for it without moving on
+                    }
+                }
+                let out = lerp(*prev, *next, *t as f32);
+                *t += step;
+                Some(out)
+            }
+        }
+    }
+}
+
+fn lerp(a: (f32, f32), b: (f32, f32), t: f32) -> (f32, f32) {
+    (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)
+}
+
+pub(crate) struct Voice {
+    pub(crate) id: u64,
+    source: Source,
+    pub(crate) volume: f32,
+    pub(crate) pitch: f32, // Playback speed, 1 is as recorded
+    pub(crate) looping: bool, // Streams loop on their decoding thread instead
+    pub(crate) paused: bool,
+    pub(crate) bus: Bus,
+    pub(crate) spatial: Option<Spatial>,
+    pub(crate) finished: bool
+}
+
+impl Voice {
+    pub(crate) fn new(id: u64, source: Source) -> Voice {
+        Voice {
+            id,
+            source,
+            volume: 1.0,
+            pitch: 1.0,
+            looping: false,
+            paused: false,
+            bus: Bus::Sfx,
+            spatial: None,
+            finished: false
+        }
+    }
+
+    // Adds the voice into out, interleaved stereo at output_rate
+    fn mix(&mut self, out: &mut [f32], output_rate: u32, listener: &Listener, bus_volume: f32) {
+        let (left_gain, right_gain) = match self.spatial.as_ref() {
+            Some(s) => spatial_gains(s, listener),
+            None => (1.0, 1.0)
+        };
+        let volume = self.volume * bus_volume;
+        let (left_gain, right_gain) = (left_gain * volume, right_gain * volume);
+        let step = self.source.sample_rate() as f64 / output_rate as f64 * self.pitch.max(0.0) as f64;
+
+        for frame in out.chunks_exact_mut(2) {
+            match self.source.next(step, self.looping) {
+                Some((left, right)) => {
+                    frame[0] += left * left_gain;
+                    frame[1] += right * right_gain;
+                },
+                None => {
+                    self.finished = true;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// Shared between Audio and the output stream's callback
+pub(crate) struct Mixer {
+    pub(crate) voices: Vec<Voice>,
+    pub(crate) listener: Listener,
+    pub(crate) master_volume: f32,
+    pub(crate) bus_volumes: [f32; Bus::ALL.len()], // Indexed by Bus as usize
+    pub(crate) output_rate: u32
+}
+
+impl Mixer {
+    pub(crate) fn new(output_rate: u32) -> Mixer {
+        Mixer {
+            voices: Vec::new(),
+            listener: Listener::default(),
+            master_volume: 1.0,
+            bus_volumes: [1.0; Bus::ALL.len()],
+            output_rate
+        }
+    }
+
+    pub(crate) fn voice_mut(&mut self, id: u64) -> Option<&mut Voice> {
+        self.voices.iter_mut().find(|v| v.id == id)
+    }
+
+    // Fills out, interleaved stereo, with every playing voice. Finished voices are dropped.
+    pub(crate) fn render(&mut self, out: &mut [f32]) {
+        out.fill(0.0);
+        let (rate, listener, buses) = (self.output_rate, self.listener, self.bus_volumes);
+        for voice in self.voices.iter_mut().filter(|v| !v.paused) {
+            voice.mix(out, rate, &listener, buses[voice.bus as usize]);
+        }
+        self.voices.retain(|v| !v.finished);
+
+        for sample in out.iter_mut() {
+            *sample = (*sample * self.master_volume).clamp(-1.0, 1.0);
+        }
+    }
+}
@@ -0,0 +1,345 @@
+pub mod components;
+pub mod mixer;
+pub mod bank;
+mod decode;
+mod stream;
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use glam::Vec3;
+
+use crate::audio::components::AudioSource;
+use crate::audio::decode::{decode_file, Decoder};
+use crate::audio::mixer::{Bus, Listener, Mixer, SoundData, Source, Spatial, Voice};
+use crate::audio::stream::StreamReader;
+use crate::ecs::components::Transform;
+use crate::ecs::{Entity, World};
+use crate::renderer::camera::Camera;
+
+#[derive(Debug)]
+pub enum AudioError {
+    NoDevice,
+    Stream(String), // Opening or starting the output stream failed
+    Io(PathBuf, String),
+    Decode(PathBuf, String),
+    UnsupportedFormat(PathBuf), // Only .wav and .ogg files can be played
+    Bank(PathBuf, usize, String) // A sound bank line that doesn't parse, with its line number
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::NoDevice => write!(f, "No audio output device"),
+            AudioError::Stream(e) => write!(f, "Failed to open the audio output: {}", e),
+            AudioError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            AudioError::Decode(path, e) => write!(f, "{}: failed to decode: {}", path.display(), e),
+            AudioError::UnsupportedFormat(path) => write!(f, "{}: not a WAV or OGG file", path.display()),
+            AudioError::Bank(path, line, e) => write!(f, "{}:{}: {}", path.display(), line, e)
+        }
+    }
+}
+
+impl std::error::Error for AudioError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SoundHandle(pub(crate) usize); // Index into Audio's sound list
+
+// One playback of a sound. Stays valid after the sound finishes, calls on it just do nothing then.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VoiceHandle(u64);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PlayParams {
+    pub volume: f32,
+    pub pitch: f32, // Playback speed, 1 is as recorded
+    pub looping: bool,
+    pub bus: Bus,
+    pub spatial: Option<Spatial> // None plays at the same volume on both sides, I.E. UI sounds and music
+}
+
+impl PlayParams {
+    pub fn at(position: Vec3) -> PlayParams {
+        PlayParams {
+            spatial: Some(Spatial::new(position)),
+            ..PlayParams::default()
+        }
+    }
+}
+
+impl Default for PlayParams {
+    fn default() -> Self {
+        PlayParams {
+            volume: 1.0,
+            pitch: 1.0,
+            looping: false,
+            bus: Bus::Sfx,
+            spatial: None
+        }
+    }
+}
+
+// Plays sounds on the default output device. Voices are mixed to stereo on the output stream's thread,
+// positioned ones attenuated and panned relative to the listener, which follows the camera through
+// update or set_listener.
+pub struct Audio {
+    _stream: cpal::Stream, // Output stops when it's dropped
+    mixer: Arc<Mutex<Mixer>>,
+    sounds: Vec<Arc<SoundData>>,
+    next_voice: u64,
+    music: Option<VoiceHandle>,
+    source_entities: Vec<(Entity, VoiceHandle)> // Voices started for AudioSources, to notice removed components
+}
+
+impl Audio {
+    pub fn new() -> Result<Audio, AudioError> {
+        let device = cpal::default_host().default_output_device().ok_or(AudioError::NoDevice)?;
+        let supported = device.default_output_config().map_err(|e| AudioError::Stream(e.to_string()))?;
+        let sample_format = supported.sample_format();
+        let config: cpal::StreamConfig = supported.into();
+        log::info!("Audio output: {} at {} Hz, {} channels", device.name().unwrap_or_default(),
+                   config.sample_rate.0, config.channels);
+
+        let mixer = Arc::new(Mutex::new(Mixer::new(config.sample_rate.0)));
+        let stream = match sample_format {
+            cpal::SampleFormat::F32 => build_stream::<f32>(&device, &config, mixer.clone()),
+            cpal::SampleFormat::I16 => build_stream::<i16>(&device, &config, mixer.clone()),
+            cpal::SampleFormat::U16 => build_stream::<u16>(&device, &config, mixer.clone())
+        }?;
+        stream.play().map_err(|e| AudioError::Stream(e.to_string()))?;
+
+        Ok(Audio {
+            _stream: stream,
+            mixer,
+            sounds: Vec::new(),
+            next_voice: 0,
+            music: None,
+            source_entities: Vec::new()
+        })
+    }
+
+    // samples are interleaved when there's more than one channel, 1 or 2 channels are played as is and
+    // anything past the second is dropped. Sounds are resampled to the output's rate as they play.
+    pub fn add_sound(&mut self, samples: Vec<f32>, channels: u16, sample_rate: u32) -> SoundHandle {
+        assert!(channels > 0, "A sound needs at least one channel");
+        let frames = samples.len() / channels as usize;
+        let mut samples = samples;
+        samples.truncate(frames * channels as usize);
+        self.sounds.push(Arc::new(SoundData {
+            samples: samples.into(),
+            channels,
+            sample_rate
+        }));
+
+        SoundHandle(self.sounds.len() - 1)
+    }
+
+    // Decodes a whole WAV or OGG/Vorbis file up front, for short sounds played often. Stream music instead.
+    pub fn load_sound(&mut self, path: &Path) -> Result<SoundHandle, AudioError> {
+        let (samples, channels, sample_rate) = decode_file(path)?;
+        Ok(self.add_sound(samples, channels, sample_rate))
+    }
+
+    fn start(&mut self, source: Source, params: PlayParams) -> VoiceHandle {
+        let handle = VoiceHandle(self.next_voice);
+        self.next_voice += 1;
+        let mut voice = Voice::new(handle.0, source);
+        voice.volume = params.volume;
+        voice.pitch = params.pitch;
+        voice.looping = params.looping;
+        voice.bus = params.bus;
+        voice.spatial = params.spatial;
+        self.mixer.lock().unwrap().voices.push(voice);
+
+        handle
+    }
+
+    pub fn play(&mut self, sound: SoundHandle, params: PlayParams) -> VoiceHandle {
+        self.start(Source::buffer(self.sounds[sound.0].clone()), params)
+    }
+
+    // Plays a WAV or OGG/Vorbis file while decoding it on a thread of its own, keeping only a second or so
+    // of it in memory. Suits music and long ambience. The file is opened before this returns, so missing
+    // or unsupported files are reported here.
+    pub fn stream(&mut self, path: &Path, params: PlayParams) -> Result<VoiceHandle, AudioError> {
+        let stream = StreamReader::spawn(Decoder::open(path)?, params.looping);
+        Ok(self.start(Source::stream(stream), PlayParams {
+            looping: false, // The stream loops itself
+            ..params
+        }))
+    }
+
+    // Loops the sound as the music track, stopping the one playing before
+    pub fn play_music(&mut self, sound: SoundHandle, volume: f32) -> VoiceHandle {
+        if let Some(music) = self.music.take() {
+            self.stop(music);
+        }
+        let voice = self.play(sound, PlayParams {
+            volume,
+            looping: true,
+            bus: Bus::Music,
+            ..PlayParams::default()
+        });
+        self.music = Some(voice);
+        voice
+    }
+
+    // Like play_music, streaming the file
+    pub fn stream_music(&mut self, path: &Path, volume: f32) -> Result<VoiceHandle, AudioError> {
+        let voice = self.stream(path, PlayParams {
+            volume,
+            looping: true,
+            bus: Bus::Music,
+            ..PlayParams::default()
+        })?;
+        if let Some(music) = self.music.replace(voice) {
+            self.stop(music);
+        }
+        Ok(voice)
+    }
+
+    pub fn stop_music(&mut self) {
+        if let Some(music) = self.music.take() {
+            self.stop(music);
+        }
+    }
+
+    fn with_voice(&self, voice: VoiceHandle, f: impl FnOnce(&mut Voice)) {
+        if let Some(v) = self.mixer.lock().unwrap().voice_mut(voice.0) {
+            f(v);
+        }
+    }
+
+    pub fn stop(&mut self, voice: VoiceHandle) {
+        self.mixer.lock().unwrap().voices.retain(|v| v.id != voice.0);
+    }
+
+    // False once a non looping voice has finished or the voice was stopped
+    pub fn is_playing(&self, voice: VoiceHandle) -> bool {
+        self.mixer.lock().unwrap().voices.iter().any(|v| v.id == voice.0)
+    }
+
+    pub fn set_paused(&mut self, voice: VoiceHandle, paused: bool) {
+        self.with_voice(voice, |v| v.paused = paused);
+    }
+
+    pub fn set_volume(&mut self, voice: VoiceHandle, volume: f32) {
+        self.with_voice(voice, |v| v.volume = volume);
+    }
+
+    // Moves a positioned voice, I.E. one following a moving object. Voices played without a position
+    // stay that way.
+    pub fn set_position(&mut self, voice: VoiceHandle, position: Vec3) {
+        self.with_voice(voice, |v| {
+            if let Some(spatial) = v.spatial.as_mut() {
+                spatial.position = position;
+            }
+        });
+    }
+
+    // Scales everything, 0 to 1
+    pub fn set_master_volume(&mut self, volume: f32) {
+        self.mixer.lock().unwrap().master_volume = volume.max(0.0);
+    }
+
+    // Scales every voice on the bus, 0 to 1
+    pub fn set_bus_volume(&mut self, bus: Bus, volume: f32) {
+        self.mixer.lock().unwrap().bus_volumes[bus as usize] = volume.max(0.0);
+    }
+
+    pub fn bus_volume(&self, bus: Bus) -> f32 {
+        self.mixer.lock().unwrap().bus_volumes[bus as usize]
+    }
+
+    // Hears from the camera's position, facing where it looks
+    pub fn set_listener(&mut self, camera: &Camera) {
+        let forward = (camera.target() - camera.position()).normalize_or_zero();
+        let right = forward.cross(Vec3::Y).try_normalize().unwrap_or(Vec3::X);
+        self.mixer.lock().unwrap().listener = Listener {
+            position: camera.position(),
+            right
+        };
+    }
+
+    // Once per frame. Moves the listener to the camera, starts, moves and stops the voices of the world's
+    // AudioSource entities, and clears playing on sources whose sound has finished.
+    pub fn update(&mut self, world: &mut World, camera: &Camera) {
+        self.set_listener(camera);
+        let mut mixer = self.mixer.lock().unwrap();
+
+        // Voices whose entity no longer has the source that started them, or has stopped it
+        self.source_entities.retain(|(entity, voice)| {
+            let current = world.get::<AudioSource>(*entity).filter(|s| s.playing).and_then(|s| s.voice) == Some(*voice);
+            if !current {
+                mixer.voices.retain(|v| v.id != voice.0);
+            }
+            current
+        });
+
+        let entities: Vec<Entity> = world.query::<AudioSource>().map(|(e, _)| e).collect();
+        for entity in entities {
+            let position = world.get::<Transform>(entity).map_or(Vec3::ZERO, |t| t.translation);
+            let source = world.get_mut::<AudioSource>(entity).unwrap(); // From the query
+            let spatial = Spatial {
+                position,
+                min_distance: source.min_distance,
+                max_distance: source.max_distance
+            };
+            match (source.playing, source.voice) {
+                (true, Some(voice)) => match mixer.voice_mut(voice.0) {
+                    Some(v) => {
+                        v.volume = source.volume;
+                        v.spatial = Some(spatial);
+                    },
+                    None => {
+                        // Finished
+                        source.playing = false;
+                        source.voice = None;
+                    }
+                },
+                (true, None) => {
+                    let handle = VoiceHandle(self.next_voice);
+                    self.next_voice += 1;
+                    let mut voice = Voice::new(handle.0, Source::buffer(self.sounds[source.sound.0].clone()));
+                    voice.volume = source.volume;
+                    voice.pitch = source.pitch;
+                    voice.looping = source.looping;
+                    voice.bus = source.bus;
+                    voice.spatial = Some(spatial);
+                    mixer.voices.push(voice);
+                    source.voice = Some(handle);
+                    self.source_entities.push((entity, handle));
+                },
+                (false, _) => source.voice = None
+            }
+        }
+    }
+}
+
+// Mixes to stereo, then spreads that over the device's channels
+fn build_stream<T: cpal::Sample>(device: &cpal::Device, config: &cpal::StreamConfig, mixer: Arc<Mutex<Mixer>>)
+    -> Result<cpal::Stream, AudioError> {
+    let channels = config.channels as usize;
+    let mut stereo = Vec::new();
+    device.build_output_stream(config, move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+        stereo.resize(data.len() / channels * 2, 0.0);
+        match mixer.lock() {
+            Ok(mut m) => m.render(&mut stereo),
+            Err(_) => stereo.fill(0.0) // Audio panicked while holding the lock
+        }
+        for (out, s) in data.chunks_exact_mut(channels).zip(stereo.chunks_exact(2)) {
+            for (channel, sample) in out.iter_mut().enumerate() {
+                let value = match (channels, channel) {
+                    (1, _) => (s[0] + s[1]) * 0.5,
+                    (_, 0) => s[0],
+                    (_, 1) => s[1],
+                    _ => 0.0
+                };
+                *sample = T::from(&value);
+            }
+        }
+    }, |e| log::error!("Audio output error: {}", e))
+        .map_err(|e| AudioError::Stream(e.to_string()))
+}
@@ -0,0 +1,170 @@
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::audio::decode::Decoder;
+
+const RING_FRAMES: usize = 1 << 16; // About 1.4 seconds at 48 kHz decoded ahead
+const IDLE_WAIT: Duration = Duration::from_millis(10); // Between checks for space while the ring is full
+
+// Single producer, single consumer queue of samples. Samples are stored as their bits so both sides can
+// touch the buffer without a lock, and the positions only ever grow so full and empty are told apart.
+struct RingBuffer {
+    samples: Box<[AtomicU32]>, // Power of two long
+    read: AtomicUsize,
+    write: AtomicUsize
+}
+
+impl RingBuffer {
+    fn new(capacity: usize) -> RingBuffer {
+        RingBuffer {
+            samples: (0..capacity.next_power_of_two()).map(|_| AtomicU32::new(0)).collect(),
+            read: AtomicUsize::new(0),
+            write: AtomicUsize::new(0)
+        }
+    }
+
+    // Writes as many whole frames of data as fit, returning how many samples that was
+    fn push(&self, data: &[f32], channels: usize) -> usize {
+        let mask = self.samples.len() - 1;
+        let write = self.write.load(Ordering::Relaxed);
+        let free = self.samples.len() - write.wrapping_sub(self.read.load(Ordering::Acquire));
+        let count = free.min(data.len()) / channels * channels;
+        for (i, sample) in data[..count].iter().enumerate() {
+            self.samples[(write + i) & mask].store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.write.store(write.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    // Fills out completely or not at all
+    fn pop(&self, out: &mut [f32]) -> bool {
+        let mask = self.samples.len() - 1;
+        let read = self.read.load(Ordering::Relaxed);
+        if self.write.load(Ordering::Acquire).wrapping_sub(read) < out.len() {
+            return false;
+        }
+        for (i, sample) in out.iter_mut().enumerate() {
+            *sample = f32::from_bits(self.samples[(read + i) & mask].load(Ordering::Relaxed));
+        }
+        self.read.store(read.wrapping_add(out.len()), Ordering::Release);
+        true
+    }
+}
+
+struct Shared {
+    ring: RingBuffer,
+    ended: AtomicBool, // Set by the decoding thread once it's pushed the last frame
+    stop: AtomicBool // Set when the reader is dropped, ending the decoding thread
+}
+
+// The output side of a file decoded on a thread of its own, so long tracks never have to fit in memory
+// whole. The thread stays RING_FRAMES ahead of playback, looping back to the start of the file if asked.
+pub(crate) struct StreamReader {
+    shared: Arc<Shared>,
+    channels: u16,
+    sample_rate: u32,
+    frame: Vec<f32> // One frame popped from the ring
+}
+
+impl StreamReader {
+    pub(crate) fn spawn(decoder: Decoder, looping: bool) -> StreamReader {
+        let channels = decoder.channels();
+        let sample_rate = decoder.sample_rate();
+        let shared = Arc::new(Shared {
+            ring: RingBuffer::new(RING_FRAMES * channels as usize),
+            ended: AtomicBool::new(false),
+            stop: AtomicBool::new(false)
+        });
+
+        let worker_shared = shared.clone();
+        let name = format!("audio stream {}", decoder.path().display());
+        // Not joined, it notices stop within IDLE_WAIT and dropping the reader mustn't block the audio thread
+        thread::Builder::new()
+            .name(name)
+            .spawn(move || decode(decoder, looping, &worker_shared))
+            .expect("Failed to spawn an audio stream thread");
+
+        StreamReader {
+            shared,
+            channels,
+            sample_rate,
+            frame: vec![0.0; channels as usize]
+        }
+    }
+
+    pub(crate) fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // Left and right, mono on both sides and anything past stereo dropped. None while the ring is empty.
+    pub(crate) fn pop_frame(&mut self) -> Option<(f32, f32)> {
+        if !self.shared.ring.pop(&mut self.frame) {
+            return None;
+        }
+        Some(match self.channels {
+            1 => (self.frame[0], self.frame[0]),
+            _ => (self.frame[0], self.frame[1])
+        })
+    }
+
+    // True once every frame has been decoded. Check after pop_frame comes back empty, the last frames
+    // may still be in the ring.
+    pub(crate) fn ended(&self) -> bool {
+        let ring = &self.shared.ring;
+        self.shared.ended.load(Ordering::Acquire) && ring.write.load(Ordering::Acquire) == ring.read.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for StreamReader {
+    fn drop(&mut self) {
+        self.shared.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+fn decode(mut decoder: Decoder, looping: bool, shared: &Shared) {
+    let channels = decoder.channels() as usize;
+    let mut pending = Vec::new();
+    let mut offset = 0;
+    let mut decoded_any = false; // Since the file was last opened, so an empty file doesn't loop forever
+
+    while !shared.stop.load(Ordering::Relaxed) {
+        if offset == pending.len() {
+            pending.clear();
+            offset = 0;
+            match decoder.read(&mut pending) {
+                Ok(true) => {
+                    // Only the last block can end mid frame, I.E. a WAV whose sample count isn't a multiple of
+                    // channels. push only takes whole frames, so the rest would never be written.
+                    pending.truncate(pending.len() / channels * channels);
+                    decoded_any = true;
+                },
+                Ok(false) if looping && decoded_any => match Decoder::open(decoder.path()) {
+                    Ok(d) => {
+                        decoder = d;
+                        decoded_any = false;
+                        continue;
+                    },
+                    Err(e) => {
+                        log::error!("{}", e);
+                        break;
+                    }
+                },
+                Ok(false) => break,
+                Err(e) => {
+                    log::error!("{}", e);
+                    break;
+                }
+            }
+        }
+
+        let written = shared.ring.push(&pending[offset..], channels);
+        offset += written;
+        if written == 0 {
+            thread::sleep(IDLE_WAIT);
+        }
+    }
+
+    shared.ended.store(true, Ordering::Release);
+}
@@ -0,0 +1,111 @@
+use std::path::{Path, PathBuf};
+
+use crate::config::{Settings, WindowMode, MAX_RENDER_DISTANCE, MIN_RENDER_DISTANCE};
+use crate::renderer::frame::Frame;
+
+// Offered on top of whatever size the window was set to
+const RESOLUTIONS: [(u32, u32); 7] = [(800, 600), (1024, 768), (1280, 720), (1366, 768), (1600, 900), (1920, 1080), (2560, 1440)];
+
+// An egui window editing the graphics settings, which reach the renderer as soon as they change. The game
+// applies render_distance itself since the renderer has no notion of it.
+pub struct SettingsMenu {
+    pub open: bool,
+    path: PathBuf, // Written by the Save button
+    status: Option<String> // How the last save went
+}
+
+impl SettingsMenu {
+    pub fn new(path: &Path) -> SettingsMenu {
+        SettingsMenu {
+            open: false,
+            path: path.to_owned(),
+            status: None
+        }
+    }
+
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+        self.status = None;
+    }
+
+    // Draws the menu while it's open and applies what changed to the renderer. Returns whether anything did.
+    pub fn show(&mut self, frame: &mut Frame, settings: &mut Settings) -> bool {
+        if !self.open {
+            return false;
+        }
+
+        let before = settings.clone();
+        let msaa_options = frame.renderer().supported_msaa();
+        let mut resolutions = RESOLUTIONS.to_vec();
+        let current = (settings.graphics.width, settings.graphics.height);
+        if !resolutions.contains(&current) {
+            resolutions.push(current);
+            resolutions.sort();
+        }
+
+        let mut open = self.open;
+        let mut save = false;
+        let status = self.status.as_deref();
+        frame.ui(|ctx| {
+            egui::Window::new("Settings").open(&mut open).show(ctx, |ui| {
+                let graphics = &mut settings.graphics;
+                egui::ComboBox::from_label("Window mode")
+                    .selected_text(format!("{:?}", graphics.window_mode))
+                    .show_ui(ui, |ui| {
+                        for mode in [WindowMode::Windowed, WindowMode::Borderless, WindowMode::Exclusive] {
+                            ui.selectable_value(&mut graphics.window_mode, mode, format!("{:?}", mode));
+                        }
+                    });
+                ui.add_enabled_ui(graphics.window_mode == WindowMode::Windowed, |ui| {
+                    let mut size = (graphics.width, graphics.height);
+                    egui::ComboBox::from_label("Resolution")
+                        .selected_text(format!("{}x{}", size.0, size.1))
+                        .show_ui(ui, |ui| {
+                            for r in resolutions.iter() {
+                                ui.selectable_value(&mut size, *r, format!("{}x{}", r.0, r.1));
+                            }
+                        });
+                    (graphics.width, graphics.height) = size;
+                });
+                let msaa_text = |samples: u32| match samples {
+                    1 => String::from("Off"),
+                    s => format!("{}x", s)
+                };
+                egui::ComboBox::from_label("Anti-aliasing")
+                    .selected_text(msaa_text(graphics.msaa))
+                    .show_ui(ui, |ui| {
+                        for samples in msaa_options.iter() {
+                            ui.selectable_value(&mut graphics.msaa, *samples, msaa_text(*samples));
+                        }
+                    });
+                ui.checkbox(&mut graphics.vsync, "Vsync");
+                ui.add(egui::Slider::new(&mut graphics.render_distance, MIN_RENDER_DISTANCE..=MAX_RENDER_DISTANCE).text("render distance"));
+
+                ui.separator();
+                ui.horizontal(|ui| {
+                    save = ui.button("Save").clicked();
+                    if let Some(status) = status {
+                        ui.label(status);
+                    }
+                });
+            });
+        });
+        self.open = open;
+
+        if settings.graphics != before.graphics {
+            settings.apply_to_running(frame.renderer());
+            settings.graphics.msaa = frame.renderer().msaa(); // What the device and pipeline allow, I.E. 1 when deferred
+        }
+        if save {
+            self.status = Some(match settings.save(&self.path) {
+                Ok(()) => String::from("Saved"),
+                Err(e) => {
+                    log::error!("{}", e);
+                    e.to_string()
+                }
+            });
+        }
+
+        *settings != before
+    }
+}
@@ -0,0 +1,405 @@
+pub mod menu;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::audio::mixer::Bus;
+use crate::audio::Audio;
+use crate::input::actions::{ActionMap, Binding};
+use crate::net::transport::{Protocol, QuicSettings};
+use crate::renderer::config::{FullscreenMode, PresentMode, RendererConfig, MAX_RENDER_SCALE, MIN_RENDER_SCALE};
+use crate::renderer::renderer::CubulousRenderer;
+use crate::save::{write_replacing, SaveError};
+
+pub const MIN_RENDER_DISTANCE: f32 = 16.0; // GraphicsSettings::render_distance's range, also the settings menu's
+pub const MAX_RENDER_DISTANCE: f32 = 1024.0;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, String),
+    Parse(PathBuf, String) // The file was read but isn't valid TOML for Settings
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Parse(path, e) => write!(f, "{}: invalid settings: {}", path.display(), e)
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WindowMode {
+    Windowed,
+    Borderless,
+    Exclusive
+}
+
+impl WindowMode {
+    pub fn fullscreen_mode(self) -> FullscreenMode {
+        match self {
+            WindowMode::Windowed => FullscreenMode::Windowed,
+            WindowMode::Borderless => FullscreenMode::Borderless,
+            WindowMode::Exclusive => FullscreenMode::Exclusive
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GraphicsSettings {
+    pub width: u32, // Logical pixels of the window while windowed
+    pub height: u32,
+    pub window_mode: WindowMode,
+    pub vsync: bool,
+    pub msaa: u32, // Samples per pixel, 1 for none
+    pub render_distance: f32, // Blocks to the fog wall, the camera's far plane and chunk culling follow it
+    pub render_scale: f32, // 0.25 to 2, the scene's resolution relative to the window's
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_fps: Option<u32>
+}
+
+impl Default for GraphicsSettings {
+    fn default() -> Self {
+        GraphicsSettings {
+            width: 800,
+            height: 600,
+            window_mode: WindowMode::Windowed,
+            vsync: true,
+            msaa: 1,
+            render_distance: 96.0,
+            render_scale: 1.0,
+            max_fps: None
+        }
+    }
+}
+
+// Volumes from 0 to 1
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AudioSettings {
+    pub master: f32,
+    pub sfx: f32,
+    pub music: f32,
+    pub ui: f32,
+    pub ambient: f32
+}
+
+impl AudioSettings {
+    pub fn bus_volume(&self, bus: Bus) -> f32 {
+        match bus {
+            Bus::Sfx => self.sfx,
+            Bus::Music => self.music,
+            Bus::Ui => self.ui,
+            Bus::Ambient => self.ambient
+        }
+    }
+}
+
+impl Default for AudioSettings {
+    fn default() -> Self {
+        AudioSettings {
+            master: 1.0,
+            sfx: 1.0,
+            music: 0.7,
+            ui: 1.0,
+            ambient: 1.0
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControlSettings {
+    pub mouse_sensitivity: f32,
+    pub bindings: BTreeMap<String, Vec<String>> // Action to bindings in the form Binding::parse reads
+}
+
+impl Default for ControlSettings {
+    fn default() -> Self {
+        let defaults = ActionMap::with_defaults();
+        ControlSettings {
+            mouse_sensitivity: 1.0,
+            bindings: defaults.actions()
+                .map(|(action, bindings)| (action.to_owned(), bindings.iter().map(|b| b.to_string()).collect()))
+                .collect()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NetProtocol {
+    Udp, // UDP datagrams with a TCP connection for reliable messages
+    Quic
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkSettings {
+    pub server_address: String, // Host and port, I.E. "play.example.com:25565"
+    pub protocol: NetProtocol,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_name: Option<String> // For QUIC's certificate check, the host in server_address by default
+}
+
+impl Default for NetworkSettings {
+    fn default() -> Self {
+        NetworkSettings {
+            server_address: String::from("127.0.0.1:25565"),
+            protocol: NetProtocol::Udp,
+            server_name: None
+        }
+    }
+}
+
+impl NetworkSettings {
+    // Resolves server_address, which may take a DNS lookup
+    pub fn socket_addr(&self) -> Result<SocketAddr, String> {
+        self.server_address.to_socket_addrs()
+            .map_err(|e| format!("{}: {}", self.server_address, e))?
+            .next()
+            .ok_or_else(|| format!("{} didn't resolve to an address", self.server_address))
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        match self.protocol {
+            NetProtocol::Udp => Protocol::UdpTcp,
+            NetProtocol::Quic => {
+                let host = self.server_address.rsplit_once(':').map_or(self.server_address.as_str(), |(h, _)| h);
+                Protocol::Quic(QuicSettings::new(self.server_name.as_deref().unwrap_or(host)))
+            }
+        }
+    }
+}
+
+// The user's settings file. Missing fields and sections take their defaults, so files from older versions
+// still load, and out of range values are reset with a warning by load.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct Settings {
+    pub graphics: GraphicsSettings,
+    pub audio: AudioSettings,
+    pub controls: ControlSettings,
+    pub network: NetworkSettings
+}
+
+impl Settings {
+    // Defaults when the file doesn't exist yet
+    pub fn load(path: &Path) -> Result<Settings, ConfigError> {
+        let text = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Settings::default()),
+            Err(e) => return Err(ConfigError::Io(path.to_owned(), e.to_string()))
+        };
+        let mut settings: Settings = toml::from_str(&text).map_err(|e| ConfigError::Parse(path.to_owned(), e.to_string()))?;
+        for problem in settings.validate() {
+            log::warn!("{}: {}", path.display(), problem);
+        }
+
+        Ok(settings)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        let text = toml::to_string_pretty(self).map_err(|e| ConfigError::Parse(path.to_owned(), e.to_string()))?;
+        if let Some(dir) = path.parent().filter(|d| !d.as_os_str().is_empty()) {
+            fs::create_dir_all(dir).map_err(|e| ConfigError::Io(dir.to_owned(), e.to_string()))?;
+        }
+        write_replacing(path, text.as_bytes()).map_err(|e| match e {
+            SaveError::Io(path, e) | SaveError::Corrupt(path, e) => ConfigError::Io(path, e)
+        })
+    }
+
+    // Resets values out of range to their defaults and drops bindings that don't parse, returning what
+    // was wrong
+    pub fn validate(&mut self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let defaults = Settings::default();
+
+        let graphics = &mut self.graphics;
+        if graphics.width == 0 || graphics.height == 0 {
+            problems.push(format!("Window size {}x{} is empty", graphics.width, graphics.height));
+            graphics.width = defaults.graphics.width;
+            graphics.height = defaults.graphics.height;
+        }
+        if !(MIN_RENDER_SCALE..=MAX_RENDER_SCALE).contains(&graphics.render_scale) {
+            problems.push(format!("Render scale {} is outside 0.25 to 2", graphics.render_scale));
+            graphics.render_scale = defaults.graphics.render_scale;
+        }
+        if !graphics.msaa.is_power_of_two() || graphics.msaa > 64 {
+            problems.push(format!("MSAA {} isn't a power of two up to 64", graphics.msaa));
+            graphics.msaa = defaults.graphics.msaa;
+        }
+        if !(MIN_RENDER_DISTANCE..=MAX_RENDER_DISTANCE).contains(&graphics.render_distance) {
+            problems.push(format!("Render distance {} is outside {} to {}", graphics.render_distance, MIN_RENDER_DISTANCE,
+                                  MAX_RENDER_DISTANCE));
+            graphics.render_distance = defaults.graphics.render_distance;
+        }
+        if graphics.max_fps == Some(0) {
+            problems.push(String::from("max_fps is 0"));
+            graphics.max_fps = None;
+        }
+
+        let audio = &mut self.audio;
+        for (name, volume) in [("master", &mut audio.master), ("sfx", &mut audio.sfx), ("music", &mut audio.music),
+                               ("ui", &mut audio.ui), ("ambient", &mut audio.ambient)] {
+            if !(0.0..=1.0).contains(volume) {
+                problems.push(format!("Volume {} = {} is outside 0 to 1", name, volume));
+                *volume = match volume.is_nan() {
+                    true => 0.0, // clamp keeps NaN
+                    false => volume.clamp(0.0, 1.0)
+                };
+            }
+        }
+
+        if self.controls.mouse_sensitivity.is_nan() || self.controls.mouse_sensitivity <= 0.0 {
+            problems.push(format!("Mouse sensitivity {} isn't positive", self.controls.mouse_sensitivity));
+            self.controls.mouse_sensitivity = defaults.controls.mouse_sensitivity;
+        }
+        for (action, bindings) in self.controls.bindings.iter_mut() {
+            bindings.retain(|b| {
+                let valid = Binding::parse(b).is_some();
+                if !valid {
+                    problems.push(format!("Unknown binding {} for {}", b, action));
+                }
+                valid
+            });
+        }
+
+        if self.network.server_address.rsplit_once(':').map_or(true, |(_, port)| port.parse::<u16>().is_err()) {
+            problems.push(format!("Server address {} has no port", self.network.server_address));
+            self.network.server_address = defaults.network.server_address;
+        }
+
+        problems
+    }
+
+    // Window size and mode, vsync, MSAA, render scale and the frame cap. Render distance is up to the game.
+    pub fn apply_to_renderer(&self, config: &mut RendererConfig) {
+        config.window.size = (self.graphics.width, self.graphics.height);
+        config.window.fullscreen = self.graphics.window_mode.fullscreen_mode();
+        config.present_mode = match self.graphics.vsync {
+            true => PresentMode::Fifo,
+            false => PresentMode::Mailbox // Falls back to Fifo where unsupported
+        };
+        config.msaa = self.graphics.msaa;
+        config.render_scale = self.graphics.render_scale;
+        config.max_fps = self.graphics.max_fps;
+    }
+
+    // The same as apply_to_renderer on a running renderer, I.E. after a settings menu changed them. The
+    // swapchain and anything sized or sampled like it are rebuilt before the next frame.
+    pub fn apply_to_running(&self, renderer: &mut CubulousRenderer) {
+        let fullscreen = self.graphics.window_mode.fullscreen_mode();
+        if renderer.fullscreen() != fullscreen {
+            renderer.set_fullscreen(fullscreen);
+        }
+        if fullscreen == FullscreenMode::Windowed && renderer.window_size() != (self.graphics.width, self.graphics.height) {
+            renderer.set_window_size(self.graphics.width, self.graphics.height);
+        }
+        renderer.set_vsync(self.graphics.vsync);
+        renderer.set_msaa(self.graphics.msaa);
+        renderer.set_render_scale(self.graphics.render_scale);
+        renderer.set_max_fps(self.graphics.max_fps);
+    }
+
+    // The bindings as an ActionMap, I.E. for InputState::bindings. Actions missing from the file keep no
+    // bindings, so unbinding an action in the file sticks.
+    pub fn action_map(&self) -> ActionMap {
+        let mut map = ActionMap::new();
+        for (action, bindings) in self.controls.bindings.iter() {
+            for binding in bindings.iter().filter_map(|b| Binding::parse(b)) {
+                map.bind(action, binding);
+            }
+        }
+        map
+    }
+
+    // Stores a rebound ActionMap, I.E. from a controls menu, for the next save
+    pub fn set_action_map(&mut self, map: &ActionMap) {
+        self.controls.bindings = map.actions()
+            .map(|(action, bindings)| (action.to_owned(), bindings.iter().map(|b| b.to_string()).collect()))
+            .collect();
+    }
+
+    pub fn apply_to_audio(&self, audio: &mut Audio) {
+        audio.set_master_volume(self.audio.master);
+        for bus in Bus::ALL {
+            audio.set_bus_volume(bus, self.audio.bus_volume(bus));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_are_valid() {
+        let mut settings = Settings::default();
+        assert!(settings.validate().is_empty());
+        assert_eq!(settings, Settings::default());
+    }
+
+    #[test]
+    fn out_of_range_values_are_reset() {
+        let mut settings = Settings::default();
+        settings.graphics.width = 0;
+        settings.graphics.render_scale = 3.0;
+        settings.graphics.msaa = 3;
+        settings.graphics.render_distance = 4.0;
+        settings.graphics.max_fps = Some(0);
+        settings.audio.music = 1.5;
+        settings.audio.sfx = f32::NAN;
+        settings.controls.mouse_sensitivity = -1.0;
+        settings.controls.bindings.insert(String::from("jump"), vec![String::from("key:Space"), String::from("key:Nope")]);
+        settings.network.server_address = String::from("localhost");
+
+        assert_eq!(settings.validate().len(), 10);
+        let defaults = Settings::default();
+        assert_eq!(settings.graphics, defaults.graphics);
+        assert_eq!(settings.audio.music, 1.0);
+        assert_eq!(settings.audio.sfx, 0.0);
+        assert_eq!(settings.controls.mouse_sensitivity, defaults.controls.mouse_sensitivity);
+        assert_eq!(settings.controls.bindings["jump"], vec![String::from("key:Space")]);
+        assert_eq!(settings.network, defaults.network);
+        assert!(settings.validate().is_empty());
+    }
+
+    #[test]
+    fn toml_round_trip() {
+        let mut settings = Settings::default();
+        settings.graphics.window_mode = WindowMode::Borderless;
+        settings.graphics.max_fps = Some(144);
+        settings.audio.ui = 0.25;
+        settings.network.protocol = NetProtocol::Quic;
+        let text = toml::to_string_pretty(&settings).unwrap();
+        assert_eq!(toml::from_str::<Settings>(&text).unwrap(), settings);
+    }
+
+    #[test]
+    fn missing_fields_take_defaults() {
+        let settings: Settings = toml::from_str("[graphics]\nvsync = false\n").unwrap();
+        assert!(!settings.graphics.vsync);
+        assert_eq!(settings.audio, AudioSettings::default());
+        assert_eq!(settings.graphics.width, GraphicsSettings::default().width);
+    }
+
+    #[test]
+    fn action_map_round_trip() {
+        let settings = Settings::default();
+        let mut copy = Settings::default();
+        copy.controls.bindings.clear();
+        copy.set_action_map(&settings.action_map());
+        assert_eq!(copy.controls, settings.controls);
+    }
+}
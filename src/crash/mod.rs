@@ -0,0 +1,180 @@
+use std::backtrace::Backtrace;
+use std::fmt::Write as _;
+use std::fs;
+use std::panic::{self, PanicInfo};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::thread;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use log::{Level, Log, Metadata, Record};
+
+use crate::renderer::capabilities::DeviceCapabilities;
+use crate::renderer::gpu::GpuInfo;
+
+const RECENT_LOG_LINES: usize = 200;
+const RECENT_VALIDATION_MESSAGES: usize = 20;
+const RECORDED_LEVEL: Level = Level::Info; // Kept for crash reports whatever RUST_LOG lets through to stderr
+const VALIDATION_TARGET: &str = "vulkan"; // What the debug messenger logs under
+
+// The newest lines, the oldest is overwritten once it's full
+struct Ring {
+    lines: Vec<String>,
+    next: usize, // Where the next line goes, also the oldest once full
+    capacity: usize
+}
+
+impl Ring {
+    const fn new(capacity: usize) -> Ring {
+        Ring {
+            lines: Vec::new(),
+            next: 0,
+            capacity
+        }
+    }
+
+    fn push(&mut self, line: String) {
+        match self.lines.len() < self.capacity {
+            true => self.lines.push(line),
+            false => self.lines[self.next] = line
+        }
+        self.next = (self.next + 1) % self.capacity;
+    }
+
+    // Oldest first
+    fn iter(&self) -> impl Iterator<Item = &String> {
+        let (newer, older) = self.lines.split_at(self.next);
+        older.iter().chain(newer.iter())
+    }
+}
+
+// What's written into a report besides the panic itself
+struct Diagnostics {
+    log: Ring,
+    validation: Ring,
+    device: Option<String> // Set once the renderer picked a GPU
+}
+
+static DIAGNOSTICS: Mutex<Diagnostics> = Mutex::new(Diagnostics {
+    log: Ring::new(RECENT_LOG_LINES),
+    validation: Ring::new(RECENT_VALIDATION_MESSAGES),
+    device: None
+});
+
+// A panic while a thread held the lock, I.E. one inside a logger call, would deadlock the hook with lock()
+fn diagnostics() -> Option<MutexGuard<'static, Diagnostics>> {
+    match DIAGNOSTICS.try_lock() {
+        Ok(d) => Some(d),
+        Err(TryLockError::Poisoned(p)) => Some(p.into_inner()),
+        Err(TryLockError::WouldBlock) => None
+    }
+}
+
+// env_logger's output, with recent records kept for crash reports
+struct CrashLogger {
+    inner: env_logger::Logger,
+    start: Instant
+}
+
+impl Log for CrashLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= RECORDED_LEVEL || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.inner.matches(record) {
+            self.inner.log(record);
+        }
+        if record.level() <= RECORDED_LEVEL {
+            let line = format!("[{:9.3}s {:5} {}] {}", self.start.elapsed().as_secs_f64(), record.level(), record.target(), record.args());
+            if let Ok(mut d) = DIAGNOSTICS.lock() {
+                if record.target() == VALIDATION_TARGET {
+                    d.validation.push(line.clone());
+                }
+                d.log.push(line);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+// In place of env_logger::init, RUST_LOG still filters what's printed
+pub fn init_logging() {
+    let inner = env_logger::Builder::from_default_env().build();
+    let max_level = inner.filter().max(RECORDED_LEVEL.to_level_filter());
+    match log::set_logger(Box::leak(Box::new(CrashLogger { inner, start: Instant::now() }))) {
+        Ok(()) => log::set_max_level(max_level),
+        Err(e) => eprintln!("Logging is already set up: {}", e)
+    }
+}
+
+// Panics anywhere in the process write a report into dir and abort, after printing the usual message
+pub fn install(dir: &Path) {
+    let dir = dir.to_owned();
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        match write_report(&dir, info) {
+            Ok(path) => eprintln!("Crash report written to {}", path.display()),
+            Err(e) => eprintln!("Couldn't write a crash report: {}", e)
+        }
+        std::process::abort();
+    }));
+}
+
+// The GPU and driver reports are made against, from PhysicalLayer once it picked one of gpus
+pub(crate) fn set_device(gpus: &[GpuInfo], selected: usize, capabilities: &DeviceCapabilities) {
+    let gpu = &gpus[selected];
+    let mut device = format!("{} ({:?}, {}, device {:#06x})\n", gpu.name, gpu.device_type,
+                             gpu.vendor_name().map_or_else(|| format!("vendor {:#06x}", gpu.vendor_id), String::from), gpu.device_id);
+    let _ = writeln!(device, "Driver {}, Vulkan {}.{}.{}", gpu.driver_version_text(), gpu.api_version.0, gpu.api_version.1, gpu.api_version.2);
+    let _ = writeln!(device, "{} MiB device local memory", gpu.vram >> 20);
+    let _ = writeln!(device, "{:?}", capabilities);
+    let _ = writeln!(device, "Other devices: {:?}", gpus.iter()
+        .filter(|g| g.index != gpu.index)
+        .map(|g| g.name.as_str())
+        .collect::<Vec<_>>());
+    if let Ok(mut d) = DIAGNOSTICS.lock() {
+        d.device = Some(device);
+    }
+}
+
+fn write_report(dir: &Path, info: &PanicInfo) -> Result<PathBuf, String> {
+    let message = match (info.payload().downcast_ref::<&str>(), info.payload().downcast_ref::<String>()) {
+        (Some(s), _) => s.to_string(),
+        (_, Some(s)) => s.clone(),
+        _ => String::from("<no message>")
+    };
+    let location = info.location().map_or_else(|| String::from("<unknown>"), |l| l.to_string());
+    let time = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+
+    let mut report = String::new();
+    let _ = writeln!(report, "{} {} crash report", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    let _ = writeln!(report, "Time: {} (seconds since the Unix epoch)", time);
+    let _ = writeln!(report, "Platform: {} {}", std::env::consts::OS, std::env::consts::ARCH);
+    let _ = writeln!(report, "Thread: {}", thread::current().name().unwrap_or("<unnamed>"));
+    let _ = writeln!(report, "Panic: {}\nAt: {}", message, location);
+    let _ = writeln!(report, "\nBacktrace:\n{}", Backtrace::force_capture());
+    match diagnostics() {
+        Some(d) => {
+            let _ = writeln!(report, "GPU:\n{}", d.device.as_deref().unwrap_or("<not picked yet>\n"));
+            let _ = writeln!(report, "Last validation messages:");
+            for line in d.validation.iter() {
+                let _ = writeln!(report, "{}", line);
+            }
+            let _ = writeln!(report, "\nRecent log:");
+            for line in d.log.iter() {
+                let _ = writeln!(report, "{}", line);
+            }
+        },
+        None => report.push_str("Diagnostics were locked by the panicking thread\n")
+    }
+
+    fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    let path = dir.join(format!("crash-{}.txt", time));
+    fs::write(&path, report).map_err(|e| format!("{}: {}", path.display(), e))?;
+    Ok(path)
+}
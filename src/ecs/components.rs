@@ -0,0 +1,101 @@
+use glam::{Mat4, Quat, Vec3};
+
+use crate::renderer::game_loop::Interpolate;
+use crate::renderer::mesh::MeshHandle;
+use crate::renderer::render_queue::MaterialHandle;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub scale: Vec3
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        translation: Vec3::ZERO,
+        rotation: Quat::IDENTITY,
+        scale: Vec3::ONE
+    };
+
+    pub fn from_translation(translation: Vec3) -> Transform {
+        Transform {
+            translation,
+            ..Transform::IDENTITY
+        }
+    }
+
+    // Turns to face target, keeping +Y up
+    pub fn looking_at(mut self, target: Vec3) -> Transform {
+        self.rotation = Quat::from_mat4(&Mat4::look_at_rh(self.translation, target, Vec3::Y).inverse());
+        self
+    }
+
+    pub fn matrix(&self) -> Mat4 {
+        Mat4::from_scale_rotation_translation(self.scale, self.rotation, self.translation)
+    }
+
+    // -Z, the direction cameras look in
+    pub fn forward(&self) -> Vec3 {
+        self.rotation * -Vec3::Z
+    }
+}
+
+impl Interpolate for Transform {
+    fn interpolate(&self, next: &Self, alpha: f32) -> Self {
+        Transform {
+            translation: self.translation.interpolate(&next.translation, alpha),
+            rotation: self.rotation.interpolate(&next.rotation, alpha),
+            scale: self.scale.interpolate(&next.scale, alpha)
+        }
+    }
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::IDENTITY
+    }
+}
+
+// The entity's Transform as of the previous tick, written by render::store_previous. Entities with one
+// are drawn between the two by render::extract.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PreviousTransform(pub Transform);
+
+// Draws a mesh at the entity's Transform
+#[derive(Clone, Copy, Debug)]
+pub struct MeshRenderer {
+    pub mesh: MeshHandle,
+    pub material: MaterialHandle,
+    pub visible: bool
+}
+
+impl MeshRenderer {
+    pub fn new(mesh: MeshHandle) -> MeshRenderer {
+        MeshRenderer {
+            mesh,
+            material: MaterialHandle::DEFAULT,
+            visible: true
+        }
+    }
+}
+
+// Views the scene from the entity's Transform. Only the first active camera is used.
+#[derive(Clone, Copy, Debug)]
+pub struct Camera {
+    pub fov_y: f32, // Radians
+    pub near: f32,
+    pub far: f32,
+    pub active: bool
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera {
+            fov_y: 45.0_f32.to_radians(),
+            near: 0.1,
+            far: 100.0,
+            active: true
+        }
+    }
+}
@@ -0,0 +1,174 @@
+pub mod components;
+pub mod render;
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+// Index into the world's entity slots. The generation is bumped whenever a slot is reused, so handles
+// to despawned entities stay invalid.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Entity {
+    index: u32,
+    generation: u32
+}
+
+impl Entity {
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+}
+
+// Type erased so the world can hold one storage per component type
+trait Storage {
+    fn remove(&mut self, index: u32);
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+// Indexed by entity index, None where the entity doesn't have the component
+struct ComponentVec<T>(Vec<Option<T>>);
+
+impl<T: 'static> Storage for ComponentVec<T> {
+    fn remove(&mut self, index: u32) {
+        if let Some(c) = self.0.get_mut(index as usize) {
+            *c = None;
+        }
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+// Entities and their components. Any 'static type can be a component, an entity has at most one of each.
+#[derive(Default)]
+pub struct World {
+    generations: Vec<u32>, // Current generation of each slot
+    alive: Vec<bool>,
+    free: Vec<u32>, // Slots of despawned entities
+    storages: HashMap<TypeId, Box<dyn Storage>>
+}
+
+impl World {
+    pub fn new() -> World {
+        World {
+            generations: Vec::new(),
+            alive: Vec::new(),
+            free: Vec::new(),
+            storages: HashMap::new()
+        }
+    }
+
+    pub fn spawn(&mut self) -> Entity {
+        match self.free.pop() {
+            Some(index) => {
+                let i = index as usize;
+                self.generations[i] += 1;
+                self.alive[i] = true;
+                Entity { index, generation: self.generations[i] }
+            },
+            None => {
+                self.generations.push(0);
+                self.alive.push(true);
+                Entity { index: self.generations.len() as u32 - 1, generation: 0 }
+            }
+        }
+    }
+
+    // Drops the entity's components, returns false if it was already despawned
+    pub fn despawn(&mut self, entity: Entity) -> bool {
+        if !self.is_alive(entity) {
+            return false;
+        }
+        for storage in self.storages.values_mut() {
+            storage.remove(entity.index);
+        }
+        self.alive[entity.index as usize] = false;
+        self.free.push(entity.index);
+
+        true
+    }
+
+    pub fn is_alive(&self, entity: Entity) -> bool {
+        let i = entity.index as usize;
+        i < self.alive.len() && self.alive[i] && self.generations[i] == entity.generation
+    }
+
+    // Replaces the component if the entity already has one. Panics if the entity was despawned.
+    pub fn insert<T: 'static>(&mut self, entity: Entity, component: T) {
+        assert!(self.is_alive(entity), "Inserting a component into a despawned entity");
+
+        let components = &mut self.storage_mut::<T>().0;
+        let i = entity.index as usize;
+        if i >= components.len() {
+            components.resize_with(i + 1, || None);
+        }
+        components[i] = Some(component);
+    }
+
+    pub fn remove<T: 'static>(&mut self, entity: Entity) -> Option<T> {
+        if !self.is_alive(entity) {
+            return None;
+        }
+        self.storage_mut::<T>().0.get_mut(entity.index as usize).and_then(|c| c.take())
+    }
+
+    pub fn get<T: 'static>(&self, entity: Entity) -> Option<&T> {
+        if !self.is_alive(entity) {
+            return None;
+        }
+        self.storage::<T>()?.0.get(entity.index as usize)?.as_ref()
+    }
+
+    pub fn get_mut<T: 'static>(&mut self, entity: Entity) -> Option<&mut T> {
+        if !self.is_alive(entity) {
+            return None;
+        }
+        self.storages.get_mut(&TypeId::of::<T>())?
+            .as_any_mut()
+            .downcast_mut::<ComponentVec<T>>()?
+            .0.get_mut(entity.index as usize)?
+            .as_mut()
+    }
+
+    // Every entity with a T
+    pub fn query<T: 'static>(&self) -> impl Iterator<Item = (Entity, &T)> {
+        let generations = &self.generations;
+        self.storage::<T>()
+            .into_iter()
+            .flat_map(|s| s.0.iter().enumerate())
+            .filter_map(move |(i, c)| c.as_ref().map(|c| (Entity { index: i as u32, generation: generations[i] }, c)))
+    }
+
+    pub fn query_mut<T: 'static>(&mut self) -> impl Iterator<Item = (Entity, &mut T)> {
+        let generations = &self.generations;
+        self.storages.get_mut(&TypeId::of::<T>())
+            .and_then(|s| s.as_any_mut().downcast_mut::<ComponentVec<T>>())
+            .into_iter()
+            .flat_map(|s| s.0.iter_mut().enumerate())
+            .filter_map(move |(i, c)| c.as_mut().map(|c| (Entity { index: i as u32, generation: generations[i] }, c)))
+    }
+
+    // Every entity with both an A and a B
+    pub fn query2<A: 'static, B: 'static>(&self) -> impl Iterator<Item = (Entity, &A, &B)> {
+        let bs = self.storage::<B>();
+        self.query::<A>()
+            .filter_map(move |(e, a)| bs?.0.get(e.index as usize)?.as_ref().map(|b| (e, a, b)))
+    }
+
+    fn storage<T: 'static>(&self) -> Option<&ComponentVec<T>> {
+        self.storages.get(&TypeId::of::<T>())?.as_any().downcast_ref::<ComponentVec<T>>()
+    }
+
+    fn storage_mut<T: 'static>(&mut self) -> &mut ComponentVec<T> {
+        self.storages.entry(TypeId::of::<T>())
+            .or_insert_with(|| Box::new(ComponentVec::<T>(Vec::new())))
+            .as_any_mut()
+            .downcast_mut::<ComponentVec<T>>()
+            .unwrap() // Keyed by the type it holds
+    }
+}
@@ -0,0 +1,45 @@
+use crate::ecs::components::{Camera, MeshRenderer, PreviousTransform, Transform};
+use crate::ecs::{Entity, World};
+use crate::renderer::frame::Frame;
+use crate::renderer::game_loop::Interpolate;
+
+// Call at the start of every tick, before anything moves. Gives every entity with a Transform a
+// PreviousTransform so extract can blend between ticks.
+pub fn store_previous(world: &mut World) {
+    let transforms: Vec<(Entity, Transform)> = world.query::<Transform>().map(|(e, t)| (e, *t)).collect();
+    for (entity, transform) in transforms {
+        world.insert(entity, PreviousTransform(transform));
+    }
+}
+
+// Where the entity is drawn this frame, between its previous and current Transform by Frame::alpha
+fn render_transform(world: &World, entity: Entity, transform: &Transform, alpha: f32) -> Transform {
+    match world.get::<PreviousTransform>(entity) {
+        Some(previous) => previous.0.interpolate(transform, alpha),
+        None => *transform
+    }
+}
+
+// Copies the scene into the frame: the active camera entity drives the renderer's camera and every
+// visible MeshRenderer with a Transform is queued. Without a camera entity the renderer's camera is
+// left alone, so camera controllers keep working.
+pub fn extract(world: &World, frame: &mut Frame) {
+    let alpha = frame.alpha();
+    if let Some((entity, transform, camera)) = world.query2::<Transform, Camera>().find(|(_, _, c)| c.active) {
+        let transform = render_transform(world, entity, transform, alpha);
+        let renderer_camera = frame.camera();
+        renderer_camera.look_at(transform.translation, transform.translation + transform.forward());
+        if renderer_camera.fov_y != camera.fov_y {
+            renderer_camera.set_fov(camera.fov_y);
+        }
+        if renderer_camera.near != camera.near || renderer_camera.far != camera.far {
+            renderer_camera.set_clip_planes(camera.near, camera.far);
+        }
+    }
+
+    for (entity, transform, mesh_renderer) in world.query2::<Transform, MeshRenderer>() {
+        if mesh_renderer.visible {
+            frame.draw(mesh_renderer.mesh, render_transform(world, entity, transform, alpha).matrix(), mesh_renderer.material);
+        }
+    }
+}
@@ -0,0 +1,309 @@
+pub mod scenes;
+
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use crate::renderer::config::RendererConfig;
+use crate::renderer::error::RendererError;
+use crate::renderer::renderer::CubulousRenderer;
+
+pub const GOLDEN_DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden"); // <scene>.png, failures write <scene>.actual.png and <scene>.diff.png beside it
+pub const GOLDEN_SIZE: (u32, u32) = (256, 256);
+
+// How far output may drift from a golden image before it fails, I.E. from driver rounding differences
+#[derive(Clone, Copy, Debug)]
+pub struct Tolerance {
+    pub channel: u8, // Largest per channel difference that doesn't count the pixel as different
+    pub max_differing: f32 // Fraction of pixels that may differ
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Tolerance {
+            channel: 2,
+            max_differing: 0.001
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum GoldenError {
+    Io(PathBuf, String),
+    Png(PathBuf, String),
+    SizeMismatch { expected: (u32, u32), actual: (u32, u32) },
+    Renderer(RendererError)
+}
+
+impl fmt::Display for GoldenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GoldenError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            GoldenError::Png(path, e) => write!(f, "{}: invalid PNG: {}", path.display(), e),
+            GoldenError::SizeMismatch { expected, actual } =>
+                write!(f, "Expected a {}x{} image but got {}x{}", expected.0, expected.1, actual.0, actual.1),
+            GoldenError::Renderer(e) => write!(f, "{}", e)
+        }
+    }
+}
+
+impl std::error::Error for GoldenError {}
+
+impl From<RendererError> for GoldenError {
+    fn from(e: RendererError) -> Self {
+        GoldenError::Renderer(e)
+    }
+}
+
+// Tightly packed RGBA8 pixels, rows from the top
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>
+}
+
+impl Image {
+    pub fn load_png(path: &Path) -> Result<Image, GoldenError> {
+        let file = File::open(path).map_err(|e| GoldenError::Io(path.to_owned(), e.to_string()))?;
+        let mut decoder = png::Decoder::new(file);
+        decoder.set_transformations(png::Transformations::EXPAND); // Palettes and low bit depths to 8 bit
+        let mut reader = decoder.read_info().map_err(|e| GoldenError::Png(path.to_owned(), e.to_string()))?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).map_err(|e| GoldenError::Png(path.to_owned(), e.to_string()))?;
+        buf.truncate(info.buffer_size());
+
+        let pixels = match info.color_type {
+            png::ColorType::Rgba => buf,
+            png::ColorType::Rgb => buf.chunks(3).flat_map(|p| [p[0], p[1], p[2], 255]).collect(),
+            png::ColorType::GrayscaleAlpha => buf.chunks(2).flat_map(|p| [p[0], p[0], p[0], p[1]]).collect(),
+            png::ColorType::Grayscale => buf.iter().flat_map(|p| [*p, *p, *p, 255]).collect(),
+            png::ColorType::Indexed => return Err(GoldenError::Png(path.to_owned(), "Unexpanded palette".to_owned()))
+        };
+
+        Ok(Image {
+            width: info.width,
+            height: info.height,
+            pixels
+        })
+    }
+
+    pub fn save_png(&self, path: &Path) -> Result<(), GoldenError> {
+        let file = File::create(path).map_err(|e| GoldenError::Io(path.to_owned(), e.to_string()))?;
+        let mut encoder = png::Encoder::new(BufWriter::new(file), self.width, self.height);
+        encoder.set_color(png::ColorType::Rgba);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.write_header()
+            .and_then(|mut w| w.write_image_data(&self.pixels))
+            .map_err(|e| GoldenError::Png(path.to_owned(), e.to_string()))
+    }
+
+    // FNV-1a over the size and pixels. Identical hashes skip the per pixel comparison.
+    pub fn hash(&self) -> u64 {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in self.width.to_le_bytes().iter().chain(self.height.to_le_bytes().iter()).chain(self.pixels.iter()) {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        hash
+    }
+
+    fn pixel(&self, i: usize) -> [u8; 4] {
+        [self.pixels[i * 4], self.pixels[i * 4 + 1], self.pixels[i * 4 + 2], self.pixels[i * 4 + 3]]
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PixelDiff {
+    pub x: u32,
+    pub y: u32,
+    pub expected: [u8; 4],
+    pub actual: [u8; 4]
+}
+
+#[derive(Clone, Debug)]
+pub struct Comparison {
+    pub width: u32,
+    pub height: u32,
+    pub differing: Vec<PixelDiff>, // Pixels with a channel off by more than the tolerance
+    pub max_channel_difference: u8 // Over every pixel, including ones within tolerance
+}
+
+impl Comparison {
+    pub fn passes(&self, tolerance: Tolerance) -> bool {
+        self.differing.len() as f32 <= tolerance.max_differing * (self.width * self.height) as f32
+    }
+
+    // The expected image dimmed to grey with differing pixels in red
+    pub fn diff_image(&self, expected: &Image) -> Image {
+        let mut pixels: Vec<u8> = expected.pixels.chunks(4)
+            .flat_map(|p| {
+                let grey = ((p[0] as u32 + p[1] as u32 + p[2] as u32) / 12) as u8;
+                [grey, grey, grey, 255]
+            })
+            .collect();
+        for d in self.differing.iter() {
+            let i = ((d.y * self.width + d.x) * 4) as usize;
+            pixels[i..i + 4].copy_from_slice(&[255, 0, 0, 255]);
+        }
+
+        Image {
+            width: self.width,
+            height: self.height,
+            pixels
+        }
+    }
+}
+
+pub fn compare(expected: &Image, actual: &Image, tolerance: Tolerance) -> Result<Comparison, GoldenError> {
+    if (expected.width, expected.height) != (actual.width, actual.height) {
+        return Err(GoldenError::SizeMismatch {
+            expected: (expected.width, expected.height),
+            actual: (actual.width, actual.height)
+        });
+    }
+
+    let mut comparison = Comparison {
+        width: expected.width,
+        height: expected.height,
+        differing: Vec::new(),
+        max_channel_difference: 0
+    };
+    if expected.hash() == actual.hash() {
+        return Ok(comparison);
+    }
+    for i in 0..(expected.width * expected.height) as usize {
+        let (e, a) = (expected.pixel(i), actual.pixel(i));
+        let difference = e.iter().zip(a.iter()).map(|(e, a)| e.abs_diff(*a)).max().unwrap();
+        comparison.max_channel_difference = comparison.max_channel_difference.max(difference);
+        if difference > tolerance.channel {
+            comparison.differing.push(PixelDiff {
+                x: i as u32 % expected.width,
+                y: i as u32 / expected.width,
+                expected: e,
+                actual: a
+            });
+        }
+    }
+
+    Ok(comparison)
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Passed { hash: u64 },
+    Failed(Comparison),
+    Written, // Updating was requested, the output is the new golden image
+    Missing // No golden image to compare against, the output is written as <scene>.actual.png
+}
+
+impl Outcome {
+    pub fn passed(&self) -> bool {
+        matches!(self, Outcome::Passed { .. } | Outcome::Written)
+    }
+}
+
+#[derive(Debug)]
+pub struct Report {
+    pub scene: &'static str,
+    pub outcome: Outcome
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.outcome {
+            Outcome::Passed { hash } => write!(f, "{}: passed ({:016x})", self.scene, hash),
+            Outcome::Written => write!(f, "{}: golden image written", self.scene),
+            Outcome::Missing => write!(f, "{}: no golden image, see {}/{}.actual.png and rerun with --update to accept it",
+                                       self.scene, GOLDEN_DIR, self.scene),
+            Outcome::Failed(c) => {
+                writeln!(f, "{}: {} of {} pixels differ, up to {} per channel", self.scene, c.differing.len(),
+                         c.width * c.height, c.max_channel_difference)?;
+                for d in c.differing.iter().take(8) {
+                    writeln!(f, "  ({}, {}) expected {:?} got {:?}", d.x, d.y, d.expected, d.actual)?;
+                }
+                if c.differing.len() > 8 {
+                    writeln!(f, "  ...")?;
+                }
+                write!(f, "  see {}/{}.diff.png", GOLDEN_DIR, self.scene)
+            }
+        }
+    }
+}
+
+// Renders one scene in a fresh headless renderer and returns the frame
+pub fn render(scene: &scenes::Scene) -> Result<Image, GoldenError> {
+    let config = RendererConfig {
+        validation: false, // Messages don't change the output, and CI machines rarely have the layers
+        ..RendererConfig::default()
+    };
+    let mut renderer = CubulousRenderer::new_headless(GOLDEN_SIZE.0, GOLDEN_SIZE.1, config)?;
+    (scene.setup)(&mut renderer)?;
+    renderer.render_frame()?;
+    let (width, height, pixels) = renderer.read_frame()?;
+
+    Ok(Image { width, height, pixels })
+}
+
+// Renders every reference scene and checks it against its golden image. update rewrites the golden
+// images instead, after an intended change to the output. Scenes without a golden image only get one
+// written with update, so a checkout missing them fails rather than passing against itself.
+pub fn run(update: bool, tolerance: Tolerance) -> Result<Vec<Report>, GoldenError> {
+    let dir = Path::new(GOLDEN_DIR);
+    std::fs::create_dir_all(dir).map_err(|e| GoldenError::Io(dir.to_owned(), e.to_string()))?;
+
+    let mut reports = Vec::with_capacity(scenes::SCENES.len());
+    for scene in scenes::SCENES.iter() {
+        let actual = render(scene)?;
+        let golden_path = dir.join(format!("{}.png", scene.name));
+        let outcome = match (update, golden_path.exists()) {
+            (true, _) => {
+                actual.save_png(&golden_path)?;
+                Outcome::Written
+            },
+            (false, false) => {
+                actual.save_png(&dir.join(format!("{}.actual.png", scene.name)))?;
+                Outcome::Missing
+            },
+            (false, true) => {
+                let expected = Image::load_png(&golden_path)?;
+                let comparison = compare(&expected, &actual, tolerance)?;
+                match comparison.passes(tolerance) {
+                    true => Outcome::Passed { hash: actual.hash() },
+                    false => {
+                        actual.save_png(&dir.join(format!("{}.actual.png", scene.name)))?;
+                        comparison.diff_image(&expected).save_png(&dir.join(format!("{}.diff.png", scene.name)))?;
+                        Outcome::Failed(comparison)
+                    }
+                }
+            }
+        };
+        reports.push(Report { scene: scene.name, outcome });
+    }
+
+    Ok(reports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The same check as --golden, under cargo test. Machines without a Vulkan device skip it, and
+    // UPDATE_GOLDEN=1 rewrites the golden images like --update.
+    #[test]
+    fn golden_images() {
+        let reports = match run(std::env::var_os("UPDATE_GOLDEN").is_some(), Tolerance::default()) {
+            Ok(reports) => reports,
+            Err(GoldenError::Renderer(e @ (RendererError::LoaderNotFound(_) | RendererError::IncompatibleDriver
+                                           | RendererError::NoSuitableDevice))) => {
+                eprintln!("Skipping the golden images: {}", e);
+                return;
+            },
+            Err(e) => panic!("{}", e)
+        };
+
+        let failed: Vec<String> = reports.iter().filter(|r| !r.outcome.passed()).map(|r| r.to_string()).collect();
+        assert!(failed.is_empty(), "{}", failed.join("\n"));
+    }
+}
@@ -0,0 +1,110 @@
+use glam::{Mat4, Quat, Vec3, Vec4};
+
+use crate::renderer::error::RendererError;
+use crate::renderer::instance::Instance;
+use crate::renderer::light::Light;
+use crate::renderer::material::{MaterialDesc, MaterialParams, ShaderVariant};
+use crate::renderer::render_queue::MaterialHandle;
+use crate::renderer::renderer::CubulousRenderer;
+use crate::renderer::vertex::Vertex;
+
+// A reference scene, setup fills a fresh renderer's queue, camera and lights for a single frame
+pub struct Scene {
+    pub name: &'static str,
+    pub setup: fn(&mut CubulousRenderer) -> Result<(), RendererError>
+}
+
+// Every scene the harness checks. Adding one needs a golden image, which the first run writes.
+pub const SCENES: [Scene; 4] = [
+    Scene { name: "clear", setup: clear },
+    Scene { name: "vertex_colors", setup: vertex_colors },
+    Scene { name: "lit_instances", setup: lit_instances },
+    Scene { name: "debug_lines", setup: debug_lines }
+];
+
+fn quad(color: [f32; 3]) -> ([Vertex; 4], [u32; 6]) {
+    let corner = |x: f32, y: f32| Vertex {
+        pos: [x, y, 0.0],
+        normal: [0.0, 0.0, 1.0],
+        uv: [x + 0.5, y + 0.5],
+        tangent: [1.0, 0.0, 0.0, 1.0],
+        color,
+        layer: 0
+    };
+    ([corner(-0.5, -0.5), corner(0.5, -0.5), corner(0.5, 0.5), corner(-0.5, 0.5)], [0, 1, 2, 2, 3, 0])
+}
+
+// Nothing queued, only the clear color through post-processing
+fn clear(renderer: &mut CubulousRenderer) -> Result<(), RendererError> {
+    renderer.set_camera(Vec3::new(0.0, 0.0, 2.0), Vec3::ZERO, 45.0_f32.to_radians());
+    Ok(())
+}
+
+// Interpolated vertex colors on an unlit quad
+fn vertex_colors(renderer: &mut CubulousRenderer) -> Result<(), RendererError> {
+    let (mut vertices, indices) = quad([1.0; 3]);
+    for (v, color) in vertices.iter_mut().zip([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0], [1.0, 1.0, 1.0]]) {
+        v.color = color;
+    }
+    let mesh = renderer.upload_mesh(&vertices, &indices)?;
+    renderer.set_camera(Vec3::new(0.0, 0.0, 2.0), Vec3::ZERO, 45.0_f32.to_radians());
+    renderer.render_queue().push(mesh, Mat4::IDENTITY, MaterialHandle::DEFAULT);
+
+    Ok(())
+}
+
+// Instanced, tinted quads under a shadow casting directional light and a point light
+fn lit_instances(renderer: &mut CubulousRenderer) -> Result<(), RendererError> {
+    let (vertices, indices) = quad([1.0; 3]);
+    let mesh = renderer.upload_mesh(&vertices, &indices)?;
+    let material = renderer.create_material(&MaterialDesc {
+        shader: ShaderVariant::LIT,
+        params: MaterialParams::new([1.0, 1.0, 1.0, 1.0], 0.0, 0.8),
+        ..Default::default()
+    })?;
+    renderer.set_lights(&[
+        Light::Directional {
+            direction: Vec3::new(-0.4, -1.0, -0.6),
+            color: Vec3::ONE,
+            intensity: 1.0,
+            cast_shadows: true
+        },
+        Light::Point {
+            position: Vec3::new(0.0, 1.0, 1.0),
+            color: Vec3::new(1.0, 0.6, 0.3),
+            intensity: 4.0,
+            range: 6.0
+        }
+    ]);
+    renderer.set_camera(Vec3::new(0.0, 2.0, 4.0), Vec3::ZERO, 45.0_f32.to_radians());
+
+    let floor = Mat4::from_rotation_x(-90.0_f32.to_radians()) * Mat4::from_scale(Vec3::splat(6.0));
+    renderer.render_queue().push(mesh, floor, material);
+    let instances: Vec<Instance> = (0..9)
+        .map(|i| {
+            let (x, z) = ((i % 3) as f32 - 1.0, (i / 3) as f32 - 1.0);
+            let transform = Mat4::from_scale_rotation_translation(Vec3::splat(0.6),
+                                                                   Quat::from_rotation_y(i as f32 * 0.3),
+                                                                   Vec3::new(x * 1.2, 0.3, z * 1.2));
+            Instance::new(transform, Vec4::new(x * 0.5 + 0.5, 0.5, z * 0.5 + 0.5, 1.0), i)
+        })
+        .collect();
+    renderer.render_queue().push_instanced(mesh, &instances, material);
+
+    Ok(())
+}
+
+// Depth tested debug lines over a quad
+fn debug_lines(renderer: &mut CubulousRenderer) -> Result<(), RendererError> {
+    let (vertices, indices) = quad([0.3; 3]);
+    let mesh = renderer.upload_mesh(&vertices, &indices)?;
+    renderer.set_camera(Vec3::new(2.0, 2.0, 3.0), Vec3::ZERO, 45.0_f32.to_radians());
+    renderer.render_queue().push(mesh, Mat4::IDENTITY, MaterialHandle::DEFAULT);
+
+    let lines = renderer.debug_draw();
+    lines.grid(Vec3::new(0.0, -0.5, 0.0), 4.0, 8, Vec3::splat(0.6));
+    lines.axes(Mat4::IDENTITY, 1.0);
+    lines.sphere(Vec3::ZERO, 0.75, Vec3::new(1.0, 1.0, 0.0));
+
+    Ok(())
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde::de::value::{Error as ValueError, StrDeserializer};
+use serde::de::IntoDeserializer;
+use serde::Deserialize;
+use winit::event::{MouseButton, VirtualKeyCode};
+
+use crate::input::gamepad::{GamepadAxis, GamepadButton};
+
+// Gamepad bindings match any connected controller
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Binding {
+    Key(VirtualKeyCode),
+    Mouse(MouseButton),
+    GamepadButton(GamepadButton),
+    GamepadAxis { axis: GamepadAxis, positive: bool } // One direction of the axis, I.E. left stick up
+}
+
+// Variant names through serde, which winit and gilrs derive it for, so names match their enums
+fn parse_name<'de, T: Deserialize<'de>>(name: &'de str) -> Option<T> {
+    let deserializer: StrDeserializer<'de, ValueError> = name.into_deserializer();
+    T::deserialize(deserializer).ok()
+}
+
+impl Binding {
+    // The form Display writes: "key:W", "mouse:Right" or "mouse:4" for other buttons, "button:South", and
+    // "axis:LeftStickY+" or "axis:LeftStickY-" for one direction of an axis
+    pub fn parse(text: &str) -> Option<Binding> {
+        let (kind, name) = text.split_once(':')?;
+        match kind {
+            "key" => parse_name(name).map(Binding::Key),
+            "mouse" => match name {
+                "Left" => Some(Binding::Mouse(MouseButton::Left)),
+                "Right" => Some(Binding::Mouse(MouseButton::Right)),
+                "Middle" => Some(Binding::Mouse(MouseButton::Middle)),
+                _ => name.parse().ok().map(|n| Binding::Mouse(MouseButton::Other(n)))
+            },
+            "button" => parse_name(name).map(Binding::GamepadButton),
+            "axis" => {
+                let positive = match name.chars().last()? {
+                    '+' => true,
+                    '-' => false,
+                    _ => return None
+                };
+                parse_name(&name[..name.len() - 1]).map(|axis| Binding::GamepadAxis { axis, positive })
+            },
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for Binding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Binding::Key(key) => write!(f, "key:{:?}", key),
+            Binding::Mouse(MouseButton::Other(n)) => write!(f, "mouse:{}", n),
+            Binding::Mouse(button) => write!(f, "mouse:{:?}", button),
+            Binding::GamepadButton(button) => write!(f, "button:{:?}", button),
+            Binding::GamepadAxis { axis, positive } => write!(f, "axis:{:?}{}", axis, if *positive { '+' } else { '-' })
+        }
+    }
+}
+
+// Named actions, I.E. "move_forward", mapped to the inputs that trigger them so application code
+// doesn't hardcode keys
+#[derive(Clone, Debug, Default)]
+pub struct ActionMap {
+    bindings: HashMap<String, Vec<Binding>>
+}
+
+impl ActionMap {
+    pub fn new() -> ActionMap {
+        ActionMap {
+            bindings: HashMap::new()
+        }
+    }
+
+    // WASD movement with space and shift for up and down, or the left stick with A and B on gamepads.
+    // The right stick looks around.
+    pub fn with_defaults() -> ActionMap {
+        let mut map = ActionMap::new();
+        map.bind("move_forward", Binding::Key(VirtualKeyCode::W));
+        map.bind("move_back", Binding::Key(VirtualKeyCode::S));
+        map.bind("move_left", Binding::Key(VirtualKeyCode::A));
+        map.bind("move_right", Binding::Key(VirtualKeyCode::D));
+        map.bind("move_up", Binding::Key(VirtualKeyCode::Space));
+        map.bind("move_down", Binding::Key(VirtualKeyCode::LShift));
+        map.bind("look", Binding::Mouse(MouseButton::Right));
+
+        map.bind("move_forward", Binding::GamepadAxis { axis: GamepadAxis::LeftStickY, positive: true });
+        map.bind("move_back", Binding::GamepadAxis { axis: GamepadAxis::LeftStickY, positive: false });
+        map.bind("move_left", Binding::GamepadAxis { axis: GamepadAxis::LeftStickX, positive: false });
+        map.bind("move_right", Binding::GamepadAxis { axis: GamepadAxis::LeftStickX, positive: true });
+        map.bind("move_up", Binding::GamepadButton(GamepadButton::South));
+        map.bind("move_down", Binding::GamepadButton(GamepadButton::East));
+        map.bind("look_up", Binding::GamepadAxis { axis: GamepadAxis::RightStickY, positive: true });
+        map.bind("look_down", Binding::GamepadAxis { axis: GamepadAxis::RightStickY, positive: false });
+        map.bind("look_left", Binding::GamepadAxis { axis: GamepadAxis::RightStickX, positive: false });
+        map.bind("look_right", Binding::GamepadAxis { axis: GamepadAxis::RightStickX, positive: true });
+
+        map
+    }
+
+    // Actions can have several bindings, binding the same input twice is a no-op
+    pub fn bind(&mut self, action: &str, binding: Binding) {
+        let bindings = self.bindings.entry(String::from(action)).or_default();
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+    }
+
+    pub fn unbind(&mut self, action: &str, binding: Binding) {
+        if let Some(bindings) = self.bindings.get_mut(action) {
+            bindings.retain(|b| *b != binding);
+        }
+    }
+
+    pub fn clear(&mut self, action: &str) {
+        self.bindings.remove(action);
+    }
+
+    pub fn get(&self, action: &str) -> &[Binding] {
+        self.bindings.get(action).map_or(&[], |b| b.as_slice())
+    }
+
+    // Every action with at least one binding
+    pub fn actions(&self) -> impl Iterator<Item = (&str, &[Binding])> {
+        self.bindings.iter().filter(|(_, b)| !b.is_empty()).map(|(a, b)| (a.as_str(), b.as_slice()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bindings_round_trip() {
+        let bindings = [
+            Binding::Key(VirtualKeyCode::W),
+            Binding::Key(VirtualKeyCode::LShift),
+            Binding::Mouse(MouseButton::Right),
+            Binding::Mouse(MouseButton::Other(4)),
+            Binding::GamepadButton(GamepadButton::South),
+            Binding::GamepadAxis { axis: GamepadAxis::LeftStickY, positive: true },
+            Binding::GamepadAxis { axis: GamepadAxis::RightStickX, positive: false }
+        ];
+        for binding in bindings {
+            assert_eq!(Binding::parse(&binding.to_string()), Some(binding));
+        }
+        assert_eq!(Binding::parse("key:W").unwrap().to_string(), "key:W");
+        assert_eq!(Binding::parse("axis:LeftStickY-").unwrap().to_string(), "axis:LeftStickY-");
+    }
+
+    #[test]
+    fn invalid_bindings() {
+        for text in ["", "W", "key:", "key:NotAKey", "mouse:Fourth", "button:Nope", "axis:LeftStickY", "axis:+", "pedal:1"] {
+            assert_eq!(Binding::parse(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn defaults_round_trip() {
+        for (_, bindings) in ActionMap::with_defaults().actions() {
+            for binding in bindings {
+                assert_eq!(Binding::parse(&binding.to_string()), Some(*binding));
+            }
+        }
+    }
+}
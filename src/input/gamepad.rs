@@ -0,0 +1,112 @@
+use std::collections::{HashMap, HashSet};
+
+pub use gilrs::{Axis as GamepadAxis, Button as GamepadButton, GamepadId};
+use gilrs::{EventType, Gilrs};
+
+use crate::input::InputState;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GamepadEvent {
+    Connected(GamepadId),
+    Disconnected(GamepadId)
+}
+
+// One controller's state, with the same per frame pressed and released semantics as the keyboard
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GamepadState {
+    pub(crate) name: String,
+    pub(crate) buttons_down: HashSet<GamepadButton>,
+    pub(crate) buttons_pressed: HashSet<GamepadButton>,
+    pub(crate) buttons_released: HashSet<GamepadButton>,
+    pub(crate) axes: HashMap<GamepadAxis, f32> // Raw -1 to 1, the deadzone is applied when read
+}
+
+impl GamepadState {
+    pub(crate) fn end_frame(&mut self) {
+        self.buttons_pressed.clear();
+        self.buttons_released.clear();
+    }
+}
+
+const MAX_DEADZONE: f32 = 0.99; // Below 1 so the rescale never divides by 0 or flips the sign
+
+// Values inside the deadzone read as 0, the rest is rescaled so output still starts at 0 and ends at 1.
+// The deadzone is clamped to 0 to MAX_DEADZONE, NaN counts as 0.
+pub(crate) fn apply_deadzone(value: f32, deadzone: f32) -> f32 {
+    let deadzone = if deadzone.is_nan() { 0.0 } else { deadzone.clamp(0.0, MAX_DEADZONE) };
+    if value.abs() <= deadzone {
+        0.0
+    } else {
+        value.signum() * (value.abs() - deadzone) / (1.0 - deadzone)
+    }
+}
+
+// Polls gilrs into InputState. Without gamepad support, I.E. no permission to read the devices,
+// input carries on with keyboard and mouse only.
+pub(crate) struct Gamepads {
+    gilrs: Option<Gilrs>
+}
+
+impl Gamepads {
+    // Controllers already plugged in are added to input straight away
+    pub(crate) fn new(input: &mut InputState) -> Gamepads {
+        let gilrs = match Gilrs::new() {
+            Ok(g) => Some(g),
+            Err(e) => {
+                log::warn!("Gamepad support disabled: {}", e);
+                None
+            }
+        };
+        if let Some(g) = &gilrs {
+            for (id, pad) in g.gamepads() {
+                input.connect_gamepad(id, pad.name(), false);
+            }
+        }
+
+        Gamepads { gilrs }
+    }
+
+    pub(crate) fn poll(&mut self, input: &mut InputState) {
+        let gilrs = match self.gilrs.as_mut() {
+            Some(g) => g,
+            None => return
+        };
+
+        while let Some(event) = gilrs.next_event() {
+            match event.event {
+                EventType::Connected => {
+                    let name = gilrs.gamepad(event.id).name().to_owned();
+                    input.connect_gamepad(event.id, &name, true);
+                },
+                EventType::Disconnected => input.disconnect_gamepad(event.id),
+                EventType::ButtonPressed(button, _) => input.gamepad_button(event.id, button, true),
+                EventType::ButtonReleased(button, _) => input.gamepad_button(event.id, button, false),
+                EventType::AxisChanged(axis, value, _) => input.gamepad_axis_changed(event.id, axis, value),
+                _ => ()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deadzone_rescales() {
+        assert_eq!(apply_deadzone(0.1, 0.2), 0.0);
+        assert_eq!(apply_deadzone(1.0, 0.2), 1.0);
+        assert_eq!(apply_deadzone(-1.0, 0.2), -1.0);
+        assert!((apply_deadzone(0.6, 0.2) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn deadzone_out_of_range() {
+        for deadzone in [1.0, 1.5, f32::INFINITY] {
+            let v = apply_deadzone(1.0, deadzone);
+            assert!(v.is_finite() && v >= 0.0, "deadzone {deadzone} gave {v}");
+        }
+        assert_eq!(apply_deadzone(0.5, -1.0), 0.5);
+        assert_eq!(apply_deadzone(0.5, f32::NAN), 0.5);
+    }
+}
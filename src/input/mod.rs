@@ -0,0 +1,283 @@
+pub mod actions;
+pub mod gamepad;
+
+use std::collections::{HashMap, HashSet};
+
+use glam::Vec2;
+use winit::event::{DeviceEvent, ElementState, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent};
+
+use crate::input::actions::{ActionMap, Binding};
+use crate::input::gamepad::{apply_deadzone, GamepadAxis, GamepadButton, GamepadEvent, GamepadId, GamepadState};
+
+const PIXELS_PER_LINE: f32 = 20.0; // Converts touchpad pixel scrolling into mouse wheel lines
+const DEFAULT_DEADZONE: f32 = 0.15;
+const AXIS_DOWN_THRESHOLD: f32 = 0.5; // How far an axis bound to an action has to move for action_down
+
+// Keyboard, mouse and gamepad state, fed by the window's events and gilrs. "pressed" and "released" only hold for the
+// frame the transition happened in, end_frame() clears them after each frame is drawn.
+#[derive(Default)]
+pub struct InputState {
+    keys_down: HashSet<VirtualKeyCode>,
+    keys_pressed: HashSet<VirtualKeyCode>,
+    keys_released: HashSet<VirtualKeyCode>,
+    buttons_down: HashSet<MouseButton>,
+    buttons_pressed: HashSet<MouseButton>,
+    buttons_released: HashSet<MouseButton>,
+    cursor_pos: Option<Vec2>, // Physical pixels from the top left, None until the cursor enters the window
+    cursor_delta: Vec2,
+    mouse_delta: Vec2, // Raw device motion, keeps going when the cursor hits the screen edge
+    scroll_delta: Vec2, // In lines
+    gamepads: HashMap<GamepadId, GamepadState>,
+    gamepad_events: Vec<GamepadEvent>, // Connections and disconnections since the last frame
+    text: String, // Typed since the last frame while text is captured
+    pub(crate) text_capture: bool, // Set from CubulousRenderer::set_text_capture before each frame
+    pub deadzone: f32, // Stick values closer to the center than this read as 0
+    pub bindings: ActionMap
+}
+
+impl InputState {
+    pub fn new() -> InputState {
+        InputState {
+            bindings: ActionMap::with_defaults(),
+            deadzone: DEFAULT_DEADZONE,
+            ..Default::default()
+        }
+    }
+
+    // notify is unset for controllers that were already connected at startup
+    pub(crate) fn connect_gamepad(&mut self, id: GamepadId, name: &str, notify: bool) {
+        self.gamepads.insert(id, GamepadState {
+            name: name.to_owned(),
+            ..Default::default()
+        });
+        if notify {
+            self.gamepad_events.push(GamepadEvent::Connected(id));
+        }
+    }
+
+    pub(crate) fn disconnect_gamepad(&mut self, id: GamepadId) {
+        self.gamepads.remove(&id);
+        self.gamepad_events.push(GamepadEvent::Disconnected(id));
+    }
+
+    pub(crate) fn gamepad_button(&mut self, id: GamepadId, button: GamepadButton, pressed: bool) {
+        if let Some(pad) = self.gamepads.get_mut(&id) {
+            match pressed {
+                true => if pad.buttons_down.insert(button) {
+                    pad.buttons_pressed.insert(button);
+                },
+                false => if pad.buttons_down.remove(&button) {
+                    pad.buttons_released.insert(button);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn gamepad_axis_changed(&mut self, id: GamepadId, axis: GamepadAxis, value: f32) {
+        if let Some(pad) = self.gamepads.get_mut(&id) {
+            pad.axes.insert(axis, value);
+        }
+    }
+
+    pub(crate) fn handle_window_event(&mut self, event: &WindowEvent) {
+        match event {
+            WindowEvent::KeyboardInput { input, .. } => {
+                if let Some(key) = input.virtual_keycode {
+                    match input.state {
+                        ElementState::Pressed => {
+                            if self.keys_down.insert(key) { // Key repeats aren't new presses
+                                self.keys_pressed.insert(key);
+                            }
+                        },
+                        ElementState::Released => {
+                            self.keys_down.remove(&key);
+                            self.keys_released.insert(key);
+                        }
+                    }
+                }
+            },
+            WindowEvent::ReceivedCharacter(c) if self.text_capture => self.text.push(*c),
+            WindowEvent::MouseInput { state, button, .. } => match state {
+                ElementState::Pressed => {
+                    self.buttons_down.insert(*button);
+                    self.buttons_pressed.insert(*button);
+                },
+                ElementState::Released => {
+                    self.buttons_down.remove(button);
+                    self.buttons_released.insert(*button);
+                }
+            },
+            WindowEvent::CursorMoved { position, .. } => {
+                let pos = Vec2::new(position.x as f32, position.y as f32);
+                if let Some(last) = self.cursor_pos {
+                    self.cursor_delta += pos - last;
+                }
+                self.cursor_pos = Some(pos);
+            },
+            WindowEvent::CursorLeft { .. } => self.cursor_pos = None,
+            WindowEvent::MouseWheel { delta, .. } => match delta {
+                MouseScrollDelta::LineDelta(x, y) => self.scroll_delta += Vec2::new(*x, *y),
+                MouseScrollDelta::PixelDelta(p) => self.scroll_delta += Vec2::new(p.x as f32, p.y as f32) / PIXELS_PER_LINE
+            },
+            WindowEvent::Focused(false) => {
+                // Releases that happen while unfocused are never delivered, so don't leave keys stuck down
+                self.keys_released.extend(self.keys_down.drain());
+                self.buttons_released.extend(self.buttons_down.drain());
+            },
+            _ => ()
+        }
+    }
+
+    pub(crate) fn handle_device_event(&mut self, event: &DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta } = event {
+            self.mouse_delta += Vec2::new(delta.0 as f32, delta.1 as f32);
+        }
+    }
+
+    pub(crate) fn end_frame(&mut self) {
+        self.keys_pressed.clear();
+        self.keys_released.clear();
+        self.buttons_pressed.clear();
+        self.buttons_released.clear();
+        self.cursor_delta = Vec2::ZERO;
+        self.mouse_delta = Vec2::ZERO;
+        self.scroll_delta = Vec2::ZERO;
+        self.text.clear();
+        self.gamepad_events.clear();
+        for pad in self.gamepads.values_mut() {
+            pad.end_frame();
+        }
+    }
+
+    pub fn key_down(&self, key: VirtualKeyCode) -> bool {
+        self.keys_down.contains(&key)
+    }
+
+    pub fn key_pressed(&self, key: VirtualKeyCode) -> bool {
+        self.keys_pressed.contains(&key)
+    }
+
+    pub fn key_released(&self, key: VirtualKeyCode) -> bool {
+        self.keys_released.contains(&key)
+    }
+
+    pub fn button_down(&self, button: MouseButton) -> bool {
+        self.buttons_down.contains(&button)
+    }
+
+    pub fn button_pressed(&self, button: MouseButton) -> bool {
+        self.buttons_pressed.contains(&button)
+    }
+
+    pub fn button_released(&self, button: MouseButton) -> bool {
+        self.buttons_released.contains(&button)
+    }
+
+    pub fn cursor_pos(&self) -> Option<Vec2> {
+        self.cursor_pos
+    }
+
+    pub fn cursor_delta(&self) -> Vec2 {
+        self.cursor_delta
+    }
+
+    pub fn mouse_delta(&self) -> Vec2 {
+        self.mouse_delta
+    }
+
+    pub fn scroll_delta(&self) -> Vec2 {
+        self.scroll_delta
+    }
+
+    pub fn gamepads(&self) -> impl Iterator<Item = GamepadId> + '_ {
+        self.gamepads.keys().copied()
+    }
+
+    pub fn gamepad_name(&self, id: GamepadId) -> Option<&str> {
+        self.gamepads.get(&id).map(|p| p.name.as_str())
+    }
+
+    pub fn gamepad_events(&self) -> &[GamepadEvent] {
+        &self.gamepad_events
+    }
+
+    pub fn gamepad_button_down(&self, id: GamepadId, button: GamepadButton) -> bool {
+        self.gamepads.get(&id).map_or(false, |p| p.buttons_down.contains(&button))
+    }
+
+    pub fn gamepad_button_pressed(&self, id: GamepadId, button: GamepadButton) -> bool {
+        self.gamepads.get(&id).map_or(false, |p| p.buttons_pressed.contains(&button))
+    }
+
+    pub fn gamepad_button_released(&self, id: GamepadId, button: GamepadButton) -> bool {
+        self.gamepads.get(&id).map_or(false, |p| p.buttons_released.contains(&button))
+    }
+
+    // -1 to 1 with the deadzone applied, 0 for disconnected controllers. Sticks are positive up and right.
+    pub fn gamepad_axis(&self, id: GamepadId, axis: GamepadAxis) -> f32 {
+        self.gamepads.get(&id)
+            .and_then(|p| p.axes.get(&axis))
+            .map_or(0.0, |v| apply_deadzone(*v, self.deadzone))
+    }
+
+    // 0 to 1, how far the binding is pushed. Keys and buttons are either 0 or 1.
+    fn binding_value(&self, binding: &Binding) -> f32 {
+        match binding {
+            Binding::Key(k) => self.key_down(*k) as i32 as f32,
+            Binding::Mouse(b) => self.button_down(*b) as i32 as f32,
+            Binding::GamepadButton(b) => self.gamepads().any(|id| self.gamepad_button_down(id, *b)) as i32 as f32,
+            Binding::GamepadAxis { axis, positive } => self.gamepads()
+                .map(|id| self.gamepad_axis(id, *axis) * if *positive { 1.0 } else { -1.0 })
+                .fold(0.0, f32::max)
+        }
+    }
+
+    fn binding_down(&self, binding: &Binding) -> bool {
+        self.binding_value(binding) >= AXIS_DOWN_THRESHOLD
+    }
+
+    fn binding_pressed(&self, binding: &Binding) -> bool {
+        match binding {
+            Binding::Key(k) => self.key_pressed(*k),
+            Binding::Mouse(b) => self.button_pressed(*b),
+            Binding::GamepadButton(b) => self.gamepads().any(|id| self.gamepad_button_pressed(id, *b)),
+            Binding::GamepadAxis { .. } => false // Axes have no press events, poll action_down instead
+        }
+    }
+
+    // Characters typed since the last frame while text is captured, including '\u{8}' for backspace and other
+    // control characters
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    // While text is captured, I.E. a chat box is open, every action reads as released so typing doesn't
+    // move the player. Raw key queries still work, games should skip their own key shortcuts meanwhile.
+    pub fn text_captured(&self) -> bool {
+        self.text_capture
+    }
+
+    // True while any input bound to the action is held
+    pub fn action_down(&self, action: &str) -> bool {
+        !self.text_capture && self.bindings.get(action).iter().any(|b| self.binding_down(b))
+    }
+
+    // True on the frame any input bound to the action was pressed
+    pub fn action_pressed(&self, action: &str) -> bool {
+        !self.text_capture && self.bindings.get(action).iter().any(|b| self.binding_pressed(b))
+    }
+
+    // 0 to 1, the furthest any input bound to the action is pushed
+    pub fn action_value(&self, action: &str) -> f32 {
+        match self.text_capture {
+            true => 0.0,
+            false => self.bindings.get(action).iter().map(|b| self.binding_value(b)).fold(0.0, f32::max)
+        }
+    }
+
+    // -1 to 1 from a pair of opposing actions, I.E. move_back and move_forward. Keys give -1, 0 or 1 and
+    // sticks anything in between.
+    pub fn action_axis(&self, negative: &str, positive: &str) -> f32 {
+        self.action_value(positive) - self.action_value(negative)
+    }
+}
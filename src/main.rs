@@ -1,23 +1,441 @@
 extern crate core;
 
 pub mod renderer;
+pub mod assets;
+pub mod util;
+pub mod input;
+pub mod ecs;
+pub mod voxel;
+pub mod terrain;
+pub mod physics;
+pub mod save;
+pub mod net;
+pub mod golden;
+pub mod audio;
+pub mod config;
+pub mod crash;
+pub mod profiling;
 
+use std::path::Path;
+use std::time::Duration;
+
+use winit::event::{MouseButton, VirtualKeyCode};
 use winit::event_loop::EventLoop;
 
+use glam::{IVec3, Mat4, Quat, Vec3, Vec4};
+
+use config::menu::SettingsMenu;
+use config::Settings;
+use ecs::components::{MeshRenderer, Transform};
+use ecs::{Entity, World};
+use input::gamepad::GamepadEvent;
+use input::InputState;
+use physics::components::{Collider, RigidBody};
+use physics::Physics;
+
+use renderer::camera_controller::FlyCameraController;
+use renderer::config::{CursorMode, FullscreenMode, RendererConfig};
+use renderer::decal::Decal;
+use renderer::error::RendererError;
+use renderer::fog::Fog;
+use renderer::frame::Frame;
+use renderer::frustum::Aabb;
+use renderer::game_loop::Game;
+use renderer::instance::Instance;
+use renderer::light::Light;
+use renderer::material::{MaterialDesc, MaterialParams, ShaderVariant};
+use renderer::mesh::MeshHandle;
+use renderer::pick::Ray;
+use renderer::render_queue::MaterialHandle;
+use renderer::render_mode::RenderMode;
 use renderer::renderer::CubulousRenderer;
+use renderer::sky::TimeOfDay;
+use renderer::texture::TextureHandle;
+use renderer::vertex::Vertex;
+use save::WorldSave;
+use voxel::world::VoxelWorld;
+use voxel::{BlockId, BlockTextures};
+
+const VERTICES: [Vertex; 4] = [ // White Vertices
+    Vertex {
+        pos: [-0.5, -0.5, 0.0],
+        normal: [0.0, 0.0, 1.0],
+        uv: [0.0, 0.0],
+        tangent: [1.0, 0.0, 0.0, 1.0],
+        color: [1.0, 0.0, 0.0],
+        layer: 0
+    },
+    Vertex {
+        pos: [0.5, -0.5, 0.0],
+        normal: [0.0, 0.0, 1.0],
+        uv: [1.0, 0.0],
+        tangent: [1.0, 0.0, 0.0, 1.0],
+        color: [0.0, 1.0, 0.0],
+        layer: 0
+    },
+    Vertex {
+        pos: [0.5, 0.5, 0.0],
+        normal: [0.0, 0.0, 1.0],
+        uv: [1.0, 1.0],
+        tangent: [1.0, 0.0, 0.0, 1.0],
+        color: [0.0, 0.0, 1.0],
+        layer: 0
+    },
+    Vertex {
+        pos: [-0.5, 0.5, 0.0],
+        normal: [0.0, 0.0, 1.0],
+        uv: [0.0, 1.0],
+        tangent: [1.0, 0.0, 0.0, 1.0],
+        color: [1.0, 1.0, 1.0],
+        layer: 0
+    }
+];
+
+const INDICES: [u32; 6] = [0, 1, 2, 2, 3, 0];
+
+const SETTINGS_FILE: &str = "settings.toml";
+const CRASH_REPORT_DIR: &str = "crash_reports";
+const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(10);
+const BLOCK_TEXTURE_SIZE: u32 = 16;
+const SCORCH_SIZE: u32 = 32;
+const FAR_PLANE_MARGIN: f32 = 4.0; // Past the render distance, where the fog is opaque
+const LANTERN: Light = Light::Point {
+    position: Vec3::new(0.0, 0.0, 1.0),
+    color: Vec3::new(1.0, 0.6, 0.3),
+    intensity: 8.0,
+    range: 12.0
+};
+
+// Speckled 16x16 pixel art, rows from the top below top_rows take the second color. Stands in for block
+// textures loaded from disk.
+fn block_texture(top: [u8; 3], rest: [u8; 3], top_rows: u32, seed: u32) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity((BLOCK_TEXTURE_SIZE * BLOCK_TEXTURE_SIZE * 4) as usize);
+    for y in 0..BLOCK_TEXTURE_SIZE {
+        for x in 0..BLOCK_TEXTURE_SIZE {
+            let hash = (x * 73 + y * 151 + seed * 97).wrapping_mul(2654435761) >> 28; // 0 to 15
+            let color = match y < top_rows {
+                true => top,
+                false => rest
+            };
+            let shade = 0.8 + hash as f32 / 15.0 * 0.3;
+            pixels.extend(color.map(|c| (c as f32 * shade).min(255.0) as u8));
+            pixels.push(255);
+        }
+    }
+
+    pixels
+}
+
+// A soot mark with ragged edges, transparent outside it
+fn scorch_texture() -> Vec<u8> {
+    let mut pixels = Vec::with_capacity((SCORCH_SIZE * SCORCH_SIZE * 4) as usize);
+    for y in 0..SCORCH_SIZE {
+        for x in 0..SCORCH_SIZE {
+            let hash = (x * 73 + y * 151).wrapping_mul(2654435761) >> 28; // 0 to 15
+            let offset = (Vec3::new(x as f32, y as f32, 0.0) + 0.5) / SCORCH_SIZE as f32 * 2.0 - Vec3::new(1.0, 1.0, 0.0);
+            let coverage = (1.0 - offset.length() - hash as f32 / 15.0 * 0.3).clamp(0.0, 0.6) / 0.6;
+            pixels.extend([20, 16, 12, (coverage * 230.0) as u8]);
+        }
+    }
 
+    pixels
+}
 
-fn hello_triangle() {
+fn hello_triangle() -> Result<(), RendererError> {
     // Generic window setup
     let event_loop = EventLoop::new();
 
-    let renderer = CubulousRenderer::new(&event_loop);
+    let settings = Settings::load(Path::new(SETTINGS_FILE)).unwrap_or_else(|e| {
+        log::error!("{}, using the defaults", e);
+        Settings::default()
+    });
+    let mut config = RendererConfig::default();
+    settings.apply_to_renderer(&mut config);
+    let mut renderer = CubulousRenderer::new(&event_loop, config)?;
+    renderer.input_mut().bindings = settings.action_map();
+
+    let quad = renderer.upload_mesh(&VERTICES, &INDICES)?;
+
+    let mut scene = World::new();
+    let spinner = scene.spawn();
+    scene.insert(spinner, Transform::IDENTITY);
+    scene.insert(spinner, MeshRenderer::new(quad));
+
+    // A ball bouncing on a slab in front of the quad, F6 shows their colliders
+    let slab = scene.spawn();
+    scene.insert(slab, Transform::from_translation(Vec3::new(0.0, -2.0, 0.0)));
+    scene.insert(slab, RigidBody::fixed());
+    scene.insert(slab, Collider::cuboid(Vec3::new(2.0, 0.1, 2.0)));
+    let ball = scene.spawn();
+    scene.insert(ball, Transform::from_translation(Vec3::new(0.3, 2.0, 0.5)));
+    scene.insert(ball, RigidBody::dynamic());
+    scene.insert(ball, Collider {
+        restitution: 0.7,
+        ..Collider::ball(0.25)
+    });
+
+    let tinted = renderer.create_material(&MaterialDesc {
+        params: MaterialParams::new([1.0, 0.8, 0.6, 1.0], 0.0, 1.0),
+        ..Default::default()
+    })?;
+
+    // A wall of smaller quads behind the main one, drawn in a single call
+    let grid: Vec<Instance> = (0..32 * 32)
+        .map(|i| {
+            let (x, y) = ((i % 32) as f32 - 15.5, (i / 32) as f32 - 15.5);
+            let transform = Mat4::from_translation(Vec3::new(x * 0.25, y * 0.25, -2.0)) * Mat4::from_scale(Vec3::splat(0.2));
+            Instance::new(transform, Vec4::new((i % 32) as f32 / 31.0, (i / 32) as f32 / 31.0, 1.0, 1.0), i)
+        })
+        .collect();
 
-    renderer.run_blocking(event_loop);
+    // Rolling hills of grass over stone below the quads
+    let (stone, grass) = (BlockId(1), BlockId(2));
+    let mut world = VoxelWorld::new();
+    let (stone_color, grass_color, dirt_color) = ([128, 128, 128], [80, 170, 50], [120, 85, 55]);
+    let layers = [ // Stone, grass, dirt, then grass over dirt for the sides of grass blocks
+        block_texture(stone_color, stone_color, 0, 1),
+        block_texture(grass_color, grass_color, 0, 2),
+        block_texture(dirt_color, dirt_color, 0, 3),
+        block_texture(grass_color, dirt_color, 4, 4)
+    ];
+    let layers: Vec<&[u8]> = layers.iter().map(|l| l.as_slice()).collect();
+    let block_textures = renderer.upload_texture_array(BLOCK_TEXTURE_SIZE, BLOCK_TEXTURE_SIZE, &layers, true)?;
+    world.material = renderer.create_material(&MaterialDesc {
+        shader: ShaderVariant::VOXEL,
+        base_color_texture: Some(block_textures),
+        params: MaterialParams::new([1.0, 1.0, 1.0, 1.0], 0.0, 0.9),
+        ..Default::default()
+    })?;
+    world.set_textures(stone, BlockTextures::all(0));
+    world.set_textures(grass, BlockTextures { top: 1, side: 3, bottom: 2 });
+    let scorch = renderer.upload_texture(SCORCH_SIZE, SCORCH_SIZE, &scorch_texture(), true)?;
+    // Edits are saved, the hills are only generated when there's no save yet
+    let save = match WorldSave::open(Path::new("saves/demo")) {
+        Ok(save) => Some(save),
+        Err(e) => {
+            log::error!("{}, edits won't be saved", e);
+            None
+        }
+    };
+    let loaded = match save.as_ref().map(|s| s.load_voxels(&mut world)) {
+        Some(Ok(count)) => count,
+        Some(Err(e)) => {
+            log::error!("{}", e);
+            0
+        }
+        None => 0
+    };
+    if loaded == 0 {
+        for x in -48..48 {
+            for z in -48..48 {
+                let height = ((x as f32 * 0.15).sin() * (z as f32 * 0.1).cos() * 3.0) as i32 - 6;
+                for y in -12..=height {
+                    world.set_block(IVec3::new(x, y, z), if y == height { grass } else { stone });
+                }
+            }
+        }
+    }
+
+    let controller = FlyCameraController::new(renderer.camera());
+    let _profiler = profiling::start(); // Serves until the process exits, run_fixed never returns
+    let near = renderer.camera().near;
+    renderer.camera_mut().set_clip_planes(near, settings.graphics.render_distance + FAR_PLANE_MARGIN);
+    world.view_distance = Some(settings.graphics.render_distance);
+
+    renderer.run_fixed(event_loop, HelloTriangle { scene, spinner, physics: Physics::new(), show_colliders: false, world, place: stone, save,
+        since_save: Duration::ZERO, time: TimeOfDay::new(9.0), controller,
+        scorch, quad, grid, tinted, settings, settings_menu: SettingsMenu::new(Path::new(SETTINGS_FILE)) });
+}
+
+struct HelloTriangle {
+    scene: World,
+    spinner: Entity,
+    physics: Physics,
+    show_colliders: bool,
+    world: VoxelWorld,
+    place: BlockId, // Placed with the middle mouse button, the left one breaks blocks
+    save: Option<WorldSave>, // None when the save directory couldn't be opened
+    since_save: Duration,
+    time: TimeOfDay, // Drives the sky, the sun and the ambient light
+    controller: FlyCameraController,
+    scorch: TextureHandle, // Left on the block under the cursor with E
+    quad: MeshHandle,
+    grid: Vec<Instance>,
+    tinted: MaterialHandle,
+    settings: Settings,
+    settings_menu: SettingsMenu // Toggled with F10
+}
+
+impl Game for HelloTriangle {
+    fn tick(&mut self, renderer: &mut CubulousRenderer, _input: &InputState, step: Duration) {
+        ecs::render::store_previous(&mut self.scene);
+        if let Some(transform) = self.scene.get_mut::<Transform>(self.spinner) {
+            transform.rotation *= Quat::from_rotation_y(step.as_secs_f32());
+        }
+        self.physics.tick(&mut self.scene, step);
+
+        self.time.advance(step);
+        renderer.set_lights(&[self.time.light(), LANTERN]);
+        let (sky, ground) = self.time.ambient();
+        renderer.set_ambient_hemisphere(sky, ground);
+        renderer.set_sky(Some(self.time.sky()));
+        renderer.set_fog(Some(Fog::view_distance(self.time.fog_color(), self.settings.graphics.render_distance)));
+
+        self.since_save += step;
+        if self.since_save >= AUTOSAVE_INTERVAL {
+            self.since_save = Duration::ZERO;
+            if let Some(save) = self.save.as_mut() {
+                save.save_chunks(&mut self.world);
+                for e in save.errors() {
+                    log::error!("{}", e);
+                }
+            }
+        }
+    }
+
+    fn frame(&mut self, frame: &mut Frame, input: &InputState, delta: Duration) {
+        if input.key_pressed(VirtualKeyCode::Escape) {
+            if let Some(save) = self.save.as_mut() {
+                save.save_chunks(&mut self.world);
+                save.flush();
+            }
+            frame.exit();
+        }
+        let modes = [(VirtualKeyCode::F1, RenderMode::Shaded), (VirtualKeyCode::F2, RenderMode::Wireframe),
+            (VirtualKeyCode::F3, RenderMode::Normals), (VirtualKeyCode::F4, RenderMode::Overdraw), (VirtualKeyCode::F5, RenderMode::Depth)];
+        for (key, mode) in modes {
+            if input.key_pressed(key) {
+                if let Err(e) = frame.renderer().set_render_mode(mode) {
+                    log::warn!("{}", e);
+                }
+            }
+        }
+        if input.key_pressed(VirtualKeyCode::F6) {
+            self.show_colliders = !self.show_colliders;
+        }
+        if input.key_pressed(VirtualKeyCode::Tab) { // Toggles FPS style mouse look
+            let locked = frame.renderer().cursor_mode() != CursorMode::Locked;
+            match frame.renderer().set_cursor_mode(if locked { CursorMode::Locked } else { CursorMode::Normal }) {
+                Ok(()) => self.controller.always_look = locked,
+                Err(e) => log::warn!("{}", e)
+            }
+        }
+        for event in input.gamepad_events() {
+            match event {
+                GamepadEvent::Connected(id) => log::info!("Gamepad connected: {}", input.gamepad_name(*id).unwrap_or("unknown")),
+                GamepadEvent::Disconnected(_) => log::info!("Gamepad disconnected")
+            }
+        }
+        if input.key_pressed(VirtualKeyCode::F11) {
+            let mode = match frame.renderer().fullscreen() {
+                FullscreenMode::Windowed => FullscreenMode::Borderless,
+                _ => FullscreenMode::Windowed
+            };
+            frame.renderer().set_fullscreen(mode);
+        }
+        if input.key_pressed(VirtualKeyCode::F10) {
+            self.settings_menu.toggle();
+        }
+        // The camera follows the mouse every frame rather than every tick, so looking around stays smooth
+        self.controller.update(frame.camera(), input, delta);
+        // Blocks are picked under the cursor, or at the center of the screen while it's locked
+        let ray = match (frame.renderer().cursor_mode(), input.cursor_pos()) {
+            (CursorMode::Locked, _) | (_, None) => {
+                let camera = frame.renderer().camera();
+                Ray::new(camera.position(), camera.target() - camera.position())
+            }
+            (_, Some(pos)) => frame.renderer().screen_ray(pos)
+        };
+        if let Some(hit) = self.world.raycast(&ray, 16.0) {
+            let min = hit.pos.as_vec3() - Vec3::splat(0.01);
+            frame.debug_draw().wire_box(Aabb { min, max: min + Vec3::splat(1.02) }, Vec3::ONE);
+            if input.button_pressed(MouseButton::Left) {
+                self.world.set_block(hit.pos, BlockId::AIR);
+            } else if input.button_pressed(MouseButton::Middle) && hit.normal != IVec3::ZERO {
+                self.world.set_block(hit.pos + hit.normal, self.place);
+            } else if input.key_pressed(VirtualKeyCode::E) && hit.normal != IVec3::ZERO {
+                let mark = Decal::on_surface(ray.at(hit.distance), hit.normal.as_vec3(), 0.6, self.scorch)
+                    .with_lifetime(Duration::from_secs(20), Duration::from_secs(5));
+                frame.renderer().decals().add(mark);
+            }
+        }
+        if let Err(e) = self.world.update(frame.renderer()) {
+            log::error!("{}", e);
+            frame.exit();
+        }
+        self.world.draw(frame);
+        ecs::render::extract(&self.scene, frame);
+        if self.show_colliders {
+            self.physics.draw_colliders(frame.debug_draw());
+        }
+        frame.draw_instanced(self.quad, &self.grid, self.tinted);
+
+        if self.settings_menu.show(frame, &mut self.settings) {
+            let distance = self.settings.graphics.render_distance;
+            let near = frame.camera().near;
+            frame.camera().set_clip_planes(near, distance + FAR_PLANE_MARGIN);
+            self.world.view_distance = Some(distance);
+        }
+
+        let stats = frame.stats().clone();
+        let memory = frame.memory_stats();
+        let gpu = frame.renderer().gpu().name.clone();
+        let time = &mut self.time;
+        frame.ui(|ctx| {
+            egui::Window::new("Time").show(ctx, |ui| {
+                ui.add(egui::Slider::new(&mut time.hours, 0.0..=24.0).text("hour"));
+                ui.add(egui::Slider::new(&mut time.speed, 0.0..=2.0).text("hours per second"));
+                ui.checkbox(&mut time.paused, "Paused");
+            });
+            egui::Window::new("Stats").show(ctx, |ui| {
+                ui.label(gpu);
+                ui.label(format!("{:.0} fps, {:.2} ms CPU", stats.fps, stats.cpu_frame_time.as_secs_f64() * 1000.0));
+                if let Some(gpu_time) = stats.gpu_time {
+                    ui.label(format!("{:.2} ms GPU", gpu_time.as_secs_f64() * 1000.0));
+                }
+                for pass in stats.passes.iter() {
+                    ui.label(format!("  {}: {:.2} ms", pass.name, pass.duration.as_secs_f64() * 1000.0));
+                }
+                ui.label(format!("{} draw calls, {} drawn, {} culled", stats.draw_calls, stats.drawn_objects, stats.culled_objects));
+                ui.label(format!("{} occlusion tests, {} occluded", stats.occlusion_tests, stats.occluded_objects));
+                ui.label(format!("{} of {} indirect objects visible", stats.indirect_visible, stats.indirect_objects));
+            });
+            egui::Window::new("Memory").default_open(false).show(ctx, |ui| memory.show(ui));
+        });
+    }
+}
+
+// Checks every reference scene against tests/golden, or rewrites the golden images with --update.
+// Returns whether they all passed, a scene without a golden image doesn't.
+fn golden_images(update: bool) -> Result<bool, golden::GoldenError> {
+    let reports = golden::run(update, golden::Tolerance::default())?;
+    for r in reports.iter() {
+        println!("{}", r);
+    }
+
+    Ok(reports.iter().all(|r| r.outcome.passed()))
 }
 
 fn main() {
-    hello_triangle();
+    crash::init_logging();
+    crash::install(Path::new(CRASH_REPORT_DIR));
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|a| a == "--golden") {
+        match golden_images(args.iter().any(|a| a == "--update")) {
+            Ok(true) => std::process::exit(0),
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                log::error!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+
+    if let Err(e) = hello_triangle() {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
 
     // App::new()
     //     .add_system()
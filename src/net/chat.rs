@@ -0,0 +1,250 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use glam::{Vec2, Vec4};
+use winit::event::VirtualKeyCode;
+
+use crate::input::InputState;
+use crate::renderer::frame::Frame;
+use crate::renderer::text::FontHandle;
+use crate::util::cursor::Cursor;
+
+const MAX_LENGTH: usize = 256; // Characters per message, longer ones are cut off
+const MAX_SENDER_LENGTH: usize = 32; // Characters per sender name, which like the text has to fit its u16 length
+const MAX_HISTORY: usize = 100; // Messages kept, the oldest are dropped past this
+const VISIBLE_LINES: usize = 10; // Drawn at once, scrolling shows older ones while the chat box is open
+const SHOW_FOR: Duration = Duration::from_secs(10); // How long new messages stay up while the chat box is closed
+const FADE_FOR: Duration = Duration::from_secs(1); // At the end of SHOW_FOR
+const MARGIN: f32 = 8.0; // Pixels from the bottom left corner of the window
+
+// Layout when encoded, numbers little endian: sender length u16, sender as UTF-8, text length u16, text as UTF-8.
+// Clients send messages with an empty sender, the server fills it in before passing them on.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub struct ChatMessage {
+    pub sender: String, // Empty for messages from the server itself
+    pub text: String
+}
+
+impl ChatMessage {
+    pub fn new(sender: &str, text: &str) -> ChatMessage {
+        ChatMessage {
+            sender: sanitize(sender, MAX_SENDER_LENGTH),
+            text: sanitize(text, MAX_LENGTH)
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(4 + self.sender.len() + self.text.len());
+        for s in [&self.sender, &self.text] {
+            out.extend_from_slice(&(s.len() as u16).to_le_bytes());
+            out.extend_from_slice(s.as_bytes());
+        }
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<ChatMessage, String> {
+        let mut cursor = Cursor::new(data);
+        let sender = read_string(&mut cursor)?;
+        let text = read_string(&mut cursor)?;
+        if !cursor.rest().is_empty() {
+            return Err(String::from("Trailing data after the chat message"));
+        }
+
+        Ok(ChatMessage::new(&sender, &text))
+    }
+
+    fn line(&self) -> String {
+        match self.sender.is_empty() {
+            true => self.text.clone(),
+            false => format!("<{}> {}", self.sender, self.text)
+        }
+    }
+}
+
+fn read_string(cursor: &mut Cursor) -> Result<String, String> {
+    let len = cursor.u16()? as usize;
+    String::from_utf8(cursor.bytes(len)?.to_vec()).map_err(|_| String::from("Chat message isn't UTF-8"))
+}
+
+// One line per message, so control characters like '\n' are dropped along with anything past max characters
+fn sanitize(text: &str, max: usize) -> String {
+    text.chars().filter(|c| !c.is_control()).take(max).collect()
+}
+
+// Received messages and the line being typed. Enter opens the chat box, which captures text input and
+// suspends the action bindings until Enter sends the line or Escape drops it. Messages handed to send are
+// queued for the caller to pass to whatever transport reaches the server, see take_outgoing.
+pub struct Chat {
+    history: VecDeque<(ChatMessage, Duration)>, // Oldest first, with how long ago each arrived
+    input: String,
+    open: bool,
+    scroll: usize, // Lines scrolled up from the newest
+    outgoing: Vec<ChatMessage>,
+    pub color: Vec4, // Linear, of the messages
+    pub input_color: Vec4 // Of the line being typed
+}
+
+impl Chat {
+    pub fn new() -> Chat {
+        Chat {
+            history: VecDeque::new(),
+            input: String::new(),
+            open: false,
+            scroll: 0,
+            outgoing: Vec::new(),
+            color: Vec4::ONE,
+            input_color: Vec4::new(1.0, 1.0, 0.6, 1.0)
+        }
+    }
+
+    pub fn receive(&mut self, message: ChatMessage) {
+        if self.history.len() >= MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back((message, Duration::ZERO));
+    }
+
+    // Oldest first
+    pub fn history(&self) -> impl Iterator<Item = &ChatMessage> {
+        self.history.iter().map(|(m, _)| m)
+    }
+
+    pub fn clear(&mut self) {
+        self.history.clear();
+        self.scroll = 0;
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open
+    }
+
+    // Queues a message for take_outgoing, as if it was typed. Blank text is ignored.
+    pub fn send(&mut self, text: &str) {
+        let text = sanitize(text, MAX_LENGTH);
+        if !text.trim().is_empty() {
+            self.outgoing.push(ChatMessage::new("", &text));
+        }
+    }
+
+    // Messages typed since the last call, to be encoded and sent to the server. They aren't added to the
+    // history, the server echoes them back.
+    pub fn take_outgoing(&mut self) -> Vec<ChatMessage> {
+        std::mem::take(&mut self.outgoing)
+    }
+
+    // Once per frame before draw. Opens and closes the chat box, types into it and keeps the renderer's
+    // text capture in step.
+    pub fn update(&mut self, frame: &mut Frame, input: &InputState, delta: Duration) {
+        for (_, age) in self.history.iter_mut() {
+            *age += delta;
+        }
+
+        if !self.open {
+            // Enter's own '\r' isn't collected, capture only starts from the next frame
+            if input.key_pressed(VirtualKeyCode::Return) {
+                self.set_open(frame, true);
+            }
+            return;
+        }
+
+        for c in input.text().chars() {
+            match c {
+                '\u{8}' => {
+                    self.input.pop();
+                },
+                c if !c.is_control() && self.input.chars().count() < MAX_LENGTH => self.input.push(c),
+                _ => ()
+            }
+        }
+
+        let scroll = input.scroll_delta().y;
+        let max_scroll = self.history.len().saturating_sub(VISIBLE_LINES);
+        if scroll > 0.0 {
+            self.scroll = (self.scroll + scroll.ceil() as usize).min(max_scroll);
+        } else if scroll < 0.0 {
+            self.scroll = self.scroll.saturating_sub((-scroll).ceil() as usize);
+        }
+
+        if input.key_pressed(VirtualKeyCode::Return) {
+            let line = std::mem::take(&mut self.input);
+            self.send(&line);
+            self.set_open(frame, false);
+        } else if input.key_pressed(VirtualKeyCode::Escape) {
+            self.input.clear();
+            self.set_open(frame, false);
+        }
+    }
+
+    fn set_open(&mut self, frame: &mut Frame, open: bool) {
+        self.open = open;
+        self.scroll = 0;
+        frame.renderer().set_text_capture(open);
+    }
+
+    // Bottom left of the window, the line being typed under the newest messages. While closed only recent
+    // messages are shown, fading out.
+    pub fn draw(&self, frame: &mut Frame, font: FontHandle) {
+        let screen = frame.renderer().screen_size();
+        let line_height = frame.renderer().text_size(font, " ").y;
+        let mut y = screen.y - MARGIN - line_height;
+
+        if self.open {
+            let prompt = format!("> {}_", self.input);
+            frame.draw_text(font, &prompt, Vec2::new(MARGIN, y), self.input_color);
+        }
+
+        for (message, age) in self.history.iter().rev().skip(self.scroll).take(VISIBLE_LINES) {
+            let alpha = match self.open {
+                true => 1.0,
+                false => fade(*age)
+            };
+            if alpha <= 0.0 {
+                break; // Older messages have been up even longer
+            }
+            y -= line_height;
+            let color = Vec4::new(self.color.x, self.color.y, self.color.z, self.color.w * alpha);
+            frame.draw_text(font, &message.line(), Vec2::new(MARGIN, y), color);
+        }
+    }
+}
+
+impl Default for Chat {
+    fn default() -> Self {
+        Chat::new()
+    }
+}
+
+// 1 until SHOW_FOR - FADE_FOR, then down to 0 at SHOW_FOR
+fn fade(age: Duration) -> f32 {
+    let left = SHOW_FOR.saturating_sub(age);
+    (left.as_secs_f32() / FADE_FOR.as_secs_f32()).min(1.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_round_trip() {
+        for message in [ChatMessage::new("", "Server restarting"), ChatMessage::new("Ünïcode", "hello wörld")] {
+            assert_eq!(ChatMessage::decode(&message.encode()).unwrap(), message);
+        }
+        let encoded = ChatMessage::new("a", "b").encode();
+        assert!(ChatMessage::decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn decoding_sanitizes() {
+        let raw = ChatMessage { sender: String::from("a"), text: "x\u{7}y".repeat(200) };
+        let decoded = ChatMessage::decode(&raw.encode()).unwrap();
+        assert_eq!(decoded.text.chars().count(), MAX_LENGTH);
+        assert!(!decoded.text.contains('\u{7}'));
+    }
+
+    #[test]
+    fn long_senders_are_cut_off() {
+        let message = ChatMessage::new(&"ä".repeat(40_000), "hi");
+        assert_eq!(message.sender.chars().count(), MAX_SENDER_LENGTH);
+        assert_eq!(ChatMessage::decode(&message.encode()).unwrap(), message);
+    }
+}
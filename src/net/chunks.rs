@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use glam::IVec3;
+
+use crate::net::NetError;
+use crate::save::region::{decode_chunk, encode_chunk};
+use crate::save::write_replacing;
+use crate::util::cursor::Cursor;
+use crate::voxel::Chunk;
+
+// Cache file layout, numbers little endian: MAGIC, content hash u64, then the chunk payload as in region files
+const MAGIC: [u8; 4] = *b"CBCC";
+const KIND_DATA: u8 = 0;
+const KIND_UNCHANGED: u8 = 1;
+
+// FNV-1a over the chunk's block IDs as little endian u16s, which the server has to hash the same way
+pub fn chunk_hash(chunk: &Chunk) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in chunk.blocks().iter().flat_map(|b| b.0.to_le_bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn write_pos(out: &mut Vec<u8>, pos: IVec3) {
+    out.extend(pos.to_array().iter().flat_map(|c| c.to_le_bytes()));
+}
+
+fn read_pos(cursor: &mut Cursor) -> Result<IVec3, String> {
+    Ok(IVec3::new(cursor.u32()? as i32, cursor.u32()? as i32, cursor.u32()? as i32))
+}
+
+// Sent reliably to ask for a chunk. Layout when encoded, numbers little endian: chunk position as 3 i32,
+// flags u8 (1 when a hash follows), the cached copy's hash u64
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkRequest {
+    pub pos: IVec3,
+    pub cached: Option<u64> // The server answers Unchanged instead of sending the chunk when this still matches
+}
+
+impl ChunkRequest {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(21);
+        write_pos(&mut out, self.pos);
+        out.push(self.cached.is_some() as u8);
+        out.extend_from_slice(&self.cached.unwrap_or(0).to_le_bytes());
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<ChunkRequest, String> {
+        let mut cursor = Cursor::new(data);
+        let pos = read_pos(&mut cursor)?;
+        let flags = cursor.u8()?;
+        let hash = cursor.u64()?;
+
+        Ok(ChunkRequest {
+            pos,
+            cached: (flags & 1 != 0).then_some(hash)
+        })
+    }
+}
+
+// Layout when encoded, numbers little endian: kind u8 (0 for Data, 1 for Unchanged), chunk position as 3
+// i32, hash u64, then for Data the payload to the end
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChunkReply {
+    Data { pos: IVec3, hash: u64, payload: Vec<u8> }, // payload as in region files, see ChunkReply::data
+    Unchanged { pos: IVec3, hash: u64 }
+}
+
+impl ChunkReply {
+    // How a server answers with a chunk
+    pub fn data(pos: IVec3, chunk: &Chunk) -> ChunkReply {
+        ChunkReply::Data {
+            pos,
+            hash: chunk_hash(chunk),
+            payload: encode_chunk(chunk)
+        }
+    }
+
+    pub fn pos(&self) -> IVec3 {
+        match self {
+            ChunkReply::Data { pos, .. } | ChunkReply::Unchanged { pos, .. } => *pos
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let (kind, pos, hash, payload) = match self {
+            ChunkReply::Data { pos, hash, payload } => (KIND_DATA, *pos, *hash, payload.as_slice()),
+            ChunkReply::Unchanged { pos, hash } => (KIND_UNCHANGED, *pos, *hash, &[][..])
+        };
+        let mut out = Vec::with_capacity(21 + payload.len());
+        out.push(kind);
+        write_pos(&mut out, pos);
+        out.extend_from_slice(&hash.to_le_bytes());
+        out.extend_from_slice(payload);
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<ChunkReply, String> {
+        let mut cursor = Cursor::new(data);
+        let kind = cursor.u8()?;
+        let pos = read_pos(&mut cursor)?;
+        let hash = cursor.u64()?;
+        match kind {
+            KIND_DATA => Ok(ChunkReply::Data { pos, hash, payload: cursor.rest().to_vec() }),
+            KIND_UNCHANGED if cursor.rest().is_empty() => Ok(ChunkReply::Unchanged { pos, hash }),
+            KIND_UNCHANGED => Err(String::from("Trailing data after the chunk reply")),
+            _ => Err(format!("Unknown chunk reply kind {}", kind))
+        }
+    }
+}
+
+// Downloaded chunks on disk, one file each, so chunks that haven't changed on the server since aren't
+// downloaded again. Failing to read or write the cache only costs a download, so it's logged and ignored.
+pub struct ChunkCache {
+    dir: PathBuf
+}
+
+impl ChunkCache {
+    // Creates the directory if it doesn't exist, I.E. one per server address
+    pub fn open(dir: &Path) -> Result<ChunkCache, NetError> {
+        fs::create_dir_all(dir).map_err(|e| NetError::Io(format!("{}: {}", dir.display(), e)))?;
+        Ok(ChunkCache {
+            dir: dir.to_owned()
+        })
+    }
+
+    fn path(&self, pos: IVec3) -> PathBuf {
+        self.dir.join(format!("c.{}.{}.{}.cbc", pos.x, pos.y, pos.z))
+    }
+
+    // The cached chunk and its hash. Files that don't match their stored hash are treated as missing.
+    pub fn load(&self, pos: IVec3) -> Option<(u64, Chunk)> {
+        let path = self.path(pos);
+        let data = match fs::read(&path) {
+            Ok(d) => d,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                log::warn!("Failed to read cached chunk {}: {}", path.display(), e);
+                return None;
+            }
+        };
+
+        let mut cursor = Cursor::new(&data);
+        let loaded = cursor.bytes(4)
+            .and_then(|m| if m == MAGIC { Ok(()) } else { Err(String::from("Not a chunk cache file")) })
+            .and_then(|_| cursor.u64())
+            .and_then(|hash| decode_chunk(cursor.rest()).map(|c| (hash, c)))
+            .and_then(|(hash, chunk)| match chunk_hash(&chunk) == hash {
+                true => Ok((hash, chunk)),
+                false => Err(String::from("Hash mismatch"))
+            });
+        match loaded {
+            Ok(l) => Some(l),
+            Err(e) => {
+                log::warn!("Ignoring cached chunk {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+
+    // The hash of the cached copy without decoding it, for ChunkRequest::cached
+    pub fn hash(&self, pos: IVec3) -> Option<u64> {
+        let data = fs::read(self.path(pos)).ok()?;
+        let mut cursor = Cursor::new(&data);
+        match cursor.bytes(4).ok()? == MAGIC {
+            true => cursor.u64().ok(),
+            false => None
+        }
+    }
+
+    pub fn store(&self, pos: IVec3, hash: u64, payload: &[u8]) {
+        let mut out = Vec::with_capacity(12 + payload.len());
+        out.extend_from_slice(&MAGIC);
+        out.extend_from_slice(&hash.to_le_bytes());
+        out.extend_from_slice(payload);
+        if let Err(e) = write_replacing(&self.path(pos), &out) {
+            log::warn!("Failed to cache chunk {}: {}", pos, e);
+        }
+    }
+
+    pub fn remove(&self, pos: IVec3) {
+        let _ = fs::remove_file(self.path(pos));
+    }
+}
+
+// Requests chunks from the server, answering the replies from the cache when the server says the cached
+// copy is still current. Replies are checked against their hashes, chunks that fail are requested again
+// through retries.
+pub struct ChunkDownloads {
+    pub cache: ChunkCache,
+    in_flight: HashMap<IVec3, Option<u64>>, // The cached hash each request was sent with
+    retries: Vec<ChunkRequest>
+}
+
+impl ChunkDownloads {
+    pub fn new(cache: ChunkCache) -> ChunkDownloads {
+        ChunkDownloads {
+            cache,
+            in_flight: HashMap::new(),
+            retries: Vec::new()
+        }
+    }
+
+    // The request to send reliably for a chunk, carrying the cached copy's hash if there is one
+    pub fn request(&mut self, pos: IVec3) -> ChunkRequest {
+        let request = ChunkRequest {
+            pos,
+            cached: self.cache.hash(pos)
+        };
+        self.in_flight.insert(pos, request.cached);
+        request
+    }
+
+    pub fn is_in_flight(&self, pos: IVec3) -> bool {
+        self.in_flight.contains_key(&pos)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    // Requests to send again for replies that couldn't be used, without the cache this time
+    pub fn take_retries(&mut self) -> Vec<ChunkRequest> {
+        std::mem::take(&mut self.retries)
+    }
+
+    fn retry(&mut self, pos: IVec3) {
+        self.cache.remove(pos);
+        self.in_flight.insert(pos, None);
+        self.retries.push(ChunkRequest { pos, cached: None });
+    }
+
+    // The chunk a reply delivers, for VoxelWorld::insert_chunk. None for replies to chunks that weren't
+    // requested and for ones that have been queued for a retry, which is only an error when the server
+    // sent a chunk that doesn't match its hash.
+    pub fn receive(&mut self, reply: &ChunkReply) -> Result<Option<(IVec3, Chunk)>, NetError> {
+        let pos = reply.pos();
+        if self.in_flight.remove(&pos).is_none() {
+            return Ok(None);
+        }
+
+        match reply {
+            ChunkReply::Data { hash, payload, .. } => {
+                let chunk = match decode_chunk(payload) {
+                    Ok(c) if chunk_hash(&c) == *hash => c,
+                    Ok(_) => {
+                        self.retry(pos);
+                        return Err(NetError::Invalid(format!("Chunk {} doesn't match its hash", pos)));
+                    },
+                    Err(e) => {
+                        self.retry(pos);
+                        return Err(NetError::Invalid(format!("Chunk {}: {}", pos, e)));
+                    }
+                };
+                self.cache.store(pos, *hash, payload);
+                Ok(Some((pos, chunk)))
+            },
+            ChunkReply::Unchanged { hash, .. } => match self.cache.load(pos) {
+                Some((cached, chunk)) if cached == *hash => Ok(Some((pos, chunk))),
+                _ => {
+                    // Removed or rewritten since the request was sent
+                    self.retry(pos);
+                    Ok(None)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::voxel::BlockId;
+
+    #[test]
+    fn request_round_trip() {
+        for request in [ChunkRequest { pos: IVec3::new(-4, 2, 900), cached: None },
+                        ChunkRequest { pos: IVec3::ZERO, cached: Some(0xdead_beef_0123) }] {
+            assert_eq!(ChunkRequest::decode(&request.encode()).unwrap(), request);
+        }
+        assert!(ChunkRequest::decode(&[0; 12]).is_err());
+    }
+
+    #[test]
+    fn reply_round_trip() {
+        let mut chunk = Chunk::new();
+        chunk.set(IVec3::new(1, 2, 3), BlockId(4));
+        let data = ChunkReply::data(IVec3::new(5, -6, 7), &chunk);
+        assert_eq!(ChunkReply::decode(&data.encode()).unwrap(), data);
+        let unchanged = ChunkReply::Unchanged { pos: IVec3::new(-1, -1, -1), hash: chunk_hash(&chunk) };
+        assert_eq!(ChunkReply::decode(&unchanged.encode()).unwrap(), unchanged);
+
+        let mut trailing = unchanged.encode();
+        trailing.push(0);
+        assert!(ChunkReply::decode(&trailing).is_err());
+        let mut unknown = unchanged.encode();
+        unknown[0] = 9;
+        assert!(ChunkReply::decode(&unknown).is_err());
+    }
+
+    #[test]
+    fn hash_follows_blocks() {
+        let mut chunk = Chunk::new();
+        let empty = chunk_hash(&chunk);
+        chunk.set(IVec3::new(0, 0, 1), BlockId(1));
+        assert_ne!(chunk_hash(&chunk), empty);
+        chunk.set(IVec3::new(0, 0, 1), BlockId::AIR);
+        assert_eq!(chunk_hash(&chunk), empty);
+    }
+}
@@ -0,0 +1,32 @@
+pub mod replication;
+pub mod prediction;
+pub mod chat;
+pub mod transport;
+pub mod quic;
+pub mod chunks;
+pub mod simulator;
+
+use std::fmt;
+
+#[derive(Debug)]
+pub enum NetError {
+    Io(String),
+    Connect(String), // Reaching the server or the handshake failed
+    Closed(String), // The connection was lost or closed, with why
+    TooLarge(usize), // An unreliable message over the transport's datagram size, or a reliable one over MAX_MESSAGE
+    Invalid(String) // The server sent something malformed
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::Io(e) => write!(f, "{}", e),
+            NetError::Connect(e) => write!(f, "Failed to connect: {}", e),
+            NetError::Closed(e) => write!(f, "Connection closed: {}", e),
+            NetError::TooLarge(size) => write!(f, "{} bytes is too large to send", size),
+            NetError::Invalid(e) => write!(f, "Invalid data from the server: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for NetError {}
@@ -0,0 +1,370 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use glam::{IVec3, Vec3};
+
+use crate::util::cursor::Cursor;
+
+const MAX_PENDING: usize = 256; // Unacknowledged inputs kept for replay, I.E. 4 seconds at 64 ticks per second
+const PREDICTION_TOLERANCE: f32 = 0.01; // Mispredictions this small aren't corrected
+const SNAP_DISTANCE: f32 = 2.0; // Corrections larger than this are applied at once
+const CORRECTION_RATE: f32 = 10.0; // Per second, how fast smaller corrections are blended out
+const SKIN: f32 = 1e-3; // Gap kept between the player and blocks, so resting against one isn't overlapping it
+
+// How far a prediction at predicted was off from actual, None when it's close enough to leave alone. Shared
+// by MovementPrediction and Replication so players and other predicted entities correct the same way.
+pub(crate) fn misprediction(predicted: Vec3, actual: Vec3) -> Option<Vec3> {
+    let error = actual - predicted;
+    (error.length() > PREDICTION_TOLERANCE).then_some(error)
+}
+
+// The part of an outstanding correction to apply over dt seconds: all of it past SNAP_DISTANCE, otherwise
+// an exponentially decaying share
+pub(crate) fn correction_step(correction: Vec3, dt: f32) -> Vec3 {
+    match correction.length() > SNAP_DISTANCE {
+        true => correction,
+        false => correction * (1.0 - (-CORRECTION_RATE * dt).exp())
+    }
+}
+
+// One tick of player input. The sequence numbers it so the server can say which input it applied last.
+// Layout when encoded, numbers little endian: sequence u32, movement as 3 f32, flags u8 (1 is jump)
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct PlayerInput {
+    pub sequence: u32,
+    pub movement: Vec3, // World space direction to walk in, up to 1 long. Y is ignored.
+    pub jump: bool
+}
+
+impl PlayerInput {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(17);
+        out.extend_from_slice(&self.sequence.to_le_bytes());
+        out.extend(self.movement.to_array().iter().flat_map(|f| f.to_le_bytes()));
+        out.push(self.jump as u8);
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<PlayerInput, String> {
+        let mut cursor = Cursor::new(data);
+        let sequence = cursor.u32()?;
+        let movement = read_vec3(&mut cursor)?;
+        let flags = cursor.u8()?;
+
+        Ok(PlayerInput {
+            sequence,
+            movement,
+            jump: flags & 1 != 0
+        })
+    }
+}
+
+fn read_vec3(cursor: &mut Cursor) -> Result<Vec3, String> {
+    let mut f = [0.0; 3];
+    for v in f.iter_mut() {
+        *v = f32::from_bits(cursor.u32()?);
+    }
+    Ok(Vec3::from_array(f))
+}
+
+// Shared with the server, which has to move players the same way for predictions to hold
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MovementSettings {
+    pub speed: f32, // Units per second
+    pub acceleration: f32, // Units per second squared towards the wished velocity, on the ground
+    pub air_control: f32, // Fraction of the acceleration while airborne
+    pub gravity: f32,
+    pub jump_speed: f32,
+    pub half_extents: Vec3 // Of the player's box, which position is the center of
+}
+
+impl Default for MovementSettings {
+    fn default() -> Self {
+        MovementSettings {
+            speed: 5.0,
+            acceleration: 50.0,
+            air_control: 0.2,
+            gravity: 25.0,
+            jump_speed: 8.0,
+            half_extents: Vec3::new(0.3, 0.9, 0.3)
+        }
+    }
+}
+
+// Layout when encoded, numbers little endian: position and velocity as 3 f32 each, flags u8 (1 is on ground)
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub struct PlayerState {
+    pub position: Vec3,
+    pub velocity: Vec3,
+    pub on_ground: bool
+}
+
+impl PlayerState {
+    pub fn new(position: Vec3) -> PlayerState {
+        PlayerState {
+            position,
+            ..PlayerState::default()
+        }
+    }
+
+    // One tick of movement against unit blocks where solid is true, I.E. !VoxelWorld::block(pos).is_air().
+    // Deterministic, so the client replaying an input ends up where the server did.
+    pub fn simulate(&self, input: &PlayerInput, settings: &MovementSettings, dt: Duration,
+                    solid: &dyn Fn(IVec3) -> bool) -> PlayerState {
+        let dt = dt.as_secs_f32();
+        let wish = Vec3::new(input.movement.x, 0.0, input.movement.z).clamp_length_max(1.0) * settings.speed;
+        let acceleration = match self.on_ground {
+            true => settings.acceleration,
+            false => settings.acceleration * settings.air_control
+        };
+        let horizontal = Vec3::new(self.velocity.x, 0.0, self.velocity.z);
+        let horizontal = horizontal + (wish - horizontal).clamp_length_max(acceleration * dt);
+
+        let mut velocity = Vec3::new(horizontal.x, self.velocity.y - settings.gravity * dt, horizontal.z);
+        if input.jump && self.on_ground {
+            velocity.y = settings.jump_speed;
+        }
+
+        // An axis at a time, so blocked movement along one still slides along the others
+        let mut position = self.position;
+        let mut on_ground = false;
+        for axis in 0..3 {
+            if velocity[axis] == 0.0 {
+                continue;
+            }
+            let mut moved = position;
+            moved[axis] += velocity[axis] * dt;
+            if let Some(stop) = blocked(moved, axis, velocity[axis], settings.half_extents, solid) {
+                moved[axis] = stop;
+                on_ground |= axis == 1 && velocity.y < 0.0;
+                velocity[axis] = 0.0;
+            }
+            position = moved;
+        }
+
+        PlayerState {
+            position,
+            velocity,
+            on_ground
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(25);
+        out.extend(self.position.to_array().iter().chain(self.velocity.to_array().iter()).flat_map(|f| f.to_le_bytes()));
+        out.push(self.on_ground as u8);
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<PlayerState, String> {
+        let mut cursor = Cursor::new(data);
+        let position = read_vec3(&mut cursor)?;
+        let velocity = read_vec3(&mut cursor)?;
+        let flags = cursor.u8()?;
+
+        Ok(PlayerState {
+            position,
+            velocity,
+            on_ground: flags & 1 != 0
+        })
+    }
+}
+
+// Where along axis a box at center has to stop to leave the solid blocks it moved into, None if it's clear
+fn blocked(center: Vec3, axis: usize, velocity: f32, half_extents: Vec3, solid: &dyn Fn(IVec3) -> bool) -> Option<f32> {
+    let min = (center - half_extents + SKIN).floor().as_ivec3();
+    let max = (center + half_extents - SKIN).floor().as_ivec3();
+    let mut hit = false;
+    for x in min.x..=max.x {
+        for y in min.y..=max.y {
+            for z in min.z..=max.z {
+                hit |= solid(IVec3::new(x, y, z));
+            }
+        }
+    }
+    if !hit {
+        return None;
+    }
+
+    // Against the face of the block layer it moved into
+    Some(match velocity > 0.0 {
+        true => (center[axis] + half_extents[axis] - SKIN).floor() - half_extents[axis] - SKIN,
+        false => (center[axis] - half_extents[axis] + SKIN).floor() + 1.0 + half_extents[axis] + SKIN
+    })
+}
+
+// The server's reply to a player's inputs: the last one it applied and where that left the player
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MovementAck {
+    pub sequence: u32,
+    pub state: PlayerState
+}
+
+impl MovementAck {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = self.sequence.to_le_bytes().to_vec();
+        out.extend(self.state.encode());
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<MovementAck, String> {
+        let mut cursor = Cursor::new(data);
+        let sequence = cursor.u32()?;
+        let state = PlayerState::decode(cursor.rest())?;
+        Ok(MovementAck {
+            sequence,
+            state
+        })
+    }
+}
+
+// Moves the local player as soon as input is read instead of waiting out the round trip to the server.
+// Inputs stay pending until the server acknowledges them. Each acknowledgement restarts from the server's
+// state and replays the inputs it hasn't applied yet, and any difference from the prediction is blended out
+// of the shown position instead of jumping. Give the player's entity to Replication::predict so snapshots
+// don't move it as well.
+pub struct MovementPrediction {
+    pub settings: MovementSettings,
+    step: Duration, // The tick length, which the server has to simulate inputs with too
+    next_sequence: u32,
+    pending: VecDeque<PlayerInput>, // Oldest first
+    acked: Option<u32>, // The last sequence the server applied
+    state: PlayerState, // After every input so far
+    correction: Vec3 // Added to the shown position, decaying to nothing from the next apply
+}
+
+impl MovementPrediction {
+    pub fn new(state: PlayerState, step: Duration) -> MovementPrediction {
+        MovementPrediction {
+            settings: MovementSettings::default(),
+            step,
+            next_sequence: 0,
+            pending: VecDeque::new(),
+            acked: None,
+            state,
+            correction: Vec3::ZERO
+        }
+    }
+
+    // Call once per tick with the player's input. Moves the prediction and returns the input to send.
+    pub fn apply(&mut self, movement: Vec3, jump: bool, solid: &dyn Fn(IVec3) -> bool) -> PlayerInput {
+        let input = PlayerInput {
+            sequence: self.next_sequence,
+            movement,
+            jump
+        };
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.state = self.state.simulate(&input, &self.settings, self.step, solid);
+        self.pending.push_back(input);
+        if self.pending.len() > MAX_PENDING {
+            self.pending.pop_front(); // The server is far behind, or acks are being lost
+        }
+
+        self.correction -= correction_step(self.correction, self.step.as_secs_f32());
+        input
+    }
+
+    // The server applied every input up to ack.sequence and ended at ack.state. Acks older than the last
+    // one are ignored.
+    pub fn acknowledge(&mut self, ack: &MovementAck, solid: &dyn Fn(IVec3) -> bool) {
+        let newer = self.acked.map_or(true, |a| ack.sequence.wrapping_sub(a) as i32 > 0);
+        if !newer {
+            return;
+        }
+        self.acked = Some(ack.sequence);
+        while self.pending.front().map_or(false, |i| ack.sequence.wrapping_sub(i.sequence) as i32 >= 0) {
+            self.pending.pop_front();
+        }
+
+        let mut replayed = ack.state;
+        for input in self.pending.iter() {
+            replayed = replayed.simulate(input, &self.settings, self.step, solid);
+        }
+        // Shown where it was, then eased over to the replayed position
+        if let Some(error) = misprediction(replayed.position, self.state.position) {
+            self.correction += error;
+        }
+        self.state = replayed;
+    }
+
+    // After every input applied so far, including the unacknowledged ones
+    pub fn state(&self) -> &PlayerState {
+        &self.state
+    }
+
+    // Where to draw the player, easing out of corrections
+    pub fn position(&self) -> Vec3 {
+        self.state.position + self.correction
+    }
+
+    // Inputs sent that the server hasn't acknowledged yet, resend them while packets may be lost
+    pub fn pending(&self) -> impl Iterator<Item = &PlayerInput> {
+        self.pending.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_round_trip() {
+        let input = PlayerInput { sequence: u32::MAX, movement: Vec3::new(0.5, 0.0, -1.0), jump: true };
+        assert_eq!(PlayerInput::decode(&input.encode()).unwrap(), input);
+        assert!(PlayerInput::decode(&input.encode()[..16]).is_err());
+    }
+
+    #[test]
+    fn ack_round_trip() {
+        let state = PlayerState { position: Vec3::new(1.0, 64.5, -3.25), velocity: Vec3::new(0.0, -9.0, 2.0), on_ground: false };
+        assert_eq!(PlayerState::decode(&state.encode()).unwrap(), state);
+        let ack = MovementAck { sequence: 77, state };
+        assert_eq!(MovementAck::decode(&ack.encode()).unwrap(), ack);
+    }
+
+    #[test]
+    fn small_mispredictions_are_ignored() {
+        assert_eq!(misprediction(Vec3::ZERO, Vec3::splat(PREDICTION_TOLERANCE * 0.5)), None);
+        assert_eq!(misprediction(Vec3::ZERO, Vec3::X), Some(Vec3::X));
+    }
+
+    #[test]
+    fn corrections_blend_or_snap() {
+        let small = Vec3::new(0.5, 0.0, 0.0);
+        let step = correction_step(small, 0.1);
+        assert!(step.x > 0.0 && step.x < small.x);
+        let large = Vec3::new(SNAP_DISTANCE * 2.0, 0.0, 0.0);
+        assert_eq!(correction_step(large, 0.1), large);
+    }
+
+    #[test]
+    fn falls_onto_the_ground() {
+        let settings = MovementSettings::default();
+        let solid = |pos: IVec3| pos.y < 0;
+        let mut state = PlayerState::new(Vec3::new(0.5, 3.0, 0.5));
+        for _ in 0..200 {
+            state = state.simulate(&PlayerInput::default(), &settings, Duration::from_millis(16), &solid);
+        }
+        assert!(state.on_ground);
+        assert!((state.position.y - settings.half_extents.y).abs() < 0.01);
+    }
+
+    #[test]
+    fn replays_unacknowledged_inputs() {
+        let solid = |pos: IVec3| pos.y < 0;
+        let start = PlayerState { on_ground: true, ..PlayerState::new(Vec3::new(0.5, 0.9 + SKIN, 0.5)) };
+        let mut prediction = MovementPrediction::new(start, Duration::from_millis(16));
+        let inputs: Vec<PlayerInput> = (0..10).map(|_| prediction.apply(Vec3::X, false, &solid)).collect();
+        let predicted = *prediction.state();
+
+        // The server agrees with the first five, the replay of the rest ends where the prediction did
+        let mut server = start;
+        for input in inputs[..5].iter() {
+            server = server.simulate(input, &prediction.settings, Duration::from_millis(16), &solid);
+        }
+        prediction.acknowledge(&MovementAck { sequence: inputs[4].sequence, state: server }, &solid);
+        assert_eq!(prediction.pending().count(), 5);
+        assert!((prediction.state().position - predicted.position).length() < 1e-5);
+        assert_eq!(prediction.position(), prediction.state().position);
+    }
+}
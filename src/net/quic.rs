@@ -0,0 +1,161 @@
+use std::net::SocketAddr;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use quinn::{Connection, Endpoint, SendDatagramError};
+use tokio::runtime::Runtime;
+
+use crate::net::transport::{Channel, QuicSettings, Transport, MAX_MESSAGE};
+use crate::net::NetError;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+enum Event {
+    Message(Channel, Vec<u8>),
+    Closed(String)
+}
+
+// Unreliable messages are QUIC datagrams and every reliable message is a unidirectional stream of its own.
+// The connection runs on a tokio runtime with one worker thread, which hands what arrives to receive.
+pub struct QuicTransport {
+    runtime: Runtime,
+    _endpoint: Endpoint, // Drives the connection's socket
+    connection: Connection,
+    events: Receiver<Event>
+}
+
+impl QuicTransport {
+    pub fn connect(address: SocketAddr, settings: &QuicSettings) -> Result<QuicTransport, NetError> {
+        let mut config = match settings.certificate.as_ref() {
+            Some(der) => {
+                let mut roots = rustls::RootCertStore::empty();
+                roots.add(&rustls::Certificate(der.clone())).map_err(|e| NetError::Connect(e.to_string()))?;
+                quinn::ClientConfig::with_root_certificates(roots)
+            },
+            None => quinn::ClientConfig::with_native_roots()
+        };
+        let mut transport = quinn::TransportConfig::default();
+        transport.keep_alive_interval(Some(settings.keep_alive));
+        config.transport_config(Arc::new(transport));
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("quic")
+            .enable_all()
+            .build()
+            .map_err(|e| NetError::Io(e.to_string()))?;
+        let local = match address {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0))
+        };
+        let (endpoint, connection) = runtime.block_on(async {
+            let mut endpoint = Endpoint::client(local).map_err(|e| NetError::Io(e.to_string()))?;
+            endpoint.set_default_client_config(config);
+            let connecting = endpoint.connect(address, &settings.server_name)
+                .map_err(|e| NetError::Connect(e.to_string()))?;
+            let connection = tokio::time::timeout(CONNECT_TIMEOUT, connecting).await
+                .map_err(|_| NetError::Connect(String::from("Timed out")))?
+                .map_err(|e| NetError::Connect(e.to_string()))?;
+            Ok::<_, NetError>((endpoint, connection))
+        })?;
+
+        let (sender, events) = mpsc::channel();
+        runtime.spawn(receive_datagrams(connection.clone(), sender.clone()));
+        runtime.spawn(receive_streams(connection.clone(), sender));
+
+        Ok(QuicTransport {
+            runtime,
+            _endpoint: endpoint,
+            connection,
+            events
+        })
+    }
+}
+
+async fn receive_datagrams(connection: Connection, events: Sender<Event>) {
+    loop {
+        match connection.read_datagram().await {
+            Ok(data) => {
+                let _ = events.send(Event::Message(Channel::Unreliable, data.to_vec()));
+            },
+            Err(e) => {
+                let _ = events.send(Event::Closed(e.to_string()));
+                return;
+            }
+        }
+    }
+}
+
+async fn receive_streams(connection: Connection, events: Sender<Event>) {
+    // Closing is reported by receive_datagrams
+    while let Ok(stream) = connection.accept_uni().await {
+        let events = events.clone();
+        tokio::spawn(async move {
+            match stream.read_to_end(MAX_MESSAGE).await {
+                Ok(data) => {
+                    let _ = events.send(Event::Message(Channel::Reliable, data));
+                },
+                Err(e) => log::warn!("Dropped a reliable message from the server: {}", e)
+            }
+        });
+    }
+}
+
+impl Transport for QuicTransport {
+    fn send(&mut self, channel: Channel, data: &[u8]) -> Result<(), NetError> {
+        match channel {
+            Channel::Unreliable => match self.connection.send_datagram(Bytes::copy_from_slice(data)) {
+                Ok(()) => Ok(()),
+                Err(SendDatagramError::TooLarge) => Err(NetError::TooLarge(data.len())),
+                Err(SendDatagramError::ConnectionLost(e)) => Err(NetError::Closed(e.to_string())),
+                Err(e) => Err(NetError::Io(e.to_string()))
+            },
+            Channel::Reliable => {
+                if data.len() > MAX_MESSAGE {
+                    return Err(NetError::TooLarge(data.len())); // The server's read_to_end would fail on it
+                }
+                if let Some(reason) = self.connection.close_reason() {
+                    return Err(NetError::Closed(reason.to_string()));
+                }
+                let connection = self.connection.clone();
+                let data = data.to_vec();
+                self.runtime.spawn(async move {
+                    let sent = async {
+                        let mut stream = connection.open_uni().await?;
+                        stream.write_all(&data).await?;
+                        stream.finish().await?;
+                        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(())
+                    };
+                    if let Err(e) = sent.await {
+                        log::warn!("Failed to send a reliable message: {}", e);
+                    }
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Result<Vec<(Channel, Vec<u8>)>, NetError> {
+        let mut received = Vec::new();
+        for event in self.events.try_iter() {
+            match event {
+                Event::Message(channel, data) => received.push((channel, data)),
+                Event::Closed(reason) => return Err(NetError::Closed(reason))
+            }
+        }
+        Ok(received)
+    }
+
+    fn max_unreliable_size(&self) -> usize {
+        self.connection.max_datagram_size().unwrap_or(0)
+    }
+}
+
+impl Drop for QuicTransport {
+    fn drop(&mut self) {
+        // Tells the server right away instead of leaving it to time out
+        self.connection.close(0u32.into(), b"");
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::Duration;
+
+use glam::Vec3;
+
+use crate::ecs::components::{PreviousTransform, Transform};
+use crate::ecs::{Entity, World};
+use crate::net::prediction::{correction_step, misprediction};
+use crate::renderer::game_loop::Interpolate;
+use crate::save::entities::Persistent;
+use crate::util::cursor::Cursor;
+
+// Layout of an encoded snapshot, numbers little endian:
+//   server tick u32, entity count u32
+//   per entity: NetId u32, kind u16, then its Transform as written by Persistent::save
+const TRANSFORM_SIZE: usize = 40;
+const MAX_SNAPSHOTS: usize = 32; // Buffered, the oldest are dropped past this
+const CLOCK_SNAP: f64 = 1.0; // Seconds the render clock may drift from its target before it jumps there
+const CLOCK_CATCH_UP: f64 = 2.0; // Per second, how fast smaller drift is blended out
+const MAX_HISTORY: usize = 256; // Predicted ticks kept waiting for the server to confirm them
+
+// Names an entity the same way on the server and every client, unlike Entity which is local to a World
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NetId(pub u32);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EntityState {
+    pub kind: u16, // What the entity is, for the game to pick how it's drawn
+    pub transform: Transform
+}
+
+// Every replicated entity's state after a server tick
+#[derive(Clone, Debug, PartialEq, Default)]
+pub struct Snapshot {
+    pub tick: u32,
+    pub entities: BTreeMap<NetId, EntityState>
+}
+
+impl Snapshot {
+    pub fn new(tick: u32) -> Snapshot {
+        Snapshot {
+            tick,
+            entities: BTreeMap::new()
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.entities.len() * (6 + TRANSFORM_SIZE));
+        out.extend_from_slice(&self.tick.to_le_bytes());
+        out.extend_from_slice(&(self.entities.len() as u32).to_le_bytes());
+        for (id, state) in self.entities.iter() {
+            out.extend_from_slice(&id.0.to_le_bytes());
+            out.extend_from_slice(&state.kind.to_le_bytes());
+            state.transform.save(&mut out);
+        }
+
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Snapshot, String> {
+        let mut cursor = Cursor::new(data);
+        let mut snapshot = Snapshot::new(cursor.u32()?);
+        for _ in 0..cursor.u32()? {
+            let id = NetId(cursor.u32()?);
+            let kind = cursor.u16()?;
+            let transform = Transform::load(cursor.bytes(TRANSFORM_SIZE)?).ok_or("Malformed transform")?;
+            snapshot.entities.insert(id, EntityState { kind, transform });
+        }
+        if !cursor.rest().is_empty() {
+            return Err(String::from("Trailing data after the snapshot"));
+        }
+
+        Ok(snapshot)
+    }
+}
+
+// A locally moved entity, I.E. the player, checked against the server's snapshots instead of following them
+struct Prediction {
+    entity: Entity,
+    history: VecDeque<(u32, Vec3)>, // Predicted translation for each server tick not yet confirmed, oldest first
+    correction: Vec3 // Error still to be blended out of the entity's translation
+}
+
+// Mirrors the server's entities into a World from the snapshots it sends. Snapshots are buffered and
+// entities are shown a little in the past, interpolated between the two snapshots around that time, so
+// late or lost snapshots don't make them stutter. Entities appear and disappear with the snapshots.
+pub struct Replication {
+    tick_length: Duration, // Of the server's ticks
+    pub delay: Duration, // How far behind the newest snapshot entities are shown, a few snapshot intervals
+    snapshots: VecDeque<Snapshot>, // Oldest first
+    render_tick: Option<f64>, // Server time entities are shown at, in ticks. None before the first snapshot.
+    entities: HashMap<NetId, Entity>,
+    predicted: HashMap<NetId, Prediction>
+}
+
+impl Replication {
+    pub fn new(tick_length: Duration) -> Replication {
+        Replication {
+            tick_length,
+            delay: Duration::from_millis(100),
+            snapshots: VecDeque::new(),
+            render_tick: None,
+            entities: HashMap::new(),
+            predicted: HashMap::new()
+        }
+    }
+
+    // Snapshots can arrive in any order. Ones older than everything buffered once the buffer is full, or
+    // repeats, are ignored.
+    pub fn receive(&mut self, snapshot: Snapshot) {
+        let index = self.snapshots.partition_point(|s| s.tick < snapshot.tick);
+        if self.snapshots.get(index).map_or(false, |s| s.tick == snapshot.tick) ||
+            (index == 0 && self.snapshots.len() >= MAX_SNAPSHOTS) {
+            return;
+        }
+
+        self.reconcile(&snapshot);
+        self.snapshots.insert(index, snapshot);
+        if self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    // The newest server tick received
+    pub fn latest_tick(&self) -> Option<u32> {
+        self.snapshots.back().map(|s| s.tick)
+    }
+
+    // The local entity mirroring a server one
+    pub fn entity(&self, id: NetId) -> Option<Entity> {
+        self.entities.get(&id).or_else(|| self.predicted.get(&id).map(|p| &p.entity)).copied()
+    }
+
+    // Moves entity locally from now on instead of following the snapshots, correcting it whenever the
+    // server disagrees with where record_prediction said it would be
+    pub fn predict(&mut self, id: NetId, entity: Entity) {
+        self.entities.remove(&id);
+        self.predicted.insert(id, Prediction {
+            entity,
+            history: VecDeque::new(),
+            correction: Vec3::ZERO
+        });
+    }
+
+    // The entity follows the snapshots again, it's despawned if the server no longer has it
+    pub fn stop_predicting(&mut self, id: NetId) {
+        if let Some(p) = self.predicted.remove(&id) {
+            self.entities.insert(id, p.entity);
+        }
+    }
+
+    // Call each tick after moving a predicted entity, with the server tick the move will be simulated in
+    pub fn record_prediction(&mut self, id: NetId, tick: u32, transform: &Transform) {
+        if let Some(p) = self.predicted.get_mut(&id) {
+            // Including what's still to be corrected, so the next confirmation doesn't find it again
+            p.history.push_back((tick, transform.translation + p.correction));
+            if p.history.len() > MAX_HISTORY {
+                p.history.pop_front();
+            }
+        }
+    }
+
+    // Compares the predicted entities in snapshot with what was predicted for its tick
+    fn reconcile(&mut self, snapshot: &Snapshot) {
+        for (id, prediction) in self.predicted.iter_mut() {
+            let state = match snapshot.entities.get(id) {
+                Some(s) => s,
+                None => continue
+            };
+            let predicted = match prediction.history.iter().find(|(t, _)| *t == snapshot.tick) {
+                Some((_, p)) => *p,
+                None => continue // Not predicted, or already confirmed by a newer snapshot
+            };
+            prediction.history.retain(|(t, _)| *t > snapshot.tick);
+
+            if let Some(error) = misprediction(predicted, state.transform.translation) {
+                prediction.correction += error;
+                for (_, p) in prediction.history.iter_mut() {
+                    *p += error; // Predicted from the wrong position too
+                }
+            }
+        }
+    }
+
+    // Call every frame with the time since the last one. Returns the entities spawned for server entities
+    // seen for the first time, with a Transform, for the game to give them whatever draws them.
+    pub fn update(&mut self, world: &mut World, dt: Duration) -> Vec<(NetId, Entity, u16)> {
+        self.correct_predictions(world, dt);
+        let newest = match self.snapshots.back() {
+            Some(s) => s.tick as f64,
+            None => return Vec::new()
+        };
+
+        let tick_length = self.tick_length.as_secs_f64();
+        let target = newest - self.delay.as_secs_f64() / tick_length;
+        let render_tick = match self.render_tick {
+            Some(t) => {
+                let advanced = t + dt.as_secs_f64() / tick_length;
+                match (target - advanced).abs() * tick_length > CLOCK_SNAP {
+                    true => target,
+                    false => advanced + (target - advanced) * (dt.as_secs_f64() * CLOCK_CATCH_UP).min(1.0)
+                }
+            },
+            None => target
+        };
+        self.render_tick = Some(render_tick);
+
+        // The snapshots either side of the render time, or the nearest one past either end of the buffer
+        let next = self.snapshots.partition_point(|s| (s.tick as f64) <= render_tick);
+        let from = &self.snapshots[next.saturating_sub(1)];
+        let to = self.snapshots.get(next).unwrap_or(from);
+        let alpha = match to.tick > from.tick {
+            true => ((render_tick - from.tick as f64) / (to.tick - from.tick) as f64).clamp(0.0, 1.0) as f32,
+            false => 0.0
+        };
+
+        let mut spawned = Vec::new();
+        for (id, state) in from.entities.iter().filter(|(id, _)| !self.predicted.contains_key(id)) {
+            let transform = match to.entities.get(id) {
+                Some(next) => state.transform.interpolate(&next.transform, alpha),
+                None => state.transform
+            };
+            let entity = match self.entities.get(id).filter(|e| world.is_alive(**e)) {
+                Some(e) => *e,
+                None => {
+                    let e = world.spawn();
+                    self.entities.insert(*id, e);
+                    spawned.push((*id, e, state.kind));
+                    e
+                }
+            };
+            world.insert(entity, transform);
+            world.remove::<PreviousTransform>(entity); // Already smooth, ecs::render::extract draws it as is
+        }
+
+        let gone: Vec<NetId> = self.entities.keys().filter(|id| !from.entities.contains_key(id)).copied().collect();
+        for id in gone {
+            if let Some(e) = self.entities.remove(&id) {
+                world.despawn(e);
+            }
+        }
+
+        spawned
+    }
+
+    // Blends the errors found by reconcile out of the predicted entities' translations
+    fn correct_predictions(&mut self, world: &mut World, dt: Duration) {
+        for prediction in self.predicted.values_mut() {
+            let transform = match world.get_mut::<Transform>(prediction.entity) {
+                Some(t) => t,
+                None => continue
+            };
+            let step = correction_step(prediction.correction, dt.as_secs_f32());
+            transform.translation += step;
+            prediction.correction -= step;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use glam::Quat;
+
+    #[test]
+    fn snapshot_round_trip() {
+        let mut snapshot = Snapshot::new(1234);
+        snapshot.entities.insert(NetId(1), EntityState { kind: 2, transform: Transform::from_translation(Vec3::new(1.0, 2.0, 3.0)) });
+        snapshot.entities.insert(NetId(9), EntityState {
+            kind: 0,
+            transform: Transform {
+                translation: Vec3::new(-5.0, 0.0, 0.5),
+                rotation: Quat::from_rotation_y(1.0),
+                scale: Vec3::splat(2.0)
+            }
+        });
+        let encoded = snapshot.encode();
+        assert_eq!(Snapshot::decode(&encoded).unwrap(), snapshot);
+        assert!(Snapshot::decode(&encoded[..encoded.len() - 1]).is_err());
+
+        let mut trailing = encoded;
+        trailing.push(0);
+        assert!(Snapshot::decode(&trailing).is_err());
+    }
+}
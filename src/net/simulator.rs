@@ -0,0 +1,173 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::net::transport::{Channel, Transport};
+use crate::net::NetError;
+
+// Applied to each direction separately, so the round trip is twice the latency
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NetConditions {
+    pub latency: Duration,
+    pub jitter: Duration, // Up to this much extra delay per message, picked at random
+    pub loss: f32, // 0 to 1, chance an unreliable message is dropped
+    pub duplicate: f32, // 0 to 1, chance an unreliable message arrives twice
+    pub reorder: f32, // 0 to 1, chance an unreliable message is held back by reorder_delay on top
+    pub reorder_delay: Duration
+}
+
+impl NetConditions {
+    // Passes everything straight through
+    pub const NONE: NetConditions = NetConditions {
+        latency: Duration::ZERO,
+        jitter: Duration::ZERO,
+        loss: 0.0,
+        duplicate: 0.0,
+        reorder: 0.0,
+        reorder_delay: Duration::ZERO
+    };
+
+    // A typical home connection to a distant server
+    pub fn poor() -> NetConditions {
+        NetConditions {
+            latency: Duration::from_millis(75),
+            jitter: Duration::from_millis(25),
+            loss: 0.02,
+            duplicate: 0.005,
+            reorder: 0.02,
+            reorder_delay: Duration::from_millis(30)
+        }
+    }
+}
+
+impl Default for NetConditions {
+    fn default() -> Self {
+        NetConditions::NONE
+    }
+}
+
+// xorshift64*, seeded so a run can be repeated
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    // 0 to 1
+    fn unit(&mut self) -> f32 {
+        (self.next() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    fn chance(&mut self, p: f32) -> bool {
+        p > 0.0 && self.unit() < p
+    }
+}
+
+// Messages waiting out their delay in one direction, in the order they're due
+#[derive(Default)]
+struct Delayed {
+    queue: VecDeque<(Instant, Channel, Vec<u8>)>,
+    last_reliable: Option<Instant> // Reliable messages are never due before earlier ones, since the transport keeps them in order
+}
+
+impl Delayed {
+    fn push(&mut self, conditions: &NetConditions, random: &mut Random, now: Instant, channel: Channel, data: Vec<u8>) {
+        let copies = match channel {
+            Channel::Reliable => 1,
+            Channel::Unreliable if random.chance(conditions.loss) => 0,
+            Channel::Unreliable if random.chance(conditions.duplicate) => 2,
+            Channel::Unreliable => 1
+        };
+        for _ in 0..copies {
+            let mut due = now + conditions.latency + conditions.jitter.mul_f32(random.unit());
+            match channel {
+                Channel::Reliable => {
+                    due = self.last_reliable.map_or(due, |l| due.max(l));
+                    self.last_reliable = Some(due);
+                },
+                Channel::Unreliable if random.chance(conditions.reorder) => due += conditions.reorder_delay,
+                Channel::Unreliable => ()
+            }
+            let index = self.queue.partition_point(|(d, _, _)| *d <= due);
+            self.queue.insert(index, (due, channel, data.clone()));
+        }
+    }
+
+    fn pop_due(&mut self, now: Instant) -> Option<(Channel, Vec<u8>)> {
+        match self.queue.front() {
+            Some((due, _, _)) if *due <= now => self.queue.pop_front().map(|(_, c, d)| (c, d)),
+            _ => None
+        }
+    }
+}
+
+// Wraps a transport to delay, drop, duplicate and reorder what goes through it, I.E. to check how
+// Replication and MovementPrediction hold up against a bad connection without one. Reliable messages are
+// only delayed. Outgoing messages are passed on by later send and receive calls once they're due, so keep
+// calling receive every frame.
+pub struct SimulatedTransport<T: Transport> {
+    inner: T,
+    pub conditions: NetConditions,
+    random: Random,
+    outgoing: Delayed,
+    incoming: Delayed
+}
+
+impl<T: Transport> SimulatedTransport<T> {
+    pub fn new(inner: T, conditions: NetConditions, seed: u64) -> SimulatedTransport<T> {
+        SimulatedTransport {
+            inner,
+            conditions,
+            random: Random(seed | 1), // xorshift never leaves 0
+            outgoing: Delayed::default(),
+            incoming: Delayed::default()
+        }
+    }
+
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    fn send_due(&mut self, now: Instant) -> Result<(), NetError> {
+        while let Some((channel, data)) = self.outgoing.pop_due(now) {
+            self.inner.send(channel, &data)?;
+        }
+        Ok(())
+    }
+}
+
+impl<T: Transport> Transport for SimulatedTransport<T> {
+    fn send(&mut self, channel: Channel, data: &[u8]) -> Result<(), NetError> {
+        if channel == Channel::Unreliable && data.len() > self.inner.max_unreliable_size() {
+            return Err(NetError::TooLarge(data.len())); // Reported now rather than when it's due
+        }
+        let now = Instant::now();
+        self.outgoing.push(&self.conditions, &mut self.random, now, channel, data.to_vec());
+        self.send_due(now)
+    }
+
+    fn receive(&mut self) -> Result<Vec<(Channel, Vec<u8>)>, NetError> {
+        let now = Instant::now();
+        self.send_due(now)?;
+        for (channel, data) in self.inner.receive()? {
+            self.incoming.push(&self.conditions, &mut self.random, now, channel, data);
+        }
+
+        let mut received = Vec::new();
+        while let Some(message) = self.incoming.pop_due(now) {
+            received.push(message);
+        }
+        Ok(received)
+    }
+
+    fn max_unreliable_size(&self) -> usize {
+        self.inner.max_unreliable_size()
+    }
+}
@@ -0,0 +1,198 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use crate::net::quic::QuicTransport;
+use crate::net::NetError;
+
+pub(crate) const MAX_DATAGRAM: usize = 1200; // Fits the smallest MTU QUIC allows, so datagrams aren't fragmented
+pub(crate) const MAX_MESSAGE: usize = 16 << 20; // Larger reliable messages aren't sent, and received ones close the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Channel {
+    // May be lost, duplicated or arrive out of order, for state the next update replaces, I.E. snapshots and
+    // player inputs. Up to max_unreliable_size bytes.
+    Unreliable,
+    // Arrives complete, I.E. chunk data and chat. In order over UDP/TCP. QUIC gives each message its own
+    // stream so a lost packet only holds up the message it belongs to, which means order between messages
+    // isn't kept. Up to MAX_MESSAGE bytes.
+    Reliable
+}
+
+#[derive(Clone, Debug)]
+pub enum Protocol {
+    // Unreliable messages as UDP datagrams and reliable ones over a TCP connection, both to the same port
+    UdpTcp,
+    Quic(QuicSettings)
+}
+
+#[derive(Clone, Debug)]
+pub struct QuicSettings {
+    pub server_name: String, // Checked against the server's certificate
+    pub certificate: Option<Vec<u8>>, // DER, trusted in place of the system roots, I.E. a self signed dev server
+    pub keep_alive: Duration // Sent while idle, so NATs keep the connection open
+}
+
+impl QuicSettings {
+    pub fn new(server_name: &str) -> QuicSettings {
+        QuicSettings {
+            server_name: server_name.to_owned(),
+            certificate: None,
+            keep_alive: Duration::from_secs(5)
+        }
+    }
+}
+
+// A connection to the server. Neither call blocks, poll receive once per frame or tick.
+pub trait Transport: Send {
+    fn send(&mut self, channel: Channel, data: &[u8]) -> Result<(), NetError>;
+
+    // Messages that arrived since the last call. Each channel keeps its own order, see Channel.
+    fn receive(&mut self) -> Result<Vec<(Channel, Vec<u8>)>, NetError>;
+
+    fn max_unreliable_size(&self) -> usize;
+}
+
+// So what connect returns can be wrapped, I.E. in a SimulatedTransport
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn send(&mut self, channel: Channel, data: &[u8]) -> Result<(), NetError> {
+        (**self).send(channel, data)
+    }
+
+    fn receive(&mut self) -> Result<Vec<(Channel, Vec<u8>)>, NetError> {
+        (**self).receive()
+    }
+
+    fn max_unreliable_size(&self) -> usize {
+        (**self).max_unreliable_size()
+    }
+}
+
+// Blocks until connected or the connection fails, picking the transport by protocol
+pub fn connect(address: SocketAddr, protocol: &Protocol) -> Result<Box<dyn Transport>, NetError> {
+    Ok(match protocol {
+        Protocol::UdpTcp => Box::new(SocketTransport::connect(address)?),
+        Protocol::Quic(settings) => Box::new(QuicTransport::connect(address, settings)?)
+    })
+}
+
+fn io_error(e: std::io::Error) -> NetError {
+    NetError::Io(e.to_string())
+}
+
+// Reliable messages are framed as a little endian u32 length then the message
+pub struct SocketTransport {
+    udp: UdpSocket,
+    tcp: TcpStream,
+    read_buf: Vec<u8>, // Received but not yet a whole message
+    write_buf: Vec<u8> // Queued while the socket's send buffer was full
+}
+
+impl SocketTransport {
+    pub fn connect(address: SocketAddr) -> Result<SocketTransport, NetError> {
+        let tcp = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT).map_err(|e| NetError::Connect(e.to_string()))?;
+        tcp.set_nodelay(true).map_err(io_error)?;
+        tcp.set_nonblocking(true).map_err(io_error)?;
+        let local = match address {
+            SocketAddr::V4(_) => SocketAddr::from(([0, 0, 0, 0], 0)),
+            SocketAddr::V6(_) => SocketAddr::from(([0u16; 8], 0))
+        };
+        let udp = UdpSocket::bind(local).map_err(io_error)?;
+        udp.connect(address).map_err(|e| NetError::Connect(e.to_string()))?;
+        udp.set_nonblocking(true).map_err(io_error)?;
+
+        Ok(SocketTransport {
+            udp,
+            tcp,
+            read_buf: Vec::new(),
+            write_buf: Vec::new()
+        })
+    }
+
+    // Writes as much of write_buf as the socket takes without blocking
+    fn flush(&mut self) -> Result<(), NetError> {
+        while !self.write_buf.is_empty() {
+            match self.tcp.write(&self.write_buf) {
+                Ok(0) => return Err(NetError::Closed(String::from("The server closed the connection"))),
+                Ok(n) => {
+                    self.write_buf.drain(..n);
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(NetError::Closed(e.to_string()))
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Transport for SocketTransport {
+    fn send(&mut self, channel: Channel, data: &[u8]) -> Result<(), NetError> {
+        match channel {
+            Channel::Unreliable => {
+                if data.len() > MAX_DATAGRAM {
+                    return Err(NetError::TooLarge(data.len()));
+                }
+                match self.udp.send(data) {
+                    Err(e) if e.kind() != ErrorKind::WouldBlock => Err(io_error(e)),
+                    _ => Ok(()) // A full send buffer drops the datagram, like the network could
+                }
+            },
+            Channel::Reliable => {
+                if data.len() > MAX_MESSAGE {
+                    return Err(NetError::TooLarge(data.len())); // The length would wrap or the server would hang up
+                }
+                self.write_buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                self.write_buf.extend_from_slice(data);
+                self.flush()
+            }
+        }
+    }
+
+    fn receive(&mut self) -> Result<Vec<(Channel, Vec<u8>)>, NetError> {
+        self.flush()?;
+        let mut received = Vec::new();
+
+        let mut datagram = [0u8; MAX_DATAGRAM];
+        loop {
+            match self.udp.recv(&mut datagram) {
+                Ok(n) => received.push((Channel::Unreliable, datagram[..n].to_vec())),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                // Port unreachable replies to earlier datagrams, the TCP side notices if the server is gone
+                Err(e) if e.kind() == ErrorKind::ConnectionRefused || e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(io_error(e))
+            }
+        }
+
+        let mut chunk = [0u8; 16 << 10];
+        loop {
+            match self.tcp.read(&mut chunk) {
+                Ok(0) => return Err(NetError::Closed(String::from("The server closed the connection"))),
+                Ok(n) => self.read_buf.extend_from_slice(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(NetError::Closed(e.to_string()))
+            }
+        }
+        let mut start = 0;
+        while self.read_buf.len() - start >= 4 {
+            let len = u32::from_le_bytes(self.read_buf[start..start + 4].try_into().unwrap()) as usize;
+            if len > MAX_MESSAGE {
+                return Err(NetError::Closed(format!("{} byte message from the server", len)));
+            }
+            if self.read_buf.len() - start - 4 < len {
+                break;
+            }
+            received.push((Channel::Reliable, self.read_buf[start + 4..start + 4 + len].to_vec()));
+            start += 4 + len;
+        }
+        self.read_buf.drain(..start);
+
+        Ok(received)
+    }
+
+    fn max_unreliable_size(&self) -> usize {
+        MAX_DATAGRAM
+    }
+}
@@ -0,0 +1,86 @@
+use glam::Vec3;
+use rapier3d::prelude::{ColliderHandle, RigidBodyHandle};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum BodyType {
+    #[default]
+    Dynamic, // Moved by forces and collisions, its Transform is written after every tick
+    Fixed, // Never moves
+    Kinematic // Moved by its Transform, pushing dynamic bodies out of the way
+}
+
+// Simulates the entity. Physics::tick creates the rapier body from the entity's Transform the first tick
+// it sees it, changing the fields afterwards does nothing. Removing the component removes the body.
+#[derive(Clone, Copy, Debug)]
+pub struct RigidBody {
+    pub body_type: BodyType,
+    pub linear_velocity: Vec3, // Initial
+    pub gravity_scale: f32,
+    pub(crate) handle: Option<RigidBodyHandle> // Set once the body exists
+}
+
+impl RigidBody {
+    pub fn new(body_type: BodyType) -> RigidBody {
+        RigidBody {
+            body_type,
+            linear_velocity: Vec3::ZERO,
+            gravity_scale: 1.0,
+            handle: None
+        }
+    }
+
+    pub fn dynamic() -> RigidBody {
+        RigidBody::new(BodyType::Dynamic)
+    }
+
+    pub fn fixed() -> RigidBody {
+        RigidBody::new(BodyType::Fixed)
+    }
+
+    pub fn kinematic() -> RigidBody {
+        RigidBody::new(BodyType::Kinematic)
+    }
+}
+
+// Centered on the entity, ignoring its Transform's scale
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColliderShape {
+    Cuboid { half_extents: Vec3 },
+    Ball { radius: f32 },
+    Capsule { half_height: f32, radius: f32 } // Along Y, half_height is to the centers of the caps
+}
+
+// The entity's collision shape, attached to its RigidBody if it has one and fixed at its Transform otherwise.
+// Like RigidBody, it's only read when Physics::tick creates the collider.
+#[derive(Clone, Copy, Debug)]
+pub struct Collider {
+    pub shape: ColliderShape,
+    pub friction: f32,
+    pub restitution: f32, // Bounciness, 0 to 1
+    pub density: f32, // The body's mass comes from its colliders' volumes and densities
+    pub(crate) handle: Option<ColliderHandle>
+}
+
+impl Collider {
+    pub fn new(shape: ColliderShape) -> Collider {
+        Collider {
+            shape,
+            friction: 0.5,
+            restitution: 0.0,
+            density: 1.0,
+            handle: None
+        }
+    }
+
+    pub fn cuboid(half_extents: Vec3) -> Collider {
+        Collider::new(ColliderShape::Cuboid { half_extents })
+    }
+
+    pub fn ball(radius: f32) -> Collider {
+        Collider::new(ColliderShape::Ball { radius })
+    }
+
+    pub fn capsule(half_height: f32, radius: f32) -> Collider {
+        Collider::new(ColliderShape::Capsule { half_height, radius })
+    }
+}
@@ -0,0 +1,246 @@
+pub mod components;
+
+use std::time::Duration;
+
+use glam::{Mat4, Quat, Vec3};
+use rapier3d::na::{Quaternion, Translation3, UnitQuaternion};
+use rapier3d::prelude::{BroadPhase, CCDSolver, ColliderBuilder, ColliderHandle, ColliderSet, ImpulseJointSet,
+                        IntegrationParameters, IslandManager, Isometry, MultibodyJointSet, NarrowPhase, PhysicsPipeline,
+                        Real, RigidBodyBuilder, RigidBodyHandle, RigidBodySet, RigidBodyType, vector};
+
+use crate::ecs::components::Transform;
+use crate::ecs::{Entity, World};
+use crate::physics::components::{BodyType, Collider, ColliderShape, RigidBody};
+use crate::renderer::debug_draw::DebugDraw;
+use crate::renderer::frustum::Aabb;
+
+const DYNAMIC_COLOR: Vec3 = Vec3::new(0.2, 0.9, 0.3);
+const SLEEPING_COLOR: Vec3 = Vec3::new(0.4, 0.5, 0.4); // Dynamic bodies at rest
+const FIXED_COLOR: Vec3 = Vec3::new(0.3, 0.5, 1.0); // Fixed bodies and colliders without a body
+const KINEMATIC_COLOR: Vec3 = Vec3::new(0.9, 0.7, 0.2);
+
+fn isometry(transform: &Transform) -> Isometry<Real> {
+    let (t, r) = (transform.translation, transform.rotation);
+    Isometry::from_parts(Translation3::new(t.x, t.y, t.z),
+                         UnitQuaternion::new_normalize(Quaternion::new(r.w, r.x, r.y, r.z)))
+}
+
+fn translation(position: &Isometry<Real>) -> Vec3 {
+    Vec3::new(position.translation.x, position.translation.y, position.translation.z)
+}
+
+fn rotation(position: &Isometry<Real>) -> Quat {
+    Quat::from_xyzw(position.rotation.i, position.rotation.j, position.rotation.k, position.rotation.w)
+}
+
+fn collider_builder(collider: &Collider) -> ColliderBuilder {
+    let builder = match collider.shape {
+        ColliderShape::Cuboid { half_extents } => ColliderBuilder::cuboid(half_extents.x, half_extents.y, half_extents.z),
+        ColliderShape::Ball { radius } => ColliderBuilder::ball(radius),
+        ColliderShape::Capsule { half_height, radius } => ColliderBuilder::capsule_y(half_height, radius)
+    };
+    builder
+        .friction(collider.friction)
+        .restitution(collider.restitution)
+        .density(collider.density)
+}
+
+// A rapier world simulating the ECS's RigidBody and Collider entities. Call tick from Game::tick, after
+// ecs::render::store_previous so moved bodies are interpolated like anything else.
+pub struct Physics {
+    pub gravity: Vec3,
+    bodies: RigidBodySet,
+    colliders: ColliderSet,
+    integration: IntegrationParameters,
+    pipeline: PhysicsPipeline,
+    islands: IslandManager,
+    broad_phase: BroadPhase,
+    narrow_phase: NarrowPhase,
+    impulse_joints: ImpulseJointSet,
+    multibody_joints: MultibodyJointSet,
+    ccd: CCDSolver,
+    body_entities: Vec<(Entity, RigidBodyHandle)>, // Every body created, to notice removed components
+    collider_entities: Vec<(Entity, ColliderHandle)>
+}
+
+impl Default for Physics {
+    fn default() -> Self {
+        Physics::new()
+    }
+}
+
+impl Physics {
+    pub fn new() -> Physics {
+        Physics {
+            gravity: Vec3::new(0.0, -9.81, 0.0),
+            bodies: RigidBodySet::new(),
+            colliders: ColliderSet::new(),
+            integration: IntegrationParameters::default(),
+            pipeline: PhysicsPipeline::new(),
+            islands: IslandManager::new(),
+            broad_phase: BroadPhase::new(),
+            narrow_phase: NarrowPhase::new(),
+            impulse_joints: ImpulseJointSet::new(),
+            multibody_joints: MultibodyJointSet::new(),
+            ccd: CCDSolver::new(),
+            body_entities: Vec::new(),
+            collider_entities: Vec::new()
+        }
+    }
+
+    // Advances the simulation by step, the game loop's fixed step. Bodies and colliders are created for
+    // new components and removed for despawned entities or removed components first, kinematic bodies
+    // follow their Transforms, then dynamic bodies write theirs.
+    pub fn tick(&mut self, world: &mut World, step: Duration) {
+        self.remove_stale(world);
+        self.create_bodies(world);
+        self.create_colliders(world);
+
+        for (entity, handle) in self.body_entities.iter() {
+            if let (Some(body), Some(transform)) = (self.bodies.get_mut(*handle), world.get::<Transform>(*entity)) {
+                if body.body_type() == RigidBodyType::KinematicPositionBased {
+                    body.set_next_kinematic_position(isometry(transform));
+                }
+            }
+        }
+
+        self.integration.dt = step.as_secs_f32();
+        let gravity = vector![self.gravity.x, self.gravity.y, self.gravity.z];
+        self.pipeline.step(&gravity, &self.integration, &mut self.islands, &mut self.broad_phase, &mut self.narrow_phase,
+                           &mut self.bodies, &mut self.colliders, &mut self.impulse_joints, &mut self.multibody_joints,
+                           &mut self.ccd, None, &(), &());
+
+        for (entity, handle) in self.body_entities.iter() {
+            let body = match self.bodies.get(*handle).filter(|b| b.is_dynamic()) {
+                Some(b) => b,
+                None => continue
+            };
+            if let Some(transform) = world.get_mut::<Transform>(*entity) {
+                transform.translation = translation(body.position());
+                transform.rotation = rotation(body.position());
+            }
+        }
+    }
+
+    // Bodies and colliders whose entity no longer has the component that created them
+    fn remove_stale(&mut self, world: &mut World) {
+        let bodies = &mut self.bodies;
+        let (islands, colliders) = (&mut self.islands, &mut self.colliders);
+        let (impulse_joints, multibody_joints) = (&mut self.impulse_joints, &mut self.multibody_joints);
+        self.body_entities.retain(|(entity, handle)| {
+            let alive = world.get::<RigidBody>(*entity).and_then(|b| b.handle) == Some(*handle);
+            if !alive {
+                bodies.remove(*handle, islands, colliders, impulse_joints, multibody_joints, true);
+            }
+            alive
+        });
+        // Colliders are removed along with their body, ones whose entity kept the Collider are recreated fixed
+        self.collider_entities.retain(|(entity, handle)| {
+            let current = world.get::<Collider>(*entity).and_then(|c| c.handle) == Some(*handle);
+            match (current, colliders.contains(*handle)) {
+                (true, true) => return true,
+                (true, false) => world.get_mut::<Collider>(*entity).unwrap().handle = None, // current means it's there
+                (false, _) => {
+                    colliders.remove(*handle, islands, bodies, true);
+                }
+            }
+            false
+        });
+    }
+
+    fn create_bodies(&mut self, world: &mut World) {
+        let pending: Vec<(Entity, RigidBody)> = world.query::<RigidBody>()
+            .filter(|(_, b)| b.handle.is_none())
+            .map(|(e, b)| (e, *b))
+            .collect();
+        for (entity, desc) in pending {
+            let transform = world.get::<Transform>(entity).copied().unwrap_or_default();
+            let builder = match desc.body_type {
+                BodyType::Dynamic => RigidBodyBuilder::dynamic(),
+                BodyType::Fixed => RigidBodyBuilder::fixed(),
+                BodyType::Kinematic => RigidBodyBuilder::kinematic_position_based()
+            };
+            let v = desc.linear_velocity;
+            let handle = self.bodies.insert(builder
+                .position(isometry(&transform))
+                .linvel(vector![v.x, v.y, v.z])
+                .gravity_scale(desc.gravity_scale)
+                .build());
+            if let Some(body) = world.get_mut::<RigidBody>(entity) {
+                body.handle = Some(handle);
+            }
+            self.body_entities.push((entity, handle));
+        }
+    }
+
+    // After create_bodies, so colliders added along with their body attach to it
+    fn create_colliders(&mut self, world: &mut World) {
+        let pending: Vec<(Entity, Collider)> = world.query::<Collider>()
+            .filter(|(_, c)| c.handle.is_none())
+            .map(|(e, c)| (e, *c))
+            .collect();
+        for (entity, desc) in pending {
+            let builder = collider_builder(&desc);
+            let handle = match world.get::<RigidBody>(entity).and_then(|b| b.handle) {
+                Some(parent) => self.colliders.insert_with_parent(builder.build(), parent, &mut self.bodies),
+                None => {
+                    let transform = world.get::<Transform>(entity).copied().unwrap_or_default();
+                    self.colliders.insert(builder.position(isometry(&transform)).build())
+                }
+            };
+            if let Some(collider) = world.get_mut::<Collider>(entity) {
+                collider.handle = Some(handle);
+            }
+            self.collider_entities.push((entity, handle));
+        }
+    }
+
+    // The rapier body behind the component, I.E. for applying impulses. None until the first tick after
+    // it was added.
+    pub fn body(&self, body: &RigidBody) -> Option<&rapier3d::prelude::RigidBody> {
+        self.bodies.get(body.handle?)
+    }
+
+    pub fn body_mut(&mut self, body: &RigidBody) -> Option<&mut rapier3d::prelude::RigidBody> {
+        self.bodies.get_mut(body.handle?)
+    }
+
+    // Outlines every collider, colored by its body's type. Pass Frame::debug_draw to see them this frame.
+    pub fn draw_colliders(&self, debug: &mut DebugDraw) {
+        for (_, collider) in self.colliders.iter() {
+            let color = match collider.parent().and_then(|p| self.bodies.get(p)) {
+                Some(b) if b.is_dynamic() && b.is_sleeping() => SLEEPING_COLOR,
+                Some(b) if b.is_dynamic() => DYNAMIC_COLOR,
+                Some(b) if b.is_kinematic() => KINEMATIC_COLOR,
+                _ => FIXED_COLOR
+            };
+            let position = collider.position();
+            let (center, orientation) = (translation(position), rotation(position));
+            let shape = collider.shape();
+            if let Some(cuboid) = shape.as_cuboid() {
+                let half = cuboid.half_extents;
+                let size = Vec3::new(half.x, half.y, half.z) * 2.0;
+                debug.wire_cube(Mat4::from_scale_rotation_translation(size, orientation, center), color);
+            } else if let Some(ball) = shape.as_ball() {
+                debug.sphere(center, ball.radius, color);
+            } else if let Some(capsule) = shape.as_capsule() {
+                // The caps' spheres, joined along four sides
+                let (a, b) = (capsule.segment.a, capsule.segment.b);
+                let (a, b) = (position * a, position * b);
+                let (a, b) = (Vec3::new(a.x, a.y, a.z), Vec3::new(b.x, b.y, b.z));
+                debug.sphere(a, capsule.radius, color);
+                debug.sphere(b, capsule.radius, color);
+                for side in [Vec3::X, Vec3::NEG_X, Vec3::Z, Vec3::NEG_Z] {
+                    let offset = orientation * side * capsule.radius;
+                    debug.line(a + offset, b + offset, color);
+                }
+            } else {
+                let aabb = collider.compute_aabb();
+                debug.wire_box(Aabb {
+                    min: Vec3::new(aabb.mins.x, aabb.mins.y, aabb.mins.z),
+                    max: Vec3::new(aabb.maxs.x, aabb.maxs.y, aabb.maxs.z)
+                }, color);
+            }
+        }
+    }
+}
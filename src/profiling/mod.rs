@@ -0,0 +1,61 @@
+// CPU profiling zones, recorded with puffin when built with --features profiling and compiled out otherwise.
+// Connect puffin_viewer to SERVER_ADDRESS to see where frames spend their time.
+
+pub const SERVER_ADDRESS: &str = "127.0.0.1:8585"; // puffin_viewer's default
+
+// Times the rest of the enclosing block. The optional second argument is shown alongside, I.E. a file path.
+macro_rules! profile_scope {
+    ($name:expr) => {
+        #[cfg(feature = "profiling")]
+        puffin::profile_scope!($name);
+    };
+    ($name:expr, $data:expr) => {
+        #[cfg(feature = "profiling")]
+        puffin::profile_scope!($name, $data);
+    };
+}
+
+// Times the rest of the enclosing function, named after it
+macro_rules! profile_function {
+    () => {
+        #[cfg(feature = "profiling")]
+        puffin::profile_function!();
+    };
+}
+
+pub(crate) use profile_function;
+pub(crate) use profile_scope;
+
+// Keeps serving zones while it's alive. Holds nothing without the feature.
+pub struct Profiler {
+    #[cfg(feature = "profiling")]
+    _server: Option<puffin_http::Server>
+}
+
+// Turns zones on and starts serving them. A server that can't listen is logged and skipped, zones are
+// still recorded.
+pub fn start() -> Profiler {
+    #[cfg(feature = "profiling")]
+    {
+        puffin::set_scopes_on(true);
+        let server = match puffin_http::Server::new(SERVER_ADDRESS) {
+            Ok(s) => {
+                log::info!("Serving profiling zones on {}", SERVER_ADDRESS);
+                Some(s)
+            },
+            Err(e) => {
+                log::warn!("Profiling server couldn't listen on {}: {}", SERVER_ADDRESS, e);
+                None
+            }
+        };
+        Profiler { _server: server }
+    }
+    #[cfg(not(feature = "profiling"))]
+    Profiler {}
+}
+
+// Closes the previous frame's zones, called by the event loop before each frame
+pub(crate) fn new_frame() {
+    #[cfg(feature = "profiling")]
+    puffin::GlobalProfiler::lock().new_frame();
+}
@@ -0,0 +1,391 @@
+use std::sync::Mutex;
+
+use ash::vk;
+
+use crate::renderer::core::Core;
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::{CategoryStats, HeapStats, MemoryCategory, MemoryStats};
+use crate::renderer::physical_layer::PhysicalLayer;
+
+const DEFAULT_BLOCK_SIZE: vk::DeviceSize = 64 * 1024 * 1024;
+
+// A range of a larger vkDeviceMemory block. Resources bind at memory + offset.
+#[derive(Debug)]
+pub(crate) struct Allocation {
+    pub(crate) memory: vk::DeviceMemory,
+    pub(crate) offset: vk::DeviceSize,
+    pub(crate) size: vk::DeviceSize,
+    mapped: *mut u8, // Null unless the memory is host visible
+    category: MemoryCategory,
+    pool: usize,
+    block: usize
+}
+
+impl Allocation {
+    // Pointer to the start of this allocation, blocks stay mapped for their whole lifetime
+    pub(crate) fn mapped_ptr(&self) -> Option<*mut u8> {
+        match self.mapped.is_null() {
+            true => None,
+            false => Some(self.mapped)
+        }
+    }
+}
+
+struct MemoryBlock {
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    mapped: *mut u8,
+    free_ranges: Vec<(vk::DeviceSize, vk::DeviceSize)>, // (offset, size), sorted by offset and never adjacent
+    allocation_count: usize
+}
+
+// Blocks sharing a memory type. Linear resources (buffers) and optimal tiling images get separate
+// pools so bufferImageGranularity never has to be considered.
+struct Pool {
+    memory_type: u32,
+    linear: bool,
+    blocks: Vec<Option<MemoryBlock>> // Freed blocks leave a None so allocation block indices stay valid
+}
+
+struct AllocatorState {
+    pools: Vec<Pool>,
+    heap_allocated: [vk::DeviceSize; vk::MAX_MEMORY_HEAPS], // Bytes of blocks per heap
+    heap_used: [vk::DeviceSize; vk::MAX_MEMORY_HEAPS], // Bytes of allocations per heap
+    categories: [CategoryStats; MemoryCategory::ALL.len()] // Indexed by MemoryCategory as usize
+}
+
+// Sub-allocates resources out of large blocks instead of calling vkAllocateMemory per resource,
+// which would quickly hit maxMemoryAllocationCount
+pub(crate) struct Allocator {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    state: Mutex<AllocatorState>
+}
+
+unsafe impl Send for Allocator {} // The mapped pointers are only dereferenced through allocations
+unsafe impl Sync for Allocator {}
+
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    (value + alignment - 1) / alignment * alignment
+}
+
+impl MemoryBlock {
+    fn try_allocate(&mut self, size: vk::DeviceSize, alignment: vk::DeviceSize) -> Option<vk::DeviceSize> {
+        // First fit
+        let (idx, offset) = self.free_ranges
+            .iter()
+            .enumerate()
+            .find_map(|(i, &(range_offset, range_size))| {
+                let aligned = align_up(range_offset, alignment);
+                match aligned + size <= range_offset + range_size {
+                    true => Some((i, aligned)),
+                    false => None
+                }
+            })?;
+
+        let (range_offset, range_size) = self.free_ranges.remove(idx);
+        let tail = range_offset + range_size - (offset + size);
+        if tail > 0 {
+            self.free_ranges.insert(idx, (offset + size, tail));
+        }
+        if offset > range_offset { // Alignment padding stays usable
+            self.free_ranges.insert(idx, (range_offset, offset - range_offset));
+        }
+        self.allocation_count += 1;
+
+        Some(offset)
+    }
+
+    fn free(&mut self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        let idx = self.free_ranges.partition_point(|&(o, _)| o < offset);
+        self.free_ranges.insert(idx, (offset, size));
+
+        // Merge with the following range, then the preceding one
+        if idx + 1 < self.free_ranges.len() && offset + size == self.free_ranges[idx + 1].0 {
+            self.free_ranges[idx].1 += self.free_ranges[idx + 1].1;
+            self.free_ranges.remove(idx + 1);
+        }
+        if idx > 0 && self.free_ranges[idx - 1].0 + self.free_ranges[idx - 1].1 == offset {
+            self.free_ranges[idx - 1].1 += self.free_ranges[idx].1;
+            self.free_ranges.remove(idx);
+        }
+        self.allocation_count -= 1;
+    }
+}
+
+impl Allocator {
+    pub(crate) fn new(core: &Core, physical_layer: &PhysicalLayer) -> Allocator {
+        let memory_properties = unsafe {
+            core.instance.get_physical_device_memory_properties(physical_layer.physical_device)
+        };
+
+        Allocator {
+            memory_properties,
+            state: Mutex::new(AllocatorState {
+                pools: Vec::new(),
+                heap_allocated: [0; vk::MAX_MEMORY_HEAPS],
+                heap_used: [0; vk::MAX_MEMORY_HEAPS],
+                categories: [CategoryStats::default(); MemoryCategory::ALL.len()]
+            })
+        }
+    }
+
+    pub(crate) fn find_memory_type(&self, type_bits: u32, mem_props: vk::MemoryPropertyFlags) -> Option<u32> {
+        (0..self.memory_properties.memory_type_count).find(|&i| {
+            ((1 << i) & type_bits) > 0 && // If this physical memory type is valid for the requirement
+                self.memory_properties.memory_types[i as usize]
+                    .property_flags
+                    .contains(mem_props)
+        })
+    }
+
+    fn heap_index(&self, memory_type: u32) -> usize {
+        self.memory_properties.memory_types[memory_type as usize].heap_index as usize
+    }
+
+    fn block_size(&self, memory_type: u32) -> vk::DeviceSize {
+        // Small heaps, I.E. the 256MB host visible BAR, shouldn't be swallowed by a few blocks
+        let heap_size = self.memory_properties.memory_heaps[self.heap_index(memory_type)].size;
+
+        DEFAULT_BLOCK_SIZE.min(heap_size / 8)
+    }
+
+    pub(crate) fn allocate(&self,
+                           logical_layer: &LogicalLayer,
+                           reqs: vk::MemoryRequirements,
+                           mem_props: vk::MemoryPropertyFlags,
+                           linear: bool,
+                           category: MemoryCategory) -> Result<Allocation, RendererError> {
+        let memory_type = self.find_memory_type(reqs.memory_type_bits, mem_props)
+            .ok_or(RendererError::NoSuitableMemoryType)?;
+        let host_visible = self.memory_properties.memory_types[memory_type as usize]
+            .property_flags
+            .contains(vk::MemoryPropertyFlags::HOST_VISIBLE);
+        let heap = self.heap_index(memory_type);
+
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard; // So the pool and the counters can be borrowed at once
+
+        let pool_idx = match state.pools.iter().position(|p| p.memory_type == memory_type && p.linear == linear) {
+            Some(i) => i,
+            None => {
+                state.pools.push(Pool {
+                    memory_type,
+                    linear,
+                    blocks: Vec::new()
+                });
+                state.pools.len() - 1
+            }
+        };
+        let pool = &mut state.pools[pool_idx];
+
+        let existing = pool.blocks.iter_mut()
+            .enumerate()
+            .find_map(|(i, b)| b.as_mut().and_then(|b| b.try_allocate(reqs.size, reqs.alignment)).map(|offset| (i, offset)));
+        let (block_idx, offset) = match existing {
+            Some(found) => found,
+            None => {
+                // No room, oversized requests get a block of their own
+                let block_size = self.block_size(memory_type).max(reqs.size);
+                // Any buffer might need a device address with ray tracing on, and blocks are shared between them
+                let mut flags_info = vk::MemoryAllocateFlagsInfo::default()
+                    .flags(vk::MemoryAllocateFlags::DEVICE_ADDRESS);
+                let mut alloc_info = vk::MemoryAllocateInfo::default()
+                    .allocation_size(block_size)
+                    .memory_type_index(memory_type);
+                if linear && logical_layer.ray_tracing() {
+                    alloc_info = alloc_info.push_next(&mut flags_info);
+                }
+                let memory = unsafe {
+                    logical_layer.logical_device.allocate_memory(&alloc_info, None).map_err(vk_error("vkAllocateMemory"))?
+                };
+                let mapped = match host_visible {
+                    true => unsafe {
+                        match logical_layer.logical_device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty()) {
+                            Ok(p) => p as *mut u8,
+                            Err(e) => {
+                                logical_layer.logical_device.free_memory(memory, None);
+                                return Err(vk_error("vkMapMemory")(e));
+                            }
+                        }
+                    },
+                    false => std::ptr::null_mut()
+                };
+                state.heap_allocated[heap] += block_size;
+
+                let mut block = MemoryBlock {
+                    memory,
+                    size: block_size,
+                    mapped,
+                    free_ranges: vec![(0, block_size)],
+                    allocation_count: 0
+                };
+                let offset = block.try_allocate(reqs.size, reqs.alignment).unwrap(); // Offset 0 is always aligned
+
+                let block_idx = match pool.blocks.iter().position(|b| b.is_none()) {
+                    Some(i) => {
+                        pool.blocks[i] = Some(block);
+                        i
+                    },
+                    None => {
+                        pool.blocks.push(Some(block));
+                        pool.blocks.len() - 1
+                    }
+                };
+                (block_idx, offset)
+            }
+        };
+
+        state.heap_used[heap] += reqs.size;
+        state.categories[category as usize].allocations += 1;
+        state.categories[category as usize].bytes += reqs.size;
+        let block = pool.blocks[block_idx].as_ref().unwrap();
+        Ok(Allocation {
+            memory: block.memory,
+            offset,
+            size: reqs.size,
+            mapped: match block.mapped.is_null() {
+                true => std::ptr::null_mut(),
+                false => unsafe { block.mapped.add(offset as usize) }
+            },
+            category,
+            pool: pool_idx,
+            block: block_idx
+        })
+    }
+
+    pub(crate) fn free(&self, logical_layer: &LogicalLayer, allocation: &Allocation) {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        let pool = &mut state.pools[allocation.pool];
+        let heap = self.heap_index(pool.memory_type);
+        let live_blocks = pool.blocks.iter().filter(|b| b.is_some()).count();
+        let slot = &mut pool.blocks[allocation.block];
+
+        let block = slot.as_mut().expect("Allocation freed twice");
+        block.free(allocation.offset, allocation.size);
+        state.heap_used[heap] -= allocation.size;
+        state.categories[allocation.category as usize].allocations -= 1;
+        state.categories[allocation.category as usize].bytes -= allocation.size;
+
+        // Keep one empty block around per pool to avoid churn when a resource is recreated
+        if block.allocation_count == 0 && live_blocks > 1 {
+            unsafe { logical_layer.logical_device.free_memory(block.memory, None) }; // Unmaps implicitly
+            state.heap_allocated[heap] -= block.size;
+            *slot = None;
+        }
+    }
+
+    // What's allocated per heap and category. The heaps' budget and usage are queried from the driver when
+    // VK_EXT_memory_budget is enabled, which is cheap enough to do every frame.
+    pub(crate) fn stats(&self, core: &Core, physical_layer: &PhysicalLayer, budget: bool) -> MemoryStats {
+        let mut budget_props = vk::PhysicalDeviceMemoryBudgetPropertiesEXT::default();
+        if budget {
+            let mut props2 = vk::PhysicalDeviceMemoryProperties2::default().push_next(&mut budget_props);
+            unsafe { core.instance.get_physical_device_memory_properties2(physical_layer.physical_device, &mut props2) };
+        }
+
+        let state = self.state.lock().unwrap();
+        let heaps = self.memory_properties.memory_heaps[..self.memory_properties.memory_heap_count as usize].iter()
+            .enumerate()
+            .map(|(i, heap)| HeapStats {
+                device_local: heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL),
+                size: heap.size,
+                allocated: state.heap_allocated[i],
+                used: state.heap_used[i],
+                budget: Some(budget_props.heap_budget[i]).filter(|_| budget),
+                usage: Some(budget_props.heap_usage[i]).filter(|_| budget)
+            })
+            .collect();
+
+        MemoryStats {
+            heaps,
+            categories: MemoryCategory::ALL.iter().map(|&c| (c, state.categories[c as usize])).collect(),
+            budget_supported: budget
+        }
+    }
+
+    pub(crate) fn create_buffer(&self,
+                                logical_layer: &LogicalLayer,
+                                size: vk::DeviceSize,
+                                usage: vk::BufferUsageFlags,
+                                mem_props: vk::MemoryPropertyFlags,
+                                category: MemoryCategory) -> Result<(Allocation, vk::Buffer), RendererError> {
+        let buffer_create_info = vk::BufferCreateInfo::default()
+            .size(size)
+            .usage(usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+        let buffer = unsafe {
+            logical_layer.logical_device.create_buffer(&buffer_create_info, None).map_err(vk_error("vkCreateBuffer"))?
+        };
+        let mem_reqs = unsafe { logical_layer.logical_device.get_buffer_memory_requirements(buffer) };
+
+        let allocation = match self.allocate(logical_layer, mem_reqs, mem_props, true, category) {
+            Ok(a) => a,
+            Err(e) => {
+                unsafe { logical_layer.logical_device.destroy_buffer(buffer, None) }; // Don't leak the buffer
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = unsafe { logical_layer.logical_device.bind_buffer_memory(buffer, allocation.memory, allocation.offset) } {
+            self.free(logical_layer, &allocation);
+            unsafe { logical_layer.logical_device.destroy_buffer(buffer, None) };
+            return Err(vk_error("vkBindBufferMemory")(e));
+        }
+
+        Ok((allocation, buffer))
+    }
+
+    pub(crate) fn create_image(&self,
+                               logical_layer: &LogicalLayer,
+                               create_info: &vk::ImageCreateInfo,
+                               mem_props: vk::MemoryPropertyFlags,
+                               category: MemoryCategory) -> Result<(Allocation, vk::Image), RendererError> {
+        let image = unsafe {
+            logical_layer.logical_device.create_image(create_info, None).map_err(vk_error("vkCreateImage"))?
+        };
+        let mem_reqs = unsafe { logical_layer.logical_device.get_image_memory_requirements(image) };
+
+        let linear = create_info.tiling == vk::ImageTiling::LINEAR;
+        let allocation = match self.allocate(logical_layer, mem_reqs, mem_props, linear, category) {
+            Ok(a) => a,
+            Err(e) => {
+                unsafe { logical_layer.logical_device.destroy_image(image, None) };
+                return Err(e);
+            }
+        };
+
+        if let Err(e) = unsafe { logical_layer.logical_device.bind_image_memory(image, allocation.memory, allocation.offset) } {
+            self.free(logical_layer, &allocation);
+            unsafe { logical_layer.logical_device.destroy_image(image, None) };
+            return Err(vk_error("vkBindImageMemory")(e));
+        }
+
+        Ok((allocation, image))
+    }
+
+    pub(crate) fn destroy_buffer(&self, logical_layer: &LogicalLayer, buffer: vk::Buffer, allocation: &Allocation) {
+        unsafe { logical_layer.logical_device.destroy_buffer(buffer, None) };
+        self.free(logical_layer, allocation);
+    }
+
+    pub(crate) fn destroy_image(&self, logical_layer: &LogicalLayer, image: vk::Image, allocation: &Allocation) {
+        unsafe { logical_layer.logical_device.destroy_image(image, None) };
+        self.free(logical_layer, allocation);
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer) {
+        let mut state = self.state.lock().unwrap();
+        for pool in state.pools.iter() {
+            for block in pool.blocks.iter().flatten() {
+                unsafe { logical_layer.logical_device.free_memory(block.memory, None) };
+            }
+        }
+        state.pools.clear();
+        state.heap_allocated = [0; vk::MAX_MEMORY_HEAPS];
+        state.heap_used = [0; vk::MAX_MEMORY_HEAPS];
+        state.categories = [CategoryStats::default(); MemoryCategory::ALL.len()];
+    }
+}
@@ -0,0 +1,198 @@
+use glam::Vec2;
+
+use crate::renderer::error::RendererError;
+use crate::renderer::renderer::CubulousRenderer;
+use crate::renderer::sprite::{Sprite, UvRect};
+use crate::renderer::texture::TextureHandle;
+
+const PADDING: u32 = 1; // Filled with the image's edge texels, so linear filtering doesn't bleed neighbours in
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AtlasEntry(usize); // Index into the builder's images, in the order they were added
+
+// Where an added image ended up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AtlasRegion {
+    pub texture: TextureHandle, // The page holding the image
+    pub uv: UvRect
+}
+
+struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>
+}
+
+// A row of images the height of its tallest one
+struct Shelf {
+    y: u32,
+    height: u32,
+    x: u32 // Next free column
+}
+
+struct Page {
+    size: u32, // Width, and the height the shelves can grow to
+    shelves: Vec<Shelf>,
+    used_height: u32
+}
+
+impl Page {
+    // Top left corner of a padded width x height rect, None if it doesn't fit
+    fn place(&mut self, width: u32, height: u32) -> Option<(u32, u32)> {
+        if let Some(shelf) = self.shelves.iter_mut().find(|s| s.height >= height && s.x + width <= self.size) {
+            shelf.x += width;
+            return Some((shelf.x - width, shelf.y));
+        }
+        if self.used_height + height > self.size || width > self.size {
+            return None;
+        }
+        self.shelves.push(Shelf { y: self.used_height, height, x: width });
+        self.used_height += height;
+        Some((0, self.used_height - height))
+    }
+}
+
+// Collects small RGBA8 images, I.E. block faces or UI icons, and packs them into a few large textures so
+// they can share a material or a sprite draw call. Images are placed with shelf packing, tallest first.
+pub struct AtlasBuilder {
+    images: Vec<Image>,
+    page_size: u32, // Pages are this wide and at most this tall, usually a power of two like 1024
+    srgb: bool // As in CubulousRenderer::upload_texture, for every page
+}
+
+impl AtlasBuilder {
+    pub fn new(page_size: u32, srgb: bool) -> AtlasBuilder {
+        AtlasBuilder {
+            images: Vec::new(),
+            page_size,
+            srgb
+        }
+    }
+
+    // Tightly packed RGBA8 pixels. An image too big for a page gets a page of its own.
+    pub fn add(&mut self, width: u32, height: u32, pixels: &[u8]) -> AtlasEntry {
+        assert_eq!(pixels.len(), (width * height * 4) as usize, "Expected tightly packed RGBA8 pixels");
+        self.images.push(Image {
+            width,
+            height,
+            pixels: pixels.to_vec()
+        });
+
+        AtlasEntry(self.images.len() - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.images.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.images.is_empty()
+    }
+
+    // Packs the images and uploads one texture per page
+    pub fn build(self, renderer: &mut CubulousRenderer) -> Result<Atlas, RendererError> {
+        let mut order: Vec<usize> = (0..self.images.len()).collect();
+        order.sort_by_key(|&i| std::cmp::Reverse(self.images[i].height)); // Stable, so equal heights keep their order
+
+        let mut pages: Vec<Page> = Vec::new();
+        let mut placements = vec![(0, 0, 0); self.images.len()]; // Page and top left corner of the padded rect
+        for i in order {
+            let (width, height) = (self.images[i].width + PADDING * 2, self.images[i].height + PADDING * 2);
+            let placed = pages.iter_mut()
+                .enumerate()
+                .find_map(|(p, page)| page.place(width, height).map(|(x, y)| (p, x, y)));
+            placements[i] = match placed {
+                Some(p) => p,
+                None => {
+                    let mut page = Page {
+                        size: self.page_size.max(width).max(height),
+                        shelves: Vec::new(),
+                        used_height: 0
+                    };
+                    let (x, y) = page.place(width, height).unwrap(); // Always fits an empty page its size
+                    pages.push(page);
+                    (pages.len() - 1, x, y)
+                }
+            };
+        }
+
+        // Pages are cut down to the height their shelves use
+        let mut pixels: Vec<Vec<u8>> = pages.iter().map(|p| vec![0; (p.size * p.used_height * 4) as usize]).collect();
+        for (image, &(p, x, y)) in self.images.iter().zip(placements.iter()) {
+            blit_padded(&mut pixels[p], pages[p].size, image, x, y);
+        }
+
+        let mut textures: Vec<TextureHandle> = Vec::with_capacity(pages.len());
+        for (page, pixels) in pages.iter().zip(pixels.iter()) {
+            match renderer.upload_texture(page.size, page.used_height, pixels, self.srgb) {
+                Ok(t) => textures.push(t),
+                Err(e) => {
+                    for t in textures {
+                        renderer.remove_texture(t);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let regions = self.images.iter()
+            .zip(placements.iter())
+            .map(|(image, &(p, x, y))| AtlasRegion {
+                texture: textures[p],
+                uv: UvRect::from_pixels(Vec2::new((x + PADDING) as f32, (y + PADDING) as f32),
+                                        Vec2::new(image.width as f32, image.height as f32),
+                                        Vec2::new(pages[p].size as f32, pages[p].used_height as f32))
+            })
+            .collect();
+
+        Ok(Atlas {
+            pages: textures,
+            regions
+        })
+    }
+}
+
+// Copies the image into the page with its edge texels repeated into the padding around it
+fn blit_padded(page: &mut [u8], page_width: u32, image: &Image, x: u32, y: u32) {
+    let (width, height) = (image.width + PADDING * 2, image.height + PADDING * 2);
+    for row in 0..height {
+        let src_row = row.saturating_sub(PADDING).min(image.height.saturating_sub(1));
+        for col in 0..width {
+            let src_col = col.saturating_sub(PADDING).min(image.width.saturating_sub(1));
+            let src = ((src_row * image.width + src_col) * 4) as usize;
+            let dst = (((y + row) * page_width + x + col) * 4) as usize;
+            if let Some(texel) = image.pixels.get(src..src + 4) {
+                page[dst..dst + 4].copy_from_slice(texel); // Empty images leave their padding clear
+            }
+        }
+    }
+}
+
+// Packed images, each looked up by the entry add() returned for it
+pub struct Atlas {
+    pages: Vec<TextureHandle>,
+    regions: Vec<AtlasRegion> // Indexed by AtlasEntry
+}
+
+impl Atlas {
+    pub fn region(&self, entry: AtlasEntry) -> AtlasRegion {
+        self.regions[entry.0]
+    }
+
+    pub fn pages(&self) -> &[TextureHandle] {
+        &self.pages
+    }
+
+    // A sprite showing just the entry's image
+    pub fn sprite(&self, entry: AtlasEntry, position: Vec2, size: Vec2) -> Sprite {
+        let region = self.region(entry);
+        Sprite::new(region.texture, position, size).with_region(region.uv)
+    }
+
+    // Releases the pages' textures, materials referencing them keep their own references
+    pub fn remove(self, renderer: &mut CubulousRenderer) {
+        for page in self.pages {
+            renderer.remove_texture(page);
+        }
+    }
+}
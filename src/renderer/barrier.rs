@@ -0,0 +1,303 @@
+use ash::vk;
+
+use crate::renderer::logical_layer::LogicalLayer;
+
+// How a resource is used on either side of a barrier. Stages, accesses and image layouts all follow from
+// these, so call sites only say what the resource was and what it's becoming.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Usage {
+    Undefined, // Nothing to wait on, images lose their contents
+    TransferWrite, // Copies and fills
+    ShaderRead, // Anything reading uploaded data: vertex input, uniforms and sampling in any shader stage
+    FragmentSampled, // Sampled color in fragment shaders, I.E. post-processing inputs
+    DepthSampled, // Sampled depth in fragment shaders, I.E. shadow maps
+    ColorAttachment,
+    DepthAttachment,
+    ComputeWrite, // Storage buffer writes in compute shaders
+    StorageConsumer, // Anything after a dispatch touching its buffers: later dispatches, draws and indirect commands
+    IndirectDraw, // Indirect commands and the instances they draw
+    AccelerationStructureInput, // Vertices, indices and instances read by acceleration structure builds
+    AccelerationStructureBuild, // Acceleration structures being built, and read by the builds after them
+    RayTracing, // Ray tracing shaders tracing against acceleration structures and writing storage images
+    MeshShaderRead, // Task and mesh shaders reading uploaded meshlets and vertices
+    HostRead // The CPU reading back what the GPU wrote to a host visible buffer
+}
+
+impl Usage {
+    fn scope(self) -> (vk::PipelineStageFlags2, vk::AccessFlags2, vk::ImageLayout) {
+        match self {
+            Usage::Undefined => (vk::PipelineStageFlags2::NONE, vk::AccessFlags2::NONE, vk::ImageLayout::UNDEFINED),
+            Usage::TransferWrite => (vk::PipelineStageFlags2::ALL_TRANSFER, vk::AccessFlags2::TRANSFER_WRITE,
+                                     vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+            Usage::ShaderRead => (vk::PipelineStageFlags2::VERTEX_INPUT | vk::PipelineStageFlags2::VERTEX_SHADER |
+                                      vk::PipelineStageFlags2::FRAGMENT_SHADER | vk::PipelineStageFlags2::COMPUTE_SHADER,
+                                  vk::AccessFlags2::VERTEX_ATTRIBUTE_READ | vk::AccessFlags2::INDEX_READ |
+                                      vk::AccessFlags2::UNIFORM_READ | vk::AccessFlags2::SHADER_READ,
+                                  vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            Usage::FragmentSampled => (vk::PipelineStageFlags2::FRAGMENT_SHADER, vk::AccessFlags2::SHADER_READ,
+                                       vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL),
+            Usage::DepthSampled => (vk::PipelineStageFlags2::FRAGMENT_SHADER, vk::AccessFlags2::SHADER_READ,
+                                    vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL),
+            Usage::ColorAttachment => (vk::PipelineStageFlags2::COLOR_ATTACHMENT_OUTPUT, vk::AccessFlags2::COLOR_ATTACHMENT_WRITE,
+                                       vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+            Usage::DepthAttachment => (vk::PipelineStageFlags2::EARLY_FRAGMENT_TESTS | vk::PipelineStageFlags2::LATE_FRAGMENT_TESTS,
+                                       vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_READ | vk::AccessFlags2::DEPTH_STENCIL_ATTACHMENT_WRITE,
+                                       vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
+            Usage::ComputeWrite => (vk::PipelineStageFlags2::COMPUTE_SHADER, vk::AccessFlags2::SHADER_WRITE, vk::ImageLayout::GENERAL),
+            Usage::StorageConsumer => (vk::PipelineStageFlags2::COMPUTE_SHADER | vk::PipelineStageFlags2::DRAW_INDIRECT |
+                                           vk::PipelineStageFlags2::VERTEX_INPUT | vk::PipelineStageFlags2::VERTEX_SHADER |
+                                           vk::PipelineStageFlags2::FRAGMENT_SHADER,
+                                       vk::AccessFlags2::SHADER_READ | vk::AccessFlags2::SHADER_WRITE |
+                                           vk::AccessFlags2::VERTEX_ATTRIBUTE_READ | vk::AccessFlags2::INDEX_READ |
+                                           vk::AccessFlags2::INDIRECT_COMMAND_READ,
+                                       vk::ImageLayout::GENERAL),
+            Usage::IndirectDraw => (vk::PipelineStageFlags2::DRAW_INDIRECT | vk::PipelineStageFlags2::VERTEX_INPUT,
+                                    vk::AccessFlags2::INDIRECT_COMMAND_READ | vk::AccessFlags2::VERTEX_ATTRIBUTE_READ,
+                                    vk::ImageLayout::UNDEFINED), // Only buffers are drawn from
+            Usage::AccelerationStructureInput => (vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR,
+                                                  vk::AccessFlags2::SHADER_READ, vk::ImageLayout::UNDEFINED),
+            Usage::AccelerationStructureBuild => (vk::PipelineStageFlags2::ACCELERATION_STRUCTURE_BUILD_KHR,
+                                                  vk::AccessFlags2::ACCELERATION_STRUCTURE_READ_KHR |
+                                                      vk::AccessFlags2::ACCELERATION_STRUCTURE_WRITE_KHR,
+                                                  vk::ImageLayout::UNDEFINED),
+            Usage::RayTracing => (vk::PipelineStageFlags2::RAY_TRACING_SHADER_KHR,
+                                  vk::AccessFlags2::ACCELERATION_STRUCTURE_READ_KHR | vk::AccessFlags2::SHADER_READ |
+                                      vk::AccessFlags2::SHADER_WRITE,
+                                  vk::ImageLayout::GENERAL),
+            Usage::MeshShaderRead => (vk::PipelineStageFlags2::TASK_SHADER_EXT | vk::PipelineStageFlags2::MESH_SHADER_EXT,
+                                      vk::AccessFlags2::SHADER_READ, vk::ImageLayout::UNDEFINED), // Only buffers are read
+            Usage::HostRead => (vk::PipelineStageFlags2::HOST, vk::AccessFlags2::HOST_READ, vk::ImageLayout::UNDEFINED)
+        }
+    }
+
+    // Writes have to be made available, reads only need the execution dependency
+    fn writes(self) -> bool {
+        matches!(self, Usage::TransferWrite | Usage::ColorAttachment | Usage::DepthAttachment | Usage::ComputeWrite |
+            Usage::StorageConsumer | Usage::AccelerationStructureBuild | Usage::RayTracing)
+    }
+}
+
+// Which half of a queue family ownership transfer a barrier is, each is recorded on its own family's queue
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Ownership {
+    Keep,
+    Release { src_family: u32, dst_family: u32 }, // Only the source side's stages and accesses apply
+    Acquire { src_family: u32, dst_family: u32 } // Only the destination side's stages and accesses apply
+}
+
+impl Ownership {
+    fn families(self) -> (u32, u32) {
+        match self {
+            Ownership::Keep => (vk::QUEUE_FAMILY_IGNORED, vk::QUEUE_FAMILY_IGNORED),
+            Ownership::Release { src_family, dst_family } | Ownership::Acquire { src_family, dst_family } => (src_family, dst_family)
+        }
+    }
+}
+
+// Source and destination scopes of a transition, trimmed for ownership transfers
+struct Scopes {
+    src_stages: vk::PipelineStageFlags2,
+    src_access: vk::AccessFlags2,
+    dst_stages: vk::PipelineStageFlags2,
+    dst_access: vk::AccessFlags2,
+    old_layout: vk::ImageLayout,
+    new_layout: vk::ImageLayout
+}
+
+fn scopes(from: Usage, to: Usage, ownership: Ownership, discard: bool) -> Scopes {
+    let (src_stages, src_access, old_layout) = from.scope();
+    let (dst_stages, dst_access, new_layout) = to.scope();
+    let src_access = match from.writes() {
+        true => src_access,
+        false => vk::AccessFlags2::NONE
+    };
+    let old_layout = match discard {
+        true => vk::ImageLayout::UNDEFINED,
+        false => old_layout
+    };
+
+    match ownership {
+        Ownership::Keep => Scopes { src_stages, src_access, dst_stages, dst_access, old_layout, new_layout },
+        Ownership::Release { .. } => Scopes {
+            src_stages,
+            src_access,
+            dst_stages: vk::PipelineStageFlags2::NONE,
+            dst_access: vk::AccessFlags2::NONE,
+            old_layout,
+            new_layout
+        },
+        // Waited on through a semaphore at the destination's stages, which the barrier has to chain to
+        Ownership::Acquire { .. } => Scopes {
+            src_stages: dst_stages,
+            src_access: vk::AccessFlags2::NONE,
+            dst_stages,
+            dst_access,
+            old_layout,
+            new_layout
+        }
+    }
+}
+
+// Transitions recorded together in one pipeline barrier. Built up with the chained methods and recorded
+// with record, which falls back to vkCmdPipelineBarrier when synchronization2 isn't enabled.
+#[derive(Default)]
+pub(crate) struct Barriers {
+    memory: Vec<vk::MemoryBarrier2<'static>>,
+    buffers: Vec<vk::BufferMemoryBarrier2<'static>>,
+    images: Vec<vk::ImageMemoryBarrier2<'static>>
+}
+
+impl Barriers {
+    pub(crate) fn new() -> Barriers {
+        Barriers::default()
+    }
+
+    // Covers every resource used as from, for buffers that don't need a barrier each
+    pub(crate) fn memory(mut self, from: Usage, to: Usage) -> Barriers {
+        let s = scopes(from, to, Ownership::Keep, false);
+        self.memory.push(vk::MemoryBarrier2::default()
+            .src_stage_mask(s.src_stages)
+            .src_access_mask(s.src_access)
+            .dst_stage_mask(s.dst_stages)
+            .dst_access_mask(s.dst_access));
+        self
+    }
+
+    pub(crate) fn buffer(mut self, buffer: vk::Buffer, from: Usage, to: Usage, ownership: Ownership) -> Barriers {
+        let s = scopes(from, to, ownership, false);
+        let (src_family, dst_family) = ownership.families();
+        self.buffers.push(vk::BufferMemoryBarrier2::default()
+            .src_stage_mask(s.src_stages)
+            .src_access_mask(s.src_access)
+            .dst_stage_mask(s.dst_stages)
+            .dst_access_mask(s.dst_access)
+            .src_queue_family_index(src_family)
+            .dst_queue_family_index(dst_family)
+            .buffer(buffer)
+            .offset(0)
+            .size(vk::WHOLE_SIZE));
+        self
+    }
+
+    pub(crate) fn image(self, image: vk::Image, range: vk::ImageSubresourceRange, from: Usage, to: Usage,
+                        ownership: Ownership) -> Barriers {
+        self.push_image(image, range, from, to, ownership, false)
+    }
+
+    // Like image, but the old contents are thrown away while still waiting on from's stages
+    pub(crate) fn discard_image(self, image: vk::Image, range: vk::ImageSubresourceRange, from: Usage, to: Usage) -> Barriers {
+        self.push_image(image, range, from, to, Ownership::Keep, true)
+    }
+
+    fn push_image(mut self, image: vk::Image, range: vk::ImageSubresourceRange, from: Usage, to: Usage,
+                  ownership: Ownership, discard: bool) -> Barriers {
+        let s = scopes(from, to, ownership, discard);
+        let (src_family, dst_family) = ownership.families();
+        self.images.push(vk::ImageMemoryBarrier2::default()
+            .src_stage_mask(s.src_stages)
+            .src_access_mask(s.src_access)
+            .dst_stage_mask(s.dst_stages)
+            .dst_access_mask(s.dst_access)
+            .old_layout(s.old_layout)
+            .new_layout(s.new_layout)
+            .src_queue_family_index(src_family)
+            .dst_queue_family_index(dst_family)
+            .image(image)
+            .subresource_range(range));
+        self
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.memory.is_empty() && self.buffers.is_empty() && self.images.is_empty()
+    }
+
+    pub(crate) fn record(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer) {
+        if self.is_empty() {
+            return;
+        }
+
+        match logical_layer.capabilities.synchronization2 {
+            true => {
+                let dependency_info = vk::DependencyInfo::default()
+                    .memory_barriers(&self.memory)
+                    .buffer_memory_barriers(&self.buffers)
+                    .image_memory_barriers(&self.images);
+                unsafe { logical_layer.logical_device.cmd_pipeline_barrier2(command_buffer, &dependency_info) };
+            },
+            false => self.record_legacy(logical_layer, command_buffer)
+        }
+    }
+
+    // One barrier call takes a single pair of stage masks, so every transition's stages are merged. The
+    // stage and access bits used by Usage share their values with the original flags.
+    fn record_legacy(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer) {
+        let access = |a: vk::AccessFlags2| vk::AccessFlags::from_raw(a.as_raw() as u32);
+        let mut src_stages = vk::PipelineStageFlags2::NONE;
+        let mut dst_stages = vk::PipelineStageFlags2::NONE;
+
+        let memory: Vec<vk::MemoryBarrier> = self.memory.iter()
+            .map(|b| {
+                src_stages |= b.src_stage_mask;
+                dst_stages |= b.dst_stage_mask;
+                vk::MemoryBarrier::default()
+                    .src_access_mask(access(b.src_access_mask))
+                    .dst_access_mask(access(b.dst_access_mask))
+            })
+            .collect();
+        let buffers: Vec<vk::BufferMemoryBarrier> = self.buffers.iter()
+            .map(|b| {
+                src_stages |= b.src_stage_mask;
+                dst_stages |= b.dst_stage_mask;
+                vk::BufferMemoryBarrier::default()
+                    .src_access_mask(access(b.src_access_mask))
+                    .dst_access_mask(access(b.dst_access_mask))
+                    .src_queue_family_index(b.src_queue_family_index)
+                    .dst_queue_family_index(b.dst_queue_family_index)
+                    .buffer(b.buffer)
+                    .offset(b.offset)
+                    .size(b.size)
+            })
+            .collect();
+        let images: Vec<vk::ImageMemoryBarrier> = self.images.iter()
+            .map(|b| {
+                src_stages |= b.src_stage_mask;
+                dst_stages |= b.dst_stage_mask;
+                vk::ImageMemoryBarrier::default()
+                    .src_access_mask(access(b.src_access_mask))
+                    .dst_access_mask(access(b.dst_access_mask))
+                    .old_layout(b.old_layout)
+                    .new_layout(b.new_layout)
+                    .src_queue_family_index(b.src_queue_family_index)
+                    .dst_queue_family_index(b.dst_queue_family_index)
+                    .image(b.image)
+                    .subresource_range(b.subresource_range)
+            })
+            .collect();
+
+        // Empty masks aren't allowed without synchronization2
+        let src_stages = match src_stages.is_empty() {
+            true => vk::PipelineStageFlags::TOP_OF_PIPE,
+            false => vk::PipelineStageFlags::from_raw(src_stages.as_raw() as u32)
+        };
+        let dst_stages = match dst_stages.is_empty() {
+            true => vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+            false => vk::PipelineStageFlags::from_raw(dst_stages.as_raw() as u32)
+        };
+
+        unsafe {
+            logical_layer.logical_device.cmd_pipeline_barrier(command_buffer, src_stages, dst_stages, vk::DependencyFlags::empty(),
+                                                              &memory, &buffers, &images);
+        }
+    }
+}
+
+// Mip 0 of the first layer_count layers, what every transitioned attachment covers
+pub(crate) fn first_mip(aspect_mask: vk::ImageAspectFlags, layer_count: u32) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count
+    }
+}
@@ -0,0 +1,205 @@
+use ash::vk;
+
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+
+pub(crate) const MAX_BINDLESS_TEXTURES: u32 = 4096; // Matches MAX_TEXTURES in the fragment shaders
+pub(crate) const MAX_BINDLESS_TEXTURE_ARRAYS: u32 = 256; // Matches MAX_TEXTURE_ARRAYS in voxel.frag
+
+// Hands out the slots of one bindless binding
+struct Slots {
+    free: Vec<u32>,
+    next: u32, // Slots past this one have never been written, partially bound leaves them empty
+    max: u32
+}
+
+impl Slots {
+    fn new(max: u32) -> Slots {
+        Slots {
+            free: Vec::new(),
+            next: 0,
+            max
+        }
+    }
+
+    fn has_free(&self) -> bool {
+        !self.free.is_empty() || self.next < self.max
+    }
+
+    fn take(&mut self) -> u32 {
+        match self.free.pop() {
+            Some(s) => s,
+            None => {
+                assert!(self.next < self.max, "Every bindless texture slot is in use");
+                self.next += 1;
+                self.next - 1
+            }
+        }
+    }
+}
+
+// Descriptor set 1 of the scene pipelines: the material sampler and one big array holding every texture
+// the renderer owns, then the same for texture arrays with a nearest filtering sampler for pixel art. Draws
+// pick their textures by slot through push constants, so nothing is rebound between materials. Slots are
+// written with update after bind while frames in flight have the set bound, which is fine as long as those
+// frames don't sample the slot, so a slot is only reused once the frames that could have sampled its old
+// texture have finished.
+pub(crate) struct BindlessTextures {
+    pub(crate) set_layout: vk::DescriptorSetLayout,
+    pool: vk::DescriptorPool,
+    pub(crate) set: vk::DescriptorSet,
+    slots: Slots,
+    array_slots: Slots // Numbered separately, a texture and an array can share a slot number
+}
+
+impl BindlessTextures {
+    pub(crate) fn new(logical_layer: &LogicalLayer, sampler: vk::Sampler, pixel_sampler: vk::Sampler) -> Result<BindlessTextures, RendererError> {
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(1)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE) // naga has no combined image samplers
+                .descriptor_count(MAX_BINDLESS_TEXTURES)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(2)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(MAX_BINDLESS_TEXTURE_ARRAYS)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+            vk::DescriptorSetLayoutBinding::default()
+                .binding(3)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+        ];
+        let bindless_flags = vk::DescriptorBindingFlags::UPDATE_AFTER_BIND |
+            vk::DescriptorBindingFlags::UPDATE_UNUSED_WHILE_PENDING |
+            vk::DescriptorBindingFlags::PARTIALLY_BOUND;
+        let binding_flags = [
+            vk::DescriptorBindingFlags::empty(),
+            bindless_flags,
+            bindless_flags,
+            vk::DescriptorBindingFlags::empty()
+        ];
+        let mut binding_flags_info = vk::DescriptorSetLayoutBindingFlagsCreateInfo::default()
+            .binding_flags(&binding_flags);
+        let create_info = vk::DescriptorSetLayoutCreateInfo::default()
+            .flags(vk::DescriptorSetLayoutCreateFlags::UPDATE_AFTER_BIND_POOL)
+            .bindings(&bindings)
+            .push_next(&mut binding_flags_info);
+        let set_layout = unsafe {
+            logical_layer.logical_device.create_descriptor_set_layout(&create_info, None)
+                .map_err(vk_error("vkCreateDescriptorSetLayout"))?
+        };
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::SAMPLER)
+                .descriptor_count(2),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(MAX_BINDLESS_TEXTURES + MAX_BINDLESS_TEXTURE_ARRAYS)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .flags(vk::DescriptorPoolCreateFlags::UPDATE_AFTER_BIND)
+            .pool_sizes(&pool_sizes)
+            .max_sets(1);
+        let pool = match unsafe { logical_layer.logical_device.create_descriptor_pool(&pool_create_info, None) } {
+            Ok(p) => p,
+            Err(e) => {
+                unsafe { logical_layer.logical_device.destroy_descriptor_set_layout(set_layout, None) };
+                return Err(vk_error("vkCreateDescriptorPool")(e));
+            }
+        };
+
+        let layouts = [set_layout];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts);
+        let set = match unsafe { logical_layer.logical_device.allocate_descriptor_sets(&alloc_info) } {
+            Ok(sets) => sets[0],
+            Err(e) => {
+                unsafe {
+                    logical_layer.logical_device.destroy_descriptor_pool(pool, None);
+                    logical_layer.logical_device.destroy_descriptor_set_layout(set_layout, None);
+                }
+                return Err(vk_error("vkAllocateDescriptorSets")(e));
+            }
+        };
+
+        let sampler_infos = [vk::DescriptorImageInfo::default()
+            .sampler(sampler)];
+        let pixel_sampler_infos = [vk::DescriptorImageInfo::default()
+            .sampler(pixel_sampler)];
+        let writes = [
+            vk::WriteDescriptorSet::default()
+                .dst_set(set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .image_info(&sampler_infos),
+            vk::WriteDescriptorSet::default()
+                .dst_set(set)
+                .dst_binding(3)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .image_info(&pixel_sampler_infos)
+        ];
+        unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+
+        Ok(BindlessTextures {
+            set_layout,
+            pool,
+            set,
+            slots: Slots::new(MAX_BINDLESS_TEXTURES),
+            array_slots: Slots::new(MAX_BINDLESS_TEXTURE_ARRAYS)
+        })
+    }
+
+    // array picks the texture array binding over the 2D one
+    pub(crate) fn has_free_slot(&self, array: bool) -> bool {
+        match array {
+            true => self.array_slots.has_free(),
+            false => self.slots.has_free()
+        }
+    }
+
+    // Writes the view into a free slot, panics if there's none. Check has_free_slot before creating the texture.
+    pub(crate) fn add(&mut self, logical_layer: &LogicalLayer, view: vk::ImageView, array: bool) -> u32 {
+        let (slot, binding) = match array {
+            true => (self.array_slots.take(), 2),
+            false => (self.slots.take(), 1)
+        };
+
+        let image_infos = [vk::DescriptorImageInfo::default()
+            .image_view(view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let writes = [vk::WriteDescriptorSet::default()
+            .dst_set(self.set)
+            .dst_binding(binding)
+            .dst_array_element(slot)
+            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+            .image_info(&image_infos)];
+        unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+
+        slot
+    }
+
+    // No frame in flight may still sample the slot. It keeps pointing at the old view until it's reused,
+    // which is fine since nothing reads it.
+    pub(crate) fn free(&mut self, slot: u32, array: bool) {
+        match array {
+            true => self.array_slots.free.push(slot),
+            false => self.slots.free.push(slot)
+        }
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer) {
+        unsafe {
+            logical_layer.logical_device.destroy_descriptor_pool(self.pool, None); // Frees the set as well
+            logical_layer.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use glam::{Mat4, Vec2, Vec3};
+
+use crate::renderer::frustum::Frustum;
+use crate::renderer::pick::Ray;
+
+pub struct Camera {
+    pub(crate) position: Vec3,
+    pub(crate) target: Vec3,
+    pub(crate) up: Vec3,
+    pub(crate) fov_y: f32, // Vertical field of view in radians
+    pub(crate) aspect: f32,
+    pub(crate) near: f32,
+    pub(crate) far: f32,
+    pub(crate) view: Mat4,
+    pub(crate) proj: Mat4
+}
+
+impl Camera {
+    pub fn new(position: Vec3, target: Vec3, fov_y: f32, aspect: f32) -> Camera {
+        let mut camera = Camera {
+            position,
+            target,
+            up: Vec3::Y,
+            fov_y,
+            aspect,
+            near: 0.1,
+            far: 100.0,
+            view: Mat4::IDENTITY,
+            proj: Mat4::IDENTITY
+        };
+        camera.update_view();
+        camera.update_proj();
+
+        camera
+    }
+
+    pub fn look_at(&mut self, position: Vec3, target: Vec3) {
+        self.position = position;
+        self.target = target;
+        self.update_view();
+    }
+
+    pub fn set_fov(&mut self, fov_y: f32) {
+        self.fov_y = fov_y;
+        self.update_proj();
+    }
+
+    pub fn set_clip_planes(&mut self, near: f32, far: f32) {
+        self.near = near;
+        self.far = far;
+        self.update_proj();
+    }
+
+    pub(crate) fn set_aspect(&mut self, aspect: f32) {
+        self.aspect = aspect;
+        self.update_proj();
+    }
+
+    pub fn position(&self) -> Vec3 {
+        self.position
+    }
+
+    pub fn target(&self) -> Vec3 {
+        self.target
+    }
+
+    pub fn view(&self) -> Mat4 {
+        self.view
+    }
+
+    pub fn proj(&self) -> Mat4 {
+        self.proj
+    }
+
+    pub fn frustum(&self) -> Frustum {
+        Frustum::from_view_proj(self.proj * self.view)
+    }
+
+    // Ray through a pixel of a viewport_size render, I.E. the cursor
+    pub fn screen_ray(&self, screen_pos: Vec2, viewport_size: Vec2) -> Ray {
+        Ray::from_screen(self.proj * self.view, screen_pos, viewport_size)
+    }
+
+    fn update_view(&mut self) {
+        self.view = Mat4::look_at_rh(self.position, self.target, self.up);
+    }
+
+    fn update_proj(&mut self) {
+        // glam's perspective_rh already maps depth to Vulkan's [0, 1] range
+        self.proj = Mat4::perspective_rh(self.fov_y, self.aspect, self.near, self.far);
+        self.proj.y_axis.y *= -1.0; // Vulkan clip space Y points down, unlike OpenGL
+    }
+}
@@ -0,0 +1,114 @@
+use std::f32::consts::FRAC_PI_2;
+use std::time::Duration;
+
+use glam::{Vec2, Vec3};
+
+use crate::input::InputState;
+use crate::renderer::camera::Camera;
+
+const PITCH_LIMIT: f32 = FRAC_PI_2 - 0.01; // Looking straight up or down makes look_at degenerate
+
+// Unit direction from yaw and pitch, yaw 0 looks down -Z like the default camera
+fn direction(yaw: f32, pitch: f32) -> Vec3 {
+    Vec3::new(yaw.sin() * pitch.cos(), pitch.sin(), -yaw.cos() * pitch.cos())
+}
+
+// Yaw and pitch that look along dir
+fn angles(dir: Vec3) -> (f32, f32) {
+    let dir = dir.normalize_or_zero();
+    (dir.x.atan2(-dir.z), dir.y.clamp(-1.0, 1.0).asin())
+}
+
+// WASD to move, space and shift for up and down, mouse to look while the "look" action is held.
+// Movement uses the move_* actions from the input bindings, look_* turns at stick_speed without holding "look".
+pub struct FlyCameraController {
+    pub speed: f32, // Units per second
+    pub sensitivity: f32, // Radians per pixel of mouse motion
+    pub stick_speed: f32, // Radians per second with the look_* actions fully pushed
+    pub always_look: bool, // Look without holding "look", I.E. while the cursor is locked
+    yaw: f32,
+    pitch: f32
+}
+
+impl FlyCameraController {
+    // Starts looking the same way as the camera
+    pub fn new(camera: &Camera) -> FlyCameraController {
+        let (yaw, pitch) = angles(camera.target() - camera.position());
+
+        FlyCameraController {
+            speed: 5.0,
+            sensitivity: 0.003,
+            stick_speed: 2.5,
+            always_look: false,
+            yaw,
+            pitch
+        }
+    }
+
+    pub fn update(&mut self, camera: &mut Camera, input: &InputState, delta: Duration) {
+        if self.always_look || input.action_down("look") {
+            let look = input.mouse_delta() * self.sensitivity;
+            self.yaw += look.x;
+            self.pitch = (self.pitch - look.y).clamp(-PITCH_LIMIT, PITCH_LIMIT); // Screen Y points down
+        }
+        let turn = self.stick_speed * delta.as_secs_f32();
+        self.yaw += input.action_axis("look_left", "look_right") * turn;
+        self.pitch = (self.pitch + input.action_axis("look_down", "look_up") * turn).clamp(-PITCH_LIMIT, PITCH_LIMIT);
+
+        let forward = direction(self.yaw, self.pitch);
+        let right = forward.cross(Vec3::Y).normalize();
+        let movement = forward * input.action_axis("move_back", "move_forward") +
+            right * input.action_axis("move_left", "move_right") +
+            Vec3::Y * input.action_axis("move_down", "move_up");
+
+        // Full speed on keys, partly pushed sticks move slower
+        let movement = movement.clamp_length_max(1.0);
+        let position = camera.position() + movement * self.speed * delta.as_secs_f32();
+        camera.look_at(position, position + forward);
+    }
+}
+
+// Circles a focus point. Drag with the "look" action held to rotate, scroll to zoom.
+pub struct OrbitCameraController {
+    pub focus: Vec3,
+    pub sensitivity: f32, // Radians per pixel of mouse motion
+    pub zoom_speed: f32, // Fraction of the distance per scroll line
+    pub min_distance: f32,
+    pub max_distance: f32,
+    yaw: f32,
+    pitch: f32,
+    distance: f32
+}
+
+impl OrbitCameraController {
+    // Orbits the camera's current target from its current position
+    pub fn new(camera: &Camera) -> OrbitCameraController {
+        let offset = camera.position() - camera.target();
+        let (yaw, pitch) = angles(-offset); // Angles of the view direction, the camera sits opposite it
+
+        OrbitCameraController {
+            focus: camera.target(),
+            sensitivity: 0.005,
+            zoom_speed: 0.1,
+            min_distance: 0.5,
+            max_distance: 50.0,
+            yaw,
+            pitch,
+            distance: offset.length().max(0.5)
+        }
+    }
+
+    pub fn update(&mut self, camera: &mut Camera, input: &InputState, _delta: Duration) {
+        if input.action_down("look") {
+            let drag: Vec2 = input.mouse_delta() * self.sensitivity;
+            self.yaw += drag.x;
+            self.pitch = (self.pitch - drag.y).clamp(-PITCH_LIMIT, PITCH_LIMIT);
+        }
+
+        let zoom = 1.0 - input.scroll_delta().y * self.zoom_speed;
+        self.distance = (self.distance * zoom).clamp(self.min_distance, self.max_distance);
+
+        let position = self.focus - direction(self.yaw, self.pitch) * self.distance;
+        camera.look_at(position, self.focus);
+    }
+}
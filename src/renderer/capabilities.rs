@@ -0,0 +1,99 @@
+use std::ffi::{CStr, CString};
+
+use ash::{vk, Instance};
+
+use crate::renderer::meshlet::mesh_shading_extensions;
+use crate::renderer::ray_tracing::ray_tracing_extensions;
+
+// Optional device features negotiated at device creation. PhysicalLayer records what the device supports,
+// LogicalLayer what was both requested and supported, and only the latter is enabled and branched on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct DeviceCapabilities {
+    pub descriptor_indexing: bool, // Bindless sampled images, updated after bind and partially bound. Required.
+    pub dynamic_rendering: bool, // The scene pass can skip its render pass and framebuffer
+    pub synchronization2: bool, // Barriers are recorded with vkCmdPipelineBarrier2
+    pub buffer_device_address: bool, // Always enabled along with ray tracing
+    pub ray_tracing: bool, // The extensions in ray_tracing_extensions, with acceleration structures and ray tracing pipelines
+    pub mesh_shading: bool, // VK_EXT_mesh_shader with task and mesh shaders
+    pub memory_budget: bool // VK_EXT_memory_budget, so MemoryStats has the driver's budget per heap
+}
+
+impl DeviceCapabilities {
+    // What the device supports, from one vkGetPhysicalDeviceFeatures2 call with every feature struct chained.
+    // Extension feature structs are only chained when the device has the extensions.
+    pub(crate) fn query(instance: &Instance, physical_device: vk::PhysicalDevice) -> DeviceCapabilities {
+        let dev_extensions = unsafe {
+            instance.enumerate_device_extension_properties(physical_device).unwrap_or_default()
+        };
+        let has_all = |extensions: Vec<CString>| extensions.iter()
+            .all(|e| dev_extensions.iter().any(|d| unsafe { CStr::from_ptr(d.extension_name.as_ptr()) } == e.as_c_str()));
+        let ray_tracing_present = has_all(ray_tracing_extensions());
+        let mesh_shading_present = has_all(mesh_shading_extensions());
+        let memory_budget = has_all(vec![CString::from(vk::ExtMemoryBudgetFn::name())]); // Has no feature struct
+
+        let mut features12 = vk::PhysicalDeviceVulkan12Features::default();
+        let mut features13 = vk::PhysicalDeviceVulkan13Features::default();
+        let mut acceleration_structure = vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default();
+        let mut ray_tracing_pipeline = vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default();
+        let mut mesh_shader = vk::PhysicalDeviceMeshShaderFeaturesEXT::default();
+        let mut features2 = vk::PhysicalDeviceFeatures2::default()
+            .push_next(&mut features12)
+            .push_next(&mut features13);
+        if ray_tracing_present {
+            features2 = features2
+                .push_next(&mut acceleration_structure)
+                .push_next(&mut ray_tracing_pipeline);
+        }
+        if mesh_shading_present {
+            features2 = features2.push_next(&mut mesh_shader);
+        }
+        unsafe { instance.get_physical_device_features2(physical_device, &mut features2) };
+        let dynamic_indexing = features2.features.shader_sampled_image_array_dynamic_indexing != 0;
+
+        let buffer_device_address = features12.buffer_device_address != 0;
+        DeviceCapabilities {
+            descriptor_indexing: dynamic_indexing &&
+                features12.descriptor_binding_sampled_image_update_after_bind != 0 &&
+                features12.descriptor_binding_partially_bound != 0 &&
+                features12.descriptor_binding_update_unused_while_pending != 0,
+            dynamic_rendering: features13.dynamic_rendering != 0,
+            synchronization2: features13.synchronization2 != 0,
+            buffer_device_address,
+            ray_tracing: ray_tracing_present && buffer_device_address &&
+                acceleration_structure.acceleration_structure != 0 &&
+                ray_tracing_pipeline.ray_tracing_pipeline != 0,
+            mesh_shading: mesh_shading_present && mesh_shader.task_shader != 0 && mesh_shader.mesh_shader != 0,
+            memory_budget
+        }
+    }
+
+    // Whatever was requested that's also supported, with the features granted ones depend on
+    pub(crate) fn negotiate(self, supported: DeviceCapabilities) -> DeviceCapabilities {
+        let ray_tracing = self.ray_tracing && supported.ray_tracing;
+        DeviceCapabilities {
+            descriptor_indexing: self.descriptor_indexing && supported.descriptor_indexing,
+            dynamic_rendering: self.dynamic_rendering && supported.dynamic_rendering,
+            synchronization2: self.synchronization2 && supported.synchronization2,
+            buffer_device_address: (self.buffer_device_address || ray_tracing) && supported.buffer_device_address,
+            ray_tracing,
+            mesh_shading: self.mesh_shading && supported.mesh_shading,
+            memory_budget: self.memory_budget && supported.memory_budget
+        }
+    }
+
+    // Extensions enabled on top of the required ones for what's set
+    pub(crate) fn extensions(&self) -> Vec<CString> {
+        let mut extensions = Vec::new();
+        if self.ray_tracing {
+            extensions.extend(ray_tracing_extensions());
+        }
+        if self.mesh_shading {
+            extensions.extend(mesh_shading_extensions());
+        }
+        if self.memory_budget {
+            extensions.push(CString::from(vk::ExtMemoryBudgetFn::name()));
+        }
+
+        extensions
+    }
+}
@@ -0,0 +1,228 @@
+use ash::vk;
+use bytemuck::Pod;
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::barrier::{Barriers, Usage};
+use crate::renderer::compute_pipeline::ComputePipeline;
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::shader::{ShaderError, ShaderSource};
+use crate::renderer::staging_buf::UploadContext;
+
+const DISPATCHES_PER_POOL: u32 = 64; // Another pool is created whenever a frame's last one fills up
+pub const MAX_STORAGE_BUFFERS: u32 = 8; // Per pipeline, within the guaranteed maxPerStageDescriptorStorageBuffers
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct StorageBufferHandle(pub(crate) usize); // Index into the renderer's storage buffer list
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ComputePipelineHandle(pub(crate) usize);
+
+// Device local, so the GPU writes it with compute shaders and reads it back as vertices, indices or
+// indirect draw arguments
+struct StorageBuffer {
+    buf: vk::Buffer,
+    alloc: Allocation,
+    size: vk::DeviceSize
+}
+
+impl StorageBuffer {
+    fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        allocator.destroy_buffer(logical_layer, self.buf, &self.alloc);
+    }
+}
+
+// Buffers are bound to set 0 in order, binding 0 onwards
+#[derive(Clone, Debug)]
+pub struct ComputeDispatch {
+    pub pipeline: ComputePipelineHandle,
+    pub buffers: Vec<StorageBufferHandle>,
+    pub groups: [u32; 3], // Workgroup counts, not invocations
+    pub push_constants: Vec<u8>
+}
+
+impl ComputeDispatch {
+    pub fn new(pipeline: ComputePipelineHandle, buffers: &[StorageBufferHandle], groups: [u32; 3]) -> ComputeDispatch {
+        ComputeDispatch {
+            pipeline,
+            buffers: buffers.to_vec(),
+            groups,
+            push_constants: Vec::new()
+        }
+    }
+
+    pub fn with_push_constants<T: Pod>(mut self, data: &T) -> ComputeDispatch {
+        self.push_constants = bytemuck::bytes_of(data).to_vec();
+        self
+    }
+}
+
+// Pipelines, storage buffers and the dispatches queued for the next frame. Dispatches run once,
+// before anything else in that frame's command buffer.
+pub(crate) struct Compute {
+    pipelines: Vec<ComputePipeline>,
+    buffers: Vec<StorageBuffer>,
+    pools: Vec<Vec<vk::DescriptorPool>>, // Per frame slot, reset once that slot's timeline value has been waited on
+    pool_cursor: usize, // First pool of the recording slot that may have room left
+    dispatches: Vec<ComputeDispatch>
+}
+
+impl Compute {
+    pub(crate) fn new(frames_in_flight: usize) -> Compute {
+        Compute {
+            pipelines: Vec::new(),
+            buffers: Vec::new(),
+            pools: (0..frames_in_flight).map(|_| Vec::new()).collect(),
+            pool_cursor: 0,
+            dispatches: Vec::new()
+        }
+    }
+
+    pub(crate) fn create_pipeline(&mut self, logical_layer: &LogicalLayer, shader: &ShaderSource, storage_buffers: u32,
+                                  push_constant_size: u32) -> Result<ComputePipelineHandle, ShaderError> {
+        assert!(storage_buffers <= MAX_STORAGE_BUFFERS, "Too many storage buffers for one compute pipeline");
+        self.pipelines.push(ComputePipeline::new(logical_layer, shader, storage_buffers, push_constant_size)?);
+
+        Ok(ComputePipelineHandle(self.pipelines.len() - 1))
+    }
+
+    pub(crate) fn create_buffer(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator,
+                                size: vk::DeviceSize) -> Result<StorageBufferHandle, RendererError> {
+        let (alloc, buf) = allocator.create_buffer(logical_layer,
+                                                   size,
+                                                   vk::BufferUsageFlags::STORAGE_BUFFER |
+                                                       vk::BufferUsageFlags::VERTEX_BUFFER |
+                                                       vk::BufferUsageFlags::INDEX_BUFFER |
+                                                       vk::BufferUsageFlags::INDIRECT_BUFFER |
+                                                       vk::BufferUsageFlags::TRANSFER_DST,
+                                                   vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                   MemoryCategory::Buffers)?;
+        self.buffers.push(StorageBuffer { buf, alloc, size });
+
+        Ok(StorageBufferHandle(self.buffers.len() - 1))
+    }
+
+    pub(crate) fn write_buffer<T: Pod>(&self, logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                                       handle: StorageBufferHandle, offset: vk::DeviceSize, data: &[T]) -> Result<(), RendererError> {
+        let buffer = &self.buffers[handle.0];
+        assert!(offset + std::mem::size_of_val(data) as vk::DeviceSize <= buffer.size, "Write past the end of the storage buffer");
+
+        upload.upload_buffer(logical_layer, allocator, data, buffer.buf, offset)
+    }
+
+    pub(crate) fn queue(&mut self, dispatch: ComputeDispatch) {
+        let pipeline = &self.pipelines[dispatch.pipeline.0];
+        assert_eq!(dispatch.buffers.len() as u32, pipeline.storage_buffers, "Dispatch buffers don't match the pipeline's bindings");
+        self.dispatches.push(dispatch);
+    }
+
+    pub(crate) fn has_dispatches(&self) -> bool {
+        !self.dispatches.is_empty()
+    }
+
+    fn create_pool(logical_layer: &LogicalLayer) -> Result<vk::DescriptorPool, RendererError> {
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(DISPATCHES_PER_POOL * MAX_STORAGE_BUFFERS)];
+
+        let create_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(DISPATCHES_PER_POOL);
+
+        unsafe {
+            logical_layer.logical_device.create_descriptor_pool(&create_info, None)
+                .map_err(vk_error("vkCreateDescriptorPool"))
+        }
+    }
+
+    fn allocate_set(&mut self, logical_layer: &LogicalLayer, frame: usize,
+                    set_layout: vk::DescriptorSetLayout) -> Result<vk::DescriptorSet, RendererError> {
+        let layouts = [set_layout];
+
+        while let Some(pool) = self.pools[frame].get(self.pool_cursor) {
+            let alloc_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(*pool)
+                .set_layouts(&layouts);
+            match unsafe { logical_layer.logical_device.allocate_descriptor_sets(&alloc_info) } {
+                Ok(sets) => return Ok(sets[0]),
+                Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY) | Err(vk::Result::ERROR_FRAGMENTED_POOL) => self.pool_cursor += 1, // Full
+                Err(e) => return Err(vk_error("vkAllocateDescriptorSets")(e))
+            }
+        }
+
+        let pool = Self::create_pool(logical_layer)?;
+        self.pools[frame].push(pool);
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts);
+        let sets = unsafe {
+            logical_layer.logical_device.allocate_descriptor_sets(&alloc_info).map_err(vk_error("vkAllocateDescriptorSets"))?
+        };
+
+        Ok(sets[0])
+    }
+
+    // Records the queued dispatches, then makes their writes visible to the rest of the frame. The
+    // frame slot's previous command buffer must have finished.
+    pub(crate) fn record(&mut self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer,
+                         frame: usize) -> Result<(), RendererError> {
+        // Pools are reset rather than destroyed, so a slot only grows them on its busiest frame
+        for pool in self.pools[frame].iter() {
+            unsafe {
+                logical_layer.logical_device.reset_descriptor_pool(*pool, vk::DescriptorPoolResetFlags::empty())
+                    .map_err(vk_error("vkResetDescriptorPool"))?;
+            }
+        }
+        self.pool_cursor = 0;
+        if self.dispatches.is_empty() {
+            return Ok(());
+        }
+
+        let dispatches = std::mem::take(&mut self.dispatches);
+        for dispatch in dispatches.iter() {
+            let set_layout = self.pipelines[dispatch.pipeline.0].set_layout;
+            let set = self.allocate_set(logical_layer, frame, set_layout)?;
+
+            let buffer_infos: Vec<[vk::DescriptorBufferInfo; 1]> = dispatch.buffers.iter()
+                .map(|b| [vk::DescriptorBufferInfo::default()
+                    .buffer(self.buffers[b.0].buf)
+                    .offset(0)
+                    .range(vk::WHOLE_SIZE)])
+                .collect();
+            let writes: Vec<vk::WriteDescriptorSet> = buffer_infos.iter()
+                .enumerate()
+                .map(|(i, info)| vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(i as u32)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(info))
+                .collect();
+            unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+
+            self.pipelines[dispatch.pipeline.0].dispatch(logical_layer, command_buffer, set, &dispatch.push_constants,
+                                                         dispatch.groups);
+
+            // Later dispatches in the same frame may read what this one wrote
+            Barriers::new()
+                .memory(Usage::ComputeWrite, Usage::StorageConsumer)
+                .record(logical_layer, command_buffer);
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        for p in self.pipelines.iter() {
+            p.destroy(logical_layer);
+        }
+        for b in self.buffers.iter() {
+            b.destroy(logical_layer, allocator);
+        }
+        unsafe {
+            for p in self.pools.iter().flatten() {
+                logical_layer.logical_device.destroy_descriptor_pool(*p, None); // Frees the sets as well
+            }
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use ash::vk;
+use naga::ShaderStage;
+
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::shader::{compile, ShaderError, ShaderSource};
+
+const MIN_PUSH_CONSTANTS_SIZE: u32 = 128; // maxPushConstantsSize is guaranteed to be at least this large
+
+// A compute shader whose set 0 is storage_buffers storage buffers, at bindings 0 onwards
+pub(crate) struct ComputePipeline {
+    pub(crate) pipeline_layout: vk::PipelineLayout,
+    pub(crate) pipeline: vk::Pipeline,
+    pub(crate) set_layout: vk::DescriptorSetLayout,
+    pub(crate) storage_buffers: u32,
+    push_constant_range: Option<vk::PushConstantRange>
+}
+
+impl ComputePipeline {
+    pub(crate) fn new(logical_layer: &LogicalLayer, shader: &ShaderSource, storage_buffers: u32,
+                      push_constant_size: u32) -> Result<ComputePipeline, ShaderError> {
+        assert!(push_constant_size <= MIN_PUSH_CONSTANTS_SIZE, "Push constant range exceeds the guaranteed limit");
+
+        let compiled = compile(shader, ShaderStage::Compute)?;
+        let device = &logical_layer.logical_device;
+
+        let bindings: Vec<vk::DescriptorSetLayoutBinding> = (0..storage_buffers)
+            .map(|b| vk::DescriptorSetLayoutBinding::default()
+                .binding(b)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::COMPUTE))
+            .collect();
+        let set_layout_info = vk::DescriptorSetLayoutCreateInfo::default()
+            .bindings(&bindings);
+        let set_layout = unsafe { device.create_descriptor_set_layout(&set_layout_info, None).unwrap() };
+
+        let push_constant_range = (push_constant_size > 0).then(|| vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::COMPUTE)
+            .offset(0)
+            .size(push_constant_size));
+        let push_constant_ranges: Vec<vk::PushConstantRange> = push_constant_range.into_iter().collect();
+        let set_layouts = [set_layout];
+        let layout_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_ranges);
+        let pipeline_layout = unsafe { device.create_pipeline_layout(&layout_info, None).unwrap() };
+
+        let module_info = vk::ShaderModuleCreateInfo::default()
+            .code(&compiled.code);
+        let module = unsafe { device.create_shader_module(&module_info, None).unwrap() };
+
+        let stage = vk::PipelineShaderStageCreateInfo::default()
+            .name(compiled.entry_point.as_c_str())
+            .stage(vk::ShaderStageFlags::COMPUTE)
+            .module(module);
+        let pipeline_info = vk::ComputePipelineCreateInfo::default()
+            .stage(stage)
+            .layout(pipeline_layout);
+
+        let pipelines = unsafe {
+            device.create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info], None).unwrap()
+        };
+        unsafe { device.destroy_shader_module(module, None) };
+
+        Ok(ComputePipeline {
+            pipeline_layout,
+            pipeline: pipelines[0],
+            set_layout,
+            storage_buffers,
+            push_constant_range
+        })
+    }
+
+    // Binds the pipeline and its buffers then dispatches groups workgroups
+    pub(crate) fn dispatch(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer,
+                           descriptor_set: vk::DescriptorSet, push_constants: &[u8], groups: [u32; 3]) {
+        let device = &logical_layer.logical_device;
+        let descriptor_sets = [descriptor_set];
+
+        unsafe {
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::COMPUTE, self.pipeline);
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::COMPUTE, self.pipeline_layout,
+                                            0, &descriptor_sets, &[]);
+            if let Some(range) = self.push_constant_range {
+                assert!(push_constants.len() as u32 <= range.size, "Push constants written outside of the declared range");
+                device.cmd_push_constants(command_buffer, self.pipeline_layout, range.stage_flags, 0, push_constants);
+            }
+            device.cmd_dispatch(command_buffer, groups[0], groups[1], groups[2]);
+        }
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer) {
+        unsafe {
+            logical_layer.logical_device.destroy_pipeline(self.pipeline, None);
+            logical_layer.logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+            logical_layer.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
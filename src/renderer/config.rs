@@ -0,0 +1,204 @@
+use std::path::PathBuf;
+
+use ash::vk;
+use log::Level;
+
+use crate::renderer::post::PostEffect;
+use crate::renderer::ray_tracing::RayTracingSettings;
+use crate::renderer::ssao::SsaoSettings;
+use crate::renderer::streaming::StreamingSettings;
+
+pub const MIN_RENDER_SCALE: f32 = 0.25; // RendererConfig::render_scale's range
+pub const MAX_RENDER_SCALE: f32 = 2.0;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresentMode {
+    Immediate, // No vsync, may tear
+    Mailbox, // No tearing, newer frames replace queued ones
+    Fifo, // Vsync, always supported
+    FifoRelaxed // Vsync unless a frame is late, in which case it tears
+}
+
+impl PresentMode {
+    pub(crate) fn to_vk(self) -> vk::PresentModeKHR {
+        match self {
+            PresentMode::Immediate => vk::PresentModeKHR::IMMEDIATE,
+            PresentMode::Mailbox => vk::PresentModeKHR::MAILBOX,
+            PresentMode::Fifo => vk::PresentModeKHR::FIFO,
+            PresentMode::FifoRelaxed => vk::PresentModeKHR::FIFO_RELAXED
+        }
+    }
+}
+
+// How the swapchain's values are interpreted by the display
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ColorSpace {
+    Srgb, // Standard dynamic range, every surface supports it
+    DisplayP3, // Wide gamut standard dynamic range
+    Hdr10, // BT.2020 primaries with the PQ curve, values are absolute brightness
+    ScRgb // Linear with sRGB primaries where 1 is 80 nits, values past 1 are brighter
+}
+
+impl ColorSpace {
+    pub(crate) fn to_vk(self) -> vk::ColorSpaceKHR {
+        match self {
+            ColorSpace::Srgb => vk::ColorSpaceKHR::SRGB_NONLINEAR,
+            ColorSpace::DisplayP3 => vk::ColorSpaceKHR::DISPLAY_P3_NONLINEAR_EXT,
+            ColorSpace::Hdr10 => vk::ColorSpaceKHR::HDR10_ST2084_EXT,
+            ColorSpace::ScRgb => vk::ColorSpaceKHR::EXTENDED_SRGB_LINEAR_EXT
+        }
+    }
+
+    // None for color spaces the renderer can't encode for
+    pub(crate) fn from_vk(color_space: vk::ColorSpaceKHR) -> Option<ColorSpace> {
+        [ColorSpace::Srgb, ColorSpace::DisplayP3, ColorSpace::Hdr10, ColorSpace::ScRgb]
+            .into_iter()
+            .find(|c| c.to_vk() == color_space)
+    }
+
+    pub fn is_hdr(self) -> bool {
+        matches!(self, ColorSpace::Hdr10 | ColorSpace::ScRgb)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct SurfaceFormat {
+    pub format: vk::Format,
+    pub color_space: ColorSpace
+}
+
+impl SurfaceFormat {
+    // 8 bit sRGB, what the swapchain uses unless told otherwise
+    pub fn default_preferences() -> Vec<SurfaceFormat> {
+        vec![SurfaceFormat { format: vk::Format::B8G8R8A8_SRGB, color_space: ColorSpace::Srgb },
+             SurfaceFormat { format: vk::Format::R8G8B8A8_SRGB, color_space: ColorSpace::Srgb }]
+    }
+
+    // HDR10, then scRGB, then the default preferences for displays without HDR
+    pub fn hdr_preferences() -> Vec<SurfaceFormat> {
+        let mut preferences = vec![
+            SurfaceFormat { format: vk::Format::A2B10G10R10_UNORM_PACK32, color_space: ColorSpace::Hdr10 },
+            SurfaceFormat { format: vk::Format::R16G16B16A16_SFLOAT, color_space: ColorSpace::ScRgb }
+        ];
+        preferences.extend(SurfaceFormat::default_preferences());
+        preferences
+    }
+}
+
+// Which GPU to render on when there are several that can. See CubulousRenderer::gpus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DevicePreference {
+    #[default]
+    HighPerformance, // Discrete over integrated, then the most memory
+    LowPower, // Integrated first, to save battery
+    Vendor(u32), // PCI vendor ID, I.E. gpu::VENDOR_NVIDIA, otherwise the same as HighPerformance
+    Index(usize) // A GpuInfo::index, falls back to HighPerformance with a warning if that device can't be used
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FullscreenMode {
+    Windowed,
+    Borderless, // A window covering the current monitor, switches instantly
+    Exclusive // Takes over the monitor at its highest resolution and refresh rate
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CursorMode {
+    #[default]
+    Normal,
+    Hidden, // Invisible over the window but free to leave it
+    Locked // Invisible and held in place, only InputState::mouse_delta moves. For mouse look.
+}
+
+// How the scene is lit. Deferred scales to many lights, but every material goes through the
+// metallic-roughness model so custom shader variants are ignored. Debug render modes always draw forward.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ShadingPipeline {
+    #[default]
+    Forward, // Every draw lights its own fragments, including ones later drawn over
+    Deferred // Draws write surface attributes to a G-buffer, then each pixel is lit once
+}
+
+#[derive(Clone, Debug)]
+pub struct WindowConfig {
+    pub title: String,
+    pub size: (u32, u32), // Logical pixels, scaled by the monitor's DPI
+    pub resizable: bool,
+    pub decorations: bool, // Title bar and borders
+    pub icon: Option<PathBuf>, // PNG, a missing or broken icon is skipped
+    pub fullscreen: FullscreenMode
+}
+
+impl Default for WindowConfig {
+    fn default() -> Self {
+        WindowConfig {
+            title: String::from("Hello Triangle"),
+            size: (800, 600),
+            resizable: true,
+            decorations: true,
+            icon: Some(PathBuf::from("assets/g1141.png")),
+            fullscreen: FullscreenMode::Windowed
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RendererConfig {
+    pub window: WindowConfig, // Unused when headless
+    pub validation: bool, // Requires the Khronos validation layer, skipped with a warning when it's missing
+    pub validation_severity: Level, // Least severe validation message that gets logged
+    pub device: DevicePreference,
+    pub present_mode: PresentMode, // Falls back to Fifo when the surface doesn't support it
+    pub surface_formats: Vec<SurfaceFormat>, // In order of preference, the first the surface supports is used
+    pub paper_white: f32, // Nits a scene value of 1 is shown at on HDR displays
+    pub frustum_culling: bool, // Skip draws outside the camera's view before recording
+    pub occlusion_culling: bool, // Skip draws with an OcclusionId whose bounds were recently hidden
+    pub id_buffer: bool, // Render pick IDs after the scene for CubulousRenderer::read_id_at
+    pub shadow_resolution: u32, // Width and height of each shadow map
+    pub shadow_distance: f32, // Radius around the camera that receives directional shadows
+    pub hdr: bool, // Render the scene to a float target, falls back to 8 bit color if the device can't
+    pub msaa: u32, // Samples per pixel of the scene, 1 for none. Rounded down to what the device supports, forward pipeline only.
+    pub render_scale: f32, // The scene's resolution relative to the window's, 0.25 to 2. Post-processing's last pass scales it to fit.
+    pub dynamic_rendering: bool, // Draw the scene without a render pass or framebuffer, falls back to them if the device can't
+    pub pipeline: ShadingPipeline,
+    pub ssao: Option<SsaoSettings>, // Ambient occlusion from the G-buffer, only with the Deferred pipeline
+    pub ray_tracing: Option<RayTracingSettings>, // Traced shadows and reflections, only with the Deferred pipeline on capable devices
+    pub texture_streaming: StreamingSettings, // For textures uploaded with upload_streamed_texture
+    pub mesh_shading: bool, // Draw meshes uploaded with meshlets through task and mesh shaders, falls back to vertex buffers if the device can't
+    pub post_effects: Vec<PostEffect>, // Applied in order to the scene before it's presented
+    pub tick_rate: u32, // Fixed updates per second under run_fixed
+    pub max_fps: Option<u32>, // Sleeps between frames to stay under this rate, on top of any vsync
+    pub headless: Option<(u32, u32)> // Render offscreen at this width and height without a window or surface
+}
+
+impl Default for RendererConfig {
+    fn default() -> Self {
+        RendererConfig {
+            window: WindowConfig::default(),
+            validation: cfg!(debug_assertions),
+            validation_severity: Level::Warn,
+            device: DevicePreference::default(),
+            present_mode: PresentMode::Mailbox,
+            surface_formats: SurfaceFormat::default_preferences(),
+            paper_white: 200.0,
+            frustum_culling: true,
+            occlusion_culling: true,
+            id_buffer: false,
+            shadow_resolution: 2048,
+            shadow_distance: 32.0,
+            hdr: true,
+            msaa: 1,
+            render_scale: 1.0,
+            dynamic_rendering: true,
+            pipeline: ShadingPipeline::Forward,
+            ssao: None,
+            ray_tracing: None,
+            texture_streaming: StreamingSettings::default(),
+            mesh_shading: true,
+            post_effects: PostEffect::default_chain(),
+            tick_rate: 60,
+            max_fps: None,
+            headless: None
+        }
+    }
+}
@@ -1,10 +1,12 @@
 use std::env;
 use std::fs::File;
-use std::ffi::{c_char, CStr, CString};
+use std::ffi::{c_char, c_void, CStr, CString};
 use std::path::Path;
 
 use ash::{vk, Device, Entry, Instance};
+use ash::extensions::ext::DebugUtils;
 use ash::extensions::khr::{Surface, Swapchain};
+use log::Level;
 
 use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle}; // Entry holds Vulkan functions
 
@@ -12,39 +14,122 @@ use winit::{
     dpi::LogicalSize,
     event::{Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    window::{Icon, Window, WindowBuilder, WindowId},
+    monitor::MonitorHandle,
+    window::{CursorGrabMode, Fullscreen, Icon, Window, WindowBuilder, WindowId},
 };
 
+use crate::renderer::config::{CursorMode, FullscreenMode, RendererConfig, WindowConfig};
+use crate::renderer::error::{vk_error, RendererError};
+
+const VALIDATION_LAYER: &str = "VK_LAYER_KHRONOS_validation";
+
+unsafe extern "system" fn vulkan_debug_callback(severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+                                                message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+                                                callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+                                                _user_data: *mut c_void) -> vk::Bool32 {
+    let message = match callback_data.is_null() || (*callback_data).p_message.is_null() {
+        true => String::from("<no message>"),
+        false => CStr::from_ptr((*callback_data).p_message).to_string_lossy().into_owned()
+    };
+
+    let level = if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+        Level::Error
+    } else if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+        Level::Warn
+    } else if severity.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+        Level::Info
+    } else {
+        Level::Debug
+    };
+
+    log::log!(target: "vulkan", level, "[{:?}] {}", message_type, message);
+
+    vk::FALSE // Never abort the call that triggered the message
+}
+
+fn severity_flags(min_severity: Level) -> vk::DebugUtilsMessageSeverityFlagsEXT {
+    let mut flags = vk::DebugUtilsMessageSeverityFlagsEXT::ERROR;
+    if min_severity >= Level::Warn {
+        flags |= vk::DebugUtilsMessageSeverityFlagsEXT::WARNING;
+    }
+    if min_severity >= Level::Info {
+        flags |= vk::DebugUtilsMessageSeverityFlagsEXT::INFO;
+    }
+    if min_severity >= Level::Debug {
+        flags |= vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE;
+    }
+
+    flags
+}
+
+// None for windowed, or when there's no monitor to go fullscreen on
+pub(crate) fn winit_fullscreen(mode: FullscreenMode, monitor: Option<MonitorHandle>) -> Option<Fullscreen> {
+    match mode {
+        FullscreenMode::Windowed => None,
+        FullscreenMode::Borderless => Some(Fullscreen::Borderless(monitor)), // None picks the current monitor
+        FullscreenMode::Exclusive => {
+            let best_mode = monitor.and_then(|m| m.video_modes()
+                .max_by_key(|v| (v.size().width * v.size().height, v.refresh_rate_millihertz(), v.bit_depth())));
+            match best_mode {
+                Some(v) => Some(Fullscreen::Exclusive(v)),
+                None => {
+                    log::warn!("No video modes to go exclusive fullscreen with, staying windowed");
+                    None
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn apply_cursor_mode(window: &Window, mode: CursorMode) -> Result<(), RendererError> {
+    let grab = match mode {
+        CursorMode::Normal | CursorMode::Hidden => window.set_cursor_grab(CursorGrabMode::None),
+        // X11 and Windows can't lock the cursor, confining it is the closest they get. The raw device
+        // motion behind mouse_delta keeps coming at the window's edge either way.
+        CursorMode::Locked => window.set_cursor_grab(CursorGrabMode::Locked)
+            .or_else(|_| window.set_cursor_grab(CursorGrabMode::Confined))
+    };
+    grab.map_err(|e| RendererError::CursorGrab(e.to_string()))?;
+    window.set_cursor_visible(mode == CursorMode::Normal);
+
+    Ok(())
+}
+
 pub struct Core {
     entry: Entry,
-    pub(crate) window: Window,
+    pub(crate) window: Option<Window>, // None when headless
     pub(crate) instance: Instance,
-    pub(crate) surface: vk::SurfaceKHR,
+    pub(crate) surface: Option<vk::SurfaceKHR>, // None when headless
     pub(crate) surface_loader: Surface,
+    pub(crate) offscreen_extent: Option<vk::Extent2D>, // Size of the offscreen target, only set when headless
+    debug_utils: Option<(DebugUtils, vk::DebugUtilsMessengerEXT)> // Only present with validation enabled
 }
 
 impl Core {
-    pub(crate) fn new(ev_loop: &EventLoop<()>, required_layers: &Vec<String>) -> Core {
-        fn load_entry() -> Entry {
-            let vk_lib_env = env::var("VK_LIB_PATH").unwrap();
-            let vk_lib_path = Path::new(&vk_lib_env).join("libvulkan.so");
+    // ev_loop is unused when config.headless is set, and may only be None then
+    pub(crate) fn new(ev_loop: Option<&EventLoop<()>>, config: &RendererConfig) -> Result<Core, RendererError> {
+        fn load_entry() -> Result<Entry, RendererError> {
+            // Prefer the SDK's loader, otherwise fall back to the system one
+            let entry_local = match env::var("VK_LIB_PATH") {
+                Ok(vk_lib_env) => {
+                    let vk_lib_path = Path::new(&vk_lib_env).join("libvulkan.so");
+                    unsafe { Entry::load_from(vk_lib_path) }
+                },
+                Err(_) => unsafe { Entry::load() }
+            };
 
-            let entry_local: Entry;
-            unsafe {
-                entry_local = Entry::load_from(vk_lib_path.to_str().unwrap()).unwrap();
-            }
-
-            entry_local
+            entry_local.map_err(|e| RendererError::LoaderNotFound(e.to_string()))
         }
 
-        fn read_window_icon(path: &str) -> Option<Icon> {
+        fn read_window_icon(path: &Path) -> Option<Icon> {
             // From https://docs.rs/png/latest/png/
-            let decoder = png::Decoder::new(File::open(path).unwrap()); // TODO Worry about proper asset import paths later
-            let mut reader = decoder.read_info().unwrap();
+            // A missing icon isn't worth failing over, so every error maps to None
+            let decoder = png::Decoder::new(File::open(path).ok()?); // TODO Worry about proper asset import paths later
+            let mut reader = decoder.read_info().ok()?;
             // Allocate the output buffer.
             let mut buf = vec![0; reader.output_buffer_size()];
             // Read the next frame. An APNG might contain multiple frames.
-            let info = reader.next_frame(&mut buf).unwrap();
+            let info = reader.next_frame(&mut buf).ok()?;
             // Grab the bytes of the image.
             let bytes = &buf[..info.buffer_size()];
             // Inspect more details of the last read frame.
@@ -54,22 +139,24 @@ impl Core {
             Icon::from_rgba(bytes.iter().cloned().collect(), width, height).ok()
         }
 
-        fn init_window(event_loop: &EventLoop<()>) -> Window {
+        fn init_window(event_loop: &EventLoop<()>, config: &WindowConfig) -> Result<Window, RendererError> {
             WindowBuilder::new()
-                .with_title("Hello Triangle")
-                .with_inner_size(LogicalSize::new(800, 600))
-                .with_window_icon(read_window_icon("assets/g1141.png"))
+                .with_title(&config.title)
+                .with_inner_size(LogicalSize::new(config.size.0, config.size.1))
+                .with_resizable(config.resizable)
+                .with_decorations(config.decorations)
+                .with_window_icon(config.icon.as_deref().and_then(read_window_icon))
+                .with_fullscreen(winit_fullscreen(config.fullscreen, event_loop.primary_monitor()))
                 .build(event_loop)
-                .unwrap()
+                .map_err(|e| RendererError::Window(e.to_string()))
         }
 
         fn required_layers_present(entry: &Entry, required_layers: &Vec<String>) -> bool {
-            // TODO Make contingent on validation layer enable
             let vk_layers: Vec<String>;
             unsafe {
                 vk_layers = entry
                     .enumerate_instance_layer_properties()
-                    .unwrap()
+                    .unwrap_or_default()
                     .iter()
                     .map(|l| String::from(CStr::from_ptr(l.layer_name.as_ptr()).to_str().unwrap()))
                     .collect();
@@ -125,14 +212,23 @@ impl Core {
             extensions_found
         }
 
-        fn instance_init(entry: &Entry, window: &Window, required_layers: &Vec<String>) -> Result<Instance, String> {
-            // Get all the window manager extensions that Vulkan can use
-            let mut winit_extensions =
-                ash_window::enumerate_required_extensions(window.raw_display_handle())
-                    .unwrap()
-                    .to_vec();
+        fn instance_extension_present(entry: &Entry, name: &CStr) -> bool {
+            entry.enumerate_instance_extension_properties(None)
+                .unwrap_or_default()
+                .iter()
+                .any(|e| unsafe { CStr::from_ptr(e.extension_name.as_ptr()) } == name)
+        }
+
+        fn instance_init(entry: &Entry, window: Option<&Window>, required_layers: &Vec<String>, validation: bool) -> Result<Instance, RendererError> {
+            // Get all the window manager extensions that Vulkan can use, headless instances need none
+            let mut winit_extensions = match window {
+                Some(w) => ash_window::enumerate_required_extensions(w.raw_display_handle())
+                    .map_err(|_| RendererError::MissingInstanceExtensions)?
+                    .to_vec(),
+                None => Vec::new()
+            };
 
-            if required_window_extensions_present(entry, &winit_extensions) &&
+            if (winit_extensions.is_empty() || required_window_extensions_present(entry, &winit_extensions)) &&
                 required_layers_present(entry, required_layers) {
                 // TODO Work out a better way to define paths later
                 let engine_name: &CStr;
@@ -152,6 +248,13 @@ impl Core {
 
                 // Required for MacOs compatibility
                 winit_extensions.push(vk::KhrPortabilityEnumerationFn::name().as_ptr());
+                // Surfaces only report wide gamut and HDR color spaces with this enabled
+                if window.is_some() && instance_extension_present(entry, vk::ExtSwapchainColorspaceFn::name()) {
+                    winit_extensions.push(vk::ExtSwapchainColorspaceFn::name().as_ptr());
+                }
+                if validation {
+                    winit_extensions.push(DebugUtils::name().as_ptr()); // Ships with the validation layers
+                }
                 let create_flags = vk::InstanceCreateFlags::ENUMERATE_PORTABILITY_KHR;
 
                 // Wrap previous stuff into a higher level struct
@@ -166,7 +269,6 @@ impl Core {
                 let layer_names_raw: Vec<*const c_char>;
                 let layer_names_cstring: Vec<CString>;
 
-                println!("\nValidation support present");
                 let layer_names_string: Vec<&str> = required_layers
                     .iter()
                     .map(|s| s.as_str())
@@ -181,43 +283,98 @@ impl Core {
 
                 let instance: Instance;
                 unsafe {
-                    instance = entry.create_instance(&create_info, None).unwrap();
+                    instance = entry.create_instance(&create_info, None).map_err(vk_error("vkCreateInstance"))?;
                 }
 
                 Ok(instance)
             }
             else {
-                Err(String::from("Required window extensions missing"))
+                Err(RendererError::MissingInstanceExtensions)
             }
         }
 
-        let entry = load_entry();
-        let window = init_window(&ev_loop);
-        let instance = instance_init(&entry, &window, &required_layers).unwrap();
-        let surface: vk::SurfaceKHR;
-        unsafe {
-            surface = ash_window::create_surface(
-                &entry,
-                &instance,
-                window.raw_display_handle(),
-                window.raw_window_handle(),
-                None,
-            ).unwrap();
+        fn setup_debug_messenger(entry: &Entry, instance: &Instance, min_severity: Level)
+            -> Result<(DebugUtils, vk::DebugUtilsMessengerEXT), RendererError> {
+            let create_info = vk::DebugUtilsMessengerCreateInfoEXT::default()
+                .message_severity(severity_flags(min_severity))
+                .message_type(vk::DebugUtilsMessageTypeFlagsEXT::GENERAL |
+                    vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION |
+                    vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE)
+                .pfn_user_callback(Some(vulkan_debug_callback));
+
+            let debug_utils = DebugUtils::new(entry, instance);
+            let messenger = unsafe {
+                debug_utils.create_debug_utils_messenger(&create_info, None)
+                    .map_err(vk_error("vkCreateDebugUtilsMessengerEXT"))?
+            };
+
+            Ok((debug_utils, messenger))
         }
+
+        let entry = load_entry()?;
+        let window = match (config.headless, ev_loop) {
+            (None, Some(ev_loop)) => Some(init_window(ev_loop, &config.window)?),
+            (None, None) => panic!("Windowed renderers need an event loop"),
+            (Some(_), _) => None
+        };
+
+        let validation_layers = vec![String::from(VALIDATION_LAYER)];
+        let validation = config.validation && required_layers_present(&entry, &validation_layers);
+        if config.validation && !validation {
+            log::warn!("{} not found, continuing without validation", VALIDATION_LAYER);
+        }
+        let required_layers = match validation {
+            true => validation_layers,
+            false => Vec::new()
+        };
+
+        let instance = instance_init(&entry, window.as_ref(), &required_layers, validation)?;
+        let debug_utils = match validation {
+            true => Some(setup_debug_messenger(&entry, &instance, config.validation_severity)?),
+            false => None
+        };
+        let surface = match &window {
+            Some(w) => unsafe {
+                Some(ash_window::create_surface(
+                    &entry,
+                    &instance,
+                    w.raw_display_handle(),
+                    w.raw_window_handle(),
+                    None,
+                ).map_err(vk_error("vkCreateSurfaceKHR"))?)
+            },
+            None => None
+        };
         let surface_loader = Surface::new(&entry, &instance);
+        let offscreen_extent = config.headless.map(|(width, height)| vk::Extent2D { width, height });
 
-        Core {
+        Ok(Core {
             entry,
             window,
             instance,
             surface,
-            surface_loader
-        }
+            surface_loader,
+            offscreen_extent,
+            debug_utils
+        })
+    }
+
+    pub(crate) fn headless(&self) -> bool {
+        self.window.is_none()
+    }
+
+    pub(crate) fn window(&self) -> &Window {
+        self.window.as_ref().expect("Headless renderers have no window")
     }
 
     pub(crate) fn destroy(&self) {
         unsafe {
-            self.surface_loader.destroy_surface(self.surface, None);
+            if let Some(surface) = self.surface {
+                self.surface_loader.destroy_surface(surface, None);
+            }
+            if let Some((debug_utils, messenger)) = &self.debug_utils {
+                debug_utils.destroy_debug_utils_messenger(*messenger, None);
+            }
             self.instance.destroy_instance(None);
         }
     }
@@ -0,0 +1,110 @@
+use std::f32::consts::TAU;
+
+use glam::{Mat4, Vec3};
+
+use crate::renderer::frustum::Aabb;
+use crate::renderer::vertex::Vertex;
+
+const CIRCLE_SEGMENTS: usize = 32;
+
+// Lines drawn on top of the frame's scene for a single frame, I.E. physics volumes or chunk bounds.
+// Everything is in world space and depth tested against the scene.
+#[derive(Default)]
+pub struct DebugDraw {
+    vertices: Vec<Vertex> // Line list, two per line
+}
+
+impl DebugDraw {
+    pub fn new() -> DebugDraw {
+        DebugDraw {
+            vertices: Vec::new()
+        }
+    }
+
+    pub fn line(&mut self, a: Vec3, b: Vec3, color: Vec3) {
+        for p in [a, b] {
+            self.vertices.push(Vertex {
+                pos: p.to_array(),
+                color: color.to_array(),
+                ..Vertex::default()
+            });
+        }
+    }
+
+    // The 12 edges of the box with corners in order of their bit pattern, x is bit 0 then y then z
+    fn box_edges(&mut self, corners: [Vec3; 8], color: Vec3) {
+        for i in 0..8 {
+            for axis in [1, 2, 4] {
+                if i & axis == 0 {
+                    self.line(corners[i], corners[i | axis], color);
+                }
+            }
+        }
+    }
+
+    pub fn wire_box(&mut self, bounds: Aabb, color: Vec3) {
+        let corner = |i: usize| Vec3::new(
+            if i & 1 == 0 { bounds.min.x } else { bounds.max.x },
+            if i & 2 == 0 { bounds.min.y } else { bounds.max.y },
+            if i & 4 == 0 { bounds.min.z } else { bounds.max.z });
+        self.box_edges(std::array::from_fn(corner), color);
+    }
+
+    // A unit cube centered on the origin, moved by transform. For oriented boxes.
+    pub fn wire_cube(&mut self, transform: Mat4, color: Vec3) {
+        let corner = |i: usize| transform.transform_point3(Vec3::new(
+            if i & 1 == 0 { -0.5 } else { 0.5 },
+            if i & 2 == 0 { -0.5 } else { 0.5 },
+            if i & 4 == 0 { -0.5 } else { 0.5 }));
+        self.box_edges(std::array::from_fn(corner), color);
+    }
+
+    pub fn circle(&mut self, center: Vec3, normal: Vec3, radius: f32, color: Vec3) {
+        let (u, v) = normal.normalize().any_orthonormal_pair();
+        let point = |i: usize| {
+            let angle = i as f32 / CIRCLE_SEGMENTS as f32 * TAU;
+            center + (u * angle.cos() + v * angle.sin()) * radius
+        };
+        for i in 0..CIRCLE_SEGMENTS {
+            self.line(point(i), point(i + 1), color);
+        }
+    }
+
+    // Three great circles, one around each axis
+    pub fn sphere(&mut self, center: Vec3, radius: f32, color: Vec3) {
+        for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+            self.circle(center, axis, radius, color);
+        }
+    }
+
+    // Red, green and blue lines along transform's X, Y and Z axes
+    pub fn axes(&mut self, transform: Mat4, size: f32) {
+        let origin = transform.transform_point3(Vec3::ZERO);
+        for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+            self.line(origin, transform.transform_point3(axis * size), axis);
+        }
+    }
+
+    // A square grid on the XZ plane, size wide with divisions cells along each side
+    pub fn grid(&mut self, center: Vec3, size: f32, divisions: u32, color: Vec3) {
+        let half = size * 0.5;
+        let divisions = divisions.max(1);
+        for i in 0..=divisions {
+            let offset = -half + size * i as f32 / divisions as f32;
+            self.line(center + Vec3::new(offset, 0.0, -half), center + Vec3::new(offset, 0.0, half), color);
+            self.line(center + Vec3::new(-half, 0.0, offset), center + Vec3::new(half, 0.0, offset), color);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.vertices.clear();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.vertices.is_empty()
+    }
+
+    pub(crate) fn vertices(&self) -> &[Vertex] {
+        &self.vertices
+    }
+}
@@ -0,0 +1,236 @@
+use std::time::Duration;
+
+use bytemuck::{Pod, Zeroable};
+use glam::{IVec3, Mat4, Quat, Vec3, Vec4};
+
+use crate::renderer::frustum::{Aabb, Frustum};
+use crate::renderer::texture::{TextureHandle, Textures};
+
+pub const MAX_DECALS: usize = 512; // Alive at once, adding more replaces the oldest
+pub const MAX_VISIBLE_DECALS: usize = 64; // Matches MAX_DECALS in the lit shaders, the nearest visible ones are drawn
+
+const UNIT_CUBE: Aabb = Aabb { min: Vec3::new(-0.5, -0.5, -0.5), max: Vec3::new(0.5, 0.5, 0.5) };
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecalProjection {
+    Planar, // Along the box's -Z, fading out on surfaces turned away from it. Bullet marks and signs.
+    Box // Onto each side of the box along whichever axis faces it, I.E. block damage on a box around the block
+}
+
+// A texture projected onto the lit surfaces inside a box, blended over their base color before lighting
+#[derive(Clone, Copy, Debug)]
+pub struct Decal {
+    pub transform: Mat4, // Places a unit cube from -0.5 to 0.5, the texture's top is +Y
+    pub texture: TextureHandle, // 2D, alpha is the coverage. Array textures draw as white.
+    pub color: Vec4, // Multiplies the texture
+    pub projection: DecalProjection,
+    pub lifetime: Option<Duration>, // Removed after this long, None keeps it until it's removed or replaced
+    pub fade: Duration // Fades out over the end of the lifetime
+}
+
+impl Decal {
+    pub fn new(transform: Mat4, texture: TextureHandle) -> Decal {
+        Decal {
+            transform,
+            texture,
+            color: Vec4::ONE,
+            projection: DecalProjection::Planar,
+            lifetime: None,
+            fade: Duration::ZERO
+        }
+    }
+
+    // Lying on a surface at position facing out along normal, I.E. from a raycast hit. The box reaches
+    // half of size into and out of the surface, so it still covers it where it curves away a little.
+    pub fn on_surface(position: Vec3, normal: Vec3, size: f32, texture: TextureHandle) -> Decal {
+        let rotation = Quat::from_rotation_arc(Vec3::Z, normal.normalize_or_zero());
+        Decal::new(Mat4::from_scale_rotation_translation(Vec3::splat(size), rotation, position), texture)
+    }
+
+    // Every face of the block at pos, for block damage
+    pub fn block(pos: IVec3, texture: TextureHandle) -> Decal {
+        // A little larger than the block so its faces aren't exactly on the box's edge
+        let transform = Mat4::from_scale_rotation_translation(Vec3::splat(1.01), Quat::IDENTITY, pos.as_vec3() + 0.5);
+        Decal {
+            projection: DecalProjection::Box,
+            ..Decal::new(transform, texture)
+        }
+    }
+
+    pub fn with_lifetime(self, lifetime: Duration, fade: Duration) -> Decal {
+        Decal {
+            lifetime: Some(lifetime),
+            fade,
+            ..self
+        }
+    }
+
+    // 1 until the fade starts, then down to 0 at the end of the lifetime
+    fn opacity(&self, age: Duration) -> f32 {
+        match self.lifetime {
+            Some(lifetime) if !self.fade.is_zero() => {
+                let remaining = lifetime.saturating_sub(age);
+                (remaining.as_secs_f32() / self.fade.as_secs_f32()).min(1.0)
+            },
+            _ => 1.0
+        }
+    }
+}
+
+// Names a decal until it expires, is removed or is replaced by a newer one
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DecalId {
+    index: u32,
+    generation: u32
+}
+
+struct Alive {
+    decal: Decal,
+    age: Duration,
+    order: u64 // When it was added, the lowest is replaced first
+}
+
+struct Slot {
+    alive: Option<Alive>,
+    generation: u32
+}
+
+// std140 layout of one entry of the decals array in the frame uniform block
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub(crate) struct GpuDecal {
+    world_to_decal: Mat4,
+    color: [f32; 4], // A includes the fade
+    params: [f32; 4] // X is the texture slot, Y 1 for box projection
+}
+
+// The renderer's decals, aged by the run loops every frame
+pub struct Decals {
+    slots: Vec<Slot>,
+    next_order: u64
+}
+
+impl Decals {
+    pub(crate) fn new() -> Decals {
+        Decals {
+            slots: Vec::new(),
+            next_order: 0
+        }
+    }
+
+    // Replaces the oldest decal once there are MAX_DECALS
+    pub fn add(&mut self, decal: Decal) -> DecalId {
+        let index = match self.slots.iter().position(|s| s.alive.is_none()) {
+            Some(i) => i,
+            None if self.slots.len() < MAX_DECALS => {
+                self.slots.push(Slot { alive: None, generation: 0 });
+                self.slots.len() - 1
+            },
+            None => {
+                let oldest = self.slots.iter()
+                    .enumerate()
+                    .min_by_key(|(_, s)| s.alive.as_ref().map(|a| a.order))
+                    .map(|(i, _)| i)
+                    .unwrap();
+                self.slots[oldest].generation = self.slots[oldest].generation.wrapping_add(1);
+                oldest
+            }
+        };
+
+        let slot = &mut self.slots[index];
+        slot.alive = Some(Alive { decal, age: Duration::ZERO, order: self.next_order });
+        self.next_order += 1;
+        DecalId { index: index as u32, generation: slot.generation }
+    }
+
+    fn slot(&self, id: DecalId) -> Option<&Alive> {
+        self.slots.get(id.index as usize)
+            .filter(|s| s.generation == id.generation)
+            .and_then(|s| s.alive.as_ref())
+    }
+
+    pub fn get(&self, id: DecalId) -> Option<&Decal> {
+        self.slot(id).map(|a| &a.decal)
+    }
+
+    // I.E. to change the texture as block damage progresses
+    pub fn get_mut(&mut self, id: DecalId) -> Option<&mut Decal> {
+        self.slots.get_mut(id.index as usize)
+            .filter(|s| s.generation == id.generation)
+            .and_then(|s| s.alive.as_mut())
+            .map(|a| &mut a.decal)
+    }
+
+    // None if it already expired or was replaced
+    pub fn remove(&mut self, id: DecalId) -> Option<Decal> {
+        let slot = self.slots.get_mut(id.index as usize).filter(|s| s.generation == id.generation)?;
+        let alive = slot.alive.take()?;
+        slot.generation = slot.generation.wrapping_add(1);
+        Some(alive.decal)
+    }
+
+    pub fn clear(&mut self) {
+        for slot in self.slots.iter_mut().filter(|s| s.alive.is_some()) {
+            slot.alive = None;
+            slot.generation = slot.generation.wrapping_add(1);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.iter().filter(|s| s.alive.is_some()).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Ages every decal, removing the ones past their lifetime
+    pub(crate) fn advance(&mut self, dt: Duration) {
+        for slot in self.slots.iter_mut() {
+            let expired = match slot.alive.as_mut() {
+                Some(alive) => {
+                    alive.age += dt;
+                    alive.decal.lifetime.map_or(false, |l| alive.age >= l)
+                },
+                None => false
+            };
+            if expired {
+                slot.alive = None;
+                slot.generation = slot.generation.wrapping_add(1);
+            }
+        }
+    }
+
+    // The decals in view, nearest to the camera first, at most MAX_VISIBLE_DECALS. Returns how many were written.
+    pub(crate) fn write_visible(&self, frustum: &Frustum, camera_pos: Vec3, textures: &Textures,
+                                out: &mut [GpuDecal; MAX_VISIBLE_DECALS]) -> usize {
+        let mut visible: Vec<(f32, &Alive)> = self.slots.iter()
+            .filter_map(|s| s.alive.as_ref())
+            .filter_map(|a| {
+                let bounds = UNIT_CUBE.transformed(&a.decal.transform);
+                match frustum.intersects(&bounds) {
+                    true => Some((bounds.center().distance_squared(camera_pos), a)),
+                    false => None
+                }
+            })
+            .collect();
+        visible.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        for ((_, alive), gpu) in visible.iter().zip(out.iter_mut()) {
+            let decal = &alive.decal;
+            let texture = match textures.is_array(decal.texture) {
+                true => TextureHandle::WHITE,
+                false => decal.texture
+            };
+            let mut color = decal.color;
+            color.w *= decal.opacity(alive.age);
+            *gpu = GpuDecal {
+                world_to_decal: decal.transform.inverse(),
+                color: color.to_array(),
+                params: [textures.slot(texture) as f32, (decal.projection == DecalProjection::Box) as u32 as f32, 0.0, 0.0]
+            };
+        }
+
+        visible.len().min(MAX_VISIBLE_DECALS)
+    }
+}
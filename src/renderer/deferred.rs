@@ -0,0 +1,556 @@
+use std::mem;
+use std::path::PathBuf;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use glam::Mat4;
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::deletion_queue::Deletion;
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::raster_pipeline::{RasterPipeline, RasterState};
+use crate::renderer::ray_tracing::{RayTracing, RayTracingSettings, TraceInstance};
+use crate::renderer::render_pass::{setup_render_pass, PassTarget};
+use crate::renderer::render_target::RenderTarget;
+use crate::renderer::resources::ResourceManager;
+use crate::renderer::shader::{ShaderSet, ShaderSource};
+use crate::renderer::ssao::{Ssao, SsaoSettings};
+use crate::renderer::vertex::VertexLayout;
+
+// Albedo, normal, material and depth, matching the outputs of gbuffer.frag
+const GBUFFER_FORMATS: [vk::Format; 4] = [
+    vk::Format::R8G8B8A8_SRGB, // Base color and occlusion
+    vk::Format::R16G16B16A16_SFLOAT, // World space normal and roughness
+    vk::Format::R16G16B16A16_SFLOAT, // Emissive, which can go past 1, and metallic
+    vk::Format::R32_SFLOAT // The depth buffer's value, which can't be sampled itself
+];
+
+// The G-buffer pass clears depth to the far plane so the lighting pass can tell where nothing was drawn
+const GBUFFER_CLEAR: [vk::ClearValue; 5] = [
+    vk::ClearValue { color: vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 0.0] } },
+    vk::ClearValue { color: vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 0.0] } },
+    vk::ClearValue { color: vk::ClearColorValue { float32: [0.0, 0.0, 0.0, 0.0] } },
+    vk::ClearValue { color: vk::ClearColorValue { float32: [1.0, 0.0, 0.0, 0.0] } },
+    vk::ClearValue { depth_stencil: vk::ClearDepthStencilValue { depth: 1.0, stencil: 0 } }
+];
+
+// Of every pipeline drawing into the G-buffer pass
+fn gbuffer_state() -> RasterState {
+    RasterState {
+        color_targets: GBUFFER_FORMATS.len() as u32,
+        ..RasterState::default()
+    }
+}
+
+// Binding of the occlusion the lighting pass multiplies its ambient term by, after the sampler
+const OCCLUSION_BINDING: u32 = GBUFFER_FORMATS.len() as u32 + 1;
+// Ray traced shadows and reflections, after the occlusion. Both hold the SSAO result without ray tracing.
+const RT_SHADOW_BINDING: u32 = OCCLUSION_BINDING + 1;
+const RT_REFLECTION_BINDING: u32 = OCCLUSION_BINDING + 2;
+
+// Matches the PushConstants block in deferred_light.frag
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct LightConstants {
+    inv_view_proj: Mat4, // Rebuilds positions from the G-buffer's depth
+    occlusion: [f32; 4], // X is 1 when the occlusion binding holds this frame's SSAO
+    ray_tracing: [f32; 4] // X is 1 when the ray traced shadows replace the shadow maps, Y when reflections are traced
+}
+
+// Targets that depend on the swapchain's size
+struct GBuffer {
+    images: Vec<(vk::Image, Allocation, vk::ImageView)>, // In GBUFFER_FORMATS order
+    framebuffer: vk::Framebuffer, // The targets then the render target's depth buffer
+    extent: vk::Extent2D
+}
+
+impl GBuffer {
+    fn new(logical_layer: &LogicalLayer, allocator: &Allocator, render_pass: vk::RenderPass,
+           render_target: &RenderTarget) -> Result<GBuffer, RendererError> {
+        let extent = render_target.scene_extent;
+        let mut gbuffer = GBuffer {
+            images: Vec::with_capacity(GBUFFER_FORMATS.len()),
+            framebuffer: vk::Framebuffer::null(),
+            extent
+        };
+
+        // Created one at a time so a failure only has to clean up the ones before it
+        let result: Result<(), RendererError> = (|| {
+            for format in GBUFFER_FORMATS {
+                let create_info = vk::ImageCreateInfo::default()
+                    .image_type(vk::ImageType::TYPE_2D)
+                    .format(format)
+                    .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+                    .mip_levels(1)
+                    .array_layers(1)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .tiling(vk::ImageTiling::OPTIMAL)
+                    .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+                    .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED);
+                let (alloc, image) = allocator.create_image(logical_layer, &create_info, vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                            MemoryCategory::RenderTargets)?;
+
+                let view_create_info = vk::ImageViewCreateInfo::default()
+                    .image(image)
+                    .view_type(vk::ImageViewType::TYPE_2D)
+                    .format(format)
+                    .subresource_range(vk::ImageSubresourceRange {
+                        aspect_mask: vk::ImageAspectFlags::COLOR,
+                        base_mip_level: 0,
+                        level_count: 1,
+                        base_array_layer: 0,
+                        layer_count: 1
+                    });
+                let view = match unsafe { logical_layer.logical_device.create_image_view(&view_create_info, None) } {
+                    Ok(v) => v,
+                    Err(e) => {
+                        allocator.destroy_image(logical_layer, image, &alloc);
+                        return Err(vk_error("vkCreateImageView")(e));
+                    }
+                };
+                gbuffer.images.push((image, alloc, view));
+            }
+
+            let attachments: Vec<vk::ImageView> = gbuffer.images.iter()
+                .map(|(_, _, v)| *v)
+                .chain([render_target.depth_view])
+                .collect();
+            let framebuffer_create_info = vk::FramebufferCreateInfo::default()
+                .render_pass(render_pass)
+                .attachments(&attachments)
+                .width(extent.width)
+                .height(extent.height)
+                .layers(1);
+            gbuffer.framebuffer = unsafe {
+                logical_layer.logical_device.create_framebuffer(&framebuffer_create_info, None).map_err(vk_error("vkCreateFramebuffer"))?
+            };
+
+            Ok(())
+        })();
+        if let Err(e) = result {
+            gbuffer.destroy(logical_layer, allocator);
+            return Err(e);
+        }
+
+        Ok(gbuffer)
+    }
+
+    fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        unsafe { logical_layer.logical_device.destroy_framebuffer(self.framebuffer, None) }; // Null if creation failed
+        for (image, alloc, view) in self.images.iter() {
+            unsafe { logical_layer.logical_device.destroy_image_view(*view, None) };
+            allocator.destroy_image(logical_layer, *image, alloc);
+        }
+    }
+}
+
+// Deferred shading. The scene's geometry is drawn into the G-buffer pass, writing surface attributes
+// instead of lit colors, then the scene pass opens on the same depth buffer and lights every pixel once
+// with a fullscreen triangle. Anything drawn after that, I.E. debug lines, is forward shaded as usual.
+// The lighting pass reads the frame's uniforms at set 0, the G-buffer at set 1 (the targets at bindings
+// 0 to 3, a nearest sampler at 4, the SSAO result at 5 and the ray traced shadows and reflections at 6
+// and 7) and the shadow maps at set 2.
+pub(crate) struct Deferred {
+    render_pass: vk::RenderPass, // The G-buffer pass
+    scene_pass: Option<vk::RenderPass>, // Scene pass that loads the G-buffer pass's depth, None with dynamic rendering
+    pub(crate) gbuffer_pipeline: RasterPipeline, // Every material is drawn with it, not rebuilt on shader reloads
+    light_pipeline: RasterPipeline,
+    sampler: vk::Sampler,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    descriptor_set: vk::DescriptorSet, // Rewritten whenever the targets are recreated
+    targets: Option<GBuffer>, // None between destroy_targets and resize
+    ssao: Ssao, // Always created, so it can be turned on at runtime
+    pub(crate) ssao_settings: Option<SsaoSettings>, // None skips the SSAO passes
+    ray_tracing: Option<RayTracing>, // None when the device can't ray trace
+    pub(crate) ray_tracing_settings: Option<RayTracingSettings> // None skips building and tracing
+}
+
+impl Deferred {
+    // scene_set_layouts are the four sets every scene pipeline shares, push_constant_range their draw constants
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, scene_target: PassTarget, scene_format: vk::Format,
+                      render_target: &RenderTarget, vertex_layouts: &[VertexLayout], scene_set_layouts: &[vk::DescriptorSetLayout],
+                      push_constant_range: vk::PushConstantRange, ssao_settings: Option<SsaoSettings>, ray_tracing: Option<RayTracing>,
+                      ray_tracing_settings: Option<RayTracingSettings>) -> Result<Deferred, RendererError> {
+        fn setup_gbuffer_pass(logical_layer: &LogicalLayer, depth_format: vk::Format) -> Result<vk::RenderPass, RendererError> {
+            let mut attachments: Vec<vk::AttachmentDescription> = GBUFFER_FORMATS.iter()
+                .map(|&format| vk::AttachmentDescription::default()
+                    .format(format)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .load_op(vk::AttachmentLoadOp::CLEAR)
+                    .store_op(vk::AttachmentStoreOp::STORE) // Read by the lighting pass
+                    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED)
+                    .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL))
+                .collect();
+            attachments.push(vk::AttachmentDescription::default()
+                .format(depth_format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::STORE) // The scene pass keeps testing against it
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL));
+
+            let color_refs: Vec<vk::AttachmentReference> = (0..GBUFFER_FORMATS.len() as u32)
+                .map(|i| vk::AttachmentReference::default()
+                    .attachment(i)
+                    .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL))
+                .collect();
+            let depth_ref = vk::AttachmentReference::default()
+                .attachment(GBUFFER_FORMATS.len() as u32)
+                .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+            let subpasses = [vk::SubpassDescription::default()
+                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                .color_attachments(&color_refs)
+                .depth_stencil_attachment(&depth_ref)];
+
+            let dependencies = [
+                // The previous frame's lighting has to finish reading the targets, and its scene pass the depth
+                vk::SubpassDependency::default()
+                    .src_subpass(vk::SUBPASS_EXTERNAL)
+                    .dst_subpass(0)
+                    .src_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS |
+                        vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+                    .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+                    .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE),
+                // The lighting pass samples the targets and the rest of the scene tests against the depth
+                vk::SubpassDependency::default()
+                    .src_subpass(0)
+                    .dst_subpass(vk::SUBPASS_EXTERNAL)
+                    .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+                    .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ |
+                        vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+            ];
+
+            let create_info = vk::RenderPassCreateInfo::default()
+                .attachments(&attachments)
+                .subpasses(&subpasses)
+                .dependencies(&dependencies);
+
+            unsafe {
+                logical_layer.logical_device.create_render_pass(&create_info, None).map_err(vk_error("vkCreateRenderPass"))
+            }
+        }
+
+        fn setup_sampler(logical_layer: &LogicalLayer) -> Result<vk::Sampler, RendererError> {
+            let create_info = vk::SamplerCreateInfo::default()
+                .mag_filter(vk::Filter::NEAREST) // One texel per pixel, filtering would blend unrelated surfaces
+                .min_filter(vk::Filter::NEAREST)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .max_lod(0.0);
+
+            unsafe { logical_layer.logical_device.create_sampler(&create_info, None).map_err(vk_error("vkCreateSampler")) }
+        }
+
+        fn setup_descriptors(logical_layer: &LogicalLayer) -> Result<(vk::DescriptorSetLayout, vk::DescriptorPool, vk::DescriptorSet), RendererError> {
+            let mut bindings: Vec<vk::DescriptorSetLayoutBinding> = (0..GBUFFER_FORMATS.len() as u32)
+                .map(|i| vk::DescriptorSetLayoutBinding::default()
+                    .binding(i)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT))
+                .collect();
+            bindings.push(vk::DescriptorSetLayoutBinding::default()
+                .binding(GBUFFER_FORMATS.len() as u32)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT));
+            for binding in [OCCLUSION_BINDING, RT_SHADOW_BINDING, RT_REFLECTION_BINDING] {
+                bindings.push(vk::DescriptorSetLayoutBinding::default()
+                    .binding(binding)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT));
+            }
+            let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+                .bindings(&bindings);
+            let set_layout = unsafe {
+                logical_layer.logical_device.create_descriptor_set_layout(&layout_create_info, None)
+                    .map_err(vk_error("vkCreateDescriptorSetLayout"))?
+            };
+
+            let pool_sizes = [
+                vk::DescriptorPoolSize::default()
+                    .ty(vk::DescriptorType::SAMPLED_IMAGE)
+                    .descriptor_count(GBUFFER_FORMATS.len() as u32 + 3),
+                vk::DescriptorPoolSize::default()
+                    .ty(vk::DescriptorType::SAMPLER)
+                    .descriptor_count(1)
+            ];
+            let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+                .pool_sizes(&pool_sizes)
+                .max_sets(1);
+            let pool = unsafe {
+                logical_layer.logical_device.create_descriptor_pool(&pool_create_info, None)
+                    .map_err(vk_error("vkCreateDescriptorPool"))?
+            };
+
+            let layouts = [set_layout];
+            let alloc_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(pool)
+                .set_layouts(&layouts);
+            let sets = unsafe {
+                logical_layer.logical_device.allocate_descriptor_sets(&alloc_info).map_err(vk_error("vkAllocateDescriptorSets"))?
+            };
+
+            Ok((set_layout, pool, sets[0]))
+        }
+
+        let render_pass = setup_gbuffer_pass(logical_layer, render_target.depth_format)?;
+        let scene_pass = match scene_target {
+            PassTarget::RenderPass(..) => Some(setup_render_pass(logical_layer, scene_format, render_target, true)?),
+            PassTarget::Dynamic { .. } => None
+        };
+        let sampler = setup_sampler(logical_layer)?;
+        let (set_layout, descriptor_pool, descriptor_set) = setup_descriptors(logical_layer)?;
+
+        let gbuffer_shaders = ShaderSet {
+            vertex: ShaderSource::GlslFile(PathBuf::from("shaders/src/shader.vert")),
+            fragment: ShaderSource::GlslFile(PathBuf::from("shaders/src/gbuffer.frag"))
+        };
+        let gbuffer_pipeline = RasterPipeline::with_state(logical_layer, PassTarget::single(render_pass), &gbuffer_shaders,
+                                                          vertex_layouts, scene_set_layouts, Some(push_constant_range),
+                                                          gbuffer_state())?;
+
+        let light_shaders = ShaderSet {
+            vertex: ShaderSource::GlslFile(PathBuf::from("shaders/src/fullscreen.vert")),
+            fragment: ShaderSource::GlslFile(PathBuf::from("shaders/src/deferred_light.frag"))
+        };
+        let light_push_range = vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .offset(0)
+            .size(mem::size_of::<LightConstants>() as u32);
+        let light_state = RasterState {
+            cull_mode: vk::CullModeFlags::NONE,
+            depth_test: false, // Covers the screen, leaving the depth for whatever is drawn after it
+            ..RasterState::default()
+        };
+        let light_pipeline = RasterPipeline::with_state(logical_layer, scene_target, &light_shaders, &[],
+                                                        &[scene_set_layouts[0], set_layout, scene_set_layouts[2]],
+                                                        Some(light_push_range), light_state)?;
+        let ssao = Ssao::new(logical_layer, scene_set_layouts[0])?;
+
+        let mut deferred = Deferred {
+            render_pass,
+            scene_pass,
+            gbuffer_pipeline,
+            light_pipeline,
+            sampler,
+            set_layout,
+            descriptor_pool,
+            descriptor_set,
+            targets: None,
+            ssao,
+            ssao_settings,
+            ray_tracing,
+            ray_tracing_settings
+        };
+        deferred.resize(logical_layer, allocator, render_target)?;
+
+        Ok(deferred)
+    }
+
+    // Recreates the targets at the render target's size, on its new depth buffer. The GPU must not be using
+    // the old ones.
+    pub(crate) fn resize(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator,
+                         render_target: &RenderTarget) -> Result<(), RendererError> {
+        self.destroy_targets(logical_layer, allocator);
+        let targets = GBuffer::new(logical_layer, allocator, self.render_pass, render_target)?;
+        if let Err(e) = self.ssao.resize(logical_layer, allocator, targets.extent, targets.images[1].2, targets.images[3].2,
+                                         self.sampler) {
+            targets.destroy(logical_layer, allocator);
+            return Err(e);
+        }
+        if let Some(rt) = self.ray_tracing.as_mut() {
+            if let Err(e) = rt.resize(logical_layer, allocator, targets.extent, targets.images[1].2, targets.images[3].2, self.sampler) {
+                targets.destroy(logical_layer, allocator);
+                return Err(e);
+            }
+        }
+
+        let image_infos: Vec<[vk::DescriptorImageInfo; 1]> = targets.images.iter()
+            .map(|(_, _, view)| [vk::DescriptorImageInfo::default()
+                .image_view(*view)
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)])
+            .collect();
+        let sampler_infos = [vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)];
+        let occlusion_infos = [vk::DescriptorImageInfo::default()
+            .image_view(self.ssao.output_view())
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let (rt_shadow_view, rt_reflection_view) = match self.ray_tracing.as_ref() {
+            Some(rt) => (rt.shadow_view(), rt.reflection_view()),
+            None => (self.ssao.output_view(), self.ssao.output_view()) // Never sampled, only bound
+        };
+        let rt_infos = [rt_shadow_view, rt_reflection_view].map(|view| [vk::DescriptorImageInfo::default()
+            .image_view(view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)]);
+        let mut writes: Vec<vk::WriteDescriptorSet> = image_infos.iter()
+            .enumerate()
+            .map(|(i, info)| vk::WriteDescriptorSet::default()
+                .dst_set(self.descriptor_set)
+                .dst_binding(i as u32)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .image_info(info))
+            .collect();
+        writes.push(vk::WriteDescriptorSet::default()
+            .dst_set(self.descriptor_set)
+            .dst_binding(GBUFFER_FORMATS.len() as u32)
+            .descriptor_type(vk::DescriptorType::SAMPLER)
+            .image_info(&sampler_infos));
+        writes.push(vk::WriteDescriptorSet::default()
+            .dst_set(self.descriptor_set)
+            .dst_binding(OCCLUSION_BINDING)
+            .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+            .image_info(&occlusion_infos));
+        for (binding, info) in [RT_SHADOW_BINDING, RT_REFLECTION_BINDING].iter().zip(rt_infos.iter()) {
+            writes.push(vk::WriteDescriptorSet::default()
+                .dst_set(self.descriptor_set)
+                .dst_binding(*binding)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .image_info(info));
+        }
+        unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+
+        self.targets = Some(targets);
+        Ok(())
+    }
+
+    // The scene pass to continue in after end, None when the scene is begun with dynamic rendering
+    // What other pipelines drawing into the G-buffer are built for
+    pub(crate) fn gbuffer_target(&self) -> (PassTarget, RasterState) {
+        (PassTarget::single(self.render_pass), gbuffer_state())
+    }
+
+    pub(crate) fn scene_pass(&self) -> Option<vk::RenderPass> {
+        self.scene_pass
+    }
+
+    // Opens the G-buffer pass, geometry drawn until end goes into the targets
+    pub(crate) fn begin(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer) {
+        let targets = self.targets.as_ref().expect("G-buffer targets are missing");
+        let render_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(self.render_pass)
+            .framebuffer(targets.framebuffer)
+            .render_area(vk::Rect2D::default().extent(targets.extent))
+            .clear_values(&GBUFFER_CLEAR);
+
+        unsafe {
+            logical_layer.logical_device.cmd_begin_render_pass(command_buffer, &render_pass_info, vk::SubpassContents::INLINE);
+        }
+    }
+
+    pub(crate) fn end(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer) {
+        unsafe { logical_layer.logical_device.cmd_end_render_pass(command_buffer) };
+    }
+
+    // Readies the SSAO and ray tracing results for the lighting pass to bind, before anything else is recorded
+    pub(crate) fn prepare(&mut self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer) {
+        self.ssao.prepare(logical_layer, command_buffer);
+        if let Some(rt) = self.ray_tracing.as_mut() {
+            rt.prepare(logical_layer, command_buffer);
+        }
+    }
+
+    pub(crate) fn ray_tracing_supported(&self) -> bool {
+        self.ray_tracing.is_some()
+    }
+
+    // Ray tracing with its settings, when it's both supported and on
+    fn tracing(&self) -> Option<(&RayTracing, &RayTracingSettings)> {
+        self.ray_tracing.as_ref().zip(self.ray_tracing_settings.as_ref())
+    }
+
+    // Builds the acceleration structures instances are traced against if ray tracing is on, before begin.
+    // Returns what to destroy once frame has finished.
+    pub(crate) fn record_acceleration_structures(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator,
+                                                 command_buffer: vk::CommandBuffer, frame: usize, instances: &[TraceInstance],
+                                                 resources: &ResourceManager) -> Result<Vec<Deletion>, RendererError> {
+        match (self.ray_tracing.as_mut(), self.ray_tracing_settings.is_some()) {
+            (Some(rt), true) => rt.build(logical_layer, allocator, command_buffer, frame, instances, resources),
+            _ => Ok(Vec::new())
+        }
+    }
+
+    // Traces shadows and reflections from the targets if ray tracing is on, between end and the lighting pass
+    pub(crate) fn record_ray_tracing(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame: usize,
+                                     frame_set: vk::DescriptorSet, inv_view_proj: &Mat4) {
+        if let Some((rt, settings)) = self.tracing() {
+            rt.record(logical_layer, command_buffer, frame, frame_set, settings, *inv_view_proj);
+        }
+    }
+
+    // Renders SSAO from the targets if it's on, between end and the lighting pass
+    pub(crate) fn record_occlusion(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer,
+                                   frame_set: vk::DescriptorSet, inv_view_proj: &Mat4) {
+        if let Some(settings) = self.ssao_settings.as_ref() {
+            self.ssao.record(logical_layer, command_buffer, frame_set, settings, *inv_view_proj);
+        }
+    }
+
+    // Lights the whole screen from the targets, inside the scene pass. Leaves the lighting pipeline's
+    // layout bound, so the scene's descriptor sets have to be bound again for anything drawn after it.
+    pub(crate) fn record_lighting(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer,
+                                  frame_set: vk::DescriptorSet, shadow_set: vk::DescriptorSet, inv_view_proj: &Mat4) {
+        let descriptor_sets = [frame_set, self.descriptor_set, shadow_set];
+
+        unsafe {
+            let device = &logical_layer.logical_device;
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.light_pipeline.pipelines[0]);
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.light_pipeline.pipeline_layout,
+                                            0, &descriptor_sets, &[]);
+            self.light_pipeline.push_constants(logical_layer, command_buffer, 0, &LightConstants {
+                inv_view_proj: *inv_view_proj,
+                occlusion: [self.ssao_settings.is_some() as u32 as f32, 0.0, 0.0, 0.0],
+                ray_tracing: match self.tracing() {
+                    Some((_, s)) => [s.shadows as u32 as f32, s.reflections as u32 as f32, 0.0, 0.0],
+                    None => [0.0; 4]
+                }
+            });
+            device.cmd_draw(command_buffer, 3, 1, 0, 0); // Fullscreen triangle generated from the vertex index
+        }
+    }
+
+    // Targets are taken so destroying twice, I.E. after a failed resize, is harmless
+    pub(crate) fn destroy_targets(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        if let Some(targets) = self.targets.take() {
+            targets.destroy(logical_layer, allocator);
+        }
+        self.ssao.destroy_targets(logical_layer, allocator);
+        if let Some(rt) = self.ray_tracing.as_mut() {
+            rt.destroy_targets(logical_layer, allocator);
+        }
+    }
+
+    pub(crate) fn destroy(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        self.destroy_targets(logical_layer, allocator);
+        self.gbuffer_pipeline.destroy(logical_layer);
+        self.light_pipeline.destroy(logical_layer);
+        self.ssao.destroy(logical_layer, allocator);
+        if let Some(rt) = self.ray_tracing.as_mut() {
+            rt.destroy(logical_layer, allocator);
+        }
+        unsafe {
+            logical_layer.logical_device.destroy_descriptor_pool(self.descriptor_pool, None); // Frees the set as well
+            logical_layer.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+            logical_layer.logical_device.destroy_sampler(self.sampler, None);
+            logical_layer.logical_device.destroy_render_pass(self.render_pass, None);
+            if let Some(p) = self.scene_pass {
+                logical_layer.logical_device.destroy_render_pass(p, None);
+            }
+        }
+    }
+}
@@ -0,0 +1,63 @@
+use ash::vk;
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::mesh::Mesh;
+use crate::renderer::raster_pipeline::RasterPipeline;
+use crate::renderer::texture::Texture;
+
+// Something released while a command buffer in flight may still reference it
+pub(crate) enum Deletion {
+    Buffer(vk::Buffer, Allocation),
+    Mesh(Mesh),
+    Texture(Texture),
+    Pipeline(RasterPipeline),
+    DescriptorPool(vk::DescriptorPool), // Frees its sets too
+    AccelerationStructure(vk::AccelerationStructureKHR, vk::Buffer, Allocation) // Along with the buffer holding it
+}
+
+// Per frame slot lists of released objects. Whatever is pushed to a slot is destroyed the next time that
+// slot's timeline value has been waited on, so releasing things mid-frame never frees memory the GPU is
+// reading.
+pub(crate) struct DeletionQueue {
+    frames: Vec<Vec<Deletion>>
+}
+
+impl DeletionQueue {
+    pub(crate) fn new(frames_in_flight: usize) -> DeletionQueue {
+        DeletionQueue {
+            frames: (0..frames_in_flight).map(|_| Vec::new()).collect()
+        }
+    }
+
+    // frame should be the slot of the last submitted frame, the newest one that can reference the object
+    pub(crate) fn push(&mut self, frame: usize, deletion: Deletion) {
+        self.frames[frame].push(deletion);
+    }
+
+    // Call after waiting on the frame slot's timeline value
+    pub(crate) fn flush(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, frame: usize) {
+        for d in self.frames[frame].drain(..) {
+            match d {
+                Deletion::Buffer(buf, alloc) => allocator.destroy_buffer(logical_layer, buf, &alloc),
+                Deletion::Mesh(m) => m.destroy(logical_layer, allocator),
+                Deletion::Texture(t) => t.destroy(logical_layer, allocator),
+                Deletion::Pipeline(mut p) => p.destroy(logical_layer),
+                Deletion::DescriptorPool(p) => unsafe { logical_layer.logical_device.destroy_descriptor_pool(p, None) },
+                Deletion::AccelerationStructure(a, buf, alloc) => {
+                    if let Some(loader) = logical_layer.acceleration_structure.as_ref() {
+                        unsafe { loader.destroy_acceleration_structure(a, None) };
+                    }
+                    allocator.destroy_buffer(logical_layer, buf, &alloc);
+                }
+            }
+        }
+    }
+
+    // The GPU must be idle
+    pub(crate) fn destroy(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        for frame in 0..self.frames.len() {
+            self.flush(logical_layer, allocator, frame);
+        }
+    }
+}
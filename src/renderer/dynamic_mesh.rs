@@ -0,0 +1,137 @@
+use std::mem;
+
+use ash::vk;
+use bytemuck::Pod;
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::deletion_queue::{Deletion, DeletionQueue};
+use crate::renderer::error::RendererError;
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+
+const INITIAL_CAPACITY: vk::DeviceSize = 64 * 1024; // Bytes, shared by the vertices and indices
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DynamicMeshHandle(pub(crate) usize); // Index into the renderer's dynamic mesh list
+
+// One frame slot's copy of the geometry, vertices first then indices
+struct FrameBuffer {
+    buf: vk::Buffer,
+    alloc: Allocation,
+    capacity: vk::DeviceSize,
+    index_offset: vk::DeviceSize,
+    index_count: u32,
+    stale: bool // Set whenever the geometry changes, cleared once this slot has been rewritten
+}
+
+// Geometry that's replaced often, I.E. every frame. Each frame in flight has its own host visible
+// buffer that stays mapped, so updates are a memcpy once the slot's timeline value has been waited on instead
+// of a staging copy.
+pub(crate) struct DynamicMesh {
+    vertices: Vec<u8>, // Any vertex format, usually Vertex
+    indices: Vec<u32>,
+    frames: Vec<FrameBuffer>
+}
+
+impl DynamicMesh {
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, frame_count: usize) -> Result<DynamicMesh, RendererError> {
+        let mut mesh = DynamicMesh {
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            frames: Vec::with_capacity(frame_count)
+        };
+
+        for _ in 0..frame_count {
+            match Self::create_buffer(logical_layer, allocator, INITIAL_CAPACITY) {
+                Ok((alloc, buf)) => mesh.frames.push(FrameBuffer {
+                    buf,
+                    alloc,
+                    capacity: INITIAL_CAPACITY,
+                    index_offset: 0,
+                    index_count: 0,
+                    stale: false
+                }),
+                Err(e) => {
+                    mesh.destroy(logical_layer, allocator);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(mesh)
+    }
+
+    fn create_buffer(logical_layer: &LogicalLayer, allocator: &Allocator, capacity: vk::DeviceSize) -> Result<(Allocation, vk::Buffer), RendererError> {
+        allocator.create_buffer(logical_layer,
+                                capacity,
+                                vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::INDEX_BUFFER,
+                                vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                    vk::MemoryPropertyFlags::HOST_COHERENT, // No explicit flushes needed
+                                MemoryCategory::Meshes)
+    }
+
+    // Replaces the geometry from the next recorded frame on
+    pub(crate) fn set<V: Pod>(&mut self, vertices: &[V], indices: &[u32]) {
+        assert!(indices.iter().all(|i| (*i as usize) < vertices.len()), "Index out of range of the vertices");
+        assert_eq!(mem::size_of::<V>() % 4, 0, "Indices following the vertices have to stay 4 byte aligned");
+
+        self.vertices.clear();
+        self.vertices.extend_from_slice(bytemuck::cast_slice(vertices));
+        self.indices.clear();
+        self.indices.extend_from_slice(indices);
+        for f in self.frames.iter_mut() {
+            f.stale = true;
+        }
+    }
+
+    // Copies the geometry into the frame's buffer if it changed since the slot was last written. The
+    // frame's previous submission must have finished, since the buffer may be replaced with a larger one.
+    pub(crate) fn write(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, frame: usize) -> Result<(), RendererError> {
+        let slot = &mut self.frames[frame];
+        if !slot.stale {
+            return Ok(());
+        }
+
+        let vertex_bytes = self.vertices.len() as vk::DeviceSize;
+        let index_bytes = mem::size_of_val(self.indices.as_slice()) as vk::DeviceSize;
+        let size = vertex_bytes + index_bytes;
+        if size > slot.capacity {
+            let capacity = size.next_power_of_two();
+            let (alloc, buf) = Self::create_buffer(logical_layer, allocator, capacity)?;
+            allocator.destroy_buffer(logical_layer, slot.buf, &slot.alloc);
+            slot.buf = buf;
+            slot.alloc = alloc;
+            slot.capacity = capacity;
+        }
+
+        let ptr = slot.alloc.mapped_ptr().unwrap(); // Mapped for as long as the allocation lives
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.vertices.as_ptr(), ptr, vertex_bytes as usize);
+            std::ptr::copy_nonoverlapping(self.indices.as_ptr() as *const u8, ptr.add(vertex_bytes as usize), index_bytes as usize);
+        }
+        slot.index_offset = vertex_bytes;
+        slot.index_count = self.indices.len() as u32;
+        slot.stale = false;
+
+        Ok(())
+    }
+
+    // The buffer to bind as both vertex and index buffer, the offset of its indices and their count
+    pub(crate) fn draw_info(&self, frame: usize) -> (vk::Buffer, vk::DeviceSize, u32) {
+        let slot = &self.frames[frame];
+        (slot.buf, slot.index_offset, slot.index_count)
+    }
+
+    // Hands every slot's buffer to the deletion queue, since any frame in flight may be drawing one
+    pub(crate) fn retire(self, deletions: &mut DeletionQueue, frame: usize) {
+        for f in self.frames {
+            deletions.push(frame, Deletion::Buffer(f.buf, f.alloc));
+        }
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        for f in self.frames.iter() {
+            allocator.destroy_buffer(logical_layer, f.buf, &f.alloc);
+        }
+    }
+}
@@ -0,0 +1,76 @@
+use std::fmt;
+
+use ash::vk;
+
+use crate::renderer::morph::MAX_MORPH_TARGETS;
+use crate::renderer::shader::ShaderError;
+
+#[derive(Debug)]
+pub enum RendererError {
+    LoaderNotFound(String), // libvulkan couldn't be loaded, I.E. no driver installed
+    IncompatibleDriver, // The driver doesn't support Vulkan 1.3
+    MissingInstanceExtensions,
+    NoSuitableDevice, // No GPU met the requirements listed in PhysicalLayer::new
+    NoSuitableMemoryType,
+    NoSuitableFormat(&'static str),
+    TooManyTextures, // Every slot of the bindless texture array is taken
+    TooManyMorphTargets(usize), // More than MAX_MORPH_TARGETS
+    ComputeUnsupported, // The graphics queue family can't run compute shaders
+    MissingFeature(&'static str), // An optional device feature a call needs, I.E. fillModeNonSolid
+    IdBufferDisabled, // read_id_at without the ID buffer turned on
+    SurfaceLost,
+    DeviceLost,
+    OutOfMemory,
+    Window(String),
+    VideoModeUnavailable,
+    CursorGrab(String), // The platform refused to grab the cursor // The monitor or video mode isn't there anymore, I.E. after unplugging a monitor
+    Shader(ShaderError),
+    Font(String), // The TTF couldn't be parsed
+    Vulkan { call: &'static str, result: vk::Result } // Anything without a more specific variant
+}
+
+impl fmt::Display for RendererError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RendererError::LoaderNotFound(e) => write!(f, "Failed to load the Vulkan library ({}), is a Vulkan driver installed and VK_LIB_PATH set?", e),
+            RendererError::IncompatibleDriver => write!(f, "The installed Vulkan driver doesn't support Vulkan 1.3"),
+            RendererError::MissingInstanceExtensions => write!(f, "The Vulkan instance lacks the extensions needed to present to this window"),
+            RendererError::NoSuitableDevice => write!(f, "No GPU supports graphics, presentation to this window and the required extensions"),
+            RendererError::NoSuitableMemoryType => write!(f, "No device memory type matches the requested properties"),
+            RendererError::NoSuitableFormat(usage) => write!(f, "No supported format for the {}", usage),
+            RendererError::TooManyTextures => write!(f, "Every bindless texture slot is in use"),
+            RendererError::TooManyMorphTargets(count) => write!(f, "{} morph targets, at most {} are supported", count, MAX_MORPH_TARGETS),
+            RendererError::ComputeUnsupported => write!(f, "The graphics queue doesn't support compute shaders"),
+            RendererError::MissingFeature(feature) => write!(f, "The GPU doesn't support {}", feature),
+            RendererError::IdBufferDisabled => write!(f, "Pick IDs aren't being rendered, turn on the ID buffer first"),
+            RendererError::SurfaceLost => write!(f, "The window surface was lost"),
+            RendererError::DeviceLost => write!(f, "The GPU was lost, I.E. after a driver reset"),
+            RendererError::OutOfMemory => write!(f, "Out of host or device memory"),
+            RendererError::Window(e) => write!(f, "Failed to create the window: {}", e),
+            RendererError::CursorGrab(e) => write!(f, "Failed to grab the cursor: {}", e),
+            RendererError::VideoModeUnavailable => write!(f, "The requested monitor or video mode isn't available"),
+            RendererError::Shader(e) => write!(f, "{}", e),
+            RendererError::Font(e) => write!(f, "Failed to load the font: {}", e),
+            RendererError::Vulkan { call, result } => write!(f, "{} failed with {:?}", call, result)
+        }
+    }
+}
+
+impl std::error::Error for RendererError {}
+
+impl From<ShaderError> for RendererError {
+    fn from(e: ShaderError) -> Self {
+        RendererError::Shader(e)
+    }
+}
+
+// For use with map_err, I.E. .map_err(vk_error("vkCreateDevice"))
+pub(crate) fn vk_error(call: &'static str) -> impl Fn(vk::Result) -> RendererError {
+    move |result| match result {
+        vk::Result::ERROR_SURFACE_LOST_KHR => RendererError::SurfaceLost,
+        vk::Result::ERROR_DEVICE_LOST => RendererError::DeviceLost,
+        vk::Result::ERROR_INCOMPATIBLE_DRIVER => RendererError::IncompatibleDriver,
+        vk::Result::ERROR_OUT_OF_HOST_MEMORY | vk::Result::ERROR_OUT_OF_DEVICE_MEMORY => RendererError::OutOfMemory,
+        _ => RendererError::Vulkan { call, result }
+    }
+}
@@ -0,0 +1,62 @@
+use glam::{Vec3, Vec4};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FogFalloff {
+    Linear {
+        start: f32, // Distance from the camera where the fog begins
+        end: f32 // Where it's fully opaque
+    },
+    Exponential {
+        density: f32 // Per world unit, I.E. 0.02 hides most of what's past 150 units
+    },
+    ExponentialSquared {
+        density: f32 // Clearer up close than Exponential, then thickening faster
+    }
+}
+
+// Fades lit surfaces into color with distance from the camera. Whatever the falloff, the fog is opaque at the
+// camera's far plane so geometry never visibly clips against it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Fog {
+    pub color: Vec3, // Match the horizon, I.E. TimeOfDay::fog_color, so fogged terrain blends into the sky
+    pub falloff: FogFalloff,
+    pub sky: f32 // 0 to 1, how much the fog covers the sky at the horizon
+}
+
+impl Fog {
+    pub fn linear(color: Vec3, start: f32, end: f32) -> Fog {
+        Fog {
+            color,
+            falloff: FogFalloff::Linear { start, end },
+            sky: 1.0
+        }
+    }
+
+    pub fn exponential(color: Vec3, density: f32) -> Fog {
+        Fog {
+            color,
+            falloff: FogFalloff::Exponential { density },
+            sky: 1.0
+        }
+    }
+
+    // Linear fog that's opaque at distance, I.E. the chunk view distance, so chunks streaming in at the edge
+    // fade in rather than pop
+    pub fn view_distance(color: Vec3, distance: f32) -> Fog {
+        Fog::linear(color, distance * 0.6, distance)
+    }
+
+    // The frame uniform's fog_color and fog_params
+    pub(crate) fn uniforms(fog: Option<&Fog>, far: f32) -> (Vec4, Vec4) {
+        let fog = match fog {
+            Some(f) => f,
+            None => return (Vec4::ZERO, Vec4::new(0.0, 0.0, 0.0, far))
+        };
+        let (mode, start, end, density) = match fog.falloff {
+            FogFalloff::Linear { start, end } => (1.0, start, end.min(far), 0.0),
+            FogFalloff::Exponential { density } => (2.0, 0.0, 0.0, density),
+            FogFalloff::ExponentialSquared { density } => (3.0, 0.0, 0.0, density)
+        };
+        (fog.color.extend(mode), Vec4::new(start, end, density, far))
+    }
+}
@@ -0,0 +1,135 @@
+use glam::{Mat4, Vec2, Vec4};
+
+use crate::renderer::camera::Camera;
+use crate::renderer::compute::ComputeDispatch;
+use crate::renderer::debug_draw::DebugDraw;
+use crate::renderer::dynamic_mesh::DynamicMeshHandle;
+use crate::renderer::indirect::IndirectBatchHandle;
+use crate::renderer::instance::Instance;
+use crate::renderer::lod::LodGroup;
+use crate::renderer::memory::MemoryStats;
+use crate::renderer::mesh::MeshHandle;
+use crate::renderer::occlusion::OcclusionId;
+use crate::renderer::render_queue::MaterialHandle;
+use crate::renderer::renderer::CubulousRenderer;
+use crate::renderer::sprite::{Sprite, SpriteBatch};
+use crate::renderer::stats::FrameStats;
+use crate::renderer::text::FontHandle;
+
+// Handed to the run_with callback once per frame. The render queue starts empty every frame, so
+// everything that should be visible has to be drawn again.
+pub struct Frame<'a> {
+    renderer: &'a mut CubulousRenderer,
+    alpha: f32,
+    exit_requested: bool
+}
+
+impl<'a> Frame<'a> {
+    pub(crate) fn new(renderer: &'a mut CubulousRenderer, alpha: f32) -> Frame<'a> {
+        Frame {
+            renderer,
+            alpha,
+            exit_requested: false
+        }
+    }
+
+    // How far this frame falls between the previous tick and the latest one under run_fixed, 0 to 1.
+    // Always 1 under run_with, which has no ticks.
+    pub fn alpha(&self) -> f32 {
+        self.alpha
+    }
+
+    pub fn draw(&mut self, mesh: MeshHandle, transform: Mat4, material: MaterialHandle) {
+        self.renderer.render_queue().push(mesh, transform, material);
+    }
+
+    // Skipped while occlusion queries find the mesh's bounds hidden behind what was drawn in earlier
+    // frames. Suits large or expensive objects, each test costs a box drawn into the depth buffer.
+    pub fn draw_occludable(&mut self, mesh: MeshHandle, transform: Mat4, material: MaterialHandle, id: OcclusionId) {
+        self.renderer.render_queue().push_occludable(mesh, transform, material, id);
+    }
+
+    // Covers its pixels with pick_id in the ID buffer, see CubulousRenderer::read_id_at. Instanced draws
+    // write each Instance::id instead.
+    pub fn draw_pickable(&mut self, mesh: MeshHandle, transform: Mat4, material: MaterialHandle, pick_id: u32) {
+        self.renderer.render_queue().push_pickable(mesh, transform, material, pick_id);
+    }
+
+    // Draws the group's level for the object's distance or size on screen, or nothing past its last level
+    pub fn draw_lod(&mut self, lod: &LodGroup, transform: Mat4, material: MaterialHandle) {
+        if let Some(mesh) = lod.select(self.renderer.camera(), &transform) {
+            self.renderer.render_queue().push(mesh, transform, material);
+        }
+    }
+
+    // One draw call for every instance of mesh
+    pub fn draw_instanced(&mut self, mesh: MeshHandle, instances: &[Instance], material: MaterialHandle) {
+        self.renderer.render_queue().push_instanced(mesh, instances, material);
+    }
+
+    // Every object in the batch, culled on the GPU
+    pub fn draw_indirect(&mut self, batch: IndirectBatchHandle) {
+        self.renderer.render_queue().push_indirect(batch);
+    }
+
+    // Draws whatever the mesh holds when the frame is recorded
+    pub fn draw_dynamic(&mut self, mesh: DynamicMeshHandle, transform: Mat4, material: MaterialHandle) {
+        self.renderer.render_queue().push_dynamic(mesh, transform, material);
+    }
+
+    // Runs before this frame's draws, which can read what it writes
+    pub fn dispatch(&mut self, dispatch: ComputeDispatch) {
+        self.renderer.dispatch(dispatch);
+    }
+
+    // Screen space, position is the top left of the text in pixels
+    pub fn draw_text(&mut self, font: FontHandle, text: &str, position: Vec2, color: Vec4) {
+        self.renderer.draw_text(font, text, position, color);
+    }
+
+    // Builds this frame's UI, which is drawn over everything else. Can be called any number of times
+    // per frame, I.E. once per debug panel.
+    pub fn ui<R>(&mut self, build: impl FnOnce(&egui::Context) -> R) -> R {
+        build(self.renderer.ui_context())
+    }
+
+    // Screen space quad, drawn in order with text
+    pub fn draw_sprite(&mut self, sprite: &Sprite) {
+        self.renderer.draw_sprite(sprite);
+    }
+
+    pub fn draw_sprites(&mut self, batch: &SpriteBatch) {
+        self.renderer.draw_sprites(batch);
+    }
+
+    // Lines that are only drawn this frame
+    pub fn debug_draw(&mut self) -> &mut DebugDraw {
+        self.renderer.debug_draw()
+    }
+
+    pub fn camera(&mut self) -> &mut Camera {
+        self.renderer.camera_mut()
+    }
+
+    pub fn stats(&self) -> &FrameStats {
+        self.renderer.stats()
+    }
+
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.renderer.memory_stats()
+    }
+
+    // Everything else, I.E. uploading meshes mid run
+    pub fn renderer(&mut self) -> &mut CubulousRenderer {
+        self.renderer
+    }
+
+    // Closes the window once this frame has been drawn
+    pub fn exit(&mut self) {
+        self.exit_requested = true;
+    }
+
+    pub(crate) fn exit_requested(&self) -> bool {
+        self.exit_requested
+    }
+}
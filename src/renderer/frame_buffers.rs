@@ -1,14 +1,15 @@
 use ash::{vk, Device};
 
+use crate::renderer::error::{vk_error, RendererError};
 use crate::renderer::logical_layer::LogicalLayer;
 use crate::renderer::physical_layer::PhysicalLayer;
 use crate::renderer::render_target::RenderTarget;
 
 pub(crate) fn setup_frame_buffers(logical_layer: &LogicalLayer, render_pass: vk::RenderPass,
-                       render_target: &RenderTarget) -> Vec<vk::Framebuffer> {
+                       render_target: &RenderTarget) -> Result<Vec<vk::Framebuffer>, RendererError> {
     let mut frame_buffers: Vec<vk::Framebuffer> = Vec::with_capacity(render_target.image_views.len());
     for v in render_target.image_views.iter() {
-        let image_slice = [*v];
+        let image_slice = [*v]; // Only post-processing's final pass renders to the swapchain
         let create_info = vk::FramebufferCreateInfo::default()
             .render_pass(render_pass)
             .attachments(&image_slice)
@@ -16,10 +17,13 @@ pub(crate) fn setup_frame_buffers(logical_layer: &LogicalLayer, render_pass: vk:
             .height(render_target.extent.height)
             .layers(1);
 
-        unsafe { frame_buffers.push(logical_layer.logical_device.create_framebuffer(&create_info, None).unwrap()) }
+        unsafe {
+            frame_buffers.push(logical_layer.logical_device.create_framebuffer(&create_info, None)
+                .map_err(vk_error("vkCreateFramebuffer"))?)
+        }
     }
 
-    frame_buffers
+    Ok(frame_buffers)
 }
 
 pub(crate) fn destroy_frame_buffers(logical_layer: &LogicalLayer, frame_buffers: &Vec<vk::Framebuffer>) {
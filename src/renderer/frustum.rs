@@ -0,0 +1,94 @@
+use glam::{Mat4, Vec3, Vec4};
+
+// Axis aligned bounding box
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3
+}
+
+impl Aabb {
+    // A point at the origin when there are no points
+    pub fn from_points<I: IntoIterator<Item = Vec3>>(points: I) -> Aabb {
+        let mut points = points.into_iter();
+        let first = match points.next() {
+            Some(p) => p,
+            None => return Aabb { min: Vec3::ZERO, max: Vec3::ZERO }
+        };
+
+        points.fold(Aabb { min: first, max: first }, |b, p| Aabb {
+            min: b.min.min(p),
+            max: b.max.max(p)
+        })
+    }
+
+    pub fn center(&self) -> Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn half_extents(&self) -> Vec3 {
+        (self.max - self.min) * 0.5
+    }
+
+    // Box around the transformed box (Arvo's method), looser than the transformed points under rotation
+    pub fn transformed(&self, transform: &Mat4) -> Aabb {
+        let center = transform.transform_point3(self.center());
+        let half = self.half_extents();
+        let extents = transform.x_axis.truncate().abs() * half.x +
+            transform.y_axis.truncate().abs() * half.y +
+            transform.z_axis.truncate().abs() * half.z;
+
+        Aabb {
+            min: center - extents,
+            max: center + extents
+        }
+    }
+
+    // Center and radius
+    pub fn bounding_sphere(&self) -> (Vec3, f32) {
+        (self.center(), self.half_extents().length())
+    }
+}
+
+// The six clip planes of a view projection matrix, normals pointing inwards
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    planes: [Vec4; 6] // xyz is the unit normal, w the distance from the origin
+}
+
+impl Frustum {
+    // Gribb/Hartmann plane extraction, with Vulkan's [0, 1] depth range for the near plane
+    pub fn from_view_proj(view_proj: Mat4) -> Frustum {
+        let (r0, r1, r2, r3) = (view_proj.row(0), view_proj.row(1), view_proj.row(2), view_proj.row(3));
+        let planes = [r3 + r0, r3 - r0, r3 + r1, r3 - r1, r2, r3 - r2]
+            .map(|p| p / p.truncate().length());
+
+        Frustum {
+            planes
+        }
+    }
+
+    // Conservative, boxes near the frustum's edges can pass without being visible
+    pub fn intersects_aabb(&self, min: Vec3, max: Vec3) -> bool {
+        self.planes.iter().all(|p| {
+            let normal = p.truncate();
+            let furthest = Vec3::select(normal.cmpge(Vec3::ZERO), max, min); // Corner furthest along the normal
+            normal.dot(furthest) + p.w >= 0.0
+        })
+    }
+
+    // Sphere first since it's cheaper and rejects most objects well outside the frustum
+    pub fn intersects(&self, bounds: &Aabb) -> bool {
+        let (center, radius) = bounds.bounding_sphere();
+        self.intersects_sphere(center, radius) && self.intersects_aabb(bounds.min, bounds.max)
+    }
+
+    pub fn intersects_sphere(&self, center: Vec3, radius: f32) -> bool {
+        self.planes.iter().all(|p| p.truncate().dot(center) + p.w >= -radius)
+    }
+
+    // For culling in shaders
+    pub(crate) fn planes(&self) -> [Vec4; 6] {
+        self.planes
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use glam::{Quat, Vec2, Vec3, Vec4};
+
+use crate::input::InputState;
+use crate::renderer::frame::Frame;
+use crate::renderer::renderer::CubulousRenderer;
+
+// A frame longer than this only simulates this much, so a stall (I.E. dragging the window) doesn't leave
+// the loop running ticks back to back to catch up
+const MAX_FRAME_TIME: Duration = Duration::from_millis(250);
+
+// Game state driven by CubulousRenderer::run_fixed. tick advances the simulation by a fixed step,
+// frame draws it as often as the display allows.
+pub trait Game {
+    // Runs zero or more times before each frame. input holds the whole frame's input, so presses are seen
+    // by every tick of the frame and by none if the frame has no ticks. Handle them in frame or read held
+    // state here.
+    fn tick(&mut self, renderer: &mut CubulousRenderer, input: &InputState, step: Duration);
+
+    // Frame::alpha is how far between the last two ticks the frame falls, for interpolating
+    fn frame(&mut self, frame: &mut Frame, input: &InputState, delta: Duration);
+}
+
+// Counts how many fixed steps fit into the time that passed, carrying the remainder over
+#[derive(Clone, Copy, Debug)]
+pub struct FixedTimestep {
+    step: Duration,
+    accumulator: Duration
+}
+
+impl FixedTimestep {
+    pub fn new(rate: u32) -> FixedTimestep {
+        FixedTimestep {
+            step: Duration::from_secs(1) / rate.max(1),
+            accumulator: Duration::ZERO
+        }
+    }
+
+    pub fn step(&self) -> Duration {
+        self.step
+    }
+
+    // Returns the number of ticks to run for delta
+    pub fn advance(&mut self, delta: Duration) -> u32 {
+        self.accumulator += delta.min(MAX_FRAME_TIME);
+        let mut ticks = 0;
+        while self.accumulator >= self.step {
+            self.accumulator -= self.step;
+            ticks += 1;
+        }
+        ticks
+    }
+
+    // 0 to 1, the time left over after the last tick as a fraction of a step
+    pub fn alpha(&self) -> f32 {
+        self.accumulator.as_secs_f32() / self.step.as_secs_f32()
+    }
+}
+
+// Blends from self at alpha 0 to next at alpha 1
+pub trait Interpolate {
+    fn interpolate(&self, next: &Self, alpha: f32) -> Self;
+}
+
+impl Interpolate for f32 {
+    fn interpolate(&self, next: &Self, alpha: f32) -> Self {
+        self + (next - self) * alpha
+    }
+}
+
+impl Interpolate for Vec2 {
+    fn interpolate(&self, next: &Self, alpha: f32) -> Self {
+        self.lerp(*next, alpha)
+    }
+}
+
+impl Interpolate for Vec3 {
+    fn interpolate(&self, next: &Self, alpha: f32) -> Self {
+        self.lerp(*next, alpha)
+    }
+}
+
+impl Interpolate for Vec4 {
+    fn interpolate(&self, next: &Self, alpha: f32) -> Self {
+        self.lerp(*next, alpha)
+    }
+}
+
+impl Interpolate for Quat {
+    fn interpolate(&self, next: &Self, alpha: f32) -> Self {
+        self.slerp(*next, alpha)
+    }
+}
+
+// A value updated by ticks and read by frames. set at most once per tick, then get with Frame::alpha.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Interpolated<T> {
+    previous: T,
+    current: T
+}
+
+impl<T: Interpolate + Clone> Interpolated<T> {
+    pub fn new(value: T) -> Interpolated<T> {
+        Interpolated {
+            previous: value.clone(),
+            current: value
+        }
+    }
+
+    // The last tick's value becomes the one frames blend from
+    pub fn set(&mut self, value: T) {
+        self.previous = std::mem::replace(&mut self.current, value);
+    }
+
+    // Jumps straight to value without blending from the old one, I.E. for teleports
+    pub fn reset(&mut self, value: T) {
+        self.previous = value.clone();
+        self.current = value;
+    }
+
+    pub fn current(&self) -> &T {
+        &self.current
+    }
+
+    pub fn get(&self, alpha: f32) -> T {
+        self.previous.interpolate(&self.current, alpha)
+    }
+}
@@ -0,0 +1,133 @@
+use std::ffi::CStr;
+
+use ash::{vk, Instance};
+
+use crate::renderer::config::DevicePreference;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceType {
+    Discrete,
+    Integrated, // Shares memory with the CPU
+    Virtual,
+    Cpu, // Software rendering, I.E. lavapipe
+    Other
+}
+
+impl DeviceType {
+    fn from_vk(device_type: vk::PhysicalDeviceType) -> DeviceType {
+        match device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => DeviceType::Discrete,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => DeviceType::Integrated,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => DeviceType::Virtual,
+            vk::PhysicalDeviceType::CPU => DeviceType::Cpu,
+            _ => DeviceType::Other
+        }
+    }
+}
+
+// One of the system's Vulkan devices, as reported by the driver
+#[derive(Clone, Debug)]
+pub struct GpuInfo {
+    pub index: usize, // In the order Vulkan enumerates devices, what DevicePreference::Index refers to
+    pub name: String,
+    pub device_type: DeviceType,
+    pub vendor_id: u32, // PCI vendor, see vendor_name
+    pub device_id: u32,
+    pub vram: u64, // Bytes of device local memory, shared system memory on integrated GPUs
+    pub api_version: (u32, u32, u32),
+    pub driver_version: u32, // Encoded differently by each vendor
+    pub suitable: bool // Supports everything the renderer needs, only suitable devices can be picked
+}
+
+impl GpuInfo {
+    pub(crate) fn new(instance: &Instance, device: vk::PhysicalDevice, index: usize, suitable: bool) -> GpuInfo {
+        let properties = unsafe { instance.get_physical_device_properties(device) };
+        let memory = unsafe { instance.get_physical_device_memory_properties(device) };
+        let vram = memory.memory_heaps[..memory.memory_heap_count as usize].iter()
+            .filter(|h| h.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+            .map(|h| h.size)
+            .sum();
+        let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }.to_string_lossy().into_owned();
+
+        GpuInfo {
+            index,
+            name,
+            device_type: DeviceType::from_vk(properties.device_type),
+            vendor_id: properties.vendor_id,
+            device_id: properties.device_id,
+            vram,
+            api_version: (vk::api_version_major(properties.api_version),
+                          vk::api_version_minor(properties.api_version),
+                          vk::api_version_patch(properties.api_version)),
+            driver_version: properties.driver_version,
+            suitable
+        }
+    }
+
+    // None for vendors without a well known PCI ID
+    pub fn vendor_name(&self) -> Option<&'static str> {
+        match self.vendor_id {
+            VENDOR_AMD => Some("AMD"),
+            VENDOR_NVIDIA => Some("NVIDIA"),
+            VENDOR_INTEL => Some("Intel"),
+            VENDOR_ARM => Some("ARM"),
+            VENDOR_QUALCOMM => Some("Qualcomm"),
+            VENDOR_APPLE => Some("Apple"),
+            _ => None
+        }
+    }
+
+    // driver_version decoded the way the vendor packs it
+    pub fn driver_version_text(&self) -> String {
+        let v = self.driver_version;
+        match self.vendor_id {
+            VENDOR_NVIDIA => format!("{}.{}.{}.{}", v >> 22, (v >> 14) & 0xff, (v >> 6) & 0xff, v & 0x3f),
+            VENDOR_INTEL if cfg!(windows) => format!("{}.{}", v >> 14, v & 0x3fff),
+            _ => format!("{}.{}.{}", vk::api_version_major(v), vk::api_version_minor(v), vk::api_version_patch(v))
+        }
+    }
+}
+
+// PCI vendor IDs for DevicePreference::Vendor
+pub const VENDOR_AMD: u32 = 0x1002;
+pub const VENDOR_NVIDIA: u32 = 0x10de;
+pub const VENDOR_INTEL: u32 = 0x8086;
+pub const VENDOR_ARM: u32 = 0x13b5;
+pub const VENDOR_QUALCOMM: u32 = 0x5143;
+pub const VENDOR_APPLE: u32 = 0x106b;
+
+// Index into gpus of the suitable device the preference picks, None if none are suitable
+pub(crate) fn choose_gpu(gpus: &[GpuInfo], preference: DevicePreference) -> Option<usize> {
+    // Lower ranks first, more memory breaks ties
+    fn best(gpus: &[GpuInfo], rank: impl Fn(&GpuInfo) -> u32) -> Option<usize> {
+        gpus.iter()
+            .filter(|g| g.suitable)
+            .min_by_key(|g| (rank(g), std::cmp::Reverse(g.vram)))
+            .map(|g| g.index)
+    }
+    fn performance_rank(gpu: &GpuInfo) -> u32 {
+        match gpu.device_type {
+            DeviceType::Discrete => 0,
+            DeviceType::Integrated => 1,
+            DeviceType::Virtual => 2,
+            DeviceType::Other => 3,
+            DeviceType::Cpu => 4
+        }
+    }
+
+    match preference {
+        DevicePreference::HighPerformance => best(gpus, performance_rank),
+        DevicePreference::LowPower => best(gpus, |g| match g.device_type {
+            DeviceType::Integrated => 0,
+            _ => 1 + performance_rank(g)
+        }),
+        DevicePreference::Vendor(vendor) => best(gpus, |g| (g.vendor_id != vendor) as u32 * 5 + performance_rank(g)),
+        DevicePreference::Index(i) => match gpus.get(i) {
+            Some(g) if g.suitable => Some(i),
+            _ => {
+                log::warn!("GPU {} doesn't exist or can't run the renderer, picking the fastest instead", i);
+                best(gpus, performance_rank)
+            }
+        }
+    }
+}
@@ -0,0 +1,561 @@
+use std::mem;
+use std::path::PathBuf;
+
+use ash::vk;
+use glam::Mat4;
+use naga::ShaderStage;
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::barrier::{Barriers, Ownership, Usage};
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::render_target::RenderTarget;
+use crate::renderer::shader::{compile, ShaderSource};
+use crate::renderer::timeline::Timeline;
+use crate::renderer::vertex::VertexLayout;
+
+const ID_VERTEX_SHADER: &str = "shaders/src/id.vert";
+const ID_FRAGMENT_SHADER: &str = "shaders/src/id.frag";
+const ID_FORMAT: vk::Format = vk::Format::R32_UINT; // Color attachment support is required of every device
+
+// The ID image and the depth buffer it's tested against, sized to the render target
+struct IdTargets {
+    extent: vk::Extent2D,
+    image: vk::Image,
+    alloc: Allocation,
+    view: vk::ImageView,
+    depth_image: vk::Image,
+    depth_alloc: Allocation,
+    depth_view: vk::ImageView,
+    framebuffer: vk::Framebuffer
+}
+
+impl IdTargets {
+    fn new(logical_layer: &LogicalLayer, allocator: &Allocator, render_pass: vk::RenderPass, depth_format: vk::Format,
+           extent: vk::Extent2D) -> Result<IdTargets, RendererError> {
+        let create_image = |format: vk::Format, usage: vk::ImageUsageFlags, aspect_mask: vk::ImageAspectFlags| {
+            let create_info = vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .format(format)
+                .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+                .mip_levels(1)
+                .array_layers(1)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .usage(usage)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .initial_layout(vk::ImageLayout::UNDEFINED);
+            let (alloc, image) = allocator.create_image(logical_layer, &create_info, vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                        MemoryCategory::RenderTargets)?;
+            let view_create_info = vk::ImageViewCreateInfo::default()
+                .image(image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(format)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1
+                });
+            match unsafe { logical_layer.logical_device.create_image_view(&view_create_info, None) } {
+                Ok(view) => Ok((image, alloc, view)),
+                Err(e) => {
+                    allocator.destroy_image(logical_layer, image, &alloc);
+                    Err(vk_error("vkCreateImageView")(e))
+                }
+            }
+        };
+        let destroy = |image: vk::Image, alloc: &Allocation, view: vk::ImageView| {
+            unsafe { logical_layer.logical_device.destroy_image_view(view, None) };
+            allocator.destroy_image(logical_layer, image, alloc);
+        };
+
+        let (image, alloc, view) = create_image(ID_FORMAT, vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC,
+                                                vk::ImageAspectFlags::COLOR)?;
+        let (depth_image, depth_alloc, depth_view) = match create_image(depth_format, vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                                                                        vk::ImageAspectFlags::DEPTH) {
+            Ok(d) => d,
+            Err(e) => {
+                destroy(image, &alloc, view);
+                return Err(e);
+            }
+        };
+
+        let attachments = [view, depth_view];
+        let framebuffer_create_info = vk::FramebufferCreateInfo::default()
+            .render_pass(render_pass)
+            .attachments(&attachments)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let framebuffer = match unsafe { logical_layer.logical_device.create_framebuffer(&framebuffer_create_info, None) } {
+            Ok(f) => f,
+            Err(e) => {
+                destroy(depth_image, &depth_alloc, depth_view);
+                destroy(image, &alloc, view);
+                return Err(vk_error("vkCreateFramebuffer")(e));
+            }
+        };
+
+        Ok(IdTargets {
+            extent,
+            image,
+            alloc,
+            view,
+            depth_image,
+            depth_alloc,
+            depth_view,
+            framebuffer
+        })
+    }
+
+    fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        unsafe {
+            logical_layer.logical_device.destroy_framebuffer(self.framebuffer, None);
+            logical_layer.logical_device.destroy_image_view(self.depth_view, None);
+            logical_layer.logical_device.destroy_image_view(self.view, None);
+        }
+        allocator.destroy_image(logical_layer, self.depth_image, &self.depth_alloc);
+        allocator.destroy_image(logical_layer, self.image, &self.alloc);
+    }
+}
+
+// Object IDs for pixel exact picking. After the scene, the queue's draws are rendered again into an R32_UINT
+// image holding the pick ID of whatever is frontmost at each pixel, 0 where there's nothing with one. read
+// copies a single texel of it back to the CPU.
+pub(crate) struct IdBuffer {
+    render_pass: vk::RenderPass,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    depth_format: vk::Format,
+    targets: IdTargets,
+    readback: vk::Buffer, // One texel, host visible
+    readback_alloc: Allocation,
+    command_pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer, // Records the readback copies, each is waited on before the next
+    timeline: Timeline,
+    rendered: bool // Whether a frame has filled the image since it was created, it's undefined before
+}
+
+impl IdBuffer {
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, family_index: u32, frame_set_layout: vk::DescriptorSetLayout,
+                      vertex_layouts: &[VertexLayout], render_target: &RenderTarget) -> Result<IdBuffer, RendererError> {
+        fn setup_render_pass(logical_layer: &LogicalLayer, depth_format: vk::Format) -> Result<vk::RenderPass, RendererError> {
+            let attachments = [
+                vk::AttachmentDescription::default()
+                    .format(ID_FORMAT)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .load_op(vk::AttachmentLoadOp::CLEAR) // To 0, no object
+                    .store_op(vk::AttachmentStoreOp::STORE) // Read back
+                    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED)
+                    .final_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL),
+                vk::AttachmentDescription::default()
+                    .format(depth_format)
+                    .samples(vk::SampleCountFlags::TYPE_1)
+                    .load_op(vk::AttachmentLoadOp::CLEAR)
+                    .store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                    .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                    .initial_layout(vk::ImageLayout::UNDEFINED)
+                    .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+            ];
+
+            let color_refs = [vk::AttachmentReference::default()
+                .attachment(0)
+                .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+            let depth_ref = vk::AttachmentReference::default()
+                .attachment(1)
+                .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+            let subpasses = [vk::SubpassDescription::default()
+                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                .color_attachments(&color_refs)
+                .depth_stencil_attachment(&depth_ref)];
+
+            let dependencies = [
+                // Every frame in flight renders into the same images, so the previous frame's pass and any
+                // readback copy of it have to finish first
+                vk::SubpassDependency::default()
+                    .src_subpass(vk::SUBPASS_EXTERNAL)
+                    .dst_subpass(0)
+                    .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS |
+                        vk::PipelineStageFlags::TRANSFER)
+                    .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+                    .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ |
+                        vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE),
+                // Readback copies are submitted after the frame, and see its IDs
+                vk::SubpassDependency::default()
+                    .src_subpass(0)
+                    .dst_subpass(vk::SUBPASS_EXTERNAL)
+                    .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+                    .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags::TRANSFER)
+                    .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+            ];
+
+            let create_info = vk::RenderPassCreateInfo::default()
+                .attachments(&attachments)
+                .subpasses(&subpasses)
+                .dependencies(&dependencies);
+
+            unsafe {
+                logical_layer.logical_device.create_render_pass(&create_info, None).map_err(vk_error("vkCreateRenderPass"))
+            }
+        }
+
+        fn setup_pipeline(logical_layer: &LogicalLayer, render_pass: vk::RenderPass, frame_set_layout: vk::DescriptorSetLayout,
+                          vertex_layouts: &[VertexLayout]) -> Result<(vk::PipelineLayout, vk::Pipeline), RendererError> {
+            let vertex = compile(&ShaderSource::GlslFile(PathBuf::from(ID_VERTEX_SHADER)), ShaderStage::Vertex)?;
+            let fragment = compile(&ShaderSource::GlslFile(PathBuf::from(ID_FRAGMENT_SHADER)), ShaderStage::Fragment)?;
+            let create_module = |code: &[u32]| {
+                let module_create_info = vk::ShaderModuleCreateInfo::default()
+                    .code(code);
+                unsafe {
+                    logical_layer.logical_device.create_shader_module(&module_create_info, None)
+                        .map_err(vk_error("vkCreateShaderModule"))
+                }
+            };
+            let vertex_module = create_module(&vertex.code)?;
+            let fragment_module = match create_module(&fragment.code) {
+                Ok(m) => m,
+                Err(e) => {
+                    unsafe { logical_layer.logical_device.destroy_shader_module(vertex_module, None) };
+                    return Err(e);
+                }
+            };
+            let destroy_modules = || unsafe {
+                logical_layer.logical_device.destroy_shader_module(vertex_module, None);
+                logical_layer.logical_device.destroy_shader_module(fragment_module, None);
+            };
+            let stages = [
+                vk::PipelineShaderStageCreateInfo::default()
+                    .name(vertex.entry_point.as_c_str())
+                    .stage(vk::ShaderStageFlags::VERTEX)
+                    .module(vertex_module),
+                vk::PipelineShaderStageCreateInfo::default()
+                    .name(fragment.entry_point.as_c_str())
+                    .stage(vk::ShaderStageFlags::FRAGMENT)
+                    .module(fragment_module)
+            ];
+
+            let vertex_binding_descriptions: Vec<vk::VertexInputBindingDescription> = vertex_layouts
+                .iter()
+                .enumerate()
+                .map(|(i, l)| l.binding_description(i as u32))
+                .collect();
+            let vertex_attribute_descriptions: Vec<vk::VertexInputAttributeDescription> = vertex_layouts
+                .iter()
+                .enumerate()
+                .flat_map(|(i, l)| l.attribute_descriptions(i as u32))
+                .collect();
+            let vertex_inputs = vk::PipelineVertexInputStateCreateInfo::default()
+                .vertex_attribute_descriptions(&vertex_attribute_descriptions)
+                .vertex_binding_descriptions(&vertex_binding_descriptions);
+
+            let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::default()
+                .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+            let viewport_state = vk::PipelineViewportStateCreateInfo::default()
+                .viewport_count(1)
+                .scissor_count(1);
+
+            let rasterization_state = vk::PipelineRasterizationStateCreateInfo::default()
+                .polygon_mode(vk::PolygonMode::FILL)
+                .line_width(1.0)
+                .cull_mode(vk::CullModeFlags::NONE) // Single sided geometry seen from behind can still be picked
+                .front_face(vk::FrontFace::COUNTER_CLOCKWISE);
+
+            let multisample_state = vk::PipelineMultisampleStateCreateInfo::default()
+                .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+            let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::default()
+                .depth_test_enable(true)
+                .depth_write_enable(true)
+                .depth_compare_op(vk::CompareOp::LESS);
+
+            let blend_attachments = [vk::PipelineColorBlendAttachmentState::default()
+                .blend_enable(false) // Integer formats can't blend
+                .color_write_mask(vk::ColorComponentFlags::R)];
+            let color_blend_state = vk::PipelineColorBlendStateCreateInfo::default()
+                .attachments(&blend_attachments);
+
+            let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+            let dynamic_state = vk::PipelineDynamicStateCreateInfo::default()
+                .dynamic_states(&dynamic_states);
+
+            let push_constant_ranges = [vk::PushConstantRange::default()
+                .stage_flags(vk::ShaderStageFlags::VERTEX)
+                .offset(0)
+                .size((mem::size_of::<Mat4>() + mem::size_of::<u32>()) as u32)]; // Model matrix then the ID
+            let set_layouts = [frame_set_layout]; // For the camera matrices
+            let layout_create_info = vk::PipelineLayoutCreateInfo::default()
+                .set_layouts(&set_layouts)
+                .push_constant_ranges(&push_constant_ranges);
+            let pipeline_layout = match unsafe { logical_layer.logical_device.create_pipeline_layout(&layout_create_info, None) } {
+                Ok(l) => l,
+                Err(e) => {
+                    destroy_modules();
+                    return Err(vk_error("vkCreatePipelineLayout")(e));
+                }
+            };
+
+            let pipeline_info = vk::GraphicsPipelineCreateInfo::default()
+                .stages(&stages)
+                .vertex_input_state(&vertex_inputs)
+                .input_assembly_state(&input_assembly)
+                .viewport_state(&viewport_state)
+                .rasterization_state(&rasterization_state)
+                .multisample_state(&multisample_state)
+                .depth_stencil_state(&depth_stencil_state)
+                .color_blend_state(&color_blend_state)
+                .dynamic_state(&dynamic_state)
+                .layout(pipeline_layout)
+                .render_pass(render_pass)
+                .subpass(0);
+            let result = unsafe {
+                logical_layer.logical_device.create_graphics_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
+            };
+            destroy_modules();
+
+            match result {
+                Ok(pipelines) => Ok((pipeline_layout, pipelines[0])),
+                Err((_, e)) => {
+                    unsafe { logical_layer.logical_device.destroy_pipeline_layout(pipeline_layout, None) };
+                    Err(vk_error("vkCreateGraphicsPipelines")(e))
+                }
+            }
+        }
+
+        fn setup_commands(logical_layer: &LogicalLayer, family_index: u32) -> Result<(vk::CommandPool, vk::CommandBuffer), RendererError> {
+            let pool_create_info = vk::CommandPoolCreateInfo::default()
+                .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+                .queue_family_index(family_index);
+            let pool = unsafe {
+                logical_layer.logical_device.create_command_pool(&pool_create_info, None).map_err(vk_error("vkCreateCommandPool"))?
+            };
+            let alloc_info = vk::CommandBufferAllocateInfo::default()
+                .command_pool(pool)
+                .level(vk::CommandBufferLevel::PRIMARY)
+                .command_buffer_count(1);
+            match unsafe { logical_layer.logical_device.allocate_command_buffers(&alloc_info) } {
+                Ok(buffers) => Ok((pool, buffers[0])),
+                Err(e) => {
+                    unsafe { logical_layer.logical_device.destroy_command_pool(pool, None) };
+                    Err(vk_error("vkAllocateCommandBuffers")(e))
+                }
+            }
+        }
+
+        // Each step tears down the ones before it when it fails
+        let depth_format = render_target.depth_format;
+        let render_pass = setup_render_pass(logical_layer, depth_format)?;
+        let destroy_render_pass = || unsafe { logical_layer.logical_device.destroy_render_pass(render_pass, None) };
+        let (pipeline_layout, pipeline) = match setup_pipeline(logical_layer, render_pass, frame_set_layout, vertex_layouts) {
+            Ok(p) => p,
+            Err(e) => {
+                destroy_render_pass();
+                return Err(e);
+            }
+        };
+        let destroy_pipeline = || unsafe {
+            logical_layer.logical_device.destroy_pipeline(pipeline, None);
+            logical_layer.logical_device.destroy_pipeline_layout(pipeline_layout, None);
+            destroy_render_pass();
+        };
+        let targets = match IdTargets::new(logical_layer, allocator, render_pass, depth_format, render_target.scene_extent) {
+            Ok(t) => t,
+            Err(e) => {
+                destroy_pipeline();
+                return Err(e);
+            }
+        };
+        let (readback_alloc, readback) = match allocator.create_buffer(logical_layer, mem::size_of::<u32>() as vk::DeviceSize,
+                                                                       vk::BufferUsageFlags::TRANSFER_DST,
+                                                                       vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                                                           vk::MemoryPropertyFlags::HOST_COHERENT, // No explicit invalidates needed
+                                                                       MemoryCategory::Staging) {
+            Ok(b) => b,
+            Err(e) => {
+                targets.destroy(logical_layer, allocator);
+                destroy_pipeline();
+                return Err(e);
+            }
+        };
+        let destroy_targets = || {
+            allocator.destroy_buffer(logical_layer, readback, &readback_alloc);
+            targets.destroy(logical_layer, allocator);
+            destroy_pipeline();
+        };
+        let (command_pool, command_buffer) = match setup_commands(logical_layer, family_index) {
+            Ok(c) => c,
+            Err(e) => {
+                destroy_targets();
+                return Err(e);
+            }
+        };
+        let timeline = match Timeline::new(logical_layer) {
+            Ok(t) => t,
+            Err(e) => {
+                unsafe { logical_layer.logical_device.destroy_command_pool(command_pool, None) };
+                destroy_targets();
+                return Err(e);
+            }
+        };
+
+        Ok(IdBuffer {
+            render_pass,
+            pipeline_layout,
+            pipeline,
+            depth_format,
+            targets,
+            readback,
+            readback_alloc,
+            command_pool,
+            command_buffer,
+            timeline,
+            rendered: false
+        })
+    }
+
+    // Follows the render target's size. The GPU must be idle.
+    pub(crate) fn resize(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, render_target: &RenderTarget) -> Result<(), RendererError> {
+        let targets = IdTargets::new(logical_layer, allocator, self.render_pass, self.depth_format, render_target.scene_extent)?;
+        self.targets.destroy(logical_layer, allocator);
+        self.targets = targets;
+        self.rendered = false;
+
+        Ok(())
+    }
+
+    // Starts rendering IDs with set 0 bound. Draws then only need push and the mesh's buffers, with the
+    // instance buffer still bound to binding 1.
+    pub(crate) fn begin(&mut self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame_set: vk::DescriptorSet) {
+        let extent = self.targets.extent;
+        let clear_values = [
+            vk::ClearValue {
+                color: vk::ClearColorValue { uint32: [0; 4] }
+            },
+            vk::ClearValue {
+                depth_stencil: vk::ClearDepthStencilValue {
+                    depth: 1.0,
+                    stencil: 0
+                }
+            }
+        ];
+        let render_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(self.render_pass)
+            .framebuffer(self.targets.framebuffer)
+            .render_area(vk::Rect2D::default().extent(extent))
+            .clear_values(&clear_values);
+        let viewports = [vk::Viewport::default()
+            .width(extent.width as f32)
+            .height(extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)];
+        let scissors = [vk::Rect2D::default().extent(extent)];
+        let descriptor_sets = [frame_set];
+
+        unsafe {
+            let device = &logical_layer.logical_device;
+            device.cmd_begin_render_pass(command_buffer, &render_pass_info, vk::SubpassContents::INLINE);
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline_layout,
+                                            0, &descriptor_sets, &[]);
+            device.cmd_set_viewport(command_buffer, 0, &viewports);
+            device.cmd_set_scissor(command_buffer, 0, &scissors);
+        }
+        self.rendered = true;
+    }
+
+    // The model matrix and ID of the next draw. Instanced draws push 0 to write each instance's Instance::id.
+    pub(crate) fn push(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, model: &Mat4, id: u32) {
+        unsafe {
+            let device = &logical_layer.logical_device;
+            device.cmd_push_constants(command_buffer, self.pipeline_layout, vk::ShaderStageFlags::VERTEX, 0, bytemuck::bytes_of(model));
+            device.cmd_push_constants(command_buffer, self.pipeline_layout, vk::ShaderStageFlags::VERTEX,
+                                      mem::size_of::<Mat4>() as u32, bytemuck::bytes_of(&id));
+        }
+    }
+
+    pub(crate) fn end(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer) {
+        unsafe { logical_layer.logical_device.cmd_end_render_pass(command_buffer) };
+    }
+
+    // The ID at a pixel of the last rendered frame, which the caller has waited on. Copies the texel into the
+    // readback buffer and blocks until it's there. None outside the image, before any frame or where nothing
+    // with an ID was drawn.
+    pub(crate) fn read(&mut self, logical_layer: &LogicalLayer, x: u32, y: u32) -> Result<Option<u32>, RendererError> {
+        if !self.rendered || x >= self.targets.extent.width || y >= self.targets.extent.height {
+            return Ok(None);
+        }
+
+        let region = [vk::BufferImageCopy::default()
+            .buffer_offset(0)
+            .buffer_row_length(0)
+            .buffer_image_height(0)
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1
+            })
+            .image_offset(vk::Offset3D { x: x as i32, y: y as i32, z: 0 })
+            .image_extent(vk::Extent3D { width: 1, height: 1, depth: 1 })];
+        let begin_info = vk::CommandBufferBeginInfo::default()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe {
+            let device = &logical_layer.logical_device;
+            device.reset_command_buffer(self.command_buffer, vk::CommandBufferResetFlags::empty())
+                .map_err(vk_error("vkResetCommandBuffer"))?;
+            device.begin_command_buffer(self.command_buffer, &begin_info).map_err(vk_error("vkBeginCommandBuffer"))?;
+            // The render pass left the image in TRANSFER_SRC_OPTIMAL and made its writes available to transfers
+            device.cmd_copy_image_to_buffer(self.command_buffer, self.targets.image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                                            self.readback, &region);
+        }
+        Barriers::new()
+            .buffer(self.readback, Usage::TransferWrite, Usage::HostRead, Ownership::Keep)
+            .record(logical_layer, self.command_buffer);
+
+        let value = self.timeline.next();
+        let command_buffers = [self.command_buffer];
+        let semaphores = [self.timeline.semaphore];
+        let values = [value];
+        let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::default()
+            .signal_semaphore_values(&values);
+        let submit_info = vk::SubmitInfo::default()
+            .command_buffers(&command_buffers)
+            .signal_semaphores(&semaphores)
+            .push_next(&mut timeline_info);
+        unsafe {
+            let device = &logical_layer.logical_device;
+            device.end_command_buffer(self.command_buffer).map_err(vk_error("vkEndCommandBuffer"))?;
+            device.queue_submit(logical_layer.logical_queue, &[submit_info], vk::Fence::null()).map_err(vk_error("vkQueueSubmit"))?;
+        }
+        self.timeline.wait(logical_layer, value)?;
+
+        let ptr = self.readback_alloc.mapped_ptr().expect("Readback buffer isn't mapped");
+        let id = unsafe { (ptr as *const u32).read_unaligned() };
+        Ok(match id {
+            0 => None,
+            id => Some(id)
+        })
+    }
+
+    // The GPU must be idle
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        self.targets.destroy(logical_layer, allocator);
+        allocator.destroy_buffer(logical_layer, self.readback, &self.readback_alloc);
+        self.timeline.destroy(logical_layer);
+        unsafe {
+            logical_layer.logical_device.destroy_command_pool(self.command_pool, None); // Frees the command buffer as well
+            logical_layer.logical_device.destroy_pipeline(self.pipeline, None);
+            logical_layer.logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+            logical_layer.logical_device.destroy_render_pass(self.render_pass, None);
+        }
+    }
+}
@@ -1,73 +1,68 @@
 use std::mem;
 
 use ash::vk;
-use crate::renderer::core::Core;
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::error::RendererError;
 use crate::renderer::logical_layer::LogicalLayer;
-use crate::renderer::physical_layer::PhysicalLayer;
-use crate::renderer::staging_buf::*;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::ray_tracing::input_usage;
+use crate::renderer::staging_buf::UploadContext;
 
 pub(crate) struct IndexBuffer {
     pub(crate) buf: vk::Buffer,
-    dev_mem: vk::DeviceMemory,
+    alloc: Allocation,
     data_size: vk::DeviceSize,
-    pub(crate) index_count: u32
-}
-
-#[repr(C)]
-#[derive(Clone, Debug, Copy)]
-pub(crate) struct Index {
-    pub data: [u16; 6]
+    pub(crate) index_count: u32,
+    pub(crate) index_type: vk::IndexType
 }
 
 impl IndexBuffer {
-    pub(crate) fn new(core: &Core, physical_layer: &PhysicalLayer, logical_layer: &LogicalLayer, cmd_pool: vk::CommandPool, indices: &Index) -> IndexBuffer {
-        let data_size: vk::DeviceSize = mem::size_of_val(&indices.data) as vk::DeviceSize;
-        let index_count = indices.data.len();
-
-        let (transfer_mem, transfer_buffer) = create_buffer(core, physical_layer, logical_layer, data_size, vk::BufferUsageFlags::TRANSFER_SRC,
-                      vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT).unwrap();
-
-        unsafe {
-            let dev_memory = logical_layer.logical_device
-                .map_memory(transfer_mem,
-                            0,
-                            data_size,
-                            vk::MemoryMapFlags::empty())
-                .unwrap() as *mut u16;
-            dev_memory.copy_from_nonoverlapping(indices.data.as_ptr(), index_count);
-            logical_layer.logical_device.unmap_memory(transfer_mem);
-        }
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                      indices: &[u32], vertex_count: usize) -> Result<IndexBuffer, RendererError> {
+        // 16 bit indices halve the memory and bandwidth whenever every vertex is reachable with them
+        let index_type = match vertex_count <= u16::MAX as usize + 1 {
+            true => vk::IndexType::UINT16,
+            false => vk::IndexType::UINT32
+        };
+        let indices_u16: Vec<u16> = match index_type {
+            vk::IndexType::UINT16 => indices.iter().map(|i| *i as u16).collect(),
+            _ => Vec::new()
+        };
 
-        let (dev_mem, buf) = create_buffer(core,
-                                           physical_layer,
-                                           logical_layer,
-                                           data_size,
-                                           vk::BufferUsageFlags::INDEX_BUFFER | // Used by the vertex shader stage
-                                               vk::BufferUsageFlags::TRANSFER_DST, // Can be a destination for transfer commands
-                                           vk::MemoryPropertyFlags::DEVICE_LOCAL) // Local to GPU
-            .expect("Failed to locate suitable device memory");
+        let data_size: vk::DeviceSize = match index_type {
+            vk::IndexType::UINT16 => mem::size_of_val(indices_u16.as_slice()),
+            _ => mem::size_of_val(indices)
+        } as vk::DeviceSize;
+        let index_count = indices.len();
 
-        copy_buffer(logical_layer, cmd_pool, transfer_buffer, buf, data_size);
+        let (alloc, buf) = allocator.create_buffer(logical_layer,
+                                                   data_size,
+                                                   vk::BufferUsageFlags::INDEX_BUFFER | // Used by the vertex shader stage
+                                                       vk::BufferUsageFlags::TRANSFER_DST | // Can be a destination for transfer commands
+                                                       input_usage(logical_layer), // Built into acceleration structures with ray tracing on
+                                                   vk::MemoryPropertyFlags::DEVICE_LOCAL, // Local to GPU
+                                                   MemoryCategory::Meshes)?;
 
-        let ibuf = IndexBuffer {
-            buf,
-            dev_mem,
-            data_size,
-            index_count: index_count as u32
+        // The copy is only recorded here, the contents are valid once the upload context is flushed
+        let uploaded = match index_type {
+            vk::IndexType::UINT16 => upload.upload_buffer(logical_layer, allocator, &indices_u16, buf, 0),
+            _ => upload.upload_buffer(logical_layer, allocator, indices, buf, 0)
         };
-
-        unsafe {
-            logical_layer.logical_device.destroy_buffer(transfer_buffer, None);
-            logical_layer.logical_device.free_memory(transfer_mem, None);
+        if let Err(e) = uploaded {
+            allocator.destroy_buffer(logical_layer, buf, &alloc);
+            return Err(e);
         }
 
-        ibuf
+        Ok(IndexBuffer {
+            buf,
+            alloc,
+            data_size,
+            index_count: index_count as u32,
+            index_type
+        })
     }
 
-    pub fn destroy(&self, logical_layer: &LogicalLayer) {
-        unsafe {
-            logical_layer.logical_device.destroy_buffer(self.buf, None);
-            logical_layer.logical_device.free_memory(self.dev_mem, None);
-        }
+    pub fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        allocator.destroy_buffer(logical_layer, self.buf, &self.alloc);
     }
-}
\ No newline at end of file
+}
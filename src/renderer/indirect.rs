@@ -0,0 +1,321 @@
+use std::mem;
+use std::path::PathBuf;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use glam::Vec3;
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::barrier::{Barriers, Usage};
+use crate::renderer::compute_pipeline::ComputePipeline;
+use crate::renderer::deletion_queue::{Deletion, DeletionQueue};
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::frustum::{Aabb, Frustum};
+use crate::renderer::instance::Instance;
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::render_queue::MaterialHandle;
+use crate::renderer::shader::{ShaderError, ShaderSource};
+use crate::renderer::staging_buf::UploadContext;
+use crate::renderer::vertex::Vertex;
+
+const WORKGROUP_SIZE: u32 = 64; // local_size_x of the cull shader
+const COMMAND_SIZE: u32 = mem::size_of::<vk::DrawIndexedIndirectCommand>() as u32;
+
+// Buffers, in order, VERTICES to MESHES are shared then each frame slot has FRAME_BUFFERS of its own
+const VERTICES: usize = 0;
+const INDICES: usize = 1;
+const MESHES: usize = 2;
+const OBJECTS: usize = 0; // Offsets within a frame slot's buffers
+const COMMANDS: usize = 1;
+const INSTANCES: usize = 2;
+const COUNTER: usize = 3;
+const FRAME_BUFFERS: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IndirectBatchHandle(pub(crate) usize); // Index into the renderer's batch list
+
+// One object of an indirect batch
+#[derive(Clone, Copy, Debug)]
+pub struct IndirectObject {
+    pub mesh: u32, // Index into the meshes the batch was created with
+    pub instance: Instance // Transform, color and id, as with instanced draws
+}
+
+// std430, as read by the cull shader
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct GpuMesh {
+    range: [u32; 4], // First index, index count, vertex offset, unused
+    sphere: [f32; 4] // Model space bounding sphere, radius in W
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct GpuObject {
+    transform: [[f32; 4]; 4],
+    color: [f32; 4],
+    ids: [u32; 4] // Mesh index then the instance id
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct CullConstants {
+    planes: [[f32; 4]; 6],
+    object_count: u32,
+    padding: [u32; 3]
+}
+
+// The compute pipeline every batch is culled with
+pub(crate) fn create_cull_pipeline(logical_layer: &LogicalLayer) -> Result<ComputePipeline, ShaderError> {
+    ComputePipeline::new(logical_layer,
+                         &ShaderSource::GlslFile(PathBuf::from("shaders/src/indirect_cull.comp")),
+                         5, // Meshes, objects, commands, instances and the counter
+                         mem::size_of::<CullConstants>() as u32)
+}
+
+// Objects drawn from a fixed set of meshes with one material, in a single indirect draw. The meshes share
+// a vertex and index buffer. Each frame a compute shader frustum culls the objects and packs a draw command
+// and an instance for every visible one, so the CPU cost doesn't grow with the object count.
+pub(crate) struct IndirectBatch {
+    pub(crate) material: MaterialHandle,
+    capacity: u32,
+    mesh_count: u32,
+    buffers: Vec<(vk::Buffer, Allocation)>, // See VERTICES
+    pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>, // Per frame slot
+    objects: Vec<GpuObject>,
+    stale: Vec<bool>, // Slots whose object buffer doesn't hold the current objects yet
+    culled: Vec<u32>, // Objects each slot's last frame culled, 0 if the batch wasn't drawn
+    visible: u32 // Survivors of the latest culling read back
+}
+
+impl IndirectBatch {
+    // Room for capacity objects. meshes are vertices and indices, indices relative to their own vertices.
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                      cull_set_layout: vk::DescriptorSetLayout, frame_count: usize, material: MaterialHandle,
+                      meshes: &[(&[Vertex], &[u32])], capacity: u32) -> Result<IndirectBatch, RendererError> {
+        assert!(!meshes.is_empty() && capacity > 0, "An indirect batch needs at least one mesh and object");
+
+        let mut batch = IndirectBatch {
+            material,
+            capacity,
+            mesh_count: meshes.len() as u32,
+            buffers: Vec::with_capacity(MESHES + 1 + FRAME_BUFFERS * frame_count),
+            pool: vk::DescriptorPool::null(),
+            sets: Vec::new(),
+            objects: Vec::new(),
+            stale: vec![true; frame_count],
+            culled: vec![0; frame_count],
+            visible: 0
+        };
+        if let Err(e) = batch.setup(logical_layer, allocator, upload, cull_set_layout, frame_count, meshes) {
+            batch.destroy(logical_layer, allocator);
+            return Err(e);
+        }
+
+        Ok(batch)
+    }
+
+    fn setup(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+             cull_set_layout: vk::DescriptorSetLayout, frame_count: usize,
+             meshes: &[(&[Vertex], &[u32])]) -> Result<(), RendererError> {
+        let mut vertices: Vec<Vertex> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+        let mut gpu_meshes: Vec<GpuMesh> = Vec::with_capacity(meshes.len());
+        for (v, i) in meshes.iter() {
+            let (center, radius) = Aabb::from_points(v.iter().map(|v| Vec3::from(v.pos))).bounding_sphere();
+            gpu_meshes.push(GpuMesh {
+                range: [indices.len() as u32, i.len() as u32, vertices.len() as u32, 0],
+                sphere: center.extend(radius).to_array()
+            });
+            vertices.extend_from_slice(v);
+            indices.extend_from_slice(i);
+        }
+
+        let device_local = vk::MemoryPropertyFlags::DEVICE_LOCAL;
+        let host_visible = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+        let shared = [
+            (mem::size_of_val(vertices.as_slice()), vk::BufferUsageFlags::VERTEX_BUFFER),
+            (mem::size_of_val(indices.as_slice()), vk::BufferUsageFlags::INDEX_BUFFER),
+            (mem::size_of_val(gpu_meshes.as_slice()), vk::BufferUsageFlags::STORAGE_BUFFER)
+        ];
+        for (size, usage) in shared {
+            self.buffers.push(allocator.create_buffer(logical_layer, size.max(4) as vk::DeviceSize,
+                                                      usage | vk::BufferUsageFlags::TRANSFER_DST, device_local,
+                                                      MemoryCategory::Meshes)?);
+        }
+        upload.upload_buffer(logical_layer, allocator, &vertices, self.buffers[VERTICES].0, 0)?;
+        upload.upload_buffer(logical_layer, allocator, &indices, self.buffers[INDICES].0, 0)?;
+        upload.upload_buffer(logical_layer, allocator, &gpu_meshes, self.buffers[MESHES].0, 0)?;
+
+        let capacity = self.capacity as vk::DeviceSize;
+        let per_frame = [
+            (capacity * mem::size_of::<GpuObject>() as vk::DeviceSize, vk::BufferUsageFlags::STORAGE_BUFFER, host_visible),
+            (capacity * COMMAND_SIZE as vk::DeviceSize,
+             vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::INDIRECT_BUFFER | vk::BufferUsageFlags::TRANSFER_DST,
+             device_local),
+            (capacity * mem::size_of::<Instance>() as vk::DeviceSize,
+             vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::VERTEX_BUFFER,
+             device_local),
+            (mem::size_of::<u32>() as vk::DeviceSize, vk::BufferUsageFlags::STORAGE_BUFFER, host_visible)
+        ];
+        for _ in 0..frame_count {
+            for (size, usage, props) in per_frame {
+                self.buffers.push(allocator.create_buffer(logical_layer, size, usage, props, MemoryCategory::Buffers)?);
+            }
+        }
+
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(5 * frame_count as u32)];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(frame_count as u32);
+        self.pool = unsafe {
+            logical_layer.logical_device.create_descriptor_pool(&pool_create_info, None)
+                .map_err(vk_error("vkCreateDescriptorPool"))?
+        };
+
+        let layouts = vec![cull_set_layout; frame_count];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(self.pool)
+            .set_layouts(&layouts);
+        self.sets = unsafe {
+            logical_layer.logical_device.allocate_descriptor_sets(&alloc_info).map_err(vk_error("vkAllocateDescriptorSets"))?
+        };
+
+        for (frame, set) in self.sets.iter().enumerate() {
+            let bound = [MESHES, self.frame_buffer(frame, OBJECTS), self.frame_buffer(frame, COMMANDS),
+                self.frame_buffer(frame, INSTANCES), self.frame_buffer(frame, COUNTER)];
+            let infos: Vec<[vk::DescriptorBufferInfo; 1]> = bound.iter()
+                .map(|&b| [vk::DescriptorBufferInfo::default()
+                    .buffer(self.buffers[b].0)
+                    .offset(0)
+                    .range(vk::WHOLE_SIZE)])
+                .collect();
+            let writes: Vec<vk::WriteDescriptorSet> = infos.iter()
+                .enumerate()
+                .map(|(binding, info)| vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(binding as u32)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(info))
+                .collect();
+            unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+        }
+
+        Ok(())
+    }
+
+    // Index into buffers of one of a frame slot's buffers
+    fn frame_buffer(&self, frame: usize, buffer: usize) -> usize {
+        MESHES + 1 + frame * FRAME_BUFFERS + buffer
+    }
+
+    pub(crate) fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    // Objects that passed the GPU's culling the last time a frame slot finished drawing the batch
+    pub(crate) fn visible(&self) -> u32 {
+        self.visible
+    }
+
+    // Replaces every object, used from the next recorded frame on
+    pub(crate) fn set_objects(&mut self, objects: &[IndirectObject]) {
+        assert!(objects.len() <= self.capacity as usize, "More objects than the indirect batch has room for");
+        assert!(objects.iter().all(|o| o.mesh < self.mesh_count), "Indirect object mesh out of range");
+
+        self.objects = objects.iter()
+            .map(|o| GpuObject {
+                transform: o.instance.transform,
+                color: o.instance.color,
+                ids: [o.mesh, o.instance.id, 0, 0]
+            })
+            .collect();
+        for s in self.stale.iter_mut() {
+            *s = true;
+        }
+    }
+
+    // Reads back how many objects the slot's previous frame drew, then readies the slot for this frame:
+    // copies the objects if they changed and zeroes the counter. The slot's timeline value must have been waited on.
+    pub(crate) fn write(&mut self, frame: usize, drawn: bool) {
+        let counter = self.buffers[self.frame_buffer(frame, COUNTER)].1.mapped_ptr().unwrap() as *mut u32; // Mapped for as long as the allocation lives
+        if self.culled[frame] > 0 {
+            self.visible = unsafe { counter.read_unaligned() };
+        }
+        unsafe { counter.write_unaligned(0) };
+
+        if self.stale[frame] {
+            let objects = self.buffers[self.frame_buffer(frame, OBJECTS)].1.mapped_ptr().unwrap() as *mut GpuObject;
+            unsafe { objects.copy_from_nonoverlapping(self.objects.as_ptr(), self.objects.len()) };
+            self.stale[frame] = false;
+        }
+        self.culled[frame] = match drawn {
+            true => self.objects.len() as u32,
+            false => 0
+        };
+    }
+
+    // Fills the slot's draw commands and instances with the objects inside the frustum. Must be recorded
+    // outside of a render pass, after write.
+    pub(crate) fn record_cull(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame: usize,
+                              pipeline: &ComputePipeline, frustum: &Frustum) {
+        let count = self.culled[frame];
+        if count == 0 {
+            return;
+        }
+        let device = &logical_layer.logical_device;
+        let commands = self.buffers[self.frame_buffer(frame, COMMANDS)].0;
+        let constants = CullConstants {
+            planes: frustum.planes().map(|p| p.to_array()),
+            object_count: count,
+            padding: [0; 3]
+        };
+
+        // Zero instance counts for the draws past the visible ones
+        unsafe { device.cmd_fill_buffer(command_buffer, commands, 0, (count * COMMAND_SIZE) as vk::DeviceSize, 0) };
+        Barriers::new()
+            .memory(Usage::TransferWrite, Usage::ComputeWrite)
+            .record(logical_layer, command_buffer);
+        pipeline.dispatch(logical_layer, command_buffer, self.sets[frame], bytemuck::bytes_of(&constants),
+                          [(count + WORKGROUP_SIZE - 1) / WORKGROUP_SIZE, 1, 1]);
+        Barriers::new()
+            .memory(Usage::ComputeWrite, Usage::IndirectDraw)
+            .record(logical_layer, command_buffer);
+    }
+
+    // Binds the batch's vertices, instances and indices and draws what record_cull left. The material's
+    // pipeline and push constants must be bound already, and binding 1 rebound afterwards.
+    pub(crate) fn record_draw(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame: usize) {
+        let count = self.culled[frame];
+        if count == 0 {
+            return;
+        }
+        let device = &logical_layer.logical_device;
+        let vertex_buffers = [self.buffers[VERTICES].0, self.buffers[self.frame_buffer(frame, INSTANCES)].0];
+
+        unsafe {
+            device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &[0, 0]);
+            device.cmd_bind_index_buffer(command_buffer, self.buffers[INDICES].0, 0, vk::IndexType::UINT32);
+            device.cmd_draw_indexed_indirect(command_buffer, self.buffers[self.frame_buffer(frame, COMMANDS)].0, 0, count,
+                                             COMMAND_SIZE);
+        }
+    }
+
+    pub(crate) fn retire(self, deletions: &mut DeletionQueue, frame: usize) {
+        for (buf, alloc) in self.buffers {
+            deletions.push(frame, Deletion::Buffer(buf, alloc));
+        }
+        deletions.push(frame, Deletion::DescriptorPool(self.pool));
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        unsafe { logical_layer.logical_device.destroy_descriptor_pool(self.pool, None) }; // Frees the sets, null is ignored
+        for (buf, alloc) in self.buffers.iter() {
+            allocator.destroy_buffer(logical_layer, *buf, alloc);
+        }
+    }
+}
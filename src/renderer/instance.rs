@@ -0,0 +1,137 @@
+use memoffset::offset_of;
+use std::mem;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use glam::{Mat4, Vec4};
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::error::RendererError;
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::vertex::{VertexAttribute, VertexFormat, VertexLayout};
+
+pub(crate) const BASE_INSTANCE: u32 = 1; // Slot 0 holds the identity instance used by non instanced draws
+const INITIAL_CAPACITY: usize = 1024;
+
+// Per instance data, read by the vertex shader from the second vertex binding
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub struct Instance {
+    pub transform: [[f32; 4]; 4], // Column major, applied after the draw's model matrix
+    pub color: [f32; 4], // Multiplies the vertex color
+    pub id: u32 // Free for the application, I.E. an entity ID for picking
+}
+
+impl Instance {
+    pub fn new(transform: Mat4, color: Vec4, id: u32) -> Instance {
+        Instance {
+            transform: transform.to_cols_array_2d(),
+            color: color.to_array(),
+            id
+        }
+    }
+}
+
+impl Default for Instance {
+    fn default() -> Self {
+        Instance::new(Mat4::IDENTITY, Vec4::ONE, 0)
+    }
+}
+
+impl VertexFormat for Instance {
+    fn layout() -> VertexLayout {
+        let transform = offset_of!(Instance, transform) as u32;
+        let column = mem::size_of::<[f32; 4]>() as u32;
+
+        VertexLayout {
+            stride: mem::size_of::<Instance>() as u32,
+            per_instance: true,
+            attributes: vec![
+                // A mat4 input takes one location per column
+                VertexAttribute { location: 5, format: vk::Format::R32G32B32A32_SFLOAT, offset: transform },
+                VertexAttribute { location: 6, format: vk::Format::R32G32B32A32_SFLOAT, offset: transform + column },
+                VertexAttribute { location: 7, format: vk::Format::R32G32B32A32_SFLOAT, offset: transform + column * 2 },
+                VertexAttribute { location: 8, format: vk::Format::R32G32B32A32_SFLOAT, offset: transform + column * 3 },
+                VertexAttribute { location: 9, format: vk::Format::R32G32B32A32_SFLOAT, offset: offset_of!(Instance, color) as u32 },
+                VertexAttribute { location: 10, format: vk::Format::R32_UINT, offset: offset_of!(Instance, id) as u32 }
+            ]
+        }
+    }
+}
+
+// Instance data for every draw in a frame, rewritten each frame. Host visible so the CPU writes it
+// directly instead of going through the upload context.
+pub(crate) struct InstanceBuffer {
+    bufs: Vec<vk::Buffer>, // One buffer per frame in flight so the CPU never writes to a buffer the GPU is reading
+    allocs: Vec<Allocation>,
+    capacities: Vec<usize> // In instances, including the identity slot
+}
+
+impl InstanceBuffer {
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, frame_count: usize) -> Result<InstanceBuffer, RendererError> {
+        let mut instance_buffer = InstanceBuffer {
+            bufs: Vec::with_capacity(frame_count),
+            allocs: Vec::with_capacity(frame_count),
+            capacities: Vec::with_capacity(frame_count)
+        };
+
+        for _ in 0..frame_count {
+            match Self::create_buffer(logical_layer, allocator, INITIAL_CAPACITY) {
+                Ok((alloc, buf)) => {
+                    instance_buffer.bufs.push(buf);
+                    instance_buffer.allocs.push(alloc);
+                    instance_buffer.capacities.push(INITIAL_CAPACITY);
+                },
+                Err(e) => {
+                    instance_buffer.destroy(logical_layer, allocator);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(instance_buffer)
+    }
+
+    fn create_buffer(logical_layer: &LogicalLayer, allocator: &Allocator, capacity: usize) -> Result<(Allocation, vk::Buffer), RendererError> {
+        allocator.create_buffer(logical_layer,
+                                (capacity * mem::size_of::<Instance>()) as vk::DeviceSize,
+                                vk::BufferUsageFlags::VERTEX_BUFFER,
+                                vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                    vk::MemoryPropertyFlags::HOST_COHERENT, // No explicit flushes needed
+                                MemoryCategory::Buffers)
+    }
+
+    // Writes the identity instance followed by instances. The frame's previous submission must have
+    // finished, since the buffer may be replaced with a larger one.
+    pub(crate) fn update(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, frame: usize,
+                         instances: &[Instance]) -> Result<(), RendererError> {
+        let count = instances.len() + BASE_INSTANCE as usize;
+        if count > self.capacities[frame] {
+            let capacity = count.next_power_of_two();
+            let (alloc, buf) = Self::create_buffer(logical_layer, allocator, capacity)?;
+            allocator.destroy_buffer(logical_layer, self.bufs[frame], &self.allocs[frame]);
+            self.bufs[frame] = buf;
+            self.allocs[frame] = alloc;
+            self.capacities[frame] = capacity;
+        }
+
+        let ptr = self.allocs[frame].mapped_ptr().unwrap() as *mut Instance; // Mapped for as long as the allocation lives
+        unsafe {
+            ptr.write(Instance::default());
+            std::ptr::copy_nonoverlapping(instances.as_ptr(), ptr.add(BASE_INSTANCE as usize), instances.len());
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn buf(&self, frame: usize) -> vk::Buffer {
+        self.bufs[frame]
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        for (buf, alloc) in self.bufs.iter().zip(self.allocs.iter()) {
+            allocator.destroy_buffer(logical_layer, *buf, alloc);
+        }
+    }
+}
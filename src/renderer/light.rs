@@ -0,0 +1,54 @@
+use bytemuck::{Pod, Zeroable};
+use glam::{Vec3, Vec4};
+
+pub const MAX_LIGHTS: usize = 16; // Matches MAX_LIGHTS in the lit shaders
+
+const NO_SHADOW: [f32; 4] = [-1.0, 0.0, 0.0, 0.0];
+
+#[derive(Clone, Copy, Debug)]
+pub enum Light {
+    Directional {
+        direction: Vec3, // Direction the light travels in, I.E. -Y for a sun overhead
+        color: Vec3,
+        intensity: f32,
+        cast_shadows: bool // Only the first MAX_SHADOW_CASTERS shadowed lights get a shadow map
+    },
+    Point {
+        position: Vec3,
+        color: Vec3,
+        intensity: f32,
+        range: f32 // Distance where the light fades out entirely
+    }
+}
+
+// std140 layout of one entry of the lights array in the frame uniform block
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub(crate) struct GpuLight {
+    position: [f32; 4], // W is 0 for directional lights, where xyz is the direction towards the light
+    color: [f32; 4], // Premultiplied by intensity, W is the range of point lights
+    shadow: [f32; 4] // X is the shadow map layer, negative without one
+}
+
+impl GpuLight {
+    pub(crate) fn set_shadow_layer(&mut self, layer: usize) {
+        self.shadow[0] = layer as f32;
+    }
+}
+
+impl From<&Light> for GpuLight {
+    fn from(light: &Light) -> Self {
+        match *light {
+            Light::Directional { direction, color, intensity, .. } => GpuLight {
+                position: (-direction.normalize_or_zero()).extend(0.0).to_array(),
+                color: (color * intensity).extend(0.0).to_array(),
+                shadow: NO_SHADOW
+            },
+            Light::Point { position, color, intensity, range } => GpuLight {
+                position: position.extend(1.0).to_array(),
+                color: Vec4::from((color * intensity, range)).to_array(),
+                shadow: NO_SHADOW
+            }
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use glam::Mat4;
+
+use crate::renderer::camera::Camera;
+use crate::renderer::error::RendererError;
+use crate::renderer::frustum::Aabb;
+use crate::renderer::mesh::MeshHandle;
+use crate::renderer::renderer::CubulousRenderer;
+use crate::renderer::vertex::Vertex;
+
+// What a level's threshold is measured against
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LodMetric {
+    Distance, // World units from the camera to the bounds, a level is used up to its threshold
+    ScreenCoverage // Height of the bounding sphere on screen over the screen's height, a level is used down to its threshold
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LodLevel {
+    pub mesh: MeshHandle,
+    pub threshold: f32
+}
+
+// Meshes of one object at decreasing detail. Frame::draw_lod picks one per draw from where the object is
+// relative to the camera, draws past the last level are dropped.
+#[derive(Clone, Debug)]
+pub struct LodGroup {
+    levels: Vec<LodLevel>, // Full detail first
+    metric: LodMetric,
+    bounds: Aabb, // Model space, of the first level
+    pub bias: f32 // Multiplies distances and divides coverages, above 1 switches to coarser levels sooner
+}
+
+impl LodGroup {
+    // Levels are taken in the given order, which should be from full detail down with thresholds moving
+    // away from the camera
+    pub fn new(renderer: &CubulousRenderer, metric: LodMetric, levels: Vec<LodLevel>) -> LodGroup {
+        assert!(!levels.is_empty(), "A LOD group needs at least one level");
+
+        LodGroup {
+            bounds: renderer.mesh(levels[0].mesh).bounds(),
+            levels,
+            metric,
+            bias: 1.0
+        }
+    }
+
+    // Uploads the mesh, then a simplified copy for each ratio (the fraction of its triangles to keep).
+    // thresholds has one entry per uploaded level, the full detail mesh first.
+    pub fn generate(renderer: &mut CubulousRenderer, metric: LodMetric, vertices: &[Vertex], indices: &[u32],
+                    ratios: &[f32], thresholds: &[f32]) -> Result<LodGroup, RendererError> {
+        assert_eq!(thresholds.len(), ratios.len() + 1, "Expected a threshold per level");
+
+        let mut levels: Vec<LodLevel> = Vec::with_capacity(thresholds.len());
+        let meshes = std::iter::once((vertices.to_vec(), indices.to_vec()))
+            .chain(ratios.iter().map(|&r| simplify(vertices, indices, r, SIMPLIFY_ERROR)));
+        for ((v, i), &threshold) in meshes.zip(thresholds.iter()) {
+            match renderer.upload_mesh(&v, &i) {
+                Ok(mesh) => levels.push(LodLevel { mesh, threshold }),
+                Err(e) => {
+                    for l in levels {
+                        renderer.remove_mesh(l.mesh);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(LodGroup::new(renderer, metric, levels))
+    }
+
+    pub fn levels(&self) -> &[LodLevel] {
+        &self.levels
+    }
+
+    pub fn metric(&self) -> LodMetric {
+        self.metric
+    }
+
+    // The level to draw with the model matrix, None past the last one
+    pub fn select(&self, camera: &Camera, transform: &Mat4) -> Option<MeshHandle> {
+        let bounds = self.bounds.transformed(transform);
+        let eye = camera.position();
+        let distance = eye.clamp(bounds.min, bounds.max).distance(eye) * self.bias; // 0 inside the box
+
+        let level = match self.metric {
+            LodMetric::Distance => self.levels.iter().find(|l| distance <= l.threshold),
+            LodMetric::ScreenCoverage => {
+                let (_, radius) = bounds.bounding_sphere();
+                let coverage = match distance > 0.0 {
+                    true => radius / (distance * (camera.fov_y * 0.5).tan()),
+                    false => f32::INFINITY
+                };
+                self.levels.iter().find(|l| coverage >= l.threshold)
+            }
+        };
+        level.map(|l| l.mesh)
+    }
+
+    // Releases every level's mesh
+    pub fn remove(self, renderer: &mut CubulousRenderer) {
+        for l in self.levels {
+            renderer.remove_mesh(l.mesh);
+        }
+    }
+}
+
+// Largest deviation simplify may introduce, relative to the mesh's size
+pub const SIMPLIFY_ERROR: f32 = 0.01;
+
+// Collapses edges with meshoptimizer until about ratio of the triangles are left or the error would pass
+// target_error. Returns the vertices the simplified indices still use and the indices remapped to them.
+pub fn simplify(vertices: &[Vertex], indices: &[u32], ratio: f32, target_error: f32) -> (Vec<Vertex>, Vec<u32>) {
+    let target_count = ((indices.len() / 3) as f32 * ratio.clamp(0.0, 1.0)) as usize * 3;
+    let adapter = meshopt::VertexDataAdapter::new(bytemuck::cast_slice(vertices),
+                                                  std::mem::size_of::<Vertex>(),
+                                                  0) // pos is the first field
+        .expect("Vertex data matches its stride");
+    let simplified = meshopt::simplify(indices, &adapter, target_count, target_error);
+
+    let mut remap: Vec<Option<u32>> = vec![None; vertices.len()];
+    let mut kept: Vec<Vertex> = Vec::new();
+    let indices = simplified.iter()
+        .map(|&i| *remap[i as usize].get_or_insert_with(|| {
+            kept.push(vertices[i as usize]);
+            kept.len() as u32 - 1
+        }))
+        .collect();
+
+    (kept, indices)
+}
+
@@ -1,48 +1,132 @@
 use ash::{vk, Device};
+use ash::extensions::ext::MeshShader;
+use ash::extensions::khr::AccelerationStructure;
 
 use std::ffi::{c_char, CStr, CString};
 
+use crate::renderer::capabilities::DeviceCapabilities;
 use crate::renderer::core::Core;
+use crate::renderer::error::{vk_error, RendererError};
 use crate::renderer::physical_layer::PhysicalLayer;
 use crate::renderer::render_target::RenderTarget;
 
 pub(crate) struct LogicalLayer {
-    pub(crate) logical_queue: vk::Queue,
+    pub(crate) logical_queue: vk::Queue, // Graphics
+    pub(crate) present_queue: vk::Queue, // Same as logical_queue unless presentation needs another family
+    pub(crate) transfer_queue: vk::Queue, // Same as logical_queue without a dedicated transfer family
+    pub(crate) transfer_family_index: u32,
+    pub(crate) capabilities: DeviceCapabilities, // What was requested and granted, see Barriers::record for synchronization2
+    pub(crate) acceleration_structure: Option<AccelerationStructure>, // Loaded when ray tracing is enabled
+    pub(crate) mesh_shader: Option<MeshShader>, // Loaded when mesh shading is enabled
     pub(crate) logical_device: Device
 }
 
 impl LogicalLayer {
-    pub(crate) fn new(core: &Core, physical_layer: &PhysicalLayer, required_extensions: &Vec<CString>) -> LogicalLayer {
+    // Enables each of the requested capabilities the physical layer supports, through the features' pNext
+    // structs and extensions. The rest stay off, callers check capabilities for what was granted.
+    pub(crate) fn new(core: &Core, physical_layer: &PhysicalLayer, required_extensions: &Vec<CString>,
+                      requested: DeviceCapabilities) -> Result<LogicalLayer, RendererError> {
+        let granted = requested.negotiate(physical_layer.capabilities);
+        if granted != requested {
+            log::info!("Device capabilities requested {:?}, granted {:?}", requested, granted);
+        }
+        let optional_extensions = granted.extensions();
         let extensions_cvec: Vec<*const c_char> = required_extensions
             .iter()
+            .chain(optional_extensions.iter())
             .map(|e| e.as_ptr())
             .collect();
 
         let queue_priority: [f32; 1] = [1.0];
-        let queue_create_info = vk::DeviceQueueCreateInfo::default()
-            .queue_family_index(physical_layer.family_index)
-            .queue_priorities(&queue_priority);
+        // Each family can only be requested once
+        let mut families = vec![physical_layer.family_index];
+        for f in [Some(physical_layer.present_family_index), physical_layer.transfer_family_index].into_iter().flatten() {
+            if !families.contains(&f) {
+                families.push(f);
+            }
+        }
+        let queue_create_infos: Vec<vk::DeviceQueueCreateInfo> = families.iter()
+            .map(|f| vk::DeviceQueueCreateInfo::default()
+                .queue_family_index(*f)
+                .queue_priorities(&queue_priority))
+            .collect();
         let enabled_features: vk::PhysicalDeviceFeatures;
         unsafe {
             enabled_features = core.instance.get_physical_device_features(physical_layer.physical_device);
         }
 
-        let qci_slice = [queue_create_info];
-        let device_create_info = vk::DeviceCreateInfo::default()
+        // Descriptor indexing is what the bindless texture array needs, PhysicalLayer only picks devices supporting it
+        let mut features12 = vk::PhysicalDeviceVulkan12Features::default()
+            .descriptor_binding_sampled_image_update_after_bind(granted.descriptor_indexing)
+            .descriptor_binding_partially_bound(granted.descriptor_indexing)
+            .descriptor_binding_update_unused_while_pending(granted.descriptor_indexing)
+            .timeline_semaphore(true) // Required of every 1.2 device
+            .buffer_device_address(granted.buffer_device_address); // Acceleration structure builds and the shader binding table take addresses
+        let mut features13 = vk::PhysicalDeviceVulkan13Features::default()
+            .dynamic_rendering(granted.dynamic_rendering)
+            .synchronization2(granted.synchronization2);
+        let mut acceleration_structure_features = vk::PhysicalDeviceAccelerationStructureFeaturesKHR::default()
+            .acceleration_structure(true);
+        let mut ray_tracing_pipeline_features = vk::PhysicalDeviceRayTracingPipelineFeaturesKHR::default()
+            .ray_tracing_pipeline(true);
+        let mut mesh_shader_features = vk::PhysicalDeviceMeshShaderFeaturesEXT::default()
+            .task_shader(true)
+            .mesh_shader(true);
+
+        let mut device_create_info = vk::DeviceCreateInfo::default()
             .enabled_extension_names(&extensions_cvec)
             .enabled_features(&enabled_features)
-            .queue_create_infos(&qci_slice);
+            .queue_create_infos(&queue_create_infos)
+            .push_next(&mut features12)
+            .push_next(&mut features13);
+        if granted.ray_tracing {
+            device_create_info = device_create_info
+                .push_next(&mut acceleration_structure_features)
+                .push_next(&mut ray_tracing_pipeline_features);
+        }
+        if granted.mesh_shading {
+            device_create_info = device_create_info.push_next(&mut mesh_shader_features);
+        }
 
         let logical_device = unsafe { core.instance.create_device(physical_layer.physical_device, &device_create_info,
-                                          None).unwrap() };
+                                          None).map_err(vk_error("vkCreateDevice"))? };
 
         let logical_queue = unsafe {
             logical_device.get_device_queue(physical_layer.family_index, 0) };
+        let present_queue = unsafe {
+            logical_device.get_device_queue(physical_layer.present_family_index, 0) };
+        let transfer_family_index = physical_layer.transfer_family_index.unwrap_or(physical_layer.family_index);
+        let transfer_queue = unsafe {
+            logical_device.get_device_queue(transfer_family_index, 0) };
 
-        LogicalLayer {
+        Ok(LogicalLayer {
             logical_queue,
+            present_queue,
+            transfer_queue,
+            transfer_family_index,
+            capabilities: granted,
+            acceleration_structure: match granted.ray_tracing {
+                true => Some(AccelerationStructure::new(&core.instance, &logical_device)),
+                false => None
+            },
+            mesh_shader: match granted.mesh_shading {
+                true => Some(MeshShader::new(&core.instance, &logical_device)),
+                false => None
+            },
             logical_device
-        }
+        })
+    }
+
+    // Whether the ray tracing extensions are enabled. Buffers that can feed acceleration structure builds
+    // are created with device addresses when they are.
+    pub(crate) fn ray_tracing(&self) -> bool {
+        self.acceleration_structure.is_some()
+    }
+
+    // Whether VK_EXT_mesh_shader is enabled. Vertex buffers are readable as storage buffers and the scene's
+    // uniforms and push constants reach the task and mesh stages when it is.
+    pub(crate) fn mesh_shading(&self) -> bool {
+        self.mesh_shader.is_some()
     }
 
     pub(crate) fn wait_idle(&self) {
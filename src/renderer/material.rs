@@ -0,0 +1,209 @@
+use bytemuck::{Pod, Zeroable};
+use glam::Mat4;
+
+use crate::assets::MaterialData;
+use crate::renderer::render_queue::MaterialHandle;
+use crate::renderer::resources::Pool;
+use crate::renderer::texture::{TextureHandle, Textures};
+
+const TEXTURES_PER_MATERIAL: usize = 5; // Base color, normal, metallic-roughness, occlusion and emissive
+
+// Index into the renderer's pipelines, one per ShaderSet
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct ShaderVariant(pub(crate) usize);
+
+impl ShaderVariant {
+    pub const DEFAULT: ShaderVariant = ShaderVariant(0); // Unlit vertex color times the material's base color
+    pub const LIT: ShaderVariant = ShaderVariant(1); // Blinn-Phong with the renderer's lights
+    pub const PBR: ShaderVariant = ShaderVariant(2); // glTF metallic-roughness, uses every material texture
+    pub const TERRAIN: ShaderVariant = ShaderVariant(3); // LIT with layers blended by a splat map, see terrain::world::SplatDesc
+    pub const VOXEL: ShaderVariant = ShaderVariant(4); // LIT with the base color texture an array, layer picked per vertex
+}
+
+// How a material's fragments combine with what's already drawn
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum BlendMode {
+    #[default]
+    Opaque, // Writes depth, alpha is ignored
+    Alpha // Blended by alpha over the opaque geometry, drawn back to front without writing depth. I.E. water and glass.
+}
+
+// Pushed with every draw using the material, see MaterialConstants
+#[derive(Clone, Copy, Debug)]
+pub struct MaterialParams {
+    pub base_color: [f32; 4], // Multiplies the vertex color and the base color texture
+    pub emissive: [f32; 3], // Multiplies the emissive texture
+    pub metallic: f32, // Multiplies the metallic-roughness texture's blue channel
+    pub roughness: f32, // And its green channel
+    pub normal_scale: f32, // Scales the normal map's X and Y
+    pub occlusion_strength: f32 // 0 ignores the occlusion texture
+}
+
+impl MaterialParams {
+    pub fn new(base_color: [f32; 4], metallic: f32, roughness: f32) -> MaterialParams {
+        MaterialParams {
+            base_color,
+            emissive: [0.0; 3],
+            metallic,
+            roughness,
+            normal_scale: 1.0,
+            occlusion_strength: 1.0
+        }
+    }
+}
+
+impl Default for MaterialParams {
+    fn default() -> Self {
+        MaterialParams::new([1.0, 1.0, 1.0, 1.0], 0.0, 1.0)
+    }
+}
+
+// Textures left as None don't affect the result. Color textures (base color and emissive) should be
+// uploaded as sRGB, the others as linear.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MaterialDesc {
+    pub shader: ShaderVariant,
+    pub base_color_texture: Option<TextureHandle>,
+    pub normal_texture: Option<TextureHandle>, // Tangent space
+    pub metallic_roughness_texture: Option<TextureHandle>, // Roughness in G, metallic in B as in glTF
+    pub occlusion_texture: Option<TextureHandle>, // R channel
+    pub emissive_texture: Option<TextureHandle>,
+    pub params: MaterialParams,
+    pub blend: BlendMode
+}
+
+impl MaterialDesc {
+    // A PBR material from an imported one. textures holds the handle of each of the importer's images.
+    pub fn from_material_data(data: &MaterialData, textures: &[TextureHandle]) -> MaterialDesc {
+        let texture = |i: Option<usize>| i.and_then(|i| textures.get(i).copied());
+        let mut params = MaterialParams::new(data.base_color, data.metallic, data.roughness);
+        params.emissive = data.emissive;
+
+        MaterialDesc {
+            shader: ShaderVariant::PBR,
+            base_color_texture: texture(data.base_color_texture),
+            normal_texture: texture(data.normal_texture),
+            metallic_roughness_texture: texture(data.metallic_roughness_texture),
+            occlusion_texture: texture(data.occlusion_texture),
+            emissive_texture: texture(data.emissive_texture),
+            params,
+            blend: match data.blended {
+                true => BlendMode::Alpha,
+                false => BlendMode::Opaque
+            }
+        }
+    }
+}
+
+// Per draw push constants of the scene pipelines, matching the PushConstants blocks of the shaders. 128
+// bytes, the most every device is guaranteed to take.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub(crate) struct DrawConstants {
+    pub(crate) model: Mat4,
+    pub(crate) material: MaterialConstants
+}
+
+// A material's params plus the bindless slots of its textures, std430 layout
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+pub(crate) struct MaterialConstants {
+    base_color: [f32; 4],
+    emissive: [f32; 3],
+    metallic: f32,
+    roughness: f32,
+    normal_scale: f32,
+    occlusion_strength: f32,
+    textures: [u32; TEXTURES_PER_MATERIAL] // Base color, normal, metallic-roughness, occlusion and emissive
+}
+
+// Nothing on the GPU, draws push the material's constants. Shaders that don't need some textures just
+// don't read their slots.
+pub(crate) struct Material {
+    pub(crate) shader: ShaderVariant,
+    pub(crate) blend: BlendMode,
+    desc: MaterialDesc,
+    pub(crate) textures: Vec<TextureHandle> // Retained for as long as the material lives
+}
+
+impl Material {
+    pub(crate) fn params(&self) -> &MaterialParams {
+        &self.desc.params
+    }
+
+    // Looked up when drawing, so a replaced texture's new slot is picked up by the next frame
+    pub(crate) fn constants(&self, textures: &Textures) -> MaterialConstants {
+        let desc = &self.desc;
+        // Missing textures get one that leaves the result unchanged
+        let base_color = match desc.shader == ShaderVariant::VOXEL {
+            true => TextureHandle::WHITE_ARRAY,
+            false => TextureHandle::WHITE
+        };
+        let slots = [
+            desc.base_color_texture.unwrap_or(base_color),
+            desc.normal_texture.unwrap_or(TextureHandle::FLAT_NORMAL),
+            desc.metallic_roughness_texture.unwrap_or(TextureHandle::WHITE),
+            desc.occlusion_texture.unwrap_or(TextureHandle::WHITE),
+            desc.emissive_texture.unwrap_or(TextureHandle::WHITE)
+        ].map(|t| textures.slot(t));
+
+        MaterialConstants {
+            base_color: desc.params.base_color,
+            emissive: desc.params.emissive,
+            metallic: desc.params.metallic,
+            roughness: desc.params.roughness,
+            normal_scale: desc.params.normal_scale,
+            occlusion_strength: desc.params.occlusion_strength,
+            textures: slots
+        }
+    }
+}
+
+pub(crate) struct Materials {
+    materials: Pool<Material>
+}
+
+impl Materials {
+    pub(crate) fn new() -> Materials {
+        Materials {
+            materials: Pool::new()
+        }
+    }
+
+    // Retains the textures in desc, released textures are replaced by WHITE
+    pub(crate) fn create(&mut self, textures: &mut Textures, desc: &MaterialDesc) -> MaterialHandle {
+        let retained = [desc.base_color_texture, desc.normal_texture, desc.metallic_roughness_texture,
+            desc.occlusion_texture, desc.emissive_texture]
+            .into_iter()
+            .flatten()
+            .filter(|t| textures.retain(*t))
+            .collect();
+        let handle = self.materials.insert(Material {
+            shader: desc.shader,
+            blend: desc.blend,
+            desc: *desc,
+            textures: retained
+        });
+
+        MaterialHandle(handle)
+    }
+
+    // Released materials draw as DEFAULT
+    pub(crate) fn get(&self, handle: MaterialHandle) -> &Material {
+        self.materials.get(handle.0)
+            .or_else(|| self.materials.get(MaterialHandle::DEFAULT.0))
+            .unwrap()
+    }
+
+    pub(crate) fn retain(&mut self, handle: MaterialHandle) -> bool {
+        self.materials.retain(handle.0)
+    }
+
+    // DEFAULT stays put however often it's released
+    pub(crate) fn release(&mut self, handle: MaterialHandle) -> Option<Material> {
+        match handle == MaterialHandle::DEFAULT {
+            true => None,
+            false => self.materials.release(handle.0)
+        }
+    }
+}
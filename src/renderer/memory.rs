@@ -0,0 +1,117 @@
+// What a device memory allocation holds, for MemoryStats
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MemoryCategory {
+    Textures,
+    Meshes, // Vertex, index, meshlet and morph target buffers
+    RenderTargets, // Depth, MSAA, post process, G-buffer, shadow and SSAO images
+    Staging, // Upload and readback buffers
+    Buffers, // Uniform, instance, indirect and compute buffers
+    AccelerationStructures // Ray tracing structures, their scratch and the shader binding table
+}
+
+impl MemoryCategory {
+    pub const ALL: [MemoryCategory; 6] = [MemoryCategory::Textures, MemoryCategory::Meshes, MemoryCategory::RenderTargets,
+                                          MemoryCategory::Staging, MemoryCategory::Buffers, MemoryCategory::AccelerationStructures];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MemoryCategory::Textures => "textures",
+            MemoryCategory::Meshes => "meshes",
+            MemoryCategory::RenderTargets => "render targets",
+            MemoryCategory::Staging => "staging",
+            MemoryCategory::Buffers => "buffers",
+            MemoryCategory::AccelerationStructures => "acceleration structures"
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CategoryStats {
+    pub allocations: usize,
+    pub bytes: u64
+}
+
+// One of the device's memory heaps. Bytes the allocator knows of, plus the driver's view with VK_EXT_memory_budget.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HeapStats {
+    pub device_local: bool, // VRAM on discrete GPUs
+    pub size: u64,
+    pub allocated: u64, // In vkDeviceMemory blocks, including their free ranges
+    pub used: u64, // Of allocated, by live resources
+    pub budget: Option<u64>, // What the process can use before the driver starts evicting or failing allocations
+    pub usage: Option<u64> // What the driver counts against the budget, including swapchain images and other processes' share on some drivers
+}
+
+impl HeapStats {
+    // Bytes left before the budget, the heap size minus what's allocated without VK_EXT_memory_budget
+    pub fn available(&self) -> u64 {
+        match (self.budget, self.usage) {
+            (Some(budget), Some(usage)) => budget.saturating_sub(usage),
+            _ => self.size.saturating_sub(self.allocated)
+        }
+    }
+
+    // Fraction of the budget in use, past 1 when over it
+    pub fn pressure(&self) -> f32 {
+        match (self.budget, self.usage) {
+            (Some(budget), Some(usage)) if budget > 0 => usage as f32 / budget as f32,
+            _ if self.size > 0 => self.allocated as f32 / self.size as f32,
+            _ => 0.0
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStats {
+    pub heaps: Vec<HeapStats>, // Indexed by Vulkan memory heap
+    pub categories: Vec<(MemoryCategory, CategoryStats)>, // In MemoryCategory::ALL order
+    pub budget_supported: bool // Whether the heaps' budget and usage come from VK_EXT_memory_budget
+}
+
+impl MemoryStats {
+    pub fn category(&self, category: MemoryCategory) -> CategoryStats {
+        self.categories.iter()
+            .find(|(c, _)| *c == category)
+            .map_or(CategoryStats::default(), |(_, s)| *s)
+    }
+
+    // The most pressured device local heap's, what texture streaming should back off on
+    pub fn device_local_pressure(&self) -> f32 {
+        self.heaps.iter()
+            .filter(|h| h.device_local)
+            .map(|h| h.pressure())
+            .fold(0.0, f32::max)
+    }
+
+    // Bytes left in the device local heaps' budgets
+    pub fn device_local_available(&self) -> u64 {
+        self.heaps.iter()
+            .filter(|h| h.device_local)
+            .map(|h| h.available())
+            .sum()
+    }
+
+    // A debug panel's contents, I.E. inside an egui::Window
+    pub fn show(&self, ui: &mut egui::Ui) {
+        fn mib(bytes: u64) -> f64 {
+            bytes as f64 / (1024.0 * 1024.0)
+        }
+
+        for (i, heap) in self.heaps.iter().enumerate() {
+            let kind = if heap.device_local { "device local" } else { "host" };
+            ui.label(format!("Heap {} ({}, {:.0} MiB)", i, kind, mib(heap.size)));
+            ui.label(format!("  {:.1} MiB used of {:.1} MiB allocated", mib(heap.used), mib(heap.allocated)));
+            if let (Some(budget), Some(usage)) = (heap.budget, heap.usage) {
+                ui.add(egui::ProgressBar::new(heap.pressure().min(1.0))
+                    .text(format!("{:.1} / {:.1} MiB budget", mib(usage), mib(budget))));
+            }
+        }
+        if !self.budget_supported {
+            ui.label("No VK_EXT_memory_budget, budgets unknown");
+        }
+        ui.separator();
+        for (category, stats) in self.categories.iter() {
+            ui.label(format!("{}: {:.1} MiB in {} allocations", category.name(), mib(stats.bytes), stats.allocations));
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use glam::Vec3;
+
+use crate::renderer::allocator::Allocator;
+use crate::renderer::error::RendererError;
+use crate::renderer::frustum::Aabb;
+use crate::renderer::index::IndexBuffer;
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::meshlet::Meshlets;
+use crate::renderer::morph::{MorphTarget, MorphTargets};
+use crate::renderer::resources::Handle;
+use crate::renderer::staging_buf::UploadContext;
+use crate::renderer::vertex::{Vertex, VertexBuffer};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct MeshHandle(pub(crate) Handle);
+
+pub struct Mesh {
+    pub(crate) vertex_buffer: VertexBuffer,
+    pub(crate) index_buffer: IndexBuffer,
+    pub(crate) morph: Option<MorphTargets>,
+    pub(crate) meshlets: Option<Meshlets>, // Drawn with the meshlet pipelines instead of the vertex buffer when set
+    bounds: Aabb // In model space, for culling. Covers every target at weights between 0 and 1.
+}
+
+impl Mesh {
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                      vertices: &[Vertex], indices: &[u32]) -> Result<Mesh, RendererError> {
+        let vertex_buffer = VertexBuffer::new(logical_layer, allocator, upload, vertices)?;
+        let index_buffer = match IndexBuffer::new(logical_layer, allocator, upload, indices, vertices.len()) {
+            Ok(i) => i,
+            Err(e) => {
+                vertex_buffer.destroy(logical_layer, allocator);
+                return Err(e);
+            }
+        };
+
+        Ok(Mesh {
+            vertex_buffer,
+            index_buffer,
+            morph: None,
+            meshlets: None,
+            bounds: Aabb::from_points(vertices.iter().map(|v| Vec3::from(v.pos)))
+        })
+    }
+
+    // Takes ownership of the mesh's morph targets and grows its bounds to fit them
+    pub(crate) fn with_morph_targets(mut self, morph: MorphTargets, vertices: &[Vertex], targets: &[MorphTarget]) -> Mesh {
+        let extremes = vertices.iter().enumerate().flat_map(|(i, v)| {
+            let pos = Vec3::from(v.pos);
+            let offsets = targets.iter().map(|t| Vec3::from(t.positions[i]));
+            let low = offsets.clone().fold(pos, |p, o| p + o.min(Vec3::ZERO));
+            let high = offsets.fold(pos, |p, o| p + o.max(Vec3::ZERO));
+            [low, high]
+        });
+        self.bounds = Aabb::from_points(extremes);
+        self.morph = Some(morph);
+        self
+    }
+
+    // Takes ownership of meshlets built from the mesh's vertices
+    pub(crate) fn with_meshlets(mut self, meshlets: Meshlets) -> Mesh {
+        self.meshlets = Some(meshlets);
+        self
+    }
+
+    pub fn vertex_count(&self) -> u32 {
+        self.vertex_buffer.vertex_count
+    }
+
+    pub fn index_count(&self) -> u32 {
+        self.index_buffer.index_count
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.bounds
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        if let Some(m) = self.morph.as_ref() {
+            m.destroy(logical_layer, allocator);
+        }
+        if let Some(m) = self.meshlets.as_ref() {
+            m.destroy(logical_layer, allocator);
+        }
+        self.index_buffer.destroy(logical_layer, allocator);
+        self.vertex_buffer.destroy(logical_layer, allocator);
+    }
+}
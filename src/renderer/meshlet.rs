@@ -0,0 +1,343 @@
+use std::ffi::CString;
+use std::mem;
+use std::path::PathBuf;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::barrier::{Barriers, Usage};
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::material::ShaderVariant;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::raster_pipeline::{RasterPipeline, RasterState};
+use crate::renderer::render_pass::PassTarget;
+use crate::renderer::shader::{ShaderSet, ShaderSource};
+use crate::renderer::staging_buf::UploadContext;
+use crate::renderer::vertex::Vertex;
+
+pub const MAX_MESHLET_VERTICES: usize = 64; // Matches the mesh shader's max_vertices
+pub const MAX_MESHLET_TRIANGLES: usize = 124; // Matches its max_primitives, meshoptimizer wants a multiple of 4
+pub const TASK_GROUP_SIZE: u32 = 32; // Meshlets culled by each task shader workgroup, matches local_size_x
+const CONE_WEIGHT: f32 = 0.25; // How much meshoptimizer favors tight normal cones over tight spheres
+
+const TASK_SHADER: &str = "meshlet.task.spv"; // In SHADER_SPV_DIR
+const MESH_SHADER: &str = "meshlet.mesh.spv";
+
+pub(crate) fn mesh_shading_extensions() -> Vec<CString> {
+    vec![CString::from(vk::ExtMeshShaderFn::name())]
+}
+
+// Vertex buffers are read as storage buffers by the mesh shader
+pub(crate) fn storage_usage(logical_layer: &LogicalLayer) -> vk::BufferUsageFlags {
+    match logical_layer.mesh_shading() {
+        true => vk::BufferUsageFlags::STORAGE_BUFFER,
+        false => vk::BufferUsageFlags::empty()
+    }
+}
+
+// Added to the scene's uniforms and push constants, which the meshlet pipelines read in place of the vertex stage
+pub(crate) fn mesh_stages(logical_layer: &LogicalLayer) -> vk::ShaderStageFlags {
+    match logical_layer.mesh_shading() {
+        true => vk::ShaderStageFlags::TASK_EXT | vk::ShaderStageFlags::MESH_EXT,
+        false => vk::ShaderStageFlags::empty()
+    }
+}
+
+// std430, matches Meshlet in the task and mesh shaders
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct GpuMeshlet {
+    center: [f32; 3], // Bounding sphere in model space
+    radius: f32,
+    cone_axis: [f32; 3], // Normal cone, the meshlet faces away from cameras inside it
+    cone_cutoff: f32,
+    cone_apex: [f32; 3],
+    vertex_offset: u32, // Into the meshlet vertices
+    triangle_offset: u32, // Into the packed triangles
+    vertex_count: u32,
+    triangle_count: u32,
+    _padding: u32
+}
+
+// Descriptor set 3 of the meshlet pipelines, in place of the morph targets: binding 0 is the mesh's vertices
+// as floats, binding 1 a uvec4 header holding the meshlet count followed by the meshlets, binding 2 the
+// vertex indices each meshlet uses and binding 3 its triangles, three bytes indexing those packed per uint.
+fn create_set_layout(logical_layer: &LogicalLayer) -> Result<vk::DescriptorSetLayout, RendererError> {
+    let bindings: Vec<vk::DescriptorSetLayoutBinding> = (0..4)
+        .map(|b| vk::DescriptorSetLayoutBinding::default()
+            .binding(b)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::TASK_EXT | vk::ShaderStageFlags::MESH_EXT))
+        .collect();
+    let create_info = vk::DescriptorSetLayoutCreateInfo::default()
+        .bindings(&bindings);
+
+    unsafe {
+        logical_layer.logical_device.create_descriptor_set_layout(&create_info, None)
+            .map_err(vk_error("vkCreateDescriptorSetLayout"))
+    }
+}
+
+// A mesh split into meshlets by meshoptimizer at upload time. The vertex buffer stays owned by the mesh.
+pub(crate) struct Meshlets {
+    meshlets: vk::Buffer,
+    meshlets_alloc: Allocation,
+    vertices: vk::Buffer,
+    vertices_alloc: Allocation,
+    triangles: vk::Buffer,
+    triangles_alloc: Allocation,
+    pool: vk::DescriptorPool,
+    set: vk::DescriptorSet,
+    meshlet_count: u32
+}
+
+impl Meshlets {
+    // vertex_buffer holds vertices and needs storage_usage
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                      set_layout: vk::DescriptorSetLayout, vertices: &[Vertex], indices: &[u32],
+                      vertex_buffer: vk::Buffer) -> Result<Meshlets, RendererError> {
+        let adapter = meshopt::VertexDataAdapter::new(bytemuck::cast_slice(vertices),
+                                                      mem::size_of::<Vertex>(),
+                                                      0) // pos is the first field
+            .expect("Vertex data matches its stride");
+        let built = meshopt::build_meshlets(indices, &adapter, MAX_MESHLET_VERTICES, MAX_MESHLET_TRIANGLES, CONE_WEIGHT);
+
+        let mut meshlets: Vec<GpuMeshlet> = Vec::with_capacity(built.len());
+        let mut meshlet_vertices: Vec<u32> = Vec::new();
+        let mut triangles: Vec<u32> = Vec::new();
+        for meshlet in built.iter() {
+            let bounds = meshopt::compute_meshlet_bounds(meshlet, &adapter);
+            meshlets.push(GpuMeshlet {
+                center: bounds.center,
+                radius: bounds.radius,
+                cone_axis: bounds.cone_axis,
+                cone_cutoff: bounds.cone_cutoff,
+                cone_apex: bounds.cone_apex,
+                vertex_offset: meshlet_vertices.len() as u32,
+                triangle_offset: triangles.len() as u32,
+                vertex_count: meshlet.vertices.len() as u32,
+                triangle_count: (meshlet.triangles.len() / 3) as u32,
+                _padding: 0
+            });
+            meshlet_vertices.extend_from_slice(meshlet.vertices);
+            triangles.extend(meshlet.triangles.chunks_exact(3)
+                .map(|t| t[0] as u32 | ((t[1] as u32) << 8) | ((t[2] as u32) << 16)));
+        }
+        // Storage buffers can't be empty
+        let header = [meshlets.len() as u32, 0, 0, 0];
+        if meshlet_vertices.is_empty() {
+            meshlet_vertices.push(0);
+            triangles.push(0);
+        }
+
+        let header_size = mem::size_of_val(&header) as vk::DeviceSize;
+        let usage = vk::BufferUsageFlags::STORAGE_BUFFER | vk::BufferUsageFlags::TRANSFER_DST;
+        let (meshlets_alloc, meshlets_buf) = allocator.create_buffer(logical_layer,
+                                                                     header_size + mem::size_of_val(meshlets.as_slice()) as vk::DeviceSize,
+                                                                     usage,
+                                                                     vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                                     MemoryCategory::Meshes)?;
+        let (vertices_alloc, vertices_buf) = match allocator.create_buffer(logical_layer,
+                                                                           mem::size_of_val(meshlet_vertices.as_slice()) as vk::DeviceSize,
+                                                                           usage,
+                                                                           vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                                           MemoryCategory::Meshes) {
+            Ok(b) => b,
+            Err(e) => {
+                allocator.destroy_buffer(logical_layer, meshlets_buf, &meshlets_alloc);
+                return Err(e);
+            }
+        };
+        let (triangles_alloc, triangles_buf) = match allocator.create_buffer(logical_layer,
+                                                                             mem::size_of_val(triangles.as_slice()) as vk::DeviceSize,
+                                                                             usage,
+                                                                             vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                                             MemoryCategory::Meshes) {
+            Ok(b) => b,
+            Err(e) => {
+                allocator.destroy_buffer(logical_layer, vertices_buf, &vertices_alloc);
+                allocator.destroy_buffer(logical_layer, meshlets_buf, &meshlets_alloc);
+                return Err(e);
+            }
+        };
+        let mut m = Meshlets {
+            meshlets: meshlets_buf,
+            meshlets_alloc,
+            vertices: vertices_buf,
+            vertices_alloc,
+            triangles: triangles_buf,
+            triangles_alloc,
+            pool: vk::DescriptorPool::null(),
+            set: vk::DescriptorSet::null(),
+            meshlet_count: meshlets.len() as u32
+        };
+        let uploaded = upload.upload_buffer(logical_layer, allocator, &header, m.meshlets, 0)
+            .and_then(|_| upload.upload_buffer(logical_layer, allocator, &meshlets, m.meshlets, header_size))
+            .and_then(|_| upload.upload_buffer(logical_layer, allocator, &meshlet_vertices, m.vertices, 0))
+            .and_then(|_| upload.upload_buffer(logical_layer, allocator, &triangles, m.triangles, 0))
+            .and_then(|_| m.setup_set(logical_layer, set_layout, vertex_buffer));
+        if let Err(e) = uploaded {
+            m.destroy(logical_layer, allocator);
+            return Err(e);
+        }
+
+        Ok(m)
+    }
+
+    fn setup_set(&mut self, logical_layer: &LogicalLayer, set_layout: vk::DescriptorSetLayout,
+                 vertex_buffer: vk::Buffer) -> Result<(), RendererError> {
+        let pool_sizes = [vk::DescriptorPoolSize::default()
+            .ty(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(4)];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(1);
+        self.pool = unsafe {
+            logical_layer.logical_device.create_descriptor_pool(&pool_create_info, None)
+                .map_err(vk_error("vkCreateDescriptorPool"))?
+        };
+
+        let layouts = [set_layout];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(self.pool)
+            .set_layouts(&layouts);
+        self.set = unsafe {
+            logical_layer.logical_device.allocate_descriptor_sets(&alloc_info).map_err(vk_error("vkAllocateDescriptorSets"))?[0]
+        };
+
+        let infos: Vec<[vk::DescriptorBufferInfo; 1]> = [vertex_buffer, self.meshlets, self.vertices, self.triangles].iter()
+            .map(|b| [vk::DescriptorBufferInfo::default()
+                .buffer(*b)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)])
+            .collect();
+        let writes: Vec<vk::WriteDescriptorSet> = infos.iter()
+            .enumerate()
+            .map(|(binding, info)| vk::WriteDescriptorSet::default()
+                .dst_set(self.set)
+                .dst_binding(binding as u32)
+                .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                .buffer_info(info))
+            .collect();
+        unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+
+        Ok(())
+    }
+
+    // Binds the set with a meshlet pipeline's layout and launches a task workgroup per TASK_GROUP_SIZE meshlets.
+    // The pipeline and the draw's push constants have to be set already.
+    pub(crate) fn draw(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, pipeline: &RasterPipeline) {
+        let mesh_shader = match logical_layer.mesh_shader.as_ref() {
+            Some(m) => m,
+            None => return // Only built with mesh shading on
+        };
+        let sets = [self.set];
+        unsafe {
+            logical_layer.logical_device.cmd_bind_descriptor_sets(command_buffer,
+                                                                  vk::PipelineBindPoint::GRAPHICS,
+                                                                  pipeline.pipeline_layout,
+                                                                  3, // In place of the morph targets
+                                                                  &sets,
+                                                                  &[]);
+            mesh_shader.cmd_draw_mesh_tasks(command_buffer, (self.meshlet_count + TASK_GROUP_SIZE - 1) / TASK_GROUP_SIZE, 1, 1);
+        }
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        unsafe { logical_layer.logical_device.destroy_descriptor_pool(self.pool, None) }; // Frees the set, null is ignored
+        allocator.destroy_buffer(logical_layer, self.triangles, &self.triangles_alloc);
+        allocator.destroy_buffer(logical_layer, self.vertices, &self.vertices_alloc);
+        allocator.destroy_buffer(logical_layer, self.meshlets, &self.meshlets_alloc);
+    }
+}
+
+// Task and mesh shader pipelines that replace the vertex pipelines for opaque, non-instanced meshes with
+// meshlets. Sets 0 to 2 and the push constants match the scene pipelines, so binding these keeps them bound.
+pub(crate) struct MeshShading {
+    pub(crate) set_layout: vk::DescriptorSetLayout,
+    pipelines: Vec<RasterPipeline>, // Forward shaded, indexed by ShaderVariant. Not rebuilt on shader reloads.
+    gbuffer_pipeline: Option<RasterPipeline> // With the deferred pipeline, every material is drawn with it
+}
+
+impl MeshShading {
+    // scene_set_layouts are sets 0 to 2 of the scene pipelines. gbuffer is the deferred pipeline's G-buffer
+    // pass and state, if it's on.
+    pub(crate) fn new(logical_layer: &LogicalLayer, scene_target: PassTarget, shader_variants: &[ShaderSet],
+                      scene_set_layouts: &[vk::DescriptorSetLayout], push_constant_range: vk::PushConstantRange,
+                      gbuffer: Option<(PassTarget, RasterState)>) -> Result<MeshShading, RendererError> {
+        let set_layout = create_set_layout(logical_layer)?;
+        let mut mesh_shading = MeshShading {
+            set_layout,
+            pipelines: Vec::with_capacity(shader_variants.len()),
+            gbuffer_pipeline: None
+        };
+        if let Err(e) = mesh_shading.setup_pipelines(logical_layer, scene_target, shader_variants, scene_set_layouts,
+                                                     push_constant_range, gbuffer) {
+            mesh_shading.destroy(logical_layer);
+            return Err(e);
+        }
+
+        Ok(mesh_shading)
+    }
+
+    fn setup_pipelines(&mut self, logical_layer: &LogicalLayer, scene_target: PassTarget, shader_variants: &[ShaderSet],
+                       scene_set_layouts: &[vk::DescriptorSetLayout], push_constant_range: vk::PushConstantRange,
+                       gbuffer: Option<(PassTarget, RasterState)>) -> Result<(), RendererError> {
+        let set_layouts: Vec<vk::DescriptorSetLayout> = scene_set_layouts.iter()
+            .copied()
+            .chain([self.set_layout])
+            .collect();
+        for shaders in shader_variants {
+            self.pipelines.push(RasterPipeline::with_mesh_shaders(logical_layer, scene_target, TASK_SHADER, MESH_SHADER,
+                                                                  &shaders.fragment, &set_layouts, Some(push_constant_range),
+                                                                  RasterState::default())?);
+        }
+        if let Some((target, state)) = gbuffer {
+            let fragment = ShaderSource::GlslFile(PathBuf::from("shaders/src/gbuffer.frag"));
+            self.gbuffer_pipeline = Some(RasterPipeline::with_mesh_shaders(logical_layer, target, TASK_SHADER, MESH_SHADER,
+                                                                           &fragment, &set_layouts, Some(push_constant_range),
+                                                                           state)?);
+        }
+
+        Ok(())
+    }
+
+    // Builds the pipelines again for a changed scene target, I.E. a new MSAA sample count. The GPU must not
+    // be using the old ones.
+    pub(crate) fn rebuild(&mut self, logical_layer: &LogicalLayer, scene_target: PassTarget, shader_variants: &[ShaderSet],
+                          scene_set_layouts: &[vk::DescriptorSetLayout], push_constant_range: vk::PushConstantRange,
+                          gbuffer: Option<(PassTarget, RasterState)>) -> Result<(), RendererError> {
+        for mut p in self.pipelines.drain(..).chain(self.gbuffer_pipeline.take()) {
+            p.destroy(logical_layer);
+        }
+        self.setup_pipelines(logical_layer, scene_target, shader_variants, scene_set_layouts, push_constant_range, gbuffer)
+    }
+
+    // None for variants added after the pipelines were built, those meshes fall back to their vertex buffers
+    pub(crate) fn pipeline(&self, shader: ShaderVariant, deferred: bool) -> Option<&RasterPipeline> {
+        match deferred {
+            true => self.gbuffer_pipeline.as_ref(),
+            false => self.pipelines.get(shader.0)
+        }
+    }
+
+    // Uploads are only made visible to the vertex and fragment stages, the task and mesh shaders read them too
+    pub(crate) fn prepare(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer) {
+        Barriers::new()
+            .memory(Usage::ShaderRead, Usage::MeshShaderRead)
+            .record(logical_layer, command_buffer);
+    }
+
+    pub(crate) fn destroy(&mut self, logical_layer: &LogicalLayer) {
+        for p in self.pipelines.iter_mut() {
+            p.destroy(logical_layer);
+        }
+        if let Some(p) = self.gbuffer_pipeline.as_mut() {
+            p.destroy(logical_layer);
+        }
+        unsafe { logical_layer.logical_device.destroy_descriptor_set_layout(self.set_layout, None) };
+    }
+}
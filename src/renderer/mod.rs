@@ -1,11 +1,65 @@
 pub mod renderer;
 pub mod index;
+pub mod instance;
+pub mod camera;
+pub mod camera_controller;
+pub mod frustum;
+pub mod pick;
+pub mod mesh;
+pub mod morph;
+pub mod lod;
+pub mod occlusion;
+pub mod indirect;
+pub mod dynamic_mesh;
+pub mod debug_draw;
+pub mod text;
+pub mod sprite;
+pub mod atlas;
+mod overlay;
+mod ui;
+pub mod material;
+pub mod light;
+pub mod shadow;
+pub mod sky;
+pub mod fog;
+pub mod decal;
+pub mod post;
+mod deferred;
+mod id_buffer;
+pub mod ssao;
+pub mod ray_tracing;
+mod meshlet;
+pub mod compute;
+pub mod texture;
+pub mod streaming;
+pub mod render_queue;
+pub mod render_mode;
+pub mod shader;
+pub mod config;
+pub mod monitor;
+pub mod gpu;
+pub mod capabilities;
+pub mod error;
 mod core;
 mod physical_layer;
 mod render_target;
 mod logical_layer;
 mod render_pass;
 mod raster_pipeline;
+mod compute_pipeline;
 mod staging_buf;
-mod vertex;
-mod frame_buffers;
\ No newline at end of file
+pub mod vertex;
+pub mod stats;
+pub mod memory;
+pub mod frame;
+pub mod game_loop;
+mod uniform;
+mod frame_buffers;
+mod shader_watcher;
+mod allocator;
+mod resources;
+mod bindless;
+mod deletion_queue;
+mod timestamps;
+mod timeline;
+mod barrier;
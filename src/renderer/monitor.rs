@@ -0,0 +1,69 @@
+use winit::monitor::{MonitorHandle, VideoMode as WinitVideoMode};
+use winit::window::{Fullscreen, Window};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct VideoMode {
+    pub size: (u32, u32), // Physical pixels
+    pub refresh_rate_millihertz: u32,
+    pub bit_depth: u16
+}
+
+impl VideoMode {
+    fn from_winit(mode: &WinitVideoMode) -> VideoMode {
+        VideoMode {
+            size: (mode.size().width, mode.size().height),
+            refresh_rate_millihertz: mode.refresh_rate_millihertz(),
+            bit_depth: mode.bit_depth()
+        }
+    }
+
+    pub fn refresh_rate(&self) -> f32 {
+        self.refresh_rate_millihertz as f32 / 1000.0
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Monitor {
+    pub index: usize, // Into the list monitors() returned, only valid until monitors are plugged in or out
+    pub name: Option<String>,
+    pub position: (i32, i32), // Top left corner on the desktop, in physical pixels
+    pub size: (u32, u32), // Current resolution
+    pub scale_factor: f64,
+    pub primary: bool,
+    pub video_modes: Vec<VideoMode> // Largest and fastest first
+}
+
+pub(crate) fn monitors(window: &Window) -> Vec<Monitor> {
+    let primary = window.primary_monitor();
+    window.available_monitors()
+        .enumerate()
+        .map(|(index, m)| {
+            let mut video_modes: Vec<VideoMode> = m.video_modes().map(|v| VideoMode::from_winit(&v)).collect();
+            video_modes.sort_by_key(|v| std::cmp::Reverse((v.size.0 * v.size.1, v.refresh_rate_millihertz, v.bit_depth)));
+            video_modes.dedup();
+            Monitor {
+                index,
+                name: m.name(),
+                position: (m.position().x, m.position().y),
+                size: (m.size().width, m.size().height),
+                scale_factor: m.scale_factor(),
+                primary: primary.as_ref() == Some(&m),
+                video_modes
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn monitor_handle(window: &Window, index: usize) -> Option<MonitorHandle> {
+    window.available_monitors().nth(index)
+}
+
+// Borderless on the monitor with None, otherwise exclusive in the monitor's matching video mode
+pub(crate) fn fullscreen_on(monitor: MonitorHandle, mode: Option<VideoMode>) -> Option<Fullscreen> {
+    match mode {
+        None => Some(Fullscreen::Borderless(Some(monitor))),
+        Some(mode) => monitor.video_modes()
+            .find(|v| VideoMode::from_winit(v) == mode)
+            .map(Fullscreen::Exclusive)
+    }
+}
@@ -0,0 +1,235 @@
+use std::mem;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::staging_buf::UploadContext;
+
+pub const MAX_MORPH_TARGETS: usize = 8; // Matches MAX_MORPH_TARGETS in the vertex shader
+
+// Offsets blended onto a mesh's vertices by the target's weight, one per vertex. glTF calls these morph
+// targets, other tools blend shapes.
+#[derive(Clone, Debug, Default)]
+pub struct MorphTarget {
+    pub positions: Vec<[f32; 3]>,
+    pub normals: Vec<[f32; 3]>, // Empty when the target leaves them alone
+    pub tangents: Vec<[f32; 3]>
+}
+
+// std430, vec4s so the array has no padding rules to match
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct MorphDelta {
+    position: [f32; 4],
+    normal: [f32; 4],
+    tangent: [f32; 4]
+}
+
+// std140, the weights are read as vec4s
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct MorphWeights {
+    counts: [u32; 4], // Target count then vertex count
+    weights: [f32; MAX_MORPH_TARGETS]
+}
+
+// Descriptor set 3 of the scene pipelines: binding 0 is a storage buffer of every target's deltas, target
+// major, binding 1 the weights. Meshes without targets bind the empty set, which has a target count of 0.
+pub(crate) fn create_set_layout(logical_layer: &LogicalLayer) -> Result<vk::DescriptorSetLayout, RendererError> {
+    let bindings = [
+        vk::DescriptorSetLayoutBinding::default()
+            .binding(0)
+            .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::VERTEX),
+        vk::DescriptorSetLayoutBinding::default()
+            .binding(1)
+            .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+            .descriptor_count(1)
+            .stage_flags(vk::ShaderStageFlags::VERTEX)
+    ];
+    let create_info = vk::DescriptorSetLayoutCreateInfo::default()
+        .bindings(&bindings);
+
+    unsafe {
+        logical_layer.logical_device.create_descriptor_set_layout(&create_info, None)
+            .map_err(vk_error("vkCreateDescriptorSetLayout"))
+    }
+}
+
+// A mesh's targets on the GPU. The weights get a buffer per frame in flight, so changing them never
+// touches one the GPU is reading.
+pub(crate) struct MorphTargets {
+    deltas: vk::Buffer,
+    deltas_alloc: Allocation,
+    weight_bufs: Vec<(vk::Buffer, Allocation)>, // Host visible and mapped, one per frame slot
+    pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>, // Per frame slot
+    counts: [u32; 4],
+    weights: [f32; MAX_MORPH_TARGETS],
+    stale: Vec<bool> // Slots whose buffer doesn't hold the current weights yet
+}
+
+impl MorphTargets {
+    // Every target has to have an offset for each of the vertex_count vertices. No targets makes the set
+    // bound for meshes without any.
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                      set_layout: vk::DescriptorSetLayout, frame_count: usize, vertex_count: usize,
+                      targets: &[MorphTarget]) -> Result<MorphTargets, RendererError> {
+        assert!(targets.len() <= MAX_MORPH_TARGETS, "More morph targets than the vertex shader blends");
+        assert!(targets.iter().all(|t| t.positions.len() == vertex_count), "Morph targets need a position offset per vertex");
+
+        let zero = [0.0; 3];
+        let extend = |v: &[f32; 3]| [v[0], v[1], v[2], 0.0];
+        let mut deltas: Vec<MorphDelta> = targets.iter()
+            .flat_map(|t| (0..vertex_count).map(move |i| MorphDelta {
+                position: extend(&t.positions[i]),
+                normal: extend(t.normals.get(i).unwrap_or(&zero)),
+                tangent: extend(t.tangents.get(i).unwrap_or(&zero))
+            }))
+            .collect();
+        if deltas.is_empty() {
+            deltas.push(MorphDelta::zeroed()); // Storage buffers can't be empty
+        }
+
+        let deltas_size = mem::size_of_val(deltas.as_slice()) as vk::DeviceSize;
+        let (deltas_alloc, deltas_buf) = allocator.create_buffer(logical_layer,
+                                                                 deltas_size,
+                                                                 vk::BufferUsageFlags::STORAGE_BUFFER |
+                                                                     vk::BufferUsageFlags::TRANSFER_DST,
+                                                                 vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                                 MemoryCategory::Meshes)?;
+        let mut morph = MorphTargets {
+            deltas: deltas_buf,
+            deltas_alloc,
+            weight_bufs: Vec::with_capacity(frame_count),
+            pool: vk::DescriptorPool::null(),
+            sets: Vec::new(),
+            counts: [targets.len() as u32, vertex_count as u32, 0, 0],
+            weights: [0.0; MAX_MORPH_TARGETS],
+            stale: vec![true; frame_count]
+        };
+        if let Err(e) = morph.setup(logical_layer, allocator, upload, set_layout, frame_count, &deltas) {
+            morph.destroy(logical_layer, allocator);
+            return Err(e);
+        }
+
+        Ok(morph)
+    }
+
+    fn setup(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+             set_layout: vk::DescriptorSetLayout, frame_count: usize, deltas: &[MorphDelta]) -> Result<(), RendererError> {
+        upload.upload_buffer(logical_layer, allocator, deltas, self.deltas, 0)?;
+
+        let weights_size = mem::size_of::<MorphWeights>() as vk::DeviceSize;
+        for frame in 0..frame_count {
+            self.weight_bufs.push(allocator.create_buffer(logical_layer,
+                                                          weights_size,
+                                                          vk::BufferUsageFlags::UNIFORM_BUFFER,
+                                                          vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                                              vk::MemoryPropertyFlags::HOST_COHERENT,
+                                                          MemoryCategory::Buffers)?);
+            self.write(frame); // Not in use by any frame yet
+        }
+
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::STORAGE_BUFFER)
+                .descriptor_count(frame_count as u32),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(frame_count as u32)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(frame_count as u32);
+        self.pool = unsafe {
+            logical_layer.logical_device.create_descriptor_pool(&pool_create_info, None)
+                .map_err(vk_error("vkCreateDescriptorPool"))?
+        };
+
+        let layouts = vec![set_layout; frame_count];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(self.pool)
+            .set_layouts(&layouts);
+        self.sets = unsafe {
+            logical_layer.logical_device.allocate_descriptor_sets(&alloc_info).map_err(vk_error("vkAllocateDescriptorSets"))?
+        };
+
+        for (set, (weights_buf, _)) in self.sets.iter().zip(self.weight_bufs.iter()) {
+            let delta_infos = [vk::DescriptorBufferInfo::default()
+                .buffer(self.deltas)
+                .offset(0)
+                .range(vk::WHOLE_SIZE)];
+            let weight_infos = [vk::DescriptorBufferInfo::default()
+                .buffer(*weights_buf)
+                .offset(0)
+                .range(weights_size)];
+            let writes = [
+                vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(&delta_infos),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                    .buffer_info(&weight_infos)
+            ];
+            unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn target_count(&self) -> usize {
+        self.counts[0] as usize
+    }
+
+    // Weights past the target count are ignored, missing ones are 0. Used from the next recorded frame on.
+    pub(crate) fn set_weights(&mut self, weights: &[f32]) {
+        self.weights = [0.0; MAX_MORPH_TARGETS];
+        for (w, new) in self.weights.iter_mut().zip(weights.iter().take(self.target_count())) {
+            *w = *new;
+        }
+        for s in self.stale.iter_mut() {
+            *s = true;
+        }
+    }
+
+    pub(crate) fn weights(&self) -> &[f32] {
+        &self.weights[..self.target_count()]
+    }
+
+    // Copies the weights into the frame's buffer if they changed since it was last written. The frame's
+    // previous submission must have finished.
+    pub(crate) fn write(&mut self, frame: usize) {
+        if !self.stale[frame] {
+            return;
+        }
+        let weights = MorphWeights {
+            counts: self.counts,
+            weights: self.weights
+        };
+        let ptr = self.weight_bufs[frame].1.mapped_ptr().unwrap() as *mut MorphWeights; // Mapped for as long as the allocation lives
+        unsafe { ptr.write_unaligned(weights) };
+        self.stale[frame] = false;
+    }
+
+    pub(crate) fn set(&self, frame: usize) -> vk::DescriptorSet {
+        self.sets[frame]
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        unsafe { logical_layer.logical_device.destroy_descriptor_pool(self.pool, None) }; // Frees the sets, null is ignored
+        for (buf, alloc) in self.weight_bufs.iter() {
+            allocator.destroy_buffer(logical_layer, *buf, alloc);
+        }
+        allocator.destroy_buffer(logical_layer, self.deltas, &self.deltas_alloc);
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use glam::{Mat4, Vec3};
+
+use crate::renderer::allocator::Allocator;
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::frustum::Aabb;
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::material::{DrawConstants, MaterialConstants};
+use crate::renderer::mesh::Mesh;
+use crate::renderer::raster_pipeline::RasterPipeline;
+use crate::renderer::staging_buf::UploadContext;
+use crate::renderer::vertex::Vertex;
+
+pub const MAX_OCCLUSION_QUERIES: u32 = 1024; // Per frame, objects past this are drawn without being tested
+const PROXY_MARGIN: f32 = 1.02; // Proxies are grown a little so they don't lose the depth test to their own object
+
+// Names an object across frames so its occlusion results can be found again. Created by
+// CubulousRenderer::create_occlusion_id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct OcclusionId(pub(crate) u32);
+
+#[derive(Clone, Copy, Debug)]
+struct Visibility {
+    visible: bool, // Whether any sample of the bounds passed the depth test
+    frame: u64 // Frame the test was recorded in
+}
+
+// Occlusion queries against the bounds of objects drawn with an OcclusionId. The bounds are drawn after the
+// opaque geometry without writing anything, counting the samples that would have passed the depth test.
+// Results are read once the frame's timeline value has been waited on, so an object hidden in one frame is only
+// skipped MAX_FRAMES_IN_FLIGHT frames later and shows up that many frames after it's uncovered. Objects
+// whose results are missing or older than that are drawn, never skipped.
+pub(crate) struct OcclusionQueries {
+    query_pool: vk::QueryPool,
+    cube: Mesh, // Corners at -1 and 1, scaled onto each tested bounds
+    tested: Vec<Vec<OcclusionId>>, // Ids tested in each frame slot, in query order
+    tested_frame: Vec<u64>, // Frame each slot was last recorded in
+    pending: Vec<(OcclusionId, Mat4)>, // This frame's tests, proxy model matrices
+    visibility: HashMap<OcclusionId, Visibility>,
+    next_id: u32
+}
+
+impl OcclusionQueries {
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                      frame_count: usize) -> Result<OcclusionQueries, RendererError> {
+        let vertices: Vec<Vertex> = (0..8)
+            .map(|i| Vertex {
+                pos: [(i & 1) as f32 * 2.0 - 1.0, ((i >> 1) & 1) as f32 * 2.0 - 1.0, ((i >> 2) & 1) as f32 * 2.0 - 1.0],
+                ..Vertex::default()
+            })
+            .collect();
+        // Two triangles per face, the proxy pipeline doesn't cull so the winding doesn't matter
+        let indices: [u32; 36] = [
+            0, 1, 3, 0, 3, 2, // -Z
+            4, 5, 7, 4, 7, 6, // +Z
+            0, 1, 5, 0, 5, 4, // -Y
+            2, 3, 7, 2, 7, 6, // +Y
+            0, 2, 6, 0, 6, 4, // -X
+            1, 3, 7, 1, 7, 5 // +X
+        ];
+        let cube = Mesh::new(logical_layer, allocator, upload, &vertices, &indices)?;
+
+        let create_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::OCCLUSION)
+            .query_count(MAX_OCCLUSION_QUERIES * frame_count as u32);
+        let query_pool = match unsafe { logical_layer.logical_device.create_query_pool(&create_info, None) } {
+            Ok(p) => p,
+            Err(r) => {
+                cube.destroy(logical_layer, allocator);
+                return Err(vk_error("vkCreateQueryPool")(r));
+            }
+        };
+
+        Ok(OcclusionQueries {
+            query_pool,
+            cube,
+            tested: vec![Vec::new(); frame_count],
+            tested_frame: vec![0; frame_count],
+            pending: Vec::new(),
+            visibility: HashMap::new(),
+            next_id: 0
+        })
+    }
+
+    pub(crate) fn create_id(&mut self) -> OcclusionId {
+        self.next_id += 1;
+        OcclusionId(self.next_id - 1)
+    }
+
+    pub(crate) fn remove_id(&mut self, id: OcclusionId) {
+        self.visibility.remove(&id);
+    }
+
+    // Takes in the results of the slot's last frame. Only valid after the slot's timeline value has been reached.
+    pub(crate) fn read(&mut self, logical_layer: &LogicalLayer, frame: usize) {
+        let ids = &self.tested[frame];
+        if ids.is_empty() {
+            return;
+        }
+
+        let mut samples = vec![0u64; ids.len()];
+        let read = unsafe {
+            logical_layer.logical_device.get_query_pool_results(self.query_pool,
+                                                                frame as u32 * MAX_OCCLUSION_QUERIES,
+                                                                &mut samples,
+                                                                vk::QueryResultFlags::TYPE_64)
+        };
+        if read.is_err() {
+            return; // Treated like any other missing result
+        }
+
+        let tested_frame = self.tested_frame[frame];
+        for (id, count) in ids.iter().zip(samples) {
+            // An id drawn more than once in a frame is visible if any of its draws are
+            let visibility = self.visibility.entry(*id).or_insert(Visibility { visible: false, frame: tested_frame });
+            if visibility.frame != tested_frame {
+                *visibility = Visibility { visible: false, frame: tested_frame };
+            }
+            visibility.visible |= count > 0;
+        }
+        self.tested[frame].clear();
+    }
+
+    // Whether an object should be drawn in this frame, from its latest results. max_age is how many frames
+    // old a result can be before it's no longer trusted.
+    pub(crate) fn is_visible(&self, id: OcclusionId, frame: u64, max_age: u64) -> bool {
+        match self.visibility.get(&id) {
+            Some(v) if frame.saturating_sub(v.frame) <= max_age => v.visible,
+            _ => true
+        }
+    }
+
+    // Queues a test of world space bounds for this frame. False if the camera is inside them, where the
+    // proxy's faces would be clipped, so the object has to be drawn regardless.
+    pub(crate) fn test(&mut self, id: OcclusionId, bounds: &Aabb, eye: Vec3) -> bool {
+        let half = bounds.half_extents() * PROXY_MARGIN + Vec3::splat(f32::EPSILON);
+        let center = bounds.center();
+        if (eye - center).abs().cmple(half).all() {
+            return false;
+        }
+        self.pending.push((id, Mat4::from_translation(center) * Mat4::from_scale(half)));
+        true
+    }
+
+    pub(crate) fn pending_count(&self) -> usize {
+        self.pending.len().min(MAX_OCCLUSION_QUERIES as usize)
+    }
+
+    // Must be recorded outside of a render pass
+    pub(crate) fn reset(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame: usize) {
+        unsafe {
+            logical_layer.logical_device.cmd_reset_query_pool(command_buffer, self.query_pool,
+                                                              frame as u32 * MAX_OCCLUSION_QUERIES, MAX_OCCLUSION_QUERIES);
+        }
+    }
+
+    // Draws every pending test's proxy inside a query with the proxy pipeline. Must be recorded inside the
+    // scene render pass after the opaque draws, with the empty morph set bound.
+    pub(crate) fn record(&mut self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame: usize,
+                         frame_number: u64, pipeline: &RasterPipeline, material: MaterialConstants) {
+        let device = &logical_layer.logical_device;
+        self.tested[frame].clear();
+        self.tested_frame[frame] = frame_number;
+        if self.pending.is_empty() {
+            return;
+        }
+
+        let vertex_buffers = [self.cube.vertex_buffer.buf];
+        unsafe {
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.pipelines[0]);
+            device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &[0]);
+            device.cmd_bind_index_buffer(command_buffer, self.cube.index_buffer.buf, 0, self.cube.index_buffer.index_type);
+        }
+        for (query, (id, model)) in self.pending.drain(..).take(MAX_OCCLUSION_QUERIES as usize).enumerate() {
+            let query = frame as u32 * MAX_OCCLUSION_QUERIES + query as u32;
+            pipeline.push_constants(logical_layer, command_buffer, 0, &DrawConstants { model, material });
+            unsafe {
+                device.cmd_begin_query(command_buffer, self.query_pool, query, vk::QueryControlFlags::empty()); // Any passing sample will do
+                device.cmd_draw_indexed(command_buffer, self.cube.index_buffer.index_count, 1, 0, 0, 0);
+                device.cmd_end_query(command_buffer, self.query_pool, query);
+            }
+            self.tested[frame].push(id);
+        }
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        unsafe { logical_layer.logical_device.destroy_query_pool(self.query_pool, None) };
+        self.cube.destroy(logical_layer, allocator);
+    }
+}
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::mem;
+use std::path::PathBuf;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use memoffset::offset_of;
+
+use crate::renderer::allocator::Allocator;
+use crate::renderer::dynamic_mesh::DynamicMesh;
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::raster_pipeline::{RasterPipeline, RasterState};
+use crate::renderer::render_pass::PassTarget;
+use crate::renderer::shader::{ShaderSet, ShaderSource};
+use crate::renderer::texture::{TextureHandle, Textures};
+use crate::renderer::vertex::{VertexAttribute, VertexFormat, VertexLayout};
+
+const TEXTURES_PER_POOL: u32 = 16; // Another pool is created whenever the last one fills up
+
+// Screen space, in pixels from the top left corner of the window
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+pub struct OverlayVertex {
+    pub pos: [f32; 2],
+    pub uv: [f32; 2],
+    pub color: [f32; 4] // Linear, multiplies the texture
+}
+
+impl VertexFormat for OverlayVertex {
+    fn layout() -> VertexLayout {
+        VertexLayout {
+            stride: mem::size_of::<OverlayVertex>() as u32,
+            per_instance: false,
+            attributes: vec![
+                VertexAttribute { location: 0, format: vk::Format::R32G32_SFLOAT, offset: offset_of!(OverlayVertex, pos) as u32 },
+                VertexAttribute { location: 1, format: vk::Format::R32G32_SFLOAT, offset: offset_of!(OverlayVertex, uv) as u32 },
+                VertexAttribute { location: 2, format: vk::Format::R32G32B32A32_SFLOAT, offset: offset_of!(OverlayVertex, color) as u32 }
+            ]
+        }
+    }
+}
+
+// A run of indices drawn with one texture and scissor
+struct Batch {
+    texture: TextureHandle,
+    clip: Option<vk::Rect2D>, // None draws over the whole screen
+    first_index: u32,
+    index_count: u32
+}
+
+// Textured triangles drawn over the final image in the present pass, after post-processing so they
+// aren't tonemapped or bloomed. Text, sprites and the UI all end up here, drawn in the order they
+// were pushed without depth testing.
+pub(crate) struct Overlay {
+    pipeline: RasterPipeline,
+    set_layout: vk::DescriptorSetLayout,
+    sampler: vk::Sampler,
+    pools: Vec<vk::DescriptorPool>,
+    sets: HashMap<TextureHandle, vk::DescriptorSet>, // Textures are never removed, so sets are made once each
+    mesh: DynamicMesh,
+    vertices: Vec<OverlayVertex>,
+    indices: Vec<u32>,
+    batches: Vec<Batch>
+}
+
+impl Overlay {
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, present_pass: vk::RenderPass,
+                      frame_count: usize) -> Result<Overlay, RendererError> {
+        fn setup_set_layout(logical_layer: &LogicalLayer) -> Result<vk::DescriptorSetLayout, RendererError> {
+            let bindings = [
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(0)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(1)
+                    .descriptor_type(vk::DescriptorType::SAMPLER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            ];
+            let create_info = vk::DescriptorSetLayoutCreateInfo::default()
+                .bindings(&bindings);
+
+            unsafe {
+                logical_layer.logical_device.create_descriptor_set_layout(&create_info, None)
+                    .map_err(vk_error("vkCreateDescriptorSetLayout"))
+            }
+        }
+
+        fn setup_sampler(logical_layer: &LogicalLayer) -> Result<vk::Sampler, RendererError> {
+            let create_info = vk::SamplerCreateInfo::default()
+                .mag_filter(vk::Filter::LINEAR)
+                .min_filter(vk::Filter::LINEAR)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE) // Atlas regions at the edge don't wrap
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .max_lod(0.0);
+
+            unsafe { logical_layer.logical_device.create_sampler(&create_info, None).map_err(vk_error("vkCreateSampler")) }
+        }
+
+        let set_layout = setup_set_layout(logical_layer)?;
+        let sampler = setup_sampler(logical_layer)?;
+        let shaders = ShaderSet {
+            vertex: ShaderSource::GlslFile(PathBuf::from("shaders/src/overlay.vert")),
+            fragment: ShaderSource::GlslFile(PathBuf::from("shaders/src/overlay.frag"))
+        };
+        let push_constant_range = vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::VERTEX)
+            .offset(0)
+            .size(mem::size_of::<[f32; 2]>() as u32); // Screen size
+        let state = RasterState {
+            cull_mode: vk::CullModeFlags::NONE, // Winding depends on the caller, I.E. mirrored sprites
+            depth_test: false, // The present pass has no depth attachment
+            ..RasterState::default()
+        };
+        let pipeline = RasterPipeline::with_state(logical_layer, PassTarget::single(present_pass), &shaders, &[OverlayVertex::layout()],
+                                                  &[set_layout], Some(push_constant_range), state)?;
+        let mesh = DynamicMesh::new(logical_layer, allocator, frame_count)?;
+
+        Ok(Overlay {
+            pipeline,
+            set_layout,
+            sampler,
+            pools: Vec::new(),
+            sets: HashMap::new(),
+            mesh,
+            vertices: Vec::new(),
+            indices: Vec::new(),
+            batches: Vec::new()
+        })
+    }
+
+    // indices are relative to vertices
+    pub(crate) fn push(&mut self, texture: TextureHandle, vertices: &[OverlayVertex], indices: &[u32]) {
+        self.push_clipped(texture, None, vertices, indices);
+    }
+
+    // Like push, but nothing is drawn outside of clip, in pixels
+    pub(crate) fn push_clipped(&mut self, texture: TextureHandle, clip: Option<vk::Rect2D>, vertices: &[OverlayVertex],
+                               indices: &[u32]) {
+        let base = self.vertices.len() as u32;
+        self.vertices.extend_from_slice(vertices);
+        self.indices.extend(indices.iter().map(|i| base + i));
+
+        match self.batches.last_mut() {
+            Some(b) if b.texture == texture && b.clip == clip => b.index_count += indices.len() as u32,
+            _ => self.batches.push(Batch {
+                texture,
+                clip,
+                first_index: self.indices.len() as u32 - indices.len() as u32,
+                index_count: indices.len() as u32
+            })
+        }
+    }
+
+    fn create_pool(logical_layer: &LogicalLayer) -> Result<vk::DescriptorPool, RendererError> {
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(TEXTURES_PER_POOL),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::SAMPLER)
+                .descriptor_count(TEXTURES_PER_POOL)
+        ];
+
+        let create_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(TEXTURES_PER_POOL);
+
+        unsafe {
+            logical_layer.logical_device.create_descriptor_pool(&create_info, None)
+                .map_err(vk_error("vkCreateDescriptorPool"))
+        }
+    }
+
+    fn create_set(&mut self, logical_layer: &LogicalLayer, textures: &Textures, texture: TextureHandle) -> Result<vk::DescriptorSet, RendererError> {
+        let layouts = [self.set_layout];
+        let allocate = |pool: vk::DescriptorPool| {
+            let alloc_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(pool)
+                .set_layouts(&layouts);
+            unsafe { logical_layer.logical_device.allocate_descriptor_sets(&alloc_info) }
+        };
+
+        let set = match self.pools.last().map(|p| allocate(*p)) {
+            Some(Ok(sets)) => sets[0],
+            Some(Err(vk::Result::ERROR_OUT_OF_POOL_MEMORY)) | Some(Err(vk::Result::ERROR_FRAGMENTED_POOL)) | None => {
+                let pool = Self::create_pool(logical_layer)?;
+                self.pools.push(pool);
+                allocate(pool).map_err(vk_error("vkAllocateDescriptorSets"))?[0]
+            },
+            Some(Err(e)) => return Err(vk_error("vkAllocateDescriptorSets")(e))
+        };
+
+        let image_infos = [vk::DescriptorImageInfo::default()
+            .image_view(textures.get(texture).view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let sampler_infos = [vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)];
+        let writes = [
+            vk::WriteDescriptorSet::default()
+                .dst_set(set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .image_info(&image_infos),
+            vk::WriteDescriptorSet::default()
+                .dst_set(set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .image_info(&sampler_infos)
+        ];
+        unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+        self.sets.insert(texture, set);
+
+        Ok(set)
+    }
+
+    // Copies everything pushed since the last frame into the frame's buffer. The frame's previous
+    // submission must have finished.
+    pub(crate) fn prepare(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, textures: &Textures,
+                          frame: usize) -> Result<(), RendererError> {
+        for i in 0..self.batches.len() {
+            let texture = self.batches[i].texture;
+            if !self.sets.contains_key(&texture) {
+                self.create_set(logical_layer, textures, texture)?;
+            }
+        }
+        self.mesh.set(&self.vertices, &self.indices);
+        self.mesh.write(logical_layer, allocator, frame)?;
+        self.vertices.clear();
+        self.indices.clear();
+
+        Ok(())
+    }
+
+    // Draws the prepared batches inside the present pass, then starts over for the next frame
+    pub(crate) fn record(&mut self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame: usize,
+                         extent: vk::Extent2D) {
+        let batches = mem::take(&mut self.batches);
+        if batches.is_empty() {
+            return;
+        }
+        let (buf, index_offset, _) = self.mesh.draw_info(frame);
+
+        let device = &logical_layer.logical_device;
+        let vertex_buffers = [buf];
+        let offsets: [vk::DeviceSize; 1] = [0];
+        let screen_size = [extent.width as f32, extent.height as f32];
+        let full_screen = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent
+        };
+        unsafe {
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline.pipelines[0]);
+            device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
+            device.cmd_bind_index_buffer(command_buffer, buf, index_offset, vk::IndexType::UINT32);
+            self.pipeline.push_constants(logical_layer, command_buffer, 0, &screen_size);
+            for batch in batches.iter() {
+                let scissors = [batch.clip.unwrap_or(full_screen)];
+                device.cmd_set_scissor(command_buffer, 0, &scissors);
+                let descriptor_sets = [self.sets[&batch.texture]];
+                device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline.pipeline_layout,
+                                                0, &descriptor_sets, &[]);
+                device.cmd_draw_indexed(command_buffer, batch.index_count, 1, batch.first_index, 0, 0);
+            }
+        }
+    }
+
+    pub(crate) fn destroy(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        self.pipeline.destroy(logical_layer);
+        self.mesh.destroy(logical_layer, allocator);
+        unsafe {
+            for p in self.pools.iter() {
+                logical_layer.logical_device.destroy_descriptor_pool(*p, None); // Frees the sets as well
+            }
+            logical_layer.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+            logical_layer.logical_device.destroy_sampler(self.sampler, None);
+        }
+    }
+}
@@ -2,17 +2,32 @@ use std::ffi::{c_char, CStr, CString};
 
 use ash::{vk, Instance};
 
+use crate::renderer::capabilities::DeviceCapabilities;
+use crate::renderer::config::{ColorSpace, DevicePreference, SurfaceFormat};
 use crate::renderer::core::Core;
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::gpu::{choose_gpu, GpuInfo};
+use crate::renderer::texture::BlockFormat;
 
 pub(crate) struct PhysicalLayer {
     pub(crate)physical_device: vk::PhysicalDevice,
-    pub(crate) family_index: u32,
-    pub(crate) supported_surface_formats: Vec<vk::SurfaceFormatKHR>,
-    pub(crate) present_modes: Vec<vk::PresentModeKHR>
+    pub(crate) family_index: u32, // Graphics
+    pub(crate) present_family_index: u32, // Usually the same as family_index
+    pub(crate) transfer_family_index: Option<u32>, // A transfer only family, I.E. the DMA engines on discrete GPUs
+    pub(crate) compute_family_index: Option<u32>, // Dispatches are recorded into the frame's command buffers, so only the graphics family
+    pub(crate) fill_mode_non_solid: bool, // Wireframe polygon mode
+    pub(crate) indirect_draws: bool, // multiDrawIndirect and drawIndirectFirstInstance, for indirect batches
+    pub(crate) sample_counts: vk::SampleCountFlags, // MSAA sample counts supported by both color and depth attachments
+    pub(crate) capabilities: DeviceCapabilities, // Supported, LogicalLayer enables whichever of them are requested
+    pub(crate) compressed_formats: Vec<BlockFormat>, // Sampleable with linear filtering in both sRGB and UNORM
+    pub(crate) supported_surface_formats: Vec<vk::SurfaceFormatKHR>, // Empty when headless
+    pub(crate) present_modes: Vec<vk::PresentModeKHR>, // Empty when headless
+    pub(crate) gpus: Vec<GpuInfo>, // Every device on the system, suitable or not
+    pub(crate) selected: usize // Index into gpus of physical_device
 }
 
 impl PhysicalLayer {
-    pub fn new(core: &Core, required_extensions: &Vec<CString>) -> Option<PhysicalLayer> {
+    pub fn new(core: &Core, required_extensions: &Vec<CString>, preference: DevicePreference) -> Result<PhysicalLayer, RendererError> {
         fn required_physical_extensions_present(instance: &Instance,
                                                 physical_device: vk::PhysicalDevice,
                                                 required_extensions: &Vec<CString>) -> bool {
@@ -20,7 +35,7 @@ impl PhysicalLayer {
             unsafe {
                 dev_extensions = instance
                     .enumerate_device_extension_properties(physical_device)
-                    .unwrap()
+                    .unwrap_or_default() // A device we can't query is treated as unsuitable
                     .iter()
                     .map(|i| CStr::from_ptr(i.extension_name.as_ptr()).to_str().unwrap())
                     .collect();
@@ -35,101 +50,182 @@ impl PhysicalLayer {
                 .all(|e| dev_extensions.contains(&e.to_str().unwrap()))
         }
 
-        let physical_devices: Vec<vk::PhysicalDevice>;
-        unsafe {
-            physical_devices = core.instance.enumerate_physical_devices().unwrap();
+        fn find_transfer_family(instance: &Instance, physical_device: vk::PhysicalDevice) -> Option<u32> {
+            let queue_families = unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+            let transfer_only = |qf: &vk::QueueFamilyProperties| qf.queue_flags.contains(vk::QueueFlags::TRANSFER) &&
+                !qf.queue_flags.contains(vk::QueueFlags::GRAPHICS);
+
+            // Prefer a family without compute too, that's usually the dedicated copy engine
+            queue_families.iter()
+                .position(|qf| transfer_only(qf) && !qf.queue_flags.contains(vk::QueueFlags::COMPUTE))
+                .or_else(|| queue_families.iter().position(transfer_only))
+                .map(|i| i as u32)
+        }
+
+        // Vulkan only guarantees compute on some graphics family, not necessarily the one picked
+        fn find_compute_family(instance: &Instance, physical_device: vk::PhysicalDevice, graphics_family: u32) -> Option<u32> {
+            let queue_families = unsafe { instance.get_physical_device_queue_family_properties(physical_device) };
+            queue_families.get(graphics_family as usize)
+                .filter(|qf| qf.queue_flags.contains(vk::QueueFlags::COMPUTE))
+                .map(|_| graphics_family)
         }
 
-        // Get the first physical device that satisfies the suitability check
-        // Suitability requirements:
-        // - Discrete GPU
-        // - Geometry shaders
+        // Graphics and present queue families of a device that can run the renderer, with the surface's
+        // supported present modes and formats
+        struct Candidate {
+            family_index: u32,
+            present_family_index: u32,
+            present_modes: Vec<vk::PresentModeKHR>,
+            surface_formats: Vec<vk::SurfaceFormatKHR>
+        }
+
+        // None when the device is unsuitable. Suitability requirements:
+        // - supports the required extensions
         // - supports these logical requirements:
         //      - Graphics pipelines
         //      - Can present images to the window manager surface
-        let mut queue_family_idx = 0;
-        let mut dev_found = false;
-        let mut dev_idx: usize = 0;
-        let mut present_modes: Vec<vk::PresentModeKHR> = vec![];
-        let mut surface_formats: Vec<vk::SurfaceFormatKHR> = vec![];
-
-        // For each physical device
-        for (p_idx, device) in physical_devices.iter().enumerate() {
-            let dev_properties: vk::PhysicalDeviceProperties;
-            let dev_features: vk::PhysicalDeviceFeatures;
-            unsafe {
-                dev_properties = core.instance.get_physical_device_properties(*device);
-                dev_features = core.instance.get_physical_device_features(*device);
+        //      - Bindless sampled images, see DeviceCapabilities::descriptor_indexing
+        fn check_device(core: &Core, device: vk::PhysicalDevice, required_extensions: &Vec<CString>)
+            -> Result<Option<Candidate>, RendererError> {
+            let mut present_modes: Vec<vk::PresentModeKHR> = vec![];
+            let mut surface_formats: Vec<vk::SurfaceFormatKHR> = vec![];
+
+            // Ensure that at least one kind of surface color/pixel format is supported. Headless
+            // renderers have no surface, so nothing to check.
+            if let Some(surface) = core.surface {
+                unsafe {
+                    surface_formats = core.surface_loader
+                        .get_physical_device_surface_formats(device, surface)
+                        .map_err(vk_error("vkGetPhysicalDeviceSurfaceFormatsKHR"))?;
+                    // Ensure that the desired FIFO format for pushing images to the screen is available
+                    present_modes = core.surface_loader
+                        .get_physical_device_surface_present_modes(device, surface)
+                        .map_err(vk_error("vkGetPhysicalDeviceSurfacePresentModesKHR"))?;
+                }
+            }
+
+            if !required_physical_extensions_present(&core.instance, device, required_extensions) ||
+                !DeviceCapabilities::query(&core.instance, device).descriptor_indexing ||
+                !(core.headless() || (!present_modes.is_empty() && !surface_formats.is_empty())) {
+                return Ok(None);
             }
 
-            // Ensure that at least one kind of surface color/pixel format is supported
+            let queue_families: Vec<vk::QueueFamilyProperties>;
             unsafe {
-                surface_formats = core.surface_loader
-                    .get_physical_device_surface_formats(*device, core.surface).unwrap();
-                // Ensure that the desired FIFO format for pushing images to the screen is available
-                present_modes = core.surface_loader
-                    .get_physical_device_surface_present_modes(*device, core.surface).unwrap();
+                queue_families = core.instance
+                    .get_physical_device_queue_family_properties(device);
             }
 
-            let mut queue_found = false;
-            if required_physical_extensions_present(&core.instance,
-                                                    *device,
-                                                    required_extensions) &&
-                !present_modes.is_empty() &&
-                !surface_formats.is_empty() &&
-                !present_modes.is_empty() {
-                let queue_families: Vec<vk::QueueFamilyProperties>;
-                unsafe {
-                    queue_families = core.instance
-                        .get_physical_device_queue_family_properties(*device);
-                }
+            let mut graphics_families: Vec<u32> = Vec::new();
+            let mut present_families: Vec<u32> = Vec::new();
 
-                let queue_fam_enumerator = queue_families.iter().enumerate();
-
-                // For each Queue family associated with a given device
-                for (idx, qf) in queue_fam_enumerator {
-                    // Check for graphics support
-                    let graphics_support =
-                        (qf.queue_flags & vk::QueueFlags::GRAPHICS) == vk::QueueFlags::GRAPHICS;
-                    if graphics_support {
-                        // Check family suitability
-                        let idx_u32 = idx as u32;
-                        let surface_support: bool;
-                        unsafe {
-                            surface_support = core.surface_loader
-                                .get_physical_device_surface_support(*device, idx as u32, core.surface)
-                                .unwrap();
-                        }
-                        if surface_support {
-                            queue_family_idx = idx_u32;
-                            queue_found = true;
-                            break;
-                        }
-                    }
+            // For each Queue family associated with a given device
+            for (idx, qf) in queue_families.iter().enumerate() {
+                // Headless renderers never present, so any family will do
+                let surface_support = match core.surface {
+                    Some(surface) => unsafe {
+                        core.surface_loader
+                            .get_physical_device_surface_support(device, idx as u32, surface)
+                            .map_err(vk_error("vkGetPhysicalDeviceSurfaceSupportKHR"))?
+                    },
+                    None => true
+                };
+                if qf.queue_flags.contains(vk::QueueFlags::GRAPHICS) {
+                    graphics_families.push(idx as u32);
+                }
+                if surface_support {
+                    present_families.push(idx as u32);
                 }
             }
 
-            // If the queue family and the device are suitable
-            if queue_found
-                && dev_properties.device_type == vk::PhysicalDeviceType::DISCRETE_GPU
-                && dev_features.geometry_shader != 0
-            {
-                dev_found = true;
-                dev_idx = p_idx;
-                break; // Done
-            }
-        }
+            // A single family doing both avoids sharing the swapchain images between queues
+            let shared_family = graphics_families.iter().find(|f| present_families.contains(f));
+            let families = match (shared_family, graphics_families.first(), present_families.first()) {
+                (Some(f), _, _) => Some((*f, *f)),
+                (None, Some(g), Some(p)) => Some((*g, *p)),
+                _ => None
+            };
 
-        if dev_found {
-            let physical_dependencies = PhysicalLayer {
-                physical_device: physical_devices[dev_idx],
-                family_index: queue_family_idx,
+            Ok(families.map(|(family_index, present_family_index)| Candidate {
+                family_index,
+                present_family_index,
                 present_modes,
-                supported_surface_formats: surface_formats
-            };
-            Some(physical_dependencies)
-        } else {
-            None
+                surface_formats
+            }))
+        }
+
+        let physical_devices: Vec<vk::PhysicalDevice>;
+        unsafe {
+            physical_devices = core.instance.enumerate_physical_devices()
+                .map_err(vk_error("vkEnumeratePhysicalDevices"))?;
+        }
+
+        let mut candidates: Vec<Option<Candidate>> = Vec::with_capacity(physical_devices.len());
+        for device in physical_devices.iter() {
+            candidates.push(check_device(core, *device, required_extensions)?);
         }
+        let gpus: Vec<GpuInfo> = physical_devices.iter()
+            .zip(candidates.iter())
+            .enumerate()
+            .map(|(i, (device, candidate))| GpuInfo::new(&core.instance, *device, i, candidate.is_some()))
+            .collect();
+
+        let selected = choose_gpu(&gpus, preference).ok_or(RendererError::NoSuitableDevice)?;
+        let candidate = candidates[selected].take().unwrap(); // Only suitable devices are chosen
+        let physical_device = physical_devices[selected];
+        log::info!("Using {} ({:?}, {} MiB)", gpus[selected].name, gpus[selected].device_type, gpus[selected].vram >> 20);
+
+        let transfer_family_idx = find_transfer_family(&core.instance, physical_device);
+        let compute_family_idx = find_compute_family(&core.instance, physical_device, candidate.family_index);
+        let features = unsafe { core.instance.get_physical_device_features(physical_device) };
+        let limits = unsafe { core.instance.get_physical_device_properties(physical_device) }.limits;
+        let capabilities = DeviceCapabilities::query(&core.instance, physical_device);
+        // The textureCompression features gate the formats, and they're enabled whenever supported
+        let compressed_formats = BlockFormat::ALL.iter()
+            .copied()
+            .filter(|f| [true, false].iter().all(|&srgb| {
+                let props = unsafe { core.instance.get_physical_device_format_properties(physical_device, f.vk(srgb)) };
+                props.optimal_tiling_features.contains(vk::FormatFeatureFlags::SAMPLED_IMAGE |
+                    vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR)
+            }))
+            .collect();
+        Ok(PhysicalLayer {
+            physical_device,
+            family_index: candidate.family_index,
+            present_family_index: candidate.present_family_index,
+            transfer_family_index: transfer_family_idx,
+            compute_family_index: compute_family_idx,
+            fill_mode_non_solid: features.fill_mode_non_solid != 0, // Enabled along with every other supported feature
+            indirect_draws: features.multi_draw_indirect != 0 && features.draw_indirect_first_instance != 0,
+            sample_counts: limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts,
+            capabilities,
+            compressed_formats,
+            present_modes: candidate.present_modes,
+            supported_surface_formats: candidate.surface_formats,
+            gpus,
+            selected
+        })
+    }
+
+    // Whether optimally tiled images of the format support every one of the features
+    pub(crate) fn supports_format(&self, core: &Core, format: vk::Format, features: vk::FormatFeatureFlags) -> bool {
+        let props = unsafe { core.instance.get_physical_device_format_properties(self.physical_device, format) };
+        props.optimal_tiling_features.contains(features)
+    }
+
+    // The most MSAA samples up to requested that the device supports, the flag bits are the counts themselves
+    pub(crate) fn sample_count(&self, requested: u32) -> vk::SampleCountFlags {
+        [2, 4, 8, 16, 32, 64].into_iter()
+            .map(vk::SampleCountFlags::from_raw)
+            .filter(|&s| s.as_raw() <= requested && self.sample_counts.contains(s))
+            .last()
+            .unwrap_or(vk::SampleCountFlags::TYPE_1) // Always supported
+    }
+
+    // The surface's formats in color spaces the renderer can encode for, in the order the surface reports them
+    pub(crate) fn surface_formats(&self) -> Vec<SurfaceFormat> {
+        self.supported_surface_formats.iter()
+            .filter_map(|f| ColorSpace::from_vk(f.color_space).map(|color_space| SurfaceFormat { format: f.format, color_space }))
+            .collect()
     }
 }
\ No newline at end of file
@@ -0,0 +1,74 @@
+use glam::{Mat4, Vec2, Vec3};
+
+use crate::renderer::frustum::Aabb;
+use crate::renderer::mesh::MeshHandle;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ray {
+    pub origin: Vec3,
+    pub direction: Vec3 // Unit length
+}
+
+impl Ray {
+    pub fn new(origin: Vec3, direction: Vec3) -> Ray {
+        Ray {
+            origin,
+            direction: direction.normalize()
+        }
+    }
+
+    // The ray from the near plane through a pixel, screen_pos in physical pixels from the top left as
+    // InputState::cursor_pos reports it. The projection's flipped Y already points NDC Y down the screen.
+    pub fn from_screen(view_proj: Mat4, screen_pos: Vec2, viewport_size: Vec2) -> Ray {
+        let ndc = screen_pos / viewport_size * 2.0 - Vec2::ONE;
+        let inverse = view_proj.inverse();
+        let near = inverse.project_point3(ndc.extend(0.0));
+        let far = inverse.project_point3(ndc.extend(1.0));
+
+        Ray::new(near, far - near)
+    }
+
+    pub fn at(&self, distance: f32) -> Vec3 {
+        self.origin + self.direction * distance
+    }
+
+    // Slab test. Distance along the ray to where it enters the box and the face it enters through,
+    // 0 and no normal when it starts inside.
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<(f32, Vec3)> {
+        let inv = self.direction.recip(); // Infinite on axes the ray is parallel to, which the min/max handle
+        let t0 = (aabb.min - self.origin) * inv;
+        let t1 = (aabb.max - self.origin) * inv;
+        let (near, far) = (t0.min(t1), t0.max(t1));
+        let enter = near.max_element();
+        let exit = far.min_element();
+        if enter > exit || exit < 0.0 || enter.is_nan() {
+            return None;
+        }
+        if enter < 0.0 {
+            return Some((0.0, Vec3::ZERO));
+        }
+
+        let axis = match (enter == near.x, enter == near.y) {
+            (true, _) => Vec3::X,
+            (false, true) => Vec3::Y,
+            (false, false) => Vec3::Z
+        };
+        Some((enter, axis * -self.direction.dot(axis).signum()))
+    }
+}
+
+// What a pick hit, indexing into the render queue the pick was made against
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PickTarget {
+    Item(usize), // RenderQueue::items
+    Instance { item: usize, id: u32 } // RenderQueue::instanced_items, and the hit instance's Instance::id
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hit {
+    pub target: PickTarget,
+    pub mesh: MeshHandle,
+    pub distance: f32, // Along the ray from the near plane
+    pub point: Vec3,
+    pub normal: Vec3 // Of the bounding box face that was hit, zero when the camera is inside the box
+}
@@ -0,0 +1,604 @@
+use std::mem;
+use std::path::PathBuf;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::config::ColorSpace;
+use crate::renderer::core::Core;
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::physical_layer::PhysicalLayer;
+use crate::renderer::raster_pipeline::RasterPipeline;
+use crate::renderer::render_pass::{setup_post_render_pass, PassTarget};
+use crate::renderer::render_target::RenderTarget;
+use crate::renderer::shader::{ShaderSet, ShaderSource};
+
+// Float formats in order of preference, so lighting isn't clamped until tonemapping
+const HDR_FORMATS: [vk::Format; 2] = [vk::Format::R16G16B16A16_SFLOAT, vk::Format::B10G11R11_UFLOAT_PACK32];
+const LDR_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB; // sRGB storage keeps dark gradients from banding
+
+// Format of the scene and every post-process target
+pub(crate) fn choose_scene_format(core: &Core, physical_layer: &PhysicalLayer, hdr: bool) -> vk::Format {
+    let required = vk::FormatFeatureFlags::COLOR_ATTACHMENT |
+        vk::FormatFeatureFlags::SAMPLED_IMAGE |
+        vk::FormatFeatureFlags::SAMPLED_IMAGE_FILTER_LINEAR; // The bloom is upsampled
+    if !hdr {
+        return LDR_FORMAT;
+    }
+
+    match HDR_FORMATS.iter().copied().find(|&f| physical_layer.supports_format(core, f, required)) {
+        Some(f) => f,
+        None => {
+            log::warn!("No float color format can be rendered to, lighting will clip at 1");
+            LDR_FORMAT // Required to support all of the above
+        }
+    }
+}
+
+pub(crate) fn is_hdr(format: vk::Format) -> bool {
+    HDR_FORMATS.contains(&format)
+}
+
+// Formats the hardware sRGB encodes on write, anything else in a nonlinear color space is encoded by the blit
+fn encodes_srgb(format: vk::Format) -> bool {
+    [vk::Format::B8G8R8A8_SRGB, vk::Format::R8G8B8A8_SRGB, vk::Format::A8B8G8R8_SRGB_PACK32].contains(&format)
+}
+
+// Matches the color space numbering in blit.frag
+fn color_space_index(color_space: ColorSpace) -> f32 {
+    match color_space {
+        ColorSpace::Srgb => 0.0,
+        ColorSpace::DisplayP3 => 1.0,
+        ColorSpace::Hdr10 => 2.0,
+        ColorSpace::ScRgb => 3.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tonemapper {
+    Aces, // Narkowicz's fit of the ACES filmic curve, more contrast and desaturated highlights
+    Reinhard // x / (1 + x), flatter but never shifts hues
+}
+
+// One step of the post-process chain, applied in order to the HDR scene
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PostEffect {
+    Bloom {
+        threshold: f32, // Brightness above which pixels bleed into their surroundings
+        intensity: f32
+    },
+    Tonemap {
+        operator: Tonemapper,
+        exposure: f32 // Scales the scene before the curve
+    },
+    Fxaa // Expects tonemapped input, so it should come after Tonemap
+}
+
+impl PostEffect {
+    pub fn default_chain() -> Vec<PostEffect> {
+        vec![PostEffect::Bloom { threshold: 1.0, intensity: 0.3 },
+             PostEffect::Tonemap { operator: Tonemapper::Aces, exposure: 1.0 },
+             PostEffect::Fxaa]
+    }
+}
+
+// Indices into PostProcess::pipelines
+const THRESHOLD: usize = 0;
+const BLUR: usize = 1;
+const COMPOSITE: usize = 2;
+const TONEMAP: usize = 3;
+const FXAA: usize = 4;
+const BLIT: usize = 5; // Built against the present pass rather than the post pass
+
+// Matches the PostParams push constant block in the post shaders
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Pod, Zeroable)]
+struct PostParams {
+    texel_size: [f32; 2], // Of the input
+    direction: [f32; 2], // Blur axis
+    params: [f32; 4] // Per effect
+}
+
+// A color image rendered by one pass and sampled by the next
+struct PostTarget {
+    image: vk::Image,
+    alloc: Allocation,
+    view: vk::ImageView,
+    framebuffer: vk::Framebuffer, // Null for a scene target rendered with dynamic rendering
+    extent: vk::Extent2D
+}
+
+impl PostTarget {
+    // scene_attachments are attached after the color image, the depth buffer and any MSAA image for the scene
+    // target. Without a render pass no framebuffer is made.
+    fn new(logical_layer: &LogicalLayer, allocator: &Allocator, render_pass: Option<vk::RenderPass>, format: vk::Format,
+           extent: vk::Extent2D, scene_attachments: &[vk::ImageView]) -> Result<PostTarget, RendererError> {
+        let create_info = vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let (alloc, image) = allocator.create_image(logical_layer, &create_info, vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                    MemoryCategory::RenderTargets)?;
+
+        let view_create_info = vk::ImageViewCreateInfo::default()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1
+            });
+        let view = match unsafe { logical_layer.logical_device.create_image_view(&view_create_info, None) } {
+            Ok(v) => v,
+            Err(e) => {
+                allocator.destroy_image(logical_layer, image, &alloc);
+                return Err(vk_error("vkCreateImageView")(e));
+            }
+        };
+
+        let render_pass = match render_pass {
+            Some(p) => p,
+            None => return Ok(PostTarget { image, alloc, view, framebuffer: vk::Framebuffer::null(), extent })
+        };
+        let attachments: Vec<vk::ImageView> = [view].into_iter().chain(scene_attachments.iter().copied()).collect();
+        let framebuffer_create_info = vk::FramebufferCreateInfo::default()
+            .render_pass(render_pass)
+            .attachments(&attachments)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let framebuffer = match unsafe { logical_layer.logical_device.create_framebuffer(&framebuffer_create_info, None) } {
+            Ok(f) => f,
+            Err(e) => {
+                unsafe { logical_layer.logical_device.destroy_image_view(view, None) };
+                allocator.destroy_image(logical_layer, image, &alloc);
+                return Err(vk_error("vkCreateFramebuffer")(e));
+            }
+        };
+
+        Ok(PostTarget {
+            image,
+            alloc,
+            view,
+            framebuffer,
+            extent
+        })
+    }
+
+    fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        unsafe {
+            logical_layer.logical_device.destroy_framebuffer(self.framebuffer, None);
+            logical_layer.logical_device.destroy_image_view(self.view, None);
+        }
+        allocator.destroy_image(logical_layer, self.image, &self.alloc);
+    }
+}
+
+// Every target that depends on the swapchain's size
+struct PostTargets {
+    scene: PostTarget, // Rendered by the main pass, with the render target's depth buffer
+    ping_pong: [PostTarget; 2], // Full size effects alternate between these
+    bloom: [PostTarget; 2] // Half size, for the blur
+}
+
+#[derive(Clone, Copy)]
+enum Output {
+    PingPong(usize),
+    Bloom(usize)
+}
+
+struct PostStep {
+    pipeline: usize,
+    descriptor_set: vk::DescriptorSet,
+    output: Output,
+    params: PostParams
+}
+
+// Runs the effect chain over the scene target and copies the result to the swapchain, tonemapping it
+// there if the scene is HDR and the chain doesn't already. Every pass draws a fullscreen triangle
+// reading set 0: the input at binding 0, a second input at binding 1 (the blurred bloom for its
+// composite) and a linear clamped sampler at binding 2.
+pub(crate) struct PostProcess {
+    pub(crate) format: vk::Format, // Of the scene and every target
+    render_pass: vk::RenderPass,
+    pipelines: Vec<RasterPipeline>,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool, // Recreated whenever the steps are rebuilt
+    sampler: vk::Sampler,
+    effects: Vec<PostEffect>,
+    targets: Option<PostTargets>, // None between destroy_targets and resize
+    steps: Vec<PostStep>,
+    blit_set: vk::DescriptorSet, // Reads the end of the chain
+    blit_params: PostParams,
+    output: (ColorSpace, bool), // The swapchain's color space and whether the blit has to sRGB encode
+    paper_white: f32 // Nits for scene value 1 on HDR outputs
+}
+
+impl PostProcess {
+    // scene_pass is None when the scene is drawn with dynamic rendering
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, format: vk::Format, scene_pass: Option<vk::RenderPass>,
+                      present_pass: vk::RenderPass, render_target: &RenderTarget, effects: &[PostEffect],
+                      paper_white: f32) -> Result<PostProcess, RendererError> {
+        fn setup_set_layout(logical_layer: &LogicalLayer) -> Result<vk::DescriptorSetLayout, RendererError> {
+            let bindings = [
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(0)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(1)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(2)
+                    .descriptor_type(vk::DescriptorType::SAMPLER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            ];
+            let create_info = vk::DescriptorSetLayoutCreateInfo::default()
+                .bindings(&bindings);
+
+            unsafe {
+                logical_layer.logical_device.create_descriptor_set_layout(&create_info, None)
+                    .map_err(vk_error("vkCreateDescriptorSetLayout"))
+            }
+        }
+
+        fn setup_sampler(logical_layer: &LogicalLayer) -> Result<vk::Sampler, RendererError> {
+            let create_info = vk::SamplerCreateInfo::default()
+                .mag_filter(vk::Filter::LINEAR) // The bloom is upsampled and the blur taps between texels
+                .min_filter(vk::Filter::LINEAR)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE) // No bleeding in from the opposite edge
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .max_lod(0.0);
+
+            unsafe { logical_layer.logical_device.create_sampler(&create_info, None).map_err(vk_error("vkCreateSampler")) }
+        }
+
+        let render_pass = setup_post_render_pass(logical_layer, format)?;
+        let set_layout = setup_set_layout(logical_layer)?;
+        let sampler = setup_sampler(logical_layer)?;
+
+        let push_constant_range = vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .offset(0)
+            .size(mem::size_of::<PostParams>() as u32);
+        // In THRESHOLD, BLUR, COMPOSITE, TONEMAP, FXAA, BLIT order
+        let fragments = ["bloom_threshold.frag", "blur.frag", "bloom_composite.frag", "tonemap.frag", "fxaa.frag", "blit.frag"];
+        let mut pipelines: Vec<RasterPipeline> = Vec::with_capacity(fragments.len());
+        for (i, fragment) in fragments.iter().enumerate() {
+            let shaders = ShaderSet {
+                vertex: ShaderSource::GlslFile(PathBuf::from("shaders/src/fullscreen.vert")),
+                fragment: ShaderSource::GlslFile(PathBuf::from("shaders/src").join(fragment))
+            };
+            let pass = match i {
+                BLIT => present_pass,
+                _ => render_pass
+            };
+            pipelines.push(RasterPipeline::new(logical_layer, PassTarget::single(pass), &shaders, &[], &[set_layout], Some(push_constant_range))?);
+        }
+
+        let mut post = PostProcess {
+            format,
+            render_pass,
+            pipelines,
+            set_layout,
+            descriptor_pool: vk::DescriptorPool::null(),
+            sampler,
+            effects: effects.to_vec(),
+            targets: None,
+            steps: Vec::new(),
+            blit_set: vk::DescriptorSet::null(),
+            blit_params: PostParams::default(),
+            output: (ColorSpace::Srgb, false),
+            paper_white
+        };
+        post.resize(logical_layer, allocator, scene_pass, render_target)?;
+
+        Ok(post)
+    }
+
+    // Recreates the targets at the render target's size. The GPU must not be using the old ones.
+    pub(crate) fn resize(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, scene_pass: Option<vk::RenderPass>,
+                         render_target: &RenderTarget) -> Result<(), RendererError> {
+        self.destroy_targets(logical_layer, allocator);
+
+        let extent = render_target.scene_extent;
+        let half_extent = vk::Extent2D {
+            width: (extent.width / 2).max(1),
+            height: (extent.height / 2).max(1)
+        };
+        let target = |pass: Option<vk::RenderPass>, extent: vk::Extent2D, scene_attachments: &[vk::ImageView]| {
+            PostTarget::new(logical_layer, allocator, pass, self.format, extent, scene_attachments)
+        };
+        // In the order setup_render_pass numbers them
+        let scene_attachments: Vec<vk::ImageView> = match render_target.multisampled() {
+            true => vec![render_target.depth_view, render_target.msaa_view],
+            false => vec![render_target.depth_view]
+        };
+
+        // Created one at a time so a failure only has to clean up the ones before it
+        let mut created: Vec<PostTarget> = Vec::with_capacity(5);
+        let result: Result<(), RendererError> = (|| {
+            created.push(target(scene_pass, extent, &scene_attachments)?);
+            created.push(target(Some(self.render_pass), extent, &[])?);
+            created.push(target(Some(self.render_pass), extent, &[])?);
+            created.push(target(Some(self.render_pass), half_extent, &[])?);
+            created.push(target(Some(self.render_pass), half_extent, &[])?);
+            Ok(())
+        })();
+        if let Err(e) = result {
+            for t in created.iter() {
+                t.destroy(logical_layer, allocator);
+            }
+            return Err(e);
+        }
+
+        let mut created = created.into_iter();
+        let mut next = || created.next().unwrap();
+        self.targets = Some(PostTargets {
+            scene: next(),
+            ping_pong: [next(), next()],
+            bloom: [next(), next()]
+        });
+        let color_space = render_target.color_space;
+        self.output = (color_space, !color_space.is_hdr() && !encodes_srgb(render_target.surface_format));
+
+        self.build_steps(logical_layer)
+    }
+
+    // Replaces the chain. The GPU must not be using the old descriptor sets.
+    pub(crate) fn set_effects(&mut self, logical_layer: &LogicalLayer, effects: &[PostEffect]) -> Result<(), RendererError> {
+        self.effects = effects.to_vec();
+        self.build_steps(logical_layer)
+    }
+
+    pub(crate) fn effects(&self) -> &[PostEffect] {
+        &self.effects
+    }
+
+    // The main pass renders into this, with the render target's depth buffer
+    pub(crate) fn scene_framebuffer(&self) -> vk::Framebuffer {
+        self.targets.as_ref().expect("Post-process targets are missing").scene.framebuffer
+    }
+
+    // What dynamic rendering attaches in place of scene_framebuffer
+    pub(crate) fn scene_image(&self) -> (vk::Image, vk::ImageView) {
+        let scene = &self.targets.as_ref().expect("Post-process targets are missing").scene;
+        (scene.image, scene.view)
+    }
+
+    fn build_steps(&mut self, logical_layer: &LogicalLayer) -> Result<(), RendererError> {
+        let targets = self.targets.as_ref().expect("Post-process targets are missing");
+        let texel_size = |t: &PostTarget| [1.0 / t.extent.width as f32, 1.0 / t.extent.height as f32];
+        let output_target = |o: Output| match o {
+            Output::PingPong(i) => &targets.ping_pong[i],
+            Output::Bloom(i) => &targets.bloom[i]
+        };
+
+        // (pipeline, input, second input, output, params) of every pass
+        let mut passes: Vec<(usize, &PostTarget, &PostTarget, Output, PostParams)> = Vec::new();
+        let mut current = &targets.scene;
+        let mut next = 0;
+        for effect in self.effects.iter() {
+            let output = Output::PingPong(next);
+            match *effect {
+                PostEffect::Bloom { threshold, intensity } => {
+                    let bloom = &targets.bloom;
+                    let blur_params = |direction: [f32; 2]| PostParams {
+                        texel_size: texel_size(&bloom[0]),
+                        direction,
+                        params: [0.0; 4]
+                    };
+                    passes.push((THRESHOLD, current, current, Output::Bloom(0), PostParams {
+                        texel_size: texel_size(current),
+                        params: [threshold, 0.0, 0.0, 0.0],
+                        ..Default::default()
+                    }));
+                    passes.push((BLUR, &bloom[0], &bloom[0], Output::Bloom(1), blur_params([1.0, 0.0])));
+                    passes.push((BLUR, &bloom[1], &bloom[1], Output::Bloom(0), blur_params([0.0, 1.0])));
+                    passes.push((COMPOSITE, current, &bloom[0], output, PostParams {
+                        texel_size: texel_size(current),
+                        params: [intensity, 0.0, 0.0, 0.0],
+                        ..Default::default()
+                    }));
+                },
+                PostEffect::Tonemap { operator, exposure } => {
+                    let operator = match operator {
+                        Tonemapper::Aces => 0.0,
+                        Tonemapper::Reinhard => 1.0
+                    };
+                    passes.push((TONEMAP, current, current, output, PostParams {
+                        texel_size: texel_size(current),
+                        params: [operator, exposure, 0.0, 0.0],
+                        ..Default::default()
+                    }));
+                },
+                PostEffect::Fxaa => {
+                    passes.push((FXAA, current, current, output, PostParams {
+                        texel_size: texel_size(current),
+                        ..Default::default()
+                    }));
+                }
+            }
+            current = output_target(output);
+            next ^= 1;
+        }
+
+        // One set per pass plus the blit
+        let set_count = passes.len() as u32 + 1;
+        let pool_sizes = [
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(set_count * 2),
+            vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::SAMPLER)
+                .descriptor_count(set_count)
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+            .pool_sizes(&pool_sizes)
+            .max_sets(set_count);
+        let pool = unsafe {
+            logical_layer.logical_device.create_descriptor_pool(&pool_create_info, None)
+                .map_err(vk_error("vkCreateDescriptorPool"))?
+        };
+        let layouts = vec![self.set_layout; set_count as usize];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(pool)
+            .set_layouts(&layouts);
+        let sets = match unsafe { logical_layer.logical_device.allocate_descriptor_sets(&alloc_info) } {
+            Ok(s) => s,
+            Err(e) => {
+                unsafe { logical_layer.logical_device.destroy_descriptor_pool(pool, None) };
+                return Err(vk_error("vkAllocateDescriptorSets")(e));
+            }
+        };
+
+        let inputs = passes.iter().map(|(_, a, b, _, _)| (a.view, b.view)).chain([(current.view, current.view)]);
+        for (set, (first, second)) in sets.iter().zip(inputs) {
+            let image_info = |view: vk::ImageView| [vk::DescriptorImageInfo::default()
+                .image_view(view)
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+            let (first_infos, second_infos) = (image_info(first), image_info(second));
+            let sampler_infos = [vk::DescriptorImageInfo::default()
+                .sampler(self.sampler)];
+            let writes = [
+                vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .image_info(&first_infos),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .image_info(&second_infos),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(*set)
+                    .dst_binding(2)
+                    .descriptor_type(vk::DescriptorType::SAMPLER)
+                    .image_info(&sampler_infos)
+            ];
+            unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+        }
+
+        let steps: Vec<PostStep> = passes.iter()
+            .zip(sets.iter())
+            .map(|((pipeline, _, _, output, params), set)| PostStep {
+                pipeline: *pipeline,
+                descriptor_set: *set,
+                output: *output,
+                params: *params
+            })
+            .collect();
+
+        // Float values above 1 would clip in an SDR swapchain, so the blit tonemaps if nothing before it did.
+        // HDR swapchains show them as they are.
+        let tonemapped = self.effects.iter().any(|e| matches!(e, PostEffect::Tonemap { .. }));
+        let (color_space, encode_srgb) = self.output;
+        let blit_params = PostParams {
+            texel_size: texel_size(current),
+            direction: [color_space_index(color_space), encode_srgb as u32 as f32],
+            params: [self.paper_white, 1.0, (is_hdr(self.format) && !tonemapped && !color_space.is_hdr()) as u32 as f32, 0.0] // ACES at exposure 1
+        };
+
+        unsafe { logical_layer.logical_device.destroy_descriptor_pool(self.descriptor_pool, None) }; // Null the first time
+        self.descriptor_pool = pool;
+        self.blit_set = sets[sets.len() - 1];
+        self.blit_params = blit_params;
+        self.steps = steps;
+
+        Ok(())
+    }
+
+    fn draw(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, render_pass: vk::RenderPass,
+            framebuffer: vk::Framebuffer, extent: vk::Extent2D, pipeline: usize, descriptor_set: vk::DescriptorSet,
+            params: &PostParams, end_pass: bool) {
+        let render_area = vk::Rect2D::default().extent(extent);
+        let render_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(render_pass)
+            .framebuffer(framebuffer)
+            .render_area(render_area); // Nothing is cleared
+        let viewports = [vk::Viewport::default()
+            .width(extent.width as f32)
+            .height(extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)];
+        let scissors = [render_area];
+        let descriptor_sets = [descriptor_set];
+        let pipeline = &self.pipelines[pipeline];
+
+        unsafe {
+            let device = &logical_layer.logical_device;
+            device.cmd_begin_render_pass(command_buffer, &render_pass_info, vk::SubpassContents::INLINE);
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.pipelines[0]);
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.pipeline_layout,
+                                            0, &descriptor_sets, &[]);
+            device.cmd_set_viewport(command_buffer, 0, &viewports);
+            device.cmd_set_scissor(command_buffer, 0, &scissors);
+            pipeline.push_constants(logical_layer, command_buffer, 0, params);
+            device.cmd_draw(command_buffer, 3, 1, 0, 0); // Fullscreen triangle generated from the vertex index
+            if end_pass {
+                device.cmd_end_render_pass(command_buffer);
+            }
+        }
+    }
+
+    // Records every effect then the blit into the swapchain framebuffer, after the main pass has ended.
+    // The present pass is left open for the overlay, the caller ends it.
+    pub(crate) fn record(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, present_pass: vk::RenderPass,
+                         present_framebuffer: vk::Framebuffer, present_extent: vk::Extent2D) {
+        let targets = self.targets.as_ref().expect("Post-process targets are missing");
+        for step in self.steps.iter() {
+            let output = match step.output {
+                Output::PingPong(i) => &targets.ping_pong[i],
+                Output::Bloom(i) => &targets.bloom[i]
+            };
+            self.draw(logical_layer, command_buffer, self.render_pass, output.framebuffer, output.extent, step.pipeline,
+                      step.descriptor_set, &step.params, true);
+        }
+
+        self.draw(logical_layer, command_buffer, present_pass, present_framebuffer, present_extent, BLIT, self.blit_set,
+                  &self.blit_params, false);
+    }
+
+    // Targets are taken so destroying twice, I.E. after a failed resize, is harmless
+    pub(crate) fn destroy_targets(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        if let Some(targets) = self.targets.take() {
+            targets.scene.destroy(logical_layer, allocator);
+            for t in targets.ping_pong.iter().chain(targets.bloom.iter()) {
+                t.destroy(logical_layer, allocator);
+            }
+        }
+    }
+
+    pub(crate) fn destroy(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        self.destroy_targets(logical_layer, allocator);
+        for p in self.pipelines.iter_mut() {
+            p.destroy(logical_layer);
+        }
+        unsafe {
+            logical_layer.logical_device.destroy_descriptor_pool(self.descriptor_pool, None); // Frees the sets as well
+            logical_layer.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+            logical_layer.logical_device.destroy_sampler(self.sampler, None);
+            logical_layer.logical_device.destroy_render_pass(self.render_pass, None);
+        }
+    }
+}
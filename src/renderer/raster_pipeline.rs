@@ -5,72 +5,190 @@ use std::marker::PhantomData;
 use std::mem;
 
 use ash::vk;
+use bytemuck::Pod;
+use naga::ShaderStage;
 
 use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::render_pass::PassTarget;
 use crate::renderer::render_target::RenderTarget;
-use crate::renderer::vertex::Vertex;
-
-fn load_shader(path: &str) -> Result<Vec<u8>, String> {
-    let mut buf = Vec::new();
-    let mut file = File::open(path).unwrap();
-    let filesize = file.seek(SeekFrom::End(0)).unwrap();
-    file.seek(SeekFrom::Start(0)).unwrap();
-    let size = file.read_to_end(&mut buf).unwrap();
-
-    match filesize == size as u64 && (filesize % mem::size_of::<u32>() as u64) == 0 {
-        true => Ok(buf),
-        false => Err(String::from("Failed to read ") + path)
-    }
+use crate::renderer::shader::{compile, read_spirv, CompiledShader, ShaderError, ShaderSet, ShaderSource};
+use crate::renderer::vertex::VertexLayout;
+
+pub(crate) const SHADER_SRC_DIR: &str = "shaders/src";
+pub(crate) const SHADER_SPV_DIR: &str = "shaders/spv";
+
+type ShaderModules = Vec<(vk::ShaderModule, CompiledShader, vk::ShaderStageFlags)>;
+
+fn load_all_shaders(logical_layer: &LogicalLayer, shaders: &ShaderSet) -> Result<ShaderModules, ShaderError> {
+    // Compile everything up front so a bad stage doesn't leak the modules created before it
+    let compiled = [(compile(&shaders.vertex, ShaderStage::Vertex)?, vk::ShaderStageFlags::VERTEX),
+        (compile(&shaders.fragment, ShaderStage::Fragment)?, vk::ShaderStageFlags::FRAGMENT)];
+
+    Ok(create_modules(logical_layer, compiled))
 }
 
+// Task and mesh shaders are precompiled SPIR-V in SHADER_SPV_DIR, naga can't compile them
+fn load_mesh_shaders(logical_layer: &LogicalLayer, task: &str, mesh: &str,
+                     fragment: &ShaderSource) -> Result<ShaderModules, ShaderError> {
+    let spv_dir = std::path::Path::new(SHADER_SPV_DIR);
+    let compiled = [(read_spirv(&spv_dir.join(task))?, vk::ShaderStageFlags::TASK_EXT),
+        (read_spirv(&spv_dir.join(mesh))?, vk::ShaderStageFlags::MESH_EXT),
+        (compile(fragment, ShaderStage::Fragment)?, vk::ShaderStageFlags::FRAGMENT)];
 
-fn load_all_shaders(logical_layer: &LogicalLayer) -> Vec<vk::ShaderModule> {
-    let shader_paths = ["shaders/spv/vert.spv", "shaders/spv/frag.spv"];
+    Ok(create_modules(logical_layer, compiled))
+}
 
-    let mut shader_modules: Vec<vk::ShaderModule> = Vec::with_capacity(shader_paths.len());
-    for sp in shader_paths.iter() {
-        let shader_spv = load_shader(sp).unwrap();
-        let shader_create_info = vk::ShaderModuleCreateInfo {
-            s_type: vk::StructureType::SHADER_MODULE_CREATE_INFO,
-            p_next: std::ptr::null(),
-            flags: vk::ShaderModuleCreateFlags::default(),
-            code_size: shader_spv.len(),
-            p_code: shader_spv.as_ptr().cast::<u32>(),
-            _marker: PhantomData
-        };
-        shader_modules.push(unsafe {
+fn create_modules<const N: usize>(logical_layer: &LogicalLayer, compiled: [(CompiledShader, vk::ShaderStageFlags); N]) -> ShaderModules {
+    let mut shader_modules: ShaderModules = Vec::with_capacity(compiled.len());
+    for (shader, stage) in compiled {
+        let shader_create_info = vk::ShaderModuleCreateInfo::default()
+            .code(&shader.code);
+        let module = unsafe {
             logical_layer.logical_device.create_shader_module(&shader_create_info, None).unwrap()
-        });
+        };
+        shader_modules.push((module, shader, stage));
     }
 
     shader_modules
 }
 
-fn setup_pipeline_layout(logical_layer: &LogicalLayer) -> vk::PipelineLayout {
-    let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::default();
+const MIN_PUSH_CONSTANTS_SIZE: u32 = 128; // maxPushConstantsSize is guaranteed to be at least this large
+
+fn setup_pipeline_layout(logical_layer: &LogicalLayer,
+                         set_layouts: &[vk::DescriptorSetLayout],
+                         push_constant_range: Option<vk::PushConstantRange>) -> vk::PipelineLayout {
+    let push_constant_ranges: Vec<vk::PushConstantRange> = push_constant_range.into_iter().collect();
+
+    let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::default()
+        .set_layouts(set_layouts) // Descriptor sets the shaders can access, I.E. uniform buffers
+        .push_constant_ranges(&push_constant_ranges);
 
     unsafe {
         logical_layer.logical_device.create_pipeline_layout(&pipeline_layout_create_info, None).unwrap() }
 }
 
+// Fixed function state that differs between pipelines sharing shaders
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct RasterState {
+    pub(crate) topology: vk::PrimitiveTopology,
+    pub(crate) polygon_mode: vk::PolygonMode,
+    pub(crate) cull_mode: vk::CullModeFlags,
+    pub(crate) depth_test: bool,
+    pub(crate) depth_write: bool, // Only when depth_test is set too
+    pub(crate) depth_compare: vk::CompareOp, // LESS, or LESS_OR_EQUAL for passes at the far plane
+    pub(crate) additive: bool, // Sums fragments instead of alpha blending them
+    pub(crate) query_only: bool, // Depth tests without writing depth or color, for occlusion queries
+    pub(crate) color_targets: u32 // Color attachments written, blending only applies with a single one
+}
+
+impl RasterState {
+    pub(crate) const LINES: RasterState = RasterState {
+        topology: vk::PrimitiveTopology::LINE_LIST,
+        polygon_mode: vk::PolygonMode::FILL, // Lines ignore the polygon mode
+        cull_mode: vk::CullModeFlags::NONE,
+        depth_test: true,
+        depth_write: true,
+        depth_compare: vk::CompareOp::LESS,
+        additive: false,
+        query_only: false,
+        color_targets: 1
+    };
+
+    // Bounding boxes drawn for occlusion queries, the winding of their faces is arbitrary
+    pub(crate) const OCCLUSION_PROXY: RasterState = RasterState {
+        topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+        polygon_mode: vk::PolygonMode::FILL,
+        cull_mode: vk::CullModeFlags::NONE,
+        depth_test: true,
+        depth_write: true,
+        depth_compare: vk::CompareOp::LESS,
+        additive: false,
+        query_only: true,
+        color_targets: 1
+    };
+
+    // Alpha blended materials, tested against the opaque geometry's depth but leaving it untouched so
+    // surfaces behind them still blend in
+    pub(crate) const TRANSPARENT: RasterState = RasterState {
+        topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+        polygon_mode: vk::PolygonMode::FILL,
+        cull_mode: vk::CullModeFlags::BACK,
+        depth_test: true,
+        depth_write: false,
+        depth_compare: vk::CompareOp::LESS,
+        additive: false,
+        query_only: false,
+        color_targets: 1
+    };
+}
+
+impl Default for RasterState {
+    fn default() -> Self {
+        RasterState {
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            polygon_mode: vk::PolygonMode::FILL,
+            cull_mode: vk::CullModeFlags::BACK,
+            depth_test: true,
+            depth_write: true,
+            depth_compare: vk::CompareOp::LESS,
+            additive: false,
+            query_only: false,
+            color_targets: 1
+        }
+    }
+}
+
 pub(crate) struct RasterPipeline {
-    pipeline_layout: vk::PipelineLayout,
+    pub(crate) pipeline_layout: vk::PipelineLayout,
     pub(crate) pipelines: Vec<vk::Pipeline>,
+    push_constant_range: Option<vk::PushConstantRange> // Small per draw data, I.E. model matrices
 }
 
 impl RasterPipeline {
-    pub(crate) fn new(logical_layer: &LogicalLayer, render_pass: vk::RenderPass) -> RasterPipeline {
-        fn setup_pipeline_stages(shader_modules: &Vec<vk::ShaderModule>) -> Vec<vk::PipelineShaderStageCreateInfo> {
-            // Reminder that shader modules are in [vert, frag] order
-            let create_bits = [vk::ShaderStageFlags::VERTEX,
-                vk::ShaderStageFlags::FRAGMENT];
+    pub(crate) fn new(logical_layer: &LogicalLayer, target: PassTarget,
+                      shaders: &ShaderSet,
+                      vertex_layouts: &[VertexLayout],
+                      set_layouts: &[vk::DescriptorSetLayout],
+                      push_constant_range: Option<vk::PushConstantRange>) -> Result<RasterPipeline, ShaderError> {
+        Self::with_state(logical_layer, target, shaders, vertex_layouts, set_layouts, push_constant_range,
+                         RasterState::default())
+    }
+
+    pub(crate) fn with_state(logical_layer: &LogicalLayer, target: PassTarget,
+                             shaders: &ShaderSet,
+                             vertex_layouts: &[VertexLayout],
+                             set_layouts: &[vk::DescriptorSetLayout],
+                             push_constant_range: Option<vk::PushConstantRange>,
+                             state: RasterState) -> Result<RasterPipeline, ShaderError> {
+        let shader_modules = load_all_shaders(logical_layer, shaders)?;
+        Ok(Self::from_modules(logical_layer, target, shader_modules, Some(vertex_layouts), set_layouts, push_constant_range, state))
+    }
+
+    // The task and mesh shaders in SHADER_SPV_DIR replace the vertex stage, so there are no vertex inputs.
+    // Needs mesh shading enabled on the logical layer.
+    pub(crate) fn with_mesh_shaders(logical_layer: &LogicalLayer, target: PassTarget,
+                                    task: &str, mesh: &str, fragment: &ShaderSource,
+                                    set_layouts: &[vk::DescriptorSetLayout],
+                                    push_constant_range: Option<vk::PushConstantRange>,
+                                    state: RasterState) -> Result<RasterPipeline, ShaderError> {
+        let shader_modules = load_mesh_shaders(logical_layer, task, mesh, fragment)?;
+        Ok(Self::from_modules(logical_layer, target, shader_modules, None, set_layouts, push_constant_range, state))
+    }
+
+    // Destroys the modules once the pipeline is built. vertex_layouts is None for mesh shading pipelines.
+    fn from_modules(logical_layer: &LogicalLayer, target: PassTarget,
+                    shader_modules: ShaderModules,
+                    vertex_layouts: Option<&[VertexLayout]>,
+                    set_layouts: &[vk::DescriptorSetLayout],
+                    push_constant_range: Option<vk::PushConstantRange>,
+                    state: RasterState) -> RasterPipeline {
+        fn setup_pipeline_stages(shader_modules: &ShaderModules) -> Vec<vk::PipelineShaderStageCreateInfo> {
             let mut create_info: Vec<vk::PipelineShaderStageCreateInfo> = Vec::with_capacity(
                 shader_modules.len());
-            for (sm, flag) in shader_modules.iter()
-                .zip(create_bits) {
+            for (sm, compiled, flag) in shader_modules.iter() {
                 create_info.push(vk::PipelineShaderStageCreateInfo::default()
-                    .name(CStr::from_bytes_with_nul(b"main\0").unwrap())
-                    .stage(flag)
+                    .name(compiled.entry_point.as_c_str())
+                    .stage(*flag)
                     .module(*sm)
                 );
             }
@@ -78,19 +196,32 @@ impl RasterPipeline {
             create_info
         }
 
-        let shader_modules = load_all_shaders(logical_layer);
+        if let Some(range) = push_constant_range {
+            assert!(range.offset + range.size <= MIN_PUSH_CONSTANTS_SIZE, "Push constant range exceeds the guaranteed limit");
+        }
 
         let pipeline_stages = setup_pipeline_stages(&shader_modules);
 
-        let vertex_binding_descriptions = [Vertex::get_binding_description()];
-        let vertex_attribute_descriptions = &Vertex::get_attribute_descriptions();
+        // Each layout gets the binding matching its index
+        let vertex_binding_descriptions: Vec<vk::VertexInputBindingDescription> = vertex_layouts
+            .unwrap_or_default()
+            .iter()
+            .enumerate()
+            .map(|(i, l)| l.binding_description(i as u32))
+            .collect();
+        let vertex_attribute_descriptions: Vec<vk::VertexInputAttributeDescription> = vertex_layouts
+            .unwrap_or_default()
+            .iter()
+            .enumerate()
+            .flat_map(|(i, l)| l.attribute_descriptions(i as u32))
+            .collect();
 
         let vertex_inputs = vk::PipelineVertexInputStateCreateInfo::default() // Describe the format of each Vertex buffer entry
-            .vertex_attribute_descriptions(vertex_attribute_descriptions)
+            .vertex_attribute_descriptions(&vertex_attribute_descriptions)
             .vertex_binding_descriptions(&vertex_binding_descriptions);
 
         let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::default()
-            .topology(vk::PrimitiveTopology::TRIANGLE_LIST) // Triangle from every three vertices
+            .topology(state.topology) // I.E. a triangle from every three vertices
             .primitive_restart_enable(false); // ??
 
         let viewport_state = vk::PipelineViewportStateCreateInfo::default()
@@ -100,10 +231,10 @@ impl RasterPipeline {
         let rasterization_state = vk::PipelineRasterizationStateCreateInfo::default()
             .depth_clamp_enable(false) // Clamps (?) fragments beyond the far and near planes to said planes
             .rasterizer_discard_enable(false) // Makes geometry not pass through the rasterizer
-            .polygon_mode(vk::PolygonMode::FILL) // Determines whether polygons are represented as points, lines or surfaces
+            .polygon_mode(state.polygon_mode) // Determines whether polygons are represented as points, lines or surfaces
             .line_width(1.0) // Line thickness in units of fragment numbers (probably roughly equivalent to pixels?)
-            .cull_mode(vk::CullModeFlags::BACK) // Cull the back faces of geometry
-            .front_face(vk::FrontFace::CLOCKWISE) // Rules for determining if a face is front ??
+            .cull_mode(state.cull_mode) // Usually the back faces of geometry
+            .front_face(vk::FrontFace::COUNTER_CLOCKWISE) // Counter clockwise since the projection matrix flips Y
             .depth_bias_enable(false) // Parameters for transforming depth values
             .depth_bias_constant_factor(0.0)
             .depth_bias_clamp(0.0)
@@ -111,22 +242,34 @@ impl RasterPipeline {
 
         let multisample_state = vk::PipelineMultisampleStateCreateInfo::default()
             .sample_shading_enable(false) // Disabled for now
-            .rasterization_samples(vk::SampleCountFlags::TYPE_1)
+            .rasterization_samples(target.samples()) // Must match the attachments, I.E. the scene's with MSAA
             .min_sample_shading(1.0)
             // .sample_mask() Leave NULL
             .alpha_to_coverage_enable(false)
             .alpha_to_one_enable(false);
 
-        let additive_color_blending_create_infos = [
+        let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::default()
+            .depth_test_enable(state.depth_test) // Compare new fragments against the depth buffer
+            .depth_write_enable(state.depth_test && state.depth_write && !state.query_only)
+            .depth_compare_op(state.depth_compare) // Lower depth is closer
+            .depth_bounds_test_enable(false)
+            .stencil_test_enable(false);
+
+        // G-buffer targets pack unrelated values into alpha, so multiple targets are written as they are
+        let additive_color_blending_create_infos = vec![
             vk::PipelineColorBlendAttachmentState::default()
-                .color_write_mask(vk::ColorComponentFlags::RGBA)
-                .blend_enable(true)
-                .src_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
-                .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+                .color_write_mask(match state.query_only {
+                    true => vk::ColorComponentFlags::empty(),
+                    false => vk::ColorComponentFlags::RGBA
+                })
+                .blend_enable(state.color_targets == 1)
+                .src_color_blend_factor(if state.additive { vk::BlendFactor::ONE } else { vk::BlendFactor::SRC_ALPHA })
+                .dst_color_blend_factor(if state.additive { vk::BlendFactor::ONE } else { vk::BlendFactor::ONE_MINUS_SRC_ALPHA })
                 .color_blend_op(vk::BlendOp::ADD) // Blend operation
                 .src_alpha_blend_factor(vk::BlendFactor::ONE)
                 .dst_alpha_blend_factor(vk::BlendFactor::ZERO)
-                .alpha_blend_op(vk::BlendOp::ADD)
+                .alpha_blend_op(vk::BlendOp::ADD);
+            state.color_targets as usize
         ];
 
         let blend_constants: [f32; 4] = [0.0, 0.0, 0.0, 0.0];
@@ -142,33 +285,72 @@ impl RasterPipeline {
         let dynamic_state_create_info = vk::PipelineDynamicStateCreateInfo::default()
             .dynamic_states(&dynamic_states);
 
-        let pipeline_layout = setup_pipeline_layout(logical_layer);
+        let pipeline_layout = setup_pipeline_layout(logical_layer, set_layouts, push_constant_range);
+
+        // Dynamic rendering has no render pass, the attachment formats are given up front instead
+        let (render_pass, color_formats, depth_format) = match target {
+            PassTarget::RenderPass(p, _) => (p, [vk::Format::UNDEFINED], vk::Format::UNDEFINED),
+            PassTarget::Dynamic { color_format, depth_format, .. } => (vk::RenderPass::null(), [color_format], depth_format)
+        };
+        let mut rendering_info = vk::PipelineRenderingCreateInfo::default()
+            .color_attachment_formats(&color_formats)
+            .depth_attachment_format(depth_format);
 
         let pipeline_info = vk::GraphicsPipelineCreateInfo::default()
             .stages(&pipeline_stages)
-            .vertex_input_state(&vertex_inputs)
-            .input_assembly_state(&input_assembly)
             .viewport_state(&viewport_state)
             .rasterization_state(&rasterization_state)
             .multisample_state(&multisample_state)
-            // .depth_stencil_state() Currently unused
+            .depth_stencil_state(&depth_stencil_state)
             .color_blend_state(&color_blending_create_info)
             .dynamic_state(&dynamic_state_create_info)
             .layout(pipeline_layout)
             .render_pass(render_pass)
             .subpass(0);
+        // Mesh shaders assemble their own primitives
+        let pipeline_info = match vertex_layouts {
+            Some(_) => pipeline_info
+                .vertex_input_state(&vertex_inputs)
+                .input_assembly_state(&input_assembly),
+            None => pipeline_info
+        };
+        let pipeline_info = match target {
+            PassTarget::RenderPass(..) => pipeline_info,
+            PassTarget::Dynamic { .. } => pipeline_info.push_next(&mut rendering_info)
+        };
 
         let pipelines = unsafe { logical_layer.logical_device.create_graphics_pipelines(vk::PipelineCache::null(),
                                                                                    &[pipeline_info],
                                                                                    None).unwrap() };
 
-        for &s in shader_modules.iter() {
-            unsafe { logical_layer.logical_device.destroy_shader_module(s, None) }
+        for (s, _, _) in shader_modules.iter() {
+            unsafe { logical_layer.logical_device.destroy_shader_module(*s, None) }
         }
 
         RasterPipeline {
             pipeline_layout,
-            pipelines
+            pipelines,
+            push_constant_range
+        }
+    }
+
+    pub(crate) fn push_constant_range(&self) -> Option<vk::PushConstantRange> {
+        self.push_constant_range
+    }
+
+    pub(crate) fn push_constants<T: Pod>(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer,
+                                         offset: u32, data: &T) {
+        let range = self.push_constant_range.expect("Pipeline was created without push constants");
+        let bytes = bytemuck::bytes_of(data);
+        assert!(offset >= range.offset && offset + bytes.len() as u32 <= range.offset + range.size,
+                "Push constants written outside of the declared range");
+
+        unsafe {
+            logical_layer.logical_device.cmd_push_constants(command_buffer,
+                                                            self.pipeline_layout,
+                                                            range.stage_flags,
+                                                            offset,
+                                                            bytes);
         }
     }
 
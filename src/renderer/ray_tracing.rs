@@ -0,0 +1,881 @@
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::mem;
+use std::path::Path;
+
+use ash::extensions::khr::{AccelerationStructure, RayTracingPipeline};
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use glam::Mat4;
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::barrier::{first_mip, Barriers, Ownership, Usage};
+use crate::renderer::core::Core;
+use crate::renderer::deletion_queue::Deletion;
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::mesh::MeshHandle;
+use crate::renderer::physical_layer::PhysicalLayer;
+use crate::renderer::render_queue::MaterialHandle;
+use crate::renderer::resources::ResourceManager;
+use crate::renderer::shader::read_spirv;
+use crate::renderer::vertex::Vertex;
+
+const MAX_INSTANCES: u32 = 4096; // Per frame, draws past it are left out of the scene that's traced
+const SHADOW_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM; // Visibility of each shadow layer, one per channel
+const REFLECTION_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT; // Radiance, alpha is how much it replaces the ambient
+
+// Indices into RayTracing::targets
+const SHADOWS: usize = 0;
+const REFLECTIONS: usize = 1;
+
+// naga can't compile ray tracing stages, so these are built from shaders/src with glslc ahead of time. In
+// shader group order: ray generation, the reflection and shadow misses, then the closest hit.
+const SHADERS: [(&str, vk::ShaderStageFlags); 4] = [
+    ("shaders/spv/rt.rgen.spv", vk::ShaderStageFlags::RAYGEN_KHR),
+    ("shaders/spv/rt.rmiss.spv", vk::ShaderStageFlags::MISS_KHR),
+    ("shaders/spv/rt_shadow.rmiss.spv", vk::ShaderStageFlags::MISS_KHR),
+    ("shaders/spv/rt.rchit.spv", vk::ShaderStageFlags::CLOSEST_HIT_KHR)
+];
+
+// Ray traced shadows and reflections for the deferred pipeline, on devices with VK_KHR_ray_tracing_pipeline
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayTracingSettings {
+    pub shadows: bool, // Directional lights with shadow maps trace a ray towards the light instead
+    pub reflections: bool, // Smooth surfaces reflect the scene instead of the ambient hemisphere
+    pub max_roughness: f32 // Rougher surfaces aren't traced, reflections fade out towards it
+}
+
+impl Default for RayTracingSettings {
+    fn default() -> Self {
+        RayTracingSettings {
+            shadows: true,
+            reflections: true,
+            max_roughness: 0.5
+        }
+    }
+}
+
+// Device extensions enabled along with ray tracing, PhysicalLayer::ray_tracing says whether they're all there
+pub(crate) fn ray_tracing_extensions() -> Vec<CString> {
+    vec![
+        CString::from(vk::KhrAccelerationStructureFn::name()),
+        CString::from(vk::KhrRayTracingPipelineFn::name()),
+        CString::from(vk::KhrDeferredHostOperationsFn::name()) // Required by VK_KHR_acceleration_structure
+    ]
+}
+
+// Extra usage for buffers that acceleration structures are built from, none without ray tracing
+pub(crate) fn input_usage(logical_layer: &LogicalLayer) -> vk::BufferUsageFlags {
+    match logical_layer.ray_tracing() {
+        true => vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS | vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR,
+        false => vk::BufferUsageFlags::empty()
+    }
+}
+
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    (value + alignment - 1) / alignment * alignment
+}
+
+fn buffer_address(logical_layer: &LogicalLayer, buffer: vk::Buffer) -> vk::DeviceAddress {
+    let info = vk::BufferDeviceAddressInfo::default()
+        .buffer(buffer);
+    unsafe { logical_layer.logical_device.get_buffer_device_address(&info) }
+}
+
+// A mesh instance to trace against, gathered from the frame's render queue
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TraceInstance {
+    pub(crate) mesh: MeshHandle,
+    pub(crate) transform: Mat4,
+    pub(crate) material: MaterialHandle
+}
+
+// Matches InstanceShading in rt.rchit, indexed by each instance's custom index. Reflected surfaces are
+// shaded from their material's factors, textures aren't sampled.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct InstanceShading {
+    vertices: vk::DeviceAddress,
+    indices: vk::DeviceAddress,
+    base_color: [f32; 4],
+    emissive: [f32; 3],
+    index_size: u32 // 2 or 4 bytes
+}
+
+// Matches the PushConstants block in rt.rgen
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct TraceConstants {
+    inv_view_proj: Mat4, // Rebuilds positions from the G-buffer's depth
+    params: [f32; 4] // Shadows on, reflections on, the max roughness and how far rays start off the surface
+}
+
+// A buffer whose device address is aligned to alignment, the buffer is grown to make room
+struct AddressedBuffer {
+    buf: vk::Buffer,
+    alloc: Allocation,
+    address: vk::DeviceAddress,
+    offset: usize // Bytes between the start of the buffer and address
+}
+
+impl AddressedBuffer {
+    fn new(logical_layer: &LogicalLayer, allocator: &Allocator, size: vk::DeviceSize, usage: vk::BufferUsageFlags,
+           mem_props: vk::MemoryPropertyFlags, alignment: vk::DeviceSize) -> Result<AddressedBuffer, RendererError> {
+        let (alloc, buf) = allocator.create_buffer(logical_layer, size + alignment,
+                                                   usage | vk::BufferUsageFlags::SHADER_DEVICE_ADDRESS, mem_props,
+                                                   MemoryCategory::AccelerationStructures)?;
+        let base = buffer_address(logical_layer, buf);
+        let address = align_up(base, alignment);
+
+        Ok(AddressedBuffer {
+            buf,
+            alloc,
+            address,
+            offset: (address - base) as usize
+        })
+    }
+
+    // Only for host visible buffers
+    fn mapped<T>(&self) -> *mut T {
+        let ptr = self.alloc.mapped_ptr().expect("Buffer isn't host visible");
+        unsafe { ptr.add(self.offset) as *mut T }
+    }
+
+    fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        allocator.destroy_buffer(logical_layer, self.buf, &self.alloc);
+    }
+
+    fn retire(self) -> Deletion {
+        Deletion::Buffer(self.buf, self.alloc)
+    }
+}
+
+// An acceleration structure and the buffer it lives in
+struct AccelerationStructureBuffer {
+    accel: vk::AccelerationStructureKHR,
+    storage: AddressedBuffer,
+    address: vk::DeviceAddress // What instances and descriptors reference, not the storage buffer's
+}
+
+impl AccelerationStructureBuffer {
+    fn new(logical_layer: &LogicalLayer, allocator: &Allocator, ty: vk::AccelerationStructureTypeKHR,
+           size: vk::DeviceSize) -> Result<AccelerationStructureBuffer, RendererError> {
+        let loader = logical_layer.acceleration_structure.as_ref().expect("Ray tracing isn't enabled");
+        // Acceleration structures have to start 256 byte aligned within their buffer
+        let storage = AddressedBuffer::new(logical_layer, allocator, size, vk::BufferUsageFlags::ACCELERATION_STRUCTURE_STORAGE_KHR,
+                                           vk::MemoryPropertyFlags::DEVICE_LOCAL, 256)?;
+        let create_info = vk::AccelerationStructureCreateInfoKHR::default()
+            .buffer(storage.buf)
+            .offset(storage.offset as vk::DeviceSize)
+            .size(size)
+            .ty(ty);
+        let accel = match unsafe { loader.create_acceleration_structure(&create_info, None) } {
+            Ok(a) => a,
+            Err(e) => {
+                storage.destroy(logical_layer, allocator);
+                return Err(vk_error("vkCreateAccelerationStructureKHR")(e));
+            }
+        };
+        let address_info = vk::AccelerationStructureDeviceAddressInfoKHR::default()
+            .acceleration_structure(accel);
+        let address = unsafe { loader.get_acceleration_structure_device_address(&address_info) };
+
+        Ok(AccelerationStructureBuffer {
+            accel,
+            storage,
+            address
+        })
+    }
+
+    fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        if let Some(loader) = logical_layer.acceleration_structure.as_ref() {
+            unsafe { loader.destroy_acceleration_structure(self.accel, None) };
+        }
+        self.storage.destroy(logical_layer, allocator);
+    }
+
+    fn retire(self) -> Deletion {
+        Deletion::AccelerationStructure(self.accel, self.storage.buf, self.storage.alloc)
+    }
+}
+
+// A mesh's bottom level acceleration structure
+struct Blas {
+    accel: AccelerationStructureBuffer,
+    source: (vk::Buffer, vk::Buffer) // Vertex and index buffers it was built from, replacing the mesh changes them
+}
+
+// Per frame slot top level acceleration structure, rebuilt from scratch every frame
+struct FrameScene {
+    tlas: AccelerationStructureBuffer,
+    scratch: AddressedBuffer,
+    instances: AddressedBuffer, // Host visible VkAccelerationStructureInstanceKHRs
+    shading: AddressedBuffer, // Host visible InstanceShading, in the same order
+    set: vk::DescriptorSet
+}
+
+// A storage image written by the ray generation shader and sampled by the lighting pass
+struct TraceTarget {
+    image: vk::Image,
+    alloc: Allocation,
+    view: vk::ImageView
+}
+
+impl TraceTarget {
+    fn new(logical_layer: &LogicalLayer, allocator: &Allocator, format: vk::Format, extent: vk::Extent2D) -> Result<TraceTarget, RendererError> {
+        let create_info = vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::STORAGE | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let (alloc, image) = allocator.create_image(logical_layer, &create_info, vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                    MemoryCategory::RenderTargets)?;
+
+        let view_create_info = vk::ImageViewCreateInfo::default()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(first_mip(vk::ImageAspectFlags::COLOR, 1));
+        let view = match unsafe { logical_layer.logical_device.create_image_view(&view_create_info, None) } {
+            Ok(v) => v,
+            Err(e) => {
+                allocator.destroy_image(logical_layer, image, &alloc);
+                return Err(vk_error("vkCreateImageView")(e));
+            }
+        };
+
+        Ok(TraceTarget {
+            image,
+            alloc,
+            view
+        })
+    }
+
+    fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        unsafe { logical_layer.logical_device.destroy_image_view(self.view, None) };
+        allocator.destroy_image(logical_layer, self.image, &self.alloc);
+    }
+}
+
+// Shadows and reflections traced against the frame's render queue, from the deferred G-buffer. Meshes get
+// a BLAS the first time they're drawn, kept until the mesh is removed or replaced. Every frame slot has a
+// TLAS rebuilt from the queue's items and instanced items, so dynamic meshes and indirect batches aren't
+// traced, and morph targets trace in their base pose. One ray per pixel is traced towards each shadow
+// casting directional light and, for smooth surfaces, one along the reflection. The pipeline reads the
+// frame's uniforms at set 0 and set 1: the TLAS at binding 0, the shadow and reflection targets at 1 and
+// 2, the G-buffer's normals and depth at 3 and 4, a nearest sampler at 5 and the instances' shading at 6.
+pub(crate) struct RayTracing {
+    loader: RayTracingPipeline,
+    pipeline: vk::Pipeline,
+    pipeline_layout: vk::PipelineLayout,
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sbt: AddressedBuffer, // Shader binding table, one handle per shader group
+    regions: [vk::StridedDeviceAddressRegionKHR; 4], // Ray generation, miss, hit and the unused callable region
+    scratch_alignment: vk::DeviceSize,
+    blases: HashMap<MeshHandle, Blas>,
+    frames: Vec<FrameScene>,
+    targets: Option<[TraceTarget; 2]>, // In SHADOWS, REFLECTIONS order, None between destroy_targets and resize
+    extent: vk::Extent2D,
+    needs_transition: bool // The targets start out UNDEFINED but the lighting pass binds them either way
+}
+
+impl RayTracing {
+    // Fails when the device doesn't support ray tracing or the precompiled shaders are missing
+    pub(crate) fn new(core: &Core, physical_layer: &PhysicalLayer, logical_layer: &LogicalLayer, allocator: &Allocator,
+                      frame_set_layout: vk::DescriptorSetLayout, frame_count: usize) -> Result<RayTracing, RendererError> {
+        fn setup_set_layout(logical_layer: &LogicalLayer) -> Result<vk::DescriptorSetLayout, RendererError> {
+            let binding = |binding: u32, ty: vk::DescriptorType, stages: vk::ShaderStageFlags| vk::DescriptorSetLayoutBinding::default()
+                .binding(binding)
+                .descriptor_type(ty)
+                .descriptor_count(1)
+                .stage_flags(stages);
+            let bindings = [
+                binding(0, vk::DescriptorType::ACCELERATION_STRUCTURE_KHR, vk::ShaderStageFlags::RAYGEN_KHR),
+                binding(1, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::RAYGEN_KHR),
+                binding(2, vk::DescriptorType::STORAGE_IMAGE, vk::ShaderStageFlags::RAYGEN_KHR),
+                binding(3, vk::DescriptorType::SAMPLED_IMAGE, vk::ShaderStageFlags::RAYGEN_KHR),
+                binding(4, vk::DescriptorType::SAMPLED_IMAGE, vk::ShaderStageFlags::RAYGEN_KHR),
+                binding(5, vk::DescriptorType::SAMPLER, vk::ShaderStageFlags::RAYGEN_KHR),
+                binding(6, vk::DescriptorType::STORAGE_BUFFER, vk::ShaderStageFlags::CLOSEST_HIT_KHR)
+            ];
+            let create_info = vk::DescriptorSetLayoutCreateInfo::default()
+                .bindings(&bindings);
+
+            unsafe {
+                logical_layer.logical_device.create_descriptor_set_layout(&create_info, None)
+                    .map_err(vk_error("vkCreateDescriptorSetLayout"))
+            }
+        }
+
+        fn setup_descriptors(logical_layer: &LogicalLayer, set_layout: vk::DescriptorSetLayout, frame_count: usize)
+            -> Result<(vk::DescriptorPool, Vec<vk::DescriptorSet>), RendererError> {
+            let count = frame_count as u32;
+            let pool_sizes = [
+                vk::DescriptorPoolSize::default()
+                    .ty(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
+                    .descriptor_count(count),
+                vk::DescriptorPoolSize::default()
+                    .ty(vk::DescriptorType::STORAGE_IMAGE)
+                    .descriptor_count(2 * count),
+                vk::DescriptorPoolSize::default()
+                    .ty(vk::DescriptorType::SAMPLED_IMAGE)
+                    .descriptor_count(2 * count),
+                vk::DescriptorPoolSize::default()
+                    .ty(vk::DescriptorType::SAMPLER)
+                    .descriptor_count(count),
+                vk::DescriptorPoolSize::default()
+                    .ty(vk::DescriptorType::STORAGE_BUFFER)
+                    .descriptor_count(count)
+            ];
+            let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+                .pool_sizes(&pool_sizes)
+                .max_sets(count);
+            let pool = unsafe {
+                logical_layer.logical_device.create_descriptor_pool(&pool_create_info, None)
+                    .map_err(vk_error("vkCreateDescriptorPool"))?
+            };
+
+            let layouts = vec![set_layout; frame_count];
+            let alloc_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(pool)
+                .set_layouts(&layouts);
+            match unsafe { logical_layer.logical_device.allocate_descriptor_sets(&alloc_info) } {
+                Ok(sets) => Ok((pool, sets)),
+                Err(e) => {
+                    unsafe { logical_layer.logical_device.destroy_descriptor_pool(pool, None) };
+                    Err(vk_error("vkAllocateDescriptorSets")(e))
+                }
+            }
+        }
+
+        fn setup_pipeline(logical_layer: &LogicalLayer, loader: &RayTracingPipeline, layout: vk::PipelineLayout)
+            -> Result<vk::Pipeline, RendererError> {
+            let device = &logical_layer.logical_device;
+            let mut compiled = Vec::with_capacity(SHADERS.len());
+            for (path, _) in SHADERS {
+                compiled.push(read_spirv(Path::new(path))?);
+            }
+            let modules: Vec<vk::ShaderModule> = compiled.iter()
+                .map(|c| {
+                    let module_info = vk::ShaderModuleCreateInfo::default()
+                        .code(&c.code);
+                    unsafe { device.create_shader_module(&module_info, None).unwrap() }
+                })
+                .collect();
+            let stages: Vec<vk::PipelineShaderStageCreateInfo> = SHADERS.iter()
+                .zip(modules.iter().zip(compiled.iter()))
+                .map(|((_, stage), (module, c))| vk::PipelineShaderStageCreateInfo::default()
+                    .name(c.entry_point.as_c_str())
+                    .stage(*stage)
+                    .module(*module))
+                .collect();
+
+            let general = |shader: u32| vk::RayTracingShaderGroupCreateInfoKHR::default()
+                .ty(vk::RayTracingShaderGroupTypeKHR::GENERAL)
+                .general_shader(shader)
+                .closest_hit_shader(vk::SHADER_UNUSED_KHR)
+                .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                .intersection_shader(vk::SHADER_UNUSED_KHR);
+            let groups = [
+                general(0),
+                general(1),
+                general(2),
+                vk::RayTracingShaderGroupCreateInfoKHR::default()
+                    .ty(vk::RayTracingShaderGroupTypeKHR::TRIANGLES_HIT_GROUP)
+                    .general_shader(vk::SHADER_UNUSED_KHR)
+                    .closest_hit_shader(3)
+                    .any_hit_shader(vk::SHADER_UNUSED_KHR)
+                    .intersection_shader(vk::SHADER_UNUSED_KHR)
+            ];
+            let create_info = vk::RayTracingPipelineCreateInfoKHR::default()
+                .stages(&stages)
+                .groups(&groups)
+                .max_pipeline_ray_recursion_depth(1) // Hits don't trace further rays
+                .layout(layout);
+
+            let pipelines = unsafe {
+                loader.create_ray_tracing_pipelines(vk::DeferredOperationKHR::null(), vk::PipelineCache::null(), &[create_info], None)
+                    .unwrap()
+            };
+            for m in modules {
+                unsafe { device.destroy_shader_module(m, None) };
+            }
+
+            Ok(pipelines[0])
+        }
+
+        // One record per shader group, each region starting on the base alignment
+        fn setup_sbt(logical_layer: &LogicalLayer, allocator: &Allocator, loader: &RayTracingPipeline, pipeline: vk::Pipeline,
+                     properties: &vk::PhysicalDeviceRayTracingPipelinePropertiesKHR)
+            -> Result<(AddressedBuffer, [vk::StridedDeviceAddressRegionKHR; 4]), RendererError> {
+            let handle_size = properties.shader_group_handle_size as vk::DeviceSize;
+            let base_alignment = properties.shader_group_base_alignment as vk::DeviceSize;
+            let stride = align_up(handle_size, properties.shader_group_handle_alignment as vk::DeviceSize);
+            let handles = unsafe {
+                loader.get_ray_tracing_shader_group_handles(pipeline, 0, SHADERS.len() as u32, SHADERS.len() * handle_size as usize)
+                    .map_err(vk_error("vkGetRayTracingShaderGroupHandlesKHR"))?
+            };
+
+            // Ray generation's size has to equal its stride. Groups 1 and 2 are the misses, 3 the hit group.
+            let raygen_size = align_up(stride, base_alignment);
+            let miss_size = align_up(2 * stride, base_alignment);
+            let hit_size = align_up(stride, base_alignment);
+            let sbt = AddressedBuffer::new(logical_layer, allocator, raygen_size + miss_size + hit_size,
+                                           vk::BufferUsageFlags::SHADER_BINDING_TABLE_KHR,
+                                           vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+                                           base_alignment)?;
+            let record_offsets = [0, raygen_size, raygen_size + stride, raygen_size + miss_size];
+            let ptr = sbt.mapped::<u8>();
+            for (group, offset) in record_offsets.iter().enumerate() {
+                let handle = &handles[group * handle_size as usize..(group + 1) * handle_size as usize];
+                unsafe { std::ptr::copy_nonoverlapping(handle.as_ptr(), ptr.add(*offset as usize), handle.len()) };
+            }
+
+            let region = |offset: vk::DeviceSize, stride: vk::DeviceSize, size: vk::DeviceSize| vk::StridedDeviceAddressRegionKHR {
+                device_address: sbt.address + offset,
+                stride,
+                size
+            };
+            let regions = [
+                region(0, raygen_size, raygen_size),
+                region(raygen_size, stride, miss_size),
+                region(raygen_size + miss_size, stride, hit_size),
+                vk::StridedDeviceAddressRegionKHR::default()
+            ];
+
+            Ok((sbt, regions))
+        }
+
+        fn setup_frame(logical_layer: &LogicalLayer, allocator: &Allocator, set: vk::DescriptorSet,
+                       sizes: &vk::AccelerationStructureBuildSizesInfoKHR, scratch_alignment: vk::DeviceSize) -> Result<FrameScene, RendererError> {
+            let host = vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT;
+            let tlas = AccelerationStructureBuffer::new(logical_layer, allocator, vk::AccelerationStructureTypeKHR::TOP_LEVEL,
+                                                        sizes.acceleration_structure_size)?;
+            let scratch = AddressedBuffer::new(logical_layer, allocator, sizes.build_scratch_size, vk::BufferUsageFlags::STORAGE_BUFFER,
+                                               vk::MemoryPropertyFlags::DEVICE_LOCAL, scratch_alignment)?;
+            let instances = AddressedBuffer::new(logical_layer, allocator,
+                                                 MAX_INSTANCES as vk::DeviceSize * mem::size_of::<vk::AccelerationStructureInstanceKHR>() as vk::DeviceSize,
+                                                 vk::BufferUsageFlags::ACCELERATION_STRUCTURE_BUILD_INPUT_READ_ONLY_KHR, host, 16)?;
+            let shading = AddressedBuffer::new(logical_layer, allocator,
+                                               MAX_INSTANCES as vk::DeviceSize * mem::size_of::<InstanceShading>() as vk::DeviceSize,
+                                               vk::BufferUsageFlags::STORAGE_BUFFER, host, 16)?;
+
+            // The TLAS and shading buffer never change, only the images are rewritten on resizes
+            let accels = [tlas.accel];
+            let mut accel_write = vk::WriteDescriptorSetAccelerationStructureKHR::default()
+                .acceleration_structures(&accels);
+            let mut tlas_write = vk::WriteDescriptorSet::default()
+                .dst_set(set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::ACCELERATION_STRUCTURE_KHR)
+                .push_next(&mut accel_write);
+            tlas_write.descriptor_count = 1; // Not implied by any of the info slices
+            let buffer_infos = [vk::DescriptorBufferInfo::default()
+                .buffer(shading.buf)
+                .offset(shading.offset as vk::DeviceSize)
+                .range(MAX_INSTANCES as vk::DeviceSize * mem::size_of::<InstanceShading>() as vk::DeviceSize)];
+            let writes = [
+                tlas_write,
+                vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(6)
+                    .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+                    .buffer_info(&buffer_infos)
+            ];
+            unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+
+            Ok(FrameScene {
+                tlas,
+                scratch,
+                instances,
+                shading,
+                set
+            })
+        }
+
+        let acceleration_structure = logical_layer.acceleration_structure.as_ref()
+            .ok_or(RendererError::MissingFeature("ray tracing"))?;
+        let mut accel_properties = vk::PhysicalDeviceAccelerationStructurePropertiesKHR::default();
+        let mut pipeline_properties = vk::PhysicalDeviceRayTracingPipelinePropertiesKHR::default();
+        let mut properties2 = vk::PhysicalDeviceProperties2::default()
+            .push_next(&mut accel_properties)
+            .push_next(&mut pipeline_properties);
+        unsafe { core.instance.get_physical_device_properties2(physical_layer.physical_device, &mut properties2) };
+        let scratch_alignment = accel_properties.min_acceleration_structure_scratch_offset_alignment as vk::DeviceSize;
+
+        let loader = RayTracingPipeline::new(&core.instance, &logical_layer.logical_device);
+        let set_layout = setup_set_layout(logical_layer)?;
+        let push_constant_range = [vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::RAYGEN_KHR)
+            .offset(0)
+            .size(mem::size_of::<TraceConstants>() as u32)];
+        let set_layouts = [frame_set_layout, set_layout];
+        let layout_info = vk::PipelineLayoutCreateInfo::default()
+            .set_layouts(&set_layouts)
+            .push_constant_ranges(&push_constant_range);
+        let pipeline_layout = unsafe {
+            logical_layer.logical_device.create_pipeline_layout(&layout_info, None).map_err(vk_error("vkCreatePipelineLayout"))?
+        };
+        let pipeline = match setup_pipeline(logical_layer, &loader, pipeline_layout) {
+            Ok(p) => p,
+            Err(e) => {
+                unsafe {
+                    logical_layer.logical_device.destroy_pipeline_layout(pipeline_layout, None);
+                    logical_layer.logical_device.destroy_descriptor_set_layout(set_layout, None);
+                }
+                return Err(e);
+            }
+        };
+        let (sbt, regions) = setup_sbt(logical_layer, allocator, &loader, pipeline, &pipeline_properties)?;
+        let (descriptor_pool, sets) = setup_descriptors(logical_layer, set_layout, frame_count)?;
+
+        // Sized once for the most instances a frame can hold
+        let geometries = [tlas_geometry(0)];
+        let build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
+            .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+            .geometries(&geometries);
+        let sizes = unsafe {
+            acceleration_structure.get_acceleration_structure_build_sizes(vk::AccelerationStructureBuildTypeKHR::DEVICE,
+                                                                           &build_info, &[MAX_INSTANCES])
+        };
+        let mut frames = Vec::with_capacity(frame_count);
+        for set in sets {
+            frames.push(setup_frame(logical_layer, allocator, set, &sizes, scratch_alignment)?);
+        }
+
+        Ok(RayTracing {
+            loader,
+            pipeline,
+            pipeline_layout,
+            set_layout,
+            descriptor_pool,
+            sbt,
+            regions,
+            scratch_alignment,
+            blases: HashMap::new(),
+            frames,
+            targets: None,
+            extent: vk::Extent2D::default(),
+            needs_transition: true
+        })
+    }
+
+    // Recreates the targets at extent, reading the G-buffer's new normal and depth views. sampler is the
+    // G-buffer's. The GPU must not be using the old ones.
+    pub(crate) fn resize(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, extent: vk::Extent2D,
+                         normal_view: vk::ImageView, depth_view: vk::ImageView, sampler: vk::Sampler) -> Result<(), RendererError> {
+        self.destroy_targets(logical_layer, allocator);
+
+        let shadows = TraceTarget::new(logical_layer, allocator, SHADOW_FORMAT, extent)?;
+        let reflections = match TraceTarget::new(logical_layer, allocator, REFLECTION_FORMAT, extent) {
+            Ok(t) => t,
+            Err(e) => {
+                shadows.destroy(logical_layer, allocator);
+                return Err(e);
+            }
+        };
+
+        let storage_info = |view: vk::ImageView| [vk::DescriptorImageInfo::default()
+            .image_view(view)
+            .image_layout(vk::ImageLayout::GENERAL)];
+        let sampled_info = |view: vk::ImageView| [vk::DescriptorImageInfo::default()
+            .image_view(view)
+            .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+        let image_infos = [
+            (1, vk::DescriptorType::STORAGE_IMAGE, storage_info(shadows.view)),
+            (2, vk::DescriptorType::STORAGE_IMAGE, storage_info(reflections.view)),
+            (3, vk::DescriptorType::SAMPLED_IMAGE, sampled_info(normal_view)),
+            (4, vk::DescriptorType::SAMPLED_IMAGE, sampled_info(depth_view)),
+            (5, vk::DescriptorType::SAMPLER, [vk::DescriptorImageInfo::default().sampler(sampler)])
+        ];
+        for frame in self.frames.iter() {
+            let writes: Vec<vk::WriteDescriptorSet> = image_infos.iter()
+                .map(|(binding, ty, info)| vk::WriteDescriptorSet::default()
+                    .dst_set(frame.set)
+                    .dst_binding(*binding)
+                    .descriptor_type(*ty)
+                    .image_info(info))
+                .collect();
+            unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+        }
+
+        self.targets = Some([shadows, reflections]);
+        self.extent = extent;
+        self.needs_transition = true;
+        Ok(())
+    }
+
+    // What the lighting pass samples
+    pub(crate) fn shadow_view(&self) -> vk::ImageView {
+        self.targets.as_ref().expect("Ray tracing targets are missing")[SHADOWS].view
+    }
+
+    pub(crate) fn reflection_view(&self) -> vk::ImageView {
+        self.targets.as_ref().expect("Ray tracing targets are missing")[REFLECTIONS].view
+    }
+
+    // Moves the targets into the layout the lighting pass expects, so they're valid to bind while ray
+    // tracing is off. Only records anything after creation or a resize.
+    pub(crate) fn prepare(&mut self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer) {
+        if !self.needs_transition {
+            return;
+        }
+        self.needs_transition = false;
+
+        let targets = self.targets.as_ref().expect("Ray tracing targets are missing");
+        let range = first_mip(vk::ImageAspectFlags::COLOR, 1);
+        Barriers::new()
+            .image(targets[SHADOWS].image, range, Usage::Undefined, Usage::FragmentSampled, Ownership::Keep)
+            .image(targets[REFLECTIONS].image, range, Usage::Undefined, Usage::FragmentSampled, Ownership::Keep)
+            .record(logical_layer, command_buffer);
+    }
+
+    // Builds a BLAS for every instanced mesh without one, then the frame slot's TLAS over the instances.
+    // Recorded outside of any render pass, before the scene is traced. Returns the structures and scratch
+    // buffers to destroy once the frame is done with them.
+    pub(crate) fn build(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, command_buffer: vk::CommandBuffer,
+                        frame: usize, instances: &[TraceInstance], resources: &ResourceManager) -> Result<Vec<Deletion>, RendererError> {
+        let loader = logical_layer.acceleration_structure.as_ref().expect("Ray tracing isn't enabled");
+        let mut retired: Vec<Deletion> = Vec::new();
+
+        // Removed and replaced meshes, a replaced one is rebuilt below if it's still drawn
+        let stale: Vec<MeshHandle> = self.blases.iter()
+            .filter(|(handle, blas)| resources.mesh(**handle)
+                .map_or(true, |m| blas.source != (m.vertex_buffer.buf, m.index_buffer.buf)))
+            .map(|(handle, _)| *handle)
+            .collect();
+        for handle in stale {
+            retired.push(self.blases.remove(&handle).unwrap().accel.retire());
+        }
+
+        // Every new BLAS gets its own scratch, so the builds can all go in one call
+        let mut pending: Vec<(MeshHandle, [vk::AccelerationStructureGeometryKHR; 1], u32, vk::AccelerationStructureKHR,
+                              vk::DeviceAddress)> = Vec::new();
+        let mut result: Result<(), RendererError> = Ok(());
+        for instance in instances.iter() {
+            if self.blases.contains_key(&instance.mesh) || pending.iter().any(|p| p.0 == instance.mesh) {
+                continue;
+            }
+            let mesh = match resources.mesh(instance.mesh) {
+                Some(m) if m.index_buffer.index_count >= 3 => m,
+                _ => continue
+            };
+
+            let triangles = vk::AccelerationStructureGeometryTrianglesDataKHR::default()
+                .vertex_format(vk::Format::R32G32B32_SFLOAT) // Vertex::pos, at the start of every vertex
+                .vertex_data(vk::DeviceOrHostAddressConstKHR { device_address: buffer_address(logical_layer, mesh.vertex_buffer.buf) })
+                .vertex_stride(mem::size_of::<Vertex>() as vk::DeviceSize)
+                .max_vertex(mesh.vertex_count().saturating_sub(1))
+                .index_type(mesh.index_buffer.index_type)
+                .index_data(vk::DeviceOrHostAddressConstKHR { device_address: buffer_address(logical_layer, mesh.index_buffer.buf) });
+            let geometries = [vk::AccelerationStructureGeometryKHR::default()
+                .geometry_type(vk::GeometryTypeKHR::TRIANGLES)
+                .geometry(vk::AccelerationStructureGeometryDataKHR { triangles })
+                .flags(vk::GeometryFlagsKHR::OPAQUE)];
+            let primitive_count = mesh.index_buffer.index_count / 3;
+
+            let build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+                .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+                .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+                .geometries(&geometries);
+            let sizes = unsafe {
+                loader.get_acceleration_structure_build_sizes(vk::AccelerationStructureBuildTypeKHR::DEVICE, &build_info,
+                                                              &[primitive_count])
+            };
+            let accel = match AccelerationStructureBuffer::new(logical_layer, allocator, vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL,
+                                                               sizes.acceleration_structure_size) {
+                Ok(a) => a,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+            let scratch = match AddressedBuffer::new(logical_layer, allocator, sizes.build_scratch_size,
+                                                     vk::BufferUsageFlags::STORAGE_BUFFER, vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                     self.scratch_alignment) {
+                Ok(s) => s,
+                Err(e) => {
+                    accel.destroy(logical_layer, allocator);
+                    result = Err(e);
+                    break;
+                }
+            };
+
+            pending.push((instance.mesh, geometries, primitive_count, accel.accel, scratch.address));
+            retired.push(scratch.retire());
+            self.blases.insert(instance.mesh, Blas {
+                accel,
+                source: (mesh.vertex_buffer.buf, mesh.index_buffer.buf)
+            });
+        }
+
+        if !pending.is_empty() {
+            // Uploads are only made visible to the stages reading vertices, the builds read them too
+            Barriers::new()
+                .memory(Usage::ShaderRead, Usage::AccelerationStructureInput)
+                .record(logical_layer, command_buffer);
+            let build_infos: Vec<vk::AccelerationStructureBuildGeometryInfoKHR> = pending.iter()
+                .map(|(_, geometries, _, accel, scratch)| vk::AccelerationStructureBuildGeometryInfoKHR::default()
+                    .ty(vk::AccelerationStructureTypeKHR::BOTTOM_LEVEL)
+                    .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+                    .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+                    .dst_acceleration_structure(*accel)
+                    .geometries(geometries)
+                    .scratch_data(vk::DeviceOrHostAddressKHR { device_address: *scratch }))
+                .collect();
+            let ranges: Vec<[vk::AccelerationStructureBuildRangeInfoKHR; 1]> = pending.iter()
+                .map(|(_, _, primitive_count, _, _)| [vk::AccelerationStructureBuildRangeInfoKHR::default()
+                    .primitive_count(*primitive_count)])
+                .collect();
+            let range_refs: Vec<&[vk::AccelerationStructureBuildRangeInfoKHR]> = ranges.iter().map(|r| r.as_slice()).collect();
+            unsafe { loader.cmd_build_acceleration_structures(command_buffer, &build_infos, &range_refs) };
+            // The TLAS build reads them
+            Barriers::new()
+                .memory(Usage::AccelerationStructureBuild, Usage::AccelerationStructureBuild)
+                .record(logical_layer, command_buffer);
+        }
+        result?;
+
+        // The slot's previous frame has finished, so its buffers can be rewritten
+        let scene = &self.frames[frame];
+        let gpu_instances = scene.instances.mapped::<vk::AccelerationStructureInstanceKHR>();
+        let shading = scene.shading.mapped::<InstanceShading>();
+        let mut count: u32 = 0;
+        for instance in instances.iter() {
+            if count == MAX_INSTANCES {
+                break;
+            }
+            let (blas, mesh) = match (self.blases.get(&instance.mesh), resources.mesh(instance.mesh)) {
+                (Some(b), Some(m)) => (b, m),
+                _ => continue
+            };
+            let params = resources.materials.get(instance.material).params();
+            // Row major 3x4, the transpose's first three columns
+            let rows = instance.transform.transpose().to_cols_array();
+            let mut matrix = [0.0; 12];
+            matrix.copy_from_slice(&rows[..12]);
+
+            unsafe {
+                gpu_instances.add(count as usize).write(vk::AccelerationStructureInstanceKHR {
+                    transform: vk::TransformMatrixKHR { matrix },
+                    instance_custom_index_and_mask: vk::Packed24_8::new(count, 0xff), // Indexes the shading buffer
+                    instance_shader_binding_table_record_offset_and_flags: vk::Packed24_8::new(
+                        0, vk::GeometryInstanceFlagsKHR::TRIANGLE_FACING_CULL_DISABLE.as_raw() as u8),
+                    acceleration_structure_reference: vk::AccelerationStructureReferenceKHR { device_handle: blas.accel.address }
+                });
+                shading.add(count as usize).write(InstanceShading {
+                    vertices: buffer_address(logical_layer, mesh.vertex_buffer.buf),
+                    indices: buffer_address(logical_layer, mesh.index_buffer.buf),
+                    base_color: params.base_color,
+                    emissive: params.emissive,
+                    index_size: match mesh.index_buffer.index_type {
+                        vk::IndexType::UINT16 => 2,
+                        _ => 4
+                    }
+                });
+            }
+            count += 1;
+        }
+
+        let geometries = [tlas_geometry(scene.instances.address)];
+        let build_info = vk::AccelerationStructureBuildGeometryInfoKHR::default()
+            .ty(vk::AccelerationStructureTypeKHR::TOP_LEVEL)
+            .flags(vk::BuildAccelerationStructureFlagsKHR::PREFER_FAST_TRACE)
+            .mode(vk::BuildAccelerationStructureModeKHR::BUILD)
+            .dst_acceleration_structure(scene.tlas.accel)
+            .geometries(&geometries)
+            .scratch_data(vk::DeviceOrHostAddressKHR { device_address: scene.scratch.address });
+        let ranges = [vk::AccelerationStructureBuildRangeInfoKHR::default()
+            .primitive_count(count)];
+        unsafe { loader.cmd_build_acceleration_structures(command_buffer, &[build_info], &[&ranges]) };
+        Barriers::new()
+            .memory(Usage::AccelerationStructureBuild, Usage::RayTracing)
+            .record(logical_layer, command_buffer);
+
+        Ok(retired)
+    }
+
+    // Traces the frame slot's TLAS into the targets, after the G-buffer pass has ended and before the
+    // lighting pass begins
+    pub(crate) fn record(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame: usize,
+                         frame_set: vk::DescriptorSet, settings: &RayTracingSettings, inv_view_proj: Mat4) {
+        let targets = self.targets.as_ref().expect("Ray tracing targets are missing");
+        let range = first_mip(vk::ImageAspectFlags::COLOR, 1);
+        let constants = TraceConstants {
+            inv_view_proj,
+            params: [settings.shadows as u32 as f32, settings.reflections as u32 as f32, settings.max_roughness.max(1e-3), 0.01]
+        };
+        let descriptor_sets = [frame_set, self.frames[frame].set];
+
+        // The G-buffer pass leaves its targets to fragment shaders, the ray generation shader chains onto that
+        Barriers::new()
+            .memory(Usage::FragmentSampled, Usage::RayTracing)
+            .discard_image(targets[SHADOWS].image, range, Usage::FragmentSampled, Usage::RayTracing)
+            .discard_image(targets[REFLECTIONS].image, range, Usage::FragmentSampled, Usage::RayTracing)
+            .record(logical_layer, command_buffer);
+        unsafe {
+            let device = &logical_layer.logical_device;
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::RAY_TRACING_KHR, self.pipeline);
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::RAY_TRACING_KHR, self.pipeline_layout,
+                                            0, &descriptor_sets, &[]);
+            device.cmd_push_constants(command_buffer, self.pipeline_layout, vk::ShaderStageFlags::RAYGEN_KHR, 0,
+                                      bytemuck::bytes_of(&constants));
+            self.loader.cmd_trace_rays(command_buffer, &self.regions[0], &self.regions[1], &self.regions[2], &self.regions[3],
+                                       self.extent.width, self.extent.height, 1);
+        }
+        Barriers::new()
+            .image(targets[SHADOWS].image, range, Usage::RayTracing, Usage::FragmentSampled, Ownership::Keep)
+            .image(targets[REFLECTIONS].image, range, Usage::RayTracing, Usage::FragmentSampled, Ownership::Keep)
+            .record(logical_layer, command_buffer);
+    }
+
+    // Targets are taken so destroying twice, I.E. after a failed resize, is harmless
+    pub(crate) fn destroy_targets(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        if let Some(targets) = self.targets.take() {
+            for t in targets.iter() {
+                t.destroy(logical_layer, allocator);
+            }
+        }
+    }
+
+    pub(crate) fn destroy(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        self.destroy_targets(logical_layer, allocator);
+        for (_, blas) in self.blases.drain() {
+            blas.accel.destroy(logical_layer, allocator);
+        }
+        for frame in self.frames.iter() {
+            frame.tlas.destroy(logical_layer, allocator);
+            frame.scratch.destroy(logical_layer, allocator);
+            frame.instances.destroy(logical_layer, allocator);
+            frame.shading.destroy(logical_layer, allocator);
+        }
+        self.sbt.destroy(logical_layer, allocator);
+        unsafe {
+            logical_layer.logical_device.destroy_pipeline(self.pipeline, None);
+            logical_layer.logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+            logical_layer.logical_device.destroy_descriptor_pool(self.descriptor_pool, None); // Frees the sets as well
+            logical_layer.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
+
+// The TLAS's only geometry, instances read from address
+fn tlas_geometry<'a>(address: vk::DeviceAddress) -> vk::AccelerationStructureGeometryKHR<'a> {
+    vk::AccelerationStructureGeometryKHR::default()
+        .geometry_type(vk::GeometryTypeKHR::INSTANCES)
+        .geometry(vk::AccelerationStructureGeometryDataKHR {
+            instances: vk::AccelerationStructureGeometryInstancesDataKHR::default()
+                .array_of_pointers(false)
+                .data(vk::DeviceOrHostAddressConstKHR { device_address: address })
+        })
+}
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+
+use ash::vk;
+
+use crate::renderer::raster_pipeline::RasterState;
+use crate::renderer::shader::{ShaderSet, ShaderSource};
+
+// What the main pass draws, switched at runtime for debugging
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum RenderMode {
+    #[default]
+    Shaded,
+    Wireframe, // Each material's own shaders with line polygons, needs fillModeNonSolid
+    Normals, // World space normals mapped to colors
+    Overdraw, // Brighter where more fragments land on the same pixel
+    Depth // Distance from the camera, white up close
+}
+
+impl RenderMode {
+    pub(crate) fn raster_state(self) -> RasterState {
+        match self {
+            RenderMode::Wireframe => RasterState {
+                polygon_mode: vk::PolygonMode::LINE,
+                cull_mode: vk::CullModeFlags::NONE, // Show the hidden edges too
+                ..RasterState::default()
+            },
+            RenderMode::Overdraw => RasterState {
+                depth_test: false, // Every fragment counts, not just the visible ones
+                additive: true,
+                ..RasterState::default()
+            },
+            _ => RasterState::default()
+        }
+    }
+
+    // The shaders every material is drawn with, None when materials keep their own
+    pub(crate) fn shaders(self) -> Option<ShaderSet> {
+        let fragment = match self {
+            RenderMode::Shaded | RenderMode::Wireframe => return None,
+            RenderMode::Normals => "shaders/src/debug_normals.frag",
+            RenderMode::Overdraw => "shaders/src/debug_overdraw.frag",
+            RenderMode::Depth => "shaders/src/debug_depth.frag"
+        };
+
+        Some(ShaderSet {
+            vertex: ShaderSource::GlslFile(PathBuf::from("shaders/src/shader.vert")),
+            fragment: ShaderSource::GlslFile(PathBuf::from(fragment))
+        })
+    }
+}
@@ -1,52 +1,312 @@
 use ash::vk;
 
+use crate::renderer::barrier::{first_mip, Barriers, Ownership, Usage};
+use crate::renderer::error::{vk_error, RendererError};
 use crate::renderer::logical_layer::LogicalLayer;
 use crate::renderer::render_target::RenderTarget;
 
-pub(crate) fn setup_render_pass(logical_layer: &LogicalLayer, render_target: &RenderTarget) -> vk::RenderPass {
+// What a graphics pipeline renders into
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum PassTarget {
+    RenderPass(vk::RenderPass, vk::SampleCountFlags), // Subpass 0 of it, rasterized at the sample count of its attachments
+    Dynamic { color_format: vk::Format, depth_format: vk::Format, samples: vk::SampleCountFlags } // Begun with begin_dynamic_scene, no render pass or framebuffer
+}
+
+impl PassTarget {
+    // Subpass 0 of a single sampled render pass, every pass but the scene's
+    pub(crate) fn single(render_pass: vk::RenderPass) -> PassTarget {
+        PassTarget::RenderPass(render_pass, vk::SampleCountFlags::TYPE_1)
+    }
+
+    pub(crate) fn render_pass(&self) -> Option<vk::RenderPass> {
+        match self {
+            PassTarget::RenderPass(p, _) => Some(*p),
+            PassTarget::Dynamic { .. } => None
+        }
+    }
+
+    pub(crate) fn samples(&self) -> vk::SampleCountFlags {
+        match self {
+            PassTarget::RenderPass(_, samples) | PassTarget::Dynamic { samples, .. } => *samples
+        }
+    }
+}
+
+// Images and views of the scene pass when it's begun with dynamic rendering
+pub(crate) struct SceneAttachments {
+    pub(crate) color_image: vk::Image,
+    pub(crate) color_view: vk::ImageView,
+    pub(crate) depth_image: vk::Image,
+    pub(crate) depth_view: vk::ImageView,
+    pub(crate) depth_format: vk::Format,
+    pub(crate) msaa: Option<(vk::Image, vk::ImageView)> // Drawn into in place of the color image, which it's resolved to
+}
+
+impl SceneAttachments {
+    fn depth_aspect(&self) -> vk::ImageAspectFlags {
+        match self.depth_format {
+            vk::Format::D32_SFLOAT => vk::ImageAspectFlags::DEPTH,
+            _ => vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL // Both aspects change layout together
+        }
+    }
+}
+
+// The dynamic rendering counterpart of beginning setup_render_pass's pass. The barriers stand in for its
+// subpass dependency and initial layouts. load_depth keeps the depth an earlier pass drew instead of
+// clearing it, I.E. the deferred G-buffer pass. clear_values holds the color then the depth.
+pub(crate) fn begin_dynamic_scene(logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer,
+                                  attachments: &SceneAttachments, render_area: vk::Rect2D, clear_values: &[vk::ClearValue],
+                                  load_depth: bool) {
+    // Color is cleared, so only the previous frame's reads are waited on
+    let color_range = first_mip(vk::ImageAspectFlags::COLOR, 1);
+    let barriers = Barriers::new()
+        .discard_image(attachments.color_image, color_range, Usage::FragmentSampled, Usage::ColorAttachment);
+    let barriers = match attachments.msaa {
+        Some((image, _)) => barriers.discard_image(image, color_range, Usage::ColorAttachment, Usage::ColorAttachment),
+        None => barriers
+    };
+    let depth_range = first_mip(attachments.depth_aspect(), 1);
+    let barriers = match load_depth {
+        true => barriers.image(attachments.depth_image, depth_range, Usage::DepthAttachment, Usage::DepthAttachment, Ownership::Keep),
+        false => barriers.discard_image(attachments.depth_image, depth_range, Usage::DepthAttachment, Usage::DepthAttachment)
+    };
+    barriers.record(logical_layer, command_buffer);
+
+    let color_attachment = vk::RenderingAttachmentInfo::default()
+        .image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .clear_value(clear_values[0]);
+    // The samples are averaged into the color image as the pass ends, then thrown away
+    let color_attachments = [match attachments.msaa {
+        Some((_, view)) => color_attachment
+            .image_view(view)
+            .store_op(vk::AttachmentStoreOp::DONT_CARE)
+            .resolve_mode(vk::ResolveModeFlags::AVERAGE)
+            .resolve_image_view(attachments.color_view)
+            .resolve_image_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL),
+        None => color_attachment
+            .image_view(attachments.color_view)
+            .store_op(vk::AttachmentStoreOp::STORE)
+    }];
+    let depth_attachment = vk::RenderingAttachmentInfo::default()
+        .image_view(attachments.depth_view)
+        .image_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL)
+        .load_op(match load_depth {
+            true => vk::AttachmentLoadOp::LOAD,
+            false => vk::AttachmentLoadOp::CLEAR
+        })
+        .store_op(vk::AttachmentStoreOp::DONT_CARE) // Depth isn't needed once drawing finishes
+        .clear_value(clear_values[1]);
+    let rendering_info = vk::RenderingInfo::default()
+        .render_area(render_area)
+        .layer_count(1)
+        .color_attachments(&color_attachments)
+        .depth_attachment(&depth_attachment);
+
+    unsafe { logical_layer.logical_device.cmd_begin_rendering(command_buffer, &rendering_info) };
+}
+
+// Ends the scene and leaves its color ready for post-processing to sample, like the render pass's final layout
+pub(crate) fn end_dynamic_scene(logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, attachments: &SceneAttachments) {
+    unsafe { logical_layer.logical_device.cmd_end_rendering(command_buffer) };
+    Barriers::new()
+        .image(attachments.color_image, first_mip(vk::ImageAspectFlags::COLOR, 1), Usage::ColorAttachment,
+               Usage::FragmentSampled, Ownership::Keep)
+        .record(logical_layer, command_buffer);
+}
+
+// The scene pass, rendering into the offscreen target that post-processing reads. Only used when the device
+// lacks dynamic rendering, see begin_dynamic_scene. With load_depth the depth buffer is expected to hold
+// what the deferred G-buffer pass drew, the pass stays compatible with the clearing one. When the render
+// target is multisampled, its MSAA image is attachment 2 and is resolved into the scene target at the end.
+pub(crate) fn setup_render_pass(logical_layer: &LogicalLayer, color_format: vk::Format, render_target: &RenderTarget,
+                                load_depth: bool) -> Result<vk::RenderPass, RendererError> {
     let attachment_desc = vk::AttachmentDescription::default() // Color attachment
-        .format(render_target.surface_format) // Should match the format of swap chain images
+        .format(color_format) // Should match the format of the scene target
         .samples(vk::SampleCountFlags::TYPE_1)
-        .load_op(vk::AttachmentLoadOp::CLEAR) // What to do with pre existing data in the attachment before rendering
+        .load_op(match render_target.multisampled() { // What to do with pre existing data in the attachment before rendering
+            true => vk::AttachmentLoadOp::DONT_CARE, // Entirely overwritten by the resolve
+            false => vk::AttachmentLoadOp::CLEAR
+        })
         .store_op(vk::AttachmentStoreOp::STORE) // What to do with data in attachment after rendering
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE) // Not sure what stencil buffer is
         .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
         .initial_layout(vk::ImageLayout::UNDEFINED) // image layout pre render
-        .final_layout(vk::ImageLayout::PRESENT_SRC_KHR); // Ready for presentation, not sure how that maps to a layout
+        .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL); // Sampled by the first post-process pass
 
-    let attachment_desc_array = [attachment_desc];
+    let (depth_load_op, depth_initial_layout) = match load_depth {
+        true => (vk::AttachmentLoadOp::LOAD, vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL),
+        false => (vk::AttachmentLoadOp::CLEAR, vk::ImageLayout::UNDEFINED)
+    };
+    let depth_attachment_desc = vk::AttachmentDescription::default()
+        .format(render_target.depth_format)
+        .samples(render_target.samples)
+        .load_op(depth_load_op)
+        .store_op(vk::AttachmentStoreOp::DONT_CARE) // Depth isn't needed once drawing finishes
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(depth_initial_layout)
+        .final_layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+    let msaa_attachment_desc = vk::AttachmentDescription::default()
+        .format(color_format)
+        .samples(render_target.samples)
+        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .store_op(vk::AttachmentStoreOp::DONT_CARE) // Only the resolved color is kept
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
+
+    let attachment_desc_array: Vec<vk::AttachmentDescription> = match render_target.multisampled() {
+        true => vec![attachment_desc, depth_attachment_desc, msaa_attachment_desc],
+        false => vec![attachment_desc, depth_attachment_desc]
+    };
 
     let attachment_ref = vk::AttachmentReference::default()
-        .attachment(0) // Index of attachment to reference
+        .attachment(match render_target.multisampled() { // Index of attachment to reference
+            true => 2,
+            false => 0
+        })
         .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL); // Optimal layout for a color attachment
 
     let attachment_ref_array = [attachment_ref];
 
+    let resolve_ref_array = [vk::AttachmentReference::default()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+
+    let depth_attachment_ref = vk::AttachmentReference::default()
+        .attachment(1)
+        .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
     let subpass = vk::SubpassDescription::default() // Each render pass consists of subpasses
         .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS) // Future Vulkan may have compute subpasses
-        .color_attachments(&attachment_ref_array);
+        .color_attachments(&attachment_ref_array)
+        .depth_stencil_attachment(&depth_attachment_ref); // Only one depth attachment per subpass
+    let subpass = match render_target.multisampled() {
+        true => subpass.resolve_attachments(&resolve_ref_array), // Averages the samples into attachment 0
+        false => subpass
+    };
 
     let subpass_array = [subpass];
 
     let subpass_dependency = vk::SubpassDependency::default()
         .src_subpass(vk::SUBPASS_EXTERNAL) // Refers to implicit subpass before the first sub pass
         .dst_subpass(0)  // vk::SUBPASS_EXTERNAL here would refer to the implicit after the last sub pass
-        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT) // Wait on the color attachment output stage (after color blending)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | // Wait on the color attachment output stage (after color blending)
+            vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS | // and on the depth clear of the previous frame
+            vk::PipelineStageFlags::FRAGMENT_SHADER) // and on post-processing reading the previous frame's scene
         .src_access_mask(vk::AccessFlags::empty())
-        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ |
+            vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
         .dependency_flags(vk::DependencyFlags::empty());
 
-    let dependencies = [subpass_dependency];
+    let post_dependency = vk::SubpassDependency::default()
+        .src_subpass(0)
+        .dst_subpass(vk::SUBPASS_EXTERNAL) // Post-processing samples the color attachment
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+        .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+        .dst_access_mask(vk::AccessFlags::SHADER_READ);
+
+    let dependencies = [subpass_dependency, post_dependency];
 
     let render_pass_create_info = vk::RenderPassCreateInfo::default()
         .attachments(&attachment_desc_array)
         .subpasses(&subpass_array)
         .dependencies(&dependencies);
 
-    unsafe {logical_layer.logical_device.create_render_pass(&render_pass_create_info, None).unwrap() }
+    unsafe {
+        logical_layer.logical_device.create_render_pass(&render_pass_create_info, None).map_err(vk_error("vkCreateRenderPass"))
+    }
 }
 
 pub(crate) fn destroy_render_pass(logical_layer: &LogicalLayer, render_pass: vk::RenderPass) {
     unsafe { logical_layer.logical_device.destroy_render_pass(render_pass, None) };
-}
\ No newline at end of file
+}
+// Fullscreen post-process passes. Every pixel is overwritten, so the previous contents are discarded.
+pub(crate) fn setup_post_render_pass(logical_layer: &LogicalLayer, format: vk::Format) -> Result<vk::RenderPass, RendererError> {
+    let attachments = [vk::AttachmentDescription::default()
+        .format(format)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)]; // Read by the next pass
+
+    let attachment_refs = [vk::AttachmentReference::default()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+
+    let subpasses = [vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&attachment_refs)];
+
+    let dependencies = [
+        vk::SubpassDependency::default()
+            .src_subpass(vk::SUBPASS_EXTERNAL) // Earlier passes reading or writing the same target
+            .dst_subpass(0)
+            .src_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER | vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE),
+        vk::SubpassDependency::default()
+            .src_subpass(0)
+            .dst_subpass(vk::SUBPASS_EXTERNAL) // Later passes sampling the result
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+            .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+            .dst_access_mask(vk::AccessFlags::SHADER_READ)
+    ];
+
+    let create_info = vk::RenderPassCreateInfo::default()
+        .attachments(&attachments)
+        .subpasses(&subpasses)
+        .dependencies(&dependencies);
+
+    unsafe {
+        logical_layer.logical_device.create_render_pass(&create_info, None).map_err(vk_error("vkCreateRenderPass"))
+    }
+}
+
+// Copies the end of the post-process chain into the swapchain image, or the offscreen image when headless
+pub(crate) fn setup_present_render_pass(logical_layer: &LogicalLayer, render_target: &RenderTarget) -> Result<vk::RenderPass, RendererError> {
+    let attachments = [vk::AttachmentDescription::default()
+        .format(render_target.surface_format)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .load_op(vk::AttachmentLoadOp::DONT_CARE) // Fully overwritten by the blit
+        .store_op(vk::AttachmentStoreOp::STORE)
+        .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+        .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .final_layout(render_target.final_layout())];
+
+    let attachment_refs = [vk::AttachmentReference::default()
+        .attachment(0)
+        .layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)];
+
+    let subpasses = [vk::SubpassDescription::default()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&attachment_refs)];
+
+    let dependencies = [vk::SubpassDependency::default()
+        .src_subpass(vk::SUBPASS_EXTERNAL) // Waits on the image acquire semaphore's stage
+        .dst_subpass(0)
+        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .src_access_mask(vk::AccessFlags::empty())
+        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)];
+
+    let create_info = vk::RenderPassCreateInfo::default()
+        .attachments(&attachments)
+        .subpasses(&subpasses)
+        .dependencies(&dependencies);
+
+    unsafe {
+        logical_layer.logical_device.create_render_pass(&create_info, None).map_err(vk_error("vkCreateRenderPass"))
+    }
+}
@@ -0,0 +1,277 @@
+use glam::{Mat4, Vec3};
+
+use crate::renderer::dynamic_mesh::DynamicMeshHandle;
+use crate::renderer::frustum::{Aabb, Frustum};
+use crate::renderer::indirect::IndirectBatchHandle;
+use crate::renderer::instance::Instance;
+use crate::renderer::material::{BlendMode, ShaderVariant};
+use crate::renderer::mesh::MeshHandle;
+use crate::renderer::occlusion::OcclusionId;
+use crate::renderer::resources::Handle;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct MaterialHandle(pub(crate) Handle);
+
+impl MaterialHandle {
+    pub const DEFAULT: MaterialHandle = MaterialHandle(Handle::first(0)); // Default shader, white and untextured
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct RenderItem {
+    pub mesh: MeshHandle,
+    pub transform: Mat4, // Model matrix, pushed to the vertex shader per draw
+    pub material: MaterialHandle,
+    pub occlusion: Option<OcclusionId>, // Skipped while its bounds are hidden behind other geometry
+    pub pick_id: u32 // Written to the ID buffer for CubulousRenderer::read_id_at, 0 for none
+}
+
+// One draw of a mesh for each of a run of instances
+#[derive(Clone, Copy, Debug)]
+pub struct InstancedItem {
+    pub mesh: MeshHandle,
+    pub material: MaterialHandle,
+    pub first_instance: u32, // Index into the queue's instances
+    pub instance_count: u32
+}
+
+// A draw of a dynamic mesh's current geometry. Never culled, since the geometry has no fixed bounds.
+#[derive(Clone, Copy, Debug)]
+pub struct DynamicItem {
+    pub mesh: DynamicMeshHandle,
+    pub transform: Mat4,
+    pub material: MaterialHandle
+}
+
+// An alpha blended draw, indexing into the queue's items, instanced items or dynamic items
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TransparentDraw {
+    Item(usize),
+    Instanced(usize),
+    Dynamic(usize)
+}
+
+// Draws recorded each frame. The queue is not cleared by the renderer, so the application is
+// responsible for calling clear() before refilling it.
+#[derive(Default)]
+pub struct RenderQueue {
+    items: Vec<RenderItem>,
+    instanced: Vec<InstancedItem>,
+    dynamic: Vec<DynamicItem>,
+    indirect: Vec<IndirectBatchHandle>, // Culled on the GPU rather than here
+    instances: Vec<Instance>, // Shared by every instanced draw, copied to the GPU once per frame
+    opaque: [usize; 3], // Leading opaque items, instanced items and dynamic items once sorted, the blended ones follow
+    transparent: Vec<TransparentDraw> // Every blended draw back to front, filled by sort
+}
+
+impl RenderQueue {
+    pub fn new() -> RenderQueue {
+        RenderQueue {
+            items: Vec::new(),
+            instanced: Vec::new(),
+            dynamic: Vec::new(),
+            indirect: Vec::new(),
+            instances: Vec::new(),
+            opaque: [0; 3],
+            transparent: Vec::new()
+        }
+    }
+
+    pub fn push(&mut self, mesh: MeshHandle, transform: Mat4, material: MaterialHandle) {
+        self.items.push(RenderItem {
+            mesh,
+            transform,
+            material,
+            occlusion: None,
+            pick_id: 0
+        });
+    }
+
+    // As push, with an ID read_id_at returns for the draw's pixels, I.E. an entity ID. 0 is no ID.
+    pub fn push_pickable(&mut self, mesh: MeshHandle, transform: Mat4, material: MaterialHandle, pick_id: u32) {
+        self.items.push(RenderItem {
+            mesh,
+            transform,
+            material,
+            occlusion: None,
+            pick_id
+        });
+    }
+
+    // As push, but the draw is dropped while occlusion queries find the mesh's bounds hidden
+    pub fn push_occludable(&mut self, mesh: MeshHandle, transform: Mat4, material: MaterialHandle, id: OcclusionId) {
+        self.items.push(RenderItem {
+            mesh,
+            transform,
+            material,
+            occlusion: Some(id),
+            pick_id: 0
+        });
+    }
+
+    // Draws mesh once per instance in a single draw call
+    pub fn push_instanced(&mut self, mesh: MeshHandle, instances: &[Instance], material: MaterialHandle) {
+        if instances.is_empty() {
+            return;
+        }
+        self.instanced.push(InstancedItem {
+            mesh,
+            material,
+            first_instance: self.instances.len() as u32,
+            instance_count: instances.len() as u32
+        });
+        self.instances.extend_from_slice(instances);
+    }
+
+    pub fn push_dynamic(&mut self, mesh: DynamicMeshHandle, transform: Mat4, material: MaterialHandle) {
+        self.dynamic.push(DynamicItem {
+            mesh,
+            transform,
+            material
+        });
+    }
+
+    // Every object of the batch in one indirect draw. Pushing a batch more than once still draws it once.
+    pub fn push_indirect(&mut self, batch: IndirectBatchHandle) {
+        if !self.indirect.contains(&batch) {
+            self.indirect.push(batch);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.instanced.clear();
+        self.dynamic.clear();
+        self.indirect.clear();
+        self.instances.clear();
+        self.opaque = [0; 3];
+        self.transparent.clear();
+    }
+
+    // Number of draw calls, an instanced draw counts once
+    pub fn len(&self) -> usize {
+        self.items.len() + self.instanced.len() + self.dynamic.len() + self.indirect.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.is_empty() && self.instanced.is_empty() && self.dynamic.is_empty() && self.indirect.is_empty()
+    }
+
+    pub fn items(&self) -> &[RenderItem] {
+        &self.items
+    }
+
+    pub fn instanced_items(&self) -> &[InstancedItem] {
+        &self.instanced
+    }
+
+    pub fn dynamic_items(&self) -> &[DynamicItem] {
+        &self.dynamic
+    }
+
+    pub fn indirect_batches(&self) -> &[IndirectBatchHandle] {
+        &self.indirect
+    }
+
+    pub fn instances(&self) -> &[Instance] {
+        &self.instances
+    }
+
+    pub(crate) fn opaque_items(&self) -> &[RenderItem] {
+        &self.items[..self.opaque[0]]
+    }
+
+    pub(crate) fn opaque_instanced_items(&self) -> &[InstancedItem] {
+        &self.instanced[..self.opaque[1]]
+    }
+
+    pub(crate) fn opaque_dynamic_items(&self) -> &[DynamicItem] {
+        &self.dynamic[..self.opaque[2]]
+    }
+
+    pub(crate) fn transparent(&self) -> &[TransparentDraw] {
+        &self.transparent
+    }
+
+    // Drops draws and instances whose bounds are outside the frustum. bounds gives a mesh's model space
+    // bounds, draws of meshes without any are kept. Returns the number of objects drawn and culled.
+    pub(crate) fn cull<F>(&mut self, frustum: &Frustum, bounds: F) -> (usize, usize)
+        where F: Fn(MeshHandle) -> Option<Aabb> {
+        let visible = |b: &Aabb, transform: &Mat4| frustum.intersects(&b.transformed(transform));
+        let total = self.items.len() + self.instances.len() + self.dynamic.len();
+
+        self.items.retain(|i| bounds(i.mesh).map_or(true, |b| visible(&b, &i.transform)));
+
+        // Instances are compacted, so each instanced draw's range is rebuilt
+        let mut instances: Vec<Instance> = Vec::with_capacity(self.instances.len());
+        for item in self.instanced.iter_mut() {
+            let first = instances.len();
+            let range = item.first_instance as usize..(item.first_instance + item.instance_count) as usize;
+            match bounds(item.mesh) {
+                Some(b) => instances.extend(self.instances[range]
+                    .iter()
+                    .filter(|i| visible(&b, &Mat4::from_cols_array_2d(&i.transform)))),
+                None => instances.extend_from_slice(&self.instances[range])
+            }
+            item.first_instance = first as u32;
+            item.instance_count = (instances.len() - first) as u32;
+        }
+        self.instanced.retain(|i| i.instance_count > 0);
+        self.instances = instances;
+
+        let drawn = self.items.len() + self.instances.len() + self.dynamic.len();
+        (drawn, total - drawn)
+    }
+
+    // Drops items with an occlusion id that visible rejects. Returns the number dropped.
+    pub(crate) fn cull_occluded<F>(&mut self, mut visible: F) -> usize
+        where F: FnMut(OcclusionId, &RenderItem) -> bool {
+        let before = self.items.len();
+        self.items.retain(|i| match i.occlusion {
+            Some(id) => visible(id, i),
+            None => true
+        });
+        before - self.items.len()
+    }
+
+    // Group opaque draws so consecutive items share as much bound state as possible. Pipeline changes are
+    // the most expensive, then material descriptor sets, then vertex buffers. Blended draws go after
+    // every opaque one, ordered back to front from eye by their origin since blending isn't commutative.
+    // Instances of a blended instanced draw are ordered the same way, so this runs before they're uploaded.
+    pub(crate) fn sort<F>(&mut self, eye: Vec3, material: F)
+        where F: Fn(MaterialHandle) -> (ShaderVariant, BlendMode) {
+        let key = |m: MaterialHandle| {
+            let (shader, blend) = material(m);
+            (blend, shader)
+        };
+        let blended = |m: MaterialHandle| material(m).1 != BlendMode::Opaque;
+        let distance = |transform: &Mat4| eye.distance_squared(transform.w_axis.truncate());
+        self.items.sort_by_key(|i| (key(i.material), i.material, i.mesh.0));
+        self.instanced.sort_by_key(|i| (key(i.material), i.material, i.mesh.0)); // Instances stay put, items index into them
+        self.dynamic.sort_by_key(|i| (key(i.material), i.material, i.mesh.0));
+        self.opaque = [
+            self.items.iter().take_while(|i| !blended(i.material)).count(),
+            self.instanced.iter().take_while(|i| !blended(i.material)).count(),
+            self.dynamic.iter().take_while(|i| !blended(i.material)).count()
+        ];
+
+        let mut transparent: Vec<(f32, TransparentDraw)> = Vec::new();
+        for (i, item) in self.items.iter().enumerate().skip(self.opaque[0]) {
+            transparent.push((distance(&item.transform), TransparentDraw::Item(i)));
+        }
+        for (i, item) in self.instanced.iter().enumerate().skip(self.opaque[1]) {
+            let range = item.first_instance as usize..(item.first_instance + item.instance_count) as usize;
+            let instances = &mut self.instances[range];
+            // The draw as a whole goes by its farthest instance
+            instances.sort_by(|a, b| {
+                let (a, b) = (Mat4::from_cols_array_2d(&a.transform), Mat4::from_cols_array_2d(&b.transform));
+                distance(&b).total_cmp(&distance(&a))
+            });
+            transparent.push((distance(&Mat4::from_cols_array_2d(&instances[0].transform)), TransparentDraw::Instanced(i)));
+        }
+        for (i, item) in self.dynamic.iter().enumerate().skip(self.opaque[2]) {
+            transparent.push((distance(&item.transform), TransparentDraw::Dynamic(i)));
+        }
+        transparent.sort_by(|a, b| b.0.total_cmp(&a.0));
+        self.transparent = transparent.into_iter().map(|(_, d)| d).collect();
+    }
+}
@@ -10,20 +10,42 @@ use winit::{
     window::{Icon, Window, WindowBuilder, WindowId},
 };
 
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::config::{ColorSpace, PresentMode, SurfaceFormat};
 use crate::renderer::core::Core;
+use crate::renderer::error::{vk_error, RendererError};
 use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
 use crate::renderer::physical_layer::PhysicalLayer;
 
+const OFFSCREEN_FORMAT: vk::Format = vk::Format::R8G8B8A8_SRGB; // RGBA byte order, so read back pixels need no swizzle
+const OFFSCREEN_IMAGES: usize = 2; // One per frame in flight, frames index them directly instead of acquiring
+
 pub(crate) struct RenderTarget {
     pub(crate) swap_loader: Swapchain,
-    pub(crate) swap_chain: vk::SwapchainKHR,
+    pub(crate) swap_chain: vk::SwapchainKHR, // Null when headless
+    offscreen: Vec<(vk::Image, Allocation)>, // Stand in for the swapchain images when headless
     pub(crate) surface_format: vk::Format,
+    pub(crate) color_space: ColorSpace,
     pub(crate) extent: vk::Extent2D,
+    pub(crate) scene_extent: vk::Extent2D, // extent times the render scale, what the scene and post-processing draw at
     pub(crate) image_views: Vec<vk::ImageView>,
+    pub(crate) depth_format: vk::Format,
+    pub(crate) depth_image: vk::Image,
+    depth_alloc: Option<Allocation>, // Taken on destroy
+    pub(crate) depth_view: vk::ImageView,
+    pub(crate) samples: vk::SampleCountFlags, // Of the depth buffer and the scene pass, TYPE_1 without MSAA
+    pub(crate) msaa_image: vk::Image, // Multisampled scene color resolved into the post-process scene target, null without MSAA
+    msaa_alloc: Option<Allocation>,
+    pub(crate) msaa_view: vk::ImageView
 }
 
 impl RenderTarget {
-    pub(crate) fn new(core: &Core, physical_layer: &PhysicalLayer, logical_layer: &LogicalLayer) -> RenderTarget {
+    // scene_format is the color format the multisampled image is made in when samples isn't TYPE_1. The depth
+    // and multisampled images are render_scale times the swapchain's size.
+    pub(crate) fn new(core: &Core, physical_layer: &PhysicalLayer, logical_layer: &LogicalLayer, allocator: &Allocator,
+                      present_mode: PresentMode, surface_formats: &[SurfaceFormat], scene_format: vk::Format,
+                      samples: vk::SampleCountFlags, render_scale: f32) -> Result<RenderTarget, RendererError> {
         fn choose_swap_extent(window: &Window, capabilities: &vk::SurfaceCapabilitiesKHR) -> vk::Extent2D {
             if capabilities.current_extent.width != u32::MAX {
                 capabilities.current_extent
@@ -40,15 +62,10 @@ impl RenderTarget {
             }
         }
 
-        fn setup_image_views(logical_layer: &LogicalLayer, swap_loader: &Swapchain, swap_chain: vk::SwapchainKHR, surface_format: vk::Format) -> Vec<vk::ImageView> {
-            let swap_chain_images: Vec<vk::Image>;
-            unsafe {
-                swap_chain_images = swap_loader
-                    .get_swapchain_images(swap_chain).unwrap();
-            }
-
+        fn setup_image_views(logical_layer: &LogicalLayer, images: &[vk::Image], surface_format: vk::Format)
+            -> Result<Vec<vk::ImageView>, RendererError> {
             let mut image_views: Vec<vk::ImageView> = Vec::new();
-            for i in swap_chain_images {
+            for &i in images {
                 let create_info = vk::ImageViewCreateInfo::default()
                     .image(i)
                     .view_type(vk::ImageViewType::TYPE_2D)
@@ -69,100 +86,377 @@ impl RenderTarget {
                     });
 
                 unsafe {
-                    image_views.push(  logical_layer.logical_device.create_image_view(&create_info, None).unwrap());
+                    image_views.push(logical_layer.logical_device.create_image_view(&create_info, None)
+                        .map_err(vk_error("vkCreateImageView"))?);
                 }
             }
 
-            return image_views;
+            return Ok(image_views);
         }
 
-        let capabilities: vk::SurfaceCapabilitiesKHR;
-        unsafe {
-            capabilities = core.surface_loader
-                .get_physical_device_surface_capabilities(physical_layer.physical_device,
-                                                          core.surface).unwrap();
+        // Color attachments that can be copied out of, I.E. to read a frame back in tests
+        fn setup_offscreen_images(logical_layer: &LogicalLayer, allocator: &Allocator, extent: vk::Extent2D)
+            -> Result<Vec<(vk::Image, Allocation)>, RendererError> {
+            let create_info = vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .extent(vk::Extent3D {
+                    width: extent.width,
+                    height: extent.height,
+                    depth: 1
+                })
+                .mip_levels(1)
+                .array_layers(1)
+                .format(OFFSCREEN_FORMAT)
+                .tiling(vk::ImageTiling::OPTIMAL)
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+            let mut images = Vec::with_capacity(OFFSCREEN_IMAGES);
+            for _ in 0..OFFSCREEN_IMAGES {
+                match allocator.create_image(logical_layer, &create_info, vk::MemoryPropertyFlags::DEVICE_LOCAL, MemoryCategory::RenderTargets) {
+                    Ok((alloc, image)) => images.push((image, alloc)),
+                    Err(e) => {
+                        for (image, alloc) in images.iter() {
+                            allocator.destroy_image(logical_layer, *image, alloc);
+                        }
+                        return Err(e);
+                    }
+                }
+            }
+
+            Ok(images)
         }
 
-        // Choose the first surface format with the specified conditions or choose the first option
-        // otherwise
-        let surface_format =
-            match physical_layer
-                .supported_surface_formats
-                .iter()
-                .find(|f|f.format == vk::Format::B8G8R8A8_SRGB &&
-                    f.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR)
-            {
-                Some(x) => x,
-                None => &physical_layer.supported_surface_formats[0]
-            };
+        fn choose_depth_format(core: &Core, physical_layer: &PhysicalLayer) -> Result<vk::Format, RendererError> {
+            // In order of preference, stencil components are unused for now
+            let candidates = [vk::Format::D32_SFLOAT,
+                vk::Format::D32_SFLOAT_S8_UINT,
+                vk::Format::D24_UNORM_S8_UINT];
+
+            candidates.iter()
+                .copied()
+                .find(|&f| physical_layer.supports_format(core, f, vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT))
+                .ok_or(RendererError::NoSuitableFormat("depth buffer"))
+        }
+
+        // The depth buffer, or the multisampled color image. Both are only used within the scene pass.
+        fn setup_attachment(logical_layer: &LogicalLayer,
+                            allocator: &Allocator,
+                            extent: vk::Extent2D,
+                            format: vk::Format,
+                            usage: vk::ImageUsageFlags,
+                            aspect_mask: vk::ImageAspectFlags,
+                            samples: vk::SampleCountFlags) -> Result<(vk::Image, Allocation, vk::ImageView), RendererError> {
+            let image_create_info = vk::ImageCreateInfo::default()
+                .image_type(vk::ImageType::TYPE_2D)
+                .extent(vk::Extent3D {
+                    width: extent.width,
+                    height: extent.height,
+                    depth: 1
+                })
+                .mip_levels(1)
+                .array_layers(1)
+                .format(format)
+                .tiling(vk::ImageTiling::OPTIMAL) // Implementation defined layout, only the GPU touches this image
+                .initial_layout(vk::ImageLayout::UNDEFINED)
+                .usage(usage)
+                .samples(samples)
+                .sharing_mode(vk::SharingMode::EXCLUSIVE);
 
-        let presentation_mode =
-            match physical_layer
-                .present_modes
-                .iter()
-                .find(|p|**p == vk::PresentModeKHR::MAILBOX)
-            {
-                Some(x) => *x,
-                None => vk::PresentModeKHR::FIFO
+            let (alloc, image) = allocator.create_image(logical_layer,
+                                                        &image_create_info,
+                                                        vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                        MemoryCategory::RenderTargets)?;
+
+            let view_create_info = vk::ImageViewCreateInfo::default()
+                .image(image)
+                .view_type(vk::ImageViewType::TYPE_2D)
+                .format(format)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: 1
+                });
+            let view = match unsafe { logical_layer.logical_device.create_image_view(&view_create_info, None) } {
+                Ok(v) => v,
+                Err(e) => {
+                    allocator.destroy_image(logical_layer, image, &alloc);
+                    return Err(vk_error("vkCreateImageView")(e));
+                }
             };
 
-        let extent = choose_swap_extent(&core.window, &capabilities);
+            Ok((image, alloc, view))
+        }
 
-        let mut image_count = capabilities.min_image_count + 1;
-        if capabilities.max_image_count > 0 && image_count > capabilities.max_image_count {
-            image_count = capabilities.max_image_count
+        // The first preference the surface supports, otherwise the first supported format with a warning
+        fn choose_surface_format(physical_layer: &PhysicalLayer, preferences: &[SurfaceFormat]) -> SurfaceFormat {
+            let supported = physical_layer.surface_formats();
+            match preferences.iter().find(|p| supported.contains(p)) {
+                Some(p) => *p,
+                None => {
+                    // Every surface supports sRGB, so supported is never empty
+                    let fallback = supported.first().copied().unwrap_or(SurfaceFormat {
+                        format: physical_layer.supported_surface_formats[0].format,
+                        color_space: ColorSpace::Srgb
+                    });
+                    log::warn!("None of the preferred surface formats are supported, using {:?}", fallback);
+                    fallback
+                }
+            }
         }
 
-        let swap_create_info = vk::SwapchainCreateInfoKHR::default()
-            .min_image_count(image_count)
-            .image_format(surface_format.format)
-            .image_color_space(surface_format.color_space)
-            .image_extent(extent)
-            .image_array_layers(1) // Always 1 except for stereoscopic 3D, I.E. VR
-            .surface(core.surface)
-
-            // TODO This assumes only one queue family. Consider adding support for separate queue
-            // families later on
-            .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
-
-            .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT) // "It is also possible that you'll
-            // render images to a separate image first to perform
-            // operations like post-processing. In that case you may use a value like
-            // VK_IMAGE_USAGE_TRANSFER_DST_BIT instead and use a memory operation to transfer the rendered
-            // image to a swap chain image."
-            .pre_transform(capabilities.current_transform)
-            .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
-            .present_mode(presentation_mode)
-            .clipped(true)
-            .old_swapchain(vk::SwapchainKHR::null());
+        fn setup_swap_chain(core: &Core, physical_layer: &PhysicalLayer, swap_loader: &Swapchain, surface: vk::SurfaceKHR,
+                            present_mode: PresentMode, surface_format: SurfaceFormat)
+            -> Result<(vk::SwapchainKHR, vk::Extent2D), RendererError> {
+            let capabilities: vk::SurfaceCapabilitiesKHR;
+            unsafe {
+                capabilities = core.surface_loader
+                    .get_physical_device_surface_capabilities(physical_layer.physical_device,
+                                                              surface)
+                    .map_err(vk_error("vkGetPhysicalDeviceSurfaceCapabilitiesKHR"))?;
+            }
 
-        let swap_loader = Swapchain::new(&core.instance, &logical_layer.logical_device);
-        let swap_chain: vk::SwapchainKHR;
-        unsafe {
-            swap_chain = swap_loader
-                .create_swapchain(&swap_create_info, None).unwrap();
+            let presentation_mode = match physical_layer.present_modes.contains(&present_mode.to_vk()) {
+                true => present_mode.to_vk(),
+                false => {
+                    log::warn!("{:?} presentation isn't supported, falling back to Fifo", present_mode);
+                    vk::PresentModeKHR::FIFO // Always supported
+                }
+            };
+
+            let extent = choose_swap_extent(core.window(), &capabilities);
+
+            let mut image_count = capabilities.min_image_count + 1;
+            if capabilities.max_image_count > 0 && image_count > capabilities.max_image_count {
+                image_count = capabilities.max_image_count
+            }
+
+            let swap_create_info = vk::SwapchainCreateInfoKHR::default()
+                .min_image_count(image_count)
+                .image_format(surface_format.format)
+                .image_color_space(surface_format.color_space.to_vk())
+                .image_extent(extent)
+                .image_array_layers(1) // Always 1 except for stereoscopic 3D, I.E. VR
+                .surface(surface)
+
+
+                .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT) // "It is also possible that you'll
+                // render images to a separate image first to perform
+                // operations like post-processing. In that case you may use a value like
+                // VK_IMAGE_USAGE_TRANSFER_DST_BIT instead and use a memory operation to transfer the rendered
+                // image to a swap chain image."
+                .pre_transform(capabilities.current_transform)
+                .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
+                .present_mode(presentation_mode)
+                .clipped(true)
+                .old_swapchain(vk::SwapchainKHR::null());
+
+            // Images are shared between the graphics and present queues if they're in separate families,
+            // CONCURRENT avoids ownership transfers at the cost of some performance
+            let queue_families = [physical_layer.family_index, physical_layer.present_family_index];
+            let swap_create_info = match physical_layer.family_index == physical_layer.present_family_index {
+                true => swap_create_info.image_sharing_mode(vk::SharingMode::EXCLUSIVE),
+                false => swap_create_info
+                    .image_sharing_mode(vk::SharingMode::CONCURRENT)
+                    .queue_family_indices(&queue_families)
+            };
+
+            let swap_chain: vk::SwapchainKHR;
+            unsafe {
+                swap_chain = swap_loader
+                    .create_swapchain(&swap_create_info, None).map_err(vk_error("vkCreateSwapchainKHR"))?;
+            }
+            Ok((swap_chain, extent))
         }
-        let image_views = setup_image_views(&logical_layer,
-                                            &swap_loader,
-                                            swap_chain,
-                                            surface_format.format);
 
-        return RenderTarget {
+        let swap_loader = Swapchain::new(&core.instance, &logical_layer.logical_device);
+        let (swap_chain, offscreen, surface_format, extent, image_views) = match (core.surface, core.offscreen_extent) {
+            (Some(surface), _) => {
+                let surface_format = choose_surface_format(physical_layer, surface_formats);
+                let (swap_chain, extent) = setup_swap_chain(core, physical_layer, &swap_loader, surface, present_mode, surface_format)?;
+                let images = unsafe {
+                    swap_loader.get_swapchain_images(swap_chain).map_err(vk_error("vkGetSwapchainImagesKHR"))?
+                };
+                let image_views = setup_image_views(logical_layer, &images, surface_format.format)?;
+                (swap_chain, Vec::new(), surface_format, extent, image_views)
+            },
+            (None, Some(extent)) => {
+                let offscreen = setup_offscreen_images(logical_layer, allocator, extent)?;
+                let images: Vec<vk::Image> = offscreen.iter().map(|(i, _)| *i).collect();
+                let image_views = setup_image_views(logical_layer, &images, OFFSCREEN_FORMAT)?;
+                let surface_format = SurfaceFormat { format: OFFSCREEN_FORMAT, color_space: ColorSpace::Srgb };
+                (vk::SwapchainKHR::null(), offscreen, surface_format, extent, image_views)
+            },
+            (None, None) => unreachable!("Core has either a surface or an offscreen extent")
+        };
+
+        let scene_extent = vk::Extent2D {
+            width: ((extent.width as f32 * render_scale).round() as u32).max(1),
+            height: ((extent.height as f32 * render_scale).round() as u32).max(1)
+        };
+        let depth_format = choose_depth_format(core, physical_layer)?;
+        let (depth_image, depth_alloc, depth_view) = setup_attachment(logical_layer,
+                                                                      allocator,
+                                                                      scene_extent,
+                                                                      depth_format,
+                                                                      vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+                                                                      vk::ImageAspectFlags::DEPTH,
+                                                                      samples)?;
+        let msaa = match samples == vk::SampleCountFlags::TYPE_1 {
+            true => None,
+            false => match setup_attachment(logical_layer, allocator, scene_extent, scene_format,
+                                            vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT,
+                                            vk::ImageAspectFlags::COLOR, samples) {
+                Ok(m) => Some(m),
+                Err(e) => {
+                    unsafe { logical_layer.logical_device.destroy_image_view(depth_view, None) };
+                    allocator.destroy_image(logical_layer, depth_image, &depth_alloc);
+                    return Err(e);
+                }
+            }
+        };
+        let (msaa_image, msaa_alloc, msaa_view) = match msaa {
+            Some((image, alloc, view)) => (image, Some(alloc), view),
+            None => (vk::Image::null(), None, vk::ImageView::null())
+        };
+
+        return Ok(RenderTarget {
             swap_chain,
             swap_loader,
+            offscreen,
             surface_format: surface_format.format,
+            color_space: surface_format.color_space,
             extent,
-            image_views
+            scene_extent,
+            image_views,
+            depth_format,
+            depth_image,
+            depth_alloc: Some(depth_alloc),
+            depth_view,
+            samples,
+            msaa_image,
+            msaa_alloc,
+            msaa_view
+        })
+    }
+
+    pub(crate) fn multisampled(&self) -> bool {
+        self.samples != vk::SampleCountFlags::TYPE_1
+    }
+
+    pub(crate) fn headless(&self) -> bool {
+        !self.offscreen.is_empty()
+    }
+
+    // Layout the present pass leaves the final image in
+    pub(crate) fn final_layout(&self) -> vk::ImageLayout {
+        match self.headless() {
+            true => vk::ImageLayout::TRANSFER_SRC_OPTIMAL, // Only ever copied out of
+            false => vk::ImageLayout::PRESENT_SRC_KHR
+        }
+    }
+
+    // Copies an offscreen image into tightly packed RGBA8 pixels. The image must have been drawn and
+    // the device idle.
+    pub(crate) fn read_offscreen(&self, logical_layer: &LogicalLayer, allocator: &Allocator, command_pool: vk::CommandPool,
+                                 index: usize) -> Result<Vec<u8>, RendererError> {
+        fn copy(logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, image: vk::Image, buf: vk::Buffer,
+                extent: vk::Extent2D) -> Result<(), RendererError> {
+            let device = &logical_layer.logical_device;
+            let begin_info = vk::CommandBufferBeginInfo::default()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            let regions = [vk::BufferImageCopy::default()
+                .buffer_offset(0)
+                .buffer_row_length(0) // Tightly packed
+                .buffer_image_height(0)
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: 0,
+                    base_array_layer: 0,
+                    layer_count: 1
+                })
+                .image_extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })];
+            let command_buffers = [command_buffer];
+            let submits = [vk::SubmitInfo::default()
+                .command_buffers(&command_buffers)];
+
+            unsafe {
+                device.begin_command_buffer(command_buffer, &begin_info).map_err(vk_error("vkBeginCommandBuffer"))?;
+                // The present pass already left the image in TRANSFER_SRC_OPTIMAL
+                device.cmd_copy_image_to_buffer(command_buffer, image, vk::ImageLayout::TRANSFER_SRC_OPTIMAL, buf, &regions);
+                device.end_command_buffer(command_buffer).map_err(vk_error("vkEndCommandBuffer"))?;
+                device.queue_submit(logical_layer.logical_queue, &submits, vk::Fence::null()).map_err(vk_error("vkQueueSubmit"))?;
+                device.queue_wait_idle(logical_layer.logical_queue).map_err(vk_error("vkQueueWaitIdle"))?;
+            }
+
+            Ok(())
         }
+
+        let (image, _) = &self.offscreen[index];
+        let size = self.extent.width as usize * self.extent.height as usize * 4;
+        let (alloc, buf) = allocator.create_buffer(logical_layer,
+                                                   size as vk::DeviceSize,
+                                                   vk::BufferUsageFlags::TRANSFER_DST,
+                                                   vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                                       vk::MemoryPropertyFlags::HOST_COHERENT,
+                                                   MemoryCategory::Staging)?;
+        let alloc_info = vk::CommandBufferAllocateInfo::default()
+            .command_pool(command_pool)
+            .level(vk::CommandBufferLevel::PRIMARY)
+            .command_buffer_count(1);
+        let command_buffer = match unsafe { logical_layer.logical_device.allocate_command_buffers(&alloc_info) } {
+            Ok(b) => b[0],
+            Err(e) => {
+                allocator.destroy_buffer(logical_layer, buf, &alloc);
+                return Err(vk_error("vkAllocateCommandBuffers")(e));
+            }
+        };
+
+        let result = copy(logical_layer, command_buffer, *image, buf, self.extent).map(|_| {
+            let mut pixels = vec![0u8; size];
+            let ptr = alloc.mapped_ptr().unwrap(); // Host visible memory is always mapped
+            unsafe { std::ptr::copy_nonoverlapping(ptr, pixels.as_mut_ptr(), size) };
+            pixels
+        });
+        unsafe { logical_layer.logical_device.free_command_buffers(command_pool, &[command_buffer]) };
+        allocator.destroy_buffer(logical_layer, buf, &alloc);
+
+        result
     }
 
-    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer) {
+    // Handles are nulled afterwards so destroying twice, I.E. after a failed recreation, is harmless
+    pub(crate) fn destroy(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
         unsafe {
             for &v in self.image_views.iter() {
                 logical_layer.logical_device.destroy_image_view(v, None);
             }
 
-            self.swap_loader.destroy_swapchain(self.swap_chain, None);
+            logical_layer.logical_device.destroy_image_view(self.depth_view, None);
+            if let Some(depth_alloc) = self.depth_alloc.take() {
+                allocator.destroy_image(logical_layer, self.depth_image, &depth_alloc);
+            }
+            logical_layer.logical_device.destroy_image_view(self.msaa_view, None); // Null is ignored
+            if let Some(msaa_alloc) = self.msaa_alloc.take() {
+                allocator.destroy_image(logical_layer, self.msaa_image, &msaa_alloc);
+            }
+
+            if self.swap_chain != vk::SwapchainKHR::null() { // Headless targets never load the swapchain functions
+                self.swap_loader.destroy_swapchain(self.swap_chain, None);
+            }
+            for (image, alloc) in self.offscreen.drain(..) {
+                allocator.destroy_image(logical_layer, image, &alloc);
+            }
         }
+
+        self.image_views.clear();
+        self.depth_view = vk::ImageView::null();
+        self.depth_image = vk::Image::null();
+        self.msaa_view = vk::ImageView::null();
+        self.msaa_image = vk::Image::null();
+        self.swap_chain = vk::SwapchainKHR::null();
     }
-}
+}
\ No newline at end of file
@@ -1,3 +1,4 @@
+use std::cell::Cell;
 use std::env;
 use std::ffi::{c_char, CStr, CString};
 use std::fs::File;
@@ -5,10 +6,13 @@ use std::io::{Read, Seek, SeekFrom};
 use std::marker::PhantomData;
 use std::mem;
 use std::path::Path;
+use std::time::{Duration, Instant};
 
 use ash::{vk, Device, Entry, Instance};
 use ash::extensions::khr::{Surface, Swapchain};
 use ash::vk::{CommandBuffer, PhysicalDevice};
+use bytemuck::Pod;
+use glam::{Mat4, Vec2, Vec3, Vec4};
 use num::clamp;
 use raw_window_handle::{HasRawDisplayHandle, HasRawWindowHandle}; // Entry holds Vulkan functions
 // vk holds Vulkan structs with no methods along with Vulkan macros
@@ -19,62 +23,183 @@ use winit::{
     dpi::LogicalSize,
     event::{Event, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
-    window::{Icon, Window, WindowBuilder, WindowId},
+    window::{Fullscreen, Icon, Window, WindowBuilder, WindowId},
 };
-use crate::renderer::core::Core;
+use crate::crash;
+use crate::input::InputState;
+use crate::input::gamepad::Gamepads;
+use crate::profiling::{self, profile_function, profile_scope};
+use crate::renderer::allocator::Allocator;
+use crate::renderer::camera::Camera;
+use crate::renderer::capabilities::DeviceCapabilities;
+use crate::renderer::compute::{Compute, ComputeDispatch, ComputePipelineHandle, StorageBufferHandle};
+use crate::renderer::compute_pipeline::ComputePipeline;
+use crate::renderer::config::{CursorMode, FullscreenMode, PresentMode, RendererConfig, ShadingPipeline, SurfaceFormat,
+                              MAX_RENDER_SCALE, MIN_RENDER_SCALE};
+use crate::renderer::core::{apply_cursor_mode, winit_fullscreen, Core};
+use crate::renderer::debug_draw::DebugDraw;
+use crate::renderer::deferred::Deferred;
+use crate::renderer::dynamic_mesh::{DynamicMesh, DynamicMeshHandle};
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::frame::Frame;
+use crate::renderer::game_loop::{FixedTimestep, Game};
+use crate::renderer::indirect::{create_cull_pipeline, IndirectBatch, IndirectBatchHandle, IndirectObject};
+use crate::renderer::instance::{Instance, InstanceBuffer, BASE_INSTANCE};
+use crate::renderer::gpu::GpuInfo;
+use crate::renderer::id_buffer::IdBuffer;
 use crate::renderer::frame_buffers::{destroy_frame_buffers, setup_frame_buffers};
 use crate::renderer::logical_layer::LogicalLayer;
 use crate::renderer::physical_layer::PhysicalLayer;
-use crate::renderer::raster_pipeline::RasterPipeline;
-use crate::renderer::render_pass::{destroy_render_pass, setup_render_pass};
+use crate::renderer::pick::{Hit, PickTarget, Ray};
+use crate::renderer::light::{GpuLight, Light, MAX_LIGHTS};
+use crate::renderer::monitor::{self, Monitor, VideoMode};
+use crate::renderer::meshlet::{mesh_stages, MeshShading, Meshlets};
+use crate::renderer::material::{BlendMode, DrawConstants, MaterialConstants, MaterialDesc, ShaderVariant};
+use crate::renderer::raster_pipeline::{RasterPipeline, RasterState};
+use crate::renderer::ray_tracing::{RayTracing, RayTracingSettings, TraceInstance};
+use crate::renderer::overlay::Overlay;
+use crate::renderer::post::{choose_scene_format, is_hdr, PostEffect, PostProcess};
+use crate::renderer::render_mode::RenderMode;
+use crate::renderer::render_pass::{begin_dynamic_scene, destroy_render_pass, end_dynamic_scene, setup_present_render_pass,
+                                   setup_render_pass, PassTarget, SceneAttachments};
 use crate::renderer::render_target::RenderTarget;
-use crate::renderer::vertex::{VertexBuffer, Vertex};
-use crate::renderer::index::{Index, IndexBuffer};
+use crate::renderer::resources::ResourceManager;
+use crate::renderer::vertex::{Vertex, VertexFormat, VertexLayout};
+use crate::renderer::morph::{MorphTarget, MorphTargets, MAX_MORPH_TARGETS};
+use crate::renderer::memory::MemoryStats;
+use crate::renderer::mesh::{Mesh, MeshHandle};
+use crate::renderer::render_queue::{DynamicItem, InstancedItem, MaterialHandle, RenderItem, RenderQueue, TransparentDraw};
+use crate::renderer::shader::{ShaderError, ShaderSet, ShaderSource};
+use crate::renderer::shader_watcher::ShaderWatcher;
+use crate::renderer::sprite::{Sprite, SpriteBatch};
+use crate::renderer::ssao::SsaoSettings;
+use crate::renderer::shadow::{directional_view_proj, ShadowMaps, MAX_SHADOW_CASTERS};
+use crate::renderer::sky::{Sky, SkyPass};
+use crate::renderer::fog::Fog;
+use crate::renderer::decal::Decals;
+use crate::renderer::staging_buf::{UploadContext, STAGING_RING_SIZE};
+use crate::renderer::stats::FrameStats;
+use crate::renderer::streaming::{self, StreamingSettings, StreamingStats, TextureStreamer};
+use crate::renderer::text::{Font, FontAtlas, FontHandle};
+use crate::renderer::texture::{mip_chain, BlockFormat, CompressedImage, Texture, TextureHandle};
+use crate::renderer::timestamps::TimestampPool;
+use crate::renderer::timeline::Timeline;
+use crate::renderer::occlusion::{OcclusionId, OcclusionQueries};
+use crate::renderer::ui::{is_release, Ui};
+use crate::renderer::uniform::{UniformBuffer, UniformBufferObject};
 
 const MAX_FRAMES_IN_FLIGHT: usize = 2;
-const VERTICES: [Vertex; 4] = [ // White Vertices
-    Vertex {
-        pos: [-0.5, -0.5],
-        color: [1.0, 0.0, 0.0]
-    },
-    Vertex {
-        pos: [0.5, -0.5],
-        color: [0.0, 1.0, 0.0]
-    },
-    Vertex {
-        pos: [0.5, 0.5],
-        color: [0.0, 0.0, 1.0]
-    },
-    Vertex {
-        pos: [-0.5, 0.5],
-        color: [1.0, 1.0, 1.0]
-    }
-];
-
-const INDICES: Index = Index {
-    data: [0, 1, 2, 2, 3, 0]
-};
-
 pub struct CubulousRenderer {
     core: Core, // Windowing handles and Vk instance
     physical_layer: PhysicalLayer, // Physical device handle and derived properties
     logical_layer: LogicalLayer, // Logical device and logical queue
-    raster_pipeline: RasterPipeline,
-    render_pass: vk::RenderPass,
+    allocator: Allocator, // Device memory for every buffer and image the renderer creates
+    upload: UploadContext, // Batches staging copies, flushed before each frame is recorded
+    raster_pipelines: Vec<RasterPipeline>, // Indexed by ShaderVariant
+    transparent_pipelines: Vec<RasterPipeline>, // raster_pipelines without depth writes, for BlendMode::Alpha materials
+    debug_pipeline: RasterPipeline, // Line list version of the default shaders
+    render_mode: RenderMode,
+    mode_pipelines: Vec<RasterPipeline>, // Replace raster_pipelines outside of Shaded, one per ShaderVariant for Wireframe
+    scene_target: PassTarget, // Renders the scene into the post-process chain's HDR target
+    present_pass: vk::RenderPass, // The chain's final blit into the swapchain
     render_target: RenderTarget,
-    frame_buffers: Vec<vk::Framebuffer>,
+    frame_buffers: Vec<vk::Framebuffer>, // Per swapchain image, for the present pass
+    post: PostProcess,
+    deferred: Option<Deferred>, // G-buffer and lighting passes, None unless config.pipeline is Deferred
+    mesh_shading: Option<MeshShading>, // Meshlet pipelines, None unless config.mesh_shading is set and the device supports it
+    overlay: Overlay, // Screen space geometry drawn over the post-processed image, I.E. text
+    fonts: Vec<Font>, // Indexed by FontHandle
+    ui: Ui, // egui, drawn through the overlay after everything else
+    compute: Compute, // Storage buffers and compute pipelines, dispatched at the start of the next frame
     command_pool: vk::CommandPool,
     command_buffers: Vec<vk::CommandBuffer>,
     image_available_sems: Vec<vk::Semaphore>,
     render_finished_sems: Vec<vk::Semaphore>,
-    in_flight_fences: Vec<vk::Fence>,
+    frame_timeline: Timeline, // Signalled by every frame's submission
+    frame_values: Vec<u64>, // Timeline value each frame slot's last submission signals
     current_frame: usize,
-    vertex_buffer: VertexBuffer,
-    index_buffer: IndexBuffer
+    dynamic_meshes: Vec<Option<DynamicMesh>>, // Indexed by DynamicMeshHandle, None once removed
+    indirect_batches: Vec<Option<IndirectBatch>>, // Indexed by IndirectBatchHandle, None once removed
+    indirect_cull: Option<ComputePipeline>, // Built along with the first indirect batch
+    debug_draw: DebugDraw, // Cleared after every frame
+    debug_mesh: DynamicMesh, // The debug draw lines, drawn after the render queue
+    render_queue: RenderQueue,
+    uniform_buffer: UniformBuffer,
+    ubo: UniformBufferObject, // Per frame shader data, copied into the current frame's uniform buffer before recording
+    instance_buffer: InstanceBuffer, // Bound to vertex binding 1 for every draw
+    camera: Camera,
+    shader_variants: Vec<ShaderSet>, // Sources of each pipeline, kept for hot reloads
+    lights: Vec<Light>,
+    shadow_maps: ShadowMaps,
+    id_buffer: Option<IdBuffer>, // Pick IDs rendered after the scene, None unless enabled
+    shadow_casters: usize, // Shadow map layers rendered this frame
+    shadow_distance: f32,
+    ambient: Vec3, // Sky color for hemisphere ambient lighting
+    ambient_ground: Vec3,
+    resources: ResourceManager, // Meshes, textures and materials, destroyed once no frame in flight uses them
+    streaming: TextureStreamer, // Mip levels of streamed textures, moved in and out each frame
+    vertex_layouts: Vec<VertexLayout>, // One per vertex buffer binding, kept for pipeline rebuilds
+    shader_watcher: Option<ShaderWatcher>, // None when the shader directories can't be watched
+    swap_chain_dirty: bool, // Set by resize events, the swapchain is recreated before the next frame
+    present_mode: PresentMode, // Requested mode, RenderTarget falls back to Fifo if it's unsupported
+    samples: vk::SampleCountFlags, // Requested MSAA, the render target and scene pipelines are rebuilt to match with the swapchain
+    render_scale: f32, // Of the scene's targets, applied with the swapchain
+    surface_formats: Vec<SurfaceFormat>, // Preferences, kept for swapchain recreation
+    stats: FrameStats,
+    frustum_culling: bool,
+    occlusion_culling: bool,
+    occlusion: OcclusionQueries,
+    occlusion_pipeline: RasterPipeline, // Draws the proxies of occlusion queries, not rebuilt on shader reloads since nothing it outputs is seen
+    sky_pass: SkyPass,
+    sky: Option<Sky>, // None leaves the background the clear color
+    fog: Option<Fog>,
+    decals: Decals,
+    timestamps: Option<TimestampPool>, // None when the graphics queue doesn't support timestamps
+    input: InputState,
+    gamepads: Gamepads,
+    tick_rate: u32,
+    frame_interval: Option<Duration>, // Shortest time between frames from max_fps
+    cursor_mode: CursorMode, // Reapplied whenever the window regains focus, since platforms drop grabs on focus loss
+    text_capture: bool
+}
+
+fn frame_interval(max_fps: u32) -> Duration {
+    Duration::from_secs(1) / max_fps.max(1)
+}
+
+// The sample count MSAA runs at. The deferred pipeline's G-buffer isn't multisampled, so it never is there.
+fn scene_samples(physical_layer: &PhysicalLayer, pipeline: ShadingPipeline, msaa: u32) -> vk::SampleCountFlags {
+    match pipeline {
+        ShadingPipeline::Deferred if msaa > 1 => {
+            log::warn!("MSAA needs the forward pipeline, it stays off");
+            vk::SampleCountFlags::TYPE_1
+        },
+        _ => physical_layer.sample_count(msaa)
+    }
+}
+
+// NaN, I.E. from a hand edited settings file, draws at the window's resolution
+fn clamp_render_scale(scale: f32) -> f32 {
+    match scale.is_nan() {
+        true => 1.0,
+        false => scale.clamp(MIN_RENDER_SCALE, MAX_RENDER_SCALE)
+    }
 }
 
 impl CubulousRenderer {
-    pub fn new(ev_loop: &EventLoop<()>) -> CubulousRenderer {
+    // Opens a window unless config.headless is set
+    pub fn new(ev_loop: &EventLoop<()>, config: RendererConfig) -> Result<CubulousRenderer, RendererError> {
+        Self::create(Some(ev_loop), config)
+    }
+
+    // Renders into offscreen images instead of a window, so no display or event loop is needed. Frames
+    // are drawn with render_frame.
+    pub fn new_headless(width: u32, height: u32, mut config: RendererConfig) -> Result<CubulousRenderer, RendererError> {
+        config.headless = Some((width, height));
+        Self::create(None, config)
+    }
+
+    fn create(ev_loop: Option<&EventLoop<()>>, config: RendererConfig) -> Result<CubulousRenderer, RendererError> {
         fn setup_command_pool(logical_layer: &LogicalLayer, physical_layer: &PhysicalLayer) -> vk::CommandPool {
             let create_info = vk::CommandPoolCreateInfo::default()
                 .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
@@ -92,75 +217,273 @@ impl CubulousRenderer {
             unsafe { logical_layer.logical_device.allocate_command_buffers(&create_info).unwrap() }
         }
 
-        fn setup_sync_objects(logical_layer: &LogicalLayer) -> (Vec<vk::Semaphore>, Vec<vk::Semaphore>, Vec<vk::Fence>) {
+        // The swapchain only takes binary semaphores, frame pacing goes through a timeline instead of fences
+        fn setup_sync_objects(logical_layer: &LogicalLayer) -> Result<(Vec<vk::Semaphore>, Vec<vk::Semaphore>, Timeline), RendererError> {
             let sem_create_info = vk::SemaphoreCreateInfo::default();
-            let fence_create_info = vk::FenceCreateInfo::default()
-                .flags(vk::FenceCreateFlags::SIGNALED);
 
             let mut image_avail_vec: Vec<vk::Semaphore> = Vec::with_capacity(MAX_FRAMES_IN_FLIGHT as usize);
             let mut render_finished_vec: Vec<vk::Semaphore> = Vec::with_capacity(MAX_FRAMES_IN_FLIGHT as usize);
-            let mut fences_vec: Vec<vk::Fence> = Vec::with_capacity(MAX_FRAMES_IN_FLIGHT as usize);
 
             for _ in 0..MAX_FRAMES_IN_FLIGHT {
                 unsafe {
-                    image_avail_vec.push(logical_layer.logical_device.create_semaphore(&sem_create_info, None).unwrap());
-                    render_finished_vec.push(logical_layer.logical_device.create_semaphore(&sem_create_info, None).unwrap());
-                    fences_vec.push(logical_layer.logical_device.create_fence(&fence_create_info, None).unwrap());
+                    image_avail_vec.push(logical_layer.logical_device.create_semaphore(&sem_create_info, None)
+                        .map_err(vk_error("vkCreateSemaphore"))?);
+                    render_finished_vec.push(logical_layer.logical_device.create_semaphore(&sem_create_info, None)
+                        .map_err(vk_error("vkCreateSemaphore"))?);
                 }
             }
 
-            (image_avail_vec, render_finished_vec, fences_vec)
+            Ok((image_avail_vec, render_finished_vec, Timeline::new(logical_layer)?))
         }
 
-        let required_extensions: Vec<CString> = Vec::from([
-            CString::from(vk::KhrSwapchainFn::name()), // Equivalent to the Vulkan VK_KHR_SWAPCHAIN_EXTENSION_NAME
-        ]);
-        let required_layers: Vec<String> = Vec::from([String::from("VK_LAYER_KHRONOS_validation")]);
-
-        let core = Core::new(&ev_loop, &required_layers);
-        let physical_layer = PhysicalLayer::new(&core, &required_extensions).unwrap();
-        let logical_layer = LogicalLayer::new(&core, &physical_layer, &required_extensions);
-        let render_target = RenderTarget::new(&core, &physical_layer, &logical_layer);
-        let render_pass = setup_render_pass(&logical_layer, &render_target);
-        let raster_pipeline = RasterPipeline::new(&logical_layer, render_pass);
-        let frame_buffers = setup_frame_buffers(&logical_layer, render_pass, &render_target);
+        let core = Core::new(ev_loop, &config)?;
+        let required_extensions: Vec<CString> = match core.headless() {
+            true => Vec::new(), // Nothing is presented
+            false => Vec::from([
+                CString::from(vk::KhrSwapchainFn::name()), // Equivalent to the Vulkan VK_KHR_SWAPCHAIN_EXTENSION_NAME
+            ])
+        };
+        let physical_layer = PhysicalLayer::new(&core, &required_extensions, config.device)?;
+        crash::set_device(&physical_layer.gpus, physical_layer.selected, &physical_layer.capabilities);
+        // Only the deferred pipeline has a G-buffer to trace from
+        let requested = DeviceCapabilities {
+            descriptor_indexing: true, // The bindless texture array
+            dynamic_rendering: config.dynamic_rendering,
+            synchronization2: true,
+            buffer_device_address: false, // Only what ray tracing needs
+            ray_tracing: config.ray_tracing.is_some() && config.pipeline == ShadingPipeline::Deferred,
+            mesh_shading: config.mesh_shading,
+            memory_budget: true // Only read for MemoryStats
+        };
+        let logical_layer = LogicalLayer::new(&core, &physical_layer, &required_extensions, requested)?;
+        let allocator = Allocator::new(&core, &physical_layer);
+        let mut upload = UploadContext::new(&logical_layer, &allocator, physical_layer.family_index, STAGING_RING_SIZE)?;
+        let scene_format = choose_scene_format(&core, &physical_layer, config.hdr);
+        let samples = scene_samples(&physical_layer, config.pipeline, config.msaa);
+        let render_target = RenderTarget::new(&core, &physical_layer, &logical_layer, &allocator, config.present_mode,
+                                              &config.surface_formats, scene_format, samples,
+                                              clamp_render_scale(config.render_scale))?;
+        let scene_target = match logical_layer.capabilities.dynamic_rendering {
+            true => PassTarget::Dynamic { color_format: scene_format, depth_format: render_target.depth_format, samples },
+            false => PassTarget::RenderPass(setup_render_pass(&logical_layer, scene_format, &render_target, false)?, samples)
+        };
+        let present_pass = setup_present_render_pass(&logical_layer, &render_target)?;
+        let uniform_buffer = UniformBuffer::new(&logical_layer, &allocator, MAX_FRAMES_IN_FLIGHT)?;
+        let instance_buffer = InstanceBuffer::new(&logical_layer, &allocator, MAX_FRAMES_IN_FLIGHT)?;
+        let push_constant_range = vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT | mesh_stages(&logical_layer))
+            .offset(0)
+            .size(mem::size_of::<DrawConstants>() as u32); // Per draw model matrix and material
+        let mut resources = ResourceManager::new(&logical_layer, &allocator, &mut upload, MAX_FRAMES_IN_FLIGHT)?;
+        // In ShaderVariant order: DEFAULT, LIT, PBR, TERRAIN, VOXEL
+        let shader_variants = vec![ShaderSet::default_glsl(), ShaderSet::lit_glsl(), ShaderSet::pbr_glsl(), ShaderSet::terrain_glsl(),
+                                   ShaderSet::voxel_glsl()];
+        let vertex_layouts = vec![Vertex::layout(), Instance::layout()]; // Per vertex then per instance data
+        let shadow_maps = ShadowMaps::new(&core, &physical_layer, &logical_layer, &allocator, uniform_buffer.descriptor_set_layout,
+                                          &vertex_layouts, config.shadow_resolution)?;
+        let id_buffer = match config.id_buffer {
+            true => Some(IdBuffer::new(&logical_layer, &allocator, physical_layer.family_index, uniform_buffer.descriptor_set_layout,
+                                       &vertex_layouts, &render_target)?),
+            false => None
+        };
+        let mut raster_pipelines: Vec<RasterPipeline> = Vec::with_capacity(shader_variants.len());
+        for shaders in shader_variants.iter() {
+            raster_pipelines.push(RasterPipeline::new(&logical_layer,
+                                                      scene_target,
+                                                      shaders,
+                                                      &vertex_layouts,
+                                                      &[uniform_buffer.descriptor_set_layout, resources.textures.bindless.set_layout, shadow_maps.set_layout,
+                                                        resources.morph_layout],
+                                                      Some(push_constant_range))?);
+        }
+        let mut transparent_pipelines: Vec<RasterPipeline> = Vec::with_capacity(shader_variants.len());
+        for shaders in shader_variants.iter() {
+            transparent_pipelines.push(RasterPipeline::with_state(&logical_layer,
+                                                                  scene_target,
+                                                                  shaders,
+                                                                  &vertex_layouts,
+                                                                  &[uniform_buffer.descriptor_set_layout, resources.textures.bindless.set_layout, shadow_maps.set_layout,
+                                                                    resources.morph_layout],
+                                                                  Some(push_constant_range),
+                                                                  RasterState::TRANSPARENT)?);
+        }
+        let debug_pipeline = RasterPipeline::with_state(&logical_layer,
+                                                        scene_target,
+                                                        &shader_variants[ShaderVariant::DEFAULT.0],
+                                                        &vertex_layouts,
+                                                        &[uniform_buffer.descriptor_set_layout, resources.textures.bindless.set_layout, shadow_maps.set_layout,
+                                                        resources.morph_layout],
+                                                        Some(push_constant_range),
+                                                        RasterState::LINES)?;
+        let occlusion_pipeline = RasterPipeline::with_state(&logical_layer,
+                                                            scene_target,
+                                                            &shader_variants[ShaderVariant::DEFAULT.0],
+                                                            &vertex_layouts,
+                                                            &[uniform_buffer.descriptor_set_layout, resources.textures.bindless.set_layout, shadow_maps.set_layout,
+                                                            resources.morph_layout],
+                                                            Some(push_constant_range),
+                                                            RasterState::OCCLUSION_PROXY)?;
+        let sky_pass = SkyPass::new(&logical_layer, scene_target)?;
+        let ray_tracing = match logical_layer.ray_tracing() {
+            true => match RayTracing::new(&core, &physical_layer, &logical_layer, &allocator, uniform_buffer.descriptor_set_layout,
+                                          MAX_FRAMES_IN_FLIGHT) {
+                Ok(rt) => Some(rt),
+                Err(e) => {
+                    log::warn!("Ray tracing couldn't be set up, it stays off: {}", e);
+                    None
+                }
+            },
+            false => {
+                if config.ray_tracing.is_some() {
+                    log::warn!("Ray tracing needs the deferred pipeline and a device that supports it, it stays off");
+                }
+                None
+            }
+        };
+        let ray_tracing_settings = config.ray_tracing.filter(|_| ray_tracing.is_some());
+        let deferred = match config.pipeline {
+            ShadingPipeline::Forward => None,
+            ShadingPipeline::Deferred => Some(Deferred::new(&logical_layer, &allocator, scene_target, scene_format, &render_target,
+                                                            &vertex_layouts,
+                                                            &[uniform_buffer.descriptor_set_layout, resources.textures.bindless.set_layout,
+                                                              shadow_maps.set_layout, resources.morph_layout],
+                                                            push_constant_range, config.ssao, ray_tracing, ray_tracing_settings)?)
+        };
+        if deferred.is_none() && config.ssao.is_some() {
+            log::warn!("SSAO needs the G-buffer of the deferred pipeline, it stays off");
+        }
+        let mesh_shading = match logical_layer.mesh_shading() {
+            true => match MeshShading::new(&logical_layer, scene_target, &shader_variants,
+                                           &[uniform_buffer.descriptor_set_layout, resources.textures.bindless.set_layout,
+                                             shadow_maps.set_layout],
+                                           push_constant_range, deferred.as_ref().map(|d| d.gbuffer_target())) {
+                Ok(m) => Some(m),
+                Err(e) => {
+                    log::warn!("Mesh shading couldn't be set up, meshes are drawn from their vertex buffers: {}", e);
+                    None
+                }
+            },
+            false => None
+        };
+        let occlusion = OcclusionQueries::new(&logical_layer, &allocator, &mut upload, MAX_FRAMES_IN_FLIGHT)?;
+        let debug_mesh = DynamicMesh::new(&logical_layer, &allocator, MAX_FRAMES_IN_FLIGHT)?;
+        let post = PostProcess::new(&logical_layer, &allocator, scene_format, scene_target.render_pass(), present_pass, &render_target,
+                                    &config.post_effects, config.paper_white)?;
+        let overlay = Overlay::new(&logical_layer, &allocator, present_pass, MAX_FRAMES_IN_FLIGHT)?;
+        let ui = Ui::new(ev_loop, core.window.as_ref());
+        let frame_buffers = setup_frame_buffers(&logical_layer, present_pass, &render_target)?;
 
         let pool_create_info = vk::CommandPoolCreateInfo::default()
             .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
             .queue_family_index(physical_layer.family_index);
-        let command_pool = unsafe { logical_layer.logical_device.create_command_pool(&pool_create_info, None).unwrap() };
+        let command_pool = unsafe {
+            logical_layer.logical_device.create_command_pool(&pool_create_info, None).map_err(vk_error("vkCreateCommandPool"))?
+        };
 
         let buf_create_info = vk::CommandBufferAllocateInfo::default()
             .command_pool(command_pool)
             .level(vk::CommandBufferLevel::PRIMARY)
             .command_buffer_count(MAX_FRAMES_IN_FLIGHT as u32);
-        let command_buffers = unsafe { logical_layer.logical_device.allocate_command_buffers(&buf_create_info).unwrap() };
+        let command_buffers = unsafe {
+            logical_layer.logical_device.allocate_command_buffers(&buf_create_info).map_err(vk_error("vkAllocateCommandBuffers"))?
+        };
 
-        let vertex_buffer = VertexBuffer::new(&core, &physical_layer, &logical_layer, command_pool, &VERTICES);
-        let index_buffer = IndexBuffer::new(&core, &physical_layer, &logical_layer, command_pool, &INDICES);
-
-        let (image_available_sems, render_finished_sems, in_flight_fences) =
-        setup_sync_objects(&logical_layer);
+        let (image_available_sems, render_finished_sems, frame_timeline) =
+        setup_sync_objects(&logical_layer)?;
 
         let current_frame = 0;
 
-        CubulousRenderer {
+        let timestamps = TimestampPool::new(&core, &physical_layer, &logical_layer, MAX_FRAMES_IN_FLIGHT)?;
+
+        let shader_watcher = match ShaderWatcher::new() {
+            Ok(w) => Some(w),
+            Err(e) => {
+                log::warn!("Shader hot reload disabled: {}", e);
+                None
+            }
+        };
+
+        let camera = Camera::new(Vec3::new(0.0, 0.0, 2.0),
+                                 Vec3::ZERO,
+                                 45.0_f32.to_radians(),
+                                 render_target.extent.width as f32 / render_target.extent.height as f32);
+
+        let mut input = InputState::new();
+        let gamepads = Gamepads::new(&mut input);
+
+        Ok(CubulousRenderer {
             core,
             physical_layer,
             logical_layer,
-            raster_pipeline,
-            render_pass,
+            allocator,
+            upload,
+            raster_pipelines,
+            transparent_pipelines,
+            debug_pipeline,
+            render_mode: RenderMode::Shaded,
+            mode_pipelines: Vec::new(),
+            scene_target,
+            present_pass,
             render_target,
             frame_buffers,
+            post,
+            deferred,
+            mesh_shading,
+            overlay,
+            fonts: Vec::new(),
+            ui,
+            compute: Compute::new(MAX_FRAMES_IN_FLIGHT),
             command_pool,
             command_buffers,
             image_available_sems,
             render_finished_sems,
-            in_flight_fences,
+            frame_timeline,
+            frame_values: vec![0; MAX_FRAMES_IN_FLIGHT], // Nothing submitted yet, so nothing to wait on
             current_frame,
-            vertex_buffer,
-            index_buffer
-        }
+            dynamic_meshes: Vec::new(),
+            indirect_batches: Vec::new(),
+            indirect_cull: None,
+            debug_draw: DebugDraw::new(),
+            debug_mesh,
+            render_queue: RenderQueue::new(),
+            uniform_buffer,
+            ubo: UniformBufferObject::default(),
+            instance_buffer,
+            camera,
+            shader_variants,
+            lights: Vec::new(),
+            shadow_maps,
+            id_buffer,
+            shadow_casters: 0,
+            shadow_distance: config.shadow_distance,
+            ambient: Vec3::splat(0.1),
+            ambient_ground: Vec3::splat(0.1),
+            resources,
+            streaming: TextureStreamer::new(config.texture_streaming),
+            vertex_layouts,
+            shader_watcher,
+            swap_chain_dirty: false,
+            present_mode: config.present_mode,
+            samples,
+            render_scale: clamp_render_scale(config.render_scale),
+            surface_formats: config.surface_formats.clone(),
+            stats: FrameStats::new(),
+            frustum_culling: config.frustum_culling,
+            occlusion_culling: config.occlusion_culling,
+            occlusion,
+            occlusion_pipeline,
+            sky_pass,
+            sky: None,
+            fog: None,
+            decals: Decals::new(),
+            timestamps,
+            input,
+            gamepads,
+            tick_rate: config.tick_rate,
+            frame_interval: config.max_fps.map(frame_interval),
+            cursor_mode: CursorMode::Normal,
+            text_capture: false
+        })
     }
 
     fn destroy_command_pool(&self) {
@@ -175,13 +498,12 @@ impl CubulousRenderer {
             for r in self.render_finished_sems.iter() {
                 self.logical_layer.logical_device.destroy_semaphore(*r, None);
             }
-            for f in self.in_flight_fences.iter() {
-                self.logical_layer.logical_device.destroy_fence(*f, None);
-            }
         }
+        self.frame_timeline.destroy(&self.logical_layer);
     }
 
-    fn record_command_buffer(&self, image_index: u32) {
+    fn record_command_buffer(&mut self, image_index: u32) -> Result<(), RendererError> {
+        profile_function!();
         // Defines a transformation from a VK image to the framebuffer
         fn setup_viewport(swap_extent: &vk::Extent2D) -> vk::Viewport {
             vk::Viewport::default()
@@ -201,50 +523,134 @@ impl CubulousRenderer {
                 .extent(*swap_extent)
         }
 
+        // The scene pass when it isn't begun with dynamic rendering
+        fn begin_scene_pass(logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, render_pass: vk::RenderPass,
+                            framebuffer: vk::Framebuffer, render_area: vk::Rect2D, clear_values: &[vk::ClearValue]) {
+            let render_pass_info = vk::RenderPassBeginInfo::default()
+                .render_pass(render_pass)
+                .framebuffer(framebuffer)
+                .render_area(render_area)
+                .clear_values(clear_values);
+            unsafe {
+                logical_layer.logical_device.cmd_begin_render_pass(command_buffer,
+                                                                   &render_pass_info,
+                                                                   vk::SubpassContents::INLINE); // Execute commands in primary buffer
+            }
+        }
+
         let begin_info = vk::CommandBufferBeginInfo::default();
 
         let render_offset = vk::Offset2D::default()
             .x(0)
             .y(0);
         let render_extent = vk::Extent2D::default()
-            .height(self.render_target.extent.height)
-            .width(self.render_target.extent.width);
+            .height(self.render_target.scene_extent.height)
+            .width(self.render_target.scene_extent.width);
         let render_area = vk::Rect2D::default() // Area where shader loads and stores occur
             .offset(render_offset)
             .extent(render_extent);
 
-        let clear_colors = [vk::ClearValue {
+        let clear_color = vk::ClearValue {
             color: vk::ClearColorValue {
                 float32: [0.0, 0.0, 0.0, 1.0], // Values to use for the LOAD_OP_CLEAR attachment operation
             }
-        }];
+        };
+        let clear_colors = [clear_color, vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: 1.0, // Far plane
+                stencil: 0
+            }
+        }, clear_color]; // The third is the MSAA image's, when the scene pass has one
 
-        let render_pass_info = vk::RenderPassBeginInfo::default()
-            .render_pass(self.render_pass)
-            .framebuffer(self.frame_buffers[image_index as usize])
-            .render_area(render_area)
-            .clear_values(&clear_colors);
+        let (scene_image, scene_view) = self.post.scene_image();
+        let scene_attachments = SceneAttachments {
+            color_image: scene_image,
+            color_view: scene_view,
+            depth_image: self.render_target.depth_image,
+            depth_view: self.render_target.depth_view,
+            depth_format: self.render_target.depth_format,
+            msaa: match self.render_target.multisampled() {
+                true => Some((self.render_target.msaa_image, self.render_target.msaa_view)),
+                false => None
+            }
+        };
 
-        let viewports = [setup_viewport(&self.render_target.extent)];
+        let viewports = [setup_viewport(&self.render_target.scene_extent)];
 
-        let scissors = [setup_scissor(&self.render_target.extent)];
+        let scissors = [setup_scissor(&self.render_target.scene_extent)];
 
         let command_buffer = *self.command_buffers.get(self.current_frame).unwrap();
 
-        let vertex_buffers = [self.vertex_buffer.buf];
-
         let offsets: [vk::DeviceSize; 1] = [0];
 
+        let descriptor_sets = [self.uniform_buffer.descriptor_sets[self.current_frame],
+                               self.resources.textures.bindless.set,
+                               self.shadow_maps.descriptor_set];
+
         unsafe {
-            self.logical_layer.logical_device.begin_command_buffer(command_buffer, &begin_info).unwrap();
-            self.logical_layer.logical_device.cmd_begin_render_pass(command_buffer,
-                                                      &render_pass_info,
-                                                      vk::SubpassContents::INLINE); // Execute commands in primary buffer
-            self.logical_layer.logical_device.cmd_bind_pipeline(command_buffer,
-                                                  vk::PipelineBindPoint::GRAPHICS,
-                                                  *self.raster_pipeline.pipelines.get(0).unwrap());
-            self.logical_layer.logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
-            self.logical_layer.logical_device.cmd_bind_index_buffer(command_buffer, self.index_buffer.buf, 0, vk::IndexType::UINT16);
+            self.logical_layer.logical_device.begin_command_buffer(command_buffer, &begin_info)
+                .map_err(vk_error("vkBeginCommandBuffer"))?;
+            if let Some(t) = self.timestamps.as_mut() {
+                t.begin(&self.logical_layer, command_buffer, self.current_frame);
+            }
+            self.occlusion.reset(&self.logical_layer, command_buffer, self.current_frame);
+            let instance_buffers = [self.instance_buffer.buf(self.current_frame)];
+            self.logical_layer.logical_device.cmd_bind_vertex_buffers(command_buffer, 1, &instance_buffers, &offsets);
+            let has_dispatches = self.compute.has_dispatches();
+            if let Some(t) = self.timestamps.as_mut().filter(|_| has_dispatches) {
+                t.begin_scope(&self.logical_layer, command_buffer, self.current_frame, "compute");
+            }
+            self.compute.record(&self.logical_layer, command_buffer, self.current_frame)?; // Resets the slot's descriptor pools too
+            if let Some(t) = self.timestamps.as_mut().filter(|_| has_dispatches) {
+                t.end_scope(&self.logical_layer, command_buffer, self.current_frame);
+            }
+            if let Some(cull) = self.indirect_cull.as_ref() {
+                let frustum = self.camera.frustum();
+                for handle in self.render_queue.indirect_batches() {
+                    if let Some(b) = self.indirect_batches.get(handle.0).and_then(|b| b.as_ref()) {
+                        b.record_cull(&self.logical_layer, command_buffer, self.current_frame, cull, &frustum);
+                    }
+                }
+            }
+            self.shadow_maps.prepare(&self.logical_layer, command_buffer);
+            if let Some(d) = self.deferred.as_mut() {
+                d.prepare(&self.logical_layer, command_buffer);
+            }
+            if let Some(m) = self.mesh_shading.as_ref() {
+                m.prepare(&self.logical_layer, command_buffer);
+            }
+            self.record_acceleration_structures(command_buffer)?;
+            if self.shadow_casters > 0 {
+                if let Some(t) = self.timestamps.as_mut() {
+                    t.begin_scope(&self.logical_layer, command_buffer, self.current_frame, "shadows");
+                }
+                for layer in 0..self.shadow_casters {
+                    self.record_shadow_pass(command_buffer, layer);
+                }
+                if let Some(t) = self.timestamps.as_mut() {
+                    t.end_scope(&self.logical_layer, command_buffer, self.current_frame);
+                }
+            }
+            if let Some(t) = self.timestamps.as_mut() {
+                t.begin_scope(&self.logical_layer, command_buffer, self.current_frame, "main");
+            }
+            // Debug render modes draw forward into the scene pass, their shaders don't write a G-buffer
+            let deferred = self.deferred.as_ref().filter(|_| self.render_mode == RenderMode::Shaded);
+            match (deferred, self.scene_target.render_pass()) {
+                (Some(d), _) => d.begin(&self.logical_layer, command_buffer), // The scene pass follows the lighting
+                (None, Some(render_pass)) => begin_scene_pass(&self.logical_layer, command_buffer, render_pass,
+                                                              self.post.scene_framebuffer(), render_area, &clear_colors),
+                (None, None) => begin_dynamic_scene(&self.logical_layer, command_buffer, &scene_attachments, render_area,
+                                                    &clear_colors, false)
+            }
+            // Every pipeline layout is identical, so the sets stay bound across pipeline changes. Materials
+            // only differ in push constants, set 1 holds every texture.
+            self.logical_layer.logical_device.cmd_bind_descriptor_sets(command_buffer,
+                                                                       vk::PipelineBindPoint::GRAPHICS,
+                                                                       self.raster_pipelines[0].pipeline_layout,
+                                                                       0, // First set
+                                                                       &descriptor_sets,
+                                                                       &[]); // No dynamic offsets
             self.logical_layer.logical_device.cmd_set_viewport(command_buffer, 0, &viewports);
             self.logical_layer.logical_device.cmd_set_scissor(command_buffer, 0, &scissors);
             // self.logical_layer.logical_device.cmd_draw(command_buffer,
@@ -252,113 +658,1933 @@ impl CubulousRenderer {
             //                              1,
             //                              0, // Vertex buffer offset, lowest value of gl_VertexIndex
             //                              0); // lowest value of gl_InstanceIndex
-            self.logical_layer.logical_device.cmd_draw_indexed(command_buffer, self.index_buffer.index_count, 1, 0, 0, 0);
+            // Cells so the state can be forgotten once the deferred lighting has bound its own
+            // The flag is set for the meshlet pipelines
+            let bound_pipeline: Cell<Option<(ShaderVariant, BlendMode, bool)>> = Cell::new(None);
+            let bound_material: Cell<Option<(MaterialHandle, MaterialConstants)>> = Cell::new(None);
+            // Binds the material's pipeline, or its meshlet pipeline if meshlets is set, and returns what gets
+            // pushed alongside the model matrix
+            let bind_material = |handle: MaterialHandle, meshlets: bool| -> MaterialConstants {
+                match (bound_material.get(), bound_pipeline.get()) {
+                    (Some((h, constants)), Some((_, _, m))) if h == handle && m == meshlets => return constants, // Sorted by shader then material
+                    _ => ()
+                }
+                let material = self.resources.materials.get(handle);
+                if bound_pipeline.get() != Some((material.shader, material.blend, meshlets)) {
+                    let pipeline = match meshlets {
+                        true => self.meshlet_pipeline_for(material.shader, material.blend),
+                        false => None
+                    };
+                    self.logical_layer.logical_device.cmd_bind_pipeline(command_buffer,
+                                                                        vk::PipelineBindPoint::GRAPHICS,
+                                                                        pipeline.unwrap_or(self.pipeline_for(material.shader, material.blend)).pipelines[0]);
+                    bound_pipeline.set(Some((material.shader, material.blend, meshlets)));
+                }
+                let constants = material.constants(&self.resources.textures);
+                bound_material.set(Some((handle, constants)));
+                constants
+            };
+            // Any scene pipeline pushes for whichever is bound, their layouts are identical
+            let push_draw = |model: Mat4, material: MaterialConstants| {
+                self.raster_pipelines[0].push_constants(&self.logical_layer, command_buffer, 0, &DrawConstants { model, material });
+            };
+            let bound_mesh: Cell<Option<MeshHandle>> = Cell::new(None);
+            let meshlets_bound = Cell::new(false); // Set 3 holds a mesh's meshlets, which the vertex pipelines can't use
+            let bind_mesh = |handle: MeshHandle, mesh: &Mesh| {
+                if bound_mesh.get() != Some(handle) || meshlets_bound.get() { // The queue is sorted so repeated meshes skip the rebind
+                    let vertex_buffers = [mesh.vertex_buffer.buf];
+                    self.logical_layer.logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
+                    self.logical_layer.logical_device.cmd_bind_index_buffer(command_buffer, mesh.index_buffer.buf, 0, mesh.index_buffer.index_type);
+                    let morph_sets = [mesh.morph.as_ref().unwrap_or(&self.resources.no_morph).set(self.current_frame)];
+                    self.logical_layer.logical_device.cmd_bind_descriptor_sets(command_buffer,
+                                                                               vk::PipelineBindPoint::GRAPHICS,
+                                                                               self.raster_pipelines[0].pipeline_layout,
+                                                                               3, // Morph targets
+                                                                               &morph_sets,
+                                                                               &[]);
+                    bound_mesh.set(Some(handle));
+                    meshlets_bound.set(false);
+                }
+            };
+            // Dynamic and debug geometry has no morph targets. bound_mesh stays None while these are bound.
+            let bind_no_morph = || {
+                let morph_sets = [self.resources.no_morph.set(self.current_frame)];
+                self.logical_layer.logical_device.cmd_bind_descriptor_sets(command_buffer,
+                                                                           vk::PipelineBindPoint::GRAPHICS,
+                                                                           self.raster_pipelines[0].pipeline_layout,
+                                                                           3,
+                                                                           &morph_sets,
+                                                                           &[]);
+                bound_mesh.set(None);
+                meshlets_bound.set(false);
+            };
+            // Whether set 3 has to go back to the morph targets before drawing without a mesh
+            let needs_no_morph = || bound_mesh.get().is_some() || meshlets_bound.get();
+            let draw_item = |item: &RenderItem| {
+                let mesh = match self.resources.mesh(item.mesh) {
+                    Some(m) => m,
+                    None => return // Removed after it was queued
+                };
+                let desc = self.resources.materials.get(item.material);
+                if let Some((meshlets, pipeline)) = mesh.meshlets.as_ref().zip(self.meshlet_pipeline_for(desc.shader, desc.blend)) {
+                    let material = bind_material(item.material, true);
+                    push_draw(item.transform, material);
+                    meshlets.draw(&self.logical_layer, command_buffer, pipeline);
+                    bound_mesh.set(None);
+                    meshlets_bound.set(true);
+                    return;
+                }
+                let material = bind_material(item.material, false);
+                bind_mesh(item.mesh, mesh);
+                push_draw(item.transform, material);
+                self.logical_layer.logical_device.cmd_draw_indexed(command_buffer, mesh.index_buffer.index_count,
+                                                                   1,
+                                                                   0,
+                                                                   0,
+                                                                   0); // The identity instance
+            };
+            let draw_instanced = |item: &InstancedItem| {
+                let mesh = match self.resources.mesh(item.mesh) {
+                    Some(m) => m,
+                    None => return
+                };
+                let material = bind_material(item.material, false);
+                bind_mesh(item.mesh, mesh);
+                push_draw(Mat4::IDENTITY, material);
+                self.logical_layer.logical_device.cmd_draw_indexed(command_buffer, mesh.index_buffer.index_count,
+                                                                   item.instance_count,
+                                                                   0, // First index
+                                                                   0, // Vertex offset
+                                                                   BASE_INSTANCE + item.first_instance);
+            };
+            let draw_dynamic = |item: &DynamicItem| {
+                let (buf, index_offset, index_count) = match self.dynamic_meshes[item.mesh.0].as_ref() {
+                    Some(m) => m.draw_info(self.current_frame),
+                    None => return // Removed after it was queued
+                };
+                if index_count == 0 {
+                    return;
+                }
+                if needs_no_morph() {
+                    bind_no_morph();
+                }
+                let material = bind_material(item.material, false);
+                let vertex_buffers = [buf];
+                self.logical_layer.logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
+                self.logical_layer.logical_device.cmd_bind_index_buffer(command_buffer, buf, index_offset, vk::IndexType::UINT32);
+                push_draw(item.transform, material);
+                self.logical_layer.logical_device.cmd_draw_indexed(command_buffer, index_count, 1, 0, 0, 0);
+            };
+            // Batches are culled on the GPU, so blended ones aren't sorted
+            let draw_batches = |blend: BlendMode| {
+                for handle in self.render_queue.indirect_batches() {
+                    let batch = match self.indirect_batches.get(handle.0).and_then(|b| b.as_ref()) {
+                        Some(b) => b,
+                        None => continue // Removed after it was queued
+                    };
+                    if self.resources.materials.get(batch.material).blend != blend {
+                        continue;
+                    }
+                    if needs_no_morph() {
+                        bind_no_morph(); // Also makes the next mesh rebind over the batch's vertex buffers
+                    }
+                    let material = bind_material(batch.material, false);
+                    push_draw(Mat4::IDENTITY, material);
+                    batch.record_draw(&self.logical_layer, command_buffer, self.current_frame);
+                }
+                // Batches bind their own instances, everything after reads the identity instance
+                self.logical_layer.logical_device.cmd_bind_vertex_buffers(command_buffer, 1, &instance_buffers, &offsets);
+            };
+            for item in self.render_queue.opaque_items() {
+                draw_item(item);
+            }
+            for item in self.render_queue.opaque_instanced_items() {
+                draw_instanced(item);
+            }
+            bind_no_morph();
+            for item in self.render_queue.opaque_dynamic_items() {
+                draw_dynamic(item);
+            }
+            draw_batches(BlendMode::Opaque);
+            if let Some(d) = deferred {
+                let frame_set = self.uniform_buffer.descriptor_sets[self.current_frame];
+                let inv_view_proj = (self.ubo.proj * self.ubo.view).inverse();
+                d.end(&self.logical_layer, command_buffer);
+                d.record_occlusion(&self.logical_layer, command_buffer, frame_set, &inv_view_proj);
+                d.record_ray_tracing(&self.logical_layer, command_buffer, self.current_frame, frame_set, &inv_view_proj);
+                match d.scene_pass() {
+                    Some(render_pass) => begin_scene_pass(&self.logical_layer, command_buffer, render_pass,
+                                                          self.post.scene_framebuffer(), render_area, &clear_colors),
+                    None => begin_dynamic_scene(&self.logical_layer, command_buffer, &scene_attachments, render_area,
+                                                &clear_colors, true)
+                }
+                d.record_lighting(&self.logical_layer, command_buffer, frame_set, self.shadow_maps.descriptor_set, &inv_view_proj);
+                // Back to the scene layout for the forward shaded draws below
+                let scene_sets = [descriptor_sets[0], descriptor_sets[1], descriptor_sets[2], self.resources.no_morph.set(self.current_frame)];
+                self.logical_layer.logical_device.cmd_bind_descriptor_sets(command_buffer,
+                                                                           vk::PipelineBindPoint::GRAPHICS,
+                                                                           self.raster_pipelines[0].pipeline_layout,
+                                                                           0,
+                                                                           &scene_sets,
+                                                                           &[]);
+                bound_mesh.set(None);
+                meshlets_bound.set(false);
+                bound_pipeline.set(None);
+                bound_material.set(None);
+            }
+            // Behind the opaque geometry, so transparent surfaces blend over it
+            if let Some(sky) = self.sky.as_ref().filter(|_| self.render_mode == RenderMode::Shaded) {
+                self.sky_pass.record(&self.logical_layer, command_buffer, sky, self.fog.as_ref(), &(self.ubo.proj * self.ubo.view).inverse());
+                bound_pipeline.set(None);
+                bound_material.set(None);
+            }
+            // Blended over the finished opaque geometry, back to front
+            for draw in self.render_queue.transparent() {
+                match *draw {
+                    TransparentDraw::Item(i) => draw_item(&self.render_queue.items()[i]),
+                    TransparentDraw::Instanced(i) => draw_instanced(&self.render_queue.instanced_items()[i]),
+                    TransparentDraw::Dynamic(i) => draw_dynamic(&self.render_queue.dynamic_items()[i])
+                }
+            }
+            draw_batches(BlendMode::Alpha);
+            if needs_no_morph() {
+                bind_no_morph(); // For the occlusion proxies and debug lines
+            }
+            // Tested against the depth of everything drawn so far, results come back with this frame slot
+            let proxy_material = self.resources.materials.get(MaterialHandle::DEFAULT).constants(&self.resources.textures);
+            self.occlusion.record(&self.logical_layer, command_buffer, self.current_frame, self.stats.frame_count,
+                                  &self.occlusion_pipeline, proxy_material);
+            let (buf, index_offset, index_count) = self.debug_mesh.draw_info(self.current_frame);
+            if index_count > 0 {
+                let constants = DrawConstants {
+                    model: Mat4::IDENTITY, // Lines are in world space
+                    material: self.resources.materials.get(MaterialHandle::DEFAULT).constants(&self.resources.textures)
+                };
+                let vertex_buffers = [buf];
+                self.logical_layer.logical_device.cmd_bind_pipeline(command_buffer,
+                                                                    vk::PipelineBindPoint::GRAPHICS,
+                                                                    self.debug_pipeline.pipelines[0]);
+                self.logical_layer.logical_device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
+                self.logical_layer.logical_device.cmd_bind_index_buffer(command_buffer, buf, index_offset, vk::IndexType::UINT32);
+                self.debug_pipeline.push_constants(&self.logical_layer, command_buffer, 0, &constants);
+                self.logical_layer.logical_device.cmd_draw_indexed(command_buffer, index_count, 1, 0, 0, 0);
+            }
+            match self.scene_target.render_pass() {
+                Some(_) => self.logical_layer.logical_device.cmd_end_render_pass(command_buffer),
+                None => end_dynamic_scene(&self.logical_layer, command_buffer, &scene_attachments)
+            }
+            self.record_id_pass(command_buffer);
+            if let Some(t) = self.timestamps.as_mut() {
+                t.end_scope(&self.logical_layer, command_buffer, self.current_frame);
+                t.begin_scope(&self.logical_layer, command_buffer, self.current_frame, "post");
+            }
+            self.post.record(&self.logical_layer, command_buffer, self.present_pass, self.frame_buffers[image_index as usize],
+                             self.render_target.extent);
+            self.overlay.record(&self.logical_layer, command_buffer, self.current_frame, self.render_target.extent);
             self.logical_layer.logical_device.cmd_end_render_pass(command_buffer);
-            self.logical_layer.logical_device.end_command_buffer(command_buffer).unwrap();
+            if let Some(t) = self.timestamps.as_mut() {
+                t.end_scope(&self.logical_layer, command_buffer, self.current_frame);
+                t.end(&self.logical_layer, command_buffer, self.current_frame);
+            }
+            self.logical_layer.logical_device.end_command_buffer(command_buffer)
+                .map_err(vk_error("vkEndCommandBuffer"))?;
+        }
+
+        Ok(())
+    }
+
+    // Builds what ray tracing traces against from the camera culled queue's items and instanced items.
+    // Dynamic meshes and indirect batches have no fixed geometry on the CPU's side, so they aren't traced.
+    fn record_acceleration_structures(&mut self, command_buffer: CommandBuffer) -> Result<(), RendererError> {
+        let deferred = match self.deferred.as_mut() {
+            Some(d) if d.ray_tracing_settings.is_some() && self.render_mode == RenderMode::Shaded => d,
+            _ => return Ok(())
+        };
+        let mut instances: Vec<TraceInstance> = self.render_queue.items().iter()
+            .map(|item| TraceInstance { mesh: item.mesh, transform: item.transform, material: item.material })
+            .collect();
+        for item in self.render_queue.instanced_items() {
+            let range = item.first_instance as usize..(item.first_instance + item.instance_count) as usize;
+            instances.extend(self.render_queue.instances()[range].iter()
+                .map(|i| TraceInstance { mesh: item.mesh, transform: Mat4::from_cols_array_2d(&i.transform), material: item.material }));
+        }
+
+        let retired = deferred.record_acceleration_structures(&self.logical_layer, &self.allocator, command_buffer,
+                                                              self.current_frame, &instances, &self.resources)?;
+        for deletion in retired {
+            self.resources.deletions.push(self.current_frame, deletion);
+        }
+        Ok(())
+    }
+
+    // Renders the queue's depth into one shadow map layer. Reuses the camera culled queue, so casters
+    // outside the camera's view don't cast shadows into it. Dynamic meshes never cast shadows.
+    fn record_shadow_pass(&self, command_buffer: vk::CommandBuffer, layer: usize) {
+        let device = &self.logical_layer.logical_device;
+        let offsets: [vk::DeviceSize; 1] = [0];
+        let mut bound_mesh: Option<MeshHandle> = None;
+        let mut bind_mesh = |handle: MeshHandle, mesh: &Mesh| {
+            if bound_mesh != Some(handle) {
+                let vertex_buffers = [mesh.vertex_buffer.buf];
+                unsafe {
+                    device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
+                    device.cmd_bind_index_buffer(command_buffer, mesh.index_buffer.buf, 0, mesh.index_buffer.index_type);
+                }
+                bound_mesh = Some(handle);
+            }
+        };
+
+        self.shadow_maps.begin(&self.logical_layer, command_buffer, layer, self.uniform_buffer.descriptor_sets[self.current_frame]);
+        for item in self.render_queue.items() {
+            if let Some(mesh) = self.resources.mesh(item.mesh) {
+                bind_mesh(item.mesh, mesh);
+                self.shadow_maps.push_model(&self.logical_layer, command_buffer, &item.transform);
+                unsafe { device.cmd_draw_indexed(command_buffer, mesh.index_buffer.index_count, 1, 0, 0, 0) };
+            }
+        }
+        for item in self.render_queue.instanced_items() {
+            if let Some(mesh) = self.resources.mesh(item.mesh) {
+                bind_mesh(item.mesh, mesh);
+                self.shadow_maps.push_model(&self.logical_layer, command_buffer, &Mat4::IDENTITY);
+                unsafe {
+                    device.cmd_draw_indexed(command_buffer, mesh.index_buffer.index_count, item.instance_count, 0, 0,
+                                            BASE_INSTANCE + item.first_instance);
+                }
+            }
+        }
+        self.shadow_maps.end(&self.logical_layer, command_buffer);
+    }
+
+    // Renders the culled queue's pick IDs, when the ID buffer is on. Draws without an ID still write 0 so
+    // they hide what's behind them. Indirect batches aren't drawn, and neither are morph targets.
+    fn record_id_pass(&mut self, command_buffer: vk::CommandBuffer) {
+        let ids = match self.id_buffer.as_mut() {
+            Some(i) => i,
+            None => return
+        };
+        let device = &self.logical_layer.logical_device;
+        let offsets: [vk::DeviceSize; 1] = [0];
+        let mut bound_mesh: Option<MeshHandle> = None;
+        let mut bind_mesh = |handle: MeshHandle, mesh: &Mesh| {
+            if bound_mesh != Some(handle) {
+                let vertex_buffers = [mesh.vertex_buffer.buf];
+                unsafe {
+                    device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
+                    device.cmd_bind_index_buffer(command_buffer, mesh.index_buffer.buf, 0, mesh.index_buffer.index_type);
+                }
+                bound_mesh = Some(handle);
+            }
+        };
+
+        ids.begin(&self.logical_layer, command_buffer, self.uniform_buffer.descriptor_sets[self.current_frame]);
+        for item in self.render_queue.items() {
+            if let Some(mesh) = self.resources.mesh(item.mesh) {
+                bind_mesh(item.mesh, mesh);
+                ids.push(&self.logical_layer, command_buffer, &item.transform, item.pick_id);
+                unsafe { device.cmd_draw_indexed(command_buffer, mesh.index_buffer.index_count, 1, 0, 0, 0) };
+            }
+        }
+        for item in self.render_queue.instanced_items() {
+            if let Some(mesh) = self.resources.mesh(item.mesh) {
+                bind_mesh(item.mesh, mesh);
+                ids.push(&self.logical_layer, command_buffer, &Mat4::IDENTITY, 0);
+                unsafe {
+                    device.cmd_draw_indexed(command_buffer, mesh.index_buffer.index_count, item.instance_count, 0, 0,
+                                            BASE_INSTANCE + item.first_instance);
+                }
+            }
+        }
+        for item in self.render_queue.dynamic_items() {
+            let (buf, index_offset, index_count) = match self.dynamic_meshes[item.mesh.0].as_ref() {
+                Some(m) => m.draw_info(self.current_frame),
+                None => continue
+            };
+            if index_count == 0 {
+                continue;
+            }
+            let vertex_buffers = [buf];
+            ids.push(&self.logical_layer, command_buffer, &item.transform, 0);
+            unsafe {
+                device.cmd_bind_vertex_buffers(command_buffer, 0, &vertex_buffers, &offsets);
+                device.cmd_bind_index_buffer(command_buffer, buf, index_offset, vk::IndexType::UINT32);
+                device.cmd_draw_indexed(command_buffer, index_count, 1, 0, 0, 0);
+            }
         }
+        ids.end(&self.logical_layer, command_buffer);
     }
 
-    fn draw_frame(&mut self) {
-        let fences = [*self.in_flight_fences.get(self.current_frame).unwrap()];
+    fn draw_frame(&mut self) -> Result<(), RendererError> {
+        profile_function!();
+        if self.is_minimized() {
+            return Ok(()); // A 0x0 swapchain can't be created, rendering resumes once the window is restored
+        }
+        if self.swap_chain_dirty {
+            self.recreate_swap_chain()?;
+        }
+        self.stats.begin_frame();
+
+        if self.shader_watcher.as_ref().map_or(false, |w| w.poll()) {
+            self.reload_shaders();
+        }
+
         let wait_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
         let wait_sems = [*self.image_available_sems.get(self.current_frame).unwrap()];
         let command_buffers = [*self.command_buffers.get(self.current_frame).unwrap()];
-        let sig_sems = [*self.render_finished_sems.get(self.current_frame).unwrap()];
-        let submit_info = vk::SubmitInfo::default()
-            .wait_semaphores(&wait_sems)
-            .wait_dst_stage_mask(&wait_stages)
-            .command_buffers(&command_buffers)
-            .signal_semaphores(&sig_sems);
-        let submit_array = [submit_info];
+        let present_sems = [*self.render_finished_sems.get(self.current_frame).unwrap()];
+        let headless = self.render_target.headless();
         let swap_chains = [self.render_target.swap_chain];
 
+        {
+            profile_scope!("wait for frame slot"); // Time here is the GPU or vsync holding the CPU back
+            self.frame_timeline.wait(&self.logical_layer, self.frame_values[self.current_frame])?;
+        }
         unsafe {
-            self.logical_layer.logical_device.wait_for_fences(&fences, true, u64::MAX).unwrap();
-
-            let (next_image_idx, _) = match self.render_target.swap_loader.acquire_next_image(self.render_target.swap_chain,
-                                    u64::MAX,
-                                    *self.image_available_sems.get(self.current_frame).unwrap(),
-                                    vk::Fence::null()) {
-                Ok(img_idx) => img_idx,
-                Err(result) => match result {
-                    vk::Result::ERROR_OUT_OF_DATE_KHR => { self.recreate_swap_chain(); return },
-                    _ => panic!("Unknown error at acquire_next_image")
+            // The last use of this frame slot has finished, so its timestamps are ready
+            if let Some((gpu_time, passes)) = self.timestamps.as_ref().and_then(|t| t.read(&self.logical_layer, self.current_frame)) {
+                self.stats.gpu_time = Some(gpu_time);
+                self.stats.passes = passes;
+            }
+            self.occlusion.read(&self.logical_layer, self.current_frame); // Likewise the occlusion queries
+            self.resources.collect(&self.logical_layer, &self.allocator, self.current_frame);
+
+            // Offscreen images belong to a frame slot each, and its submission was just waited on
+            let (next_image_idx, _) = match headless {
+                true => (self.current_frame as u32, false),
+                false => match self.render_target.swap_loader.acquire_next_image(self.render_target.swap_chain,
+                                        u64::MAX,
+                                        *self.image_available_sems.get(self.current_frame).unwrap(),
+                                        vk::Fence::null()) {
+                    Ok(img_idx) => img_idx,
+                    Err(result) => match result {
+                        vk::Result::ERROR_OUT_OF_DATE_KHR => return self.recreate_swap_chain(),
+                        r => return Err(vk_error("vkAcquireNextImageKHR")(r))
+                    }
                 }
             };
 
-            self.logical_layer.logical_device.reset_fences(&fences).unwrap();
-
             let image_indices = [next_image_idx];
             let present_info = vk::PresentInfoKHR::default()
-                .wait_semaphores(&sig_sems)
+                .wait_semaphores(&present_sems)
                 .swapchains(&swap_chains)
                 .image_indices(&image_indices);
             self.logical_layer.logical_device.reset_command_buffer(*self.command_buffers.get(self.current_frame).unwrap(),
                                                      vk::CommandBufferResetFlags::empty())
-                .unwrap();
-            self.record_command_buffer(next_image_idx);
-            self.logical_layer.logical_device.queue_submit(self.logical_layer.logical_queue, &submit_array, *self.in_flight_fences.get(self.current_frame).unwrap()).unwrap();
+                .map_err(vk_error("vkResetCommandBuffer"))?;
+            self.ubo.view = self.camera.view;
+            self.ubo.proj = self.camera.proj;
+            self.ubo.camera_pos = self.camera.position.extend(1.0);
+            self.ubo.ambient = self.ambient.extend(0.0);
+            self.ubo.ambient_ground = self.ambient_ground.extend(0.0);
+            let (fog_color, fog_params) = Fog::uniforms(self.fog.as_ref(), self.camera.far);
+            self.ubo.fog_color = fog_color;
+            self.ubo.fog_params = fog_params;
+            self.ubo.decal_count[0] = self.decals.write_visible(&self.camera.frustum(), self.camera.position,
+                                                                &self.resources.textures, &mut self.ubo.decals) as u32;
+            self.ubo.light_count[0] = self.lights.len() as u32;
+            self.update_shadows();
+            self.uniform_buffer.update(self.current_frame, &self.ubo);
+            self.cull();
+            self.stream_textures()?;
+            let materials = &self.resources.materials;
+            self.render_queue.sort(self.camera.position, |m| {
+                let material = materials.get(m);
+                (material.shader, material.blend)
+            });
+            self.instance_buffer.update(&self.logical_layer, &self.allocator, self.current_frame, self.render_queue.instances())?;
+            for m in self.dynamic_meshes.iter_mut().flatten() {
+                m.write(&self.logical_layer, &self.allocator, self.current_frame)?;
+            }
+            self.resources.write_morph_weights(self.current_frame);
+            let queued = self.render_queue.indirect_batches();
+            for (i, batch) in self.indirect_batches.iter_mut().enumerate() {
+                if let Some(b) = batch {
+                    b.write(self.current_frame, queued.contains(&IndirectBatchHandle(i)));
+                }
+            }
+            let debug_indices: Vec<u32> = (0..self.debug_draw.vertices().len() as u32).collect();
+            self.debug_mesh.set(self.debug_draw.vertices(), &debug_indices);
+            self.debug_mesh.write(&self.logical_layer, &self.allocator, self.current_frame)?;
+            self.debug_draw.clear();
+            self.prepare_ui()?;
+            self.overlay.prepare(&self.logical_layer, &self.allocator, &self.resources.textures, self.current_frame)?;
+            self.upload.flush(&self.logical_layer)?; // Meshes uploaded since the last frame
+            self.record_command_buffer(next_image_idx)?;
 
-            match self.render_target.swap_loader.queue_present(self.logical_layer.logical_queue, &present_info)
-            {
-                Err(r) => match r {
-                    vk::Result::ERROR_OUT_OF_DATE_KHR | vk::Result::SUBOPTIMAL_KHR => { self.recreate_swap_chain() },
-                    _ => panic!("Unknown error")
+            // Binary semaphore values are ignored, but every semaphore needs an entry
+            let frame_value = self.frame_timeline.next();
+            let sig_sems = [self.frame_timeline.semaphore, present_sems[0]];
+            let sig_values = [frame_value, 0];
+            let wait_values = [0];
+            let (sig_count, wait_count) = match headless {
+                true => (1, 0), // Nothing to acquire or present, so only the timeline
+                false => (2, 1)
+            };
+            let mut timeline_info = vk::TimelineSemaphoreSubmitInfo::default()
+                .wait_semaphore_values(&wait_values[..wait_count])
+                .signal_semaphore_values(&sig_values[..sig_count]);
+            let submit_info = vk::SubmitInfo::default()
+                .wait_semaphores(&wait_sems[..wait_count])
+                .wait_dst_stage_mask(&wait_stages[..wait_count])
+                .command_buffers(&command_buffers)
+                .signal_semaphores(&sig_sems[..sig_count])
+                .push_next(&mut timeline_info);
+            self.logical_layer.logical_device.queue_submit(self.logical_layer.logical_queue, &[submit_info], vk::Fence::null())
+                .map_err(vk_error("vkQueueSubmit"))?;
+            self.frame_values[self.current_frame] = frame_value;
+
+            if !headless {
+                match self.render_target.swap_loader.queue_present(self.logical_layer.present_queue, &present_info)
+                {
+                    Err(r) => match r {
+                        vk::Result::ERROR_OUT_OF_DATE_KHR | vk::Result::SUBOPTIMAL_KHR => { self.recreate_swap_chain()? },
+                        r => return Err(vk_error("vkQueuePresentKHR")(r))
+                    }
+                    Ok(suboptimal) => self.swap_chain_dirty |= suboptimal // Still presented, recreate before the next frame
                 }
-                Ok(_) => { }
             }
         }
 
         self.current_frame = (self.current_frame + 1) % MAX_FRAMES_IN_FLIGHT;
+
+        Ok(())
     }
 
-    fn cleanup_swap_chain(&self) {
-        self.logical_layer.wait_idle();
+    // Uploads the textures egui changed and queues the last finished UI frame on the overlay, after any
+    // text so the UI stays on top
+    fn prepare_ui(&mut self) -> Result<(), RendererError> {
+        // Partial updates upload the whole image again as a new texture and release the old one. egui
+        // only does this when its font atlas grows, so it's rare.
+        let mut uploaded = Vec::new();
+        for (id, width, height, pixels) in self.ui.textures_to_upload() {
+            self.check_texture_slot()?;
+            let texture = Texture::new(&self.logical_layer, &self.allocator, &mut self.upload, width, height, pixels, true)?;
+            uploaded.push((id, self.resources.add_texture(&self.logical_layer, texture)));
+        }
+        for (id, texture) in uploaded {
+            self.ui.set_texture(id, texture);
+        }
+        let last_frame = self.last_frame();
+        for texture in self.ui.take_freed_textures() {
+            self.resources.release_texture(texture, last_frame);
+        }
 
-        destroy_frame_buffers(&self.logical_layer, &self.frame_buffers);
-        self.render_target.destroy(&self.logical_layer);
+        let overlay = &mut self.overlay;
+        self.ui.tessellate(self.render_target.extent, |texture, clip, vertices, indices| {
+            overlay.push_clipped(texture, Some(clip), vertices, indices);
+        });
+
+        Ok(())
     }
 
-    fn recreate_swap_chain(&mut self) {
-        self.cleanup_swap_chain();
+    // Fills the lights in the UBO, giving shadow casting directional lights a shadow map layer each
+    fn update_shadows(&mut self) {
+        // Covers the sphere in front of the camera rather than around it, since that's what's visible
+        let forward = (self.camera.target - self.camera.position).normalize_or_zero();
+        let center = self.camera.position + forward * self.shadow_distance * 0.5;
+        let texel = 1.0 / self.shadow_maps.resolution as f32;
+        self.ubo.shadow_params = Vec4::new(texel, 2.0 * self.shadow_distance * texel * 1.5, 0.0, 0.0); // 1.5 world texels of normal offset
 
-        self.render_target = RenderTarget::new(&self.core, &self.physical_layer, &self.logical_layer);
-        self.frame_buffers = setup_frame_buffers(&self.logical_layer, self.render_pass, &self.render_target);
+        self.shadow_casters = 0;
+        for (gpu_light, light) in self.ubo.lights.iter_mut().zip(self.lights.iter()) {
+            *gpu_light = GpuLight::from(light);
+            if let Light::Directional { direction, cast_shadows: true, .. } = *light {
+                if self.shadow_casters < MAX_SHADOW_CASTERS {
+                    gpu_light.set_shadow_layer(self.shadow_casters);
+                    self.ubo.shadow_view_proj[self.shadow_casters] = directional_view_proj(direction, center, self.shadow_distance,
+                                                                                           self.shadow_maps.resolution);
+                    self.shadow_casters += 1;
+                }
+            }
+        }
     }
 
-    fn window_id(&self) -> WindowId {
-        self.core.window.id()
-    }
+    // Removes draws outside the camera's view from the render queue and records the counts in the stats
+    fn cull(&mut self) {
+        let (drawn, culled) = match self.frustum_culling {
+            true => {
+                let frustum = self.camera.frustum();
+                let resources = &self.resources;
+                self.render_queue.cull(&frustum, |h| resources.mesh(h).map(|m| m.bounds()))
+            },
+            false => (self.render_queue.items().len() + self.render_queue.instances().len() + self.render_queue.dynamic_items().len(), 0)
+        };
 
-    pub fn run_blocking(mut self, event_loop: EventLoop<()>) {
-        event_loop.run(move |event, _, control_flow| {
-            *control_flow = ControlFlow::Wait;
+        let (tests, occluded) = match self.occlusion_culling {
+            true => self.cull_occluded(),
+            false => (0, 0)
+        };
 
-            match event {
-                Event::WindowEvent {
-                    // If event has Event::WindowEvent type and event: WindowEvent::CloseRequested member and if window_id == window.id()
-                    event: WindowEvent::CloseRequested,
-                    window_id,
-                } if window_id == self.window_id() => *control_flow = ControlFlow::Exit,
-                Event::MainEventsCleared => self.core.window.request_redraw(), // Emits a RedrawRequested event after input events end
-                                                                        // Needed when a redraw is needed after the user resizes for example
-                Event::RedrawRequested(window_id) if window_id == self.window_id() => self.draw_frame(),
-                Event::LoopDestroyed => unsafe { self.logical_layer.logical_device.device_wait_idle().unwrap() },
-                _ => (), // Similar to the "default" case of a switch statement: return void which is essentially () in Rust
+        self.stats.drawn_objects = drawn - occluded;
+        self.stats.culled_objects = culled;
+        self.stats.occlusion_tests = tests;
+        self.stats.occluded_objects = occluded;
+        let batches = self.render_queue.indirect_batches().iter().filter_map(|h| self.indirect_batches.get(h.0).and_then(|b| b.as_ref()));
+        self.stats.indirect_objects = batches.clone().map(|b| b.object_count()).sum();
+        self.stats.indirect_visible = batches.map(|b| b.visible() as usize).sum();
+        self.stats.draw_calls = self.render_queue.len();
+    }
+
+    // Has each draw left after culling ask for the mip levels its textures need at its size on screen, then
+    // streams levels in and out to match
+    fn stream_textures(&mut self) -> Result<(), RendererError> {
+        if self.streaming.is_empty() {
+            return Ok(());
+        }
+        profile_function!();
+        let (camera, height) = (&self.camera, self.render_target.scene_extent.height);
+        let (resources, streaming) = (&self.resources, &mut self.streaming);
+        let mut request = |material: MaterialHandle, pixels: f32| {
+            for t in resources.materials.get(material).textures.iter() {
+                streaming.request(*t, pixels);
+            }
+        };
+        for item in self.render_queue.items() {
+            if let Some(mesh) = resources.mesh(item.mesh) {
+                request(item.material, streaming::screen_size(&mesh.bounds().transformed(&item.transform), camera, height));
+            }
+        }
+        let instances = self.render_queue.instances();
+        for item in self.render_queue.instanced_items() {
+            if let Some(mesh) = resources.mesh(item.mesh) {
+                let bounds = mesh.bounds();
+                let range = item.first_instance as usize..(item.first_instance + item.instance_count) as usize;
+                let pixels = instances[range].iter()
+                    .map(|i| streaming::screen_size(&bounds.transformed(&Mat4::from_cols_array_2d(&i.transform)), camera, height))
+                    .fold(0.0, f32::max);
+                request(item.material, pixels);
             }
+        }
+        for item in self.render_queue.dynamic_items() {
+            request(item.material, f32::INFINITY); // No bounds to measure, so every level
+        }
+
+        let memory = self.memory_stats();
+        let last_frame = self.last_frame();
+        self.streaming.update(&self.logical_layer, &self.allocator, &mut self.upload, &mut self.resources, &memory, last_frame)
+    }
+
+    // Queues an occlusion test for every draw with an OcclusionId that survived frustum culling, then drops
+    // the ones the latest results found hidden. Returns the number of tests and of dropped draws.
+    fn cull_occluded(&mut self) -> (usize, usize) {
+        let (frame, eye) = (self.stats.frame_count, self.camera.position);
+        let (resources, occlusion) = (&self.resources, &mut self.occlusion);
+        let occluded = self.render_queue.cull_occluded(|id, item| {
+            let bounds = match resources.mesh(item.mesh) {
+                Some(m) => m.bounds().transformed(&item.transform),
+                None => return true // Removed after it was queued, skipped when recording
+            };
+            !occlusion.test(id, &bounds, eye) || occlusion.is_visible(id, frame, MAX_FRAMES_IN_FLIGHT as u64)
         });
+
+        (self.occlusion.pending_count(), occluded)
     }
-}
 
-impl Drop for CubulousRenderer {
-    fn drop(&mut self) {
-        self.cleanup_swap_chain();
-        self.index_buffer.destroy(&self.logical_layer);
-        self.vertex_buffer.destroy(&self.logical_layer);
-        self.destroy_sync_objects();
-        self.destroy_command_pool();
-        self.raster_pipeline.destroy(&self.logical_layer);
-        destroy_render_pass(&self.logical_layer, self.render_pass);
+    // Swaps in a pipeline built from the shaders currently on disk, keeping the old one if they fail to compile
+    fn reload_shaders(&mut self) {
+        match self.rebuild_pipelines() {
+            Ok(()) => log::info!("Shaders reloaded"),
+            Err(e) => log::warn!("Shader reload failed: {}", e)
+        }
+    }
+
+    // Builds the shader variants', debug and render mode pipelines again against scene_target, retiring the
+    // old ones. All or nothing, so a broken variant doesn't leave the others half rebuilt.
+    fn rebuild_pipelines(&mut self) -> Result<(), ShaderError> {
+        let mut pipelines: Vec<RasterPipeline> = Vec::with_capacity(self.shader_variants.len());
+        let mut transparent_pipelines: Vec<RasterPipeline> = Vec::with_capacity(self.shader_variants.len());
+        for shaders in self.shader_variants.iter() {
+            let built = self.build_pipeline(shaders)
+                .and_then(|p| match self.build_pipeline_with(shaders, RasterState::TRANSPARENT) {
+                    Ok(t) => Ok((p, t)),
+                    Err(e) => {
+                        let mut p = p;
+                        p.destroy(&self.logical_layer);
+                        Err(e)
+                    }
+                });
+            match built {
+                Ok((p, t)) => {
+                    pipelines.push(p);
+                    transparent_pipelines.push(t);
+                },
+                Err(e) => {
+                    for mut p in pipelines.into_iter().chain(transparent_pipelines) {
+                        p.destroy(&self.logical_layer);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        let built = self.build_pipeline_with(&self.shader_variants[ShaderVariant::DEFAULT.0], RasterState::LINES)
+            .and_then(|d| match self.build_mode_pipelines(self.render_mode) {
+                Ok(m) => Ok((d, m)),
+                Err(e) => {
+                    let mut d = d;
+                    d.destroy(&self.logical_layer);
+                    Err(e)
+                }
+            });
+        let (debug_pipeline, mode_pipelines) = match built {
+            Ok(b) => b,
+            Err(e) => {
+                for mut p in pipelines.into_iter().chain(transparent_pipelines) {
+                    p.destroy(&self.logical_layer);
+                }
+                return Err(e);
+            }
+        };
+
+        // The old pipelines may still be referenced by in flight command buffers
+        let last_frame = self.last_frame();
+        for old_pipeline in mem::replace(&mut self.raster_pipelines, pipelines) {
+            self.resources.retire_pipeline(old_pipeline, last_frame);
+        }
+        for old_pipeline in mem::replace(&mut self.transparent_pipelines, transparent_pipelines) {
+            self.resources.retire_pipeline(old_pipeline, last_frame);
+        }
+        for old_pipeline in mem::replace(&mut self.mode_pipelines, mode_pipelines) {
+            self.resources.retire_pipeline(old_pipeline, last_frame);
+        }
+        self.resources.retire_pipeline(mem::replace(&mut self.debug_pipeline, debug_pipeline), last_frame);
+
+        Ok(())
+    }
+
+    // Recreates the scene pass and every pipeline drawing into it at the render target's sample count.
+    // The GPU must be idle.
+    fn rebuild_scene_target(&mut self) -> Result<(), RendererError> {
+        let samples = self.render_target.samples;
+        let scene_target = match self.scene_target {
+            PassTarget::RenderPass(..) => PassTarget::RenderPass(setup_render_pass(&self.logical_layer, self.post.format,
+                                                                                   &self.render_target, false)?, samples),
+            PassTarget::Dynamic { color_format, depth_format, .. } => PassTarget::Dynamic { color_format, depth_format, samples }
+        };
+        if let Some(p) = mem::replace(&mut self.scene_target, scene_target).render_pass() {
+            destroy_render_pass(&self.logical_layer, p);
+        }
+
+        self.rebuild_pipelines()?;
+        let occlusion_pipeline = self.build_pipeline_with(&self.shader_variants[ShaderVariant::DEFAULT.0], RasterState::OCCLUSION_PROXY)?;
+        self.resources.retire_pipeline(mem::replace(&mut self.occlusion_pipeline, occlusion_pipeline), self.last_frame());
+        let sky_pass = SkyPass::new(&self.logical_layer, self.scene_target)?;
+        mem::replace(&mut self.sky_pass, sky_pass).destroy(&self.logical_layer);
+        if let Some(m) = self.mesh_shading.as_mut() {
+            m.rebuild(&self.logical_layer, self.scene_target, &self.shader_variants,
+                      &[self.uniform_buffer.descriptor_set_layout, self.resources.textures.bindless.set_layout, self.shadow_maps.set_layout],
+                      self.raster_pipelines[0].push_constant_range().unwrap(), // Every scene pipeline has them
+                      self.deferred.as_ref().map(|d| d.gbuffer_target()))?;
+        }
+
+        Ok(())
+    }
+
+    // Every pipeline shares the same layout, so descriptor sets and push constants work with any of them
+    fn build_pipeline(&self, shaders: &ShaderSet) -> Result<RasterPipeline, ShaderError> {
+        self.build_pipeline_with(shaders, RasterState::default())
+    }
+
+    fn build_pipeline_with(&self, shaders: &ShaderSet, state: RasterState) -> Result<RasterPipeline, ShaderError> {
+        RasterPipeline::with_state(&self.logical_layer,
+                                   self.scene_target,
+                                   shaders,
+                                   &self.vertex_layouts,
+                                   &[self.uniform_buffer.descriptor_set_layout, self.resources.textures.bindless.set_layout, self.shadow_maps.set_layout,
+                                     self.resources.morph_layout],
+                                   self.raster_pipelines[0].push_constant_range(),
+                                   state)
+    }
+
+    // The pipelines standing in for raster_pipelines while mode is active
+    fn build_mode_pipelines(&self, mode: RenderMode) -> Result<Vec<RasterPipeline>, ShaderError> {
+        let shader_sets = match (mode, mode.shaders()) {
+            (RenderMode::Shaded, _) => return Ok(Vec::new()),
+            (_, Some(shaders)) => vec![shaders],
+            (_, None) => self.shader_variants.clone()
+        };
+
+        let mut pipelines: Vec<RasterPipeline> = Vec::with_capacity(shader_sets.len());
+        for shaders in shader_sets.iter() {
+            match self.build_pipeline_with(shaders, mode.raster_state()) {
+                Ok(p) => pipelines.push(p),
+                Err(e) => {
+                    for mut p in pipelines {
+                        p.destroy(&self.logical_layer);
+                    }
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(pipelines)
+    }
+
+    // Meshes with meshlets are drawn with these when mesh shading is on. Blended materials and the debug
+    // render modes stay on the vertex pipelines.
+    fn meshlet_pipeline_for(&self, shader: ShaderVariant, blend: BlendMode) -> Option<&RasterPipeline> {
+        match (self.render_mode, blend) {
+            (RenderMode::Shaded, BlendMode::Opaque) => self.mesh_shading.as_ref()
+                .and_then(|m| m.pipeline(shader, self.deferred.is_some())),
+            _ => None
+        }
+    }
+
+    fn pipeline_for(&self, shader: ShaderVariant, blend: BlendMode) -> &RasterPipeline {
+        match (self.render_mode, blend) {
+            (RenderMode::Shaded, BlendMode::Alpha) => &self.transparent_pipelines[shader.0], // Forward shaded, even when deferred
+            (RenderMode::Shaded, BlendMode::Opaque) => match &self.deferred {
+                Some(d) => &d.gbuffer_pipeline, // Lighting happens afterwards, the same way for every material
+                None => &self.raster_pipelines[shader.0]
+            },
+            (RenderMode::Wireframe, _) => &self.mode_pipelines[shader.0],
+            _ => &self.mode_pipelines[0] // Every material is drawn with the mode's shaders
+        }
+    }
+
+    // A pipeline for materials that use different shaders. The shaders read the same descriptor sets and
+    // vertex inputs as the default ones.
+    pub fn add_shader_variant(&mut self, shaders: ShaderSet) -> Result<ShaderVariant, RendererError> {
+        let pipeline = self.build_pipeline(&shaders)?;
+        let transparent_pipeline = match self.build_pipeline_with(&shaders, RasterState::TRANSPARENT) {
+            Ok(p) => p,
+            Err(e) => {
+                let mut pipeline = pipeline;
+                pipeline.destroy(&self.logical_layer);
+                return Err(e.into());
+            }
+        };
+        if self.render_mode == RenderMode::Wireframe {
+            match self.build_pipeline_with(&shaders, self.render_mode.raster_state()) {
+                Ok(p) => self.mode_pipelines.push(p),
+                Err(e) => {
+                    for mut p in [pipeline, transparent_pipeline] {
+                        p.destroy(&self.logical_layer);
+                    }
+                    return Err(e.into());
+                }
+            }
+        }
+        self.raster_pipelines.push(pipeline);
+        self.transparent_pipelines.push(transparent_pipeline);
+        self.shader_variants.push(shaders);
+
+        Ok(ShaderVariant(self.raster_pipelines.len() - 1))
+    }
+
+    // Tightly packed RGBA8 pixels. srgb should be set for colors and unset for data, I.E. normal maps.
+    pub fn upload_texture(&mut self, width: u32, height: u32, pixels: &[u8], srgb: bool) -> Result<TextureHandle, RendererError> {
+        self.check_texture_slot()?;
+        let texture = Texture::new(&self.logical_layer, &self.allocator, &mut self.upload, width, height, pixels, srgb)?;
+
+        Ok(self.resources.add_texture(&self.logical_layer, texture))
+    }
+
+    // A texture array with one layer per image, each tightly packed RGBA8 pixels of width x height, I.E. one
+    // per block texture. Mip levels are generated. Only ShaderVariant::VOXEL materials read arrays, from
+    // their base color texture, picking the layer by Vertex::layer. Use the forward pipeline with them, the
+    // deferred G-buffer pass samples every base color as a 2D texture.
+    pub fn upload_texture_array(&mut self, width: u32, height: u32, layers: &[&[u8]], srgb: bool) -> Result<TextureHandle, RendererError> {
+        if !self.resources.textures.has_free_slot(true) {
+            return Err(RendererError::TooManyTextures);
+        }
+        let texture = Texture::array(&self.logical_layer, &self.allocator, &mut self.upload, width, height, layers, srgb)?;
+
+        Ok(self.resources.add_texture(&self.logical_layer, texture))
+    }
+
+    // Checked before creating a texture, since one with a pending upload can't be destroyed right away
+    fn check_texture_slot(&self) -> Result<(), RendererError> {
+        match self.resources.textures.has_free_slot(false) {
+            true => Ok(()),
+            false => Err(RendererError::TooManyTextures)
+        }
+    }
+
+    // Block compressed formats the device can sample, for picking what to load or transcode KTX2 files to
+    pub fn compressed_formats(&self) -> &[BlockFormat] {
+        &self.physical_layer.compressed_formats
+    }
+
+    // Fails with NoSuitableFormat if the image's format isn't in compressed_formats
+    pub fn upload_compressed_texture(&mut self, image: &CompressedImage) -> Result<TextureHandle, RendererError> {
+        if !self.physical_layer.compressed_formats.contains(&image.format) {
+            return Err(RendererError::NoSuitableFormat("compressed texture"));
+        }
+        self.check_texture_slot()?;
+        let texture = Texture::compressed(&self.logical_layer, &self.allocator, &mut self.upload, image)?;
+
+        Ok(self.resources.add_texture(&self.logical_layer, texture))
+    }
+
+    // As upload_texture, but the mip chain is generated and kept in system memory with only the levels up to
+    // StreamingSettings::resident_size on the GPU up front. Finer levels are streamed in while draws show
+    // the texture big enough to need them, and dropped again once they don't or device memory runs short.
+    pub fn upload_streamed_texture(&mut self, width: u32, height: u32, pixels: &[u8], srgb: bool) -> Result<TextureHandle, RendererError> {
+        assert_eq!(pixels.len(), (width * height * 4) as usize, "Expected tightly packed RGBA8 pixels");
+        self.check_texture_slot()?;
+        let format = match srgb {
+            true => vk::Format::R8G8B8A8_SRGB,
+            false => vk::Format::R8G8B8A8_UNORM
+        };
+
+        self.streaming.add(&self.logical_layer, &self.allocator, &mut self.upload, &mut self.resources, format, width, height,
+                           mip_chain(pixels, width, height))
+    }
+
+    // As upload_streamed_texture with the image's own levels, which should run down to the resident size
+    pub fn upload_streamed_compressed_texture(&mut self, image: CompressedImage) -> Result<TextureHandle, RendererError> {
+        if !self.physical_layer.compressed_formats.contains(&image.format) {
+            return Err(RendererError::NoSuitableFormat("compressed texture"));
+        }
+        assert!(!image.levels.is_empty(), "Expected at least one mip level");
+        for (i, level) in image.levels.iter().enumerate() {
+            let expected = image.format.level_size((image.width >> i).max(1), (image.height >> i).max(1));
+            assert_eq!(level.len(), expected, "Mip level {} isn't a whole number of {:?} blocks", i, image.format);
+        }
+        self.check_texture_slot()?;
+
+        self.streaming.add(&self.logical_layer, &self.allocator, &mut self.upload, &mut self.resources,
+                           image.format.vk(image.srgb), image.width, image.height, image.levels)
+    }
+
+    // Applies from the next frame, textures move to the new resident size as they're next updated
+    pub fn set_texture_streaming(&mut self, settings: StreamingSettings) {
+        self.streaming.set_settings(settings);
+    }
+
+    pub fn texture_streaming(&self) -> StreamingSettings {
+        self.streaming.settings()
+    }
+
+    pub fn texture_streaming_stats(&self) -> StreamingStats {
+        self.streaming.stats()
+    }
+
+    // Drops a reference to the texture. Once the last one is gone its handle reads as TextureHandle::WHITE,
+    // and the image lives on until the GPU is done with the frames that may have sampled it. Materials
+    // hold a reference to each of their textures.
+    pub fn remove_texture(&mut self, handle: TextureHandle) {
+        let last_frame = self.last_frame();
+        self.resources.release_texture(handle, last_frame);
+    }
+
+    // Uploads new pixels behind an existing handle, I.E. when the image changed on disk. Materials using the
+    // texture pick it up from the next frame, a streamed texture stops streaming. False if the texture was removed, is a builtin or is a texture array.
+    pub fn replace_texture(&mut self, handle: TextureHandle, width: u32, height: u32, pixels: &[u8],
+                           srgb: bool) -> Result<bool, RendererError> {
+        if !self.resources.textures.replaceable(handle) || self.resources.textures.is_array(handle) {
+            return Ok(false); // Checked first, since a texture with a pending upload can't be destroyed yet
+        }
+        self.check_texture_slot()?; // The old texture keeps its slot until the frames in flight are done with it
+        let texture = Texture::new(&self.logical_layer, &self.allocator, &mut self.upload, width, height, pixels, srgb)?;
+        let last_frame = self.last_frame();
+        self.resources.replace_texture(&self.logical_layer, handle, texture, last_frame);
+        self.streaming.forget(handle);
+
+        Ok(true)
+    }
+
+    // As replace_texture, the new image can be compressed whatever the old one was
+    pub fn replace_compressed_texture(&mut self, handle: TextureHandle, image: &CompressedImage) -> Result<bool, RendererError> {
+        if !self.physical_layer.compressed_formats.contains(&image.format) {
+            return Err(RendererError::NoSuitableFormat("compressed texture"));
+        }
+        if !self.resources.textures.replaceable(handle) || self.resources.textures.is_array(handle) {
+            return Ok(false);
+        }
+        self.check_texture_slot()?;
+        let texture = Texture::compressed(&self.logical_layer, &self.allocator, &mut self.upload, image)?;
+        let last_frame = self.last_frame();
+        self.resources.replace_texture(&self.logical_layer, handle, texture, last_frame);
+        self.streaming.forget(handle);
+
+        Ok(true)
+    }
+
+    // Another reference, released by its own remove_texture. False if the texture is already gone.
+    pub fn retain_texture(&mut self, handle: TextureHandle) -> bool {
+        self.resources.retain_texture(handle)
+    }
+
+    // Replaces the lights used by lit materials, anything past MAX_LIGHTS is dropped
+    pub fn set_lights(&mut self, lights: &[Light]) {
+        if lights.len() > MAX_LIGHTS {
+            log::warn!("{} lights set, only the first {} are used", lights.len(), MAX_LIGHTS);
+        }
+        self.lights = lights.iter().take(MAX_LIGHTS).copied().collect();
+    }
+
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
+
+    // Light reaching every lit surface regardless of the lights
+    pub fn set_ambient(&mut self, ambient: Vec3) {
+        self.ambient = ambient;
+        self.ambient_ground = ambient;
+    }
+
+    // Ambient light fading from sky above to ground below. PBR materials also reflect it as their
+    // environment, Blinn-Phong ones only use the sky color.
+    pub fn set_ambient_hemisphere(&mut self, sky: Vec3, ground: Vec3) {
+        self.ambient = sky;
+        self.ambient_ground = ground;
+    }
+
+    // Draws a scattering sky behind the scene, I.E. from TimeOfDay::sky every tick. The sun's light and the
+    // ambient are set separately, see TimeOfDay::light and TimeOfDay::ambient.
+    pub fn set_sky(&mut self, sky: Option<Sky>) {
+        self.sky = sky;
+    }
+
+    pub fn sky(&self) -> Option<&Sky> {
+        self.sky.as_ref()
+    }
+
+    // Fades lit surfaces into the fog color with distance, and the sky's horizon with it. Unlit materials
+    // aren't fogged.
+    pub fn set_fog(&mut self, fog: Option<Fog>) {
+        self.fog = fog;
+    }
+
+    pub fn fog(&self) -> Option<&Fog> {
+        self.fog.as_ref()
+    }
+
+    // Textures projected onto lit surfaces, I.E. bullet marks and block damage. The nearest
+    // MAX_VISIBLE_DECALS in view are drawn, and ones with a lifetime expire as frames are drawn.
+    pub fn decals(&mut self) -> &mut Decals {
+        &mut self.decals
+    }
+
+    pub fn create_material(&mut self, desc: &MaterialDesc) -> Result<MaterialHandle, RendererError> {
+        Ok(self.resources.create_material(desc))
+    }
+
+    // Drops a reference to the material, draws still using it afterwards fall back to MaterialHandle::DEFAULT
+    pub fn remove_material(&mut self, handle: MaterialHandle) {
+        let last_frame = self.last_frame();
+        self.resources.release_material(handle, last_frame);
+    }
+
+    // False if the material is already gone
+    pub fn retain_material(&mut self, handle: MaterialHandle) -> bool {
+        self.resources.retain_material(handle)
+    }
+
+    fn cleanup_swap_chain(&mut self) {
+        self.logical_layer.wait_idle();
+
+        destroy_frame_buffers(&self.logical_layer, &self.frame_buffers);
+        self.frame_buffers.clear();
+        self.post.destroy_targets(&self.logical_layer, &self.allocator); // Holds a framebuffer using the depth buffer
+        if let Some(d) = self.deferred.as_mut() {
+            d.destroy_targets(&self.logical_layer, &self.allocator); // So does the G-buffer
+        }
+        self.render_target.destroy(&self.logical_layer, &self.allocator);
+    }
+
+    fn recreate_swap_chain(&mut self) -> Result<(), RendererError> {
+        self.swap_chain_dirty = false;
+        self.cleanup_swap_chain();
+
+        self.render_target = RenderTarget::new(&self.core, &self.physical_layer, &self.logical_layer, &self.allocator,
+                                              self.present_mode, &self.surface_formats, self.post.format, self.samples,
+                                              self.render_scale)?;
+        if self.render_target.samples != self.scene_target.samples() {
+            self.rebuild_scene_target()?;
+        }
+        self.post.resize(&self.logical_layer, &self.allocator, self.scene_target.render_pass(), &self.render_target)?;
+        if let Some(d) = self.deferred.as_mut() {
+            d.resize(&self.logical_layer, &self.allocator, &self.render_target)?;
+        }
+        if let Some(ids) = self.id_buffer.as_mut() {
+            ids.resize(&self.logical_layer, &self.allocator, &self.render_target)?;
+        }
+        self.frame_buffers = setup_frame_buffers(&self.logical_layer, self.present_pass, &self.render_target)?;
+        self.camera.set_aspect(self.render_target.extent.width as f32 / self.render_target.extent.height as f32);
+
+        Ok(())
+    }
+
+    // Goes fullscreen on the monitor the window is on. The swapchain follows the new size before the next
+    // frame.
+    pub fn set_fullscreen(&mut self, mode: FullscreenMode) {
+        let window = self.core.window();
+        window.set_fullscreen(winit_fullscreen(mode, window.current_monitor()));
+        self.swap_chain_dirty = true;
+    }
+
+    pub fn fullscreen(&self) -> FullscreenMode {
+        match self.core.window().fullscreen() {
+            None => FullscreenMode::Windowed,
+            Some(Fullscreen::Borderless(_)) => FullscreenMode::Borderless,
+            Some(Fullscreen::Exclusive(_)) => FullscreenMode::Exclusive
+        }
+    }
+
+    // Monitors connected right now. Their indices can change whenever monitors are plugged in or out.
+    pub fn monitors(&self) -> Vec<Monitor> {
+        monitor::monitors(self.core.window())
+    }
+
+    // Index into monitors() of the one the window is mostly on
+    pub fn current_monitor(&self) -> Option<usize> {
+        let window = self.core.window();
+        let current = window.current_monitor()?;
+        window.available_monitors().position(|m| m == current)
+    }
+
+    // Fullscreen on one of monitors(), borderless with no mode or exclusive in one of its video_modes.
+    // The swapchain is recreated at the new size before the next frame.
+    pub fn set_video_mode(&mut self, monitor: usize, mode: Option<VideoMode>) -> Result<(), RendererError> {
+        let window = self.core.window();
+        let fullscreen = monitor::monitor_handle(window, monitor)
+            .and_then(|m| monitor::fullscreen_on(m, mode))
+            .ok_or(RendererError::VideoModeUnavailable)?;
+        window.set_fullscreen(Some(fullscreen));
+        self.swap_chain_dirty = true;
+
+        Ok(())
+    }
+
+    pub fn set_cursor_mode(&mut self, mode: CursorMode) -> Result<(), RendererError> {
+        apply_cursor_mode(self.core.window(), mode)?;
+        self.cursor_mode = mode;
+
+        Ok(())
+    }
+
+    pub fn cursor_mode(&self) -> CursorMode {
+        self.cursor_mode
+    }
+
+    // Collects typed characters into InputState::text and suspends the action bindings from the next frame
+    // on, I.E. while a chat box is open
+    pub fn set_text_capture(&mut self, capture: bool) {
+        self.text_capture = capture;
+    }
+
+    pub fn set_title(&mut self, title: &str) {
+        self.core.window().set_title(title);
+    }
+
+    // The swapchain is recreated with the new mode before the next frame
+    pub fn set_present_mode(&mut self, present_mode: PresentMode) {
+        if present_mode != self.present_mode {
+            self.present_mode = present_mode;
+            self.swap_chain_dirty = true;
+        }
+    }
+
+    pub fn set_vsync(&mut self, vsync: bool) {
+        let supported = |m: PresentMode| self.physical_layer.present_modes.contains(&m.to_vk());
+        let present_mode = match vsync {
+            true => PresentMode::Fifo,
+            false if supported(PresentMode::Mailbox) => PresentMode::Mailbox, // Tear free when available
+            false if supported(PresentMode::Immediate) => PresentMode::Immediate,
+            false => {
+                log::warn!("Vsync can't be disabled on this surface");
+                PresentMode::Fifo
+            }
+        };
+        self.set_present_mode(present_mode);
+    }
+
+    pub fn present_mode(&self) -> PresentMode {
+        self.present_mode
+    }
+
+    // Samples per pixel, rounded down to a count the device supports. The render target and every scene
+    // pipeline are rebuilt before the next frame. Stays 1 with the deferred pipeline.
+    pub fn set_msaa(&mut self, samples: u32) {
+        let samples = scene_samples(&self.physical_layer, self.shading_pipeline(), samples);
+        if samples != self.samples {
+            self.samples = samples;
+            self.swap_chain_dirty = true;
+        }
+    }
+
+    pub fn msaa(&self) -> u32 {
+        self.samples.as_raw()
+    }
+
+    // The scene's resolution relative to the window's, clamped to 0.25 to 2. Below 1 trades sharpness for
+    // speed, above 1 supersamples. The scene's targets are rebuilt before the next frame.
+    pub fn set_render_scale(&mut self, scale: f32) {
+        let scale = clamp_render_scale(scale);
+        if scale != self.render_scale {
+            self.render_scale = scale;
+            self.swap_chain_dirty = true;
+        }
+    }
+
+    pub fn render_scale(&self) -> f32 {
+        self.render_scale
+    }
+
+    // The sample counts set_msaa can switch between, I.E. for a settings menu
+    pub fn supported_msaa(&self) -> Vec<u32> {
+        match self.shading_pipeline() {
+            ShadingPipeline::Deferred => vec![1],
+            ShadingPipeline::Forward => [1, 2, 4, 8, 16, 32, 64].into_iter()
+                .filter(|&s| self.physical_layer.sample_count(s).as_raw() == s)
+                .collect()
+        }
+    }
+
+    // Logical pixels, scaled by the monitor's DPI. Only changes the window while it isn't fullscreen, the
+    // swapchain follows once the resize event comes in.
+    pub fn set_window_size(&mut self, width: u32, height: u32) {
+        self.core.window().set_inner_size(LogicalSize::new(width, height));
+    }
+
+    // Logical pixels of the window's inside
+    pub fn window_size(&self) -> (u32, u32) {
+        let window = self.core.window();
+        window.inner_size().to_logical::<u32>(window.scale_factor()).into()
+    }
+
+    // Every Vulkan device on the system, including ones the renderer can't use. A DevicePreference::Index
+    // from these takes effect on the next start.
+    pub fn gpus(&self) -> &[GpuInfo] {
+        &self.physical_layer.gpus
+    }
+
+    // The device being rendered on
+    pub fn gpu(&self) -> &GpuInfo {
+        &self.physical_layer.gpus[self.physical_layer.selected]
+    }
+
+    // What the swapchain ended up with from the preferences in RendererConfig::surface_formats
+    pub fn surface_format(&self) -> SurfaceFormat {
+        SurfaceFormat {
+            format: self.render_target.surface_format,
+            color_space: self.render_target.color_space
+        }
+    }
+
+    // Every format the window's surface supports in a color space the renderer can output, I.E. to offer HDR
+    // only on displays that have it. Empty when headless.
+    pub fn supported_surface_formats(&self) -> Vec<SurfaceFormat> {
+        self.physical_layer.surface_formats()
+    }
+
+    // None draws frames as fast as the present mode allows
+    pub fn set_max_fps(&mut self, max_fps: Option<u32>) {
+        self.frame_interval = max_fps.map(frame_interval);
+    }
+
+    pub fn max_fps(&self) -> Option<u32> {
+        self.frame_interval.map(|i| (1.0 / i.as_secs_f64()).round() as u32)
+    }
+
+    // Wireframe needs the fillModeNonSolid feature, the other modes work everywhere
+    pub fn set_render_mode(&mut self, mode: RenderMode) -> Result<(), RendererError> {
+        if mode == self.render_mode {
+            return Ok(());
+        }
+        if mode == RenderMode::Wireframe && !self.physical_layer.fill_mode_non_solid {
+            return Err(RendererError::MissingFeature("fillModeNonSolid"));
+        }
+
+        let pipelines = self.build_mode_pipelines(mode)?;
+        let last_frame = self.last_frame(); // The old pipelines may still be referenced by in flight command buffers
+        for old_pipeline in mem::replace(&mut self.mode_pipelines, pipelines) {
+            self.resources.retire_pipeline(old_pipeline, last_frame);
+        }
+        self.render_mode = mode;
+
+        Ok(())
+    }
+
+    pub fn render_mode(&self) -> RenderMode {
+        self.render_mode
+    }
+
+    pub fn set_frustum_culling(&mut self, enabled: bool) {
+        self.frustum_culling = enabled;
+    }
+
+    pub fn set_occlusion_culling(&mut self, enabled: bool) {
+        self.occlusion_culling = enabled;
+    }
+
+    // For RenderQueue::push_occludable. One id per object, reused every frame it's drawn.
+    pub fn create_occlusion_id(&mut self) -> OcclusionId {
+        self.occlusion.create_id()
+    }
+
+    // Forgets the id's results, I.E. once its object is gone
+    pub fn remove_occlusion_id(&mut self, id: OcclusionId) {
+        self.occlusion.remove_id(id);
+    }
+
+    // Replaces the post-process chain, waiting for the GPU to finish with the old one. An empty chain
+    // copies the scene straight to the swapchain, tonemapped with ACES if it's HDR.
+    pub fn set_post_effects(&mut self, effects: &[PostEffect]) -> Result<(), RendererError> {
+        self.logical_layer.wait_idle();
+        self.post.set_effects(&self.logical_layer, effects)
+    }
+
+    pub fn post_effects(&self) -> &[PostEffect] {
+        self.post.effects()
+    }
+
+    pub fn shading_pipeline(&self) -> ShadingPipeline {
+        match self.deferred {
+            Some(_) => ShadingPipeline::Deferred,
+            None => ShadingPipeline::Forward
+        }
+    }
+
+    // Ignored with the forward pipeline, which has no G-buffer to compute occlusion from
+    pub fn set_ssao(&mut self, settings: Option<SsaoSettings>) {
+        match self.deferred.as_mut() {
+            Some(d) => d.ssao_settings = settings,
+            None => log::warn!("SSAO needs the G-buffer of the deferred pipeline, it stays off")
+        }
+    }
+
+    pub fn ssao(&self) -> Option<SsaoSettings> {
+        self.deferred.as_ref().and_then(|d| d.ssao_settings)
+    }
+
+    // Ignored unless the device could ray trace when the renderer was created with config.ray_tracing set
+    // and the deferred pipeline, since the device features and shaders are only set up then
+    pub fn set_ray_tracing(&mut self, settings: Option<RayTracingSettings>) {
+        match self.deferred.as_mut().filter(|d| d.ray_tracing_supported()) {
+            Some(d) => d.ray_tracing_settings = settings,
+            None => log::warn!("Ray tracing wasn't set up at startup, it stays off")
+        }
+    }
+
+    pub fn ray_tracing(&self) -> Option<RayTracingSettings> {
+        self.deferred.as_ref().and_then(|d| d.ray_tracing_settings)
+    }
+
+    pub fn ray_tracing_supported(&self) -> bool {
+        self.deferred.as_ref().map_or(false, |d| d.ray_tracing_supported())
+    }
+
+    // The optional device features that were both requested by the config and supported, I.E. for skipping
+    // settings that would fall back anyway
+    pub fn capabilities(&self) -> DeviceCapabilities {
+        self.logical_layer.capabilities
+    }
+
+    // Everything the device supports, whether or not the config asked for it
+    pub fn supported_capabilities(&self) -> DeviceCapabilities {
+        self.physical_layer.capabilities
+    }
+
+    // False when HDR was disabled in the config or the device can't render to a float format
+    pub fn hdr(&self) -> bool {
+        is_hdr(self.post.format)
+    }
+
+    // Recreates the shadow maps, waiting for the GPU to stop using the old ones
+    pub fn set_shadow_resolution(&mut self, resolution: u32) -> Result<(), RendererError> {
+        assert!(resolution > 0, "Shadow maps need at least one texel");
+        if resolution != self.shadow_maps.resolution {
+            self.logical_layer.wait_idle();
+            self.shadow_maps.resize(&self.logical_layer, &self.allocator, resolution)?;
+        }
+
+        Ok(())
+    }
+
+    // Larger distances shadow more of the scene at a lower effective resolution
+    pub fn set_shadow_distance(&mut self, distance: f32) {
+        self.shadow_distance = distance;
+    }
+
+    // fov is the vertical field of view in radians
+    pub fn set_camera(&mut self, pos: Vec3, target: Vec3, fov: f32) {
+        self.camera.look_at(pos, target);
+        self.camera.set_fov(fov);
+    }
+
+    pub fn upload_mesh(&mut self, vertices: &[Vertex], indices: &[u32]) -> Result<MeshHandle, RendererError> {
+        let mesh = Mesh::new(&self.logical_layer,
+                             &self.allocator,
+                             &mut self.upload,
+                             vertices,
+                             indices)?;
+
+        Ok(self.resources.add_mesh(mesh))
+    }
+
+    // A mesh that's also split into meshlets when mesh shading is on, so opaque draws of it are culled per
+    // meshlet on the GPU by the task shader. Otherwise the same as upload_mesh. Instanced draws, shadows
+    // and blended materials still read its vertex buffer.
+    pub fn upload_meshlet_mesh(&mut self, vertices: &[Vertex], indices: &[u32]) -> Result<MeshHandle, RendererError> {
+        let mesh = Mesh::new(&self.logical_layer, &self.allocator, &mut self.upload, vertices, indices)?;
+        let set_layout = match self.mesh_shading.as_ref() {
+            Some(m) => m.set_layout,
+            None => return Ok(self.resources.add_mesh(mesh))
+        };
+        let meshlets = match Meshlets::new(&self.logical_layer, &self.allocator, &mut self.upload, set_layout, vertices, indices,
+                                           mesh.vertex_buffer.buf) {
+            Ok(m) => m,
+            Err(e) => {
+                mesh.destroy(&self.logical_layer, &self.allocator);
+                return Err(e);
+            }
+        };
+
+        Ok(self.resources.add_mesh(mesh.with_meshlets(meshlets)))
+    }
+
+    // Whether upload_meshlet_mesh builds meshlets, I.E. config.mesh_shading was set, the device supports
+    // VK_EXT_mesh_shader and the meshlet shaders loaded
+    pub fn mesh_shading_supported(&self) -> bool {
+        self.mesh_shading.is_some()
+    }
+
+    // A mesh whose vertices are blended towards each target by its weight in the vertex shader, I.E. for
+    // facial animation. Every target needs a position offset per vertex. Weights start at 0, shadows are
+    // cast by the unmorphed mesh.
+    pub fn upload_morph_mesh(&mut self, vertices: &[Vertex], indices: &[u32],
+                             targets: &[MorphTarget]) -> Result<MeshHandle, RendererError> {
+        if targets.len() > MAX_MORPH_TARGETS {
+            return Err(RendererError::TooManyMorphTargets(targets.len()));
+        }
+        let mesh = Mesh::new(&self.logical_layer, &self.allocator, &mut self.upload, vertices, indices)?;
+        let morph = match MorphTargets::new(&self.logical_layer, &self.allocator, &mut self.upload, self.resources.morph_layout,
+                                            MAX_FRAMES_IN_FLIGHT, vertices.len(), targets) {
+            Ok(m) => m,
+            Err(e) => {
+                mesh.destroy(&self.logical_layer, &self.allocator);
+                return Err(e);
+            }
+        };
+
+        Ok(self.resources.add_mesh(mesh.with_morph_targets(morph, vertices, targets)))
+    }
+
+    // One weight per target, usually between 0 and 1. Draws use them from the next frame on. False if the
+    // mesh was removed or has no morph targets.
+    pub fn set_morph_weights(&mut self, handle: MeshHandle, weights: &[f32]) -> bool {
+        self.resources.set_morph_weights(handle, weights)
+    }
+
+    // Drops a reference to the mesh. Once the last one is gone the handle is stale and draws using it are
+    // skipped. The buffers live on until the GPU is done with the frames that may have drawn them.
+    pub fn remove_mesh(&mut self, handle: MeshHandle) {
+        let last_frame = self.last_frame();
+        self.resources.release_mesh(handle, last_frame);
+    }
+
+    // Uploads new geometry behind an existing handle, draws pick it up from the next frame. False if the
+    // mesh was removed.
+    pub fn replace_mesh(&mut self, handle: MeshHandle, vertices: &[Vertex], indices: &[u32]) -> Result<bool, RendererError> {
+        if self.resources.mesh(handle).is_none() {
+            return Ok(false);
+        }
+        let mesh = Mesh::new(&self.logical_layer, &self.allocator, &mut self.upload, vertices, indices)?;
+        let last_frame = self.last_frame();
+        self.resources.replace_mesh(handle, mesh, last_frame);
+
+        Ok(true)
+    }
+
+    // Another reference, for sharing a mesh between owners that each remove it. False if the mesh is
+    // already gone.
+    pub fn retain_mesh(&mut self, handle: MeshHandle) -> bool {
+        self.resources.retain_mesh(handle)
+    }
+
+    // The last submitted frame's slot, the newest one that can reference a resource released now
+    fn last_frame(&self) -> usize {
+        (self.current_frame + MAX_FRAMES_IN_FLIGHT - 1) % MAX_FRAMES_IN_FLIGHT
+    }
+
+    // Compute shaders run in the frame's command buffer, so the graphics queue has to support them
+    pub fn create_compute_pipeline(&mut self, shader: &ShaderSource, storage_buffers: u32,
+                                   push_constant_size: u32) -> Result<ComputePipelineHandle, RendererError> {
+        if self.physical_layer.compute_family_index.is_none() {
+            return Err(RendererError::ComputeUnsupported);
+        }
+
+        Ok(self.compute.create_pipeline(&self.logical_layer, shader, storage_buffers, push_constant_size)?)
+    }
+
+    // Device local, so it's filled by write_storage_buffer or compute shaders. Usable as vertex, index or
+    // indirect draw buffers as well.
+    pub fn create_storage_buffer(&mut self, size: u64) -> Result<StorageBufferHandle, RendererError> {
+        self.compute.create_buffer(&self.logical_layer, &self.allocator, size)
+    }
+
+    // Copied before the next frame is recorded. Frames still in flight may be reading the buffer, so this
+    // is meant for initial contents rather than per frame updates.
+    pub fn write_storage_buffer<T: Pod>(&mut self, handle: StorageBufferHandle, offset: u64, data: &[T]) -> Result<(), RendererError> {
+        self.compute.write_buffer(&self.logical_layer, &self.allocator, &mut self.upload, handle, offset, data)
+    }
+
+    // Runs once at the start of the next frame, before anything is drawn
+    pub fn dispatch(&mut self, dispatch: ComputeDispatch) {
+        self.compute.queue(dispatch);
+    }
+
+    // Objects drawn from a fixed set of meshes with one material, frustum culled by a compute shader and drawn
+    // with a single indirect draw, so tens of thousands of them cost the CPU next to nothing. meshes are
+    // vertices and indices, objects refer to them by position. Indirect batches don't cast shadows.
+    pub fn create_indirect_batch(&mut self, material: MaterialHandle, meshes: &[(&[Vertex], &[u32])],
+                                 capacity: u32) -> Result<IndirectBatchHandle, RendererError> {
+        if self.physical_layer.compute_family_index.is_none() {
+            return Err(RendererError::ComputeUnsupported);
+        }
+        if !self.physical_layer.indirect_draws {
+            return Err(RendererError::MissingFeature("multiDrawIndirect and drawIndirectFirstInstance"));
+        }
+        if self.indirect_cull.is_none() {
+            self.indirect_cull = Some(create_cull_pipeline(&self.logical_layer)?);
+        }
+
+        let set_layout = self.indirect_cull.as_ref().unwrap().set_layout;
+        let batch = IndirectBatch::new(&self.logical_layer, &self.allocator, &mut self.upload, set_layout, MAX_FRAMES_IN_FLIGHT,
+                                       material, meshes, capacity)?;
+        self.indirect_batches.push(Some(batch));
+
+        Ok(IndirectBatchHandle(self.indirect_batches.len() - 1))
+    }
+
+    // Replaces every object in the batch from the next frame on. False if the batch was removed.
+    pub fn set_indirect_objects(&mut self, batch: IndirectBatchHandle, objects: &[IndirectObject]) -> bool {
+        match self.indirect_batches.get_mut(batch.0).and_then(|b| b.as_mut()) {
+            Some(b) => {
+                b.set_objects(objects);
+                true
+            },
+            None => false
+        }
+    }
+
+    pub fn remove_indirect_batch(&mut self, batch: IndirectBatchHandle) {
+        if let Some(b) = self.indirect_batches.get_mut(batch.0).and_then(|b| b.take()) {
+            let last_frame = self.last_frame();
+            b.retire(&mut self.resources.deletions, last_frame);
+        }
+    }
+
+    // Starts out empty, fill it with update_dynamic_mesh
+    pub fn create_dynamic_mesh(&mut self) -> Result<DynamicMeshHandle, RendererError> {
+        let mesh = DynamicMesh::new(&self.logical_layer, &self.allocator, MAX_FRAMES_IN_FLIGHT)?;
+
+        // Reuse the slot of a removed mesh if there is one
+        match self.dynamic_meshes.iter().position(|m| m.is_none()) {
+            Some(i) => {
+                self.dynamic_meshes[i] = Some(mesh);
+                Ok(DynamicMeshHandle(i))
+            },
+            None => {
+                self.dynamic_meshes.push(Some(mesh));
+                Ok(DynamicMeshHandle(self.dynamic_meshes.len() - 1))
+            }
+        }
+    }
+
+    // Replaces the geometry for every draw recorded from now on. Cheap enough to call every frame.
+    pub fn update_dynamic_mesh(&mut self, handle: DynamicMeshHandle, vertices: &[Vertex], indices: &[u32]) {
+        self.dynamic_meshes[handle.0].as_mut().expect("Dynamic mesh was removed").set(vertices, indices);
+    }
+
+    // The handle is invalid afterwards and may be handed out again by create_dynamic_mesh. The buffers are
+    // destroyed once the frames in flight that may draw them have finished.
+    pub fn remove_dynamic_mesh(&mut self, handle: DynamicMeshHandle) {
+        if let Some(mesh) = self.dynamic_meshes.get_mut(handle.0).and_then(|m| m.take()) {
+            let last_frame = self.last_frame();
+            mesh.retire(&mut self.resources.deletions, last_frame);
+        }
+    }
+
+    // Bakes printable ASCII at size pixels per em
+    pub fn load_font(&mut self, ttf: &[u8], size: f32) -> Result<FontHandle, RendererError> {
+        let atlas = FontAtlas::bake(ttf, size)?;
+        let texture = self.upload_texture(atlas.width, atlas.height, &atlas.pixels, false)?;
+        self.fonts.push(Font::new(atlas, texture));
+
+        Ok(FontHandle(self.fonts.len() - 1))
+    }
+
+    // Drawn over the next frame only. position is the top left of the text in pixels from the top left
+    // of the window.
+    pub fn draw_text(&mut self, font: FontHandle, text: &str, position: Vec2, color: Vec4) {
+        let font = &self.fonts[font.0];
+        let mut vertices = Vec::with_capacity(text.len() * 4);
+        let mut indices = Vec::with_capacity(text.len() * 6);
+        font.build(text, position, color, &mut vertices, &mut indices);
+        self.overlay.push(font.texture, &vertices, &indices);
+    }
+
+    // Drawn over the next frame only, in order with text and other sprites
+    pub fn draw_sprite(&mut self, sprite: &Sprite) {
+        let mut vertices = Vec::with_capacity(4);
+        let mut indices = Vec::with_capacity(6);
+        sprite.build(&mut vertices, &mut indices);
+        self.overlay.push(sprite.texture, &vertices, &indices);
+    }
+
+    // Sorted by layer and texture, then drawn over the next frame. The batch is left as is, so it can be
+    // drawn again or cleared by the caller.
+    pub fn draw_sprites(&mut self, batch: &SpriteBatch) {
+        let overlay = &mut self.overlay;
+        batch.build(|texture, vertices, indices| overlay.push(texture, vertices, indices));
+    }
+
+    // Size of text's bounding box in pixels, I.E. for right aligning it
+    pub fn text_size(&self, font: FontHandle, text: &str) -> Vec2 {
+        self.fonts[font.0].measure(text)
+    }
+
+    // egui's context, for building UI inside run_with's callback or checking whether it wants input
+    pub fn ui_context(&self) -> &egui::Context {
+        &self.ui.ctx
+    }
+
+    // Lines for the next frame only, so they have to be drawn again every frame
+    pub fn debug_draw(&mut self) -> &mut DebugDraw {
+        &mut self.debug_draw
+    }
+
+    pub fn mesh(&self, handle: MeshHandle) -> &Mesh {
+        self.resources.mesh(handle).expect("Mesh was removed")
+    }
+
+    pub fn render_queue(&mut self) -> &mut RenderQueue {
+        &mut self.render_queue
+    }
+
+    pub fn input(&self) -> &InputState {
+        &self.input
+    }
+
+    pub fn input_mut(&mut self) -> &mut InputState {
+        &mut self.input
+    }
+
+    pub fn stats(&self) -> &FrameStats {
+        &self.stats
+    }
+
+    // Device memory per heap and category against the driver's budget, queried on each call
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.allocator.stats(&self.core, &self.physical_layer, self.logical_layer.capabilities.memory_budget)
+    }
+
+    pub fn camera(&self) -> &Camera {
+        &self.camera
+    }
+
+    pub fn camera_mut(&mut self) -> &mut Camera {
+        &mut self.camera
+    }
+
+    // Of the window in physical pixels, the space text and sprites are positioned in
+    pub fn screen_size(&self) -> Vec2 {
+        Vec2::new(self.render_target.extent.width as f32, self.render_target.extent.height as f32)
+    }
+
+    // The camera ray through a pixel of the render, I.E. input.cursor_pos(), for VoxelWorld::raycast
+    pub fn screen_ray(&self, screen_pos: Vec2) -> Ray {
+        let extent = self.render_target.extent;
+        self.camera.screen_ray(screen_pos, Vec2::new(extent.width as f32, extent.height as f32))
+    }
+
+    // Nearest queued mesh under a pixel, tested against world space bounding boxes rather than triangles.
+    // Picks against what's been drawn so far this frame, so call it after drawing. Dynamic meshes and
+    // indirect batches have no bounds on the CPU's side and can't be picked. read_id_at is exact.
+    pub fn pick(&self, screen_pos: Vec2) -> Option<Hit> {
+        let ray = self.screen_ray(screen_pos);
+        let hit = |target: PickTarget, mesh: MeshHandle, transform: &Mat4| {
+            let bounds = self.resources.mesh(mesh)?.bounds().transformed(transform);
+            ray.intersect_aabb(&bounds).map(|(distance, normal)| Hit {
+                target,
+                mesh,
+                distance,
+                point: ray.at(distance),
+                normal
+            })
+        };
+
+        let items = self.render_queue.items().iter().enumerate()
+            .filter_map(|(i, item)| hit(PickTarget::Item(i), item.mesh, &item.transform));
+        let instanced = self.render_queue.instanced_items().iter().enumerate()
+            .flat_map(|(i, item)| {
+                let range = item.first_instance as usize..(item.first_instance + item.instance_count) as usize;
+                self.render_queue.instances()[range].iter()
+                    .map(move |instance| (i, item.mesh, instance))
+            })
+            .filter_map(|(i, mesh, instance)| {
+                hit(PickTarget::Instance { item: i, id: instance.id }, mesh, &Mat4::from_cols_array_2d(&instance.transform))
+            });
+
+        items.chain(instanced).min_by(|a, b| a.distance.total_cmp(&b.distance))
+    }
+
+    // Turns rendering pick IDs for read_id_at on or off. Waits for the GPU to stop using the ID buffer
+    // before destroying it.
+    pub fn set_id_buffer(&mut self, enabled: bool) -> Result<(), RendererError> {
+        match (enabled, self.id_buffer.is_some()) {
+            (true, false) => {
+                self.id_buffer = Some(IdBuffer::new(&self.logical_layer, &self.allocator, self.physical_layer.family_index,
+                                                    self.uniform_buffer.descriptor_set_layout, &self.vertex_layouts,
+                                                    &self.render_target)?);
+            },
+            (false, true) => {
+                self.logical_layer.wait_idle();
+                if let Some(ids) = self.id_buffer.take() {
+                    ids.destroy(&self.logical_layer, &self.allocator);
+                }
+            },
+            _ => ()
+        }
+
+        Ok(())
+    }
+
+    pub fn id_buffer(&self) -> bool {
+        self.id_buffer.is_some()
+    }
+
+    // The pick ID of the frontmost draw at a pixel of the last drawn frame, exact to its triangles unlike
+    // pick. Pixels are physical from the top left as InputState::cursor_pos reports them. None over the
+    // background, draws pushed without an ID, or before a frame has been drawn at this size. Waits for
+    // the frame and then a one texel copy, so it stalls the CPU on the GPU each call. Suits clicks rather
+    // than every frame. Fails with IdBufferDisabled unless set_id_buffer or RendererConfig::id_buffer is on.
+    pub fn read_id_at(&mut self, x: u32, y: u32) -> Result<Option<u32>, RendererError> {
+        let ids = match self.id_buffer.as_mut() {
+            Some(i) => i,
+            None => return Err(RendererError::IdBufferDisabled)
+        };
+        self.frame_timeline.wait(&self.logical_layer, self.frame_timeline.value())?; // Every submitted frame
+
+        let (extent, scene_extent) = (self.render_target.extent, self.render_target.scene_extent);
+        let x = (x as u64 * scene_extent.width as u64 / extent.width as u64) as u32; // To the scene's texels at any render scale
+        let y = (y as u64 * scene_extent.height as u64 / extent.height as u64) as u32;
+        ids.read(&self.logical_layer, x, y)
+    }
+
+    fn window_id(&self) -> WindowId {
+        self.core.window().id()
+    }
+
+    fn is_minimized(&self) -> bool {
+        match &self.core.window {
+            Some(w) => w.inner_size().width == 0 || w.inner_size().height == 0,
+            None => false
+        }
+    }
+
+    // Draws whatever is in the render queue once, without an event loop. This is how headless renderers
+    // draw, the queue isn't cleared afterwards.
+    pub fn render_frame(&mut self) -> Result<(), RendererError> {
+        self.draw_frame()
+    }
+
+    // The last drawn frame as tightly packed RGBA8 sRGB pixels, rows from the top. Only headless
+    // renderers can be read back since swapchain images can't be copied out of.
+    pub fn read_frame(&mut self) -> Result<(u32, u32, Vec<u8>), RendererError> {
+        assert!(self.render_target.headless(), "Only headless renderers can read frames back");
+        assert!(self.stats.frame_count > 0, "No frame has been drawn yet");
+
+        self.logical_layer.wait_idle();
+        let pixels = self.render_target.read_offscreen(&self.logical_layer, &self.allocator, self.command_pool, self.last_frame())?;
+
+        Ok((self.render_target.extent.width, self.render_target.extent.height, pixels))
+    }
+
+    // Blocks until every submitted frame has finished, I.E. before reading a headless frame back
+    pub fn wait_idle(&self) {
+        self.logical_layer.wait_idle();
+    }
+
+    // Draws whatever is in the render queue every frame until the window is closed
+    pub fn run_blocking(self, event_loop: EventLoop<()>) -> ! {
+        self.run(event_loop, |_| false);
+    }
+
+    // Calls on_frame before drawing each frame with the input gathered since the last frame and the time
+    // since the previous call. The render queue is cleared before each call.
+    pub fn run_with<F>(self, event_loop: EventLoop<()>, mut on_frame: F) -> !
+        where F: FnMut(&mut Frame, &InputState, Duration) + 'static {
+        let mut last_frame = Instant::now();
+
+        self.run(event_loop, move |renderer| {
+            let now = Instant::now();
+            let delta = now - last_frame;
+            last_frame = now;
+            renderer.decals.advance(delta);
+
+            renderer.render_queue.clear();
+            renderer.ui.begin(renderer.core.window.as_ref());
+            // Moved out for the callback so the frame can borrow the renderer mutably
+            let input = mem::take(&mut renderer.input);
+            let mut frame = Frame::new(renderer, 1.0);
+            {
+                profile_scope!("game frame");
+                on_frame(&mut frame, &input, delta);
+            }
+            let exit = frame.exit_requested();
+            renderer.input = input;
+            renderer.input.text_capture = renderer.text_capture;
+            renderer.ui.end(renderer.core.window.as_ref());
+
+            exit
+        });
+    }
+
+    // Runs game.tick at the configured tick rate, independent of how fast frames are drawn, and game.frame
+    // once per frame. Frames between ticks interpolate with Frame::alpha.
+    pub fn run_fixed<G: Game + 'static>(self, event_loop: EventLoop<()>, mut game: G) -> ! {
+        let mut timestep = FixedTimestep::new(self.tick_rate);
+        let mut last_frame = Instant::now();
+
+        self.run(event_loop, move |renderer| {
+            let now = Instant::now();
+            let delta = now - last_frame;
+            last_frame = now;
+            renderer.decals.advance(delta);
+
+            let input = mem::take(&mut renderer.input);
+            for _ in 0..timestep.advance(delta) {
+                profile_scope!("tick");
+                game.tick(renderer, &input, timestep.step());
+            }
+
+            renderer.render_queue.clear();
+            renderer.ui.begin(renderer.core.window.as_ref());
+            let mut frame = Frame::new(renderer, timestep.alpha());
+            {
+                profile_scope!("game frame");
+                game.frame(&mut frame, &input, delta);
+            }
+            let exit = frame.exit_requested();
+            renderer.input = input;
+            renderer.input.text_capture = renderer.text_capture;
+            renderer.ui.end(renderer.core.window.as_ref());
+
+            exit
+        });
+    }
+
+    // on_redraw runs before each frame is drawn and returns true to exit after it
+    fn run<F>(mut self, event_loop: EventLoop<()>, mut on_redraw: F) -> !
+        where F: FnMut(&mut CubulousRenderer) -> bool + 'static {
+        assert!(!self.core.headless(), "Headless renderers have no window events, draw with render_frame instead");
+        let mut next_frame = Instant::now(); // Earliest time the frame limiter allows the next frame
+
+        event_loop.run(move |event, _, control_flow| {
+            match event {
+                Event::WindowEvent { event, window_id } if window_id == self.window_id() => {
+                    // egui sees every event first. Events it used don't reach the game's input, except
+                    // releases so keys held down before a text box took focus don't get stuck.
+                    let consumed = self.ui.handle_window_event(&event);
+                    match event {
+                        WindowEvent::CloseRequested => *control_flow = ControlFlow::Exit,
+                        WindowEvent::Resized(_) | WindowEvent::ScaleFactorChanged { .. } => self.swap_chain_dirty = true,
+                        WindowEvent::Focused(true) if self.cursor_mode != CursorMode::Normal => {
+                            if let Err(e) = apply_cursor_mode(self.core.window(), self.cursor_mode) {
+                                log::warn!("{}", e);
+                            }
+                        },
+                        _ if !consumed || is_release(&event) => self.input.handle_window_event(&event),
+                        _ => ()
+                    }
+                },
+                Event::DeviceEvent { event, .. } => self.input.handle_device_event(&event),
+                Event::MainEventsCleared => {
+                    self.gamepads.poll(&mut self.input);
+                    if *control_flow == ControlFlow::Exit {
+                        return;
+                    }
+                    // Frames are drawn back to back, or sleep until the limiter allows the next one. Minimized
+                    // windows sleep until an event arrives.
+                    *control_flow = match self.is_minimized() {
+                        true => ControlFlow::Wait,
+                        false if self.frame_interval.is_some() && Instant::now() < next_frame => ControlFlow::WaitUntil(next_frame),
+                        false => {
+                            // Emits a RedrawRequested event after input events end
+                            self.core.window().request_redraw();
+                            ControlFlow::Poll
+                        }
+                    };
+                },
+                Event::RedrawRequested(window_id) if window_id == self.window_id() => {
+                    profiling::new_frame();
+                    profile_scope!("frame");
+                    if let Some(interval) = self.frame_interval {
+                        // Paced from the previous deadline so frames don't drift, without bursting to catch up
+                        // after a slow frame
+                        next_frame = (next_frame + interval).max(Instant::now());
+                    }
+                    if on_redraw(&mut self) {
+                        *control_flow = ControlFlow::Exit;
+                    }
+                    if let Err(e) = self.draw_frame() {
+                        log::error!("{}", e);
+                        *control_flow = ControlFlow::Exit;
+                    }
+                    self.input.end_frame();
+                },
+                Event::LoopDestroyed => unsafe { self.logical_layer.logical_device.device_wait_idle().unwrap() },
+                _ => (), // Similar to the "default" case of a switch statement: return void which is essentially () in Rust
+            }
+        });
+    }
+}
+
+impl Drop for CubulousRenderer {
+    fn drop(&mut self) {
+        self.cleanup_swap_chain();
+        for m in self.dynamic_meshes.iter().flatten() {
+            m.destroy(&self.logical_layer, &self.allocator);
+        }
+        self.debug_mesh.destroy(&self.logical_layer, &self.allocator);
+        for b in self.indirect_batches.iter().flatten() {
+            b.destroy(&self.logical_layer, &self.allocator);
+        }
+        if let Some(p) = &self.indirect_cull {
+            p.destroy(&self.logical_layer);
+        }
+        self.uniform_buffer.destroy(&self.logical_layer, &self.allocator);
+        self.instance_buffer.destroy(&self.logical_layer, &self.allocator);
+        self.upload.destroy(&self.logical_layer, &self.allocator);
+        if let Some(t) = &self.timestamps {
+            t.destroy(&self.logical_layer);
+        }
+        self.destroy_sync_objects();
+        self.destroy_command_pool();
+        for p in self.raster_pipelines.iter_mut().chain(self.transparent_pipelines.iter_mut()) {
+            p.destroy(&self.logical_layer);
+        }
+        self.debug_pipeline.destroy(&self.logical_layer);
+        self.occlusion_pipeline.destroy(&self.logical_layer);
+        self.sky_pass.destroy(&self.logical_layer);
+        self.occlusion.destroy(&self.logical_layer, &self.allocator);
+        for p in self.mode_pipelines.iter_mut() {
+            p.destroy(&self.logical_layer);
+        }
+        self.shadow_maps.destroy(&self.logical_layer, &self.allocator);
+        if let Some(ids) = self.id_buffer.as_ref() {
+            ids.destroy(&self.logical_layer, &self.allocator);
+        }
+        self.compute.destroy(&self.logical_layer, &self.allocator);
+        self.post.destroy(&self.logical_layer, &self.allocator);
+        if let Some(d) = self.deferred.as_mut() {
+            d.destroy(&self.logical_layer, &self.allocator);
+        }
+        if let Some(m) = self.mesh_shading.as_mut() {
+            m.destroy(&self.logical_layer);
+        }
+        self.overlay.destroy(&self.logical_layer, &self.allocator);
+        self.resources.destroy(&self.logical_layer, &self.allocator);
+        if let Some(p) = self.scene_target.render_pass() {
+            destroy_render_pass(&self.logical_layer, p);
+        }
+        destroy_render_pass(&self.logical_layer, self.present_pass);
+        self.allocator.destroy(&self.logical_layer);
         self.logical_layer.destroy();
         self.core.destroy();
     }
@@ -0,0 +1,277 @@
+use std::mem;
+
+use ash::vk;
+
+use crate::renderer::allocator::Allocator;
+use crate::renderer::deletion_queue::{Deletion, DeletionQueue};
+use crate::renderer::error::RendererError;
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::material::{MaterialDesc, Materials};
+use crate::renderer::mesh::{Mesh, MeshHandle};
+use crate::renderer::morph::{self, MorphTargets};
+use crate::renderer::raster_pipeline::RasterPipeline;
+use crate::renderer::render_queue::MaterialHandle;
+use crate::renderer::staging_buf::UploadContext;
+use crate::renderer::texture::{Texture, TextureHandle, Textures};
+
+// Slot index plus the generation of the resource in it. Freeing a slot bumps its generation, so stale
+// handles miss instead of reaching whatever reuses the slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub(crate) struct Handle {
+    index: u32,
+    generation: u32
+}
+
+impl Handle {
+    // The nth resource created in a fresh pool, I.E. the builtin textures and materials
+    pub(crate) const fn first(index: u32) -> Handle {
+        Handle { index, generation: 0 }
+    }
+}
+
+struct Slot<T> {
+    value: Option<T>,
+    generation: u32,
+    refs: u32
+}
+
+// Reference counted resources of one type
+pub(crate) struct Pool<T> {
+    slots: Vec<Slot<T>>,
+    free: Vec<u32> // Empty slots, reused before the pool grows
+}
+
+impl<T> Pool<T> {
+    pub(crate) fn new() -> Pool<T> {
+        Pool {
+            slots: Vec::new(),
+            free: Vec::new()
+        }
+    }
+
+    // The new resource starts out with one reference
+    pub(crate) fn insert(&mut self, value: T) -> Handle {
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.value = Some(value);
+                slot.refs = 1;
+                Handle { index, generation: slot.generation }
+            },
+            None => {
+                self.slots.push(Slot { value: Some(value), generation: 0, refs: 1 });
+                Handle::first(self.slots.len() as u32 - 1)
+            }
+        }
+    }
+
+    fn slot_mut(&mut self, handle: Handle) -> Option<&mut Slot<T>> {
+        self.slots.get_mut(handle.index as usize)
+            .filter(|s| s.generation == handle.generation && s.value.is_some())
+    }
+
+    pub(crate) fn get(&self, handle: Handle) -> Option<&T> {
+        self.slots.get(handle.index as usize)
+            .filter(|s| s.generation == handle.generation)
+            .and_then(|s| s.value.as_ref())
+    }
+
+    pub(crate) fn get_mut(&mut self, handle: Handle) -> Option<&mut T> {
+        self.slot_mut(handle).and_then(|s| s.value.as_mut())
+    }
+
+    // False for stale handles
+    pub(crate) fn retain(&mut self, handle: Handle) -> bool {
+        match self.slot_mut(handle) {
+            Some(slot) => {
+                slot.refs += 1;
+                true
+            },
+            None => false
+        }
+    }
+
+    // Drops a reference, handing back the resource once the last one is gone. Its handles are stale from
+    // then on.
+    pub(crate) fn release(&mut self, handle: Handle) -> Option<T> {
+        let slot = self.slot_mut(handle)?;
+        slot.refs -= 1;
+        if slot.refs > 0 {
+            return None;
+        }
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(handle.index);
+        slot.value.take()
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        self.slots.iter().filter_map(|s| s.value.as_ref())
+    }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        self.slots.iter_mut().filter_map(|s| s.value.as_mut())
+    }
+}
+
+// Owns every mesh, texture and material behind reference counted handles. Resources whose last reference
+// is released are destroyed once the frame slot they were retired to has finished on the GPU, so the
+// frames in flight that drew them are never left reading freed memory.
+pub(crate) struct ResourceManager {
+    meshes: Pool<Mesh>,
+    pub(crate) textures: Textures,
+    pub(crate) materials: Materials,
+    pub(crate) deletions: DeletionQueue,
+    retired_slots: Vec<Vec<(u32, bool)>>, // Bindless slots of retired textures and whether they're arrays, per frame slot like deletions
+    pub(crate) morph_layout: vk::DescriptorSetLayout, // Set 3 of the scene pipelines
+    pub(crate) no_morph: MorphTargets // Bound for meshes without morph targets
+}
+
+impl ResourceManager {
+    // Creates the builtin textures, the default material and the empty morph target set
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                      frames_in_flight: usize) -> Result<ResourceManager, RendererError> {
+        let mut textures = Textures::new(logical_layer, allocator, upload)?;
+        let mut materials = Materials::new();
+        materials.create(&mut textures, &MaterialDesc::default()); // MaterialHandle::DEFAULT
+
+        let morph_layout = match morph::create_set_layout(logical_layer) {
+            Ok(l) => l,
+            Err(e) => {
+                textures.destroy(logical_layer, allocator);
+                return Err(e);
+            }
+        };
+        let no_morph = match MorphTargets::new(logical_layer, allocator, upload, morph_layout, frames_in_flight, 0, &[]) {
+            Ok(m) => m,
+            Err(e) => {
+                unsafe { logical_layer.logical_device.destroy_descriptor_set_layout(morph_layout, None) };
+                textures.destroy(logical_layer, allocator);
+                return Err(e);
+            }
+        };
+
+        Ok(ResourceManager {
+            meshes: Pool::new(),
+            textures,
+            materials,
+            deletions: DeletionQueue::new(frames_in_flight),
+            retired_slots: (0..frames_in_flight).map(|_| Vec::new()).collect(),
+            morph_layout,
+            no_morph
+        })
+    }
+
+    pub(crate) fn add_mesh(&mut self, mesh: Mesh) -> MeshHandle {
+        MeshHandle(self.meshes.insert(mesh))
+    }
+
+    // None once the mesh has been released
+    pub(crate) fn mesh(&self, handle: MeshHandle) -> Option<&Mesh> {
+        self.meshes.get(handle.0)
+    }
+
+    // Swaps the buffers behind a live handle, the old ones are destroyed once frame has finished
+    pub(crate) fn replace_mesh(&mut self, handle: MeshHandle, mesh: Mesh, frame: usize) {
+        let slot = self.meshes.get_mut(handle.0).expect("Replaced a removed mesh");
+        let old = mem::replace(slot, mesh);
+        self.deletions.push(frame, Deletion::Mesh(old));
+    }
+
+    // False if the mesh was released or has no morph targets
+    pub(crate) fn set_morph_weights(&mut self, handle: MeshHandle, weights: &[f32]) -> bool {
+        match self.meshes.get_mut(handle.0).and_then(|m| m.morph.as_mut()) {
+            Some(m) => {
+                m.set_weights(weights);
+                true
+            },
+            None => false
+        }
+    }
+
+    // Call after waiting on the frame slot's timeline value
+    pub(crate) fn write_morph_weights(&mut self, frame: usize) {
+        for m in self.meshes.iter_mut().filter_map(|m| m.morph.as_mut()) {
+            m.write(frame);
+        }
+    }
+
+    pub(crate) fn retain_mesh(&mut self, handle: MeshHandle) -> bool {
+        self.meshes.retain(handle.0)
+    }
+
+    pub(crate) fn release_mesh(&mut self, handle: MeshHandle, frame: usize) {
+        if let Some(mesh) = self.meshes.release(handle.0) {
+            self.deletions.push(frame, Deletion::Mesh(mesh));
+        }
+    }
+
+    // Callers check textures.has_free_slot first
+    pub(crate) fn add_texture(&mut self, logical_layer: &LogicalLayer, texture: Texture) -> TextureHandle {
+        self.textures.add(logical_layer, texture)
+    }
+
+    fn retire_texture(&mut self, texture: Texture, frame: usize) {
+        self.retired_slots[frame].push((texture.slot, texture.array));
+        self.deletions.push(frame, Deletion::Texture(texture));
+    }
+
+    // Swaps the image behind a replaceable handle. It gets a new bindless slot, which materials pick up
+    // the next time they're drawn.
+    pub(crate) fn replace_texture(&mut self, logical_layer: &LogicalLayer, handle: TextureHandle, texture: Texture, frame: usize) {
+        let old = self.textures.replace(logical_layer, handle, texture);
+        self.retire_texture(old, frame);
+    }
+
+    pub(crate) fn retain_texture(&mut self, handle: TextureHandle) -> bool {
+        self.textures.retain(handle)
+    }
+
+    pub(crate) fn release_texture(&mut self, handle: TextureHandle, frame: usize) {
+        if let Some(texture) = self.textures.release(handle) {
+            self.retire_texture(texture, frame);
+        }
+    }
+
+    // Holds a reference to each of the material's textures until the material is released
+    pub(crate) fn create_material(&mut self, desc: &MaterialDesc) -> MaterialHandle {
+        self.materials.create(&mut self.textures, desc)
+    }
+
+    pub(crate) fn retain_material(&mut self, handle: MaterialHandle) -> bool {
+        self.materials.retain(handle)
+    }
+
+    pub(crate) fn release_material(&mut self, handle: MaterialHandle, frame: usize) {
+        if let Some(material) = self.materials.release(handle) {
+            for t in material.textures.iter() {
+                self.release_texture(*t, frame);
+            }
+        }
+    }
+
+    // Pipelines aren't reference counted, but replaced ones are destroyed the same way
+    pub(crate) fn retire_pipeline(&mut self, pipeline: RasterPipeline, frame: usize) {
+        self.deletions.push(frame, Deletion::Pipeline(pipeline));
+    }
+
+    // Call after waiting on the frame slot's timeline value
+    pub(crate) fn collect(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, frame: usize) {
+        self.deletions.flush(logical_layer, allocator, frame);
+        for (slot, array) in self.retired_slots[frame].drain(..) {
+            self.textures.free_slot(slot, array);
+        }
+    }
+
+    // The GPU must be idle
+    pub(crate) fn destroy(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        for frame in 0..self.retired_slots.len() {
+            self.collect(logical_layer, allocator, frame);
+        }
+        for m in self.meshes.iter() {
+            m.destroy(logical_layer, allocator);
+        }
+        self.no_morph.destroy(logical_layer, allocator);
+        unsafe { logical_layer.logical_device.destroy_descriptor_set_layout(self.morph_layout, None) };
+        self.textures.destroy(logical_layer, allocator);
+    }
+}
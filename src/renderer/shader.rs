@@ -0,0 +1,176 @@
+use std::ffi::CString;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use naga::ShaderStage;
+
+#[derive(Clone, Debug)]
+pub enum ShaderSource {
+    SpirvFile(PathBuf), // Precompiled, I.E. with glslc
+    Spirv(Vec<u32>), // Precompiled words already in memory, I.E. from an asset pack. The entry point is main.
+    GlslFile(PathBuf),
+    WgslFile(PathBuf),
+    Glsl(String),
+    Wgsl(String)
+}
+
+// Vertex and fragment stages of a raster pipeline
+#[derive(Clone, Debug)]
+pub struct ShaderSet {
+    pub vertex: ShaderSource,
+    pub fragment: ShaderSource
+}
+
+#[derive(Debug)]
+pub enum ShaderError {
+    Io { path: PathBuf, error: std::io::Error },
+    InvalidSpirv { path: PathBuf }, // Size isn't a multiple of 4
+    Parse { name: String, message: String },
+    Validation { name: String, message: String },
+    Codegen { name: String, message: String },
+    MissingEntryPoint { name: String }
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ShaderError::Io { path, error } => write!(f, "Failed to read {}: {}", path.display(), error),
+            ShaderError::InvalidSpirv { path } => write!(f, "{} is not valid SPIR-V", path.display()),
+            ShaderError::Parse { name, message } => write!(f, "Failed to parse {}:\n{}", name, message),
+            ShaderError::Validation { name, message } => write!(f, "{} failed validation: {}", name, message),
+            ShaderError::Codegen { name, message } => write!(f, "Failed to generate SPIR-V for {}: {}", name, message),
+            ShaderError::MissingEntryPoint { name } => write!(f, "{} has no entry point for its stage", name)
+        }
+    }
+}
+
+impl std::error::Error for ShaderError {}
+
+pub(crate) struct CompiledShader {
+    pub(crate) code: Vec<u32>,
+    pub(crate) entry_point: CString
+}
+
+impl ShaderSet {
+    // GLSL sources compiled at startup, so the .spv files don't have to be kept up to date
+    pub fn default_glsl() -> ShaderSet {
+        ShaderSet {
+            vertex: ShaderSource::GlslFile(PathBuf::from("shaders/src/shader.vert")),
+            fragment: ShaderSource::GlslFile(PathBuf::from("shaders/src/shader.frag"))
+        }
+    }
+
+    // Same vertex stage as default_glsl, Blinn-Phong lit fragments
+    pub fn lit_glsl() -> ShaderSet {
+        ShaderSet {
+            vertex: ShaderSource::GlslFile(PathBuf::from("shaders/src/shader.vert")),
+            fragment: ShaderSource::GlslFile(PathBuf::from("shaders/src/lit.frag"))
+        }
+    }
+
+    // Same vertex stage as default_glsl, metallic-roughness PBR fragments
+    pub fn pbr_glsl() -> ShaderSet {
+        ShaderSet {
+            vertex: ShaderSource::GlslFile(PathBuf::from("shaders/src/shader.vert")),
+            fragment: ShaderSource::GlslFile(PathBuf::from("shaders/src/pbr.frag"))
+        }
+    }
+
+    // Same vertex stage as default_glsl, Blinn-Phong lit splat map blending
+    pub fn terrain_glsl() -> ShaderSet {
+        ShaderSet {
+            vertex: ShaderSource::GlslFile(PathBuf::from("shaders/src/shader.vert")),
+            fragment: ShaderSource::GlslFile(PathBuf::from("shaders/src/terrain.frag"))
+        }
+    }
+
+    // Same vertex stage as default_glsl, Blinn-Phong lit block faces from a texture array
+    pub fn voxel_glsl() -> ShaderSet {
+        ShaderSet {
+            vertex: ShaderSource::GlslFile(PathBuf::from("shaders/src/shader.vert")),
+            fragment: ShaderSource::GlslFile(PathBuf::from("shaders/src/voxel.frag"))
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, ShaderError> {
+    let mut buf = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut buf))
+        .map_err(|error| ShaderError::Io { path: path.to_path_buf(), error })?;
+
+    Ok(buf)
+}
+
+fn read_string(path: &Path) -> Result<String, ShaderError> {
+    std::fs::read_to_string(path).map_err(|error| ShaderError::Io { path: path.to_path_buf(), error })
+}
+
+// Stages naga can't compile, I.E. ray tracing, are only ever loaded this way. The entry point is main.
+pub(crate) fn read_spirv(path: &Path) -> Result<CompiledShader, ShaderError> {
+    let bytes = read_file(path)?;
+    if bytes.len() % 4 != 0 {
+        return Err(ShaderError::InvalidSpirv { path: path.to_path_buf() });
+    }
+
+    Ok(CompiledShader {
+        code: bytes.chunks_exact(4).map(|w| u32::from_ne_bytes([w[0], w[1], w[2], w[3]])).collect(),
+        entry_point: CString::new("main").unwrap()
+    })
+}
+
+pub(crate) fn compile(source: &ShaderSource, stage: ShaderStage) -> Result<CompiledShader, ShaderError> {
+    match source {
+        ShaderSource::SpirvFile(path) => read_spirv(path),
+        ShaderSource::Spirv(code) => Ok(CompiledShader {
+            code: code.clone(),
+            entry_point: CString::new("main").unwrap()
+        }),
+        ShaderSource::GlslFile(path) => compile_glsl(&read_string(path)?, &path.display().to_string(), stage),
+        ShaderSource::WgslFile(path) => compile_wgsl(&read_string(path)?, &path.display().to_string(), stage),
+        ShaderSource::Glsl(src) => compile_glsl(src, "<inline glsl>", stage),
+        ShaderSource::Wgsl(src) => compile_wgsl(src, "<inline wgsl>", stage)
+    }
+}
+
+fn compile_glsl(src: &str, name: &str, stage: ShaderStage) -> Result<CompiledShader, ShaderError> {
+    let mut parser = naga::front::glsl::Parser::default();
+    let module = parser
+        .parse(&naga::front::glsl::Options::from(stage), src)
+        .map_err(|errors| ShaderError::Parse {
+            name: name.to_owned(),
+            message: errors.iter().map(|e| e.to_string()).collect::<Vec<String>>().join("\n")
+        })?;
+
+    write_spirv(&module, name, stage)
+}
+
+fn compile_wgsl(src: &str, name: &str, stage: ShaderStage) -> Result<CompiledShader, ShaderError> {
+    let module = naga::front::wgsl::parse_str(src).map_err(|e| ShaderError::Parse {
+        name: name.to_owned(),
+        message: e.emit_to_string(src)
+    })?;
+
+    write_spirv(&module, name, stage)
+}
+
+fn write_spirv(module: &naga::Module, name: &str, stage: ShaderStage) -> Result<CompiledShader, ShaderError> {
+    let info = naga::valid::Validator::new(naga::valid::ValidationFlags::all(), naga::valid::Capabilities::all())
+        .validate(module)
+        .map_err(|e| ShaderError::Validation { name: name.to_owned(), message: e.to_string() })?;
+
+    let entry_point = module.entry_points
+        .iter()
+        .find(|e| e.stage == stage)
+        .ok_or(ShaderError::MissingEntryPoint { name: name.to_owned() })?;
+
+    let code = naga::back::spv::write_vec(module, &info, &naga::back::spv::Options::default(), None)
+        .map_err(|e| ShaderError::Codegen { name: name.to_owned(), message: e.to_string() })?;
+
+    Ok(CompiledShader {
+        code,
+        entry_point: CString::new(entry_point.name.as_str()).unwrap()
+    })
+}
@@ -0,0 +1,58 @@
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver};
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::renderer::raster_pipeline::{SHADER_SPV_DIR, SHADER_SRC_DIR};
+
+// Watches the shader directories so pipelines can be rebuilt when a shader changes on disk
+pub(crate) struct ShaderWatcher {
+    _watcher: RecommendedWatcher, // Stops watching when dropped
+    events: Receiver<notify::Result<notify::Event>>
+}
+
+impl ShaderWatcher {
+    pub(crate) fn new() -> Result<ShaderWatcher, String> {
+        let (tx, events) = channel();
+
+        let mut watcher = notify::recommended_watcher(move |res| {
+            let _ = tx.send(res); // The receiver only goes away with the watcher
+        }).map_err(|e| e.to_string())?;
+
+        for dir in [SHADER_SRC_DIR, SHADER_SPV_DIR] {
+            watcher.watch(Path::new(dir), RecursiveMode::NonRecursive).map_err(|e| e.to_string())?;
+        }
+
+        Ok(ShaderWatcher {
+            _watcher: watcher,
+            events
+        })
+    }
+
+    // Returns true if a shader changed since the last poll and the pipelines should be rebuilt
+    pub(crate) fn poll(&self) -> bool {
+        let mut changed = false;
+
+        for res in self.events.try_iter() {
+            let event = match res {
+                Ok(e) => e,
+                Err(e) => {
+                    log::warn!("Shader watcher error: {}", e);
+                    continue
+                }
+            };
+
+            if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                continue;
+            }
+
+            // Editors write temporary files next to the real one, ignore those
+            changed |= event.paths
+                .iter()
+                .any(|p| matches!(p.extension().and_then(|e| e.to_str()),
+                                  Some("spv") | Some("vert") | Some("frag") | Some("wgsl")));
+        }
+
+        changed
+    }
+}
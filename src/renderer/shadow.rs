@@ -0,0 +1,525 @@
+use std::mem;
+use std::path::PathBuf;
+
+use ash::vk;
+use glam::{Mat4, Vec3};
+use naga::ShaderStage;
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::barrier::{first_mip, Barriers, Ownership, Usage};
+use crate::renderer::core::Core;
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::physical_layer::PhysicalLayer;
+use crate::renderer::shader::{compile, ShaderSource};
+use crate::renderer::vertex::VertexLayout;
+
+pub const MAX_SHADOW_CASTERS: usize = 4; // Matches MAX_SHADOW_CASTERS in the shaders, one array layer each
+
+const SHADOW_SHADER: &str = "shaders/src/shadow.vert";
+const CASTER_DEPTH: f32 = 2.0; // How far towards the light casters are captured, in multiples of the shadow distance
+
+// Directional light shadows. Every shadow casting light renders the draws into its own layer of a
+// depth array image before the main pass, which the lit shaders then sample with a comparison sampler.
+// Descriptor set 2 of every pipeline holds the array and the sampler.
+pub(crate) struct ShadowMaps {
+    pub(crate) resolution: u32,
+    format: vk::Format,
+    render_pass: vk::RenderPass,
+    pipeline_layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    image: vk::Image,
+    alloc: Allocation,
+    array_view: vk::ImageView, // Every layer, sampled by the lit shaders
+    layer_views: Vec<vk::ImageView>, // One per framebuffer
+    framebuffers: Vec<vk::Framebuffer>,
+    sampler: vk::Sampler,
+    pub(crate) set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    pub(crate) descriptor_set: vk::DescriptorSet,
+    needs_transition: bool // Layers start out UNDEFINED but the descriptor expects them readable
+}
+
+impl ShadowMaps {
+    pub(crate) fn new(core: &Core, physical_layer: &PhysicalLayer, logical_layer: &LogicalLayer, allocator: &Allocator,
+                      frame_set_layout: vk::DescriptorSetLayout, vertex_layouts: &[VertexLayout],
+                      resolution: u32) -> Result<ShadowMaps, RendererError> {
+        fn choose_format(core: &Core, physical_layer: &PhysicalLayer) -> Result<vk::Format, RendererError> {
+            // D16_UNORM is guaranteed to support both uses
+            let candidates = [vk::Format::D32_SFLOAT, vk::Format::D16_UNORM];
+            let required = vk::FormatFeatureFlags::DEPTH_STENCIL_ATTACHMENT | vk::FormatFeatureFlags::SAMPLED_IMAGE;
+
+            candidates.iter()
+                .copied()
+                .find(|&f| physical_layer.supports_format(core, f, required))
+                .ok_or(RendererError::NoSuitableFormat("shadow map"))
+        }
+
+        fn setup_render_pass(logical_layer: &LogicalLayer, format: vk::Format) -> Result<vk::RenderPass, RendererError> {
+            let attachments = [vk::AttachmentDescription::default()
+                .format(format)
+                .samples(vk::SampleCountFlags::TYPE_1)
+                .load_op(vk::AttachmentLoadOp::CLEAR)
+                .store_op(vk::AttachmentStoreOp::STORE) // Read by the main pass
+                .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
+                .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
+                .initial_layout(vk::ImageLayout::UNDEFINED) // Cleared anyway
+                .final_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)];
+
+            let depth_ref = vk::AttachmentReference::default()
+                .attachment(0)
+                .layout(vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL);
+
+            let subpasses = [vk::SubpassDescription::default()
+                .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+                .depth_stencil_attachment(&depth_ref)]; // No color attachments
+
+            let dependencies = [
+                // The previous frame's main pass has to finish sampling before the layer is overwritten
+                vk::SubpassDependency::default()
+                    .src_subpass(vk::SUBPASS_EXTERNAL)
+                    .dst_subpass(0)
+                    .src_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                    .src_access_mask(vk::AccessFlags::SHADER_READ)
+                    .dst_stage_mask(vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS)
+                    .dst_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE),
+                // And this frame's main pass waits for the depth writes
+                vk::SubpassDependency::default()
+                    .src_subpass(0)
+                    .dst_subpass(vk::SUBPASS_EXTERNAL)
+                    .src_stage_mask(vk::PipelineStageFlags::LATE_FRAGMENT_TESTS)
+                    .src_access_mask(vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE)
+                    .dst_stage_mask(vk::PipelineStageFlags::FRAGMENT_SHADER)
+                    .dst_access_mask(vk::AccessFlags::SHADER_READ)
+            ];
+
+            let create_info = vk::RenderPassCreateInfo::default()
+                .attachments(&attachments)
+                .subpasses(&subpasses)
+                .dependencies(&dependencies);
+
+            unsafe {
+                logical_layer.logical_device.create_render_pass(&create_info, None).map_err(vk_error("vkCreateRenderPass"))
+            }
+        }
+
+        fn setup_sampler(logical_layer: &LogicalLayer) -> Result<vk::Sampler, RendererError> {
+            let create_info = vk::SamplerCreateInfo::default()
+                .mag_filter(vk::Filter::LINEAR) // Filters the comparison results, smoothing the PCF taps further
+                .min_filter(vk::Filter::LINEAR)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_BORDER)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .border_color(vk::BorderColor::FLOAT_OPAQUE_WHITE) // Outside the map is the far plane, so unshadowed
+                .compare_enable(true)
+                .compare_op(vk::CompareOp::LESS_OR_EQUAL) // Lit when the fragment is no further than the caster
+                .max_lod(0.0);
+
+            unsafe { logical_layer.logical_device.create_sampler(&create_info, None).map_err(vk_error("vkCreateSampler")) }
+        }
+
+        fn setup_descriptors(logical_layer: &LogicalLayer) -> Result<(vk::DescriptorSetLayout, vk::DescriptorPool, vk::DescriptorSet), RendererError> {
+            let bindings = [
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(0) // Depth array
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(1) // Comparison sampler
+                    .descriptor_type(vk::DescriptorType::SAMPLER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            ];
+            let layout_create_info = vk::DescriptorSetLayoutCreateInfo::default()
+                .bindings(&bindings);
+            let set_layout = unsafe {
+                logical_layer.logical_device.create_descriptor_set_layout(&layout_create_info, None)
+                    .map_err(vk_error("vkCreateDescriptorSetLayout"))?
+            };
+
+            let pool_sizes = [
+                vk::DescriptorPoolSize::default()
+                    .ty(vk::DescriptorType::SAMPLED_IMAGE)
+                    .descriptor_count(1),
+                vk::DescriptorPoolSize::default()
+                    .ty(vk::DescriptorType::SAMPLER)
+                    .descriptor_count(1)
+            ];
+            let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+                .pool_sizes(&pool_sizes)
+                .max_sets(1);
+            let pool = unsafe {
+                logical_layer.logical_device.create_descriptor_pool(&pool_create_info, None)
+                    .map_err(vk_error("vkCreateDescriptorPool"))?
+            };
+
+            let layouts = [set_layout];
+            let alloc_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(pool)
+                .set_layouts(&layouts);
+            let sets = unsafe {
+                logical_layer.logical_device.allocate_descriptor_sets(&alloc_info).map_err(vk_error("vkAllocateDescriptorSets"))?
+            };
+
+            Ok((set_layout, pool, sets[0]))
+        }
+
+        fn setup_pipeline(logical_layer: &LogicalLayer, render_pass: vk::RenderPass, frame_set_layout: vk::DescriptorSetLayout,
+                          vertex_layouts: &[VertexLayout]) -> Result<(vk::PipelineLayout, vk::Pipeline), RendererError> {
+            let compiled = compile(&ShaderSource::GlslFile(PathBuf::from(SHADOW_SHADER)), ShaderStage::Vertex)?;
+            let module_create_info = vk::ShaderModuleCreateInfo::default()
+                .code(&compiled.code);
+            let module = unsafe {
+                logical_layer.logical_device.create_shader_module(&module_create_info, None)
+                    .map_err(vk_error("vkCreateShaderModule"))?
+            };
+            let stages = [vk::PipelineShaderStageCreateInfo::default() // Depth only, so no fragment stage
+                .name(compiled.entry_point.as_c_str())
+                .stage(vk::ShaderStageFlags::VERTEX)
+                .module(module)];
+
+            let vertex_binding_descriptions: Vec<vk::VertexInputBindingDescription> = vertex_layouts
+                .iter()
+                .enumerate()
+                .map(|(i, l)| l.binding_description(i as u32))
+                .collect();
+            let vertex_attribute_descriptions: Vec<vk::VertexInputAttributeDescription> = vertex_layouts
+                .iter()
+                .enumerate()
+                .flat_map(|(i, l)| l.attribute_descriptions(i as u32))
+                .collect();
+            let vertex_inputs = vk::PipelineVertexInputStateCreateInfo::default()
+                .vertex_attribute_descriptions(&vertex_attribute_descriptions)
+                .vertex_binding_descriptions(&vertex_binding_descriptions);
+
+            let input_assembly = vk::PipelineInputAssemblyStateCreateInfo::default()
+                .topology(vk::PrimitiveTopology::TRIANGLE_LIST);
+
+            let viewport_state = vk::PipelineViewportStateCreateInfo::default()
+                .viewport_count(1)
+                .scissor_count(1);
+
+            let rasterization_state = vk::PipelineRasterizationStateCreateInfo::default()
+                .polygon_mode(vk::PolygonMode::FILL)
+                .line_width(1.0)
+                .cull_mode(vk::CullModeFlags::NONE) // Single sided geometry still casts, and the light's projection isn't Y flipped
+                .front_face(vk::FrontFace::COUNTER_CLOCKWISE)
+                .depth_bias_enable(true) // Pushes the stored depth back against shadow acne
+                .depth_bias_constant_factor(1.25)
+                .depth_bias_clamp(0.0)
+                .depth_bias_slope_factor(1.75);
+
+            let multisample_state = vk::PipelineMultisampleStateCreateInfo::default()
+                .rasterization_samples(vk::SampleCountFlags::TYPE_1);
+
+            let depth_stencil_state = vk::PipelineDepthStencilStateCreateInfo::default()
+                .depth_test_enable(true)
+                .depth_write_enable(true)
+                .depth_compare_op(vk::CompareOp::LESS_OR_EQUAL);
+
+            let color_blend_state = vk::PipelineColorBlendStateCreateInfo::default(); // No attachments
+
+            let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+            let dynamic_state = vk::PipelineDynamicStateCreateInfo::default()
+                .dynamic_states(&dynamic_states);
+
+            let push_constant_ranges = [vk::PushConstantRange::default()
+                .stage_flags(vk::ShaderStageFlags::VERTEX)
+                .offset(0)
+                .size((mem::size_of::<Mat4>() + mem::size_of::<u32>()) as u32)]; // Model matrix then the layer
+            let set_layouts = [frame_set_layout]; // For the light matrices
+            let layout_create_info = vk::PipelineLayoutCreateInfo::default()
+                .set_layouts(&set_layouts)
+                .push_constant_ranges(&push_constant_ranges);
+            let pipeline_layout = match unsafe { logical_layer.logical_device.create_pipeline_layout(&layout_create_info, None) } {
+                Ok(l) => l,
+                Err(e) => {
+                    unsafe { logical_layer.logical_device.destroy_shader_module(module, None) };
+                    return Err(vk_error("vkCreatePipelineLayout")(e));
+                }
+            };
+
+            let pipeline_info = vk::GraphicsPipelineCreateInfo::default()
+                .stages(&stages)
+                .vertex_input_state(&vertex_inputs)
+                .input_assembly_state(&input_assembly)
+                .viewport_state(&viewport_state)
+                .rasterization_state(&rasterization_state)
+                .multisample_state(&multisample_state)
+                .depth_stencil_state(&depth_stencil_state)
+                .color_blend_state(&color_blend_state)
+                .dynamic_state(&dynamic_state)
+                .layout(pipeline_layout)
+                .render_pass(render_pass)
+                .subpass(0);
+            let result = unsafe {
+                logical_layer.logical_device.create_graphics_pipelines(vk::PipelineCache::null(), &[pipeline_info], None)
+            };
+            unsafe { logical_layer.logical_device.destroy_shader_module(module, None) };
+
+            match result {
+                Ok(pipelines) => Ok((pipeline_layout, pipelines[0])),
+                Err((_, e)) => {
+                    unsafe { logical_layer.logical_device.destroy_pipeline_layout(pipeline_layout, None) };
+                    Err(vk_error("vkCreateGraphicsPipelines")(e))
+                }
+            }
+        }
+
+        let format = choose_format(core, physical_layer)?;
+        let render_pass = setup_render_pass(logical_layer, format)?;
+        let (pipeline_layout, pipeline) = setup_pipeline(logical_layer, render_pass, frame_set_layout, vertex_layouts)?;
+        let sampler = setup_sampler(logical_layer)?;
+        let (set_layout, descriptor_pool, descriptor_set) = setup_descriptors(logical_layer)?;
+        let (image, alloc, array_view, layer_views, framebuffers) =
+            Self::setup_images(logical_layer, allocator, render_pass, format, resolution)?;
+
+        let shadow_maps = ShadowMaps {
+            resolution,
+            format,
+            render_pass,
+            pipeline_layout,
+            pipeline,
+            image,
+            alloc,
+            array_view,
+            layer_views,
+            framebuffers,
+            sampler,
+            set_layout,
+            descriptor_pool,
+            descriptor_set,
+            needs_transition: true
+        };
+        shadow_maps.write_descriptors(logical_layer);
+
+        Ok(shadow_maps)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn setup_images(logical_layer: &LogicalLayer, allocator: &Allocator, render_pass: vk::RenderPass, format: vk::Format,
+                    resolution: u32) -> Result<(vk::Image, Allocation, vk::ImageView, Vec<vk::ImageView>, Vec<vk::Framebuffer>), RendererError> {
+        let create_info = vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(vk::Extent3D { width: resolution, height: resolution, depth: 1 })
+            .mip_levels(1)
+            .array_layers(MAX_SHADOW_CASTERS as u32)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let (alloc, image) = allocator.create_image(logical_layer, &create_info, vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                    MemoryCategory::RenderTargets)?;
+
+        let create_view = |view_type: vk::ImageViewType, base_array_layer: u32, layer_count: u32| {
+            let view_create_info = vk::ImageViewCreateInfo::default()
+                .image(image)
+                .view_type(view_type)
+                .format(format)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::DEPTH,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer,
+                    layer_count
+                });
+            unsafe { logical_layer.logical_device.create_image_view(&view_create_info, None).map_err(vk_error("vkCreateImageView")) }
+        };
+
+        let mut views: Vec<vk::ImageView> = Vec::with_capacity(MAX_SHADOW_CASTERS + 1);
+        let mut framebuffers: Vec<vk::Framebuffer> = Vec::with_capacity(MAX_SHADOW_CASTERS);
+        let result: Result<(), RendererError> = (|| {
+            views.push(create_view(vk::ImageViewType::TYPE_2D_ARRAY, 0, MAX_SHADOW_CASTERS as u32)?);
+            for layer in 0..MAX_SHADOW_CASTERS as u32 {
+                let view = create_view(vk::ImageViewType::TYPE_2D, layer, 1)?;
+                views.push(view);
+
+                let attachments = [view];
+                let framebuffer_create_info = vk::FramebufferCreateInfo::default()
+                    .render_pass(render_pass)
+                    .attachments(&attachments)
+                    .width(resolution)
+                    .height(resolution)
+                    .layers(1);
+                framebuffers.push(unsafe {
+                    logical_layer.logical_device.create_framebuffer(&framebuffer_create_info, None)
+                        .map_err(vk_error("vkCreateFramebuffer"))?
+                });
+            }
+            Ok(())
+        })();
+
+        if let Err(e) = result {
+            unsafe {
+                for f in framebuffers.iter() {
+                    logical_layer.logical_device.destroy_framebuffer(*f, None);
+                }
+                for v in views.iter() {
+                    logical_layer.logical_device.destroy_image_view(*v, None);
+                }
+            }
+            allocator.destroy_image(logical_layer, image, &alloc);
+            return Err(e);
+        }
+
+        let array_view = views.remove(0);
+        Ok((image, alloc, array_view, views, framebuffers))
+    }
+
+    fn write_descriptors(&self, logical_layer: &LogicalLayer) {
+        let image_infos = [vk::DescriptorImageInfo::default()
+            .image_view(self.array_view)
+            .image_layout(vk::ImageLayout::DEPTH_STENCIL_READ_ONLY_OPTIMAL)];
+        let sampler_infos = [vk::DescriptorImageInfo::default()
+            .sampler(self.sampler)];
+        let writes = [
+            vk::WriteDescriptorSet::default()
+                .dst_set(self.descriptor_set)
+                .dst_binding(0)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .image_info(&image_infos),
+            vk::WriteDescriptorSet::default()
+                .dst_set(self.descriptor_set)
+                .dst_binding(1)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .image_info(&sampler_infos)
+        ];
+
+        unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+    }
+
+    fn destroy_images(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        unsafe {
+            for f in self.framebuffers.iter() {
+                logical_layer.logical_device.destroy_framebuffer(*f, None);
+            }
+            for v in self.layer_views.iter() {
+                logical_layer.logical_device.destroy_image_view(*v, None);
+            }
+            logical_layer.logical_device.destroy_image_view(self.array_view, None);
+        }
+        allocator.destroy_image(logical_layer, self.image, &self.alloc);
+    }
+
+    // Recreates the depth array at a new size. The caller has to make sure the GPU is idle, since the
+    // descriptor set is rewritten in place.
+    pub(crate) fn resize(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, resolution: u32) -> Result<(), RendererError> {
+        let (image, alloc, array_view, layer_views, framebuffers) =
+            Self::setup_images(logical_layer, allocator, self.render_pass, self.format, resolution)?;
+        self.destroy_images(logical_layer, allocator);
+
+        self.resolution = resolution;
+        self.image = image;
+        self.alloc = alloc;
+        self.array_view = array_view;
+        self.layer_views = layer_views;
+        self.framebuffers = framebuffers;
+        self.needs_transition = true;
+        self.write_descriptors(logical_layer);
+
+        Ok(())
+    }
+
+    // Moves every layer into the layout the descriptor expects, so layers of lights that haven't cast
+    // shadows yet are still valid to bind. Only records anything after creation or a resize.
+    pub(crate) fn prepare(&mut self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer) {
+        if !self.needs_transition {
+            return;
+        }
+        self.needs_transition = false;
+
+        Barriers::new()
+            .image(self.image, first_mip(vk::ImageAspectFlags::DEPTH, MAX_SHADOW_CASTERS as u32), Usage::Undefined,
+                   Usage::DepthSampled, Ownership::Keep)
+            .record(logical_layer, command_buffer);
+    }
+
+    // Starts rendering into a layer with the shadow pipeline and set 0 bound. Draws then only need
+    // push_model and the mesh's buffers.
+    pub(crate) fn begin(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, layer: usize,
+                        frame_set: vk::DescriptorSet) {
+        let extent = vk::Extent2D { width: self.resolution, height: self.resolution };
+        let clear_values = [vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: 1.0,
+                stencil: 0
+            }
+        }];
+        let render_pass_info = vk::RenderPassBeginInfo::default()
+            .render_pass(self.render_pass)
+            .framebuffer(self.framebuffers[layer])
+            .render_area(vk::Rect2D::default().extent(extent))
+            .clear_values(&clear_values);
+        let viewports = [vk::Viewport::default()
+            .width(self.resolution as f32)
+            .height(self.resolution as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)];
+        let scissors = [vk::Rect2D::default().extent(extent)];
+        let descriptor_sets = [frame_set];
+
+        unsafe {
+            let device = &logical_layer.logical_device;
+            device.cmd_begin_render_pass(command_buffer, &render_pass_info, vk::SubpassContents::INLINE);
+            device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline);
+            device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline_layout,
+                                            0, &descriptor_sets, &[]);
+            device.cmd_set_viewport(command_buffer, 0, &viewports);
+            device.cmd_set_scissor(command_buffer, 0, &scissors);
+            device.cmd_push_constants(command_buffer, self.pipeline_layout, vk::ShaderStageFlags::VERTEX,
+                                      mem::size_of::<Mat4>() as u32, bytemuck::bytes_of(&(layer as u32)));
+        }
+    }
+
+    pub(crate) fn push_model(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, model: &Mat4) {
+        unsafe {
+            logical_layer.logical_device.cmd_push_constants(command_buffer, self.pipeline_layout, vk::ShaderStageFlags::VERTEX,
+                                                            0, bytemuck::bytes_of(model));
+        }
+    }
+
+    pub(crate) fn end(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer) {
+        unsafe { logical_layer.logical_device.cmd_end_render_pass(command_buffer) };
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        self.destroy_images(logical_layer, allocator);
+        unsafe {
+            logical_layer.logical_device.destroy_pipeline(self.pipeline, None);
+            logical_layer.logical_device.destroy_pipeline_layout(self.pipeline_layout, None);
+            logical_layer.logical_device.destroy_render_pass(self.render_pass, None);
+            logical_layer.logical_device.destroy_sampler(self.sampler, None);
+            logical_layer.logical_device.destroy_descriptor_pool(self.descriptor_pool, None); // Frees the set as well
+            logical_layer.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
+
+// Orthographic view-projection of a directional light covering a sphere of radius around center, plus
+// anything up to CASTER_DEPTH radii further towards the light that could cast into it. The center is
+// snapped to whole shadow map texels so shadow edges don't crawl as the camera moves.
+pub(crate) fn directional_view_proj(direction: Vec3, center: Vec3, radius: f32, resolution: u32) -> Mat4 {
+    let direction = direction.normalize_or_zero();
+    let up = match direction.dot(Vec3::Y).abs() > 0.99 {
+        true => Vec3::Z, // Straight up or down, look_at needs an up vector that isn't parallel
+        false => Vec3::Y
+    };
+    let view = Mat4::look_at_rh(Vec3::ZERO, direction, up); // Rotation only, the center is applied through the bounds
+
+    let texel = 2.0 * radius / resolution as f32;
+    let light_center = view.transform_point3(center);
+    let x = (light_center.x / texel).floor() * texel;
+    let y = (light_center.y / texel).floor() * texel;
+    let distance = -light_center.z; // Right handed views look down -Z
+
+    let proj = Mat4::orthographic_rh(x - radius, x + radius,
+                                     y - radius, y + radius,
+                                     distance - radius * CASTER_DEPTH, distance + radius);
+
+    proj * view
+}
@@ -0,0 +1,197 @@
+use std::f32::consts::PI;
+use std::mem;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use glam::{Mat4, Vec3};
+
+use crate::renderer::error::RendererError;
+use crate::renderer::fog::Fog;
+use crate::renderer::light::Light;
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::raster_pipeline::{RasterPipeline, RasterState};
+use crate::renderer::render_pass::PassTarget;
+use crate::renderer::shader::{ShaderSet, ShaderSource};
+
+// What the sky pass draws behind the scene, I.E. from TimeOfDay::sky
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sky {
+    pub sun_direction: Vec3, // Towards the sun, below the horizon at night
+    pub moon_direction: Vec3,
+    pub sun_intensity: f32, // Of the light the air scatters, 20 gives a midday sky around 1 to 2 before tone mapping
+    pub moon_intensity: f32,
+    pub haze: f32, // Scales Mie scattering, the bright glow around the sun. 1 is a clear day.
+    pub stars: f32 // Brightness of the stars, 0 hides them
+}
+
+impl Default for Sky {
+    fn default() -> Self {
+        TimeOfDay::new(12.0).sky()
+    }
+}
+
+// Matches the PushConstants block in sky.frag
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct SkyConstants {
+    inv_view_proj: Mat4, // Fragments are unprojected into view rays
+    sun_direction: [f32; 4], // W is the intensity
+    moon_direction: [f32; 4],
+    params: [f32; 4], // X is the haze, Y the star brightness
+    fog: [f32; 4] // W is how much it covers the horizon, 0 without fog
+}
+
+// A fullscreen triangle at the far plane, drawn after the opaque geometry so it only shades the pixels
+// nothing else covered
+pub(crate) struct SkyPass {
+    pipeline: RasterPipeline
+}
+
+impl SkyPass {
+    pub(crate) fn new(logical_layer: &LogicalLayer, scene_target: PassTarget) -> Result<SkyPass, RendererError> {
+        let shaders = ShaderSet {
+            vertex: ShaderSource::GlslFile(PathBuf::from("shaders/src/sky.vert")),
+            fragment: ShaderSource::GlslFile(PathBuf::from("shaders/src/sky.frag"))
+        };
+        let push_constant_range = vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .offset(0)
+            .size(mem::size_of::<SkyConstants>() as u32);
+        let state = RasterState {
+            cull_mode: vk::CullModeFlags::NONE,
+            depth_write: false,
+            depth_compare: vk::CompareOp::LESS_OR_EQUAL, // Passes where the depth is still the cleared far plane
+            ..RasterState::default()
+        };
+        let pipeline = RasterPipeline::with_state(logical_layer, scene_target, &shaders, &[], &[], Some(push_constant_range), state)?;
+
+        Ok(SkyPass {
+            pipeline
+        })
+    }
+
+    // Binds its own pipeline, whatever was bound before has to be bound again afterwards
+    pub(crate) fn record(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, sky: &Sky, fog: Option<&Fog>,
+                         inv_view_proj: &Mat4) {
+        unsafe {
+            logical_layer.logical_device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, self.pipeline.pipelines[0]);
+        }
+        self.pipeline.push_constants(logical_layer, command_buffer, 0, &SkyConstants {
+            inv_view_proj: *inv_view_proj,
+            sun_direction: sky.sun_direction.normalize_or_zero().extend(sky.sun_intensity).to_array(),
+            moon_direction: sky.moon_direction.normalize_or_zero().extend(sky.moon_intensity).to_array(),
+            params: [sky.haze, sky.stars, 0.0, 0.0],
+            fog: fog.map(|f| f.color.extend(f.sky.clamp(0.0, 1.0)).to_array()).unwrap_or([0.0; 4])
+        });
+        unsafe { logical_layer.logical_device.cmd_draw(command_buffer, 3, 1, 0, 0) }; // Generated from the vertex index
+    }
+
+    pub(crate) fn destroy(&mut self, logical_layer: &LogicalLayer) {
+        self.pipeline.destroy(logical_layer);
+    }
+}
+
+// A day clock driving the sun and moon. The sun rises in +X at 6, is highest at 12 and sets in -X at 18,
+// the moon is always opposite it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimeOfDay {
+    pub hours: f32, // 0 to 24
+    pub speed: f32, // Hours that pass per second, I.E. 0.04 for a 10 minute day
+    pub paused: bool,
+    pub tilt: f32 // Radians the sun's path leans towards -Z, so it isn't straight overhead at noon
+}
+
+impl TimeOfDay {
+    pub fn new(hours: f32) -> TimeOfDay {
+        TimeOfDay {
+            hours: hours.rem_euclid(24.0),
+            speed: 0.04,
+            paused: false,
+            tilt: 0.35
+        }
+    }
+
+    // Call once per tick with the tick length
+    pub fn advance(&mut self, dt: Duration) {
+        if !self.paused {
+            self.hours = (self.hours + dt.as_secs_f32() * self.speed).rem_euclid(24.0);
+        }
+    }
+
+    pub fn sun_direction(&self) -> Vec3 {
+        let angle = (self.hours - 6.0) / 12.0 * PI; // 0 at sunrise, PI at sunset
+        let (height, across) = angle.sin_cos();
+        Vec3::new(across, height * self.tilt.cos(), -height * self.tilt.sin())
+    }
+
+    pub fn moon_direction(&self) -> Vec3 {
+        -self.sun_direction()
+    }
+
+    // 0 at night to 1 in full day, easing through dawn and dusk
+    pub fn daylight(&self) -> f32 {
+        smoothstep(-0.1, 0.25, self.sun_direction().y)
+    }
+
+    pub fn sky(&self) -> Sky {
+        Sky {
+            sun_direction: self.sun_direction(),
+            moon_direction: self.moon_direction(),
+            sun_intensity: 20.0,
+            moon_intensity: 0.6,
+            haze: 1.0,
+            stars: 1.0 - self.daylight()
+        }
+    }
+
+    // The sun while it's up and the moon otherwise, casting shadows. Both are faded out around the horizon,
+    // so the shadows don't jump when one takes over from the other.
+    pub fn light(&self) -> Light {
+        let sun = self.sun_direction();
+        match sun.y > 0.0 {
+            true => {
+                let warmth = smoothstep(0.0, 0.4, sun.y); // Reddened near the horizon, like the sky
+                Light::Directional {
+                    direction: -sun,
+                    color: Vec3::new(1.0, 0.55, 0.3).lerp(Vec3::new(1.0, 0.95, 0.85), warmth),
+                    intensity: smoothstep(0.0, 0.15, sun.y),
+                    cast_shadows: true
+                }
+            },
+            false => Light::Directional {
+                direction: sun, // From the moon
+                color: Vec3::new(0.55, 0.65, 0.9),
+                intensity: smoothstep(0.0, 0.15, -sun.y) * 0.15,
+                cast_shadows: true
+            }
+        }
+    }
+
+    // Roughly the sky's color at the horizon, for Fog::color
+    pub fn fog_color(&self) -> Vec3 {
+        let sun = self.sun_direction();
+        let day = Vec3::new(0.9, 0.55, 0.35).lerp(Vec3::new(0.7, 0.8, 0.95), smoothstep(0.0, 0.3, sun.y)); // Red at dusk
+        Vec3::new(0.01, 0.012, 0.02).lerp(day, self.daylight())
+    }
+
+    // Sky and ground colors for CubulousRenderer::set_ambient_hemisphere
+    pub fn ambient(&self) -> (Vec3, Vec3) {
+        let daylight = self.daylight();
+        let sky = Vec3::new(0.02, 0.025, 0.05).lerp(Vec3::new(0.35, 0.45, 0.6), daylight);
+        let ground = Vec3::new(0.01, 0.01, 0.015).lerp(Vec3::new(0.2, 0.18, 0.15), daylight);
+        (sky, ground)
+    }
+}
+
+impl Default for TimeOfDay {
+    fn default() -> Self {
+        TimeOfDay::new(12.0)
+    }
+}
+
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    let t = ((x - edge0) / (edge1 - edge0)).clamp(0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
@@ -0,0 +1,166 @@
+use glam::{Mat2, Vec2, Vec4};
+
+use crate::renderer::overlay::OverlayVertex;
+use crate::renderer::texture::TextureHandle;
+
+// Part of a texture in UV coordinates, I.E. one frame of a sprite sheet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UvRect {
+    pub min: Vec2,
+    pub max: Vec2
+}
+
+impl UvRect {
+    pub const FULL: UvRect = UvRect { min: Vec2::ZERO, max: Vec2::ONE };
+
+    // A region given in pixels of an atlas that's atlas_size pixels big
+    pub fn from_pixels(position: Vec2, size: Vec2, atlas_size: Vec2) -> UvRect {
+        UvRect {
+            min: position / atlas_size,
+            max: (position + size) / atlas_size
+        }
+    }
+
+    // Maps a UV across the whole texture into the rect, I.E. for a mesh textured from an atlas region
+    pub fn remap(&self, uv: Vec2) -> Vec2 {
+        self.min + (self.max - self.min) * uv
+    }
+
+    // Swapping min and max mirrors the sprite
+    pub fn flipped(self, x: bool, y: bool) -> UvRect {
+        let mut flipped = self;
+        if x {
+            flipped.min.x = self.max.x;
+            flipped.max.x = self.min.x;
+        }
+        if y {
+            flipped.min.y = self.max.y;
+            flipped.max.y = self.min.y;
+        }
+        flipped
+    }
+}
+
+// A textured quad in screen space. Positions and sizes are in pixels from the top left corner of the
+// window.
+#[derive(Clone, Copy, Debug)]
+pub struct Sprite {
+    pub texture: TextureHandle,
+    pub position: Vec2, // Where origin ends up on screen
+    pub size: Vec2,
+    pub origin: Vec2, // Pivot for position and rotation, from (0, 0) at the top left to (1, 1) at the bottom right
+    pub rotation: f32, // Radians, clockwise since Y points down
+    pub region: UvRect,
+    pub tint: Vec4, // Linear, multiplies the texture
+    pub layer: i32 // Higher layers are drawn over lower ones within a SpriteBatch
+}
+
+impl Sprite {
+    pub fn new(texture: TextureHandle, position: Vec2, size: Vec2) -> Sprite {
+        Sprite {
+            texture,
+            position,
+            size,
+            origin: Vec2::splat(0.5),
+            rotation: 0.0,
+            region: UvRect::FULL,
+            tint: Vec4::ONE,
+            layer: 0
+        }
+    }
+
+    pub fn with_region(mut self, region: UvRect) -> Sprite {
+        self.region = region;
+        self
+    }
+
+    pub fn with_tint(mut self, tint: Vec4) -> Sprite {
+        self.tint = tint;
+        self
+    }
+
+    pub fn with_rotation(mut self, rotation: f32) -> Sprite {
+        self.rotation = rotation;
+        self
+    }
+
+    pub fn with_origin(mut self, origin: Vec2) -> Sprite {
+        self.origin = origin;
+        self
+    }
+
+    pub fn with_layer(mut self, layer: i32) -> Sprite {
+        self.layer = layer;
+        self
+    }
+
+    // Two triangles, indices relative to the start of vertices
+    pub(crate) fn build(&self, vertices: &mut Vec<OverlayVertex>, indices: &mut Vec<u32>) {
+        let base = vertices.len() as u32;
+        let rotation = Mat2::from_angle(self.rotation);
+        let pivot = self.origin * self.size;
+        let color = self.tint.to_array();
+        let corners = [
+            (Vec2::new(0.0, 0.0), self.region.min),
+            (Vec2::new(1.0, 0.0), Vec2::new(self.region.max.x, self.region.min.y)),
+            (Vec2::new(1.0, 1.0), self.region.max),
+            (Vec2::new(0.0, 1.0), Vec2::new(self.region.min.x, self.region.max.y))
+        ];
+        vertices.extend(corners.iter().map(|(corner, uv)| OverlayVertex {
+            pos: (self.position + rotation * (*corner * self.size - pivot)).to_array(),
+            uv: uv.to_array(),
+            color
+        }));
+        indices.extend([0, 1, 2, 2, 3, 0].iter().map(|i| base + i));
+    }
+}
+
+// Sprites collected over a frame and drawn together. They're sorted by layer, then by texture so each
+// texture within a layer is one draw call. Sprites on the same layer and texture keep their order.
+#[derive(Default)]
+pub struct SpriteBatch {
+    sprites: Vec<Sprite>
+}
+
+impl SpriteBatch {
+    pub fn new() -> SpriteBatch {
+        SpriteBatch {
+            sprites: Vec::new()
+        }
+    }
+
+    pub fn push(&mut self, sprite: Sprite) {
+        self.sprites.push(sprite);
+    }
+
+    pub fn clear(&mut self) {
+        self.sprites.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.sprites.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sprites.is_empty()
+    }
+
+    // Calls push(texture, vertices, indices) once per run of sprites sharing a texture, in draw order
+    pub(crate) fn build<F>(&self, mut push: F)
+        where F: FnMut(TextureHandle, &[OverlayVertex], &[u32]) {
+        let mut order: Vec<&Sprite> = self.sprites.iter().collect();
+        order.sort_by_key(|s| (s.layer, s.texture.0)); // Stable
+
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for (i, sprite) in order.iter().enumerate() {
+            sprite.build(&mut vertices, &mut indices);
+            let run_ends = order.get(i + 1).map_or(true, |next| next.texture != sprite.texture);
+            if run_ends {
+                push(sprite.texture, &vertices, &indices);
+                vertices.clear();
+                indices.clear();
+            }
+        }
+    }
+}
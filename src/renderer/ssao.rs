@@ -0,0 +1,383 @@
+use std::mem;
+use std::path::PathBuf;
+
+use ash::vk;
+use bytemuck::{Pod, Zeroable};
+use glam::Mat4;
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::barrier::{first_mip, Barriers, Ownership, Usage};
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::raster_pipeline::RasterPipeline;
+use crate::renderer::render_pass::{setup_post_render_pass, PassTarget};
+use crate::renderer::shader::{ShaderSet, ShaderSource};
+
+const AO_FORMAT: vk::Format = vk::Format::R8_UNORM; // Occlusion only needs one channel
+
+// Indices into Ssao::pipelines and Ssao::sets
+const OCCLUSION: usize = 0;
+const BLUR: usize = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum SsaoQuality {
+    Low, // 8 samples, a 3x3 blur
+    #[default]
+    Medium, // 16 samples, a 5x5 blur
+    High // 32 samples, a 5x5 blur
+}
+
+impl SsaoQuality {
+    fn samples(self) -> u32 {
+        match self {
+            SsaoQuality::Low => 8,
+            SsaoQuality::Medium => 16,
+            SsaoQuality::High => 32 // MAX_SAMPLES in ssao.frag
+        }
+    }
+
+    fn blur_radius(self) -> u32 {
+        match self {
+            SsaoQuality::Low => 1,
+            SsaoQuality::Medium | SsaoQuality::High => 2
+        }
+    }
+}
+
+// Screen space ambient occlusion, darkening the ambient light in creases and where surfaces meet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SsaoSettings {
+    pub quality: SsaoQuality,
+    pub radius: f32, // World units around each pixel that can occlude it
+    pub intensity: f32 // Exponent on the result, higher darkens occluded areas more
+}
+
+impl Default for SsaoSettings {
+    fn default() -> Self {
+        SsaoSettings {
+            quality: SsaoQuality::default(),
+            radius: 0.5,
+            intensity: 1.5
+        }
+    }
+}
+
+// Matches the SsaoParams push constant block in ssao.frag and ssao_blur.frag
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+struct SsaoParams {
+    inv_view_proj: Mat4, // Rebuilds positions from the G-buffer's depth
+    params: [f32; 4], // Radius, depth bias, sample count, intensity
+    blur: [f32; 4] // Texel size, blur radius
+}
+
+// An occlusion image rendered by one pass and sampled by the next
+struct AoTarget {
+    image: vk::Image,
+    alloc: Allocation,
+    view: vk::ImageView,
+    framebuffer: vk::Framebuffer
+}
+
+impl AoTarget {
+    fn new(logical_layer: &LogicalLayer, allocator: &Allocator, render_pass: vk::RenderPass,
+           extent: vk::Extent2D) -> Result<AoTarget, RendererError> {
+        let create_info = vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(AO_FORMAT)
+            .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::SAMPLED)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let (alloc, image) = allocator.create_image(logical_layer, &create_info, vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                    MemoryCategory::RenderTargets)?;
+
+        let view_create_info = vk::ImageViewCreateInfo::default()
+            .image(image)
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(AO_FORMAT)
+            .subresource_range(first_mip(vk::ImageAspectFlags::COLOR, 1));
+        let view = match unsafe { logical_layer.logical_device.create_image_view(&view_create_info, None) } {
+            Ok(v) => v,
+            Err(e) => {
+                allocator.destroy_image(logical_layer, image, &alloc);
+                return Err(vk_error("vkCreateImageView")(e));
+            }
+        };
+
+        let attachments = [view];
+        let framebuffer_create_info = vk::FramebufferCreateInfo::default()
+            .render_pass(render_pass)
+            .attachments(&attachments)
+            .width(extent.width)
+            .height(extent.height)
+            .layers(1);
+        let framebuffer = match unsafe { logical_layer.logical_device.create_framebuffer(&framebuffer_create_info, None) } {
+            Ok(f) => f,
+            Err(e) => {
+                unsafe { logical_layer.logical_device.destroy_image_view(view, None) };
+                allocator.destroy_image(logical_layer, image, &alloc);
+                return Err(vk_error("vkCreateFramebuffer")(e));
+            }
+        };
+
+        Ok(AoTarget {
+            image,
+            alloc,
+            view,
+            framebuffer
+        })
+    }
+
+    fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        unsafe {
+            logical_layer.logical_device.destroy_framebuffer(self.framebuffer, None);
+            logical_layer.logical_device.destroy_image_view(self.view, None);
+        }
+        allocator.destroy_image(logical_layer, self.image, &self.alloc);
+    }
+}
+
+// Ambient occlusion from the deferred G-buffer's normals and depth. A fullscreen pass samples a
+// hemisphere around every pixel into a noisy target, a second one blurs it into the target the lighting
+// pass reads. Both read the frame's uniforms at set 0 and set 1: their input at binding 0, the G-buffer
+// depth at 1 and a nearest sampler at 2.
+pub(crate) struct Ssao {
+    render_pass: vk::RenderPass,
+    pipelines: Vec<RasterPipeline>, // In OCCLUSION, BLUR order
+    set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sets: Vec<vk::DescriptorSet>, // In OCCLUSION, BLUR order, rewritten whenever the targets are recreated
+    targets: Option<[AoTarget; 2]>, // Raw then blurred, None between destroy_targets and resize
+    extent: vk::Extent2D,
+    needs_transition: bool // The blurred target starts out UNDEFINED but the lighting pass binds it either way
+}
+
+impl Ssao {
+    pub(crate) fn new(logical_layer: &LogicalLayer, frame_set_layout: vk::DescriptorSetLayout) -> Result<Ssao, RendererError> {
+        fn setup_set_layout(logical_layer: &LogicalLayer) -> Result<vk::DescriptorSetLayout, RendererError> {
+            let bindings = [
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(0)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(1)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT),
+                vk::DescriptorSetLayoutBinding::default()
+                    .binding(2)
+                    .descriptor_type(vk::DescriptorType::SAMPLER)
+                    .descriptor_count(1)
+                    .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            ];
+            let create_info = vk::DescriptorSetLayoutCreateInfo::default()
+                .bindings(&bindings);
+
+            unsafe {
+                logical_layer.logical_device.create_descriptor_set_layout(&create_info, None)
+                    .map_err(vk_error("vkCreateDescriptorSetLayout"))
+            }
+        }
+
+        fn setup_descriptors(logical_layer: &LogicalLayer, set_layout: vk::DescriptorSetLayout)
+            -> Result<(vk::DescriptorPool, Vec<vk::DescriptorSet>), RendererError> {
+            let pool_sizes = [
+                vk::DescriptorPoolSize::default()
+                    .ty(vk::DescriptorType::SAMPLED_IMAGE)
+                    .descriptor_count(4),
+                vk::DescriptorPoolSize::default()
+                    .ty(vk::DescriptorType::SAMPLER)
+                    .descriptor_count(2)
+            ];
+            let pool_create_info = vk::DescriptorPoolCreateInfo::default()
+                .pool_sizes(&pool_sizes)
+                .max_sets(2);
+            let pool = unsafe {
+                logical_layer.logical_device.create_descriptor_pool(&pool_create_info, None)
+                    .map_err(vk_error("vkCreateDescriptorPool"))?
+            };
+
+            let layouts = [set_layout, set_layout];
+            let alloc_info = vk::DescriptorSetAllocateInfo::default()
+                .descriptor_pool(pool)
+                .set_layouts(&layouts);
+            match unsafe { logical_layer.logical_device.allocate_descriptor_sets(&alloc_info) } {
+                Ok(sets) => Ok((pool, sets)),
+                Err(e) => {
+                    unsafe { logical_layer.logical_device.destroy_descriptor_pool(pool, None) };
+                    Err(vk_error("vkAllocateDescriptorSets")(e))
+                }
+            }
+        }
+
+        let render_pass = setup_post_render_pass(logical_layer, AO_FORMAT)?;
+        let set_layout = setup_set_layout(logical_layer)?;
+        let (descriptor_pool, sets) = setup_descriptors(logical_layer, set_layout)?;
+
+        let push_constant_range = vk::PushConstantRange::default()
+            .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+            .offset(0)
+            .size(mem::size_of::<SsaoParams>() as u32);
+        // In OCCLUSION, BLUR order
+        let fragments = ["ssao.frag", "ssao_blur.frag"];
+        let mut pipelines: Vec<RasterPipeline> = Vec::with_capacity(fragments.len());
+        for fragment in fragments {
+            let shaders = ShaderSet {
+                vertex: ShaderSource::GlslFile(PathBuf::from("shaders/src/fullscreen.vert")),
+                fragment: ShaderSource::GlslFile(PathBuf::from("shaders/src").join(fragment))
+            };
+            pipelines.push(RasterPipeline::new(logical_layer, PassTarget::single(render_pass), &shaders, &[],
+                                               &[frame_set_layout, set_layout], Some(push_constant_range))?);
+        }
+
+        Ok(Ssao {
+            render_pass,
+            pipelines,
+            set_layout,
+            descriptor_pool,
+            sets,
+            targets: None,
+            extent: vk::Extent2D::default(),
+            needs_transition: true
+        })
+    }
+
+    // Recreates the targets at extent, reading the G-buffer's new normal and depth views. sampler is the
+    // G-buffer's, also used for the occlusion targets. The GPU must not be using the old ones.
+    pub(crate) fn resize(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, extent: vk::Extent2D,
+                         normal_view: vk::ImageView, depth_view: vk::ImageView, sampler: vk::Sampler) -> Result<(), RendererError> {
+        self.destroy_targets(logical_layer, allocator);
+
+        let raw = AoTarget::new(logical_layer, allocator, self.render_pass, extent)?;
+        let blurred = match AoTarget::new(logical_layer, allocator, self.render_pass, extent) {
+            Ok(t) => t,
+            Err(e) => {
+                raw.destroy(logical_layer, allocator);
+                return Err(e);
+            }
+        };
+
+        // The occlusion pass reads the normals, the blur the raw occlusion. Both read depth.
+        let inputs = [(self.sets[OCCLUSION], normal_view), (self.sets[BLUR], raw.view)];
+        for (set, input) in inputs {
+            let image_info = |view: vk::ImageView| [vk::DescriptorImageInfo::default()
+                .image_view(view)
+                .image_layout(vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL)];
+            let (input_infos, depth_infos) = (image_info(input), image_info(depth_view));
+            let sampler_infos = [vk::DescriptorImageInfo::default()
+                .sampler(sampler)];
+            let writes = [
+                vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(0)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .image_info(&input_infos),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(1)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .image_info(&depth_infos),
+                vk::WriteDescriptorSet::default()
+                    .dst_set(set)
+                    .dst_binding(2)
+                    .descriptor_type(vk::DescriptorType::SAMPLER)
+                    .image_info(&sampler_infos)
+            ];
+            unsafe { logical_layer.logical_device.update_descriptor_sets(&writes, &[]) };
+        }
+
+        self.targets = Some([raw, blurred]);
+        self.extent = extent;
+        self.needs_transition = true;
+        Ok(())
+    }
+
+    // What the lighting pass samples
+    pub(crate) fn output_view(&self) -> vk::ImageView {
+        self.targets.as_ref().expect("SSAO targets are missing")[1].view
+    }
+
+    // Moves the blurred target into the layout the lighting pass expects, so it's valid to bind while SSAO
+    // is off. Only records anything after creation or a resize.
+    pub(crate) fn prepare(&mut self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer) {
+        if !self.needs_transition {
+            return;
+        }
+        self.needs_transition = false;
+
+        let targets = self.targets.as_ref().expect("SSAO targets are missing");
+        Barriers::new()
+            .image(targets[1].image, first_mip(vk::ImageAspectFlags::COLOR, 1), Usage::Undefined, Usage::FragmentSampled,
+                   Ownership::Keep)
+            .record(logical_layer, command_buffer);
+    }
+
+    // Records both passes, after the G-buffer pass has ended and before the lighting pass begins
+    pub(crate) fn record(&self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame_set: vk::DescriptorSet,
+                         settings: &SsaoSettings, inv_view_proj: Mat4) {
+        let targets = self.targets.as_ref().expect("SSAO targets are missing");
+        let params = SsaoParams {
+            inv_view_proj,
+            params: [settings.radius, settings.radius * 0.05, settings.quality.samples() as f32, settings.intensity],
+            blur: [1.0 / self.extent.width as f32, 1.0 / self.extent.height as f32, settings.quality.blur_radius() as f32, 0.0]
+        };
+        let render_area = vk::Rect2D::default().extent(self.extent);
+        let viewports = [vk::Viewport::default()
+            .width(self.extent.width as f32)
+            .height(self.extent.height as f32)
+            .min_depth(0.0)
+            .max_depth(1.0)];
+        let scissors = [render_area];
+
+        for (pass, target) in [(OCCLUSION, &targets[0]), (BLUR, &targets[1])] {
+            let render_pass_info = vk::RenderPassBeginInfo::default()
+                .render_pass(self.render_pass)
+                .framebuffer(target.framebuffer)
+                .render_area(render_area); // Nothing is cleared
+            let descriptor_sets = [frame_set, self.sets[pass]];
+            let pipeline = &self.pipelines[pass];
+
+            unsafe {
+                let device = &logical_layer.logical_device;
+                device.cmd_begin_render_pass(command_buffer, &render_pass_info, vk::SubpassContents::INLINE);
+                device.cmd_bind_pipeline(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.pipelines[0]);
+                device.cmd_bind_descriptor_sets(command_buffer, vk::PipelineBindPoint::GRAPHICS, pipeline.pipeline_layout,
+                                                0, &descriptor_sets, &[]);
+                device.cmd_set_viewport(command_buffer, 0, &viewports);
+                device.cmd_set_scissor(command_buffer, 0, &scissors);
+                pipeline.push_constants(logical_layer, command_buffer, 0, &params);
+                device.cmd_draw(command_buffer, 3, 1, 0, 0); // Fullscreen triangle generated from the vertex index
+                device.cmd_end_render_pass(command_buffer);
+            }
+        }
+    }
+
+    // Targets are taken so destroying twice, I.E. after a failed resize, is harmless
+    pub(crate) fn destroy_targets(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        if let Some(targets) = self.targets.take() {
+            for t in targets.iter() {
+                t.destroy(logical_layer, allocator);
+            }
+        }
+    }
+
+    pub(crate) fn destroy(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        self.destroy_targets(logical_layer, allocator);
+        for p in self.pipelines.iter_mut() {
+            p.destroy(logical_layer);
+        }
+        unsafe {
+            logical_layer.logical_device.destroy_descriptor_pool(self.descriptor_pool, None); // Frees the sets as well
+            logical_layer.logical_device.destroy_descriptor_set_layout(self.set_layout, None);
+            logical_layer.logical_device.destroy_render_pass(self.render_pass, None);
+        }
+    }
+}
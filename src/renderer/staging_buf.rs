@@ -1,78 +1,406 @@
 use ash::vk;
-use crate::renderer::core::Core;
+use bytemuck::Pod;
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::barrier::{Barriers, Ownership, Usage};
+use crate::renderer::error::{vk_error, RendererError};
 use crate::renderer::logical_layer::LogicalLayer;
-use crate::renderer::physical_layer::PhysicalLayer;
-
-pub(crate) fn create_buffer(core: &Core,
-                 physical_layer: &PhysicalLayer,
-                 logical_layer: &LogicalLayer,
-                 size: vk::DeviceSize,
-                 usage: vk::BufferUsageFlags,
-                 mem_props: vk::MemoryPropertyFlags) -> Result<(vk::DeviceMemory, vk::Buffer), ()> {
-    let buffer_create_info = vk::BufferCreateInfo::default()
-        .size(size)
-        .usage(usage)
-        .sharing_mode(vk::SharingMode::EXCLUSIVE);
-
-    let buffer = unsafe { logical_layer.logical_device.create_buffer(&buffer_create_info, None).unwrap() };
-
-    let mem_reqs = unsafe { logical_layer.logical_device.get_buffer_memory_requirements(buffer)};
-
-    let phys_mem_props = unsafe { core.instance.get_physical_device_memory_properties(physical_layer.physical_device)};
-
-    let mut retval = Err(());
-    for i in 0..phys_mem_props.memory_type_count {
-        if ((1 << i) & mem_reqs.memory_type_bits) > 0 && // If this physical memory type is valid for the requirement
-            phys_mem_props.memory_types.get(i as usize).unwrap()
-                .property_flags
-                .contains(mem_props) {
-            // Explicit flushes are required otherwise
-            let alloc_info = vk::MemoryAllocateInfo::default()
-                .allocation_size(mem_reqs.size)
-                .memory_type_index(i);
-            let buffer_mem = unsafe { logical_layer.logical_device.allocate_memory(&alloc_info, None).unwrap()};
-            unsafe { logical_layer.logical_device.bind_buffer_memory(buffer, buffer_mem, 0).unwrap() };
-            retval = Ok((buffer_mem, buffer));
-            break;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::timeline::Timeline;
+
+pub(crate) const STAGING_RING_SIZE: vk::DeviceSize = 16 * 1024 * 1024;
+const STAGING_ALIGNMENT: vk::DeviceSize = 16; // Covers the texel size and 4 byte requirements of buffer to image copies
+
+// Stages where uploaded resources are first read, the acquire waits on the copies here. Matches Usage::ShaderRead.
+const CONSUMER_STAGES: vk::PipelineStageFlags = vk::PipelineStageFlags::from_raw(
+    vk::PipelineStageFlags::VERTEX_INPUT.as_raw() |
+        vk::PipelineStageFlags::VERTEX_SHADER.as_raw() |
+        vk::PipelineStageFlags::FRAGMENT_SHADER.as_raw() |
+        vk::PipelineStageFlags::COMPUTE_SHADER.as_raw());
+
+// Only exists with a dedicated transfer family, records the ownership acquires on the graphics queue
+struct AcquireContext {
+    command_pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer,
+    graphics_family: u32
+}
+
+// Batches uploads into a single transfer command buffer. Data is written into a persistently mapped
+// staging ring and the copies are recorded immediately, but nothing is submitted until flush().
+// Flushing doesn't block, the next batch waits on the previous one's timeline value before reusing the
+// ring, and the ring wraps by flushing when it runs out of room.
+pub(crate) struct UploadContext {
+    staging_buf: vk::Buffer,
+    staging_alloc: Allocation,
+    capacity: vk::DeviceSize,
+    head: vk::DeviceSize, // Next free byte in the ring
+    command_pool: vk::CommandPool, // On the transfer family
+    command_buffer: vk::CommandBuffer,
+    acquire: Option<AcquireContext>,
+    timeline: Timeline, // Signalled by the copies, then again by the ownership acquire if there is one
+    recording: bool,
+    in_flight: bool, // A submitted batch hasn't been waited on yet
+    uploaded_buffers: Vec<vk::Buffer>, // Destinations needing ownership transfers at flush
+    uploaded_images: Vec<(vk::Image, vk::ImageSubresourceRange)>, // Still in TRANSFER_DST_OPTIMAL until flush
+    oversized: Vec<(vk::Buffer, Allocation)>, // One off staging buffers for uploads larger than the ring
+    in_flight_oversized: Vec<(vk::Buffer, Allocation)>
+}
+
+impl UploadContext {
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, graphics_family: u32, capacity: vk::DeviceSize) -> Result<UploadContext, RendererError> {
+        fn setup_command_buffer(logical_layer: &LogicalLayer, queue_family: u32) -> Result<(vk::CommandPool, vk::CommandBuffer), RendererError> {
+            let pool_create_info = vk::CommandPoolCreateInfo::default()
+                .flags(vk::CommandPoolCreateFlags::TRANSIENT | vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+                .queue_family_index(queue_family);
+            let command_pool = unsafe {
+                logical_layer.logical_device.create_command_pool(&pool_create_info, None).map_err(vk_error("vkCreateCommandPool"))?
+            };
+
+            let buf_alloc_info = vk::CommandBufferAllocateInfo::default()
+                .level(vk::CommandBufferLevel::PRIMARY)
+                .command_pool(command_pool)
+                .command_buffer_count(1);
+            let command_buffer = unsafe {
+                logical_layer.logical_device.allocate_command_buffers(&buf_alloc_info).map_err(vk_error("vkAllocateCommandBuffers"))?[0]
+            };
+
+            Ok((command_pool, command_buffer))
         }
+
+        let (staging_alloc, staging_buf) = allocator.create_buffer(logical_layer,
+                                                                   capacity,
+                                                                   vk::BufferUsageFlags::TRANSFER_SRC, // Can be a used as a source for transfer commands
+                                                                   vk::MemoryPropertyFlags::HOST_VISIBLE | // Visible for writes on the host
+                                                                       vk::MemoryPropertyFlags::HOST_COHERENT, // No explicit flushes needed
+                                                                   MemoryCategory::Staging)?;
+
+        let (command_pool, command_buffer) = setup_command_buffer(logical_layer, logical_layer.transfer_family_index)?;
+
+        let acquire = match logical_layer.transfer_family_index != graphics_family {
+            true => {
+                let (command_pool, command_buffer) = setup_command_buffer(logical_layer, graphics_family)?;
+                Some(AcquireContext {
+                    command_pool,
+                    command_buffer,
+                    graphics_family
+                })
+            },
+            false => None
+        };
+
+        let timeline = Timeline::new(logical_layer)?;
+
+        Ok(UploadContext {
+            staging_buf,
+            staging_alloc,
+            capacity,
+            head: 0,
+            command_pool,
+            command_buffer,
+            acquire,
+            timeline,
+            recording: false,
+            in_flight: false,
+            uploaded_buffers: Vec::new(),
+            uploaded_images: Vec::new(),
+            oversized: Vec::new(),
+            in_flight_oversized: Vec::new()
+        })
     }
 
-    retval
-}
+    // Blocks until the last submitted batch completes, after which its staging memory can be reused
+    fn wait(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) -> Result<(), RendererError> {
+        if !self.in_flight {
+            return Ok(());
+        }
+
+        let result = self.timeline.wait(logical_layer, self.timeline.value());
+
+        self.in_flight = false;
+        for (buf, alloc) in self.in_flight_oversized.drain(..) {
+            allocator.destroy_buffer(logical_layer, buf, &alloc);
+        }
+
+        result
+    }
+
+    fn begin(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) -> Result<(), RendererError> {
+        if !self.recording {
+            self.wait(logical_layer, allocator)?;
+            self.head = 0;
+
+            let begin_info = vk::CommandBufferBeginInfo::default()
+                .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+            unsafe {
+                logical_layer.logical_device.reset_command_buffer(self.command_buffer, vk::CommandBufferResetFlags::empty())
+                    .map_err(vk_error("vkResetCommandBuffer"))?;
+                logical_layer.logical_device.begin_command_buffer(self.command_buffer, &begin_info)
+                    .map_err(vk_error("vkBeginCommandBuffer"))?;
+            }
+            self.recording = true;
+        }
+
+        Ok(())
+    }
+
+    // Copies data into staging memory, returning the buffer and offset the transfer should read from
+    fn stage(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, data: &[u8]) -> Result<(vk::Buffer, vk::DeviceSize), RendererError> {
+        let size = data.len() as vk::DeviceSize;
+        self.begin(logical_layer, allocator)?;
+
+        if size > self.capacity {
+            let (alloc, buf) = allocator.create_buffer(logical_layer,
+                                                       size,
+                                                       vk::BufferUsageFlags::TRANSFER_SRC,
+                                                       vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                                           vk::MemoryPropertyFlags::HOST_COHERENT,
+                                                       MemoryCategory::Staging)?;
+            unsafe {
+                let dst = alloc.mapped_ptr().unwrap(); // Host visible blocks are always mapped
+                dst.copy_from_nonoverlapping(data.as_ptr(), data.len());
+            }
+            self.oversized.push((buf, alloc));
+
+            return Ok((buf, 0));
+        }
+
+        let mut offset = (self.head + STAGING_ALIGNMENT - 1) / STAGING_ALIGNMENT * STAGING_ALIGNMENT;
+        if offset + size > self.capacity {
+            // Out of room, submit what's recorded and wait for it so the ring can start over
+            self.flush(logical_layer)?;
+            self.begin(logical_layer, allocator)?;
+            offset = 0;
+        }
 
-pub(crate) fn copy_buffer(logical_layer: &LogicalLayer, cmd_pool: vk::CommandPool,
-               src_buf: vk::Buffer, dest_buf: vk::Buffer, data_size: vk::DeviceSize) {
-    let buf_alloc_info = vk::CommandBufferAllocateInfo::default()
-        .level(vk::CommandBufferLevel::PRIMARY)
-        .command_pool(cmd_pool)
-        .command_buffer_count(1);
+        unsafe {
+            let dst = self.staging_alloc.mapped_ptr().unwrap().add(offset as usize);
+            dst.copy_from_nonoverlapping(data.as_ptr(), data.len());
+        }
+        self.head = offset + size;
 
-    let command_buffer_vec = unsafe { logical_layer.logical_device.allocate_command_buffers(&buf_alloc_info).unwrap() };
+        Ok((self.staging_buf, offset))
+    }
 
-    let command_buffer = *command_buffer_vec.get(0).unwrap();
+    pub(crate) fn upload_buffer<T: Pod>(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator,
+                                        data: &[T], dst_buf: vk::Buffer, dst_offset: vk::DeviceSize) -> Result<(), RendererError> {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        let (src_buf, src_offset) = self.stage(logical_layer, allocator, bytes)?;
 
-    let command_buffer_array = [command_buffer];
+        let copy_regions = [vk::BufferCopy::default()
+            .size(bytes.len() as vk::DeviceSize)
+            .dst_offset(dst_offset)
+            .src_offset(src_offset)];
 
-    let begin_info = vk::CommandBufferBeginInfo::default()
-        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe { logical_layer.logical_device.cmd_copy_buffer(self.command_buffer, src_buf, dst_buf, &copy_regions) };
+        self.uploaded_buffers.push(dst_buf);
 
-    unsafe { logical_layer.logical_device.begin_command_buffer(command_buffer, &begin_info).unwrap() };
+        Ok(())
+    }
 
-    let copy_region = vk::BufferCopy::default()
-        .size(data_size)
-        .dst_offset(0)
-        .src_offset(0);
+    // Fills every mip 0 layer of an image created with TRANSFER_DST usage, it's ready for sampling after flush
+    pub(crate) fn upload_image<T: Pod>(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator,
+                                       data: &[T], dst_image: vk::Image, extent: vk::Extent3D, layer_count: u32) -> Result<(), RendererError> {
+        let bytes: &[u8] = bytemuck::cast_slice(data);
+        let (src_buf, src_offset) = self.stage(logical_layer, allocator, bytes)?;
 
-    let copy_regions = [copy_region];
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count
+        };
 
-    let submit_info = vk::SubmitInfo::default().command_buffers(&command_buffer_array);
-    let submit_info_slice = [submit_info];
+        // Previous contents are discarded
+        let to_transfer = Barriers::new()
+            .image(dst_image, subresource_range, Usage::Undefined, Usage::TransferWrite, Ownership::Keep);
 
-    unsafe {
-        logical_layer.logical_device.cmd_copy_buffer(command_buffer, src_buf, dest_buf, &copy_regions);
-        logical_layer.logical_device.end_command_buffer(command_buffer).unwrap();
-        logical_layer.logical_device.queue_submit(logical_layer.logical_queue, &submit_info_slice, vk::Fence::null()).unwrap();
-        logical_layer.logical_device.queue_wait_idle(logical_layer.logical_queue).unwrap();
-        logical_layer.logical_device.free_command_buffers(cmd_pool, &command_buffer_array);
+        let copy_regions = [vk::BufferImageCopy::default()
+            .buffer_offset(src_offset)
+            .buffer_row_length(0) // Tightly packed
+            .buffer_image_height(0)
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count
+            })
+            .image_extent(extent)];
+
+        to_transfer.record(logical_layer, self.command_buffer);
+        unsafe {
+            logical_layer.logical_device.cmd_copy_buffer_to_image(self.command_buffer,
+                                                                  src_buf,
+                                                                  dst_image,
+                                                                  vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                                                                  &copy_regions);
+        }
+        // The transition to SHADER_READ_ONLY_OPTIMAL doubles as the ownership transfer, so it's recorded at flush
+        self.uploaded_images.push((dst_image, subresource_range));
+
+        Ok(())
     }
-}
\ No newline at end of file
+
+    // Fills every mip level of an image created with TRANSFER_DST usage, levels running from mip 0 down. Each
+    // level holds every layer back to back. Compressed levels are copied in whole blocks. It's ready for
+    // sampling after flush.
+    pub(crate) fn upload_image_levels(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, levels: &[Vec<u8>],
+                                      dst_image: vk::Image, extent: vk::Extent3D, layer_count: u32) -> Result<(), RendererError> {
+        // Staged together so a ring wrap can't split the levels across batches
+        let mut bytes = Vec::with_capacity(levels.iter().map(|l| l.len() + STAGING_ALIGNMENT as usize).sum());
+        let mut offsets = Vec::with_capacity(levels.len());
+        for level in levels.iter() {
+            bytes.resize((bytes.len() + STAGING_ALIGNMENT as usize - 1) / STAGING_ALIGNMENT as usize * STAGING_ALIGNMENT as usize, 0);
+            offsets.push(bytes.len() as vk::DeviceSize);
+            bytes.extend_from_slice(level);
+        }
+        let (src_buf, src_offset) = self.stage(logical_layer, allocator, &bytes)?;
+
+        let subresource_range = vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: levels.len() as u32,
+            base_array_layer: 0,
+            layer_count
+        };
+
+        let to_transfer = Barriers::new()
+            .image(dst_image, subresource_range, Usage::Undefined, Usage::TransferWrite, Ownership::Keep);
+
+        let copy_regions: Vec<vk::BufferImageCopy> = offsets.iter()
+            .enumerate()
+            .map(|(i, offset)| vk::BufferImageCopy::default()
+                .buffer_offset(src_offset + offset)
+                .buffer_row_length(0)
+                .buffer_image_height(0)
+                .image_subresource(vk::ImageSubresourceLayers {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    mip_level: i as u32,
+                    base_array_layer: 0,
+                    layer_count
+                })
+                .image_extent(vk::Extent3D {
+                    width: (extent.width >> i).max(1),
+                    height: (extent.height >> i).max(1),
+                    depth: 1
+                }))
+            .collect();
+
+        to_transfer.record(logical_layer, self.command_buffer);
+        unsafe {
+            logical_layer.logical_device.cmd_copy_buffer_to_image(self.command_buffer,
+                                                                  src_buf,
+                                                                  dst_image,
+                                                                  vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                                                                  &copy_regions);
+        }
+        self.uploaded_images.push((dst_image, subresource_range));
+
+        Ok(())
+    }
+
+    // Submits every recorded copy without waiting on them. Work submitted to the graphics queue afterwards
+    // sees the uploaded data.
+    pub(crate) fn flush(&mut self, logical_layer: &LogicalLayer) -> Result<(), RendererError> {
+        if !self.recording {
+            return Ok(());
+        }
+        self.recording = false;
+
+        let (release, acquire) = match &self.acquire {
+            Some(a) => (Ownership::Release { src_family: logical_layer.transfer_family_index, dst_family: a.graphics_family },
+                        Ownership::Acquire { src_family: logical_layer.transfer_family_index, dst_family: a.graphics_family }),
+            None => (Ownership::Keep, Ownership::Keep)
+        };
+
+        // With a dedicated transfer family these are the release half of the ownership transfer,
+        // otherwise they just make the copies visible to the consumers
+        let transitions = |ownership: Ownership| -> Barriers {
+            let barriers = self.uploaded_buffers.iter()
+                .fold(Barriers::new(), |b, buf| b.buffer(*buf, Usage::TransferWrite, Usage::ShaderRead, ownership));
+            self.uploaded_images.iter()
+                .fold(barriers, |b, (i, range)| b.image(*i, *range, Usage::TransferWrite, Usage::ShaderRead, ownership))
+        };
+        let release_barriers = transitions(release);
+        let acquire_barriers = transitions(acquire);
+        self.uploaded_buffers.clear();
+        self.uploaded_images.clear();
+
+        // The acquire waits on the copies' value and signals the next one, wait() only looks at the last
+        let timeline_sems = [self.timeline.semaphore];
+        let transfer_values = [self.timeline.next()];
+        let acquire_values = match self.acquire {
+            Some(_) => [self.timeline.next()],
+            None => transfer_values
+        };
+        let transfer_cbs = [self.command_buffer];
+        let mut transfer_timeline = vk::TimelineSemaphoreSubmitInfo::default()
+            .signal_semaphore_values(&transfer_values);
+        let transfer_submit = vk::SubmitInfo::default()
+            .command_buffers(&transfer_cbs)
+            .signal_semaphores(&timeline_sems)
+            .push_next(&mut transfer_timeline);
+
+        release_barriers.record(logical_layer, self.command_buffer);
+        unsafe {
+            logical_layer.logical_device.end_command_buffer(self.command_buffer)
+                .map_err(vk_error("vkEndCommandBuffer"))?;
+
+            match &self.acquire {
+                Some(a) => {
+                    // Graphics side of the ownership transfer, waits for the copies through the timeline
+                    let begin_info = vk::CommandBufferBeginInfo::default()
+                        .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+                    logical_layer.logical_device.reset_command_buffer(a.command_buffer, vk::CommandBufferResetFlags::empty())
+                        .map_err(vk_error("vkResetCommandBuffer"))?;
+                    logical_layer.logical_device.begin_command_buffer(a.command_buffer, &begin_info)
+                        .map_err(vk_error("vkBeginCommandBuffer"))?;
+                    acquire_barriers.record(logical_layer, a.command_buffer);
+                    logical_layer.logical_device.end_command_buffer(a.command_buffer)
+                        .map_err(vk_error("vkEndCommandBuffer"))?;
+
+                    let wait_stages = [CONSUMER_STAGES];
+                    let acquire_cbs = [a.command_buffer];
+                    let mut acquire_timeline = vk::TimelineSemaphoreSubmitInfo::default()
+                        .wait_semaphore_values(&transfer_values)
+                        .signal_semaphore_values(&acquire_values);
+                    let acquire_submit = vk::SubmitInfo::default()
+                        .wait_semaphores(&timeline_sems)
+                        .wait_dst_stage_mask(&wait_stages)
+                        .command_buffers(&acquire_cbs)
+                        .signal_semaphores(&timeline_sems)
+                        .push_next(&mut acquire_timeline);
+
+                    logical_layer.logical_device.queue_submit(logical_layer.transfer_queue, &[transfer_submit], vk::Fence::null())
+                        .map_err(vk_error("vkQueueSubmit"))?;
+                    logical_layer.logical_device.queue_submit(logical_layer.logical_queue, &[acquire_submit], vk::Fence::null())
+                        .map_err(vk_error("vkQueueSubmit"))?;
+                },
+                None => {
+                    logical_layer.logical_device.queue_submit(logical_layer.transfer_queue, &[transfer_submit], vk::Fence::null())
+                        .map_err(vk_error("vkQueueSubmit"))?;
+                }
+            }
+        }
+
+        self.in_flight = true;
+        self.in_flight_oversized.append(&mut self.oversized);
+
+        Ok(())
+    }
+
+    pub(crate) fn destroy(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        let _ = self.wait(logical_layer, allocator); // Nothing left to do with a failure at this point
+        for (buf, alloc) in self.oversized.drain(..) {
+            allocator.destroy_buffer(logical_layer, buf, &alloc);
+        }
+        unsafe {
+            logical_layer.logical_device.destroy_command_pool(self.command_pool, None); // Frees the command buffer as well
+            if let Some(a) = &self.acquire {
+                logical_layer.logical_device.destroy_command_pool(a.command_pool, None);
+            }
+        }
+        self.timeline.destroy(logical_layer);
+        allocator.destroy_buffer(logical_layer, self.staging_buf, &self.staging_alloc);
+    }
+}
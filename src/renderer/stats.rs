@@ -0,0 +1,67 @@
+use std::time::{Duration, Instant};
+
+const FPS_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy, Debug)]
+pub struct PassTiming {
+    pub name: &'static str,
+    pub duration: Duration // GPU time between the start and end of the pass
+}
+
+#[derive(Clone, Debug)]
+pub struct FrameStats {
+    pub frame_count: u64, // Frames drawn since the renderer was created
+    pub cpu_frame_time: Duration, // Wall time between the last two frames
+    pub gpu_time: Option<Duration>, // None until the first timestamps come back or if timestamps are unsupported
+    pub fps: f32, // Averaged over the last second
+    pub passes: Vec<PassTiming>, // Per pass GPU times from the same frame as gpu_time
+    pub draw_calls: usize,
+    pub drawn_objects: usize, // Objects that passed culling, each instance counts as one
+    pub culled_objects: usize, // Objects outside the camera frustum
+    pub occlusion_tests: usize, // Bounds tested by occlusion queries, their results arrive MAX_FRAMES_IN_FLIGHT frames later
+    pub occluded_objects: usize, // Objects skipped because earlier queries found them hidden
+    pub indirect_objects: usize, // Objects in the indirect batches drawn this frame, before GPU culling
+    pub indirect_visible: usize, // Indirect objects that passed GPU culling, read back MAX_FRAMES_IN_FLIGHT frames late
+    last_frame: Option<Instant>,
+    window_start: Instant,
+    window_frames: u32
+}
+
+impl FrameStats {
+    pub(crate) fn new() -> FrameStats {
+        FrameStats {
+            frame_count: 0,
+            cpu_frame_time: Duration::ZERO,
+            gpu_time: None,
+            fps: 0.0,
+            passes: Vec::new(),
+            draw_calls: 0,
+            drawn_objects: 0,
+            culled_objects: 0,
+            occlusion_tests: 0,
+            occluded_objects: 0,
+            indirect_objects: 0,
+            indirect_visible: 0,
+            last_frame: None,
+            window_start: Instant::now(),
+            window_frames: 0
+        }
+    }
+
+    pub(crate) fn begin_frame(&mut self) {
+        let now = Instant::now();
+        if let Some(last) = self.last_frame {
+            self.cpu_frame_time = now - last;
+        }
+        self.last_frame = Some(now);
+
+        self.frame_count += 1;
+        self.window_frames += 1;
+        let window = now - self.window_start;
+        if window >= FPS_WINDOW {
+            self.fps = self.window_frames as f32 / window.as_secs_f32();
+            self.window_start = now;
+            self.window_frames = 0;
+        }
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+
+use ash::vk;
+
+use crate::renderer::allocator::Allocator;
+use crate::renderer::camera::Camera;
+use crate::renderer::error::RendererError;
+use crate::renderer::frustum::Aabb;
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryStats;
+use crate::renderer::resources::ResourceManager;
+use crate::renderer::staging_buf::UploadContext;
+use crate::renderer::texture::{Texture, TextureHandle};
+
+// Levels are only dropped once a texture needs this much less than a whole level fewer, so one sitting near
+// a level's threshold doesn't load and drop it every few frames
+const EVICT_MARGIN: f32 = 0.5;
+
+// Loads stop this far under StreamingSettings::max_pressure, so the evictions pressure causes don't make
+// room for loads that push it straight back over
+const PRESSURE_MARGIN: f32 = 0.05;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StreamingSettings {
+    pub resident_size: u32, // Levels whose larger side is at most this many texels never leave the GPU
+    pub upload_budget: u64, // Bytes of mip levels uploaded per frame, one texture is updated each frame however big
+    pub evict_delay: u32, // Frames a texture has to need fewer levels for before they're dropped
+    pub max_pressure: f32, // Device local budget fraction, see MemoryStats::device_local_pressure. Past it unneeded levels are dropped right away.
+    pub bias: f32 // Added to the mip level each draw asks for, above 0 streams coarser levels
+}
+
+impl Default for StreamingSettings {
+    fn default() -> Self {
+        StreamingSettings {
+            resident_size: 64,
+            upload_budget: 16 << 20,
+            evict_delay: 120,
+            max_pressure: 0.9,
+            bias: 0.0
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StreamingStats {
+    pub textures: usize,
+    pub resident_bytes: u64, // Of the streamed textures' levels, on the GPU
+    pub full_bytes: u64, // Every level of every streamed texture, what's kept in system memory
+    pub uploaded_bytes: u64, // Last frame, including the coarser levels each update uploads again
+    pub loads: usize, // Textures that gained levels last frame
+    pub evictions: usize // Textures that dropped levels last frame
+}
+
+// A texture whose full mip chain is kept in system memory and whose GPU image only holds the levels from
+// resident down
+struct Streamed {
+    format: vk::Format,
+    width: u32, // Of level 0
+    height: u32,
+    levels: Vec<Vec<u8>>,
+    pinned: u32, // The finest level that never leaves the GPU
+    resident: u32, // The finest level on the GPU
+    wanted: f32, // Finest level any draw asked for this frame, infinite when none did
+    keep: u32, // Finest level asked for while idle
+    idle: u32 // Frames in a row with levels finer than needed resident
+}
+
+impl Streamed {
+    // Fractional mip levels rounded down to whole ones, never past the pinned level
+    fn level(&self, level: f32) -> u32 {
+        match level.is_finite() {
+            true => (level.max(0.0) as u32).min(self.pinned),
+            false if level < 0.0 => 0,
+            false => self.pinned
+        }
+    }
+
+    fn bytes(&self, from: u32) -> u64 {
+        self.levels[from as usize..].iter().map(|l| l.len() as u64).sum()
+    }
+}
+
+// The finest level whose larger side fits in resident_size, or the coarsest the chain has
+fn pinned_level(width: u32, height: u32, levels: usize, resident_size: u32) -> u32 {
+    let fits = (0..levels as u32).find(|&i| (width >> i).max(height >> i).max(1) <= resident_size);
+    fits.unwrap_or(levels as u32 - 1)
+}
+
+// Roughly how many pixels tall bounds are on screen, what a texture spread once over them needs. Infinite
+// with the camera inside them.
+pub(crate) fn screen_size(bounds: &Aabb, camera: &Camera, screen_height: u32) -> f32 {
+    let eye = camera.position;
+    let distance = eye.clamp(bounds.min, bounds.max).distance(eye);
+    let (_, radius) = bounds.bounding_sphere();
+    match distance > 0.0 {
+        true => radius / (distance * (camera.fov_y * 0.5).tan()) * screen_height as f32,
+        false => f32::INFINITY
+    }
+}
+
+// Streams the finer mip levels of large textures in while draws show them big enough to need them, and
+// back out once they don't or device memory runs short. Updates are copy based: a new image holding the
+// new range of levels replaces the texture's, which keeps its handle. Sparse residency would save
+// uploading the levels both images hold, but plenty of devices lack it.
+pub(crate) struct TextureStreamer {
+    textures: HashMap<TextureHandle, Streamed>,
+    settings: StreamingSettings,
+    stats: StreamingStats
+}
+
+impl TextureStreamer {
+    pub(crate) fn new(settings: StreamingSettings) -> TextureStreamer {
+        TextureStreamer {
+            textures: HashMap::new(),
+            settings,
+            stats: StreamingStats::default()
+        }
+    }
+
+    pub(crate) fn settings(&self) -> StreamingSettings {
+        self.settings
+    }
+
+    // Textures already streaming keep their levels until the next update moves them
+    pub(crate) fn set_settings(&mut self, settings: StreamingSettings) {
+        self.settings = settings;
+        for t in self.textures.values_mut() {
+            t.pinned = pinned_level(t.width, t.height, t.levels.len(), settings.resident_size);
+        }
+    }
+
+    pub(crate) fn stats(&self) -> StreamingStats {
+        StreamingStats {
+            textures: self.textures.len(),
+            resident_bytes: self.textures.values().map(|t| t.bytes(t.resident)).sum(),
+            full_bytes: self.textures.values().map(|t| t.bytes(0)).sum(),
+            ..self.stats
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.textures.is_empty()
+    }
+
+    // Uploads the pinned levels of a full mip chain in format. Callers check textures.has_free_slot first.
+    pub(crate) fn add(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                      resources: &mut ResourceManager, format: vk::Format, width: u32, height: u32,
+                      levels: Vec<Vec<u8>>) -> Result<TextureHandle, RendererError> {
+        let pinned = pinned_level(width, height, levels.len(), self.settings.resident_size);
+        let texture = Texture::levels(logical_layer, allocator, upload, format, (width >> pinned).max(1), (height >> pinned).max(1),
+                                      &levels[pinned as usize..])?;
+        let handle = resources.add_texture(logical_layer, texture);
+        self.textures.insert(handle, Streamed {
+            format,
+            width,
+            height,
+            levels,
+            pinned,
+            resident: pinned,
+            wanted: f32::INFINITY,
+            keep: pinned,
+            idle: 0
+        });
+
+        Ok(handle)
+    }
+
+    // Stops streaming the texture, I.E. once something else replaced its image
+    pub(crate) fn forget(&mut self, handle: TextureHandle) {
+        self.textures.remove(&handle);
+    }
+
+    // A draw showing the texture pixels tall this frame, see screen_size
+    pub(crate) fn request(&mut self, handle: TextureHandle, pixels: f32) {
+        if let Some(t) = self.textures.get_mut(&handle) {
+            let level = (t.width.max(t.height) as f32 / pixels).log2() + self.settings.bias;
+            t.wanted = t.wanted.min(level);
+        }
+    }
+
+    // Called once every draw this frame made its requests. Textures gain the levels asked for straight
+    // away while the budget allows, and lose ones they haven't needed for evict_delay frames. Replaced images
+    // are retired into frame's slot.
+    pub(crate) fn update(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                         resources: &mut ResourceManager, memory: &MemoryStats, frame: usize) -> Result<(), RendererError> {
+        self.textures.retain(|h, _| resources.textures.replaceable(*h)); // Removed since the last update
+        let pressure = memory.device_local_pressure();
+        let over_budget = pressure > self.settings.max_pressure;
+        let can_load = pressure < self.settings.max_pressure - PRESSURE_MARGIN;
+
+        let mut loads = Vec::new();
+        let mut evictions = Vec::new();
+        for (handle, t) in self.textures.iter_mut() {
+            let load = t.level(t.wanted);
+            let keep = t.level(t.wanted + EVICT_MARGIN);
+            t.wanted = f32::INFINITY;
+
+            if load < t.resident {
+                t.idle = 0;
+                if can_load {
+                    loads.push((*handle, load, t.resident - load));
+                }
+            } else if keep > t.resident {
+                t.keep = match t.idle {
+                    0 => keep,
+                    _ => t.keep.min(keep)
+                };
+                t.idle += 1;
+                if t.idle >= self.settings.evict_delay || over_budget {
+                    evictions.push((*handle, t.keep));
+                }
+            } else {
+                t.idle = 0;
+            }
+        }
+
+        // The blurriest first, then as many as the upload and memory budgets allow
+        loads.sort_by(|a, b| b.2.cmp(&a.2));
+        let mut available = memory.device_local_available();
+        self.stats.uploaded_bytes = 0;
+        self.stats.loads = 0;
+        self.stats.evictions = 0;
+        for (handle, level, _) in loads {
+            let bytes = self.textures[&handle].bytes(level);
+            let first = self.stats.uploaded_bytes == 0;
+            if (!first && self.stats.uploaded_bytes + bytes > self.settings.upload_budget) || bytes > available {
+                continue;
+            }
+            if !resources.textures.has_free_slot(false) {
+                break;
+            }
+            self.set_resident(logical_layer, allocator, upload, resources, handle, level, frame)?;
+            available -= bytes;
+            self.stats.loads += 1;
+        }
+        for (handle, level) in evictions {
+            if !resources.textures.has_free_slot(false) {
+                break;
+            }
+            self.set_resident(logical_layer, allocator, upload, resources, handle, level, frame)?;
+            self.stats.evictions += 1;
+        }
+
+        Ok(())
+    }
+
+    // Swaps the texture's image for one holding the levels from level down
+    fn set_resident(&mut self, logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                    resources: &mut ResourceManager, handle: TextureHandle, level: u32, frame: usize) -> Result<(), RendererError> {
+        let t = self.textures.get_mut(&handle).unwrap();
+        let texture = Texture::levels(logical_layer, allocator, upload, t.format, (t.width >> level).max(1), (t.height >> level).max(1),
+                                      &t.levels[level as usize..])?;
+        resources.replace_texture(logical_layer, handle, texture, frame);
+        self.stats.uploaded_bytes += t.bytes(level);
+        t.resident = level;
+        t.idle = 0;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::HashMap;
+
+use glam::{Vec2, Vec4};
+
+use crate::renderer::error::RendererError;
+use crate::renderer::overlay::OverlayVertex;
+use crate::renderer::texture::TextureHandle;
+
+const FIRST_CHAR: char = ' '; // Printable ASCII is baked, anything else is drawn as FALLBACK_CHAR
+const LAST_CHAR: char = '~';
+const FALLBACK_CHAR: char = '?';
+const ATLAS_WIDTH: usize = 512;
+const PADDING: usize = 1; // Keeps linear filtering from bleeding neighbouring glyphs in
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FontHandle(pub(crate) usize); // Index into the renderer's font list
+
+#[derive(Clone, Copy, Debug)]
+struct Glyph {
+    uv_min: Vec2,
+    uv_max: Vec2,
+    size: Vec2, // Pixels
+    offset: Vec2, // From the pen position on the baseline to the top left corner
+    advance: f32
+}
+
+// A TTF rasterized at one pixel size. The atlas is white with the glyph coverage in alpha, so the
+// overlay's vertex color tints it.
+pub(crate) struct FontAtlas {
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    pub(crate) pixels: Vec<u8>, // RGBA8
+    glyphs: HashMap<char, Glyph>,
+    ascent: f32,
+    line_height: f32
+}
+
+impl FontAtlas {
+    pub(crate) fn bake(ttf: &[u8], size: f32) -> Result<FontAtlas, RendererError> {
+        let font = fontdue::Font::from_bytes(ttf, fontdue::FontSettings::default())
+            .map_err(|e| RendererError::Font(e.to_string()))?;
+        let line_metrics = font.horizontal_line_metrics(size)
+            .ok_or_else(|| RendererError::Font("The font has no horizontal metrics".to_owned()))?;
+
+        // Shelf packing, glyphs go left to right and a new row starts when one doesn't fit
+        let rasterized: Vec<(char, fontdue::Metrics, Vec<u8>)> = (FIRST_CHAR..=LAST_CHAR)
+            .map(|c| {
+                let (metrics, coverage) = font.rasterize(c, size);
+                (c, metrics, coverage)
+            })
+            .collect();
+        let mut placements: Vec<(usize, usize)> = Vec::with_capacity(rasterized.len());
+        let (mut x, mut y, mut row_height) = (PADDING, PADDING, 0);
+        for (_, metrics, _) in rasterized.iter() {
+            if x + metrics.width + PADDING > ATLAS_WIDTH {
+                x = PADDING;
+                y += row_height + PADDING;
+                row_height = 0;
+            }
+            placements.push((x, y));
+            x += metrics.width + PADDING;
+            row_height = row_height.max(metrics.height);
+        }
+        let height = (y + row_height + PADDING).next_power_of_two();
+
+        let mut pixels = vec![0u8; ATLAS_WIDTH * height * 4];
+        let mut glyphs = HashMap::with_capacity(rasterized.len());
+        for ((c, metrics, coverage), (gx, gy)) in rasterized.iter().zip(placements) {
+            for row in 0..metrics.height {
+                for col in 0..metrics.width {
+                    let i = ((gy + row) * ATLAS_WIDTH + gx + col) * 4;
+                    pixels[i..i + 4].copy_from_slice(&[255, 255, 255, coverage[row * metrics.width + col]]);
+                }
+            }
+
+            let atlas_size = Vec2::new(ATLAS_WIDTH as f32, height as f32);
+            let size = Vec2::new(metrics.width as f32, metrics.height as f32);
+            let uv_min = Vec2::new(gx as f32, gy as f32) / atlas_size;
+            glyphs.insert(*c, Glyph {
+                uv_min,
+                uv_max: uv_min + size / atlas_size,
+                size,
+                offset: Vec2::new(metrics.xmin as f32, -(metrics.ymin as f32 + size.y)), // ymin is up from the baseline
+                advance: metrics.advance_width
+            });
+        }
+
+        Ok(FontAtlas {
+            width: ATLAS_WIDTH as u32,
+            height: height as u32,
+            pixels,
+            glyphs,
+            ascent: line_metrics.ascent,
+            line_height: line_metrics.new_line_size
+        })
+    }
+}
+
+pub(crate) struct Font {
+    pub(crate) texture: TextureHandle,
+    glyphs: HashMap<char, Glyph>,
+    ascent: f32,
+    line_height: f32
+}
+
+impl Font {
+    // The atlas pixels are dropped, they only live on in texture
+    pub(crate) fn new(atlas: FontAtlas, texture: TextureHandle) -> Font {
+        Font {
+            texture,
+            glyphs: atlas.glyphs,
+            ascent: atlas.ascent,
+            line_height: atlas.line_height
+        }
+    }
+
+    fn glyph(&self, c: char) -> &Glyph {
+        self.glyphs.get(&c).unwrap_or(&self.glyphs[&FALLBACK_CHAR])
+    }
+
+    // Calls quad with every visible glyph's top left corner and glyph. position is the top left of the
+    // first line, lines are split on '\n'.
+    fn layout<F>(&self, text: &str, position: Vec2, mut quad: F) -> Vec2
+        where F: FnMut(Vec2, &Glyph) {
+        let mut pen = position + Vec2::new(0.0, self.ascent);
+        let mut width: f32 = 0.0;
+        let mut lines = 1;
+        for c in text.chars() {
+            if c == '\n' {
+                width = width.max(pen.x - position.x);
+                pen = Vec2::new(position.x, pen.y + self.line_height);
+                lines += 1;
+                continue;
+            }
+            let glyph = self.glyph(c);
+            if glyph.size.x > 0.0 && glyph.size.y > 0.0 { // Spaces only advance
+                quad(pen + glyph.offset, glyph);
+            }
+            pen.x += glyph.advance;
+        }
+        width = width.max(pen.x - position.x);
+
+        Vec2::new(width, lines as f32 * self.line_height)
+    }
+
+    // Width of the widest line and height of all of them, in pixels
+    pub(crate) fn measure(&self, text: &str) -> Vec2 {
+        self.layout(text, Vec2::ZERO, |_, _| ())
+    }
+
+    // Two triangles per glyph, indices relative to the start of vertices
+    pub(crate) fn build(&self, text: &str, position: Vec2, color: Vec4, vertices: &mut Vec<OverlayVertex>, indices: &mut Vec<u32>) {
+        let color = color.to_array();
+        self.layout(text, position, |top_left, glyph| {
+            let base = vertices.len() as u32;
+            let bottom_right = top_left + glyph.size;
+            let corners = [
+                (top_left, glyph.uv_min),
+                (Vec2::new(bottom_right.x, top_left.y), Vec2::new(glyph.uv_max.x, glyph.uv_min.y)),
+                (bottom_right, glyph.uv_max),
+                (Vec2::new(top_left.x, bottom_right.y), Vec2::new(glyph.uv_min.x, glyph.uv_max.y))
+            ];
+            vertices.extend(corners.iter().map(|(pos, uv)| OverlayVertex {
+                pos: pos.to_array(),
+                uv: uv.to_array(),
+                color
+            }));
+            indices.extend([0, 1, 2, 2, 3, 0].iter().map(|i| base + i));
+        });
+    }
+}
@@ -0,0 +1,407 @@
+use std::mem;
+
+use ash::vk;
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::bindless::BindlessTextures;
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::resources::{Handle, Pool};
+use crate::renderer::staging_buf::UploadContext;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TextureHandle(pub(crate) Handle);
+
+impl TextureHandle {
+    pub const WHITE: TextureHandle = TextureHandle(Handle::first(0)); // 1x1, used by materials without a texture
+    pub const FLAT_NORMAL: TextureHandle = TextureHandle(Handle::first(1)); // 1x1 normal map pointing straight out of the surface
+    pub const WHITE_ARRAY: TextureHandle = TextureHandle(Handle::first(2)); // 1x1 with one layer, in texture array slot 0
+
+    fn builtin(self) -> bool {
+        self == TextureHandle::WHITE || self == TextureHandle::FLAT_NORMAL || self == TextureHandle::WHITE_ARRAY
+    }
+}
+
+// Block compressed texture formats, all with 4x4 texel blocks. Which ones the device can sample is listed by
+// CubulousRenderer::compressed_formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlockFormat {
+    Bc7, // Desktop GPUs
+    Astc4x4, // Most mobile GPUs
+    Etc2Rgb8, // Mobile GPUs without ASTC, no alpha
+    Etc2Rgba8
+}
+
+impl BlockFormat {
+    pub const ALL: [BlockFormat; 4] = [BlockFormat::Bc7, BlockFormat::Astc4x4, BlockFormat::Etc2Rgb8, BlockFormat::Etc2Rgba8];
+
+    pub fn block_bytes(self) -> usize {
+        match self {
+            BlockFormat::Etc2Rgb8 => 8,
+            _ => 16
+        }
+    }
+
+    // Bytes in a width x height level, partial blocks at the edges are stored whole
+    pub fn level_size(self, width: u32, height: u32) -> usize {
+        ((width as usize + 3) / 4) * ((height as usize + 3) / 4) * self.block_bytes()
+    }
+
+    pub(crate) fn vk(self, srgb: bool) -> vk::Format {
+        match (self, srgb) {
+            (BlockFormat::Bc7, true) => vk::Format::BC7_SRGB_BLOCK,
+            (BlockFormat::Bc7, false) => vk::Format::BC7_UNORM_BLOCK,
+            (BlockFormat::Astc4x4, true) => vk::Format::ASTC_4X4_SRGB_BLOCK,
+            (BlockFormat::Astc4x4, false) => vk::Format::ASTC_4X4_UNORM_BLOCK,
+            (BlockFormat::Etc2Rgb8, true) => vk::Format::ETC2_R8G8B8_SRGB_BLOCK,
+            (BlockFormat::Etc2Rgb8, false) => vk::Format::ETC2_R8G8B8_UNORM_BLOCK,
+            (BlockFormat::Etc2Rgba8, true) => vk::Format::ETC2_R8G8B8A8_SRGB_BLOCK,
+            (BlockFormat::Etc2Rgba8, false) => vk::Format::ETC2_R8G8B8A8_UNORM_BLOCK
+        }
+    }
+
+    // The block format and whether it's sRGB
+    pub(crate) fn from_vk(format: vk::Format) -> Option<(BlockFormat, bool)> {
+        BlockFormat::ALL.iter()
+            .flat_map(|&f| [(f, true), (f, false)])
+            .find(|&(f, srgb)| f.vk(srgb) == format)
+    }
+}
+
+// Block compressed pixels, I.E. from a KTX2 file. levels runs from the full size image down, each level half
+// the size of the previous one rounded down.
+#[derive(Clone, Debug)]
+pub struct CompressedImage {
+    pub width: u32,
+    pub height: u32,
+    pub format: BlockFormat,
+    pub srgb: bool,
+    pub levels: Vec<Vec<u8>>
+}
+
+// Sampled image, readable by shaders once the upload context has been flushed
+pub(crate) struct Texture {
+    image: vk::Image,
+    alloc: Allocation,
+    pub(crate) view: vk::ImageView,
+    pub(crate) slot: u32, // In the bindless array, assigned when Textures takes the texture
+    pub(crate) array: bool // A texture array, bound with the other arrays rather than the 2D textures
+}
+
+// Halves an RGBA8 image with a 2x2 box filter, odd edges repeat their last texel. Averages the stored
+// values even for sRGB, which darkens high contrast edges slightly but is close enough for block textures.
+fn downsample(pixels: &[u8], width: u32, height: u32) -> Vec<u8> {
+    let (w, h) = ((width / 2).max(1), (height / 2).max(1));
+    let texel = |x: u32, y: u32, c: usize| pixels[((y.min(height - 1) * width + x.min(width - 1)) * 4) as usize + c] as u32;
+    let mut out = Vec::with_capacity((w * h * 4) as usize);
+    for y in 0..h {
+        for x in 0..w {
+            for c in 0..4 {
+                let sum = texel(x * 2, y * 2, c) + texel(x * 2 + 1, y * 2, c) + texel(x * 2, y * 2 + 1, c) + texel(x * 2 + 1, y * 2 + 1, c);
+                out.push(((sum + 2) / 4) as u8);
+            }
+        }
+    }
+
+    out
+}
+
+// Every level of an RGBA8 image from the full size one down to 1x1
+pub(crate) fn mip_chain(pixels: &[u8], width: u32, height: u32) -> Vec<Vec<u8>> {
+    let mip_levels = 32 - width.max(height).leading_zeros();
+    let mut levels = vec![pixels.to_vec()];
+    for i in 1..mip_levels {
+        let previous = &levels[i as usize - 1];
+        let level = downsample(previous, (width >> (i - 1)).max(1), (height >> (i - 1)).max(1));
+        levels.push(level);
+    }
+
+    levels
+}
+
+impl Texture {
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                      width: u32, height: u32, pixels: &[u8], srgb: bool) -> Result<Texture, RendererError> {
+        assert_eq!(pixels.len(), (width * height * 4) as usize, "Expected tightly packed RGBA8 pixels");
+
+        let format = match srgb {
+            true => vk::Format::R8G8B8A8_SRGB, // Colors, the hardware converts to linear when sampling
+            false => vk::Format::R8G8B8A8_UNORM // Data, I.E. normal maps
+        };
+        let extent = vk::Extent3D { width, height, depth: 1 };
+
+        Texture::create(logical_layer, allocator, upload, format, extent, 1, None,
+                        |upload, image| upload.upload_image(logical_layer, allocator, pixels, image, extent, 1))
+    }
+
+    // One layer per image, each tightly packed RGBA8 pixels of the same size. The mip chain is generated
+    // down to 1x1 so distant faces don't shimmer.
+    pub(crate) fn array(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                        width: u32, height: u32, layers: &[&[u8]], srgb: bool) -> Result<Texture, RendererError> {
+        assert!(!layers.is_empty(), "Expected at least one layer");
+        for (i, pixels) in layers.iter().enumerate() {
+            assert_eq!(pixels.len(), (width * height * 4) as usize, "Layer {} isn't tightly packed RGBA8 pixels", i);
+        }
+
+        let format = match srgb {
+            true => vk::Format::R8G8B8A8_SRGB,
+            false => vk::Format::R8G8B8A8_UNORM
+        };
+        let extent = vk::Extent3D { width, height, depth: 1 };
+        let mip_levels = 32 - width.max(height).leading_zeros();
+
+        // Every layer of a level back to back, as upload_image_levels expects
+        let mut current: Vec<Vec<u8>> = layers.iter().map(|l| l.to_vec()).collect();
+        let mut levels = Vec::with_capacity(mip_levels as usize);
+        for i in 0..mip_levels {
+            levels.push(current.concat());
+            if i + 1 < mip_levels {
+                let (w, h) = ((width >> i).max(1), (height >> i).max(1));
+                current = current.iter().map(|l| downsample(l, w, h)).collect();
+            }
+        }
+
+        Texture::create(logical_layer, allocator, upload, format, extent, mip_levels, Some(layers.len() as u32),
+                        |upload, image| upload.upload_image_levels(logical_layer, allocator, &levels, image, extent, layers.len() as u32))
+    }
+
+    // The format must be in CubulousRenderer::compressed_formats
+    pub(crate) fn compressed(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext,
+                             image: &CompressedImage) -> Result<Texture, RendererError> {
+        assert!(!image.levels.is_empty(), "Expected at least one mip level");
+        for (i, level) in image.levels.iter().enumerate() {
+            let expected = image.format.level_size((image.width >> i).max(1), (image.height >> i).max(1));
+            assert_eq!(level.len(), expected, "Mip level {} isn't a whole number of {:?} blocks", i, image.format);
+        }
+
+        Texture::levels(logical_layer, allocator, upload, image.format.vk(image.srgb), image.width, image.height, &image.levels)
+    }
+
+    // A mip chain already in format, levels[0] being width x height. Streamed textures upload the tail of
+    // their full chain this way.
+    pub(crate) fn levels(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext, format: vk::Format,
+                         width: u32, height: u32, levels: &[Vec<u8>]) -> Result<Texture, RendererError> {
+        let extent = vk::Extent3D { width, height, depth: 1 };
+        Texture::create(logical_layer, allocator, upload, format, extent, levels.len() as u32, None,
+                        |upload, dst| upload.upload_image_levels(logical_layer, allocator, levels, dst, extent, 1))
+    }
+
+    // Creates the image and its view, record copies the pixels into the image. array_layers is None for a
+    // plain 2D texture, otherwise the image is viewed as a texture array.
+    fn create(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext, format: vk::Format,
+              extent: vk::Extent3D, mip_levels: u32, array_layers: Option<u32>,
+              record: impl FnOnce(&mut UploadContext, vk::Image) -> Result<(), RendererError>) -> Result<Texture, RendererError> {
+        let layer_count = array_layers.unwrap_or(1);
+        let create_info = vk::ImageCreateInfo::default()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(format)
+            .extent(extent)
+            .mip_levels(mip_levels)
+            .array_layers(layer_count)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE)
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+        let (alloc, image) = allocator.create_image(logical_layer, &create_info, vk::MemoryPropertyFlags::DEVICE_LOCAL,
+                                                    MemoryCategory::Textures)?;
+
+        if let Err(e) = record(upload, image) {
+            allocator.destroy_image(logical_layer, image, &alloc);
+            return Err(e);
+        }
+
+        let view_create_info = vk::ImageViewCreateInfo::default()
+            .image(image)
+            .view_type(match array_layers {
+                Some(_) => vk::ImageViewType::TYPE_2D_ARRAY,
+                None => vk::ImageViewType::TYPE_2D
+            })
+            .format(format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: mip_levels,
+                base_array_layer: 0,
+                layer_count
+            });
+        let view = match unsafe { logical_layer.logical_device.create_image_view(&view_create_info, None) } {
+            Ok(v) => v,
+            Err(e) => {
+                // The copy is already recorded, so the image has to outlive the upload
+                upload.flush(logical_layer)?;
+                logical_layer.wait_idle();
+                allocator.destroy_image(logical_layer, image, &alloc);
+                return Err(vk_error("vkCreateImageView")(e));
+            }
+        };
+
+        Ok(Texture {
+            image,
+            alloc,
+            view,
+            slot: 0,
+            array: array_layers.is_some()
+        })
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        unsafe { logical_layer.logical_device.destroy_image_view(self.view, None) };
+        allocator.destroy_image(logical_layer, self.image, &self.alloc);
+    }
+}
+
+// Every texture the renderer owns plus the samplers they're read with. Each texture has a slot in the
+// bindless array for as long as it's alive, texture arrays in the bindless array of arrays.
+pub(crate) struct Textures {
+    textures: Pool<Texture>,
+    pub(crate) sampler: vk::Sampler,
+    pixel_sampler: vk::Sampler, // Nearest texels and mip levels, for texture arrays of pixel art block faces
+    pub(crate) bindless: BindlessTextures
+}
+
+impl Textures {
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext) -> Result<Textures, RendererError> {
+        let create_info = vk::SamplerCreateInfo::default()
+            .mag_filter(vk::Filter::LINEAR)
+            .min_filter(vk::Filter::LINEAR)
+            .mipmap_mode(vk::SamplerMipmapMode::LINEAR)
+            .address_mode_u(vk::SamplerAddressMode::REPEAT) // Tiling UVs, I.E. greedy meshed voxel faces
+            .address_mode_v(vk::SamplerAddressMode::REPEAT)
+            .address_mode_w(vk::SamplerAddressMode::REPEAT)
+            .max_lod(vk::LOD_CLAMP_NONE);
+        let sampler = unsafe {
+            logical_layer.logical_device.create_sampler(&create_info, None).map_err(vk_error("vkCreateSampler"))?
+        };
+
+        // Blending texels or mip levels smears pixel art, nearest keeps texels crisp up close and picks the
+        // level that doesn't alias in the distance
+        let pixel_create_info = vk::SamplerCreateInfo::default()
+            .mag_filter(vk::Filter::NEAREST)
+            .min_filter(vk::Filter::NEAREST)
+            .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+            .address_mode_u(vk::SamplerAddressMode::REPEAT)
+            .address_mode_v(vk::SamplerAddressMode::REPEAT)
+            .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+            .max_lod(vk::LOD_CLAMP_NONE);
+        let pixel_sampler = match unsafe { logical_layer.logical_device.create_sampler(&pixel_create_info, None) } {
+            Ok(s) => s,
+            Err(e) => {
+                unsafe { logical_layer.logical_device.destroy_sampler(sampler, None) };
+                return Err(vk_error("vkCreateSampler")(e));
+            }
+        };
+
+        let bindless = match BindlessTextures::new(logical_layer, sampler, pixel_sampler) {
+            Ok(b) => b,
+            Err(e) => {
+                unsafe {
+                    logical_layer.logical_device.destroy_sampler(pixel_sampler, None);
+                    logical_layer.logical_device.destroy_sampler(sampler, None);
+                }
+                return Err(e);
+            }
+        };
+
+        let mut textures = Textures {
+            textures: Pool::new(),
+            sampler,
+            pixel_sampler,
+            bindless
+        };
+        let builtins: [(&[u8], bool); 2] = [(&[255, 255, 255, 255], true), (&[128, 128, 255, 255], false)]; // WHITE, FLAT_NORMAL
+        for (pixels, srgb) in builtins {
+            match Texture::new(logical_layer, allocator, upload, 1, 1, pixels, srgb) {
+                Ok(t) => {
+                    textures.add(logical_layer, t);
+                },
+                Err(e) => {
+                    textures.destroy(logical_layer, allocator);
+                    return Err(e);
+                }
+            }
+        }
+        match Texture::array(logical_layer, allocator, upload, 1, 1, &[&[255, 255, 255, 255]], true) { // WHITE_ARRAY
+            Ok(t) => {
+                textures.add(logical_layer, t);
+            },
+            Err(e) => {
+                textures.destroy(logical_layer, allocator);
+                return Err(e);
+            }
+        }
+
+        Ok(textures)
+    }
+
+    // Callers check has_free_slot first
+    pub(crate) fn add(&mut self, logical_layer: &LogicalLayer, mut texture: Texture) -> TextureHandle {
+        texture.slot = self.bindless.add(logical_layer, texture.view, texture.array);
+        TextureHandle(self.textures.insert(texture))
+    }
+
+    // array for texture arrays, which have slots of their own
+    pub(crate) fn has_free_slot(&self, array: bool) -> bool {
+        self.bindless.has_free_slot(array)
+    }
+
+    // The bindless slot shaders sample the texture through, released textures read as WHITE. Texture
+    // arrays are sampled through their slot in the array binding.
+    pub(crate) fn slot(&self, handle: TextureHandle) -> u32 {
+        self.get(handle).slot
+    }
+
+    // Hands back the old texture, which keeps its slot until it's destroyed since frames in flight may still
+    // sample it. The handle must be replaceable and a slot free.
+    pub(crate) fn replace(&mut self, logical_layer: &LogicalLayer, handle: TextureHandle, mut texture: Texture) -> Texture {
+        assert!(self.replaceable(handle), "Replaced a removed or builtin texture");
+        assert_eq!(self.is_array(handle), texture.array, "Replaced a texture with a texture array or the other way around");
+        texture.slot = self.bindless.add(logical_layer, texture.view, texture.array);
+        mem::replace(self.textures.get_mut(handle.0).unwrap(), texture)
+    }
+
+    // Once the frames that could sample the texture have finished
+    pub(crate) fn free_slot(&mut self, slot: u32, array: bool) {
+        self.bindless.free(slot, array);
+    }
+
+    // Released textures read as WHITE
+    pub(crate) fn get(&self, handle: TextureHandle) -> &Texture {
+        self.textures.get(handle.0)
+            .or_else(|| self.textures.get(TextureHandle::WHITE.0))
+            .unwrap()
+    }
+
+    // False for released textures and the builtins
+    pub(crate) fn replaceable(&self, handle: TextureHandle) -> bool {
+        !handle.builtin() && self.textures.get(handle.0).is_some()
+    }
+
+    pub(crate) fn is_array(&self, handle: TextureHandle) -> bool {
+        self.get(handle).array
+    }
+
+    pub(crate) fn retain(&mut self, handle: TextureHandle) -> bool {
+        self.textures.retain(handle.0)
+    }
+
+    // The builtins stay put however often they're released
+    pub(crate) fn release(&mut self, handle: TextureHandle) -> Option<Texture> {
+        match handle.builtin() {
+            true => None,
+            false => self.textures.release(handle.0)
+        }
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        for t in self.textures.iter() {
+            t.destroy(logical_layer, allocator);
+        }
+        self.bindless.destroy(logical_layer);
+        unsafe {
+            logical_layer.logical_device.destroy_sampler(self.pixel_sampler, None);
+            logical_layer.logical_device.destroy_sampler(self.sampler, None);
+        }
+    }
+}
@@ -0,0 +1,56 @@
+use ash::vk;
+
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+
+// A timeline semaphore and the last value handed out for signalling. Submissions signal increasing values
+// and the host or other queues wait for a value to be reached, so one semaphore replaces a fence and
+// semaphore per submission. Values are never reset.
+pub(crate) struct Timeline {
+    pub(crate) semaphore: vk::Semaphore,
+    value: u64 // Last value returned by next, the semaphore starts at 0
+}
+
+impl Timeline {
+    pub(crate) fn new(logical_layer: &LogicalLayer) -> Result<Timeline, RendererError> {
+        let mut type_info = vk::SemaphoreTypeCreateInfo::default()
+            .semaphore_type(vk::SemaphoreType::TIMELINE)
+            .initial_value(0);
+        let create_info = vk::SemaphoreCreateInfo::default()
+            .push_next(&mut type_info);
+        let semaphore = unsafe {
+            logical_layer.logical_device.create_semaphore(&create_info, None).map_err(vk_error("vkCreateSemaphore"))?
+        };
+
+        Ok(Timeline {
+            semaphore,
+            value: 0
+        })
+    }
+
+    // The value for the next submission to signal
+    pub(crate) fn next(&mut self) -> u64 {
+        self.value += 1;
+        self.value
+    }
+
+    // The last value handed out, reached once everything submitted so far has finished
+    pub(crate) fn value(&self) -> u64 {
+        self.value
+    }
+
+    // Blocks until the semaphore reaches value. 0 never blocks.
+    pub(crate) fn wait(&self, logical_layer: &LogicalLayer, value: u64) -> Result<(), RendererError> {
+        let semaphores = [self.semaphore];
+        let values = [value];
+        let wait_info = vk::SemaphoreWaitInfo::default()
+            .semaphores(&semaphores)
+            .values(&values);
+
+        unsafe { logical_layer.logical_device.wait_semaphores(&wait_info, u64::MAX).map_err(vk_error("vkWaitSemaphores")) }
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer) {
+        unsafe { logical_layer.logical_device.destroy_semaphore(self.semaphore, None) };
+    }
+}
@@ -0,0 +1,143 @@
+use std::time::Duration;
+
+use ash::vk;
+
+use crate::renderer::core::Core;
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::physical_layer::PhysicalLayer;
+use crate::renderer::stats::PassTiming;
+
+const MAX_SCOPES_PER_FRAME: u32 = 16;
+const QUERIES_PER_FRAME: u32 = 2 + 2 * MAX_SCOPES_PER_FRAME; // Frame start and end, then a start and end per scope
+
+// GPU timestamps written around each frame's commands and around named scopes, I.E. render passes, within
+// it. Every frame in flight has its own queries, which are read back once that frame's timeline value has been
+// waited on, so results lag by MAX_FRAMES_IN_FLIGHT frames.
+pub(crate) struct TimestampPool {
+    query_pool: vk::QueryPool,
+    period_ns: f64, // Nanoseconds per timestamp tick
+    valid_mask: u64, // Bits beyond timestampValidBits are undefined
+    written: Vec<bool>, // Whether each frame's queries have been written since creation
+    scopes: Vec<Vec<&'static str>>, // Names of the scopes written for each frame, in query order
+    open_scope: Option<u32> // Scope waiting on end_scope, None if it was dropped
+}
+
+impl TimestampPool {
+    // None if the graphics queue can't write timestamps
+    pub(crate) fn new(core: &Core, physical_layer: &PhysicalLayer, logical_layer: &LogicalLayer,
+                      frame_count: usize) -> Result<Option<TimestampPool>, RendererError> {
+        let (properties, queue_families) = unsafe {
+            (core.instance.get_physical_device_properties(physical_layer.physical_device),
+             core.instance.get_physical_device_queue_family_properties(physical_layer.physical_device))
+        };
+
+        let valid_bits = queue_families[physical_layer.family_index as usize].timestamp_valid_bits;
+        if valid_bits == 0 {
+            return Ok(None);
+        }
+
+        let create_info = vk::QueryPoolCreateInfo::default()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(QUERIES_PER_FRAME * frame_count as u32);
+        let query_pool = unsafe {
+            logical_layer.logical_device.create_query_pool(&create_info, None).map_err(vk_error("vkCreateQueryPool"))?
+        };
+
+        Ok(Some(TimestampPool {
+            query_pool,
+            period_ns: properties.limits.timestamp_period as f64,
+            valid_mask: match valid_bits {
+                64 => u64::MAX,
+                b => (1 << b) - 1
+            },
+            written: vec![false; frame_count],
+            scopes: vec![Vec::new(); frame_count],
+            open_scope: None
+        }))
+    }
+
+    // Must be recorded outside of a render pass
+    pub(crate) fn begin(&mut self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame: usize) {
+        let first = frame as u32 * QUERIES_PER_FRAME;
+        unsafe {
+            logical_layer.logical_device.cmd_reset_query_pool(command_buffer, self.query_pool, first, QUERIES_PER_FRAME);
+            logical_layer.logical_device.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE,
+                                                             self.query_pool, first);
+        }
+        self.scopes[frame].clear();
+    }
+
+    // Scopes can't nest, scopes past MAX_SCOPES_PER_FRAME are silently dropped
+    pub(crate) fn begin_scope(&mut self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame: usize,
+                              name: &'static str) {
+        let scope = self.scopes[frame].len() as u32;
+        self.open_scope = None;
+        if scope >= MAX_SCOPES_PER_FRAME {
+            return;
+        }
+
+        unsafe {
+            logical_layer.logical_device.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::TOP_OF_PIPE,
+                                                             self.query_pool, frame as u32 * QUERIES_PER_FRAME + 2 + 2 * scope);
+        }
+        self.scopes[frame].push(name);
+        self.open_scope = Some(scope);
+    }
+
+    pub(crate) fn end_scope(&mut self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame: usize) {
+        let scope = match self.open_scope.take() {
+            Some(s) => s,
+            None => return
+        };
+
+        unsafe {
+            logical_layer.logical_device.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                                                             self.query_pool, frame as u32 * QUERIES_PER_FRAME + 3 + 2 * scope);
+        }
+    }
+
+    pub(crate) fn end(&mut self, logical_layer: &LogicalLayer, command_buffer: vk::CommandBuffer, frame: usize) {
+        let first = frame as u32 * QUERIES_PER_FRAME;
+        unsafe {
+            logical_layer.logical_device.cmd_write_timestamp(command_buffer, vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                                                             self.query_pool, first + 1);
+        }
+        self.written[frame] = true;
+    }
+
+    fn duration(&self, start: u64, end: u64) -> Duration {
+        let elapsed = (end & self.valid_mask).wrapping_sub(start & self.valid_mask) & self.valid_mask;
+        Duration::from_nanos((elapsed as f64 * self.period_ns) as u64)
+    }
+
+    // Whole frame time and each scope's time. Only valid after the frame's timeline value has been reached.
+    pub(crate) fn read(&self, logical_layer: &LogicalLayer, frame: usize) -> Option<(Duration, Vec<PassTiming>)> {
+        if !self.written[frame] {
+            return None;
+        }
+
+        let names = &self.scopes[frame];
+        let mut ticks = vec![0u64; 2 + 2 * names.len()]; // Only the queries that were written
+        unsafe {
+            logical_layer.logical_device.get_query_pool_results(self.query_pool,
+                                                                frame as u32 * QUERIES_PER_FRAME,
+                                                                &mut ticks,
+                                                                vk::QueryResultFlags::TYPE_64).ok()?;
+        }
+
+        let passes = names.iter()
+            .enumerate()
+            .map(|(i, name)| PassTiming {
+                name: *name,
+                duration: self.duration(ticks[2 + 2 * i], ticks[3 + 2 * i])
+            })
+            .collect();
+
+        Some((self.duration(ticks[0], ticks[1]), passes))
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer) {
+        unsafe { logical_layer.logical_device.destroy_query_pool(self.query_pool, None) };
+    }
+}
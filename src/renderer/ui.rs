@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use ash::vk;
+use winit::event::{ElementState, WindowEvent};
+use winit::event_loop::EventLoop;
+use winit::window::Window;
+
+use crate::renderer::overlay::OverlayVertex;
+use crate::renderer::texture::TextureHandle;
+
+// An egui texture's pixels, kept so partial updates can be patched in and the whole image re-uploaded
+struct UiImage {
+    size: [usize; 2],
+    pixels: Vec<u8>, // RGBA8, sRGB and not premultiplied
+    texture: Option<TextureHandle> // None until the renderer uploads the current pixels
+}
+
+// egui's state between frames and the translation of its output into overlay geometry. The UI is
+// built between begin() and end() each frame, then drawn over everything else, text included.
+pub(crate) struct Ui {
+    pub(crate) ctx: egui::Context,
+    winit_state: Option<egui_winit::State>, // None when headless, the UI still draws but gets no input
+    images: HashMap<egui::TextureId, UiImage>,
+    freed: Vec<TextureHandle>, // Uploads of images egui dropped, released by the renderer
+    shapes: Vec<egui::epaint::ClippedShape> // From the last end(), tessellated by the renderer
+}
+
+impl Ui {
+    pub(crate) fn new(event_loop: Option<&EventLoop<()>>, window: Option<&Window>) -> Ui {
+        let winit_state = event_loop.zip(window).map(|(event_loop, window)| {
+            let mut state = egui_winit::State::new(event_loop);
+            state.set_pixels_per_point(window.scale_factor() as f32);
+            state
+        });
+
+        Ui {
+            ctx: egui::Context::default(),
+            winit_state,
+            images: HashMap::new(),
+            freed: Vec::new(),
+            shapes: Vec::new()
+        }
+    }
+
+    // Returns true when egui used the event, I.E. typing into a text box, so the game should ignore it
+    pub(crate) fn handle_window_event(&mut self, event: &WindowEvent) -> bool {
+        match self.winit_state.as_mut() {
+            Some(state) => state.on_event(&self.ctx, event).consumed,
+            None => false
+        }
+    }
+
+    pub(crate) fn begin(&mut self, window: Option<&Window>) {
+        let raw_input = match (self.winit_state.as_mut(), window) {
+            (Some(state), Some(window)) => state.take_egui_input(window),
+            _ => egui::RawInput::default()
+        };
+        self.ctx.begin_frame(raw_input);
+    }
+
+    // Finishes the frame's UI. Textures egui created or changed are left for textures_to_upload().
+    pub(crate) fn end(&mut self, window: Option<&Window>) {
+        let output = self.ctx.end_frame();
+        if let (Some(state), Some(window)) = (self.winit_state.as_mut(), window) {
+            state.handle_platform_output(window, &self.ctx, output.platform_output);
+        }
+
+        for (id, delta) in output.textures_delta.set {
+            self.apply_delta(id, &delta);
+        }
+        for id in output.textures_delta.free {
+            if let Some(texture) = self.images.remove(&id).and_then(|i| i.texture) {
+                self.freed.push(texture);
+            }
+        }
+        self.shapes = output.shapes;
+    }
+
+    fn apply_delta(&mut self, id: egui::TextureId, delta: &egui::epaint::ImageDelta) {
+        let size = delta.image.size();
+        let pixels: Vec<u8> = match &delta.image {
+            egui::ImageData::Color(image) => image.pixels.iter()
+                .flat_map(|c| c.to_srgba_unmultiplied())
+                .collect(),
+            // Same layout as baked fonts, white with the coverage in alpha
+            egui::ImageData::Font(image) => image.pixels.iter()
+                .flat_map(|coverage| [255, 255, 255, (coverage.clamp(0.0, 1.0) * 255.0).round() as u8])
+                .collect()
+        };
+
+        match (delta.pos, self.images.get_mut(&id)) {
+            (Some([x, y]), Some(image)) => {
+                for row in 0..size[1] {
+                    let src = row * size[0] * 4;
+                    let dst = ((y + row) * image.size[0] + x) * 4;
+                    image.pixels[dst..dst + size[0] * 4].copy_from_slice(&pixels[src..src + size[0] * 4]);
+                }
+                self.freed.extend(image.texture.take()); // Uploaded again as a whole
+            },
+            (Some(_), None) => log::warn!("egui updated part of unknown texture {:?}", id),
+            (None, _) => {
+                let replaced = self.images.insert(id, UiImage { size, pixels, texture: None });
+                self.freed.extend(replaced.and_then(|i| i.texture));
+            }
+        }
+    }
+
+    // Images whose current pixels haven't been uploaded yet, as (id, width, height, pixels)
+    pub(crate) fn textures_to_upload(&self) -> Vec<(egui::TextureId, u32, u32, &[u8])> {
+        self.images.iter()
+            .filter(|(_, image)| image.texture.is_none())
+            .map(|(id, image)| (*id, image.size[0] as u32, image.size[1] as u32, image.pixels.as_slice()))
+            .collect()
+    }
+
+    pub(crate) fn set_texture(&mut self, id: egui::TextureId, texture: TextureHandle) {
+        if let Some(image) = self.images.get_mut(&id) {
+            image.texture = Some(texture);
+        }
+    }
+
+    pub(crate) fn take_freed_textures(&mut self) -> Vec<TextureHandle> {
+        std::mem::take(&mut self.freed)
+    }
+
+    // Tessellates the last frame's UI into calls to push(texture, clip, vertices, indices), with
+    // positions and clip rects in pixels. Meshes using unknown textures are skipped.
+    pub(crate) fn tessellate<F>(&mut self, extent: vk::Extent2D, mut push: F)
+        where F: FnMut(TextureHandle, vk::Rect2D, &[OverlayVertex], &[u32]) {
+        let shapes = std::mem::take(&mut self.shapes);
+        let pixels_per_point = self.ctx.pixels_per_point();
+        let mut vertices = Vec::new();
+
+        for primitive in self.ctx.tessellate(shapes) {
+            let mesh = match primitive.primitive {
+                egui::epaint::Primitive::Mesh(mesh) => mesh,
+                egui::epaint::Primitive::Callback(_) => continue // Only egui's own painting is supported
+            };
+            let texture = match self.images.get(&mesh.texture_id).and_then(|i| i.texture) {
+                Some(t) => t,
+                None => continue
+            };
+
+            // Rounded outwards and kept on screen, Vulkan rejects negative scissor offsets
+            let clip = primitive.clip_rect;
+            let min_x = (clip.min.x * pixels_per_point).floor().clamp(0.0, extent.width as f32) as u32;
+            let min_y = (clip.min.y * pixels_per_point).floor().clamp(0.0, extent.height as f32) as u32;
+            let max_x = (clip.max.x * pixels_per_point).ceil().clamp(min_x as f32, extent.width as f32) as u32;
+            let max_y = (clip.max.y * pixels_per_point).ceil().clamp(min_y as f32, extent.height as f32) as u32;
+            if max_x == min_x || max_y == min_y {
+                continue;
+            }
+            let scissor = vk::Rect2D {
+                offset: vk::Offset2D { x: min_x as i32, y: min_y as i32 },
+                extent: vk::Extent2D { width: max_x - min_x, height: max_y - min_y }
+            };
+
+            vertices.clear();
+            vertices.extend(mesh.vertices.iter().map(|v| {
+                // egui colors are premultiplied sRGB, the overlay shader wants straight linear colors
+                let linear = egui::Rgba::from(v.color);
+                let alpha = linear.a();
+                let color = if alpha > 0.0 {
+                    [linear.r() / alpha, linear.g() / alpha, linear.b() / alpha, alpha]
+                } else {
+                    [0.0; 4]
+                };
+                OverlayVertex {
+                    pos: [v.pos.x * pixels_per_point, v.pos.y * pixels_per_point],
+                    uv: [v.uv.x, v.uv.y],
+                    color
+                }
+            }));
+            push(texture, scissor, &vertices, &mesh.indices);
+        }
+    }
+}
+
+// Button and key releases, which the game's input always gets
+pub(crate) fn is_release(event: &WindowEvent) -> bool {
+    match event {
+        WindowEvent::KeyboardInput { input, .. } => input.state == ElementState::Released,
+        WindowEvent::MouseInput { state, .. } => *state == ElementState::Released,
+        _ => false
+    }
+}
@@ -0,0 +1,176 @@
+use std::mem;
+
+use ash::vk;
+use glam::{Mat4, Vec4};
+
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::decal::{GpuDecal, MAX_VISIBLE_DECALS};
+use crate::renderer::error::{vk_error, RendererError};
+use crate::renderer::light::{GpuLight, MAX_LIGHTS};
+use crate::renderer::logical_layer::LogicalLayer;
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::meshlet::mesh_stages;
+use crate::renderer::shadow::MAX_SHADOW_CASTERS;
+
+#[repr(C)]
+#[derive(Clone, Debug, Copy)]
+pub(crate) struct UniformBufferObject {
+    pub view: Mat4,
+    pub proj: Mat4,
+    pub camera_pos: Vec4, // W unused, for specular highlights
+    pub ambient: Vec4, // RGB added to every lit surface, W unused. The sky color for hemisphere lighting
+    pub ambient_ground: Vec4,
+    pub light_count: [u32; 4], // Only X is used, padded for std140
+    pub lights: [GpuLight; MAX_LIGHTS],
+    pub shadow_view_proj: [Mat4; MAX_SHADOW_CASTERS], // Indexed by the lights' shadow layers
+    pub shadow_params: Vec4, // X is the shadow map texel size in UV, Y the normal offset in world units
+    pub fog_color: Vec4, // W is the falloff, 0 without fog, 1 linear, 2 exponential and 3 exponential squared
+    pub fog_params: Vec4, // X and Y are the linear start and end, Z the density and W the camera far plane
+    pub decal_count: [u32; 4], // Only X is used, padded for std140
+    pub decals: [GpuDecal; MAX_VISIBLE_DECALS]
+}
+
+impl Default for UniformBufferObject {
+    fn default() -> Self {
+        UniformBufferObject {
+            view: Mat4::IDENTITY,
+            proj: Mat4::IDENTITY,
+            camera_pos: Vec4::ZERO,
+            ambient: Vec4::ZERO,
+            ambient_ground: Vec4::ZERO,
+            light_count: [0; 4],
+            lights: [GpuLight::default(); MAX_LIGHTS],
+            shadow_view_proj: [Mat4::IDENTITY; MAX_SHADOW_CASTERS],
+            shadow_params: Vec4::ZERO,
+            fog_color: Vec4::ZERO,
+            fog_params: Vec4::ZERO,
+            decal_count: [0; 4],
+            decals: [GpuDecal::default(); MAX_VISIBLE_DECALS]
+        }
+    }
+}
+
+pub(crate) struct UniformBuffer {
+    bufs: Vec<vk::Buffer>, // One buffer per frame in flight so the CPU never writes to a buffer the GPU is reading
+    allocs: Vec<Allocation>,
+    mapped: Vec<*mut UniformBufferObject>, // Persistently mapped, written every frame
+    pub(crate) descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    pub(crate) descriptor_sets: Vec<vk::DescriptorSet>
+}
+
+impl UniformBuffer {
+    pub(crate) fn new(logical_layer: &LogicalLayer, allocator: &Allocator, frame_count: usize) -> Result<UniformBuffer, RendererError> {
+        fn setup_descriptor_set_layout(logical_layer: &LogicalLayer) -> Result<vk::DescriptorSetLayout, RendererError> {
+            // The ray tracing pass shades reflections with the same lights
+            let ray_tracing_stages = match logical_layer.ray_tracing() {
+                true => vk::ShaderStageFlags::RAYGEN_KHR | vk::ShaderStageFlags::MISS_KHR | vk::ShaderStageFlags::CLOSEST_HIT_KHR,
+                false => vk::ShaderStageFlags::empty()
+            };
+            let ubo_binding = vk::DescriptorSetLayoutBinding::default()
+                .binding(0) // Matches layout(binding = 0) in the shader
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(1) // More than 1 for arrays of uniforms, I.E. per bone transforms
+                .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT | // Lights are read per fragment
+                    ray_tracing_stages | mesh_stages(logical_layer));
+
+            let bindings = [ubo_binding];
+
+            let create_info = vk::DescriptorSetLayoutCreateInfo::default()
+                .bindings(&bindings);
+
+            unsafe {
+                logical_layer.logical_device.create_descriptor_set_layout(&create_info, None)
+                    .map_err(vk_error("vkCreateDescriptorSetLayout"))
+            }
+        }
+
+        fn setup_descriptor_pool(logical_layer: &LogicalLayer, frame_count: usize) -> Result<vk::DescriptorPool, RendererError> {
+            let pool_sizes = [vk::DescriptorPoolSize::default()
+                .ty(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(frame_count as u32)];
+
+            let create_info = vk::DescriptorPoolCreateInfo::default()
+                .pool_sizes(&pool_sizes)
+                .max_sets(frame_count as u32);
+
+            unsafe {
+                logical_layer.logical_device.create_descriptor_pool(&create_info, None)
+                    .map_err(vk_error("vkCreateDescriptorPool"))
+            }
+        }
+
+        let data_size = mem::size_of::<UniformBufferObject>() as vk::DeviceSize;
+
+        let mut bufs: Vec<vk::Buffer> = Vec::with_capacity(frame_count);
+        let mut allocs: Vec<Allocation> = Vec::with_capacity(frame_count);
+        let mut mapped: Vec<*mut UniformBufferObject> = Vec::with_capacity(frame_count);
+
+        for _ in 0..frame_count {
+            let (alloc, buf) = allocator.create_buffer(logical_layer,
+                                                       data_size,
+                                                       vk::BufferUsageFlags::UNIFORM_BUFFER,
+                                                       vk::MemoryPropertyFlags::HOST_VISIBLE |
+                                                           vk::MemoryPropertyFlags::HOST_COHERENT, // No explicit flushes needed
+                                                       MemoryCategory::Buffers)?;
+
+            let ptr = alloc.mapped_ptr().unwrap() as *mut UniformBufferObject; // Mapped for as long as the allocation lives
+            unsafe { ptr.write(UniformBufferObject::default()) };
+
+            bufs.push(buf);
+            allocs.push(alloc);
+            mapped.push(ptr);
+        }
+
+        let descriptor_set_layout = setup_descriptor_set_layout(logical_layer)?;
+        let descriptor_pool = setup_descriptor_pool(logical_layer, frame_count)?;
+
+        let layouts = vec![descriptor_set_layout; frame_count];
+        let alloc_info = vk::DescriptorSetAllocateInfo::default()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&layouts);
+        let descriptor_sets = unsafe {
+            logical_layer.logical_device.allocate_descriptor_sets(&alloc_info).map_err(vk_error("vkAllocateDescriptorSets"))?
+        };
+
+        // Point each descriptor set at its frame's buffer
+        for (set, buf) in descriptor_sets.iter().zip(bufs.iter()) {
+            let buffer_infos = [vk::DescriptorBufferInfo::default()
+                .buffer(*buf)
+                .offset(0)
+                .range(data_size)];
+
+            let write = vk::WriteDescriptorSet::default()
+                .dst_set(*set)
+                .dst_binding(0)
+                .dst_array_element(0)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .buffer_info(&buffer_infos);
+
+            unsafe { logical_layer.logical_device.update_descriptor_sets(&[write], &[]) };
+        }
+
+        Ok(UniformBuffer {
+            bufs,
+            allocs,
+            mapped,
+            descriptor_set_layout,
+            descriptor_pool,
+            descriptor_sets
+        })
+    }
+
+    pub(crate) fn update(&self, frame: usize, ubo: &UniformBufferObject) {
+        unsafe { self.mapped[frame].write(*ubo) };
+    }
+
+    pub(crate) fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        unsafe {
+            logical_layer.logical_device.destroy_descriptor_pool(self.descriptor_pool, None); // Frees the sets as well
+            logical_layer.logical_device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+        }
+        for (buf, alloc) in self.bufs.iter().zip(self.allocs.iter()) {
+            allocator.destroy_buffer(logical_layer, *buf, alloc);
+        }
+    }
+}
@@ -2,103 +2,137 @@ use memoffset::offset_of;
 use std::mem;
 
 use ash::vk;
-use crate::renderer::core::Core;
+use bytemuck::{Pod, Zeroable};
+use crate::renderer::allocator::{Allocation, Allocator};
+use crate::renderer::error::RendererError;
 use crate::renderer::logical_layer::LogicalLayer;
-use crate::renderer::physical_layer::PhysicalLayer;
-use crate::renderer::staging_buf::{create_buffer, copy_buffer};
+use crate::renderer::memory::MemoryCategory;
+use crate::renderer::meshlet::storage_usage;
+use crate::renderer::ray_tracing::input_usage;
+use crate::renderer::staging_buf::UploadContext;
 
-#[repr(C)]
-#[derive(Clone, Debug, Copy)]
-pub(crate) struct Vertex {
-    pub pos: [f32; 2],
-    pub color: [f32; 3]
+// One vertex attribute, location matches layout(location = N) in the vertex shader
+#[derive(Clone, Copy, Debug)]
+pub struct VertexAttribute {
+    pub location: u32,
+    pub format: vk::Format, // I.E. R32G32B32_SFLOAT for a vec3
+    pub offset: u32 // Byte offset within the vertex
 }
 
-pub(crate) struct VertexBuffer {
-    pub(crate) buf: vk::Buffer,
-    dev_mem: vk::DeviceMemory,
-    data_size: vk::DeviceSize,
-    pub(crate) vertex_count: u32
+// Describes one vertex buffer binding, the pipeline builds its vertex input state from these
+#[derive(Clone, Debug)]
+pub struct VertexLayout {
+    pub stride: u32, // Number of bytes per entry in the binding
+    pub per_instance: bool, // Advance once per instance rather than once per vertex
+    pub attributes: Vec<VertexAttribute>
 }
 
-impl Vertex {
-    pub(crate) fn get_binding_description() -> vk::VertexInputBindingDescription {
+impl VertexLayout {
+    pub(crate) fn binding_description(&self, binding: u32) -> vk::VertexInputBindingDescription {
         vk::VertexInputBindingDescription::default()
-            .binding(0)
-            .stride(std::mem::size_of::<Vertex>() as u32) // Number of bytes per entry in the binding
-            .input_rate(vk::VertexInputRate::VERTEX) // ??
+            .binding(binding)
+            .stride(self.stride)
+            .input_rate(match self.per_instance {
+                true => vk::VertexInputRate::INSTANCE,
+                false => vk::VertexInputRate::VERTEX
+            })
+    }
+
+    pub(crate) fn attribute_descriptions(&self, binding: u32) -> Vec<vk::VertexInputAttributeDescription> {
+        self.attributes
+            .iter()
+            .map(|a| vk::VertexInputAttributeDescription {
+                location: a.location,
+                binding, // Index of the vertex binding
+                format: a.format,
+                offset: a.offset // Offset of this attribute within this binding entry
+            })
+            .collect()
     }
+}
 
-    pub(crate) fn get_attribute_descriptions() -> [vk::VertexInputAttributeDescription; 2] {
-        [vk::VertexInputAttributeDescription {
-            location: 0,
-            binding: 0, // Index of the vertex binding
-            format: vk::Format::R32G32_SFLOAT, // Describes a vec2 of 32 bit floating point numbers, not a color
-            offset: offset_of!(Vertex, pos) as u32 // Offset of this attribute within this binding entry
-        }, vk::VertexInputAttributeDescription {
-            location: 1,
-            binding: 0,
-            format: vk::Format::R32G32B32_SFLOAT,
-            offset: offset_of!(Vertex, color) as u32
-        }]
+// Types that can be uploaded as vertex data
+pub trait VertexFormat: Pod {
+    fn layout() -> VertexLayout;
+}
+
+#[repr(C)]
+#[derive(Clone, Debug, Copy, Pod, Zeroable)]
+pub struct Vertex {
+    pub pos: [f32; 3],
+    pub normal: [f32; 3],
+    pub uv: [f32; 2],
+    pub tangent: [f32; 4], // W is the bitangent sign, as in glTF
+    pub color: [f32; 3],
+    pub layer: u32 // Texture array layer, only read by the VOXEL shader variant
+}
+
+impl Default for Vertex {
+    fn default() -> Self {
+        Vertex {
+            pos: [0.0, 0.0, 0.0],
+            normal: [0.0, 0.0, 1.0],
+            uv: [0.0, 0.0],
+            tangent: [1.0, 0.0, 0.0, 1.0],
+            color: [1.0, 1.0, 1.0],
+            layer: 0
+        }
     }
 }
 
+impl VertexFormat for Vertex {
+    fn layout() -> VertexLayout {
+        VertexLayout {
+            stride: mem::size_of::<Vertex>() as u32,
+            per_instance: false,
+            attributes: vec![
+                VertexAttribute { location: 0, format: vk::Format::R32G32B32_SFLOAT, offset: offset_of!(Vertex, pos) as u32 },
+                VertexAttribute { location: 1, format: vk::Format::R32G32B32_SFLOAT, offset: offset_of!(Vertex, normal) as u32 },
+                VertexAttribute { location: 2, format: vk::Format::R32G32_SFLOAT, offset: offset_of!(Vertex, uv) as u32 },
+                VertexAttribute { location: 3, format: vk::Format::R32G32B32A32_SFLOAT, offset: offset_of!(Vertex, tangent) as u32 },
+                VertexAttribute { location: 4, format: vk::Format::R32G32B32_SFLOAT, offset: offset_of!(Vertex, color) as u32 },
+                VertexAttribute { location: 11, format: vk::Format::R32_UINT, offset: offset_of!(Vertex, layer) as u32 } // After the instance attributes
+            ]
+        }
+    }
+}
+
+pub(crate) struct VertexBuffer {
+    pub(crate) buf: vk::Buffer,
+    alloc: Allocation,
+    data_size: vk::DeviceSize,
+    pub(crate) vertex_count: u32
+}
+
 impl VertexBuffer {
-    pub fn new(core: &Core, physical_layer: &PhysicalLayer, logical_layer: &LogicalLayer, cmd_pool: vk::CommandPool, vertices: &[Vertex]) -> VertexBuffer {
+    pub fn new<V: VertexFormat>(logical_layer: &LogicalLayer, allocator: &Allocator, upload: &mut UploadContext, vertices: &[V]) -> Result<VertexBuffer, RendererError> {
         let data_size: vk::DeviceSize = mem::size_of_val(vertices) as vk::DeviceSize;
         let vertex_count = vertices.len();
 
-        let (transfer_mem, transfer_buffer) = create_buffer(core,
-                                                            physical_layer,
-                                                            logical_layer,
-                                                            data_size,
-                                                            vk::BufferUsageFlags::TRANSFER_SRC, // Can be a used as a source for transfer commands
-                                                            vk::MemoryPropertyFlags::HOST_VISIBLE | // Visible for writes on the host
-                                                                vk::MemoryPropertyFlags::HOST_COHERENT) // COHERENT means that copy operations are atomic with respect to subsequent vkQueueSubmit calls
-            .expect("Failed to locate suitable device memory");
+        let (alloc, buf) = allocator.create_buffer(logical_layer,
+                                                   data_size,
+                                                   vk::BufferUsageFlags::VERTEX_BUFFER | // Used by the vertex shader stage
+                                                       vk::BufferUsageFlags::TRANSFER_DST | // Can be a destination for transfer commands
+                                                       input_usage(logical_layer) | // Built into acceleration structures with ray tracing on
+                                                       storage_usage(logical_layer), // Read by mesh shaders with mesh shading on
+                                                   vk::MemoryPropertyFlags::DEVICE_LOCAL, // Local to GPU
+                                                   MemoryCategory::Meshes)?;
 
-        unsafe {
-            let dev_memory = logical_layer.logical_device
-                .map_memory(transfer_mem,
-                            0,
-                            data_size,
-                            vk::MemoryMapFlags::empty())
-                .unwrap() as *mut Vertex;
-            dev_memory.copy_from_nonoverlapping(vertices.as_ptr(), vertex_count);
-            logical_layer.logical_device.unmap_memory(transfer_mem);
+        // The copy is only recorded here, the contents are valid once the upload context is flushed
+        if let Err(e) = upload.upload_buffer(logical_layer, allocator, vertices, buf, 0) {
+            allocator.destroy_buffer(logical_layer, buf, &alloc);
+            return Err(e);
         }
 
-        let (dev_mem, buf) = create_buffer(core,
-                                           physical_layer,
-                                                        logical_layer,
-                                                        data_size,
-                                                        vk::BufferUsageFlags::VERTEX_BUFFER | // Used by the vertex shader stage
-                                                            vk::BufferUsageFlags::TRANSFER_DST, // Can be a destination for transfer commands
-                                                        vk::MemoryPropertyFlags::DEVICE_LOCAL) // Local to GPU
-            .expect("Failed to locate suitable device memory");
-
-        copy_buffer(logical_layer, cmd_pool, transfer_buffer, buf, data_size);
-
-        let vbuf = VertexBuffer {
+        Ok(VertexBuffer {
             buf,
-            dev_mem,
+            alloc,
             data_size,
             vertex_count: vertex_count as u32
-        };
-
-        unsafe {
-            logical_layer.logical_device.destroy_buffer(transfer_buffer, None);
-            logical_layer.logical_device.free_memory(transfer_mem, None);
-        }
-
-        vbuf
+        })
     }
 
-    pub fn destroy(&self, logical_layer: &LogicalLayer) {
-        unsafe {
-            logical_layer.logical_device.destroy_buffer(self.buf, None);
-            logical_layer.logical_device.free_memory(self.dev_mem, None);
-        }
+    pub fn destroy(&self, logical_layer: &LogicalLayer, allocator: &Allocator) {
+        allocator.destroy_buffer(logical_layer, self.buf, &self.alloc);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,295 @@
+use std::collections::HashMap;
+
+use glam::{Quat, Vec3};
+
+use crate::ecs::components::Transform;
+use crate::ecs::{Entity, World};
+use crate::physics::components::{BodyType, Collider, ColliderShape, RigidBody};
+use crate::util::cursor::Cursor;
+
+// Layout, numbers little endian:
+//   header: MAGIC, VERSION u32, section count u32
+//   per component type: a u16 name length, the UTF-8 name, a record count u32, then per record the
+//                       entity's index u32, a payload length u32 and the payload from Persistent::save
+// Entity indices only group an entity's components, loaded entities are spawned fresh.
+const MAGIC: [u8; 4] = *b"CBEN";
+const VERSION: u32 = 1;
+
+// A component that can be saved. Handles to renderer or physics objects can't be, they're recreated from
+// the saved fields, I.E. by Physics::tick for RigidBody and Collider.
+pub trait Persistent: Sized + 'static {
+    const NAME: &'static str; // Identifies the component's records in saves, so it can't change afterwards
+
+    fn save(&self, out: &mut Vec<u8>);
+    fn load(data: &[u8]) -> Option<Self>; // None if the data is malformed
+}
+
+fn save_floats(out: &mut Vec<u8>, floats: &[f32]) {
+    out.extend(floats.iter().flat_map(|f| f.to_le_bytes()));
+}
+
+// Exactly N floats, or None
+fn load_floats<const N: usize>(data: &[u8]) -> Option<[f32; N]> {
+    match data.len() == N * 4 {
+        true => Some(std::array::from_fn(|i| f32::from_le_bytes(data[i * 4..i * 4 + 4].try_into().unwrap()))),
+        false => None
+    }
+}
+
+impl Persistent for Transform {
+    const NAME: &'static str = "transform";
+
+    fn save(&self, out: &mut Vec<u8>) {
+        save_floats(out, &self.translation.to_array());
+        save_floats(out, &self.rotation.to_array());
+        save_floats(out, &self.scale.to_array());
+    }
+
+    fn load(data: &[u8]) -> Option<Self> {
+        let f = load_floats::<10>(data)?;
+        Some(Transform {
+            translation: Vec3::new(f[0], f[1], f[2]),
+            rotation: Quat::from_xyzw(f[3], f[4], f[5], f[6]).normalize(),
+            scale: Vec3::new(f[7], f[8], f[9])
+        })
+    }
+}
+
+// The velocity saved is the initial one, not what the body was simulated to
+impl Persistent for RigidBody {
+    const NAME: &'static str = "rigid_body";
+
+    fn save(&self, out: &mut Vec<u8>) {
+        out.push(self.body_type as u8);
+        save_floats(out, &self.linear_velocity.to_array());
+        save_floats(out, &[self.gravity_scale]);
+    }
+
+    fn load(data: &[u8]) -> Option<Self> {
+        let (&body_type, rest) = data.split_first()?;
+        let body_type = match body_type {
+            0 => BodyType::Dynamic,
+            1 => BodyType::Fixed,
+            2 => BodyType::Kinematic,
+            _ => return None
+        };
+        let f = load_floats::<4>(rest)?;
+        Some(RigidBody {
+            linear_velocity: Vec3::new(f[0], f[1], f[2]),
+            gravity_scale: f[3],
+            ..RigidBody::new(body_type)
+        })
+    }
+}
+
+// The shape's tag and parameters, padded to three floats, then friction, restitution and density
+impl Persistent for Collider {
+    const NAME: &'static str = "collider";
+
+    fn save(&self, out: &mut Vec<u8>) {
+        let (tag, params) = match self.shape {
+            ColliderShape::Cuboid { half_extents } => (0, half_extents.to_array()),
+            ColliderShape::Ball { radius } => (1, [radius, 0.0, 0.0]),
+            ColliderShape::Capsule { half_height, radius } => (2, [half_height, radius, 0.0])
+        };
+        out.push(tag);
+        save_floats(out, &params);
+        save_floats(out, &[self.friction, self.restitution, self.density]);
+    }
+
+    fn load(data: &[u8]) -> Option<Self> {
+        let (&tag, rest) = data.split_first()?;
+        let f = load_floats::<6>(rest)?;
+        let shape = match tag {
+            0 => ColliderShape::Cuboid { half_extents: Vec3::new(f[0], f[1], f[2]) },
+            1 => ColliderShape::Ball { radius: f[0] },
+            2 => ColliderShape::Capsule { half_height: f[0], radius: f[1] },
+            _ => return None
+        };
+        Some(Collider {
+            friction: f[3],
+            restitution: f[4],
+            density: f[5],
+            ..Collider::new(shape)
+        })
+    }
+}
+
+type Records = Vec<(u32, Vec<u8>)>; // Entity index and payload
+
+struct Registration {
+    name: &'static str,
+    save: fn(&World) -> Records,
+    load: fn(&mut World, Entity, &[u8]) -> bool // False if the payload was malformed
+}
+
+fn save_component<T: Persistent>(world: &World) -> Records {
+    world.query::<T>()
+        .map(|(entity, component)| {
+            let mut payload = Vec::new();
+            component.save(&mut payload);
+            (entity.index(), payload)
+        })
+        .collect()
+}
+
+fn load_component<T: Persistent>(world: &mut World, entity: Entity, data: &[u8]) -> bool {
+    match T::load(data) {
+        Some(component) => {
+            world.insert(entity, component);
+            true
+        },
+        None => false
+    }
+}
+
+// The component types saved with entities. Entities without any of them aren't saved.
+pub struct ComponentRegistry {
+    components: Vec<Registration>
+}
+
+impl ComponentRegistry {
+    pub fn new() -> ComponentRegistry {
+        ComponentRegistry {
+            components: Vec::new()
+        }
+    }
+
+    // Transform, RigidBody and Collider
+    pub fn with_defaults() -> ComponentRegistry {
+        let mut registry = ComponentRegistry::new();
+        registry.register::<Transform>();
+        registry.register::<RigidBody>();
+        registry.register::<Collider>();
+
+        registry
+    }
+
+    // Registering a type twice, or two types with the same name, panics
+    pub fn register<T: Persistent>(&mut self) {
+        assert!(self.components.iter().all(|c| c.name != T::NAME), "{} is already registered", T::NAME);
+        assert!(T::NAME.len() <= u16::MAX as usize, "Component names are at most 65535 bytes");
+        self.components.push(Registration {
+            name: T::NAME,
+            save: save_component::<T>,
+            load: load_component::<T>
+        });
+    }
+}
+
+impl Default for ComponentRegistry {
+    fn default() -> Self {
+        ComponentRegistry::new()
+    }
+}
+
+pub(crate) fn encode(world: &World, registry: &ComponentRegistry) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&(registry.components.len() as u32).to_le_bytes());
+    for component in registry.components.iter() {
+        let records = (component.save)(world);
+        out.extend_from_slice(&(component.name.len() as u16).to_le_bytes());
+        out.extend_from_slice(component.name.as_bytes());
+        out.extend_from_slice(&(records.len() as u32).to_le_bytes());
+        for (index, payload) in records {
+            out.extend_from_slice(&index.to_le_bytes());
+            out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+            out.extend_from_slice(&payload);
+        }
+    }
+
+    out
+}
+
+// Spawns the saved entities into world, returning them. Components that aren't registered are skipped.
+// Nothing is left spawned if the data is malformed.
+pub(crate) fn decode(data: &[u8], world: &mut World, registry: &ComponentRegistry) -> Result<Vec<Entity>, String> {
+    let mut order = Vec::new(); // Spawn order, so the returned entities are deterministic
+    match decode_into(data, world, registry, &mut order) {
+        Ok(()) => Ok(order),
+        Err(e) => {
+            for entity in order {
+                world.despawn(entity);
+            }
+            Err(e)
+        }
+    }
+}
+
+fn decode_into(data: &[u8], world: &mut World, registry: &ComponentRegistry, order: &mut Vec<Entity>) -> Result<(), String> {
+    let mut cursor = Cursor::new(data);
+    if cursor.bytes(4)? != MAGIC {
+        return Err(String::from("Not an entity save"));
+    }
+    let version = cursor.u32()?;
+    if version != VERSION {
+        return Err(format!("Unsupported entity save version {}", version));
+    }
+
+    let mut spawned: HashMap<u32, Entity> = HashMap::new();
+    for _ in 0..cursor.u32()? {
+        let name_len = cursor.u16()? as usize;
+        let name = String::from_utf8(cursor.bytes(name_len)?.to_vec()).map_err(|e| e.to_string())?;
+        let registration = registry.components.iter().find(|c| c.name == name);
+        if registration.is_none() {
+            log::warn!("Skipping saved {} components, the type isn't registered", name);
+        }
+
+        for _ in 0..cursor.u32()? {
+            let index = cursor.u32()?;
+            let len = cursor.u32()? as usize;
+            let payload = cursor.bytes(len)?;
+            if let Some(r) = registration {
+                let entity = *spawned.entry(index).or_insert_with(|| {
+                    let e = world.spawn();
+                    order.push(e);
+                    e
+                });
+                if !(r.load)(world, entity, payload) {
+                    return Err(format!("Malformed {} component", name));
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transforms_round_trip() {
+        let mut world = World::new();
+        let transforms = [Transform::from_translation(Vec3::new(1.0, 2.0, 3.0)),
+                          Transform { translation: Vec3::ZERO, rotation: Quat::from_rotation_x(0.5), scale: Vec3::splat(0.25) }];
+        for t in transforms {
+            let e = world.spawn();
+            world.insert(e, t);
+        }
+        world.spawn(); // Without registered components, so it isn't saved
+
+        let data = encode(&world, &ComponentRegistry::with_defaults());
+        let mut loaded = World::new();
+        let entities = decode(&data, &mut loaded, &ComponentRegistry::with_defaults()).unwrap();
+        let mut decoded: Vec<Transform> = entities.iter().map(|e| *loaded.get::<Transform>(*e).unwrap()).collect();
+        decoded.sort_by(|a, b| a.translation.x.total_cmp(&b.translation.x));
+        assert_eq!(decoded, vec![transforms[1], transforms[0]]);
+    }
+
+    #[test]
+    fn malformed_saves_spawn_nothing() {
+        let mut world = World::new();
+        let e = world.spawn();
+        world.insert(e, Transform::IDENTITY);
+        let data = encode(&world, &ComponentRegistry::with_defaults());
+
+        let mut loaded = World::new();
+        assert!(decode(&data[..data.len() - 1], &mut loaded, &ComponentRegistry::with_defaults()).is_err());
+        assert_eq!(loaded.query::<Transform>().count(), 0);
+        assert!(decode(b"CBEX\x01\0\0\0\0\0\0\0", &mut loaded, &ComponentRegistry::with_defaults()).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+pub mod entities;
+pub(crate) mod region;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use glam::IVec3;
+
+use crate::ecs::{Entity, World};
+use crate::save::entities::ComponentRegistry;
+use crate::voxel::world::VoxelWorld;
+use crate::voxel::Chunk;
+
+const ENTITIES_FILE: &str = "entities.cbe";
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(PathBuf, String),
+    Corrupt(PathBuf, String) // The file was read but isn't a valid save
+}
+
+impl fmt::Display for SaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SaveError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            SaveError::Corrupt(path, e) => write!(f, "{}: corrupt save: {}", path.display(), e)
+        }
+    }
+}
+
+impl std::error::Error for SaveError {}
+
+// Writes to a temporary file that then replaces path, so a crash mid write leaves the previous save intact
+pub(crate) fn write_replacing(path: &Path, data: &[u8]) -> Result<(), SaveError> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    fs::write(&temp, data).map_err(|e| SaveError::Io(temp.clone(), e.to_string()))?;
+    fs::rename(&temp, path).map_err(|e| SaveError::Io(path.to_owned(), e.to_string()))
+}
+
+// Saved but not yet written, tagged with the save they're from so ones saved again meanwhile are kept
+#[derive(Default)]
+struct Pending {
+    chunks: HashMap<IVec3, (u64, Option<Chunk>)>, // None for chunks to remove from the region
+    entities: Option<(u64, Vec<u8>)>
+}
+
+enum Job {
+    WriteChunks,
+    WriteEntities,
+    Flush(Sender<()>)
+}
+
+// A world save directory: voxel chunks in region files of 8x8x8 chunks, and entities with the components
+// in registry in one file. Saving copies what changed and writes it on a background thread, loading reads
+// on the calling thread.
+pub struct WorldSave {
+    dir: PathBuf,
+    jobs: Option<Sender<Job>>, // Dropped on drop, which ends the worker once it's written everything
+    errors: Receiver<SaveError>,
+    pending: Arc<Mutex<Pending>>,
+    next_save: u64,
+    worker: Option<JoinHandle<()>>,
+    pub registry: ComponentRegistry // Components saved with entities, empty by default
+}
+
+impl WorldSave {
+    // Creates the directory if it doesn't exist
+    pub fn open(dir: &Path) -> Result<WorldSave, SaveError> {
+        fs::create_dir_all(dir).map_err(|e| SaveError::Io(dir.to_owned(), e.to_string()))?;
+        let (job_sender, jobs) = mpsc::channel::<Job>();
+        let (error_sender, errors) = mpsc::channel::<SaveError>();
+        let pending = Arc::new(Mutex::new(Pending::default()));
+
+        let worker = {
+            let dir = dir.to_owned();
+            let pending = pending.clone();
+            thread::Builder::new()
+                .name(String::from("world save"))
+                .spawn(move || work(&dir, &jobs, &error_sender, &pending))
+                .expect("Failed to spawn the world save thread")
+        };
+
+        Ok(WorldSave {
+            dir: dir.to_owned(),
+            jobs: Some(job_sender),
+            errors,
+            pending,
+            next_save: 0,
+            worker: Some(worker),
+            registry: ComponentRegistry::new()
+        })
+    }
+
+    fn queue(&self, job: Job) {
+        if let Some(jobs) = self.jobs.as_ref() {
+            jobs.send(job).expect("The world save thread has exited");
+        }
+    }
+
+    // Queues the chunks edited since they were last saved, I.E. every few seconds and before exiting. Chunks
+    // whose write failed are written again too.
+    pub fn save_chunks(&mut self, voxels: &mut VoxelWorld) {
+        let chunks = voxels.take_unsaved();
+        if chunks.is_empty() {
+            if !self.pending.lock().unwrap().chunks.is_empty() {
+                self.queue(Job::WriteChunks); // Retries failed writes, or finds nothing left if one is still in flight
+            }
+            return;
+        }
+
+        self.next_save += 1;
+        let mut pending = self.pending.lock().unwrap();
+        for (pos, chunk) in chunks {
+            pending.chunks.insert(pos, (self.next_save, chunk));
+        }
+        drop(pending);
+        self.queue(Job::WriteChunks);
+    }
+
+    // Queues every entity with a registered component, replacing the previous entity save
+    pub fn save_entities(&mut self, world: &World) {
+        let data = entities::encode(world, &self.registry);
+        self.next_save += 1;
+        self.pending.lock().unwrap().entities = Some((self.next_save, data));
+        self.queue(Job::WriteEntities);
+    }
+
+    // Blocks until everything saved so far is written. The event loop exits the process without dropping
+    // the game, so call this before frame.exit().
+    pub fn flush(&self) {
+        let (done, finished) = mpsc::channel();
+        self.queue(Job::Flush(done));
+        let _ = finished.recv(); // Only fails if the worker panicked, which leaves nothing to wait for
+    }
+
+    // Writes that failed since the last call. What they were writing stays queued and is retried by the next
+    // save_chunks or save_entities call, even one with nothing new to save.
+    pub fn errors(&self) -> Vec<SaveError> {
+        self.errors.try_iter().collect()
+    }
+
+    // One chunk, I.E. when streaming chunks in around the player. Chunks saved but not yet written are
+    // returned as saved.
+    pub fn load_chunk(&self, chunk_pos: IVec3) -> Result<Option<Chunk>, SaveError> {
+        if let Some((_, chunk)) = self.pending.lock().unwrap().chunks.get(&chunk_pos) {
+            return Ok(chunk.clone());
+        }
+
+        region::read_chunk(&self.dir, chunk_pos)
+    }
+
+    // Every saved chunk, inserted with VoxelWorld::insert_chunk. Returns how many there were.
+    pub fn load_voxels(&self, voxels: &mut VoxelWorld) -> Result<usize, SaveError> {
+        self.flush();
+        let entries = fs::read_dir(&self.dir).map_err(|e| SaveError::Io(self.dir.clone(), e.to_string()))?;
+        let mut count = 0;
+        for entry in entries {
+            let path = entry.map_err(|e| SaveError::Io(self.dir.clone(), e.to_string()))?.path();
+            let region = match path.file_name().and_then(|n| n.to_str()).and_then(region::parse_region_name) {
+                Some(r) => r,
+                None => continue
+            };
+            for (chunk_pos, chunk) in region::read_region(&path, region)? {
+                voxels.insert_chunk(chunk_pos, chunk);
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    // Spawns the saved entities into world with their registered components, returning them. Empty if
+    // nothing was saved.
+    pub fn load_entities(&self, world: &mut World) -> Result<Vec<Entity>, SaveError> {
+        self.flush();
+        let path = self.dir.join(ENTITIES_FILE);
+        let data = match fs::read(&path) {
+            Ok(d) => d,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(SaveError::Io(path, e.to_string()))
+        };
+
+        entities::decode(&data, world, &self.registry).map_err(|e| SaveError::Corrupt(path, e))
+    }
+}
+
+impl Drop for WorldSave {
+    fn drop(&mut self) {
+        self.jobs = None;
+        if let Some(w) = self.worker.take() {
+            let _ = w.join(); // A panicked worker has nothing left to clean up
+        }
+    }
+}
+
+fn work(dir: &Path, jobs: &Receiver<Job>, errors: &Sender<SaveError>, pending: &Mutex<Pending>) {
+    for job in jobs.iter() {
+        let result = match job {
+            Job::WriteChunks => write_chunks(dir, pending),
+            Job::WriteEntities => write_entities(dir, pending),
+            Job::Flush(done) => {
+                let _ = done.send(());
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            let _ = errors.send(e); // The save may already be dropping
+        }
+    }
+}
+
+// Everything pending, so later jobs find nothing left when a save was queued several times in a row
+fn write_chunks(dir: &Path, pending: &Mutex<Pending>) -> Result<(), SaveError> {
+    let snapshot: Vec<(IVec3, u64, Option<Chunk>)> = pending.lock().unwrap().chunks.iter()
+        .map(|(pos, (save, chunk))| (*pos, *save, chunk.clone()))
+        .collect();
+    let mut regions: HashMap<IVec3, Vec<(usize, Option<&Chunk>)>> = HashMap::new();
+    for (pos, _, chunk) in snapshot.iter() {
+        let (region, slot) = region::split_chunk_pos(*pos);
+        regions.entry(region).or_default().push((slot, chunk.as_ref()));
+    }
+
+    let mut result = Ok(());
+    let mut written = Vec::new();
+    for (region, chunks) in regions {
+        match region::write_region(dir, region, &chunks) {
+            Ok(()) => written.push(region),
+            Err(e) => result = Err(e)
+        }
+    }
+
+    let mut pending = pending.lock().unwrap();
+    for (pos, save, _) in snapshot {
+        let done = written.contains(&region::split_chunk_pos(pos).0);
+        if done && pending.chunks.get(&pos).map(|(s, _)| *s) == Some(save) {
+            pending.chunks.remove(&pos);
+        }
+    }
+
+    result
+}
+
+fn write_entities(dir: &Path, pending: &Mutex<Pending>) -> Result<(), SaveError> {
+    let (save, data) = match pending.lock().unwrap().entities.clone() {
+        Some(e) => e,
+        None => return Ok(())
+    };
+    write_replacing(&dir.join(ENTITIES_FILE), &data)?;
+
+    let mut pending = pending.lock().unwrap();
+    if pending.entities.as_ref().map(|(s, _)| *s) == Some(save) {
+        pending.entities = None;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,322 @@
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use glam::IVec3;
+
+use crate::save::{write_replacing, SaveError};
+use crate::util::cursor::Cursor;
+use crate::util::lz4::decompress_sized;
+use crate::voxel::{BlockId, Chunk, CHUNK_VOLUME};
+
+// Layout, numbers little endian:
+//   header: MAGIC, VERSION u32
+//   table: per chunk slot an offset u32 from the start of the file and a stored size u32, 0 for absent chunks
+//   chunk payloads, back to back
+// Payloads are the chunk's block IDs as u16s in Chunk index order, LZ4 compressed with the size prepended.
+// Slots are X fastest, then Y, then Z, like blocks within a chunk.
+const MAGIC: [u8; 4] = *b"CBRG";
+const VERSION: u32 = 1;
+const REGION_SIZE: i32 = 8; // Chunks along each edge of a region
+const REGION_VOLUME: usize = (REGION_SIZE * REGION_SIZE * REGION_SIZE) as usize;
+const HEADER_SIZE: u64 = 8;
+const TABLE_SIZE: u64 = REGION_VOLUME as u64 * 8;
+
+// Region coordinate of the region containing a chunk, and the chunk's slot in it
+pub(crate) fn split_chunk_pos(chunk_pos: IVec3) -> (IVec3, usize) {
+    let region = IVec3::new(chunk_pos.x.div_euclid(REGION_SIZE), chunk_pos.y.div_euclid(REGION_SIZE),
+                            chunk_pos.z.div_euclid(REGION_SIZE));
+    let local = chunk_pos - region * REGION_SIZE;
+    (region, (local.x + local.y * REGION_SIZE + local.z * REGION_SIZE * REGION_SIZE) as usize)
+}
+
+pub(crate) fn slot_chunk_pos(region: IVec3, slot: usize) -> IVec3 {
+    let slot = slot as i32;
+    region * REGION_SIZE + IVec3::new(slot % REGION_SIZE, slot / REGION_SIZE % REGION_SIZE, slot / (REGION_SIZE * REGION_SIZE))
+}
+
+pub(crate) fn region_path(dir: &Path, region: IVec3) -> PathBuf {
+    dir.join(format!("r.{}.{}.{}.cbr", region.x, region.y, region.z))
+}
+
+// The region coordinate of a file named by region_path
+pub(crate) fn parse_region_name(name: &str) -> Option<IVec3> {
+    let mut parts = name.strip_prefix("r.")?.strip_suffix(".cbr")?.split('.').map(|p| p.parse::<i32>().ok());
+    let region = IVec3::new(parts.next()??, parts.next()??, parts.next()??);
+    match parts.next() {
+        None => Some(region),
+        Some(_) => None
+    }
+}
+
+fn io_error(path: &Path, e: impl ToString) -> SaveError {
+    SaveError::Io(path.to_owned(), e.to_string())
+}
+
+fn corrupt(path: &Path, e: impl ToString) -> SaveError {
+    SaveError::Corrupt(path.to_owned(), e.to_string())
+}
+
+pub(crate) fn encode_chunk(chunk: &Chunk) -> Vec<u8> {
+    let raw: Vec<u8> = chunk.blocks().iter().flat_map(|b| b.0.to_le_bytes()).collect();
+    lz4_flex::compress_prepend_size(&raw)
+}
+
+// Corrupt sizes are rejected before anything is allocated
+pub(crate) fn decode_chunk(stored: &[u8]) -> Result<Chunk, String> {
+    let raw = decompress_sized(stored, CHUNK_VOLUME * 2)?;
+    let blocks = raw.chunks_exact(2).map(|b| BlockId(u16::from_le_bytes([b[0], b[1]]))).collect();
+    Chunk::from_blocks(blocks).ok_or_else(|| String::from("Chunk has the wrong number of blocks"))
+}
+
+// The most a chunk's payload can take up stored, I.E. when its blocks don't compress at all
+fn max_stored_size() -> usize {
+    lz4_flex::block::get_maximum_output_size(CHUNK_VOLUME * 2) + 4
+}
+
+// Table entries come from the file, so they're checked before a payload is read or allocated
+fn check_slot(path: &Path, offset: u32, size: u32) -> Result<(), SaveError> {
+    if (offset as u64) < HEADER_SIZE + TABLE_SIZE {
+        return Err(corrupt(path, format!("Chunk offset {} is inside the header", offset)));
+    }
+    if size as usize > max_stored_size() {
+        return Err(corrupt(path, format!("Chunk size {} is larger than any chunk", size)));
+    }
+    Ok(())
+}
+
+// Each slot's offset and stored size
+fn read_table(path: &Path, file: &mut File) -> Result<Vec<(u32, u32)>, SaveError> {
+    let mut header = vec![0; (HEADER_SIZE + TABLE_SIZE) as usize];
+    file.read_exact(&mut header).map_err(|e| corrupt(path, e))?;
+    let mut cursor = Cursor::new(&header);
+    let err = |e: String| corrupt(path, e);
+    if cursor.bytes(4).map_err(err)? != MAGIC {
+        return Err(corrupt(path, "Not a region file"));
+    }
+    let version = cursor.u32().map_err(err)?;
+    if version != VERSION {
+        return Err(corrupt(path, format!("Unsupported region version {}", version)));
+    }
+
+    let mut table = Vec::with_capacity(REGION_VOLUME);
+    for _ in 0..REGION_VOLUME {
+        table.push((cursor.u32().map_err(err)?, cursor.u32().map_err(err)?));
+    }
+
+    Ok(table)
+}
+
+// Each slot's stored payload, empty for absent chunks. A missing file has no chunks.
+fn read_payloads(path: &Path) -> Result<Vec<Vec<u8>>, SaveError> {
+    let mut file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![Vec::new(); REGION_VOLUME]),
+        Err(e) => return Err(io_error(path, e))
+    };
+    let table = read_table(path, &mut file)?;
+    let mut rest = Vec::new();
+    file.read_to_end(&mut rest).map_err(|e| io_error(path, e))?;
+
+    table.iter()
+        .map(|&(offset, size)| {
+            if size == 0 {
+                return Ok(Vec::new());
+            }
+            check_slot(path, offset, size)?;
+            let start = (offset as u64 - (HEADER_SIZE + TABLE_SIZE)) as usize;
+            start.checked_add(size as usize)
+                .and_then(|end| rest.get(start..end))
+                .map(|p| p.to_vec())
+                .ok_or_else(|| corrupt(path, "Truncated chunk"))
+        })
+        .collect()
+}
+
+// One chunk, reading only the table and its payload
+pub(crate) fn read_chunk(dir: &Path, chunk_pos: IVec3) -> Result<Option<Chunk>, SaveError> {
+    let (region, slot) = split_chunk_pos(chunk_pos);
+    let path = region_path(dir, region);
+    let mut file = match File::open(&path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(io_error(&path, e))
+    };
+    let (offset, size) = read_table(&path, &mut file)?[slot];
+    if size == 0 {
+        return Ok(None);
+    }
+    check_slot(&path, offset, size)?;
+    let len = file.metadata().map_err(|e| io_error(&path, e))?.len();
+    if offset as u64 + size as u64 > len {
+        return Err(corrupt(&path, "Truncated chunk"));
+    }
+
+    let mut stored = vec![0; size as usize];
+    file.seek(SeekFrom::Start(offset as u64))
+        .and_then(|_| file.read_exact(&mut stored))
+        .map_err(|e| corrupt(&path, e))?;
+    decode_chunk(&stored).map(Some).map_err(|e| corrupt(&path, format!("Chunk {}: {}", chunk_pos, e)))
+}
+
+// Every chunk in a region file, with their chunk coordinates
+pub(crate) fn read_region(path: &Path, region: IVec3) -> Result<Vec<(IVec3, Chunk)>, SaveError> {
+    read_payloads(path)?.iter()
+        .enumerate()
+        .filter(|(_, p)| !p.is_empty())
+        .map(|(slot, p)| {
+            let chunk_pos = slot_chunk_pos(region, slot);
+            decode_chunk(p).map(|c| (chunk_pos, c)).map_err(|e| corrupt(path, format!("Chunk {}: {}", chunk_pos, e)))
+        })
+        .collect()
+}
+
+// Rewrites a region with some of its chunks replaced, None removing them. The file is removed once
+// every chunk is.
+pub(crate) fn write_region(dir: &Path, region: IVec3, chunks: &[(usize, Option<&Chunk>)]) -> Result<(), SaveError> {
+    let path = region_path(dir, region);
+    let mut payloads = read_payloads(&path)?;
+    for (slot, chunk) in chunks {
+        payloads[*slot] = chunk.map(encode_chunk).unwrap_or_default();
+    }
+    if payloads.iter().all(|p| p.is_empty()) {
+        return match fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(io_error(&path, e)),
+            _ => Ok(())
+        };
+    }
+
+    let mut out = Vec::with_capacity((HEADER_SIZE + TABLE_SIZE) as usize + payloads.iter().map(|p| p.len()).sum::<usize>());
+    out.extend_from_slice(&MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    let mut offset = (HEADER_SIZE + TABLE_SIZE) as u32;
+    for p in payloads.iter() {
+        let start = match p.is_empty() {
+            true => 0,
+            false => offset
+        };
+        out.extend_from_slice(&start.to_le_bytes());
+        out.extend_from_slice(&(p.len() as u32).to_le_bytes());
+        offset += p.len() as u32;
+    }
+    for p in payloads.iter() {
+        out.extend_from_slice(p);
+    }
+
+    write_replacing(&path, &out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Removed again when dropped, so failing tests don't leave regions behind
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("cubulous-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn test_chunk(seed: u16) -> Chunk {
+        let mut chunk = Chunk::new();
+        for i in 0..200 {
+            let pos = IVec3::new(i % 32, (i * 7) % 32, (i * 13) % 32);
+            chunk.set(pos, BlockId(seed + i as u16 % 5));
+        }
+        chunk
+    }
+
+    #[test]
+    fn chunk_round_trip() {
+        let chunk = test_chunk(1);
+        let decoded = decode_chunk(&encode_chunk(&chunk)).unwrap();
+        assert_eq!(decoded.blocks(), chunk.blocks());
+        assert!(decode_chunk(&encode_chunk(&Chunk::new())).unwrap().is_empty());
+    }
+
+    #[test]
+    fn corrupt_chunks_are_errors() {
+        let stored = encode_chunk(&test_chunk(1));
+        assert!(decode_chunk(&stored[..stored.len() / 2]).is_err());
+        assert!(decode_chunk(&[]).is_err());
+
+        let mut huge = stored.clone();
+        huge[..4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decode_chunk(&huge).is_err());
+
+        let mut short = stored;
+        short[..4].copy_from_slice(&2u32.to_le_bytes());
+        assert!(decode_chunk(&short).is_err());
+    }
+
+    #[test]
+    fn slots_round_trip() {
+        for chunk_pos in [IVec3::ZERO, IVec3::new(7, 8, -1), IVec3::new(-9, -16, 100)] {
+            let (region, slot) = split_chunk_pos(chunk_pos);
+            assert!(slot < REGION_VOLUME);
+            assert_eq!(slot_chunk_pos(region, slot), chunk_pos);
+        }
+    }
+
+    #[test]
+    fn region_names_round_trip() {
+        let region = IVec3::new(-3, 0, 12);
+        let path = region_path(Path::new("saves"), region);
+        assert_eq!(parse_region_name(path.file_name().unwrap().to_str().unwrap()), Some(region));
+        assert_eq!(parse_region_name("r.1.2.cbr"), None);
+        assert_eq!(parse_region_name("r.1.2.3.4.cbr"), None);
+        assert_eq!(parse_region_name("entities.cbe"), None);
+    }
+
+    #[test]
+    fn region_round_trip() {
+        let temp = TempDir::new("region");
+        let dir = &temp.0;
+        let region = IVec3::new(-1, 0, 2);
+        let (a, b) = (test_chunk(1), test_chunk(20));
+        write_region(dir, region, &[(0, Some(&a)), (REGION_VOLUME - 1, Some(&b))]).unwrap();
+        write_region(dir, region, &[(5, Some(&b))]).unwrap(); // Keeps the chunks already there
+
+        let chunks = read_region(&region_path(dir, region), region).unwrap();
+        assert_eq!(chunks.len(), 3);
+        let first = read_chunk(dir, slot_chunk_pos(region, 0)).unwrap().unwrap();
+        assert_eq!(first.blocks(), a.blocks());
+        let last = read_chunk(dir, slot_chunk_pos(region, REGION_VOLUME - 1)).unwrap().unwrap();
+        assert_eq!(last.blocks(), b.blocks());
+        assert!(read_chunk(dir, slot_chunk_pos(region, 1)).unwrap().is_none());
+
+        write_region(dir, region, &[(0, None), (5, None), (REGION_VOLUME - 1, None)]).unwrap();
+        assert!(!region_path(dir, region).exists()); // Removed with its last chunk
+    }
+
+    #[test]
+    fn corrupt_tables_are_errors() {
+        let temp = TempDir::new("table");
+        let dir = &temp.0;
+        let region = IVec3::ZERO;
+        write_region(dir, region, &[(0, Some(&test_chunk(1)))]).unwrap();
+        let path = region_path(dir, region);
+        let data = fs::read(&path).unwrap();
+        let start = (HEADER_SIZE + TABLE_SIZE) as u32;
+        let size = data.len() as u32 - start;
+
+        for (offset, size) in [(4, size), (start, u32::MAX), (start, size + 1), (u32::MAX, size)] {
+            let mut table = data.clone();
+            table[8..12].copy_from_slice(&offset.to_le_bytes());
+            table[12..16].copy_from_slice(&size.to_le_bytes());
+            fs::write(&path, &table).unwrap();
+            assert!(read_chunk(dir, IVec3::ZERO).is_err(), "offset {} size {}", offset, size);
+            assert!(read_region(&path, region).is_err(), "offset {} size {}", offset, size);
+        }
+    }
+}
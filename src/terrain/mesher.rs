@@ -0,0 +1,81 @@
+use glam::Vec3;
+
+use crate::renderer::frustum::Aabb;
+use crate::renderer::vertex::Vertex;
+use crate::terrain::Heightmap;
+
+// A chunk of the grid, in samples
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkRect {
+    pub x: u32,
+    pub z: u32,
+    pub width: u32, // Cells, so the chunk spans width + 1 samples
+    pub depth: u32
+}
+
+// Sample offsets within the chunk at a LOD step, the last one clamped to the chunk's edge so partial
+// chunks at the heightmap's border still close up
+fn offsets(cells: u32, step: u32) -> Vec<u32> {
+    let count = (cells + step - 1) / step;
+    (0..=count).map(|i| (i * step).min(cells)).collect()
+}
+
+// Grid mesh of the chunk with every step-th sample, in the terrain's space. Edges get a skirt hanging
+// skirt_depth below the surface, covering the cracks where a neighbour is drawn at another step. UVs span
+// the whole heightmap so they can sample a splat map. Returns the bounds too, skirts included.
+pub fn chunk_mesh(heightmap: &Heightmap, rect: ChunkRect, step: u32, cell_size: f32,
+                  skirt_depth: f32) -> (Vec<Vertex>, Vec<u32>, Aabb) {
+    let (xs, zs) = (offsets(rect.width, step.max(1)), offsets(rect.depth, step.max(1)));
+    let uv_scale = [1.0 / (heightmap.width() - 1) as f32, 1.0 / (heightmap.depth() - 1) as f32];
+    let vertex = |x: u32, z: u32| {
+        let (sx, sz) = ((rect.x + x) as i64, (rect.z + z) as i64);
+        Vertex {
+            pos: [sx as f32 * cell_size, heightmap.get(sx, sz), sz as f32 * cell_size],
+            normal: heightmap.normal(sx, sz, cell_size).to_array(),
+            uv: [sx as f32 * uv_scale[0], sz as f32 * uv_scale[1]],
+            ..Vertex::default()
+        }
+    };
+
+    let row = xs.len() as u32;
+    let mut vertices: Vec<Vertex> = Vec::with_capacity(xs.len() * zs.len());
+    for &z in zs.iter() {
+        for &x in xs.iter() {
+            vertices.push(vertex(x, z));
+        }
+    }
+
+    let mut indices: Vec<u32> = Vec::with_capacity((xs.len() - 1) * (zs.len() - 1) * 6);
+    for j in 0..zs.len() as u32 - 1 {
+        for i in 0..row - 1 {
+            let a = i + j * row;
+            let (b, c, d) = (a + 1, a + row, a + row + 1);
+            indices.extend_from_slice(&[a, c, b, b, c, d]); // Counter clockwise seen from above
+        }
+    }
+
+    // Walk the border once, each edge vertex gets a dropped copy and the quad between them is emitted in
+    // both windings so it shows from either side
+    let last_row = zs.len() as u32 - 1;
+    let border: Vec<u32> = (0..row)
+        .chain((1..=last_row).map(|j| row - 1 + j * row))
+        .chain((0..row - 1).rev().map(|i| i + last_row * row))
+        .chain((1..last_row).rev().map(|j| j * row))
+        .collect();
+    let skirt_start = vertices.len() as u32;
+    for &v in border.iter() {
+        let mut dropped = vertices[v as usize];
+        dropped.pos[1] -= skirt_depth;
+        vertices.push(dropped);
+    }
+    for k in 0..border.len() {
+        let next = (k + 1) % border.len();
+        let (top0, top1) = (border[k], border[next]);
+        let (bottom0, bottom1) = (skirt_start + k as u32, skirt_start + next as u32);
+        indices.extend_from_slice(&[top0, bottom0, top1, top1, bottom0, bottom1]);
+        indices.extend_from_slice(&[top0, top1, bottom0, top1, bottom1, bottom0]);
+    }
+
+    let bounds = Aabb::from_points(vertices.iter().map(|v| Vec3::from(v.pos)));
+    (vertices, indices, bounds)
+}
@@ -0,0 +1,78 @@
+pub mod mesher;
+pub mod world;
+
+use glam::Vec3;
+
+use crate::assets::ImageData;
+
+// Grid of heights, one sample per cell corner. Sample (x, z) sits at (x, height, z) * cell size in the
+// terrain's space.
+#[derive(Clone, Debug)]
+pub struct Heightmap {
+    width: u32, // Samples along X
+    depth: u32, // Samples along Z
+    heights: Vec<f32> // X fastest, then Z
+}
+
+impl Heightmap {
+    pub fn new(width: u32, depth: u32, heights: Vec<f32>) -> Heightmap {
+        assert!(width >= 2 && depth >= 2, "A heightmap needs at least 2x2 samples");
+        assert_eq!(heights.len(), (width * depth) as usize, "Expected a height per sample");
+
+        Heightmap {
+            width,
+            depth,
+            heights
+        }
+    }
+
+    // Heights from the image's red channel, 0 to height_scale. PNGs are decoded to 8 bits, so large
+    // scales show terracing.
+    pub fn from_image(image: &ImageData, height_scale: f32) -> Heightmap {
+        let heights = image.pixels
+            .chunks_exact(4)
+            .map(|p| p[0] as f32 / 255.0 * height_scale)
+            .collect();
+
+        Heightmap::new(image.width, image.height, heights)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    // Clamped to the edges
+    pub fn get(&self, x: i64, z: i64) -> f32 {
+        let x = x.clamp(0, self.width as i64 - 1) as usize;
+        let z = z.clamp(0, self.depth as i64 - 1) as usize;
+        self.heights[x + z * self.width as usize]
+    }
+
+    // Height at a point given in samples, interpolated across the same triangles the full detail mesh
+    // uses. None outside the grid.
+    pub fn height(&self, x: f32, z: f32) -> Option<f32> {
+        if x < 0.0 || z < 0.0 || x > (self.width - 1) as f32 || z > (self.depth - 1) as f32 {
+            return None;
+        }
+        let (cx, cz) = ((x.floor() as i64).min(self.width as i64 - 2), (z.floor() as i64).min(self.depth as i64 - 2));
+        let (fx, fz) = (x - cx as f32, z - cz as f32);
+        let (a, b, c, d) = (self.get(cx, cz), self.get(cx + 1, cz), self.get(cx, cz + 1), self.get(cx + 1, cz + 1));
+
+        // Cells are split along the diagonal from (1, 0) to (0, 1), see mesher::chunk_mesh
+        Some(match fx + fz <= 1.0 {
+            true => a + (b - a) * fx + (c - a) * fz,
+            false => d + (c - d) * (1.0 - fx) + (b - d) * (1.0 - fz)
+        })
+    }
+
+    // Surface normal at a sample from its neighbours' heights
+    pub fn normal(&self, x: i64, z: i64, cell_size: f32) -> Vec3 {
+        let dx = self.get(x + 1, z) - self.get(x - 1, z);
+        let dz = self.get(x, z + 1) - self.get(x, z - 1);
+        Vec3::new(-dx, 2.0 * cell_size, -dz).normalize()
+    }
+}
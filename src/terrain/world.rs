@@ -0,0 +1,192 @@
+use glam::{Mat4, Vec3};
+
+use crate::renderer::error::RendererError;
+use crate::renderer::frame::Frame;
+use crate::renderer::frustum::Aabb;
+use crate::renderer::material::{BlendMode, MaterialDesc, MaterialParams, ShaderVariant};
+use crate::renderer::mesh::MeshHandle;
+use crate::renderer::occlusion::OcclusionId;
+use crate::renderer::render_queue::MaterialHandle;
+use crate::renderer::renderer::CubulousRenderer;
+use crate::renderer::texture::TextureHandle;
+use crate::terrain::mesher::{chunk_mesh, ChunkRect};
+use crate::terrain::Heightmap;
+
+#[derive(Clone, Copy, Debug)]
+pub struct TerrainDesc {
+    pub origin: Vec3, // Where sample (0, 0) at height 0 ends up
+    pub cell_size: f32, // World units between neighbouring samples
+    pub chunk_cells: u32, // Cells along a chunk's edge at full detail
+    pub lod_count: u32, // Each LOD uses every other sample of the one before it
+    pub lod_distance: f32, // A chunk drops a LOD every this many world units away from the camera
+    pub skirt_depth: f32 // How far chunk edges hang down to hide the cracks between LODs
+}
+
+impl Default for TerrainDesc {
+    fn default() -> Self {
+        TerrainDesc {
+            origin: Vec3::ZERO,
+            cell_size: 1.0,
+            chunk_cells: 64,
+            lod_count: 4,
+            lod_distance: 96.0,
+            skirt_depth: 2.0
+        }
+    }
+}
+
+// Textures blended by a splat map on the TERRAIN shader variant. The splat map's red, green and blue
+// channels weigh the first three layers, whatever's left up to 1 goes to the fourth. The splat map
+// covers the whole terrain once, the layers repeat every 1 / tiling of it.
+#[derive(Clone, Copy, Debug)]
+pub struct SplatDesc {
+    pub splat_map: TextureHandle, // Linear
+    pub layers: [TextureHandle; 4], // sRGB colors
+    pub tiling: f32,
+    pub roughness: f32 // For the specular highlight, as with the LIT variant
+}
+
+impl SplatDesc {
+    // TERRAIN reads the splat map from the base color slot and the layers from the other four
+    pub fn material_desc(&self) -> MaterialDesc {
+        let mut params = MaterialParams::new([1.0, 1.0, 1.0, 1.0], 0.0, self.roughness);
+        params.normal_scale = self.tiling;
+
+        MaterialDesc {
+            shader: ShaderVariant::TERRAIN,
+            base_color_texture: Some(self.splat_map),
+            normal_texture: Some(self.layers[0]),
+            metallic_roughness_texture: Some(self.layers[1]),
+            occlusion_texture: Some(self.layers[2]),
+            emissive_texture: Some(self.layers[3]),
+            params,
+            blend: BlendMode::Opaque
+        }
+    }
+}
+
+struct TerrainChunk {
+    lods: Vec<MeshHandle>, // Full detail first
+    bounds: Aabb, // In the terrain's space, covers every LOD
+    occlusion: OcclusionId // Chunks behind hills are skipped
+}
+
+// A heightmap cut into chunks, each meshed at every LOD up front. draw() picks a LOD per chunk by its
+// distance to the camera and skips chunks outside the frustum.
+pub struct Terrain {
+    heightmap: Heightmap,
+    desc: TerrainDesc,
+    chunks: Vec<TerrainChunk>,
+    pub material: MaterialHandle // Used for every chunk, see SplatDesc
+}
+
+impl Terrain {
+    pub fn new(renderer: &mut CubulousRenderer, heightmap: Heightmap, desc: TerrainDesc) -> Result<Terrain, RendererError> {
+        let mut terrain = Terrain {
+            heightmap,
+            desc,
+            chunks: Vec::new(),
+            material: MaterialHandle::DEFAULT
+        };
+
+        let chunk_cells = desc.chunk_cells.max(1);
+        let (cells_x, cells_z) = (terrain.heightmap.width() - 1, terrain.heightmap.depth() - 1);
+        for z in (0..cells_z).step_by(chunk_cells as usize) {
+            for x in (0..cells_x).step_by(chunk_cells as usize) {
+                let rect = ChunkRect {
+                    x,
+                    z,
+                    width: chunk_cells.min(cells_x - x),
+                    depth: chunk_cells.min(cells_z - z)
+                };
+                if let Err(e) = terrain.add_chunk(renderer, rect) {
+                    terrain.remove(renderer);
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(terrain)
+    }
+
+    fn add_chunk(&mut self, renderer: &mut CubulousRenderer, rect: ChunkRect) -> Result<(), RendererError> {
+        let mut chunk = TerrainChunk {
+            lods: Vec::with_capacity(self.desc.lod_count as usize),
+            bounds: Aabb { min: Vec3::ZERO, max: Vec3::ZERO },
+            occlusion: renderer.create_occlusion_id()
+        };
+        for lod in 0..self.desc.lod_count.max(1) {
+            let (vertices, indices, bounds) = chunk_mesh(&self.heightmap, rect, 1 << lod, self.desc.cell_size, self.desc.skirt_depth);
+            match renderer.upload_meshlet_mesh(&vertices, &indices) {
+                Ok(m) => chunk.lods.push(m),
+                Err(e) => {
+                    for m in chunk.lods {
+                        renderer.remove_mesh(m);
+                    }
+                    renderer.remove_occlusion_id(chunk.occlusion);
+                    return Err(e);
+                }
+            }
+            chunk.bounds = match lod {
+                0 => bounds,
+                _ => Aabb { min: chunk.bounds.min.min(bounds.min), max: chunk.bounds.max.max(bounds.max) }
+            };
+        }
+        self.chunks.push(chunk);
+
+        Ok(())
+    }
+
+    // Creates a material for the splat textures and uses it for every chunk. The previous material is left
+    // to the caller.
+    pub fn set_splat(&mut self, renderer: &mut CubulousRenderer, splat: &SplatDesc) -> Result<MaterialHandle, RendererError> {
+        self.material = renderer.create_material(&splat.material_desc())?;
+        Ok(self.material)
+    }
+
+    pub fn heightmap(&self) -> &Heightmap {
+        &self.heightmap
+    }
+
+    // Surface height under a world position, ignoring its Y. None off the edge of the terrain.
+    pub fn height_at(&self, pos: Vec3) -> Option<f32> {
+        let local = (pos - self.desc.origin) / self.desc.cell_size;
+        self.heightmap.height(local.x, local.z).map(|h| h + self.desc.origin.y)
+    }
+
+    // Surface normal under a world position, from the nearest sample
+    pub fn normal_at(&self, pos: Vec3) -> Option<Vec3> {
+        let local = (pos - self.desc.origin) / self.desc.cell_size;
+        self.heightmap.height(local.x, local.z)?;
+        Some(self.heightmap.normal(local.x.round() as i64, local.z.round() as i64, self.desc.cell_size))
+    }
+
+    // Queues the chunks intersecting the camera frustum, each at the LOD for its distance. Chunks occlusion
+    // queries found hidden behind nearer ones are left out.
+    pub fn draw(&self, frame: &mut Frame) {
+        let camera = frame.camera();
+        let (frustum, eye) = (camera.frustum(), camera.position());
+        let transform = Mat4::from_translation(self.desc.origin);
+        let lod_distance = self.desc.lod_distance.max(f32::EPSILON);
+
+        for chunk in self.chunks.iter() {
+            let (min, max) = (chunk.bounds.min + self.desc.origin, chunk.bounds.max + self.desc.origin);
+            if !frustum.intersects_aabb(min, max) {
+                continue;
+            }
+            let distance = eye.clamp(min, max).distance(eye); // 0 inside the box
+            let lod = ((distance / lod_distance) as usize).min(chunk.lods.len() - 1);
+            frame.draw_occludable(chunk.lods[lod], transform, self.material, chunk.occlusion);
+        }
+    }
+
+    // Releases every chunk's meshes and occlusion id. The material is left to the caller.
+    pub fn remove(self, renderer: &mut CubulousRenderer) {
+        for chunk in self.chunks {
+            for m in chunk.lods {
+                renderer.remove_mesh(m);
+            }
+            renderer.remove_occlusion_id(chunk.occlusion);
+        }
+    }
+}
@@ -0,0 +1,43 @@
+// Reads little endian fields out of a byte slice, I.E. asset pack indices, save files and network messages.
+// Reads past the end fail instead of panicking, so corrupt data can't crash the reader.
+pub(crate) struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> Cursor<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Cursor<'a> {
+        Cursor {
+            data,
+            pos: 0
+        }
+    }
+
+    pub(crate) fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let bytes = self.data.get(self.pos..self.pos.saturating_add(len)).ok_or("Truncated data")?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, String> {
+        Ok(u16::from_le_bytes(self.bytes(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> Result<u64, String> {
+        Ok(u64::from_le_bytes(self.bytes(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.pos.min(self.data.len())..];
+        self.pos = self.data.len();
+        rest
+    }
+}
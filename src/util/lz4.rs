@@ -0,0 +1,14 @@
+// Decompresses LZ4 written by lz4_flex::compress_prepend_size, whose size prefix has to be exactly size.
+// The prefix comes from a file or the network, so it's checked before anything is allocated.
+pub(crate) fn decompress_sized(stored: &[u8], size: usize) -> Result<Vec<u8>, String> {
+    let prefix = stored.get(..4).map(|s| u32::from_le_bytes(s.try_into().unwrap()) as usize);
+    if prefix != Some(size) {
+        return Err(format!("Expected {} bytes uncompressed, the data says {:?}", size, prefix));
+    }
+
+    let mut out = vec![0; size];
+    match lz4_flex::decompress_into(&stored[4..], &mut out).map_err(|e| e.to_string())? {
+        n if n == size => Ok(out),
+        n => Err(format!("Decompressed to {} bytes instead of {}", n, size))
+    }
+}
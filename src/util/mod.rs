@@ -0,0 +1,2 @@
+pub(crate) mod cursor;
+pub(crate) mod lz4;
@@ -0,0 +1,149 @@
+use glam::{IVec3, Vec3};
+
+use crate::profiling::profile_function;
+use crate::renderer::vertex::Vertex;
+use crate::voxel::{BlockId, Chunk, CHUNK_SIZE};
+
+const AXES: [IVec3; 3] = [IVec3::X, IVec3::Y, IVec3::Z];
+const AO_LEVELS: [f32; 4] = [0.35, 0.55, 0.78, 1.0]; // Vertex color scale by how many of a corner's neighbours are open
+
+// A visible block face, faces merge into one quad when they're equal
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Face {
+    block: BlockId, // Air where there's no face
+    ao: [u8; 4] // Per corner, 0 to 3. Corners at -u -v, +u -v, +u +v and -u +v.
+}
+
+const NO_FACE: Face = Face { block: BlockId::AIR, ao: [3; 4] };
+
+// Ambient occlusion of a face's corners from the blocks around it in front of the face: each corner is
+// darkened by the two blocks beside it and the one diagonal to it, fully when both sides are solid
+fn face_ao<F: Fn(IVec3) -> bool>(front: IVec3, u: IVec3, v: IVec3, solid: F) -> [u8; 4] {
+    [(-1, -1), (1, -1), (1, 1), (-1, 1)].map(|(su, sv)| {
+        let side_u = solid(front + u * su);
+        let side_v = solid(front + v * sv);
+        match side_u && side_v {
+            true => 0,
+            false => 3 - side_u as u8 - side_v as u8 - solid(front + u * su + v * sv) as u8
+        }
+    })
+}
+
+// Greedy meshing: visible faces in each slice of the chunk are merged into the largest rectangles of the
+// same block, so flat surfaces cost two triangles instead of two per block face.
+// sample returns blocks outside the chunk (one past each edge) so faces against neighbouring chunks
+// are culled and shaded. Positions are local to the chunk. Ambient occlusion is baked into the vertex
+// colors, only faces with the same occlusion at every corner are merged. layer gives the texture array layer
+// of a block's face pointing along a normal.
+pub fn greedy_mesh<F, C, L>(chunk: &Chunk, sample: F, color: C, layer: L) -> (Vec<Vertex>, Vec<u32>)
+    where F: Fn(IVec3) -> BlockId, C: Fn(BlockId) -> [f32; 3], L: Fn(BlockId, IVec3) -> u32 {
+    profile_function!();
+    let mut vertices: Vec<Vertex> = Vec::new();
+    let mut indices: Vec<u32> = Vec::new();
+    if chunk.is_empty() {
+        return (vertices, indices);
+    }
+
+    let size = CHUNK_SIZE as usize;
+    let block_at = |pos: IVec3| match Chunk::contains(pos) {
+        true => chunk.get(pos),
+        false => sample(pos)
+    };
+    let solid = |pos: IVec3| !block_at(pos).is_air();
+    let mut mask: Vec<Face> = vec![NO_FACE; size * size];
+
+    for axis in 0..3 {
+        // u and v span the slice, u x v points along the axis
+        let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+
+        for dir in [1, -1] {
+            let normal = AXES[axis] * dir;
+
+            for slice in 0..CHUNK_SIZE {
+                // Blocks in this slice with an exposed face pointing along normal
+                for j in 0..CHUNK_SIZE {
+                    for i in 0..CHUNK_SIZE {
+                        let pos = AXES[axis] * slice + AXES[u] * i + AXES[v] * j;
+                        let block = chunk.get(pos);
+                        mask[i as usize + j as usize * size] = match !block.is_air() && !solid(pos + normal) {
+                            true => Face { block, ao: face_ao(pos + normal, AXES[u], AXES[v], solid) },
+                            false => NO_FACE
+                        };
+                    }
+                }
+
+                for j in 0..size {
+                    let mut i = 0;
+                    while i < size {
+                        let face = mask[i + j * size];
+                        if face.block.is_air() {
+                            i += 1;
+                            continue;
+                        }
+
+                        let mut width = 1;
+                        while i + width < size && mask[i + width + j * size] == face {
+                            width += 1;
+                        }
+                        let mut height = 1;
+                        while j + height < size && mask[i + (j + height) * size..i + width + (j + height) * size].iter().all(|f| *f == face) {
+                            height += 1;
+                        }
+                        for row in j..j + height {
+                            mask[i + row * size..i + width + row * size].fill(NO_FACE);
+                        }
+
+                        // Faces pointing along +axis sit on the far side of the block
+                        let plane = slice + (dir > 0) as i32;
+                        let origin = AXES[axis] * plane + AXES[u] * i as i32 + AXES[v] * j as i32;
+                        push_quad(&mut vertices, &mut indices, origin.as_vec3(),
+                                  AXES[u].as_vec3() * width as f32,
+                                  AXES[v].as_vec3() * height as f32,
+                                  normal.as_vec3(),
+                                  color(face.block),
+                                  layer(face.block, normal),
+                                  face.ao);
+
+                        i += width;
+                    }
+                }
+            }
+        }
+    }
+
+    (vertices, indices)
+}
+
+// du x dv points along +axis, so the winding is flipped for faces pointing the other way to stay
+// counter clockwise when seen from the front
+fn push_quad(vertices: &mut Vec<Vertex>, indices: &mut Vec<u32>, origin: Vec3, du: Vec3, dv: Vec3, normal: Vec3,
+             color: [f32; 3], layer: u32, ao: [u8; 4]) {
+    let (width, height) = (du.length(), dv.length());
+    let sign = normal.dot(du.cross(dv)).signum();
+    let corners = match sign > 0.0 {
+        true => [(origin, [0.0, 0.0], ao[0]), (origin + du, [width, 0.0], ao[1]),
+                 (origin + du + dv, [width, height], ao[2]), (origin + dv, [0.0, height], ao[3])],
+        false => [(origin, [0.0, 0.0], ao[0]), (origin + dv, [0.0, height], ao[3]),
+                  (origin + du + dv, [width, height], ao[2]), (origin + du, [width, 0.0], ao[1])]
+    };
+    let tangent = du.normalize();
+
+    let base = vertices.len() as u32;
+    for (pos, uv, ao) in corners {
+        let shade = AO_LEVELS[ao as usize];
+        vertices.push(Vertex {
+            pos: pos.to_array(),
+            normal: normal.to_array(),
+            uv, // One texture repeat per block
+            tangent: [tangent.x, tangent.y, tangent.z, sign],
+            color: color.map(|c| c * shade),
+            layer
+        });
+    }
+    // Split along the diagonal that keeps the occlusion gradient symmetric, otherwise the two triangles
+    // interpolate it differently and the quad looks creased
+    match ao[0] as u32 + ao[2] as u32 > ao[1] as u32 + ao[3] as u32 {
+        true => indices.extend_from_slice(&[base + 1, base + 2, base + 3, base + 3, base, base + 1]),
+        false => indices.extend_from_slice(&[base, base + 1, base + 2, base + 2, base + 3, base])
+    }
+}
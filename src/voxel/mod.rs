@@ -0,0 +1,122 @@
+pub mod mesher;
+pub mod world;
+
+use std::mem;
+
+use glam::IVec3;
+
+pub const CHUNK_SIZE: i32 = 32; // Blocks along each edge of a chunk
+pub(crate) const CHUNK_VOLUME: usize = (CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE) as usize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct BlockId(pub u16);
+
+impl BlockId {
+    pub const AIR: BlockId = BlockId(0);
+
+    pub fn is_air(&self) -> bool {
+        *self == BlockId::AIR
+    }
+}
+
+// Texture array layers of a block's faces, read by the VOXEL shader variant
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub struct BlockTextures {
+    pub top: u32, // +Y
+    pub side: u32,
+    pub bottom: u32 // -Y
+}
+
+impl BlockTextures {
+    // The same layer on every face
+    pub fn all(layer: u32) -> BlockTextures {
+        BlockTextures {
+            top: layer,
+            side: layer,
+            bottom: layer
+        }
+    }
+
+    // The layer of the face pointing along normal
+    pub fn layer(&self, normal: IVec3) -> u32 {
+        match normal.y {
+            1 => self.top,
+            -1 => self.bottom,
+            _ => self.side
+        }
+    }
+}
+
+// A CHUNK_SIZE cube of blocks, positions are local to the chunk
+#[derive(Clone)]
+pub struct Chunk {
+    blocks: Vec<BlockId>, // X fastest, then Y, then Z
+    solid_count: usize // Number of non air blocks, so empty chunks can be skipped without scanning them
+}
+
+impl Chunk {
+    pub fn new() -> Chunk {
+        Chunk {
+            blocks: vec![BlockId::AIR; CHUNK_VOLUME],
+            solid_count: 0
+        }
+    }
+
+    pub fn contains(pos: IVec3) -> bool {
+        pos.cmpge(IVec3::ZERO).all() && pos.cmplt(IVec3::splat(CHUNK_SIZE)).all()
+    }
+
+    fn index(pos: IVec3) -> usize {
+        (pos.x + pos.y * CHUNK_SIZE + pos.z * CHUNK_SIZE * CHUNK_SIZE) as usize
+    }
+
+    // Panics if pos is outside the chunk
+    pub fn get(&self, pos: IVec3) -> BlockId {
+        assert!(Chunk::contains(pos), "{} is outside the chunk", pos);
+        self.blocks[Chunk::index(pos)]
+    }
+
+    pub fn set(&mut self, pos: IVec3, block: BlockId) {
+        assert!(Chunk::contains(pos), "{} is outside the chunk", pos);
+        let old = mem::replace(&mut self.blocks[Chunk::index(pos)], block);
+        match (old.is_air(), block.is_air()) {
+            (true, false) => self.solid_count += 1,
+            (false, true) => self.solid_count -= 1,
+            _ => ()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.solid_count == 0
+    }
+
+    // In index order, for saving
+    pub(crate) fn blocks(&self) -> &[BlockId] {
+        &self.blocks
+    }
+
+    // None unless there are exactly CHUNK_VOLUME blocks
+    pub(crate) fn from_blocks(blocks: Vec<BlockId>) -> Option<Chunk> {
+        if blocks.len() != CHUNK_VOLUME {
+            return None;
+        }
+        let solid_count = blocks.iter().filter(|b| !b.is_air()).count();
+
+        Some(Chunk {
+            blocks,
+            solid_count
+        })
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Chunk::new()
+    }
+}
+
+// Chunk coordinate of the chunk containing a block, and the block's position within it
+pub fn split_pos(pos: IVec3) -> (IVec3, IVec3) {
+    let chunk = IVec3::new(pos.x.div_euclid(CHUNK_SIZE), pos.y.div_euclid(CHUNK_SIZE), pos.z.div_euclid(CHUNK_SIZE));
+    (chunk, pos - chunk * CHUNK_SIZE)
+}
@@ -0,0 +1,317 @@
+use std::collections::{HashMap, HashSet};
+
+use glam::{IVec3, Mat4, Vec3};
+
+use crate::profiling::profile_function;
+use crate::renderer::error::RendererError;
+use crate::renderer::frame::Frame;
+use crate::renderer::mesh::MeshHandle;
+use crate::renderer::pick::Ray;
+use crate::renderer::render_queue::MaterialHandle;
+use crate::renderer::renderer::CubulousRenderer;
+use crate::voxel::mesher::greedy_mesh;
+use crate::voxel::{split_pos, BlockId, BlockTextures, Chunk, CHUNK_SIZE};
+
+// The first solid block along a ray
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BlockHit {
+    pub pos: IVec3,
+    pub block: BlockId,
+    pub normal: IVec3, // Of the face the ray entered through, so pos + normal is where to place a block against it
+    pub distance: f32
+}
+
+// The 26 chunks around chunk_pos. Edges and corners count too, since ambient occlusion reads diagonal blocks.
+fn neighbors(chunk_pos: IVec3) -> impl Iterator<Item = IVec3> {
+    (-1..=1)
+        .flat_map(|z| (-1..=1).flat_map(move |y| (-1..=1).map(move |x| IVec3::new(x, y, z))))
+        .filter(|d| *d != IVec3::ZERO)
+        .map(move |d| chunk_pos + d)
+}
+
+struct ChunkEntry {
+    chunk: Chunk,
+    mesh: Option<MeshHandle> // None until meshed, or when nothing in the chunk is visible
+}
+
+// Sparse grid of chunks. Edits mark chunks dirty, update() remeshes them a few at a time and draw()
+// submits the chunks inside the camera frustum.
+pub struct VoxelWorld {
+    chunks: HashMap<IVec3, ChunkEntry>, // Keyed by chunk coordinate, block position / CHUNK_SIZE
+    dirty: HashSet<IVec3>,
+    unsaved: HashSet<IVec3>, // Edited since the last WorldSave::save_chunks
+    colors: Vec<[f32; 3]>, // Indexed by BlockId, blocks without a color are white
+    textures: Vec<BlockTextures>, // Indexed by BlockId, blocks without textures use layer 0
+    pub max_remesh_per_update: usize, // Bounds the time update() takes after large edits
+    pub view_distance: Option<f32>, // Chunks entirely farther than this from the camera aren't drawn, I.E. past the fog
+    pub material: MaterialHandle // Used for every chunk
+}
+
+impl VoxelWorld {
+    pub fn new() -> VoxelWorld {
+        VoxelWorld {
+            chunks: HashMap::new(),
+            dirty: HashSet::new(),
+            unsaved: HashSet::new(),
+            colors: Vec::new(),
+            textures: Vec::new(),
+            max_remesh_per_update: 4,
+            view_distance: None,
+            material: MaterialHandle::DEFAULT
+        }
+    }
+
+    // Takes effect for chunks meshed afterwards
+    pub fn set_color(&mut self, block: BlockId, color: [f32; 3]) {
+        let i = block.0 as usize;
+        if i >= self.colors.len() {
+            self.colors.resize(i + 1, [1.0, 1.0, 1.0]);
+        }
+        self.colors[i] = color;
+    }
+
+    // Layers of the texture array in material's base color texture, which should use ShaderVariant::VOXEL.
+    // Takes effect for chunks meshed afterwards.
+    pub fn set_textures(&mut self, block: BlockId, textures: BlockTextures) {
+        let i = block.0 as usize;
+        if i >= self.textures.len() {
+            self.textures.resize(i + 1, BlockTextures::default());
+        }
+        self.textures[i] = textures;
+    }
+
+    pub fn block(&self, pos: IVec3) -> BlockId {
+        let (chunk_pos, local) = split_pos(pos);
+        self.chunks.get(&chunk_pos).map_or(BlockId::AIR, |e| e.chunk.get(local))
+    }
+
+    // Marks the block's chunk dirty, along with neighbouring chunks whose faces or ambient occlusion it can
+    // change, I.E. all 7 around a chunk's corner block. Returns the block that was there, I.E. what was mined
+    // when setting air.
+    pub fn set_block(&mut self, pos: IVec3, block: BlockId) -> BlockId {
+        let (chunk_pos, local) = split_pos(pos);
+        if block.is_air() && !self.chunks.contains_key(&chunk_pos) {
+            return BlockId::AIR; // Missing chunks are already air
+        }
+
+        let entry = self.chunks.entry(chunk_pos).or_insert_with(|| ChunkEntry {
+            chunk: Chunk::new(),
+            mesh: None
+        });
+        let previous = entry.chunk.get(local);
+        if previous == block {
+            return previous;
+        }
+        entry.chunk.set(local, block);
+        self.dirty.insert(chunk_pos);
+        self.unsaved.insert(chunk_pos);
+
+        // Only chunks on the sides the block is at the edge of are within a block of it
+        let borders = |d: i32, l: i32| d == 0 || (d < 0 && l == 0) || (d > 0 && l == CHUNK_SIZE - 1);
+        for n in neighbors(chunk_pos) {
+            let d = n - chunk_pos;
+            if borders(d.x, local.x) && borders(d.y, local.y) && borders(d.z, local.z) && self.chunks.contains_key(&n) {
+                self.dirty.insert(n);
+            }
+        }
+
+        previous
+    }
+
+    // Sets every block in the box between two corners, both inclusive
+    pub fn fill(&mut self, a: IVec3, b: IVec3, block: BlockId) {
+        let (min, max) = (a.min(b), a.max(b));
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for z in min.z..=max.z {
+                    self.set_block(IVec3::new(x, y, z), block);
+                }
+            }
+        }
+    }
+
+    // Walks the blocks along the ray one at a time (Amanatides and Woo) up to max_distance. Blocks span
+    // pos to pos + 1. A ray starting inside a solid block hits it with a zero normal.
+    pub fn raycast(&self, ray: &Ray, max_distance: f32) -> Option<BlockHit> {
+        let mut pos = ray.origin.floor().as_ivec3();
+        let step = ray.direction.signum().as_ivec3();
+        let inv = ray.direction.abs().recip(); // Distance along the ray to cross one block on each axis
+        let next_boundary = (pos + step.max(IVec3::ZERO)).as_vec3();
+        let mut t_max = (next_boundary - ray.origin).abs() * inv; // Distance to the next boundary on each axis
+        let mut normal = IVec3::ZERO;
+        let mut distance = 0.0;
+
+        while distance <= max_distance {
+            let block = self.block(pos);
+            if !block.is_air() {
+                return Some(BlockHit { pos, block, normal, distance });
+            }
+            let axis = match (t_max.x < t_max.y, t_max.x < t_max.z, t_max.y < t_max.z) {
+                (true, true, _) => 0,
+                (false, _, true) => 1,
+                _ => 2
+            };
+            distance = t_max[axis];
+            t_max[axis] += inv[axis];
+            pos[axis] += step[axis];
+            normal = IVec3::ZERO;
+            normal[axis] = -step[axis];
+        }
+
+        None
+    }
+
+    pub fn chunk(&self, chunk_pos: IVec3) -> Option<&Chunk> {
+        self.chunks.get(&chunk_pos).map(|e| &e.chunk)
+    }
+
+    // Replaces a chunk, I.E. one loaded by WorldSave. It's remeshed along with its neighbours, but isn't
+    // saved again until it's edited.
+    pub fn insert_chunk(&mut self, chunk_pos: IVec3, chunk: Chunk) {
+        match self.chunks.get_mut(&chunk_pos) {
+            Some(entry) => entry.chunk = chunk, // The old mesh is replaced by update
+            None => {
+                self.chunks.insert(chunk_pos, ChunkEntry { chunk, mesh: None });
+            }
+        }
+        self.unsaved.remove(&chunk_pos);
+        self.dirty.insert(chunk_pos);
+        for n in neighbors(chunk_pos) {
+            if self.chunks.contains_key(&n) {
+                self.dirty.insert(n);
+            }
+        }
+    }
+
+    // Unsaved edits to the chunk are lost, so save before unloading edited chunks
+    pub fn remove_chunk(&mut self, renderer: &mut CubulousRenderer, chunk_pos: IVec3) {
+        if let Some(entry) = self.chunks.remove(&chunk_pos) {
+            if let Some(mesh) = entry.mesh {
+                renderer.remove_mesh(mesh);
+            }
+            self.dirty.remove(&chunk_pos);
+            self.unsaved.remove(&chunk_pos);
+            for n in neighbors(chunk_pos) {
+                if self.chunks.contains_key(&n) {
+                    self.dirty.insert(n);
+                }
+            }
+        }
+    }
+
+    // Whether any chunk has been edited since it was last saved
+    pub fn has_unsaved(&self) -> bool {
+        !self.unsaved.is_empty()
+    }
+
+    // Copies of the chunks edited since the last call, None for ones that are now all air
+    pub(crate) fn take_unsaved(&mut self) -> Vec<(IVec3, Option<Chunk>)> {
+        let chunks = &self.chunks;
+        self.unsaved.drain()
+            .map(|pos| (pos, chunks.get(&pos).map(|e| &e.chunk).filter(|c| !c.is_empty()).cloned()))
+            .collect()
+    }
+
+    // Whether any chunk is waiting to be remeshed
+    pub fn is_dirty(&self) -> bool {
+        !self.dirty.is_empty()
+    }
+
+    // Remeshes up to max_remesh_per_update dirty chunks, nearest to the camera first
+    pub fn update(&mut self, renderer: &mut CubulousRenderer) -> Result<(), RendererError> {
+        profile_function!();
+        let eye = renderer.camera().position();
+        let mut dirty: Vec<IVec3> = self.dirty.iter().copied().collect();
+        dirty.sort_by(|a, b| {
+            Self::chunk_center(*a).distance_squared(eye).total_cmp(&Self::chunk_center(*b).distance_squared(eye))
+        });
+
+        for chunk_pos in dirty.into_iter().take(self.max_remesh_per_update) {
+            self.dirty.remove(&chunk_pos);
+            let (vertices, indices) = match self.chunks.get(&chunk_pos) {
+                Some(entry) => greedy_mesh(&entry.chunk,
+                                           |local| self.block(chunk_pos * CHUNK_SIZE + local),
+                                           |block| self.colors.get(block.0 as usize).copied().unwrap_or([1.0, 1.0, 1.0]),
+                                           |block, normal| self.textures.get(block.0 as usize).map_or(0, |t| t.layer(normal))),
+                None => continue
+            };
+
+            let entry = self.chunks.get_mut(&chunk_pos).unwrap();
+            if let Some(old) = entry.mesh.take() {
+                renderer.remove_mesh(old);
+            }
+            if !indices.is_empty() {
+                entry.mesh = Some(renderer.upload_meshlet_mesh(&vertices, &indices)?);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Queues every meshed chunk that intersects the camera frustum within view_distance
+    pub fn draw(&self, frame: &mut Frame) {
+        let frustum = frame.camera().frustum();
+        let eye = frame.camera().position();
+        let size = Vec3::splat(CHUNK_SIZE as f32);
+        let radius = size.length() * 0.5; // Of the chunk's bounding sphere
+
+        for (chunk_pos, entry) in self.chunks.iter() {
+            if let Some(mesh) = entry.mesh {
+                let min = (*chunk_pos * CHUNK_SIZE).as_vec3();
+                let in_range = self.view_distance
+                    .map_or(true, |d| VoxelWorld::chunk_center(*chunk_pos).distance(eye) - radius <= d);
+                if in_range && frustum.intersects_aabb(min, min + size) {
+                    frame.draw(mesh, Mat4::from_translation(min), self.material);
+                }
+            }
+        }
+    }
+
+    fn chunk_center(chunk_pos: IVec3) -> Vec3 {
+        (chunk_pos.as_vec3() + 0.5) * CHUNK_SIZE as f32
+    }
+}
+
+impl Default for VoxelWorld {
+    fn default() -> Self {
+        VoxelWorld::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A world of loaded, meshed chunks from -1 to 1 on each axis
+    fn loaded_world() -> VoxelWorld {
+        let mut world = VoxelWorld::new();
+        for n in neighbors(IVec3::ZERO).chain([IVec3::ZERO]) {
+            world.insert_chunk(n, Chunk::new());
+        }
+        world.dirty.clear();
+        world
+    }
+
+    #[test]
+    fn edits_dirty_the_chunks_they_border() {
+        let mut world = loaded_world();
+        world.set_block(IVec3::new(5, 6, 7), BlockId(1));
+        assert_eq!(world.dirty, HashSet::from([IVec3::ZERO]));
+
+        let mut world = loaded_world();
+        world.set_block(IVec3::new(0, 6, CHUNK_SIZE - 1), BlockId(1)); // On an edge
+        assert_eq!(world.dirty, HashSet::from([IVec3::ZERO, IVec3::new(-1, 0, 0), IVec3::new(0, 0, 1), IVec3::new(-1, 0, 1)]));
+
+        let mut world = loaded_world();
+        world.set_block(IVec3::splat(CHUNK_SIZE - 1), BlockId(1)); // On a corner
+        assert_eq!(world.dirty.len(), 8);
+        assert!(world.dirty.contains(&IVec3::ONE));
+    }
+
+    #[test]
+    fn loading_a_chunk_dirties_every_neighbor() {
+        let mut world = loaded_world();
+        world.insert_chunk(IVec3::ZERO, Chunk::new());
+        assert_eq!(world.dirty.len(), 27);
+    }
+}